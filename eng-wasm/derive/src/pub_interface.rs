@@ -61,7 +61,12 @@ pub(crate) fn impl_pub_interface(
 
         #[no_mangle]
         pub fn #call_func_name(){
-            #dispatch_func_name(&#function_name_func_name(), &#args_func_name());
+            let args_ = #args_func_name();
+            // Every ABI encoded argument takes whole 32 byte words, anything else was truncated.
+            if args_.len() % 32 != 0 {
+                eng_wasm::revert("the arguments are not ABI encoded, their length isn't a multiple of 32 bytes");
+            }
+            #dispatch_func_name(&#function_name_func_name(), &args_);
         }
     }
 }
@@ -205,6 +210,9 @@ fn generate_deploy_function(
             #[no_mangle]
             pub fn #deploy_func_name() {
                 let args_ = args();
+                if args_.len() % 32 != 0 {
+                    eng_wasm::revert("the arguments are not ABI encoded, their length isn't a multiple of 32 bytes");
+                }
                 let mut stream = eng_wasm::eng_pwasm_abi::eth::Stream::new(&args_);
                 #(let #variables = #parsed_inputs;)*
                 <#implementor>::#constructor_name(#(#variables),*);
@@ -320,7 +328,17 @@ fn generate_dispatch_function(
                         syn::ReturnType::Type(_arrow, type_) => type_,
                         syn::ReturnType::Default => unreachable!("We know that there is an explicit return type")
                     };
-                    let push_result_to_sink = quote_spanned!(output_type.span()=> sink.push(#result_token));
+                    // A tuple isn't an AbiType by itself, every element of it is pushed as a separate return value.
+                    let push_result_to_sink = match output_type.as_ref() {
+                        syn::Type::Tuple(return_tuple) => {
+                            let pushes = return_tuple.elems.iter().enumerate().map(|(i, elem)| {
+                                let index = syn::Index::from(i);
+                                quote_spanned!(elem.span()=> sink.push(#result_token.#index))
+                            });
+                            quote!(#(#pushes);*)
+                        }
+                        _ => quote_spanned!(output_type.span()=> sink.push(#result_token)),
+                    };
                     Some(quote! {
                         #method_name_as_string => {
                             #stream_initialization_snippet
@@ -539,6 +557,9 @@ mod tests {
             #[no_mangle]
             pub fn deploy() {
                 let args_ = args();
+                if args_.len() % 32 != 0 {
+                    eng_wasm::revert("the arguments are not ABI encoded, their length isn't a multiple of 32 bytes");
+                }
                 let mut stream = eng_wasm::eng_pwasm_abi::eth::Stream::new(&args_);
                 let var_0 = stream
                     .pop::<H256>()
@@ -721,4 +742,45 @@ mod tests {
         assert_eq!(output_ast, expected_output_ast);
         Ok(())
     }
+
+    #[test]
+    fn dispatch_generation_tuple_return() -> syn::Result<()> {
+        let input = quote!(
+            pub trait MixerInterface {
+                fn get_parties() -> (Vec<H256>, Vec<U256>);
+            }
+        );
+
+        #[rustfmt::skip]
+        let expected_output = quote!(
+            pub fn dispatch(name: &str, args: &[u8]) {
+                match name {
+                    "get_parties" => {
+                        let result = <Contract>::get_parties();
+                        let mut result_bytes = eng_wasm::Vec::with_capacity(2usize * 32);
+                        let mut sink = eng_wasm::eng_pwasm_abi::eth::Sink::new(2usize);
+                        sink.push(result.0);
+                        sink.push(result.1);
+                        sink.drain_to(&mut result_bytes);
+                        unsafe {
+                            eng_wasm::external::ret(
+                                result_bytes.as_ptr(),
+                                result_bytes.len() as u32
+                            )
+                        }
+                    }
+                    _ => panic!("Unknown method called:\"{}\"", name),
+                }
+            }
+        );
+
+        let expected_output_ast = syn::parse2::<syn::ItemFn>(expected_output)?;
+
+        let signatures = syn::parse2::<PubInterfaceSignatures>(input)?;
+        let output = generate_dispatch_function(&DISPATCH_FUNC_NAME.into_ident(), &signatures);
+        let output_ast = syn::parse2::<syn::ItemFn>(output)?;
+
+        assert_eq!(output_ast, expected_output_ast);
+        Ok(())
+    }
 }
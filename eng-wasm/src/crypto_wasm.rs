@@ -20,7 +20,7 @@ fn extra_size_for_encrypted_text() -> usize {
 
 
 pub fn generate_key() -> SymmetricKey {
-    let key_int: U256 = Rand::gen();
+    let key_int: U256 = Rand::gen_true_random();
     let key = H256::from(key_int);
     key.0
 }
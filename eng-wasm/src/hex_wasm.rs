@@ -0,0 +1,69 @@
+//! Hex helpers for the fixed size hash types used in contracts.
+//! Ethereum addresses are 20 bytes long and should be kept as `H160`,
+//! without padding them into an `H256`.
+use super::*;
+
+const HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FromHexError {
+    /// The string contains a character that isn't a hex digit.
+    InvalidCharacter { index: usize },
+    /// The string doesn't decode to the exact size of the type.
+    InvalidLength { expected: usize, found: usize },
+}
+
+pub trait Hex: Sized {
+    /// Encode as a lowercase hex string prefixed with `0x`.
+    fn to_hex(&self) -> String;
+    /// Decode from a hex string, the `0x` prefix is optional.
+    fn from_hex(hex: &str) -> Result<Self, FromHexError>;
+}
+
+fn encode(bytes: &[u8]) -> String {
+    let mut res = String::with_capacity(2 + bytes.len() * 2);
+    res.push_str("0x");
+    for byte in bytes {
+        res.push(HEX_CHARS[(byte >> 4) as usize] as char);
+        res.push(HEX_CHARS[(byte & 0xf) as usize] as char);
+    }
+    res
+}
+
+fn decode_into(hex: &str, out: &mut [u8]) -> Result<(), FromHexError> {
+    let (offset, digits) = if hex.starts_with("0x") || hex.starts_with("0X") { (2, &hex[2..]) } else { (0, hex) };
+    if digits.len() != out.len() * 2 {
+        return Err(FromHexError::InvalidLength { expected: out.len(), found: digits.len() / 2 });
+    }
+    let nibble = |index: usize, c: u8| match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(FromHexError::InvalidCharacter { index: offset + index }),
+    };
+    let digits = digits.as_bytes();
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = (nibble(2 * i, digits[2 * i])? << 4) | nibble(2 * i + 1, digits[2 * i + 1])?;
+    }
+    Ok(())
+}
+
+impl Hex for H160 {
+    fn to_hex(&self) -> String { encode(&self.0) }
+
+    fn from_hex(hex: &str) -> Result<Self, FromHexError> {
+        let mut res = H160::zero();
+        decode_into(hex, &mut res.0)?;
+        Ok(res)
+    }
+}
+
+impl Hex for H256 {
+    fn to_hex(&self) -> String { encode(&self.0) }
+
+    fn from_hex(hex: &str) -> Result<Self, FromHexError> {
+        let mut res = H256::zero();
+        decode_into(hex, &mut res.0)?;
+        Ok(res)
+    }
+}
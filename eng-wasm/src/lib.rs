@@ -13,13 +13,17 @@ extern crate serde;
 #[macro_use]
 mod internal_std;
 pub mod crypto_wasm;
+mod hex_wasm;
 mod rand_wasm;
+mod uint_wasm;
 pub extern crate eng_pwasm_abi;
 
 pub use crypto_wasm::*;
 pub use eng_pwasm_abi::types::*;
+pub use hex_wasm::*;
 pub use internal_std::*;
 pub use rand_wasm::*;
+pub use uint_wasm::*;
 pub use serde_json::Value;
 
 pub mod external {
@@ -37,6 +41,10 @@ pub mod external {
         pub fn gas(amount: u32);
         pub fn ret(payload: *const u8, payload_len: u32);
         pub fn rand(payload: *const u8, payload_len: u32);
+        pub fn true_rand(payload: *const u8, payload_len: u32);
+        pub fn revert(payload: *const u8, payload_len: u32);
+        pub fn get_caller(payload: *const u8);
+        pub fn next_counter(payload: *const u8);
         pub fn encrypt(message: *const u8, message_len: u32, key: *const u8, payload: *const u8);
         pub fn decrypt(cipheriv: *const u8, cipheriv_len: u32, key: *const u8, payload: *const u8);
     }
@@ -65,23 +73,82 @@ where T: serde::Serialize {
     unsafe { external::write_state(key.as_ptr(), key.len() as u32, value_vec.as_ptr(), value_vec.len() as u32) }
 }
 
-/// Read from state
+/// The largest integer a JSON number keeps exactly when it's read as a double (2^53 - 1).
+const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// Why a value in the state couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The stored value isn't valid JSON.
+    Malformed { key: String },
+    /// The stored value can't be decoded as the requested type.
+    WrongType { key: String },
+    /// The name of a namespace can't contain `/`, it separates the namespace from the key (see `write_ns`).
+    InvalidNamespace { ns: String },
+}
+
+/// Read from state, `None` if the key is missing or its value can't be decoded as `T` (`try_read` tells them apart).
 pub fn read<T>(key: &str) -> Option<T>
+where for<'de> T: serde::Deserialize<'de> {
+    try_read(key).unwrap_or_else(|e| {
+        eprint!("{:?}", e);
+        None
+    })
+}
+
+/// Read from state, `Ok(None)` if the key is missing and an error if its value can't be decoded as `T`.
+pub fn try_read<T>(key: &str) -> Result<Option<T>, StateError>
 where for<'de> T: serde::Deserialize<'de> {
     let val_len = unsafe { external::read_state_len(key.as_ptr(), key.len() as u32) };
     let value_holder: Vec<u8> = iter::repeat(0).take(val_len as usize).collect();
     unsafe { external::read_state(key.as_ptr(), key.len() as u32, value_holder.as_ptr()) };
-    let value: Value = serde_json::from_slice(&value_holder)
-        .map_err(|_| print("failed unwrapping from_slice in read_state"))
-        .expect("read_state failed");
+    let value: Value = serde_json::from_slice(&value_holder).map_err(|_| StateError::Malformed { key: key.to_string() })?;
+    from_state_value(key, value)
+}
+
+fn from_state_value<T>(key: &str, value: Value) -> Result<Option<T>, StateError>
+where for<'de> T: serde::Deserialize<'de> {
     if value.is_null() {
-        return None;
+        return Ok(None);
+    }
+    serde_json::from_value(value)
+        .map(Some)
+        .map_err(|_| StateError::WrongType { key: key.to_string() })
+}
+
+/// The prefix of the state keys of the namespaced entries (see `write_ns`).
+pub const NAMESPACE_KEY_PREFIX: &str = "$ns/";
+
+/// The state key `key` of the namespace `ns` is stored under, e.g. `$ns/first/value`.
+fn namespaced_key(ns: &str, key: &str) -> Result<String, StateError> {
+    if ns.contains('/') {
+        return Err(StateError::InvalidNamespace { ns: ns.to_string() });
     }
-    Some(
-        serde_json::from_value(value.clone())
-            .map_err(|_| print("failed unwrapping from_value in read_state"))
-            .expect("read_state failed"),
-    )
+    Ok(eformat!("{}{}/{}", NAMESPACE_KEY_PREFIX, ns, key))
+}
+
+/// Write to the namespace `ns`, every entry is a state key of its own prefixed with the namespace (see `namespaced_key`),
+/// so writing one doesn't rewrite the rest of the namespace, and a namespace doesn't collide with the keys outside of it.
+/// Different modules of a contract can use the same keys in their own namespaces without overwriting each other.
+pub fn write_ns<T>(ns: &str, key: &str, value: T) -> Result<(), StateError>
+where T: serde::Serialize {
+    write(&namespaced_key(ns, key)?, value);
+    Ok(())
+}
+
+/// Read `key` from the namespace `ns` (see `write_ns`), `None` if it's missing or can't be read (`try_read_ns` tells them apart).
+pub fn read_ns<T>(ns: &str, key: &str) -> Option<T>
+where for<'de> T: serde::Deserialize<'de> {
+    try_read_ns(ns, key).unwrap_or_else(|e| {
+        eprint!("{:?}", e);
+        None
+    })
+}
+
+/// Read `key` from the namespace `ns`, `Ok(None)` if the key is missing and an error if it can't be read.
+pub fn try_read_ns<T>(ns: &str, key: &str) -> Result<Option<T>, StateError>
+where for<'de> T: serde::Deserialize<'de> {
+    try_read(&namespaced_key(ns, key)?)
 }
 
 /// Remove key and value from state
@@ -96,8 +163,67 @@ pub fn write_ethereum_bridge(payload: &[u8], address: &Address) {
     unsafe { external::write_eth_bridge(payload.as_ptr(), payload.len() as u32, address.as_ptr()) };
 }
 
+/// The Ethereum address of the user that sent the task.
+/// The enclave derives it from the key the task was encrypted with (not from anything the user sends), so it can be used for access control.
+pub fn caller() -> H160 {
+    let mut address = [0u8; 20];
+    unsafe { external::get_caller(address.as_mut_ptr()) };
+    H160::from(address)
+}
+
+/// A counter of the contract that persists across tasks, it's kept by the runtime in a state key the contract can't write to.
+pub struct Counter;
+
+impl Counter {
+    /// Returns the current value of the counter and increments it, the first call of a contract returns 0.
+    /// Like any other state change, the increment is discarded if the task fails.
+    pub fn next() -> u64 {
+        let mut value = [0u8; 8];
+        unsafe { external::next_counter(value.as_mut_ptr()) };
+        u64::from_be_bytes(value)
+    }
+}
+
+/// The selector of `Error(string)`, the payload of a revert is ABI encoded like Solidity's `revert(reason)`.
+pub const REVERT_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// Stops the execution and discards its state changes, `reason` is returned to the user in the output of the failed task.
+pub fn revert(reason: &str) -> ! {
+    let mut payload = REVERT_SELECTOR.to_vec();
+    // The offset of the string and then its length, each is a 32 bytes big endian word.
+    let mut word = [0u8; 32];
+    word[31] = 0x20;
+    payload.extend_from_slice(&word);
+    word[24..].copy_from_slice(&(reason.len() as u64).to_be_bytes());
+    payload.extend_from_slice(&word);
+    payload.extend_from_slice(reason.as_bytes());
+    let padding = (32 - reason.len() % 32) % 32;
+    payload.extend_from_slice(&[0u8; 32][..padding]);
+    unsafe { external::revert(payload.as_ptr(), payload.len() as u32) };
+    unreachable!("The runtime doesn't return from a revert")
+}
+
+/// Reverts with `reason` if `cond` doesn't hold, like Solidity's `require`.
+#[macro_export]
+macro_rules! require {
+    ( $cond: expr, $reason: expr ) => {
+        if !$cond {
+            $crate::revert($reason)
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! write_state {
+     ( ns $ns: expr, $($key: expr => $val: expr),+ ) => {
+         {
+             $(
+                 if let Err(e) = $crate::write_ns($ns, $key, $val) {
+                     $crate::revert(&eformat!("{:?}", e))
+                 }
+             )+
+         }
+     };
      ( $($key: expr => $val: expr),+ ) => {
          {
              $(
@@ -109,11 +235,28 @@ macro_rules! write_state {
 
 #[macro_export]
 macro_rules! read_state {
+    ( ns $ns: expr, $key: expr ) => {{
+        $crate::read_ns($ns, $key)
+    }};
     ( $key: expr ) => {{
         $crate::read($key)
     }};
 }
 
+/// Returns several values as an ABI encoded tuple and ends the call,
+/// the same encoding `#[pub_interface]` uses for a function returning a tuple.
+#[macro_export]
+macro_rules! ret_tuple {
+    ( $($val: expr),+ ) => {{
+        let values_count = [$(stringify!($val)),+].len();
+        let mut sink = $crate::eng_pwasm_abi::eth::Sink::new(values_count);
+        $( sink.push($val); )+
+        let mut result_bytes = $crate::Vec::with_capacity(values_count * 32);
+        sink.drain_to(&mut result_bytes);
+        unsafe { $crate::external::ret(result_bytes.as_ptr(), result_bytes.len() as u32) }
+    }};
+}
+
 #[macro_export]
 macro_rules! remove_from_state {
     ( $key: expr ) => {{
@@ -131,4 +274,68 @@ mod tests {
     fn test_encrypt() {
         // TODO: Is this the right place to test APIs. If so, how should we initialize the enclave?
     }
+
+    #[test]
+    fn test_u128_abi() {
+        use eng_pwasm_abi::eth::{Sink, Stream};
+        let big = U128((1 << 127) + 12345);
+        let mut sink = Sink::new(1);
+        sink.push(big);
+        let mut encoded = Vec::new();
+        sink.drain_to(&mut encoded);
+        assert_eq!(encoded.len(), 32);
+        assert_eq!(Stream::new(&encoded).pop::<U128>().unwrap(), big);
+
+        // 2^128 doesn't fit.
+        let mut overflow = [0u8; 32];
+        overflow[15] = 1;
+        assert!(Stream::new(&overflow).pop::<U128>().is_err());
+    }
+
+    #[test]
+    fn test_u128_state_value() {
+        let big = U128(u128::max_value());
+        let value = json!(big);
+        assert_eq!(value, json!("340282366920938463463374607431768211455"));
+        assert_eq!(from_state_value::<U128>("big", value), Ok(Some(big)));
+        assert_eq!(json!(U128(7)), json!(7));
+        assert_eq!(from_state_value::<U128>("small", json!(7)), Ok(Some(U128(7))));
+    }
+
+    #[test]
+    fn test_state_value_of_wrong_type() {
+        // Bytes are stored as an array of numbers, which isn't a String.
+        let bytes = json!([0xffu8, 0xfe, 0x00, 0xc3, 0x28]);
+        assert_eq!(from_state_value::<String>("bytes", bytes.clone()), Err(StateError::WrongType { key: "bytes".to_string() }));
+        assert_eq!(from_state_value::<Vec<u8>>("bytes", bytes), Ok(Some(vec![0xff, 0xfe, 0x00, 0xc3, 0x28])));
+        assert_eq!(from_state_value::<String>("missing", Value::Null), Ok(None));
+    }
+
+    #[test]
+    fn test_namespaced_key() {
+        assert_eq!(namespaced_key("first", "value"), Ok("$ns/first/value".to_string()));
+        // The key may hold the separator, the namespace ends at the first one.
+        assert_eq!(namespaced_key("first", "a/b"), Ok("$ns/first/a/b".to_string()));
+        assert_eq!(namespaced_key("first/a", "b"), Err(StateError::InvalidNamespace { ns: "first/a".to_string() }));
+    }
+
+    #[test]
+    fn test_h160_hex() {
+        let hex = "0x5ed8cee6b63b1c6afce3ad7c92f4fd7e1b8fad9f";
+        let addr = H160::from_hex(hex).unwrap();
+        assert_eq!(addr.to_hex(), hex);
+        assert_eq!(H160::from_hex(&hex[2..]).unwrap(), addr);
+        assert_eq!(H160::from_hex("0x5ED8CEE6B63B1C6AFCE3AD7C92F4FD7E1B8FAD9F").unwrap(), addr);
+    }
+
+    #[test]
+    fn test_h160_from_bad_hex() {
+        assert_eq!(H160::from_hex("0x5ed8"), Err(FromHexError::InvalidLength { expected: 20, found: 2 }));
+        assert_eq!(
+            H160::from_hex("0x5ed8cee6b63b1c6afce3ad7c92f4fd7e1b8fadzz"),
+            Err(FromHexError::InvalidCharacter { index: 40 })
+        );
+        // An address must not be silently padded into an H256.
+        assert!(H256::from_hex("0x5ed8cee6b63b1c6afce3ad7c92f4fd7e1b8fad9f").is_err());
+    }
 }
@@ -4,12 +4,19 @@ use super::*;
 pub struct Rand;
 
 impl Rand {
+    /// Fills `slice` with the randomness of the task, it's derived from the task id
+    /// so running the same task again on the same contract gives the same bytes.
     pub fn gen_slice(slice: &mut [u8]) { unsafe { external::rand(slice.as_ptr(), slice.len() as u32) }; }
+
+    /// Fills `slice` with fresh entropy from the enclave, use it where the randomness must not be reproducible (i.e. keys).
+    pub fn gen_true_random_slice(slice: &mut [u8]) { unsafe { external::true_rand(slice.as_ptr(), slice.len() as u32) }; }
 }
 
 pub trait RandTypes<T> {
     /// generate a random number on the trusted side.
     fn gen() -> T;
+    /// generate a random number on the trusted side from fresh entropy.
+    fn gen_true_random() -> T;
 }
 
 impl RandTypes<U256> for Rand {
@@ -18,6 +25,12 @@ impl RandTypes<U256> for Rand {
         Self::gen_slice(&mut r);
         U256::from_big_endian(&r)
     }
+
+    fn gen_true_random() -> U256 {
+        let mut r: [u8; 32] = [0u8; 32];
+        Self::gen_true_random_slice(&mut r);
+        U256::from_big_endian(&r)
+    }
 }
 
 impl RandTypes<u8> for Rand {
@@ -26,6 +39,12 @@ impl RandTypes<u8> for Rand {
         Self::gen_slice(&mut r);
         r[0]
     }
+
+    fn gen_true_random() -> u8 {
+        let mut r: [u8; 1] = [0u8; 1];
+        Self::gen_true_random_slice(&mut r);
+        r[0]
+    }
 }
 
 impl RandTypes<u16> for Rand {
@@ -34,6 +53,12 @@ impl RandTypes<u16> for Rand {
         Self::gen_slice(&mut r);
         u16::from_be_bytes(r)
     }
+
+    fn gen_true_random() -> u16 {
+        let mut r: [u8; 2] = [0u8; 2];
+        Self::gen_true_random_slice(&mut r);
+        u16::from_be_bytes(r)
+    }
 }
 
 impl RandTypes<u32> for Rand {
@@ -42,6 +67,12 @@ impl RandTypes<u32> for Rand {
         Self::gen_slice(&mut r);
         u32::from_be_bytes(r)
     }
+
+    fn gen_true_random() -> u32 {
+        let mut r: [u8; 4] = [0u8; 4];
+        Self::gen_true_random_slice(&mut r);
+        u32::from_be_bytes(r)
+    }
 }
 
 impl RandTypes<u64> for Rand {
@@ -50,4 +81,10 @@ impl RandTypes<u64> for Rand {
         Self::gen_slice(&mut r);
         u64::from_be_bytes(r)
     }
+
+    fn gen_true_random() -> u64 {
+        let mut r: [u8; 8] = [0u8; 8];
+        Self::gen_true_random_slice(&mut r);
+        u64::from_be_bytes(r)
+    }
 }
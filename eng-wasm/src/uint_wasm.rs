@@ -0,0 +1,71 @@
+//! A 128 bits unsigned integer for contracts that don't need the full range of `U256` (i.e. token amounts).
+//! It's decoded from an ABI `uint` argument that fits in 128 bits, a larger one fails the decoding,
+//! and it's kept in the state as a number while it's in the safe range of a double and as a decimal string above it.
+use super::*;
+use eng_pwasm_abi::eth::{AbiType, Error as AbiError, Sink, Stream};
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct U128(pub u128);
+
+impl From<u128> for U128 {
+    fn from(value: u128) -> Self { U128(value) }
+}
+
+impl From<U128> for u128 {
+    fn from(value: U128) -> Self { value.0 }
+}
+
+impl fmt::Display for U128 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { fmt::Display::fmt(&self.0, f) }
+}
+
+impl AbiType for U128 {
+    fn decode(stream: &mut Stream) -> Result<Self, AbiError> {
+        let mut word = [0u8; 32];
+        U256::decode(stream)?.to_big_endian(&mut word);
+        if word[..16].iter().any(|b| *b != 0) {
+            return Err(AbiError::Other);
+        }
+        let mut low = [0u8; 16];
+        low.copy_from_slice(&word[16..]);
+        Ok(U128(u128::from_be_bytes(low)))
+    }
+
+    fn encode(self, sink: &mut Sink) {
+        let mut word = [0u8; 32];
+        word[16..].copy_from_slice(&self.0.to_be_bytes());
+        U256::from_big_endian(&word).encode(sink)
+    }
+
+    const IS_FIXED: bool = true;
+}
+
+impl Serialize for U128 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0 <= u128::from(MAX_SAFE_INTEGER) {
+            serializer.serialize_u64(self.0 as u64)
+        } else {
+            serializer.serialize_str(&self.0.to_string())
+        }
+    }
+}
+
+struct U128Visitor;
+
+impl<'de> Visitor<'de> for U128Visitor {
+    type Value = U128;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str("an unsigned 128 bits integer or its decimal string") }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<U128, E> { Ok(U128(u128::from(value))) }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<U128, E> {
+        value.parse::<u128>().map(U128).map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for U128 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> { deserializer.deserialize_any(U128Visitor) }
+}
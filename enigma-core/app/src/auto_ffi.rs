@@ -27,7 +27,10 @@ extern "C" {
         args_len: usize,
         address: *const ContractAddress,
         user_key: *mut [u8; 64usize],
+        deployer: *mut [u8; 20usize],
         gas_limit: *const u64,
+        gas_multiplier: *const GasMultiplier,
+        capabilities: *const HostCapabilities,
         db_ptr: *const RawPointer,
         result: *mut ExecuteResult,
     ) -> sgx_status_t;
@@ -44,31 +47,170 @@ extern "C" {
         callable_args_len: usize,
         pubkey: *mut [u8; 64usize],
         address: *const ContractAddress,
+        task_id: *mut [u8; 32usize],
         gas_limit: *const u64,
+        gas_multiplier: *const GasMultiplier,
+        capabilities: *const HostCapabilities,
+        settings_mac: *mut [u8; 32usize],
         db_ptr: *const RawPointer,
         result: *mut ExecuteResult,
     ) -> sgx_status_t;
 }
+extern "C" {
+    pub fn ecall_replay_compute(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        bytecode: *const u8,
+        bytecode_len: usize,
+        callable: *const u8,
+        callable_len: usize,
+        callable_args: *const u8,
+        callable_args_len: usize,
+        pubkey: *mut [u8; 64usize],
+        replay_key: *mut [u8; 64usize],
+        task_key: *const u8,
+        task_key_len: usize,
+        address: *const ContractAddress,
+        task_id: *mut [u8; 32usize],
+        gas_limit: *const u64,
+        gas_multiplier: *const GasMultiplier,
+        capabilities: *const HostCapabilities,
+        settings_mac: *mut [u8; 32usize],
+        db_ptr: *const RawPointer,
+        claimed_delta: *const u8,
+        claimed_delta_len: usize,
+        claimed_index: u32,
+        claimed_output: *const u8,
+        claimed_output_len: usize,
+        claimed_gas: u64,
+        matches: *mut u8,
+        sig: *mut [u8; 65usize],
+        stats: *mut ReplayStats,
+    ) -> sgx_status_t;
+}
 extern "C" {
     pub fn ecall_get_signing_address(eid: sgx_enclave_id_t, arr: *mut [u8; 20usize]) -> sgx_status_t;
 }
+extern "C" {
+    pub fn ecall_get_derived_signing_address(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        context: u8,
+        arr: *mut [u8; 20usize],
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_set_native_stack_budget(eid: sgx_enclave_id_t, budget: usize) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_set_max_json_depth(eid: sgx_enclave_id_t, depth: usize) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_set_max_state_size(eid: sgx_enclave_id_t, size: usize) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_set_error_details(eid: sgx_enclave_id_t, enabled: u8) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_set_state_keys_lock_attempts(eid: sgx_enclave_id_t, attempts: u32) -> sgx_status_t;
+}
 extern "C" {
     pub fn ecall_ptt_req(
         eid: sgx_enclave_id_t,
         retval: *mut EnclaveReturn,
         sig: *mut [u8; 65usize],
-        serialized_ptr: *mut u64,
+        output: *mut u8,
+        output_cap: usize,
+        output_len: *mut u64,
+        output_handle: *mut u64,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_fetch_output(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        handle: u64,
+        output: *mut u8,
+        output_len: usize,
     ) -> sgx_status_t;
 }
 extern "C" {
     pub fn ecall_ptt_res(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, msg_ptr: *const u8, msg_len: usize) -> sgx_status_t;
 }
+extern "C" {
+    pub fn ecall_verify_delta(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        address: *const ContractAddress,
+        key: u32,
+        data: *const u8,
+        data_len: usize,
+        sig: *mut [u8; 65usize],
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_decode_delta(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        address: *const ContractAddress,
+        key: u32,
+        data: *const u8,
+        data_len: usize,
+        output: *mut u8,
+        output_cap: usize,
+        output_len: *mut u64,
+        output_handle: *mut u64,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_get_state_at(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        db_ptr: *const RawPointer,
+        address: *const ContractAddress,
+        index: u32,
+        output: *mut u8,
+        output_cap: usize,
+        output_len: *mut u64,
+        output_handle: *mut u64,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_validate_contract(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        bytecode: *const u8,
+        bytecode_len: usize,
+        gas_multiplier: *const GasMultiplier,
+        capabilities: *const HostCapabilities,
+        output: *mut u8,
+        output_cap: usize,
+        output_len: *mut u64,
+        output_handle: *mut u64,
+    ) -> sgx_status_t;
+}
+extern "C" {
+    pub fn ecall_state_fingerprint(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        db_ptr: *const RawPointer,
+        address: *const ContractAddress,
+        delta_hash: *mut [u8; 32usize],
+        delta_index: *mut u32,
+        fingerprint: *mut [u8; 32usize],
+    ) -> sgx_status_t;
+}
 extern "C" {
     pub fn ecall_build_state(
         eid: sgx_enclave_id_t,
         retval: *mut EnclaveReturn,
         db_ptr: *const RawPointer,
-        failed_ptr: *mut u64,
+        max_deltas: u32,
+        cursor: *mut u32,
+        failed: *mut u8,
+        failed_cap: usize,
+        failed_len: *mut u64,
+        failed_handle: *mut u64,
     ) -> sgx_status_t;
 }
 extern "C" {
@@ -77,6 +219,10 @@ extern "C" {
         retval: *mut EnclaveReturn,
         sig: *mut [u8; 65usize],
         pubkey: *mut [u8; 64usize],
-        serialized_ptr: *mut u64,
+        sig_version: u32,
+        output: *mut u8,
+        output_cap: usize,
+        output_len: *mut u64,
+        output_handle: *mut u64,
     ) -> sgx_status_t;
 }
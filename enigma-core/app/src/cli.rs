@@ -7,6 +7,9 @@
 
 use std::path::PathBuf;
 use structopt::StructOpt;
+use enigma_tools_u::attestation_service::service::{AttestationConfig, OutOfDatePolicy};
+use networking::messages::GasLimits;
+use networking::sequence::ClientKey;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "Enigma Core", about = "Enigma Core CLI commands.")]
@@ -23,7 +26,92 @@ pub struct Opt {
     /// Specify the number of Attestation call retries when failing
     #[structopt(long = "retries", short = "r", default_value = "10")]
     pub retries: u32,
+    /// Specify a different URL for the Attestation Service
+    #[structopt(long = "attestation-url", default_value = "https://sgx.enigma.co/api")]
+    pub attestation_url: String,
+    /// Optional: a subscription key to send to the Attestation Service
+    #[structopt(long = "subscription-key")]
+    pub subscription_key: Option<String>,
+    /// What to do with a quote the Attestation Service reports as GROUP_OUT_OF_DATE: accept, warn (accept with a warning) or reject
+    #[structopt(long = "out-of-date-policy", default_value = "warn")]
+    pub out_of_date_policy: OutOfDatePolicy,
     /// Optional: change the minimum log level
     #[structopt(short = "l", long = "log-level", default_value = "info")]
     pub log_level: String,
-}
\ No newline at end of file
+    /// Number of threads handling IPC requests, when it's more than 1 read only requests run concurrently and so do writes to different contracts
+    #[structopt(long = "ipc-threads", default_value = "1")]
+    pub ipc_threads: usize,
+    /// Accept deltas from peers that aren't signed by the worker that generated them
+    #[structopt(long = "trust-unsigned-deltas")]
+    pub trust_unsigned: bool,
+    /// Return a snapshot of the contract state with every ComputeTask that adds this many deltas since the last one (0 to disable)
+    #[structopt(long = "snapshot-interval", default_value = "0")]
+    pub snapshot_interval: u32,
+    /// The gas limit of the tasks that don't set one, it has to be within the accepted range like any other gas limit
+    #[structopt(long = "default-gas-limit", default_value = "100000000")]
+    pub default_gas_limit: u64,
+    /// The lowest gas limit accepted for a task, tasks with a lower one are rejected before reaching the enclave
+    #[structopt(long = "min-gas-limit", default_value = "100")]
+    pub min_gas_limit: u64,
+    /// The highest gas limit accepted for a task, tasks with a higher one are rejected before reaching the enclave
+    #[structopt(long = "max-gas-limit", default_value = "10000000000")]
+    pub max_gas_limit: u64,
+    /// The most DB iterators (scans like GetDeltas) that can be open at the same time, the rest wait for them to finish
+    #[structopt(long = "max-db-iterators", default_value = "16", parse(try_from_str = "parse_max_db_iterators"))]
+    pub max_db_iterators: usize,
+    /// Compact the DB once its files take more than this many bytes, and every time they grow by as much again (0 to disable)
+    #[structopt(long = "compact-at-size", default_value = "0")]
+    pub compact_at_size: u64,
+    /// Compact the DB once this many deltas were written since the last compaction (0 to disable)
+    #[structopt(long = "compact-every-deltas", default_value = "0")]
+    pub compact_every_deltas: u64,
+    /// Bytes of the enclave thread's stack a contract execution may use, deeper executions fail (the enclave threads have 256KiB)
+    #[structopt(long = "native-stack-budget", default_value = "131072")]
+    pub native_stack_budget: usize,
+    /// The deepest nesting of arrays and objects a value in a contract's state may have, deeper writes fail the task.
+    /// The state object the values are in adds one more, the default keeps it within the 128 levels serde_json parses
+    #[structopt(long = "max-json-depth", default_value = "127")]
+    pub max_json_depth: usize,
+    /// The largest a contract's state may grow when serialized, in bytes, bigger states fail the task (0 for no limit)
+    #[structopt(long = "max-state-size", default_value = "0")]
+    pub max_state_size: usize,
+    /// Show the details of enclave errors that may hold secret material (decrypted inputs or state) instead of redacting them, for debugging only
+    #[structopt(long = "debug-error-details")]
+    pub debug_error_details: bool,
+    /// How many times reading the contracts' state keys in the enclave tries to take their lock before failing the task,
+    /// the enclave has no trusted clock so the limit is a number of attempts and not a duration
+    #[structopt(long = "state-keys-lock-attempts", default_value = "1000000")]
+    pub state_keys_lock_attempts: u32,
+    /// A client whose requests are numbered and signed, as <name>:<hex public key>, can be repeated.
+    /// Once one is given, requests that change the DB are only accepted from these clients (see `networking::sequence`)
+    #[structopt(long = "client-key")]
+    pub client_keys: Vec<ClientKey>,
+    /// Drop the messages logged by the enclave instead of passing them to the logger (they're logged with the "enclave" target)
+    #[structopt(long = "silence-enclave-logs")]
+    pub silence_enclave_logs: bool,
+}
+
+impl Opt {
+    pub fn attestation_config(&self) -> AttestationConfig {
+        AttestationConfig {
+            spid: self.spid.clone(),
+            subscription_key: self.subscription_key.clone(),
+            url: self.attestation_url.clone(),
+            retries: self.retries,
+            out_of_date_policy: self.out_of_date_policy,
+        }
+    }
+
+    /// Fails on a default gas limit outside of the accepted range, so it's rejected on startup instead of failing every task that uses it.
+    pub fn gas_limits(&self) -> Result<GasLimits, String> {
+        GasLimits::new(self.min_gas_limit, self.max_gas_limit, self.default_gas_limit)
+    }
+}
+
+/// At least one iterator has to be allowed, otherwise every scan would wait until it times out.
+fn parse_max_db_iterators(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>().map_err(|e| e.to_string())? {
+        0 => Err("At least one DB iterator has to be allowed".to_string()),
+        max => Ok(max),
+    }
+}
@@ -39,6 +39,21 @@ pub struct P2PErr {
     pub msg: String,
 }
 
+// a field of a task that isn't well formed, found before the task is processed
+#[derive(Fail, Debug)]
+#[fail(display = "Invalid {} in the task: {}", field, msg)]
+pub struct InvalidTaskField {
+    pub field: &'static str,
+    pub msg: String,
+}
+
+// a request that can't be handled as it is (e.g. of another protocol version or replayed), regardless of its contents
+#[derive(Fail, Debug)]
+#[fail(display = "{}", msg)]
+pub struct InvalidRequest {
+    pub msg: String,
+}
+
 #[derive(Fail, Debug)]
 #[fail(display = "Error while trying to {}, Because: {}", command, kind)]
 pub struct DBErr {
@@ -62,6 +77,8 @@ pub enum DBErrKind {
     MissingKey(String),
     UpdateError,
     MissingKeys,
+    OpenError(String),
+    TooManyIterators(usize),
 }
 
 impl fmt::Display for DBErrKind {
@@ -73,6 +90,8 @@ impl fmt::Display for DBErrKind {
             DBErrKind::MissingKey(k) => format!("The following Key doesn't exist: {}", &k),
             DBErrKind::UpdateError => "Failed to update the key".into(),
             DBErrKind::MissingKeys => "No keys exist the DB".into(),
+            DBErrKind::OpenError(e) => format!("Failed to open the DB: {}", &e),
+            DBErrKind::TooManyIterators(max) => format!("Timed out waiting for one of the {} open iterators to close", max),
         };
         write!(f, "{}", printable)
     }
@@ -0,0 +1,99 @@
+//! Keeps a panic while handling a task recoverable: the DB is flushed before the panic continues,
+//! and the id of the last task is logged so recovery tooling knows where the node stopped.
+use crate::db::DB;
+use enigma_tools_m::utils::LockExpectMutex;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, Once};
+
+lazy_static! {
+    static ref LAST_TASK_ID: Mutex<Option<String>> = Mutex::new(None);
+    static ref LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
+}
+
+static INSTALL: Once = Once::new();
+
+/// Records the task that's being handled, it's reported if the node panics before the next one.
+pub fn set_last_task_id(task_id: String) { *LAST_TASK_ID.lock_expect("LastTaskId") = Some(task_id); }
+
+/// The last task that started being handled.
+pub fn last_task_id() -> Option<String> { LAST_TASK_ID.lock_expect("LastTaskId").clone() }
+
+/// The report of the last panic the hook caught.
+pub fn last_panic() -> Option<String> { LAST_PANIC.lock_expect("LastPanic").clone() }
+
+/// Installs (once) a panic hook that logs the panic together with the last task id and then calls the previous hook.
+pub fn install() {
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let task_id = last_task_id().unwrap_or_else(|| "none".to_string());
+            let report = format!("Panicked while handling task {}: {}", task_id, info);
+            error!("{}", report);
+            // The hook may run while the lock is held by a panicking thread, so it mustn't block on it.
+            if let Ok(mut last_panic) = LAST_PANIC.try_lock() {
+                *last_panic = Some(report);
+            }
+            previous(info);
+        }));
+    });
+}
+
+/// Runs `f` and if it panics flushes `db` before the panic continues,
+/// so whatever the task already wrote to the DB survives the crash.
+pub fn flush_on_panic<F, R>(db: &mut DB, f: F) -> R
+where F: FnOnce(&mut DB) -> R {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(db))) {
+        Ok(res) => res,
+        Err(payload) => {
+            match db.flush() {
+                Ok(()) => error!("Flushed the DB after a panic in task {:?}", last_task_id()),
+                Err(e) => error!("Failed flushing the DB after a panic in task {:?}: {}", last_task_id(), e),
+            }
+            panic::resume_unwind(payload)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+    use super::*;
+    use crate::db::{tests::create_test_db, CRUDInterface, DeltaKey, SplitKey, Stype};
+    use rocksdb::WriteOptions;
+    use std::fs;
+
+    #[test]
+    fn test_flush_on_panic() {
+        install();
+        let (mut db, dir) = create_test_db();
+        let key = DeltaKey::new([3u8; 32].into(), Stype::Delta(1));
+        // Creates the column family of the contract.
+        db.force_update(&DeltaKey::new([3u8; 32].into(), Stype::Delta(0)), &b"delta 0"[..]).unwrap();
+        set_last_task_id("flush_on_panic_task".to_string());
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            flush_on_panic(&mut db, |db| {
+                // Without the WAL the write only survives a crash if the DB is flushed.
+                let mut write_options = WriteOptions::default();
+                write_options.disable_wal(true);
+                key.as_split(|hash, index_key| {
+                    let cf = db.database.cf_handle(hash).unwrap();
+                    db.database.put_cf_opt(cf, index_key, &b"written before the panic"[..], &write_options).unwrap();
+                });
+                panic!("the task handler panicked");
+            })
+        }));
+        assert!(res.is_err());
+        let report = last_panic().unwrap();
+        assert!(report.contains("Panicked while handling task") && report.contains("the task handler panicked"), "unexpected report: {}", report);
+
+        // Closing the DB would flush it too, so the crash is simulated by copying its files while it's still open.
+        let crashed = tempfile::tempdir().unwrap();
+        for entry in fs::read_dir(dir.path()).unwrap() {
+            let entry = entry.unwrap();
+            fs::copy(entry.path(), crashed.path().join(entry.file_name())).unwrap();
+        }
+        let crashed_db = DB::new(crashed.path(), false).unwrap();
+        assert_eq!(crashed_db.read(&key).unwrap(), b"written before the panic".to_vec());
+    }
+}
@@ -0,0 +1,212 @@
+//! # Compaction.
+//! RocksDB compacts on its own schedule, so a node that stores many deltas can pile up large SST files before it does. <br>
+//! A `CompactionTrigger` makes the DB compact itself once it grew past a size or once enough deltas were written since the last compaction,
+//! it's checked after every request that writes to the DB (see `DB::compact_if_needed`). <br>
+//! That compaction runs in a background thread so it doesn't hold up the request, RocksDB keeps serving reads and writes meanwhile.
+use db::DB;
+use failure::Error;
+use rocksdb::DB as rocks_db;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// When the DB compacts without being asked to, 0 disables a limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionTrigger {
+    /// Compact once the files of the DB take more than this many bytes,
+    /// and after that every time they grow by this many bytes past the size the last compaction left.
+    pub max_size_bytes: u64,
+    /// Compact once this many deltas were written since the last compaction.
+    pub max_new_deltas: u64,
+}
+
+/// Where the DB stands relative to its `CompactionTrigger`.
+#[derive(Debug, Default)]
+pub(crate) struct CompactionState {
+    trigger: CompactionTrigger,
+    new_deltas: u64,
+    next_size_bytes: u64,
+    background: Option<BackgroundCompaction>,
+}
+
+/// A compaction that runs in its own thread, it returns the size of the DB after it.
+#[derive(Debug)]
+struct BackgroundCompaction {
+    handle: JoinHandle<Result<u64, Error>>,
+    done: Arc<AtomicBool>,
+}
+
+/// Compacts the default column family and the column families `names`, returns the size of the files at `location` after it.
+fn compact_all(database: &rocks_db, names: &[String], location: &Path) -> Result<u64, Error> {
+    database.compact_range(None::<&[u8]>, None::<&[u8]>);
+    for name in names {
+        if let Some(cf) = database.cf_handle(name) {
+            database.compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+        }
+    }
+    size_on_disk(location)
+}
+
+fn size_on_disk(location: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in fs::read_dir(location)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+impl DB {
+    fn compaction_state(&self) -> MutexGuard<CompactionState> {
+        self.compaction.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sets when the DB compacts on its own, the counting of new deltas starts over.
+    pub fn set_compaction_trigger(&mut self, trigger: CompactionTrigger) {
+        let mut compaction = self.compaction_state();
+        let background = compaction.background.take();
+        *compaction = CompactionState { trigger, new_deltas: 0, next_size_bytes: trigger.max_size_bytes, background };
+    }
+
+    pub(crate) fn count_new_deltas(&mut self, count: u64) {
+        let mut compaction = self.compaction_state();
+        compaction.new_deltas = compaction.new_deltas.saturating_add(count);
+    }
+
+    /// The size of the files of the DB in bytes.
+    pub fn size_on_disk(&self) -> Result<u64, Error> {
+        size_on_disk(&self.location)
+    }
+
+    /// Compacts the whole DB (every contract) right away, returns its size after the compaction.
+    /// A compaction that runs in the background is waited for first.
+    pub fn compact(&mut self) -> Result<u64, Error> {
+        if let Err(e) = self.wait_for_compaction() {
+            warn!("The background compaction of the DB failed: {}", e);
+        }
+        let names = rocks_db::list_cf(&self.options, &self.location)?;
+        let size = compact_all(&self.database, &names, &self.location)?;
+        let mut compaction = self.compaction_state();
+        compaction.new_deltas = 0;
+        compaction.compacted(size);
+        Ok(size)
+    }
+
+    /// Starts compacting the DB in the background if it went over one of the limits of its `CompactionTrigger`,
+    /// returns whether it started. Only one compaction runs at a time, while it runs nothing else is started.
+    pub fn compact_if_needed(&mut self) -> Result<bool, Error> {
+        let mut compaction = self.compaction_state();
+        match compaction.background.as_ref().map(|running| running.done.load(Ordering::SeqCst)) {
+            Some(false) => return Ok(false),
+            Some(true) => compaction.wait()?,
+            None => (),
+        }
+        let (trigger, new_deltas, next_size_bytes) = (compaction.trigger, compaction.new_deltas, compaction.next_size_bytes);
+        let too_many_deltas = trigger.max_new_deltas != 0 && new_deltas >= trigger.max_new_deltas;
+        let too_big = !too_many_deltas && next_size_bytes != 0 && self.size_on_disk()? > next_size_bytes;
+        if !too_many_deltas && !too_big {
+            return Ok(false);
+        }
+        let names = rocks_db::list_cf(&self.options, &self.location)?;
+        let (database, location) = (Arc::clone(&self.database), self.location.clone());
+        let done = Arc::new(AtomicBool::new(false));
+        let thread_done = Arc::clone(&done);
+        let handle = thread::Builder::new().name("db-compaction".to_string()).spawn(move || {
+            let res = compact_all(&database, &names, &location);
+            thread_done.store(true, Ordering::SeqCst);
+            res
+        })?;
+        // The deltas written from now on count towards the next compaction.
+        compaction.new_deltas = 0;
+        compaction.background = Some(BackgroundCompaction { handle, done });
+        debug!("Started compacting the DB after {} new deltas", new_deltas);
+        Ok(true)
+    }
+
+    /// Waits for the compaction that runs in the background (if any) to finish, returns its result.
+    pub fn wait_for_compaction(&mut self) -> Result<(), Error> {
+        self.compaction_state().wait()
+    }
+}
+
+impl CompactionState {
+    /// Waits for the compaction that runs in the background (if any) to finish, returns its result.
+    fn wait(&mut self) -> Result<(), Error> {
+        let running = match self.background.take() {
+            Some(running) => running,
+            None => return Ok(()),
+        };
+        let size = running.handle.join().map_err(|_| format_err!("The compaction thread panicked"))??;
+        debug!("Compacted the DB, its size is now {} bytes", size);
+        self.compacted(size);
+        Ok(())
+    }
+
+    /// Sets the size the next compaction is triggered at, `size` is the size the last compaction left.
+    fn compacted(&mut self, size: u64) {
+        let max_size = self.trigger.max_size_bytes;
+        self.next_size_bytes = if max_size == 0 { 0 } else { max_size.max(size.saturating_add(max_size)) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate cross_test_utils;
+    use self::cross_test_utils::generate_contract_address;
+    use super::CompactionTrigger;
+    use db::{CRUDInterface, DeltaKey, P2PCalls, Stype, tests::create_test_db};
+
+    #[test]
+    fn test_compaction_keeps_the_data() {
+        let (mut db, _dir) = create_test_db();
+        db.set_compaction_trigger(CompactionTrigger { max_size_bytes: 0, max_new_deltas: 1000 });
+        let addresses = [generate_contract_address(), generate_contract_address()];
+        for i in 0..999u32 {
+            let address = addresses[i as usize % 2];
+            db.create(&DeltaKey::new(address, Stype::Delta(i / 2)), &[i as u8; 512][..]).unwrap();
+        }
+        assert!(!db.compact_if_needed().unwrap());
+
+        db.create(&DeltaKey::new(addresses[0], Stype::State), &b"state"[..]).unwrap();
+        db.create(&DeltaKey::new(addresses[0], Stype::Delta(500)), &[7u8; 512][..]).unwrap();
+        assert!(db.compact_if_needed().unwrap());
+        // The count starts over once a compaction starts, and nothing else starts while it runs.
+        db.create(&DeltaKey::new(addresses[1], Stype::Delta(500)), &[7u8; 512][..]).unwrap();
+        assert!(!db.compact_if_needed().unwrap());
+        db.wait_for_compaction().unwrap();
+        assert!(!db.compact_if_needed().unwrap());
+
+        for i in 0..999u32 {
+            let address = addresses[i as usize % 2];
+            assert_eq!(db.get_delta(DeltaKey::new(address, Stype::Delta(i / 2))).unwrap(), vec![i as u8; 512]);
+        }
+        assert_eq!(db.read(&DeltaKey::new(addresses[0], Stype::State)).unwrap(), b"state".to_vec());
+        let (tip, _) = db.get_tip::<DeltaKey>(&addresses[0]).unwrap();
+        assert_eq!(tip.key_type, Stype::Delta(500));
+    }
+
+    #[test]
+    fn test_compaction_by_size() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        for i in 0..100u32 {
+            db.create(&DeltaKey::new(address, Stype::Delta(i)), &[1u8; 1024][..]).unwrap();
+        }
+        db.flush().unwrap();
+        let size = db.size_on_disk().unwrap();
+        db.set_compaction_trigger(CompactionTrigger { max_size_bytes: size * 2, max_new_deltas: 0 });
+        assert!(!db.compact_if_needed().unwrap());
+        db.set_compaction_trigger(CompactionTrigger { max_size_bytes: size / 2, max_new_deltas: 0 });
+        assert!(db.compact_if_needed().unwrap());
+        // The DB can be used while it compacts.
+        assert_eq!(db.get_delta(DeltaKey::new(address, Stype::Delta(7))).unwrap(), vec![1u8; 1024]);
+        db.create(&DeltaKey::new(address, Stype::Delta(100)), &[2u8; 1024][..]).unwrap();
+        db.wait_for_compaction().unwrap();
+        assert_eq!(db.get_delta(DeltaKey::new(address, Stype::Delta(100))).unwrap(), vec![2u8; 1024]);
+        assert_eq!(db.get_delta(DeltaKey::new(address, Stype::Delta(42))).unwrap(), vec![1u8; 1024]);
+    }
+}
@@ -2,22 +2,36 @@ use failure::Error;
 use rocksdb::DB as rocks_db;
 use rocksdb::{Options, SliceTransform, WriteOptions, ColumnFamilyDescriptor};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use common_u::errors::{DBErr, DBErrKind};
+use db::compaction::CompactionState;
+use db::iterator::DELTA_PREFIX;
+use db::iterator_pool::{IteratorPool, DEFAULT_MAX_OPEN_ITERATORS};
 use db::primitives::SplitKey;
+use db::retention::BackgroundPruning;
 
 // These are global variables for Reade/Write/Create Options
 const SYNC: bool = true;
 const PREFIX_SIZE: usize = 1;
 
+// Everything but the location is shared by the handles of the DB (see `DB::handle`).
 pub struct DB {
     pub location: PathBuf,
-    pub database: rocks_db,
+    // shared with the compaction that runs in the background (see `compaction`)
+    pub database: Arc<rocks_db>,
     // the DB needs to store the options for creating new
     // cf's that would be able to imitate the DB behaviour
-    pub options: Options,
+    pub options: Arc<Options>,
     // keeps track if the state needs to be rebuilt
-    state_updated: bool,
+    state_updated: Arc<AtomicBool>,
+    // bounds the amount of scans that run at the same time
+    pub(crate) iterators: Arc<IteratorPool>,
+    // when the DB compacts on its own
+    pub(crate) compaction: Arc<Mutex<CompactionState>>,
+    // the deltas that are pruned in the background
+    pub(crate) pruning: Arc<Mutex<Option<BackgroundPruning>>>,
 }
 
 impl DB {
@@ -37,13 +51,35 @@ impl DB {
     /// # let path = tempdir.path();
     /// let mut db = DB::new(path, true).unwrap();
     /// ```
+    ///
+    /// If the DB files are corrupted it will try to repair them using RocksDB's repair,
+    /// and if that fails too it will return a `DBErr` with `DBErrKind::OpenError`.
     pub fn new<P: AsRef<Path>>(location: P, create_if_missing: bool) -> Result<DB, Error> {
+        match Self::open(&location, create_if_missing) {
+            Ok(db) => Ok(db),
+            Err(e) => {
+                warn!("Failed opening the DB: {}, trying to repair it", e);
+                rocks_db::repair(Self::db_options(create_if_missing), &location)
+                    .map_err(|e| DBErr { command: "repair".to_string(), kind: DBErrKind::OpenError(e.to_string()) })?;
+                info!("Repaired the DB successfully");
+                Self::open(&location, create_if_missing)
+                    .map_err(|e| DBErr { command: "open".to_string(), kind: DBErrKind::OpenError(e.to_string()) }.into())
+            }
+        }
+    }
+
+    fn db_options(create_if_missing: bool) -> Options {
         // number of bytes to take into consideration when looking for a similar prefix
         // would be helpful when querying the DB using iterators.
         let prefix_extractor = SliceTransform::create_fixed_prefix(PREFIX_SIZE);
         let mut options = Options::default();
         options.create_if_missing(create_if_missing);
         options.set_prefix_extractor(prefix_extractor);
+        options
+    }
+
+    fn open<P: AsRef<Path>>(location: P, create_if_missing: bool) -> Result<DB, rocksdb::Error> {
+        let options = Self::db_options(create_if_missing);
         // cf_list gets a list of all column families (addresses) from the location where the DB
         // is stored and when opening it, it adds the list as an argument to the DB. this is done
         // in case the DB existed, otherwise, an empty list will be added and the call will
@@ -59,24 +95,56 @@ impl DB {
             cf_opts.set_prefix_extractor(prefix_extractor);
             ColumnFamilyDescriptor::new(name, cf_opts)
         });
-        let database = rocks_db::open_cf_descriptors(&options, &location, cf_descriptors)?;
+        let database = Arc::new(rocks_db::open_cf_descriptors(&options, &location, cf_descriptors)?);
         let location = location.as_ref().to_path_buf();
         // the state_updated is initialized to true since it won't be necessary to build
         // the state when the DB is empty.
-        let db_par = DB { location, database, options, state_updated: true};
+        let db_par = DB { location, database, options: Arc::new(options), state_updated: Arc::new(AtomicBool::new(true)),
+                           iterators: Arc::new(IteratorPool::new(DEFAULT_MAX_OPEN_ITERATORS)),
+                           compaction: Arc::default(), pruning: Arc::default() };
         Ok(db_par)
     }
 
+    /// Another handle to the same DB, so requests on different contracts can each write through their own at the same time.
+    /// The handles share the status of the state, the iterators, the compaction and the pruning.
+    pub fn handle(&self) -> DB {
+        DB {
+            location: self.location.clone(),
+            database: Arc::clone(&self.database),
+            options: Arc::clone(&self.options),
+            state_updated: Arc::clone(&self.state_updated),
+            iterators: Arc::clone(&self.iterators),
+            compaction: Arc::clone(&self.compaction),
+            pruning: Arc::clone(&self.pruning),
+        }
+    }
+
     /// updates the state_updated field according to the status of the state.
     /// every time the state is built (=true) and
     /// on the other hand when new deltas enter the DB (=false).
     pub fn update_state_status(&mut self, state: bool) {
-        self.state_updated = state;
+        self.state_updated.store(state, Ordering::SeqCst);
     }
 
     /// get the current status of the state
-    pub fn get_state_status(& mut self) -> bool {
-        self.state_updated
+    pub fn get_state_status(&self) -> bool {
+        self.state_updated.load(Ordering::SeqCst)
+    }
+
+    /// Sets the amount of iterators that can be open at the same time, a scan over the limit waits for another one to finish.
+    /// It's set for the handles that are taken afterwards.
+    pub fn set_max_open_iterators(&mut self, max: usize) {
+        self.iterators = Arc::new(IteratorPool::new(max));
+    }
+
+    /// The most iterators that were open at the same time so far.
+    pub fn peak_open_iterators(&self) -> usize {
+        self.iterators.peak()
+    }
+
+    /// Flushes the memtables to the disk.
+    pub fn flush(&self) -> Result<(), Error> {
+        Ok(self.database.flush()?)
     }
 }
 
@@ -197,6 +265,7 @@ impl<'a, K: SplitKey> CRUDInterface<Error, &'a K, Vec<u8>, &'a [u8]> for DB {
 
     #[logfn(TRACE)]
     fn create(&mut self, key: &'a K, value: &'a [u8]) -> Result<(), Error> {
+        let is_delta = key.as_split(|_, index_key| index_key.starts_with(DELTA_PREFIX));
         key.as_split(|hash, index_key| {
             trace!("DB: Create: contract_address: {}, key: {:?}, value: {:?}", hash, index_key, value);
             // creates the ColumnFamily and verifies that it doesn't already exist
@@ -215,7 +284,11 @@ impl<'a, K: SplitKey> CRUDInterface<Error, &'a K, Vec<u8>, &'a [u8]> for DB {
                     Ok(())
                 }
             }
-        })
+        })?;
+        if is_delta {
+            self.count_new_deltas(1);
+        }
+        Ok(())
     }
 
     #[logfn(TRACE)]
@@ -261,6 +334,13 @@ impl<'a, K: SplitKey> CRUDInterface<Error, &'a K, Vec<u8>, &'a [u8]> for DB {
 
     #[logfn(TRACE)]
     fn delete_contract(&mut self, key: &'a K) -> Result<(), Error> {
+        // The column family can't be dropped while a background job uses it.
+        if let Err(e) = self.wait_for_compaction() {
+            warn!("The background compaction of the DB failed: {}", e);
+        }
+        if let Err(e) = self.wait_for_pruning() {
+            warn!("The background pruning of the deltas failed: {}", e);
+        }
         key.as_split(|hash, _| {
             trace!("DB: Delete Contract: contract_address: {}", hash);
             self.database.drop_cf(&hash).
@@ -288,15 +368,56 @@ impl<'a, K: SplitKey> CRUDInterface<Error, &'a K, Vec<u8>, &'a [u8]> for DB {
 #[cfg(test)]
 mod test {
 
-    use crate::db::{tests::create_test_db, dal::CRUDInterface, primitives::{Array32u8, DeltaKey, Stype}};
+    extern crate tempfile;
+    use crate::db::{tests::create_test_db, dal::{CRUDInterface, DB}, primitives::{Array32u8, DeltaKey, Stype}};
+    use crate::common_u::errors::{DBErr, DBErrKind};
     use hex::ToHex;
     use enigma_types::ContractAddress;
+    use std::fs;
 
     #[test]
     fn test_new_db() {
         let (_db, _dir) = create_test_db();
     }
 
+    #[test]
+    fn test_open_corrupted_db() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let arr = [7u8; 32];
+        {
+            let mut db = DB::new(tempdir.path(), true).unwrap();
+            db.create(&Array32u8(arr), b"Enigma").unwrap();
+        }
+        // Corrupt all the manifest files.
+        let mut corrupted = 0;
+        for entry in fs::read_dir(tempdir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap().to_str().unwrap().starts_with("MANIFEST") {
+                fs::write(path, b"this isn't a manifest").unwrap();
+                corrupted += 1;
+            }
+        }
+        assert!(corrupted > 0);
+        // The DB is repaired on open and can be used again.
+        let mut db = DB::new(tempdir.path(), true).unwrap();
+        let other = Array32u8([8u8; 32]);
+        db.create(&other, b"Enigma").unwrap();
+        assert_eq!(db.read(&other).unwrap(), b"Enigma".to_vec());
+    }
+
+    #[test]
+    fn test_open_unrepairable_db() {
+        let tempdir = tempfile::tempdir().unwrap();
+        // A regular file can't be opened nor repaired as a DB.
+        let path = tempdir.path().join("not_a_db");
+        fs::write(&path, b"Enigma").unwrap();
+        let err = DB::new(&path, true).err().unwrap().downcast::<DBErr>().unwrap();
+        match &err.kind {
+            DBErrKind::OpenError(_) => (),
+            _ => panic!("Unexpected error kind: {}", err),
+        }
+    }
+
     #[test]
     fn test_create_read() {
         let (mut db, _dir) = create_test_db();
@@ -307,6 +428,20 @@ mod test {
         assert_eq!(db.read(&Array32u8(arr)).unwrap(), v);
     }
 
+    #[test]
+    fn test_handle_shares_the_db() {
+        let (mut db, _dir) = create_test_db();
+        let mut handle = db.handle();
+
+        let arr = [7u8; 32];
+        handle.create(&Array32u8(arr), &b"Enigma"[..]).unwrap();
+        assert_eq!(db.read(&Array32u8(arr)).unwrap(), b"Enigma");
+        handle.update_state_status(false);
+        assert!(!db.get_state_status());
+        db.update_state_status(true);
+        assert!(handle.get_state_status());
+    }
+
     #[test]
     fn test_create_update_read() {
         let (mut db, _dir) = create_test_db();
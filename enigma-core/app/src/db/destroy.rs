@@ -0,0 +1,117 @@
+//! # Destroyed Contracts.
+//! Destroying a contract deletes everything the DB keeps for it (bytecode, state, deltas, metadata and settings)
+//! and leaves a tombstone in its place, so later tasks on it are told it was destroyed instead of never deployed. <br>
+//! The state is the only snapshot the DB keeps of a contract, so there's nothing else to delete.
+use common_u::errors::{self, DBErr, DBErrKind};
+use db::{CRUDInterface, DeltaKey, SplitKey, Stype, DB};
+use enigma_types::ContractAddress;
+use failure::Error;
+use hex::ToHex;
+use rocksdb::{IteratorMode, WriteBatch};
+
+impl DB {
+    /// Deletes all the keys of `address` and writes its tombstone, in a single batch so a failure leaves the contract intact.
+    /// Returns how many keys were deleted.
+    pub fn destroy_contract(&mut self, address: ContractAddress) -> Result<usize, Error> {
+        self.delete_contract(address, "destroy_contract", true)
+    }
+
+    /// Deletes all the keys a deployment of `address` just created, so it's as if it was never deployed.
+    /// Unlike `destroy_contract` it doesn't write a tombstone, one that was already there is kept. Returns how many keys were deleted.
+    pub fn undo_deployment(&mut self, address: ContractAddress) -> Result<usize, Error> {
+        self.delete_contract(address, "undo_deployment", false)
+    }
+
+    fn delete_contract(&mut self, address: ContractAddress, command: &str, tombstone: bool) -> Result<usize, Error> {
+        let str_addr = address.to_hex();
+        let cf = self.database.cf_handle(&str_addr)
+            .ok_or_else(|| DBErr { command: command.to_string(), kind: DBErrKind::MissingKey(str_addr.clone()) })?;
+        let tombstone_key = DeltaKey::new(address, Stype::Tombstone).as_split(|_, index_key| index_key.to_vec());
+        let mut batch = WriteBatch::default();
+        let mut deleted = 0;
+        {
+            let _permit = self.iterators.acquire()?;
+            for (key, _) in self.database.iterator_cf(cf, IteratorMode::Start)? {
+                if !tombstone && key[..] == tombstone_key[..] {
+                    continue;
+                }
+                batch.delete_cf(cf, &key)?;
+                deleted += 1;
+            }
+        }
+        if tombstone {
+            batch.put_cf(cf, &tombstone_key, b"")?;
+        }
+        self.database.write(batch)?;
+        debug!("{}: {}, deleted {} keys", command, str_addr, deleted);
+        Ok(deleted)
+    }
+
+    /// Whether `address` was destroyed by `destroy_contract`.
+    pub fn is_destroyed(&self, address: ContractAddress) -> Result<bool, Error> {
+        match self.read(&DeltaKey::new(address, Stype::Tombstone)) {
+            Ok(_) => Ok(true),
+            Err(e) => match errors::is_db_err_type(e)?.kind {
+                DBErrKind::MissingKey(_) => Ok(false),
+                kind => bail!("Failed reading the tombstone: {:?}", kind),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate cross_test_utils;
+    use self::cross_test_utils::generate_contract_address;
+    use db::{CRUDInterface, DeltaKey, P2PCalls, RetentionPolicy, Stype, tests::create_test_db};
+
+    #[test]
+    fn test_destroy_contract() {
+        let (mut db, _dir) = create_test_db();
+        let (address, other) = (generate_contract_address(), generate_contract_address());
+        for i in 0..10u32 {
+            db.create(&DeltaKey::new(address, Stype::Delta(i)), &i.to_be_bytes()[..]).unwrap();
+        }
+        db.create(&DeltaKey::new(address, Stype::ByteCode), &b"bytecode"[..]).unwrap();
+        db.create(&DeltaKey::new(address, Stype::State), &b"state at 9"[..]).unwrap();
+        db.set_retention_policy(address, RetentionPolicy::KeepLast(5)).unwrap();
+        db.create(&DeltaKey::new(other, Stype::ByteCode), &b"other bytecode"[..]).unwrap();
+        assert!(!db.is_destroyed(address).unwrap());
+
+        assert_eq!(db.destroy_contract(address).unwrap(), 13);
+        assert!(db.is_destroyed(address).unwrap());
+        for key_type in &[Stype::ByteCode, Stype::State, Stype::Retention, Stype::Delta(0), Stype::Delta(9)] {
+            assert!(db.read(&DeltaKey::new(address, *key_type)).is_err());
+        }
+        assert!(db.get_tip::<DeltaKey>(&address).is_err());
+        // Other contracts aren't touched.
+        assert!(!db.is_destroyed(other).unwrap());
+        assert_eq!(db.get_contract(other).unwrap(), b"other bytecode".to_vec());
+        // Nothing is left to delete but the tombstone stays.
+        assert_eq!(db.destroy_contract(address).unwrap(), 1);
+        assert!(db.is_destroyed(address).unwrap());
+        assert!(db.destroy_contract(generate_contract_address()).is_err());
+    }
+
+    #[test]
+    fn test_undo_deployment() {
+        let (mut db, _dir) = create_test_db();
+        let (address, destroyed) = (generate_contract_address(), generate_contract_address());
+        db.create(&DeltaKey::new(address, Stype::ByteCode), &b"bytecode"[..]).unwrap();
+        db.create(&DeltaKey::new(address, Stype::Delta(0)), &b"delta 0"[..]).unwrap();
+        db.create(&DeltaKey::new(address, Stype::State), &b"state at 0"[..]).unwrap();
+
+        assert_eq!(db.undo_deployment(address).unwrap(), 3);
+        assert!(db.get_contract(address).is_err());
+        assert!(db.get_tip::<DeltaKey>(&address).is_err());
+        assert!(!db.is_destroyed(address).unwrap());
+
+        // A tombstone that was there before the deployment stays.
+        db.create(&DeltaKey::new(destroyed, Stype::ByteCode), &b"bytecode"[..]).unwrap();
+        db.destroy_contract(destroyed).unwrap();
+        db.create(&DeltaKey::new(destroyed, Stype::ByteCode), &b"bytecode"[..]).unwrap();
+        assert_eq!(db.undo_deployment(destroyed).unwrap(), 1);
+        assert!(db.is_destroyed(destroyed).unwrap());
+        assert!(db.undo_deployment(generate_contract_address()).is_err());
+    }
+}
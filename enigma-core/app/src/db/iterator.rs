@@ -7,7 +7,7 @@ use hex::{FromHex, ToHex};
 use rocksdb::DB as rocks_db;
 use rocksdb::{Direction, IteratorMode, ReadOptions, WriteBatch};
 
-const DELTA_PREFIX: &[u8] = &[1];
+pub(crate) const DELTA_PREFIX: &[u8] = &[1];
 
 type ResultVec<T> = Result<Vec<T>, Error>;
 pub type ResultTypeVec<T> = Result<ResultType<Vec<T>>, Error>;
@@ -253,7 +253,8 @@ pub trait P2PCalls {
     ///
     /// # Errors
     ///
-    /// The result is a Vec of Results each one corresponds to each Key-Value
+    /// The result is a Vec of Results each one corresponds to each Key-Value,
+    /// the Key-Values that didn't fail are still written (see `DB::insert_tuples_atomic`).
     /// If the whole atomic operation failed the vec will contain only the error of the operation.
     fn insert_tuples<K: SplitKey, S: AsRef<[u8]>>(&mut self, key_vals: &[(K, S)]) -> Vec<Result<(), Error>>;
 }
@@ -268,6 +269,7 @@ impl P2PCalls for DB {
         let cf_key =
             self.database.cf_handle(&str_addr).ok_or(DBErr { command: "get_tip".to_string(), kind: DBErrKind::MissingKey(str_addr.clone()) })?;
 
+        let _permit = self.iterators.acquire()?;
         let iter = self.database.prefix_iterator_cf(cf_key, DELTA_PREFIX)?;
         let last = iter.last().ok_or(DBErr { command: "get_tip".to_string(), kind: DBErrKind::MissingKey(str_addr.clone()) })?;
         let k_key = K::from_split(&str_addr, &*last.0)?;
@@ -332,7 +334,13 @@ impl P2PCalls for DB {
     #[logfn(TRACE)]
     fn get_contract(&self, contract_address: ContractAddress) -> ResultVec<u8> {
         let key = DeltaKey { contract_address, key_type: Stype::ByteCode };
-        Ok(self.read(&key).map_err(|_| DBErr { command: "get_contract".to_string(), kind: DBErrKind::MissingKey(contract_address.to_hex()) })?)
+        // Only a missing contract is reported as such, any other failure of the DB is passed on as it is.
+        self.read(&key).map_err(|e| match e.downcast::<DBErr>() {
+            Ok(DBErr { kind: DBErrKind::MissingKey(_), .. }) =>
+                DBErr { command: "get_contract".to_string(), kind: DBErrKind::MissingKey(contract_address.to_hex()) }.into(),
+            Ok(e) => e.into(),
+            Err(e) => e,
+        })
     }
 
     #[logfn(TRACE)]
@@ -362,6 +370,7 @@ impl P2PCalls for DB {
                 // (all elements up to this key, not included!!)
                 read_opts.set_iterate_upper_bound(&to_key);
                 // build an iterator which will iterate from the first key
+                let _permit = self.iterators.acquire()?;
                 let db_iter =
                     self.database.iterator_cf_opt(cf_key, &read_opts, IteratorMode::From(&from_key, Direction::Forward))?;
                 let key_val: Vec<(K, Vec<u8>)> = db_iter
@@ -393,8 +402,33 @@ impl P2PCalls for DB {
 
     #[logfn(TRACE)]
     fn insert_tuples<K: SplitKey, S: AsRef<[u8]>>(&mut self, key_vals: &[(K, S)]) -> Vec<Result<(), Error>> {
+        let (batch, res, new_deltas) = self.batch_tuples(key_vals);
+        match self.database.write(batch) {
+            Ok(_) => {
+                self.count_new_deltas(new_deltas);
+                res
+            }
+            Err(e) => vec![Err(e.into())],
+        }
+    }
+}
+
+impl DB {
+    /// Inserts a list of Key-Values that are only valid together, unlike `insert_tuples`
+    /// nothing is written if any of them can't be added to the batch.
+    /// A column family missing for one of the keys may still be created.
+    pub fn insert_tuples_atomic<K: SplitKey, S: AsRef<[u8]>>(&mut self, key_vals: &[(K, S)]) -> Result<(), Error> {
+        let (batch, res, new_deltas) = self.batch_tuples(key_vals);
+        res.into_iter().collect::<Result<Vec<()>, _>>()?;
+        self.database.write(batch)?;
+        self.count_new_deltas(new_deltas);
+        Ok(())
+    }
+
+    fn batch_tuples<K: SplitKey, S: AsRef<[u8]>>(&mut self, key_vals: &[(K, S)]) -> (WriteBatch, Vec<Result<(), Error>>, u64) {
         let mut res = Vec::with_capacity(key_vals.len());
         let mut batch = WriteBatch::default();
+        let mut new_deltas = 0;
         for (key, val) in key_vals {
             let tmp_res = key.as_split(|cf_str, key_slice| -> Result<(), Error> {
                 let cf = match self.database.cf_handle(cf_str) {
@@ -402,14 +436,14 @@ impl P2PCalls for DB {
                     None => self.database.create_cf(cf_str, &self.options)?,
                 };
                 batch.put_cf(cf, key_slice, val)?;
+                if key_slice.starts_with(DELTA_PREFIX) {
+                    new_deltas += 1;
+                }
                 Ok(())
             });
             res.push(tmp_res);
         }
-        match self.database.write(batch) {
-            Ok(_) => res,
-            Err(e) => vec![Err(e.into())],
-        }
+        (batch, res, new_deltas)
     }
 }
 
@@ -417,7 +451,8 @@ impl P2PCalls for DB {
 mod test {
     use db::{CRUDInterface, P2PCalls, tests::create_test_db};
     use enigma_types::ContractAddress;
-    use db::primitives::{DeltaKey, Stype};
+    use db::primitives::{DeltaKey, SplitKey, Stype};
+    use failure::Error;
 
     #[test]
     fn test_get_tip_multi_deltas_success() {
@@ -780,4 +815,41 @@ mod test {
         }
     }
 
+    // Either a valid key or one whose column family name rocksdb can't create.
+    #[derive(Debug)]
+    enum TestKey {
+        Good(DeltaKey),
+        Bad,
+    }
+
+    impl SplitKey for TestKey {
+        fn as_split<T, F: FnMut(&str, &[u8]) -> T>(&self, mut f: F) -> T {
+            match self {
+                TestKey::Good(key) => key.as_split(f),
+                TestKey::Bad => f("bad\0cf", &[1, 0, 0, 0, 1]),
+            }
+        }
+
+        fn from_split(hash: &str, key_type: &[u8]) -> Result<Self, Error> { DeltaKey::from_split(hash, key_type).map(TestKey::Good) }
+    }
+
+    #[test]
+    fn test_insert_tuples_atomic() {
+        let (mut db, _dir) = create_test_db();
+        let delta = DeltaKey { contract_address: [7u8; 32].into(), key_type: Stype::Delta(1) };
+        let state = DeltaKey { contract_address: [7u8; 32].into(), key_type: Stype::State };
+        db.insert_tuples_atomic(&[(delta, b"delta"), (state, b"state")]).unwrap();
+        assert_eq!(db.read(&delta).unwrap(), b"delta".to_vec());
+        assert_eq!(db.read(&state).unwrap(), b"state".to_vec());
+
+        // Nothing is written if one of the keys fails.
+        let next_delta = DeltaKey { contract_address: [7u8; 32].into(), key_type: Stype::Delta(2) };
+        let key_vals = [(TestKey::Good(next_delta), b"next"), (TestKey::Bad, b"next")];
+        assert!(db.insert_tuples_atomic(&key_vals).is_err());
+        assert!(db.read(&next_delta).is_err());
+        // While `insert_tuples` writes the keys that didn't fail.
+        let results = db.insert_tuples(&key_vals);
+        assert!(results[0].is_ok() && results[1].is_err());
+        assert_eq!(db.read(&next_delta).unwrap(), b"next".to_vec());
+    }
 }
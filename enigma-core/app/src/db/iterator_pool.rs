@@ -0,0 +1,113 @@
+use common_u::errors::{DBErr, DBErrKind};
+use failure::Error;
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// The default amount of RocksDB iterators that can be open at the same time.
+pub const DEFAULT_MAX_OPEN_ITERATORS: usize = 16;
+/// How long a scan waits for another one to finish before it gives up.
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Default)]
+struct PoolState {
+    open: usize,
+    peak: usize,
+}
+
+/// Bounds the amount of RocksDB iterators that are open at once,
+/// every open iterator pins the memtables it iterates so concurrent scans would otherwise grow the memory unbounded.
+/// A scan over the limit waits for another one to finish, and fails if it waited for too long.
+pub struct IteratorPool {
+    max: usize,
+    timeout: Duration,
+    state: Mutex<PoolState>,
+    released: Condvar,
+}
+
+/// Holds one place in the `IteratorPool` until it's dropped.
+pub struct IteratorPermit<'a> {
+    pool: &'a IteratorPool,
+}
+
+impl IteratorPool {
+    pub fn new(max: usize) -> Self { Self::with_timeout(max, ACQUIRE_TIMEOUT) }
+
+    fn with_timeout(max: usize, timeout: Duration) -> Self {
+        assert!(max > 0, "The iterator pool needs a place for at least one iterator");
+        IteratorPool { max, timeout, state: Mutex::new(PoolState::default()), released: Condvar::new() }
+    }
+
+    /// Waits until less than `max` iterators are open and takes a place for one more.
+    pub fn acquire(&self) -> Result<IteratorPermit, Error> {
+        let deadline = Instant::now() + self.timeout;
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while state.open >= self.max {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(DBErr { command: "open an iterator".to_string(), kind: DBErrKind::TooManyIterators(self.max) }.into());
+            }
+            state = self.released.wait_timeout(state, deadline - now).unwrap_or_else(PoisonError::into_inner).0;
+        }
+        state.open += 1;
+        state.peak = state.peak.max(state.open);
+        Ok(IteratorPermit { pool: self })
+    }
+
+    /// The most iterators that were open at the same time so far.
+    pub fn peak(&self) -> usize { self.state.lock().unwrap_or_else(PoisonError::into_inner).peak }
+}
+
+impl<'a> Drop for IteratorPermit<'a> {
+    fn drop(&mut self) {
+        self.pool.state.lock().unwrap_or_else(PoisonError::into_inner).open -= 1;
+        self.pool.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::{tests::create_test_db, DeltaKey, P2PCalls, Stype, CRUDInterface};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_acquire_times_out() {
+        let pool = IteratorPool::with_timeout(2, Duration::from_millis(50));
+        let _first = pool.acquire().unwrap();
+        {
+            let _second = pool.acquire().unwrap();
+            assert!(pool.acquire().is_err());
+        }
+        // Once one is released there's a place again.
+        let _third = pool.acquire().unwrap();
+        assert_eq!(pool.peak(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_scans_respect_the_cap() {
+        let (mut db, _dir) = create_test_db();
+        db.set_max_open_iterators(2);
+        let address = [9u8; 32].into();
+        for i in 0..50 {
+            db.create(&DeltaKey::new(address, Stype::Delta(i)), &[i as u8; 64][..]).unwrap();
+        }
+        let db = Arc::new(db);
+
+        let handles: Vec<_> = (0..8).map(|_| {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    let deltas = db.get_deltas(DeltaKey::new(address, Stype::Delta(0)), DeltaKey::new(address, Stype::Delta(50))).unwrap();
+                    assert_eq!(deltas.unwrap().len(), 50);
+                    db.get_tip::<DeltaKey>(&address).unwrap();
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(db.peak_open_iterators() <= 2, "peak: {}", db.peak_open_iterators());
+        assert!(db.peak_open_iterators() >= 1);
+    }
+}
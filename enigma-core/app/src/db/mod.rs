@@ -1,10 +1,20 @@
+pub mod compaction;
 pub mod dal;
+pub mod destroy;
 pub mod iterator;
+pub mod iterator_pool;
 pub mod primitives;
+pub mod retention;
+pub mod settings;
+pub mod shard;
 
+pub use crate::db::compaction::CompactionTrigger;
 pub use crate::db::dal::*;
 pub use crate::db::iterator::*;
 pub use crate::db::primitives::*;
+pub use crate::db::retention::RetentionPolicy;
+pub use crate::db::settings::ContractSettings;
+pub use crate::db::shard::shard_for;
 
 
 #[cfg(test)]
@@ -25,6 +25,12 @@ pub enum Stype {
     Delta(u32),
     State,
     ByteCode,
+    Metadata,
+    Retention,
+    /// The settings the contract was deployed with, see `db::settings`.
+    Settings,
+    /// Left in place of a destroyed contract, see `db::destroy`.
+    Tombstone,
 }
 
 impl Stype {
@@ -72,6 +78,10 @@ impl SplitKey for DeltaKey {
             }
             Stype::State => key.push(2),    //type
             Stype::ByteCode => key.push(3), //type
+            Stype::Metadata => key.push(4), //type
+            Stype::Retention => key.push(5), //type
+            Stype::Settings => key.push(6), //type
+            Stype::Tombstone => key.push(7), //type
         }
         f(&cf, &key)
     }
@@ -85,6 +95,10 @@ impl SplitKey for DeltaKey {
             },
             2 => Stype::State,
             3 => Stype::ByteCode,
+            4 => Stype::Metadata,
+            5 => Stype::Retention,
+            6 => Stype::Settings,
+            7 => Stype::Tombstone,
             _ => bail!("Failed parsing the Key, key does not contain a correct index"),
         };
         // if the address is not a correct hex then it not a correct address.
@@ -0,0 +1,217 @@
+//! # Delta Retention.
+//! Every contract can have a policy for how many of its deltas the DB keeps. <br>
+//! The state of a contract is reconstructed from the stored state and the deltas after the one it was built up to,
+//! so the deltas up to that one (the snapshot boundary) aren't needed anymore and can be pruned.
+//! The tip is always kept, so the contract keeps reporting it. <br>
+//! After a computation the deltas are pruned in a background thread (see `DB::prune_deltas_in_background`), so the computation doesn't wait for it.
+use common_u::errors::{self, DBErrKind};
+use db::{CRUDInterface, DeltaKey, P2PCalls, SplitKey, Stype, DB};
+use enigma_types::ContractAddress;
+use failure::Error;
+use hex::ToHex;
+use rocksdb::DB as rocks_db;
+use rocksdb::{Direction, IteratorMode, ReadOptions, WriteBatch};
+use serde_json;
+use std::sync::{Arc, PoisonError};
+use std::thread::{self, JoinHandle};
+
+/// Which deltas of a contract the DB keeps.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RetentionPolicy {
+    /// Keeps every delta, this is the policy of a contract that wasn't given one.
+    KeepAll,
+    /// Keeps the latest `n` deltas.
+    KeepLast(u32),
+    /// Keeps the deltas from this index on.
+    KeepFrom(u32),
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self { RetentionPolicy::KeepAll }
+}
+
+impl RetentionPolicy {
+    /// The index of the first delta the policy keeps when the tip is `tip`.
+    pub fn first_kept(self, tip: u32) -> u32 {
+        match self {
+            RetentionPolicy::KeepAll => 0,
+            RetentionPolicy::KeepLast(n) => tip.saturating_add(1).saturating_sub(n).min(tip),
+            RetentionPolicy::KeepFrom(index) => index.min(tip),
+        }
+    }
+}
+
+impl DB {
+    /// Sets the retention policy of `address`, it's applied by the next `prune_deltas`.
+    pub fn set_retention_policy(&mut self, address: ContractAddress, policy: RetentionPolicy) -> Result<(), Error> {
+        let key = DeltaKey::new(address, Stype::Retention);
+        self.force_update(&key, &serde_json::to_vec(&policy)?)
+    }
+
+    /// The retention policy of `address`, `RetentionPolicy::KeepAll` if it wasn't given one.
+    pub fn get_retention_policy(&self, address: ContractAddress) -> Result<RetentionPolicy, Error> {
+        let key = DeltaKey::new(address, Stype::Retention);
+        match self.read(&key) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) => match errors::is_db_err_type(e)?.kind {
+                DBErrKind::MissingKey(_) => Ok(RetentionPolicy::default()),
+                kind => bail!("Failed reading the retention policy: {:?}", kind),
+            },
+        }
+    }
+
+    /// Deletes the deltas of `address` that its retention policy doesn't keep,
+    /// but only up to `snapshot`, the index of the delta the stored state was built up to.
+    /// Returns how many deltas were deleted.
+    pub fn prune_deltas(&mut self, address: ContractAddress, snapshot: u32) -> Result<usize, Error> {
+        match self.prune_end(address, snapshot)? {
+            Some(end) => delete_deltas_before(&self.database, address, end),
+            None => Ok(0),
+        }
+    }
+
+    /// Same as `prune_deltas` but the deltas are deleted in a background thread.
+    /// Only one pruning runs at a time (across the handles of the DB), the previous one is waited for first.
+    pub fn prune_deltas_in_background(&mut self, address: ContractAddress, snapshot: u32) -> Result<(), Error> {
+        let mut pruning = self.pruning.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = join_pruning(pruning.take()) {
+            warn!("The previous pruning of the deltas failed: {}", e);
+        }
+        let end = match self.prune_end(address, snapshot)? {
+            Some(end) => end,
+            None => return Ok(()),
+        };
+        let database = Arc::clone(&self.database);
+        let handle = thread::Builder::new().name("db-pruning".to_string())
+            .spawn(move || delete_deltas_before(&database, address, end))?;
+        *pruning = Some(handle);
+        Ok(())
+    }
+
+    /// Waits for the pruning that runs in the background (if any) to finish, returns how many deltas it deleted.
+    pub fn wait_for_pruning(&mut self) -> Result<usize, Error> {
+        let pruning = self.pruning.lock().unwrap_or_else(PoisonError::into_inner).take();
+        join_pruning(pruning)
+    }
+
+    /// The index of the first delta of `address` that's kept, `None` if there's nothing to prune.
+    fn prune_end(&self, address: ContractAddress, snapshot: u32) -> Result<Option<u32>, Error> {
+        let policy = self.get_retention_policy(address)?;
+        if policy == RetentionPolicy::KeepAll {
+            return Ok(None);
+        }
+        let (tip, _) = self.get_tip::<DeltaKey>(&address)?;
+        let end = policy.first_kept(tip.key_type.unwrap_delta()).min(snapshot.saturating_add(1));
+        Ok(if end == 0 { None } else { Some(end) })
+    }
+}
+
+/// The pruning that runs in the background, it returns how many deltas it deleted.
+pub(crate) type BackgroundPruning = JoinHandle<Result<usize, Error>>;
+
+fn join_pruning(pruning: Option<BackgroundPruning>) -> Result<usize, Error> {
+    match pruning {
+        Some(handle) => handle.join().map_err(|_| format_err!("The pruning thread panicked"))?,
+        None => Ok(0),
+    }
+}
+
+/// Deletes the deltas of `address` before the delta `end` in a single batch, returns how many were deleted.
+fn delete_deltas_before(database: &rocks_db, address: ContractAddress, end: u32) -> Result<usize, Error> {
+    let hash = address.to_hex();
+    let cf = database.cf_handle(&hash)
+        .ok_or_else(|| errors::DBErr { command: "prune_deltas".to_string(), kind: DBErrKind::MissingKey(hash.clone()) })?;
+    let from = DeltaKey::new(address, Stype::Delta(0)).as_split(|_, key| key.to_vec());
+    let to = DeltaKey::new(address, Stype::Delta(end)).as_split(|_, key| key.to_vec());
+    let mut read_opts = ReadOptions::default();
+    read_opts.set_iterate_upper_bound(&to[..]);
+    let mut batch = WriteBatch::default();
+    let mut deleted = 0;
+    for (key, _) in database.iterator_cf_opt(cf, &read_opts, IteratorMode::From(&from[..], Direction::Forward))? {
+        batch.delete_cf(cf, &key)?;
+        deleted += 1;
+    }
+    database.write(batch)?;
+    debug!("Pruned {} deltas of {} before {}", deleted, hash, end);
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate cross_test_utils;
+    use self::cross_test_utils::generate_contract_address;
+    use super::RetentionPolicy;
+    use db::{CRUDInterface, DeltaKey, P2PCalls, Stype, tests::create_test_db};
+
+    #[test]
+    fn test_first_kept() {
+        assert_eq!(RetentionPolicy::KeepAll.first_kept(29), 0);
+        assert_eq!(RetentionPolicy::KeepLast(10).first_kept(29), 20);
+        assert_eq!(RetentionPolicy::KeepLast(100).first_kept(29), 0);
+        // The tip is kept even if the policy says otherwise.
+        assert_eq!(RetentionPolicy::KeepLast(0).first_kept(29), 29);
+        assert_eq!(RetentionPolicy::KeepFrom(7).first_kept(29), 7);
+        assert_eq!(RetentionPolicy::KeepFrom(50).first_kept(29), 29);
+    }
+
+    #[test]
+    fn test_keep_last_prunes_older_deltas() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        for i in 0..30u32 {
+            db.create(&DeltaKey::new(address, Stype::Delta(i)), &i.to_be_bytes()[..]).unwrap();
+        }
+        db.create(&DeltaKey::new(address, Stype::State), &b"state at 29"[..]).unwrap();
+
+        // Without a policy nothing is pruned.
+        assert_eq!(db.get_retention_policy(address).unwrap(), RetentionPolicy::KeepAll);
+        assert_eq!(db.prune_deltas(address, 29).unwrap(), 0);
+
+        db.set_retention_policy(address, RetentionPolicy::KeepLast(10)).unwrap();
+        assert_eq!(db.get_retention_policy(address).unwrap(), RetentionPolicy::KeepLast(10));
+        assert_eq!(db.prune_deltas(address, 29).unwrap(), 20);
+        for i in 0..20u32 {
+            assert!(db.get_delta(DeltaKey::new(address, Stype::Delta(i))).is_err());
+        }
+        // What's needed for reconstructing the state is left intact.
+        for i in 20..30u32 {
+            assert_eq!(db.get_delta(DeltaKey::new(address, Stype::Delta(i))).unwrap(), i.to_be_bytes().to_vec());
+        }
+        let (tip, _) = db.get_tip::<DeltaKey>(&address).unwrap();
+        assert_eq!(tip.key_type, Stype::Delta(29));
+        assert_eq!(db.read(&DeltaKey::new(address, Stype::State)).unwrap(), b"state at 29".to_vec());
+        // Pruning again doesn't find anything else to delete.
+        assert_eq!(db.prune_deltas(address, 29).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_prune_stops_at_the_snapshot() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        for i in 0..30u32 {
+            db.create(&DeltaKey::new(address, Stype::Delta(i)), &i.to_be_bytes()[..]).unwrap();
+        }
+        db.set_retention_policy(address, RetentionPolicy::KeepFrom(25)).unwrap();
+        // The stored state was only built up to the delta 9, the deltas after it are still needed.
+        assert_eq!(db.prune_deltas(address, 9).unwrap(), 10);
+        assert!(db.get_delta(DeltaKey::new(address, Stype::Delta(9))).is_err());
+        assert!(db.get_delta(DeltaKey::new(address, Stype::Delta(10))).is_ok());
+    }
+
+    #[test]
+    fn test_prune_in_background() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        for i in 0..30u32 {
+            db.create(&DeltaKey::new(address, Stype::Delta(i)), &i.to_be_bytes()[..]).unwrap();
+        }
+        db.set_retention_policy(address, RetentionPolicy::KeepLast(5)).unwrap();
+        db.prune_deltas_in_background(address, 29).unwrap();
+        assert_eq!(db.wait_for_pruning().unwrap(), 25);
+        assert!(db.get_delta(DeltaKey::new(address, Stype::Delta(24))).is_err());
+        assert!(db.get_delta(DeltaKey::new(address, Stype::Delta(25))).is_ok());
+        // Nothing runs anymore.
+        assert_eq!(db.wait_for_pruning().unwrap(), 0);
+    }
+}
@@ -0,0 +1,69 @@
+//! # Contract Settings.
+//! Every contract is given settings when it's deployed: its price, a multiplier over the base costs of the wasm instructions
+//! (see `WasmCosts::with_multiplier` in the runtime), and the host functions it's allowed to import.
+//! They're kept next to the contract and given to the enclave with every task. <br>
+//! The enclave MACs the settings when it deploys the contract (`ExecuteResult::settings_mac`) and refuses tasks whose settings
+//! don't match their MAC, so they can't be changed here. Nodes that receive the contract from another node receive the MAC with it.
+use common_u::errors::{self, DBErr, DBErrKind};
+use db::{CRUDInterface, DeltaKey, Stype, DB};
+use enigma_types::{ContractAddress, GasMultiplier, HostCapabilities};
+use failure::Error;
+use hex::ToHex;
+use serde_json;
+
+/// The settings of a contract and the MAC the enclave produced over them when the contract was deployed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ContractSettings {
+    pub gas_multiplier: GasMultiplier,
+    pub capabilities: HostCapabilities,
+    pub mac: [u8; 32],
+}
+
+impl DB {
+    /// Sets the settings of `address`, the tasks that run afterwards are given them.
+    pub fn set_settings(&mut self, address: ContractAddress, settings: ContractSettings) -> Result<(), Error> {
+        let key = DeltaKey::new(address, Stype::Settings);
+        self.force_update(&key, &serde_json::to_vec(&settings)?)
+    }
+
+    /// The settings of `address`, a contract without them can't run tasks because the enclave can't tell what it was deployed with.
+    pub fn get_settings(&self, address: ContractAddress) -> Result<ContractSettings, Error> {
+        let key = DeltaKey::new(address, Stype::Settings);
+        match self.read(&key) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) => match errors::is_db_err_type(e)?.kind {
+                DBErrKind::MissingKey(_) => {
+                    let kind = DBErrKind::MissingKey(format!("The settings of {}", address.to_hex()));
+                    Err(DBErr { command: "get_settings".to_string(), kind }.into())
+                }
+                kind => bail!("Failed reading the settings: {:?}", kind),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate cross_test_utils;
+    use self::cross_test_utils::generate_contract_address;
+    use super::ContractSettings;
+    use db::tests::create_test_db;
+    use enigma_types::{GasMultiplier, HostCapabilities};
+
+    #[test]
+    fn test_settings() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        assert!(db.get_settings(address).is_err());
+
+        let settings = ContractSettings {
+            gas_multiplier: GasMultiplier { mul: 1, div: 4 },
+            capabilities: HostCapabilities::ALL.without(HostCapabilities::RANDOMNESS),
+            mac: [7u8; 32],
+        };
+        db.set_settings(address, settings).unwrap();
+        assert_eq!(db.get_settings(address).unwrap(), settings);
+        assert!(db.get_settings(generate_contract_address()).is_err());
+    }
+}
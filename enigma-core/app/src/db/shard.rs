@@ -0,0 +1,40 @@
+use enigma_crypto::hash::Keccak256;
+use enigma_types::ContractAddress;
+
+/// The shard that holds the contract when the DB is split across `num_shards` instances.
+/// It's derived from a hash of the address so the mapping is the same on every node and doesn't depend on how the address was generated.
+pub fn shard_for(address: &ContractAddress, num_shards: usize) -> usize {
+    assert!(num_shards > 0, "There must be at least one shard");
+    let hash = address[..].keccak256();
+    let mut prefix = [0u8; 8];
+    prefix.copy_from_slice(&hash[..8]);
+    (u64::from_be_bytes(prefix) % num_shards as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate cross_test_utils;
+    use self::cross_test_utils::generate_contract_address;
+    use super::shard_for;
+
+    #[test]
+    fn test_shard_for_is_deterministic() {
+        let address = generate_contract_address();
+        assert_eq!(shard_for(&address, 7), shard_for(&address, 7));
+        assert_eq!(shard_for(&address, 1), 0);
+    }
+
+    #[test]
+    fn test_shard_for_is_uniform() {
+        let (addresses, shards) = (10_000, 8);
+        let mut counts = vec![0usize; shards];
+        for _ in 0..addresses {
+            counts[shard_for(&generate_contract_address(), shards)] += 1;
+        }
+        // The standard deviation of every count is ~33, 15% of the expected count is over 5 of them.
+        let expected = addresses / shards;
+        for count in counts {
+            assert!(count > expected * 85 / 100 && count < expected * 115 / 100, "{} addresses in a shard, expected about {}", count, expected);
+        }
+    }
+}
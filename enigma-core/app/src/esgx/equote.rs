@@ -2,7 +2,8 @@ use common_u::errors;
 use failure::Error;
 use sgx_types::*;
 use std::str;
-use crate::auto_ffi::ecall_get_signing_address;
+use enigma_types::EnclaveReturn;
+use crate::auto_ffi::{ecall_get_derived_signing_address, ecall_get_signing_address};
 // this struct is returned during the process registration back to the surface.
 // quote: the base64 encoded quote
 // address : the clear text public key for ecdsa signing and registration
@@ -25,12 +26,30 @@ pub fn get_register_signing_address(eid: sgx_enclave_id_t) -> Result<[u8; 20], E
     }
 }
 
+/// The code of the context of the key that signs registration messages, see `get_derived_signing_address`.
+pub const REGISTRATION_SIGNING_CONTEXT: u8 = 0;
+/// The code of the context of the key that signs task results, see `get_derived_signing_address`.
+pub const TASK_RESULT_SIGNING_CONTEXT: u8 = 1;
+
+/// The address of the signing key the enclave derives from its sealed key for `context`,
+/// every context has its own key so a signature made in one isn't valid in another.
+#[logfn(TRACE)]
+pub fn get_derived_signing_address(eid: sgx_enclave_id_t, context: u8) -> Result<[u8; 20], Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut address = [0u8; 20];
+    let status = unsafe { ecall_get_derived_signing_address(eid, &mut ret, context, &mut address) };
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(errors::EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(address)
+}
+
 
 #[cfg(test)]
 mod test {
     use crate::esgx::general::init_enclave_wrapper;
     use enigma_tools_u::attestation_service::{self, service::AttestationService};
-    use enigma_tools_u::esgx::equote::retry_quote;
+    use enigma_tools_u::esgx::equote::{expected_quote_len, retry_quote, EPID_SIGNATURE_SIZE};
 
     // isans SPID = "3DDB338BD52EE314B01F1E4E1E84E8AA"
     // victors spid = 68A8730E9ABF1829EA3F7A66321E84D0
@@ -55,10 +74,31 @@ mod test {
         println!("{}", tested_encoded_quote);
         println!("-------------------------");
         enclave.destroy();
-        assert!(!tested_encoded_quote.is_empty());
+        assert_eq!(tested_encoded_quote.len(), expected_quote_len(EPID_SIGNATURE_SIZE));
         //assert_eq!(real_encoded_quote, tested_encoded_quote);
     }
 
+    #[test]
+    fn test_signing_address_survives_restart() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let address = super::get_register_signing_address(enclave.geteid()).unwrap();
+        enclave.destroy();
+        // The new enclave unseals the key the first one sealed.
+        let enclave = init_enclave_wrapper().unwrap();
+        assert_eq!(super::get_register_signing_address(enclave.geteid()).unwrap(), address);
+    }
+
+    #[test]
+    fn test_derived_signing_addresses() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let registration = super::get_derived_signing_address(enclave.geteid(), super::REGISTRATION_SIGNING_CONTEXT).unwrap();
+        let task_result = super::get_derived_signing_address(enclave.geteid(), super::TASK_RESULT_SIGNING_CONTEXT).unwrap();
+        assert_ne!(registration, task_result);
+        assert_ne!(registration, super::get_register_signing_address(enclave.geteid()).unwrap());
+        assert_eq!(super::get_derived_signing_address(enclave.geteid(), super::REGISTRATION_SIGNING_CONTEXT).unwrap(), registration);
+        assert!(super::get_derived_signing_address(enclave.geteid(), 2).is_err());
+    }
+
     #[test]
     fn test_produce_and_verify_qoute() {
         let enclave = init_enclave_wrapper().unwrap();
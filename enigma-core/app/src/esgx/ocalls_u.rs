@@ -5,16 +5,35 @@ use enigma_crypto::hash::Sha256;
 use enigma_types::{ContractAddress, EnclaveReturn, Hash256, RawPointer};
 use lru_cache::LruCache;
 use std::sync::Mutex;
+#[cfg(test)]
+use std::collections::HashMap;
 use std::{ptr, slice};
 use common_u::errors;
 
 lazy_static! { static ref DELTAS_CACHE: Mutex<LruCache<Hash256, Vec<Vec<u8>>>> = Mutex::new(LruCache::new(500)); }
 
+// Counts the ocalls that write a state or a delta per contract, so tests can check how many enclave transitions a task costs.
+#[cfg(test)]
+lazy_static! { static ref WRITE_OCALLS: Mutex<HashMap<ContractAddress, usize>> = Mutex::new(HashMap::new()); }
+
+#[cfg(test)]
+fn count_write_ocall(address: &ContractAddress) {
+    *WRITE_OCALLS.lock_expect("WriteOcalls").entry(*address).or_insert(0) += 1;
+}
+
+/// The amount of ocalls that wrote a state or a delta of the contract so far.
+#[cfg(test)]
+pub fn write_ocalls_count(address: &ContractAddress) -> usize {
+    WRITE_OCALLS.lock_expect("WriteOcalls").get(address).cloned().unwrap_or(0)
+}
+
 
 #[no_mangle]
 pub unsafe extern "C" fn ocall_update_state(db_ptr: *const RawPointer, id: &ContractAddress, enc_state: *const u8, state_len: usize) -> EnclaveReturn {
     let encrypted_state = slice::from_raw_parts(enc_state, state_len);
     let key = DeltaKey::new(*id, Stype::State);
+    #[cfg(test)]
+    count_write_ocall(id);
 
     let db: &mut DB = match (*db_ptr).get_mut_ref() {
         Ok(db) => db,
@@ -40,6 +59,8 @@ pub unsafe extern "C" fn ocall_new_delta(db_ptr: *const RawPointer,
     let delta_index = ptr::read(delta_index_);
     let encrypted_delta = slice::from_raw_parts(enc_delta, delta_len);
     let key = DeltaKey::new(*contract_address, Stype::Delta(delta_index));
+    #[cfg(test)]
+    count_write_ocall(contract_address);
     let db: &mut DB = match (*db_ptr).get_mut_ref() {
         Ok(db) => db,
         Err(e) => {
@@ -57,6 +78,35 @@ pub unsafe extern "C" fn ocall_new_delta(db_ptr: *const RawPointer,
 }
 
 
+/// Writes the delta and the new state of a computation in a single write batch that isn't written
+/// unless both were added to it, so that a computation crosses the enclave boundary once to store its results.
+#[no_mangle]
+pub unsafe extern "C" fn ocall_save_delta_and_state(db_ptr: *const RawPointer,
+                                                    contract_address: &ContractAddress, delta_index_: *const u32,
+                                                    enc_delta: *const u8, delta_len: usize,
+                                                    enc_state: *const u8, state_len: usize) -> EnclaveReturn {
+    let delta_index = ptr::read(delta_index_);
+    let encrypted_delta = slice::from_raw_parts(enc_delta, delta_len);
+    let encrypted_state = slice::from_raw_parts(enc_state, state_len);
+    let delta_key = DeltaKey::new(*contract_address, Stype::Delta(delta_index));
+    let state_key = DeltaKey::new(*contract_address, Stype::State);
+    #[cfg(test)]
+    count_write_ocall(contract_address);
+    let db: &mut DB = match (*db_ptr).get_mut_ref() {
+        Ok(db) => db,
+        Err(e) => {
+            error!("{}", e);
+            return EnclaveReturn::OcallDBError
+        }
+    };
+    match db.insert_tuples_atomic(&[(delta_key, encrypted_delta), (state_key, encrypted_state)]) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => {
+            error!("Failed saving the delta: {:?} and the state with: \"{}\" ", &delta_key, &e);
+            EnclaveReturn::OcallDBError
+        }
+    }
+}
 #[no_mangle]
 pub unsafe extern "C" fn ocall_get_state_size(db_ptr: *const RawPointer, addr: &ContractAddress, state_size: *mut usize) -> EnclaveReturn {
     let mut cache_id = addr.to_vec();
@@ -76,7 +126,14 @@ pub unsafe extern "C" fn ocall_get_state_size(db_ptr: *const RawPointer, addr: &
             DELTAS_CACHE.lock_expect("DeltaCache").insert(cache_id.sha256(), vec![state]);
             EnclaveReturn::Success
         }
-        Err(_) => EnclaveReturn::OcallDBError,
+        // A contract whose key was provisioned before it was deployed has no state yet, that's a size of 0.
+        Err(e) => match errors::is_db_err_type(e) {
+            Ok(errors::DBErr { kind: errors::DBErrKind::MissingKey(_), .. }) => {
+                *state_size = 0;
+                EnclaveReturn::Success
+            }
+            _ => EnclaveReturn::OcallDBError,
+        },
     }
 }
 
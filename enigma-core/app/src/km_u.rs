@@ -2,33 +2,58 @@
 
 use crate::common_u::errors::EnclaveFailError;
 use crate::db::DB;
+use enigma_tools_m::primitives::km_primitives::UserKeySigVersion;
 use enigma_types::traits::SliceCPtr;
-use enigma_types::{EnclaveReturn, ContractAddress, PubKey, RawPointer};
+use enigma_types::{EnclaveReturn, ContractAddress, Hash256, PubKey, RawPointer};
 use failure::Error;
 use sgx_types::{sgx_enclave_id_t, sgx_status_t};
-use crate::auto_ffi::{ecall_ptt_req, ecall_ptt_res, ecall_build_state, ecall_get_user_key};
+use enigma_tools_u::esgx::output_u::{ecall_output, DEFAULT_OUTPUT_CAP};
+use crate::auto_ffi::{ecall_ptt_req, ecall_ptt_res, ecall_build_state, ecall_decode_delta, ecall_fetch_output, ecall_get_state_at,
+                      ecall_get_user_key, ecall_set_state_keys_lock_attempts, ecall_state_fingerprint, ecall_verify_delta};
+
+/// The most deltas a single `ecall_build_state` applies, so building the states doesn't hold an enclave thread for too long.
+pub const BUILD_STATE_MAX_DELTAS: u32 = 5_000;
+/// The cursor `ecall_build_state` returns once every state is built.
+const BUILD_STATE_DONE: u32 = u32::max_value();
 
 /// This function builds the states that it received in ptt_req and ptt_res
 /// It returns a Vec of the failed contract addresses
 #[logfn(TRACE)]
 pub fn ptt_build_state(db: &mut DB, eid: sgx_enclave_id_t) -> Result<Vec<ContractAddress>, Error> {
-    let mut ret = EnclaveReturn::Success;
-    let mut failed_ptr = 0u64;
+    let mut failed = Vec::new();
+    let mut cursor = Some(0);
+    while let Some(start) = cursor {
+        let (part, next) = ptt_build_state_bounded(db, eid, BUILD_STATE_MAX_DELTAS, start)?;
+        failed.extend(part);
+        cursor = next;
+    }
+    Ok(failed)
+}
 
-    let db_ptr = unsafe { RawPointer::new_mut(db) };
+/// Builds the states like `ptt_build_state` but applies at most `max_deltas` deltas (0 means no limit),
+/// starting from `cursor` (0 for the first call).
+/// It returns the contracts that failed in this call and the cursor to continue from, `None` once every state is built.
+pub fn ptt_build_state_bounded(db: &mut DB, eid: sgx_enclave_id_t, max_deltas: u32, cursor: u32) -> Result<(Vec<ContractAddress>, Option<u32>), Error> {
+    let mut cursor = cursor;
 
-    let status = unsafe {
-        ecall_build_state(eid,
-                          &mut ret as *mut EnclaveReturn,
-                          &db_ptr as *const RawPointer,
-                          &mut failed_ptr as *mut u64) };
+    let db_ptr = unsafe { RawPointer::new_mut(db) };
 
-    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
-        return Err(EnclaveFailError { err: ret, status }.into());
-    }
-    let box_ptr = failed_ptr as *mut Box<[u8]>;
-    let part = unsafe { Box::from_raw(box_ptr) };
-    let part: Vec<ContractAddress> = part
+    let failed = ecall_output(DEFAULT_OUTPUT_CAP, |failed, len, handle| {
+        let mut ret = EnclaveReturn::Success;
+        let status = unsafe {
+            ecall_build_state(eid,
+                              &mut ret as *mut EnclaveReturn,
+                              &db_ptr as *const RawPointer,
+                              max_deltas,
+                              &mut cursor as *mut u32,
+                              failed.as_mut_ptr(),
+                              failed.len(),
+                              len,
+                              handle)
+        };
+        check_ecall(ret, status)
+    }, |handle, output| fetch_output(eid, handle, output))?;
+    let part: Vec<ContractAddress> = failed
         .chunks(32)
         .map(|s| {
             let mut arr = ContractAddress::default();
@@ -36,52 +61,161 @@ pub fn ptt_build_state(db: &mut DB, eid: sgx_enclave_id_t) -> Result<Vec<Contrac
             arr
         })
         .collect();
-    Ok(part)
+    let next = if cursor == BUILD_STATE_DONE { None } else { Some(cursor) };
+    Ok((part, next))
 }
 
-pub fn ptt_res(eid: sgx_enclave_id_t, msg: &[u8]) -> Result<(), Error> {
+/// Verifies inside the enclave that the delta `key` of `address` was signed by a worker the KM node selected for it.
+pub fn verify_delta(eid: sgx_enclave_id_t, address: &ContractAddress, key: u32, data: &[u8], sig: &[u8; 65]) -> Result<(), Error> {
     let mut ret = EnclaveReturn::Success;
-    let status = unsafe { ecall_ptt_res(eid, &mut ret as *mut EnclaveReturn, msg.as_c_ptr(), msg.len()) };
+    let status = unsafe {
+        ecall_verify_delta(eid,
+                           &mut ret as *mut EnclaveReturn,
+                           address,
+                           key,
+                           data.as_c_ptr(),
+                           data.len(),
+                           sig.as_ptr() as _)
+    };
     if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
         return Err(EnclaveFailError { err: ret, status }.into());
     }
     Ok(())
 }
 
-pub fn ptt_req(eid: sgx_enclave_id_t) -> Result<(Box<[u8]>, [u8; 65]), Error> {
-    let mut sig = [0u8; 65];
-    let mut ret = EnclaveReturn::default();
-    let mut serialized_ptr = 0u64;
+/// Decrypts the delta `key` of `address` inside the enclave and returns its json patch operations serialized as JSON.
+/// Only debug enclaves agree to do this.
+pub fn decode_delta(eid: sgx_enclave_id_t, address: &ContractAddress, key: u32, data: &[u8]) -> Result<Box<[u8]>, Error> {
+    let output = ecall_output(DEFAULT_OUTPUT_CAP, |output, len, handle| {
+        let mut ret = EnclaveReturn::Success;
+        let status = unsafe {
+            ecall_decode_delta(eid,
+                               &mut ret as *mut EnclaveReturn,
+                               address,
+                               key,
+                               data.as_c_ptr(),
+                               data.len(),
+                               output.as_mut_ptr(),
+                               output.len(),
+                               len,
+                               handle)
+        };
+        check_ecall(ret, status)
+    }, |handle, output| fetch_output(eid, handle, output))?;
+    Ok(output.into_boxed_slice())
+}
 
+/// Rebuilds inside the enclave the state of `address` as it was after the delta `index` and returns it serialized as JSON.
+/// Only debug enclaves agree to do this.
+pub fn get_state_at(db: &mut DB, eid: sgx_enclave_id_t, address: &ContractAddress, index: u32) -> Result<Box<[u8]>, Error> {
+    let db_ptr = unsafe { RawPointer::new_mut(db) };
+    let output = ecall_output(DEFAULT_OUTPUT_CAP, |output, len, handle| {
+        let mut ret = EnclaveReturn::Success;
+        let status = unsafe {
+            ecall_get_state_at(eid,
+                               &mut ret as *mut EnclaveReturn,
+                               &db_ptr as *const RawPointer,
+                               address,
+                               index,
+                               output.as_mut_ptr(),
+                               output.len(),
+                               len,
+                               handle)
+        };
+        check_ecall(ret, status)
+    }, |handle, output| fetch_output(eid, handle, output))?;
+    Ok(output.into_boxed_slice())
+}
+
+/// Returns the delta hash, the delta index and a fingerprint of the latest state of `address`,
+/// two workers with the same fingerprint at the same index hold the same state.
+pub fn state_fingerprint(db: &mut DB, eid: sgx_enclave_id_t, address: &ContractAddress) -> Result<(Hash256, u32, Hash256), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut delta_hash = [0u8; 32];
+    let mut delta_index = 0u32;
+    let mut fingerprint = [0u8; 32];
+    let db_ptr = unsafe { RawPointer::new_mut(db) };
     let status = unsafe {
-        ecall_ptt_req(eid,
-                      &mut ret as *mut EnclaveReturn,
-                      &mut sig,
-                      &mut serialized_ptr as *mut u64,
-        )
+        ecall_state_fingerprint(eid,
+                                &mut ret as *mut EnclaveReturn,
+                                &db_ptr as *const RawPointer,
+                                address,
+                                &mut delta_hash,
+                                &mut delta_index,
+                                &mut fingerprint)
     };
     if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
         return Err(EnclaveFailError { err: ret, status }.into());
     }
-    let box_ptr = serialized_ptr as *mut Box<[u8]>;
-    let part = unsafe { Box::from_raw(box_ptr) };
-    Ok((*part, sig))
+    Ok((delta_hash.into(), delta_index, fingerprint.into()))
 }
 
-pub fn get_user_key(eid: sgx_enclave_id_t, user_pubkey: &PubKey) -> Result<(Box<[u8]>, [u8; 65]), Error> {
+pub fn ptt_res(eid: sgx_enclave_id_t, msg: &[u8]) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = unsafe { ecall_ptt_res(eid, &mut ret as *mut EnclaveReturn, msg.as_c_ptr(), msg.len()) };
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+/// Sets how many times reading the contracts' state keys tries to take their lock,
+/// a task that can't get it by then fails with `EnclaveReturn::LockError` instead of hanging the enclave thread.
+#[logfn(TRACE)]
+pub fn set_state_keys_lock_attempts(eid: sgx_enclave_id_t, attempts: u32) -> Result<(), Error> {
+    let status = unsafe { ecall_set_state_keys_lock_attempts(eid, attempts) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: EnclaveReturn::Success, status }.into());
+    }
+    Ok(())
+}
+
+pub fn ptt_req(eid: sgx_enclave_id_t) -> Result<(Box<[u8]>, [u8; 65]), Error> {
     let mut sig = [0u8; 65];
+    let output = ecall_output(DEFAULT_OUTPUT_CAP, |output, len, handle| {
+        let mut ret = EnclaveReturn::default();
+        let status = unsafe {
+            ecall_ptt_req(eid,
+                          &mut ret as *mut EnclaveReturn,
+                          &mut sig,
+                          output.as_mut_ptr(),
+                          output.len(),
+                          len,
+                          handle,
+            )
+        };
+        check_ecall(ret, status)
+    }, |handle, output| fetch_output(eid, handle, output))?;
+    Ok((output.into_boxed_slice(), sig))
+}
+
+/// Returns the message with the enclave's DH key for `user_pubkey` and the signature of it, signed the way `sig_version` says.
+pub fn get_user_key(eid: sgx_enclave_id_t, user_pubkey: &PubKey, sig_version: UserKeySigVersion) -> Result<(Box<[u8]>, [u8; 65]), Error> {
+    let mut sig = [0u8; 65];
+    let output = ecall_output(DEFAULT_OUTPUT_CAP, |output, len, handle| {
+        let mut ret = EnclaveReturn::Success;
+        let status = unsafe {
+            ecall_get_user_key(eid, &mut ret as *mut EnclaveReturn, &mut sig, user_pubkey.as_ptr() as _, sig_version as u32,
+                               output.as_mut_ptr(), output.len(), len, handle)
+        };
+        check_ecall(ret, status)
+    }, |handle, output| fetch_output(eid, handle, output))?;
+    Ok((output.into_boxed_slice(), sig))
+}
+
+/// Fetches an output the enclave kept because it didn't fit into the buffer of the ecall that produced it,
+/// `output` must be of the length the ecall reported.
+pub fn fetch_output(eid: sgx_enclave_id_t, handle: u64, output: &mut [u8]) -> Result<(), Error> {
     let mut ret = EnclaveReturn::Success;
-    let mut serialized_ptr = 0u64;
+    let status = unsafe { ecall_fetch_output(eid, &mut ret as *mut EnclaveReturn, handle, output.as_mut_ptr(), output.len()) };
+    check_ecall(ret, status)
+}
 
-    let status = unsafe {
-        ecall_get_user_key(eid, &mut ret as *mut EnclaveReturn, &mut sig, user_pubkey.as_ptr() as _, &mut serialized_ptr as *mut u64)
-    };
+fn check_ecall(ret: EnclaveReturn, status: sgx_status_t) -> Result<(), Error> {
     if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
         return Err(EnclaveFailError { err: ret, status }.into());
     }
-    let box_ptr = serialized_ptr as *mut Box<[u8]>;
-    let part = unsafe { Box::from_raw(box_ptr) };
-    Ok((*part, sig))
+    Ok(())
 }
 
 #[cfg(test)]
@@ -97,8 +231,9 @@ pub mod tests {
     use self::cross_test_utils::*;
     use enigma_types::{ContractAddress, DhKey};
     use enigma_crypto::{KeyPair, symmetric, hash::{self, Sha256, Keccak256}};
-    use rmp_serde::{Deserializer, Serializer};
-    use serde::{Deserialize, Serialize};
+    use enigma_tools_m::primitives::{km_primitives::{PttRequest, UserKeySigVersion}, signature_domain::SignatureDomain};
+    use rmp_serde::Deserializer;
+    use serde::Deserialize;
     use serde_json::{self, Value};
     use sgx_types::sgx_enclave_id_t;
     use self::ethabi::{Token};
@@ -108,7 +243,7 @@ pub mod tests {
 
     pub fn exchange_keys(id: sgx_enclave_id_t) -> (KeyPair, DhKey, Box<[u8]>, [u8; 65]) {
         let keys = KeyPair::new().unwrap();
-        let (data, sig) = super::get_user_key(id, &keys.get_pubkey()).unwrap();
+        let (data, sig) = super::get_user_key(id, &keys.get_pubkey(), UserKeySigVersion::default()).unwrap();
         let data_borrowed = data.clone();
 
         let mut des = Deserializer::new(&data_borrowed[..]);
@@ -151,16 +286,28 @@ pub mod tests {
     #[test]
     fn test_get_user_key() {
         let enclave = init_enclave_wrapper().unwrap();
-        let (_, _, data, sig) = exchange_keys(enclave.geteid());
         let signing_key = equote::get_register_signing_address(enclave.geteid()).unwrap();
+        let user_message = |data: &[u8]| {
+            let mut des = Deserializer::new(&data[..]);
+            let res: Value = Deserialize::deserialize(&mut des).unwrap();
+            let prefix = b"Enigma User Message";
+            let pubkey = serde_json::from_value::<Vec<u8>>(res["pubkey"].clone()).unwrap();
+            hash::prepare_hash_multiple(&[&prefix[..], &pubkey])
+        };
+
+        // By default the message is signed as it was before the signature domains, so existing clients keep verifying it.
+        let (_, _, data, sig) = exchange_keys(enclave.geteid());
+        let recovered = KeyPair::recover(&user_message(&data), sig).unwrap();
+        assert_eq!(recovered.keccak256()[12..32], signing_key);
 
-        let mut des = Deserializer::new(&data[..]);
-        let res: Value = Deserialize::deserialize(&mut des).unwrap();
-        let prefix = b"Enigma User Message";
-        let pubkey = serde_json::from_value::<Vec<u8>>(res["pubkey"].clone()).unwrap();
-        let msg = hash::prepare_hash_multiple(&[&prefix[..], &pubkey]);
+        let (data, sig) = super::get_user_key(enclave.geteid(), &KeyPair::new().unwrap().get_pubkey(), UserKeySigVersion::Tagged).unwrap();
+        let msg = user_message(&data);
+        SignatureDomain::UserKey.verify(&msg, sig, &signing_key).unwrap();
+        // The signature is bound to the user key domain, it doesn't pass as the signature of an output.
+        assert!(SignatureDomain::Output.verify(&msg, sig, &signing_key).is_err());
+        // Nor does it verify over the untagged message.
         let recovered = KeyPair::recover(&msg, sig).unwrap();
-        assert_eq!(recovered.keccak256()[12..32], signing_key);
+        assert_ne!(recovered.keccak256()[12..32], signing_key);
     }
 
     #[test]
@@ -173,16 +320,10 @@ pub mod tests {
 
     pub fn instantiate_encryption_key(addresses: Vec<ContractAddress>, eid: sgx_enclave_id_t) {
         let req = ptt_req(eid).unwrap();
+        let req = PttRequest::from_message(&req.0).unwrap();
 
-        let mut des = Deserializer::new(&req.0[..]);
-        let req_val: Value = Deserialize::deserialize(&mut des).unwrap();
-
-        let enc_response = make_encrypted_response(&req_val, addresses, None);
-
-        let mut serialized_enc_response = Vec::new();
-        enc_response.serialize(&mut Serializer::new(&mut serialized_enc_response)).unwrap();
-
-        ptt_res(eid, &serialized_enc_response).unwrap();
+        let enc_response = make_encrypted_response(&req, addresses, None);
+        ptt_res(eid, &enc_response).unwrap();
     }
 
     #[test]
@@ -201,18 +342,14 @@ pub mod tests {
             }).collect();
         let enclave = init_enclave_wrapper().unwrap();
         let req = ptt_req(enclave.geteid()).unwrap();
-        // serializing the result from the request
-        let mut des = Deserializer::new(&req.0[..]);
-        let req_val: Value = Deserialize::deserialize(&mut des).unwrap();
+        // parsing the request
+        let req = PttRequest::from_message(&req.0).unwrap();
 
         // Generating the response
         println!("asking {} addresses", addresses.len());
-        let enc_response = make_encrypted_response(&req_val, addresses.clone(), Some(keys));
-
-        let mut serialized_enc_response = Vec::new();
-        enc_response.serialize(&mut Serializer::new(&mut serialized_enc_response)).unwrap();
+        let enc_response = make_encrypted_response(&req, addresses.clone(), Some(keys));
 
-        ptt_res(enclave.geteid(), &serialized_enc_response).unwrap();
+        ptt_res(enclave.geteid(), &enc_response).unwrap();
 
         let address_result = ptt_build_state(&mut db, enclave.geteid()).unwrap();
         assert_eq!(address_result, vec![addresses[2]]);
@@ -7,6 +7,7 @@ extern crate sgx_urts;
 #[macro_use]
 extern crate lazy_static;
 pub extern crate futures;
+extern crate futures_cpupool;
 extern crate rmp_serde;
 pub extern crate serde_json;
 extern crate tokio_zmq;
@@ -14,7 +15,7 @@ extern crate zmq;
 #[macro_use]
 extern crate failure;
 pub extern crate enigma_tools_u;
-extern crate enigma_tools_m;
+pub extern crate enigma_tools_m;
 extern crate enigma_crypto;
 extern crate enigma_types;
 extern crate rustc_hex as hex;
@@ -27,6 +28,7 @@ pub extern crate log;
 #[macro_use]
 pub extern crate log_derive;
 pub extern crate structopt;
+pub extern crate ethabi;
 
 pub mod common_u;
 pub mod db;
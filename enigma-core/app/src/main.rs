@@ -9,14 +9,15 @@ use std::str::FromStr;
 
 pub use enigma_core_app::*;
 pub use esgx::ocalls_u::{ocall_get_deltas, ocall_get_deltas_sizes, ocall_get_state, ocall_get_state_size,
-                                ocall_new_delta, ocall_update_state, ocall_remove_delta};
+                                ocall_new_delta, ocall_update_state, ocall_remove_delta,
+                                ocall_save_delta_and_state};
 
-pub use enigma_tools_u::esgx::ocalls_u::{ocall_get_home, ocall_save_to_memory};
+pub use enigma_tools_u::esgx::ocalls_u::{ocall_get_home, ocall_log, ocall_save_to_memory};
 use enigma_tools_u::common_u::logging;
 use enigma_tools_u::common_u::os;
 
-use networking::{ipc_listener, IpcListener};
-use db::DB;
+use networking::{ipc_listener, ConcurrentDB, ConcurrentIpcListener, IpcListener};
+use db::{CompactionTrigger, DB};
 use cli::Opt;
 use structopt::StructOpt;
 use futures::Future;
@@ -30,19 +31,68 @@ fn main() {
     let datadir = opt.data_dir.clone().unwrap_or_else(|| dirs::home_dir().unwrap().join(".enigma"));
     let hostname = os::hostname();
     let _handler = logging::init_logger(log_level, &datadir, hostname);
+    enigma_tools_u::esgx::ocalls_u::silence_enclave_logs(opt.silence_enclave_logs);
+    common_u::panic_hook::install();
 
     debug!("CLI params: {:?}", opt);
 
+    let gas_limits = match opt.gas_limits() {
+        Ok(gas_limits) => gas_limits,
+        Err(e) => {
+            error!("Bad gas limits: {}", e);
+            std::process::exit(1);
+        }
+    };
 
     let enclave = esgx::general::init_enclave_wrapper().map_err(|e| {error!("Init Enclave Failed {:?}", e);}).unwrap();
     let eid = enclave.geteid();
     info!("Init Enclave Successful. Enclave id {}", eid);
+    if let Err(e) = wasm_u::wasm::set_native_stack_budget(eid, opt.native_stack_budget) {
+        error!("Failed setting the native stack budget: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = wasm_u::wasm::set_max_json_depth(eid, opt.max_json_depth) {
+        error!("Failed setting the max JSON depth: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = wasm_u::wasm::set_max_state_size(eid, opt.max_state_size) {
+        error!("Failed setting the max state size: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = wasm_u::wasm::set_error_details(eid, opt.debug_error_details) {
+        error!("Failed setting the error details: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = km_u::set_state_keys_lock_attempts(eid, opt.state_keys_lock_attempts) {
+        error!("Failed setting the state keys lock attempts: {}", e);
+        std::process::exit(1);
+    }
 
-    let mut db = DB::new(datadir, true).expect("Failed initializing the DB");
-    let server = IpcListener::new(&format!("tcp://*:{}", opt.port));
+    let mut db = match DB::new(datadir, true) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed initializing the DB: {}", e);
+            std::process::exit(1);
+        }
+    };
+    db.set_max_open_iterators(opt.max_db_iterators);
+    db.set_compaction_trigger(CompactionTrigger { max_size_bytes: opt.compact_at_size, max_new_deltas: opt.compact_every_deltas });
+    let conn_str = format!("tcp://*:{}", opt.port);
+    let attestation = opt.attestation_config();
+    for client in &opt.client_keys {
+        networking::sequence::SEQUENCES.register(client.clone());
+    }
 
-    server
-        .run(move |multi| ipc_listener::handle_message(&mut db, multi, &opt.spid, eid, opt.retries))
-        .wait()
-        .unwrap();
+    if opt.ipc_threads > 1 {
+        let db = ConcurrentDB::new(db);
+        ConcurrentIpcListener::new(&conn_str, opt.ipc_threads)
+            .run(move |multi| ipc_listener::handle_message_concurrent(&db, multi, &attestation, eid, opt.trust_unsigned, opt.snapshot_interval, gas_limits))
+            .wait()
+            .unwrap();
+    } else {
+        IpcListener::new(&conn_str)
+            .run(move |multi| ipc_listener::handle_message(&mut db, multi, &attestation, eid, opt.trust_unsigned, opt.snapshot_interval, gas_limits))
+            .wait()
+            .unwrap();
+    }
 }
\ No newline at end of file
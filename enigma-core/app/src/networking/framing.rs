@@ -0,0 +1,161 @@
+//! # Framing.
+//! A payload larger than a threshold is sent as several ZMQ frames instead of a single one,
+//! every frame starts with a header that lets the other end reassemble the payload:
+//! a marker byte (which can't start a JSON message), the length of the whole payload (u64),
+//! the index of the frame and the number of frames (both u32), all big endian.
+//! Payloads up to the threshold are sent as is, so peers that don't know the framing keep working with them. <br>
+//! It's opt-in: the core reassembles framed requests, but only splits the responses of the requests that set a `chunkSize`
+//! (see `IpcRequest::chunk_size`).
+
+use failure::Error;
+
+/// The largest payload `join` reassembles, the length in a frame header can't be trusted before it's bounded.
+pub const MAX_PAYLOAD_LEN: u64 = 256 * 1024 * 1024;
+/// The first byte of every frame of a split payload.
+pub const FRAME_MARKER: u8 = 0;
+/// marker + payload length + index + count.
+pub const HEADER_LEN: usize = 1 + 8 + 4 + 4;
+
+/// Splits `payload` into frames of up to `threshold` bytes (plus the header),
+/// a payload that isn't larger than `threshold` is returned as the only frame without a header.
+pub fn split(payload: &[u8], threshold: usize) -> Vec<Vec<u8>> {
+    if payload.len() <= threshold || threshold == 0 {
+        return vec![payload.to_vec()];
+    }
+    let count = (payload.len() + threshold - 1) / threshold;
+    payload
+        .chunks(threshold)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut frame = Vec::with_capacity(HEADER_LEN + chunk.len());
+            frame.push(FRAME_MARKER);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+            frame.extend_from_slice(&(index as u32).to_be_bytes());
+            frame.extend_from_slice(&(count as u32).to_be_bytes());
+            frame.extend_from_slice(chunk);
+            frame
+        })
+        .collect()
+}
+
+struct Partial {
+    payload: Vec<u8>,
+    len: u64,
+    next: u32,
+    count: u32,
+}
+
+/// Reassembles the payloads that were split by `split`, a frame without a header is a payload of its own.
+/// The frames of a payload must arrive in order and can't be interleaved with other payloads,
+/// and a payload can't be longer than `max_len`.
+pub fn join<I, F>(frames: I, max_len: u64) -> Result<Vec<Vec<u8>>, Error>
+where I: IntoIterator<Item = F>, F: AsRef<[u8]> {
+    let mut payloads = Vec::new();
+    let mut partial: Option<Partial> = None;
+    for frame in frames {
+        let frame = frame.as_ref();
+        if frame.first() != Some(&FRAME_MARKER) {
+            if partial.is_some() {
+                bail!("A payload was interrupted by another one before all of its frames arrived");
+            }
+            payloads.push(frame.to_vec());
+            continue;
+        }
+        if frame.len() < HEADER_LEN {
+            bail!("A frame of {} bytes is shorter than the frame header", frame.len());
+        }
+        let (len, index, count) = parse_header(&frame[..HEADER_LEN]);
+        let mut current = match partial.take() {
+            Some(current) => current,
+            None => {
+                if index != 0 || count == 0 {
+                    bail!("A payload has to start with its first frame, got frame {} of {}", index, count);
+                }
+                if len > max_len {
+                    bail!("A payload of {} bytes is longer than the maximum of {} bytes", len, max_len);
+                }
+                Partial { payload: Vec::with_capacity(len as usize), len, next: 0, count }
+            }
+        };
+        if index != current.next || count != current.count || len != current.len {
+            bail!("Expected frame {} of {}, got frame {} of {}", current.next, current.count, index, count);
+        }
+        current.payload.extend_from_slice(&frame[HEADER_LEN..]);
+        if current.payload.len() as u64 > current.len {
+            bail!("The frames are longer than their payload of {} bytes", current.len);
+        }
+        current.next += 1;
+        if current.next < current.count {
+            partial = Some(current);
+        } else if current.payload.len() as u64 != current.len {
+            bail!("The frames are {} bytes but their payload is {} bytes", current.payload.len(), current.len);
+        } else {
+            payloads.push(current.payload);
+        }
+    }
+    if let Some(current) = partial {
+        bail!("Only {} of the {} frames of a payload arrived", current.next, current.count);
+    }
+    Ok(payloads)
+}
+
+fn parse_header(header: &[u8]) -> (u64, u32, u32) {
+    let mut len = [0u8; 8];
+    let mut index = [0u8; 4];
+    let mut count = [0u8; 4];
+    len.copy_from_slice(&header[1..9]);
+    index.copy_from_slice(&header[9..13]);
+    count.copy_from_slice(&header[13..17]);
+    (u64::from_be_bytes(len), u32::from_be_bytes(index), u32::from_be_bytes(count))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_small_payload_isnt_framed() {
+        let payload = br#"{"id":"1","type":"GetAllTips"}"#;
+        let frames = split(payload, 64);
+        assert_eq!(frames, vec![payload.to_vec()]);
+        assert_eq!(join(&frames, MAX_PAYLOAD_LEN).unwrap(), frames);
+    }
+
+    #[test]
+    fn test_round_trip_large_payload() {
+        let payload: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let frames = split(&payload, 1000);
+        assert_eq!(frames.len(), 10);
+        assert!(frames.iter().all(|frame| frame[0] == FRAME_MARKER && frame.len() <= HEADER_LEN + 1000));
+
+        // Plain frames around the framed payload are passed through.
+        let mut all = vec![b"{}".to_vec()];
+        all.extend(frames);
+        all.push(b"[]".to_vec());
+        assert_eq!(join(all, MAX_PAYLOAD_LEN).unwrap(), vec![b"{}".to_vec(), payload, b"[]".to_vec()]);
+    }
+
+    #[test]
+    fn test_join_rejects_broken_frames() {
+        let payload = vec![7u8; 300];
+        let frames = split(&payload, 100);
+        // A missing frame.
+        assert!(join(&frames[..2], MAX_PAYLOAD_LEN).is_err());
+        assert!(join(&frames[1..], MAX_PAYLOAD_LEN).is_err());
+        // Out of order.
+        assert!(join(vec![&frames[0], &frames[2], &frames[1]], MAX_PAYLOAD_LEN).is_err());
+        // Interleaved with another payload.
+        assert!(join(vec![&frames[0], &b"{}".to_vec(), &frames[1], &frames[2]], MAX_PAYLOAD_LEN).is_err());
+        // A truncated header.
+        assert!(join(vec![frames[0][..5].to_vec()], MAX_PAYLOAD_LEN).is_err());
+        // A frame that doesn't add up to the length in the header.
+        let mut short = frames.clone();
+        short[2].pop();
+        assert!(join(&short, MAX_PAYLOAD_LEN).is_err());
+        // A header claiming a huge payload is rejected before anything is allocated for it.
+        let mut huge = frames[0][..HEADER_LEN].to_vec();
+        huge[1..9].copy_from_slice(&u64::max_value().to_be_bytes());
+        assert!(join(vec![huge], MAX_PAYLOAD_LEN).is_err());
+        assert!(join(&frames, 299).is_err());
+    }
+}
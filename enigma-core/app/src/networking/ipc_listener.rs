@@ -1,10 +1,19 @@
+use crate::networking::{framing, sequence};
 use crate::networking::messages::*;
+use crate::common_u::errors::InvalidRequest;
+use crate::common_u::panic_hook;
 use crate::db::DB;
+use enigma_types::ContractAddress;
+use failure::Error as FailureError;
 use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
+use enigma_tools_u::attestation_service::service::AttestationConfig;
 use sgx_types::sgx_enclave_id_t;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 use tokio_zmq::prelude::*;
-use tokio_zmq::{Error, Multipart, Rep};
+use tokio_zmq::{Error, Multipart, Rep, Router};
+use zmq::Message;
 
 pub struct IpcListener {
     _context: Arc<zmq::Context>,
@@ -28,57 +37,249 @@ impl IpcListener {
     }
 }
 
-pub fn handle_message(db: &mut DB, request: Multipart, spid: &str, eid: sgx_enclave_id_t, retries: u32) -> Multipart {
+/// A listener that handles up to `threads` requests at the same time on a thread pool.
+/// It uses a Router socket so that a new request can be received before the previous ones were answered,
+/// the responses are routed back to their peers regardless of the order in which they finish.
+pub struct ConcurrentIpcListener {
+    _context: Arc<zmq::Context>,
+    router_future: Box<dyn Future<Item = Router, Error = Error>>,
+    pool: CpuPool,
+    threads: usize,
+}
+
+impl ConcurrentIpcListener {
+    pub fn new(conn_str: &str, threads: usize) -> Self {
+        let _context = Arc::new(zmq::Context::new());
+        let router_future = Router::builder(_context.clone()).bind(conn_str).build();
+        debug!("Binded to socket: {} with {} threads", conn_str, threads);
+        let pool = CpuPool::new(threads);
+        ConcurrentIpcListener { _context, router_future, pool, threads }
+    }
+
+    pub fn run<F>(self, f: F) -> impl Future<Item = (), Error = Error>
+    where F: Fn(Multipart) -> Multipart + Send + Sync + 'static {
+        let f = Arc::new(f);
+        let ConcurrentIpcListener { router_future, pool, threads, .. } = self;
+        router_future.and_then(move |router| {
+            let (sink, stream) = router.sink_stream(25).split();
+            stream
+                .map(move |multi| {
+                    let f = Arc::clone(&f);
+                    pool.spawn_fn(move || {
+                        let (mut envelope, request) = split_envelope(multi);
+                        for msg in f(request) {
+                            envelope.push_back(msg);
+                        }
+                        Ok::<_, Error>(envelope)
+                    })
+                })
+                .buffer_unordered(threads)
+                .forward(sink)
+                .map(|(_stream, _sink)| ())
+        })
+    }
+}
+
+/// A Router prepends the identity of the peer followed by an empty delimiter to every request,
+/// this separates them from the request so they can be prepended to the response.
+fn split_envelope(mut multi: Multipart) -> (Multipart, Multipart) {
+    let mut envelope = Multipart::new();
+    while let Some(msg) = multi.pop_front() {
+        let is_delimiter = msg.is_empty();
+        envelope.push_back(msg);
+        if is_delimiter {
+            break;
+        }
+    }
+    (envelope, multi)
+}
+
+/// `gas_limits` bound the gas limit of every task, and give one to the tasks that don't set it.
+pub fn handle_message(db: &mut DB, request: Multipart, attestation: &AttestationConfig, eid: sgx_enclave_id_t,
+                      trust_unsigned: bool, snapshot_interval: u32, gas_limits: GasLimits) -> Multipart {
+    respond_to_each(request, |req| {
+        panic_hook::flush_on_panic(db, |db| handle_request(db, req, attestation, eid, trust_unsigned, snapshot_interval, gas_limits))
+    })
+}
+
+/// The DB of a `ConcurrentIpcListener`, shared by the threads that handle its requests.
+pub struct ConcurrentDB {
+    db: RwLock<DB>,
+    // a lock per contract that's being written to, the writes to different contracts run at the same time
+    contracts: Mutex<HashMap<ContractAddress, Arc<Mutex<()>>>>,
+    // the enclave has a single TCS (see `Enclave.config.xml`), so only one request at a time calls into it
+    enclave: Mutex<()>,
+}
+
+impl ConcurrentDB {
+    pub fn new(db: DB) -> Self {
+        ConcurrentDB { db: RwLock::new(db), contracts: Mutex::new(HashMap::new()), enclave: Mutex::new(()) }
+    }
+
+    fn contract_lock(&self, address: ContractAddress) -> Arc<Mutex<()>> {
+        let mut contracts = self.contracts.lock().unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(contracts.entry(address).or_insert_with(Arc::default))
+    }
+
+    /// Forgets the lock of the contract once no other request holds or waits for it.
+    fn release_contract_lock(&self, address: ContractAddress, lock: Arc<Mutex<()>>) {
+        let mut contracts = self.contracts.lock().unwrap_or_else(PoisonError::into_inner);
+        // One reference is in the map and the other is `lock`, new ones are only taken while the map is locked.
+        if Arc::strong_count(&lock) == 2 {
+            contracts.remove(&address);
+        }
+    }
+}
+
+/// Same as `handle_message` but can be called from multiple threads at the same time.
+/// Read only requests run concurrently, requests that write to a single contract run concurrently with the ones on other contracts
+/// (see `IpcRequest::contract`) and all the other requests get exclusive access to the DB.
+pub fn handle_message_concurrent(db: &ConcurrentDB, request: Multipart, attestation: &AttestationConfig, eid: sgx_enclave_id_t,
+                                 trust_unsigned: bool, snapshot_interval: u32, gas_limits: GasLimits) -> Multipart {
+    respond_to_each(request, |req| {
+        let shared = db.db.read().unwrap_or_else(PoisonError::into_inner);
+        if req.is_read_only() {
+            return handle_read_request(&shared, req);
+        }
+        // A request that would rebuild the states writes to every contract, so it waits for all the others.
+        let address = req.contract().and_then(|address| ContractAddress::from_hex(address).ok());
+        if let (Some(address), true) = (address, shared.get_state_status()) {
+            let lock = db.contract_lock(address);
+            let response = {
+                let _contract = lock.lock().unwrap_or_else(PoisonError::into_inner);
+                let _enclave = if req.enters_enclave() { Some(db.enclave.lock().unwrap_or_else(PoisonError::into_inner)) } else { None };
+                let mut handle = shared.handle();
+                panic_hook::flush_on_panic(&mut handle, |db| handle_request(db, req, attestation, eid, trust_unsigned, snapshot_interval, gas_limits))
+            };
+            db.release_contract_lock(address, lock);
+            return response;
+        }
+        drop(shared);
+        let mut db = db.db.write().unwrap_or_else(PoisonError::into_inner);
+        panic_hook::flush_on_panic(&mut db, |db| handle_request(db, req, attestation, eid, trust_unsigned, snapshot_interval, gas_limits))
+    })
+}
+
+/// Reassembles the requests that were split into frames, and splits the responses of the requests that set a chunk size.
+fn respond_to_each<F>(request: Multipart, mut f: F) -> Multipart
+where F: FnMut(IpcRequest) -> Result<IpcResponse, FailureError> {
     let mut responses = Multipart::new();
-    for msg in request {
-        let msg: IpcMessageRequest = msg.into();
+    let payloads = match framing::join(request.iter().map(|msg| &msg[..]), framing::MAX_PAYLOAD_LEN) {
+        Ok(payloads) => payloads,
+        Err(e) => {
+            let response = Err::<IpcResponse, _>(e).unwrap_or_error();
+            responses.push_back(IpcMessageResponse::from_response(response, String::new()).into());
+            return responses;
+        }
+    };
+    for payload in payloads {
+        let msg = match IpcMessageRequest::parse(&payload) {
+            Ok(msg) => msg,
+            Err(e) => {
+                let response = Err::<IpcResponse, _>(e).unwrap_or_error();
+                responses.push_back(IpcMessageResponse::from_response(response, IpcMessageRequest::id_of(&payload)).into());
+                continue;
+            }
+        };
         let id = msg.id.clone();
-        let response_msg = match msg.request {
-            IpcRequest::GetRegistrationParams => handling::get_registration_params(eid, spid, retries),
-            IpcRequest::GetTip { input } => handling::get_tip(db, &input),
-            IpcRequest::GetTips { input } => handling::get_tips(db, &input),
-            IpcRequest::GetAllTips => handling::get_all_tips(db),
-            IpcRequest::GetAllAddrs => handling::get_all_addrs(db),
-            IpcRequest::GetDelta { input } => handling::get_delta(db, input),
-            IpcRequest::GetDeltas { input } => handling::get_deltas(db, &input),
-            IpcRequest::GetContract { input } => handling::get_contract(db, &input),
-            IpcRequest::UpdateNewContract { address, bytecode } => handling::update_new_contract(db, address, &bytecode),
-            IpcRequest::UpdateNewContractOnDeployment { address, bytecode, delta } => handling::update_new_contract_on_deployment(db, address, &bytecode, delta),
-            IpcRequest::RemoveContract {address } => handling::remove_contract(db, address),
-            IpcRequest::UpdateDeltas { deltas } => handling::update_deltas(db, deltas),
-            IpcRequest::RemoveDeltas { input } => handling::remove_deltas(db, input),
-            IpcRequest::NewTaskEncryptionKey { user_pubkey } => handling::get_dh_user_key( &user_pubkey, eid),
-            IpcRequest::DeploySecretContract { input } => handling::deploy_contract(db, input, eid),
-            IpcRequest::ComputeTask { input } => handling::compute_task(db, input, eid),
-            IpcRequest::GetPTTRequest => handling::get_ptt_req(eid),
-            IpcRequest::PTTResponse { input } => handling::ptt_response(db, &input, eid),
+        let chunk_size = msg.ensure_in_sequence(&payload, &sequence::SEQUENCES).and_then(|_| msg.request.chunk_size());
+        let (response, chunk_size) = match chunk_size {
+            Ok(chunk_size) => (f(msg.request).unwrap_or_error(), chunk_size),
+            Err(e) => (Err::<IpcResponse, _>(e).unwrap_or_error(), None),
         };
-        let msg = IpcMessageResponse::from_response(response_msg.unwrap_or_error(), id);
-        responses.push_back(msg.into());
+        let response: Message = IpcMessageResponse::from_response(response, id).into();
+        match chunk_size {
+            Some(size) => {
+                for frame in framing::split(&response, size) {
+                    responses.push_back(Message::from(frame));
+                }
+            }
+            None => responses.push_back(response),
+        }
     }
     responses
 }
 
+fn handle_request(db: &mut DB, mut request: IpcRequest, attestation: &AttestationConfig, eid: sgx_enclave_id_t,
+                  trust_unsigned: bool, snapshot_interval: u32, gas_limits: GasLimits) -> Result<IpcResponse, FailureError> {
+    request.apply_default_gas_limit(gas_limits.default);
+    request.check_gas_limits(&gas_limits)?;
+    request.validate_tasks()?;
+    match &request {
+        IpcRequest::DeploySecretContract { input } | IpcRequest::ComputeTask { input } |
+        IpcRequest::ReplayCompute { input, .. } => panic_hook::set_last_task_id(input.task_description()),
+        IpcRequest::DeployAndCompute { compute, .. } => panic_hook::set_last_task_id(compute.task_description()),
+        _ => (),
+    }
+    let response = match request {
+        IpcRequest::GetRegistrationParams => handling::get_registration_params(eid, attestation),
+        IpcRequest::UpdateNewContract { address, bytecode, settings } => handling::update_new_contract(db, address, &bytecode, settings),
+        IpcRequest::UpdateNewContractOnDeployment { address, bytecode, delta, settings } => handling::update_new_contract_on_deployment(db, address, &bytecode, delta, settings),
+        IpcRequest::RemoveContract {address } => handling::remove_contract(db, address),
+        IpcRequest::SetRetentionPolicy { address, policy } => handling::set_retention_policy(db, address, policy),
+        IpcRequest::DestroyContract { address, sig } => handling::destroy_contract(db, address, &sig),
+        IpcRequest::CompactDb => handling::compact_db(db),
+        IpcRequest::UpdateDeltas { deltas } => handling::update_deltas(db, deltas, eid, trust_unsigned),
+        IpcRequest::RemoveDeltas { input } => handling::remove_deltas(db, input),
+        IpcRequest::NewTaskEncryptionKey { user_pubkey, sig_version } => handling::get_dh_user_key( &user_pubkey, sig_version, eid),
+        IpcRequest::DeploySecretContract { input } => handling::deploy_contract(db, input, eid),
+        IpcRequest::ValidateContract { bytecode, forbid, gas_multiplier } => handling::validate_contract(&bytecode, &forbid, gas_multiplier, eid),
+        IpcRequest::ComputeTask { input } => handling::compute_task(db, input, eid, snapshot_interval),
+        IpcRequest::ReplayCompute { input, claim, replay_dhkey, task_key } =>
+            handling::replay_compute(db, input, claim, &replay_dhkey, &task_key, eid),
+        IpcRequest::GetPTTRequest => handling::get_ptt_req(eid),
+        IpcRequest::PTTResponse { input } => handling::ptt_response(db, &input, eid),
+        IpcRequest::GetDeltaDecoded { address, index } => handling::get_delta_decoded(db, &address, index, eid),
+        IpcRequest::GetStateAt { address, index } => handling::get_state_at(db, &address, index, eid),
+        IpcRequest::StateFingerprint { address } => handling::state_fingerprint(db, &address, eid),
+        IpcRequest::DeployAndCompute { deploy, compute } => handling::deploy_and_compute(db, deploy, compute, eid, snapshot_interval),
+        read_request => return handle_read_request(db, read_request),
+    };
+    // The compaction runs in the background, failing to start it doesn't fail the request, it's tried again after the next one.
+    if let Err(e) = db.compact_if_needed() {
+        warn!("Failed compacting the DB: {}", e);
+    }
+    response
+}
+
+fn handle_read_request(db: &DB, request: IpcRequest) -> Result<IpcResponse, FailureError> {
+    match request {
+        IpcRequest::GetTip { input } => handling::get_tip(db, &input),
+        IpcRequest::GetTips { input } => handling::get_tips(db, &input),
+        IpcRequest::GetAllTips => handling::get_all_tips(db),
+        IpcRequest::GetAllAddrs => handling::get_all_addrs(db),
+        IpcRequest::GetDelta { input } => handling::get_delta(db, input),
+        IpcRequest::GetDeltas { input, .. } => handling::get_deltas(db, &input),
+        IpcRequest::GetContract { input, .. } => handling::get_contract(db, &input),
+        IpcRequest::GetContracts { addresses, .. } => handling::get_contracts(db, &addresses),
+        IpcRequest::GetContractMeta { address } => handling::get_contract_meta(db, &address),
+        IpcRequest::GetMetrics { address } => handling::get_metrics(&address),
+        _ => Err(InvalidRequest { msg: format!("{:?} isn't a read only request", request) }.into()),
+    }
+}
+
 
 // TODO: Make sure that every ? that doesn't require responding with a empty Message is replaced with an appropriate handling
 pub(self) mod handling {
     #![allow(clippy::needless_pass_by_value)]
     use crate::common_u::errors::P2PErr;
-    use crate::db::{CRUDInterface, DeltaKey, P2PCalls, Stype, DB};
+    use crate::db::{CRUDInterface, ContractSettings, Delta, DeltaKey, P2PCalls, RetentionPolicy, Stype, DB};
     use crate::km_u;
     use crate::networking::messages::*;
     use crate::esgx::equote;
     use crate::wasm_u::*;
     use enigma_crypto::hash::Keccak256;
+    use enigma_tools_m::primitives::{km_primitives::UserKeySigVersion, signature_domain::SignatureDomain, task_primitives::destroy_contract_message};
     use enigma_tools_u::esgx::equote as equote_tools;
-    use enigma_tools_u::attestation_service::{service::AttestationService, constants::ATTESTATION_SERVICE_URL};
-    use enigma_types::ContractAddress;
-    use failure::Error;
+    use enigma_tools_u::attestation_service::service::{AttestationConfig, AttestationService};
+    use enigma_types::{ContractAddress, GasMultiplier, HostCapabilities};
+    use failure::{Error, Fail};
     use hex::{FromHex, ToHex};
     use rmp_serde::Deserializer;
     use serde::Deserialize;
     use serde_json::Value;
     use sgx_types::sgx_enclave_id_t;
+    use std::collections::BTreeMap;
     use std::str;
     use common_u::errors;
 
@@ -93,83 +294,117 @@ pub(self) mod handling {
                 used_gas: self.used_gas,
                 output: self.output.to_hex(),
                 signature: self.signature.to_hex(),
+                result_signature: None,
+            };
+            IpcResponse::FailedTask { result }
+        }
+    }
+
+    impl WasmTaskFailure {
+        /// A failed computation also has a signature over the full result, bound to the task id.
+        pub fn into_compute_response(self) -> IpcResponse {
+            let result = IpcResults::FailedTask {
+                used_gas: self.used_gas,
+                output: self.output.to_hex(),
+                signature: self.signature.to_hex(),
+                result_signature: Some(self.result_signature.to_hex()),
             };
             IpcResponse::FailedTask { result }
         }
     }
 
     impl WasmTaskResult {
-        pub fn into_execute_response(self) -> IpcResponse {
+        pub fn into_execute_response(self, snapshot: Option<IpcSnapshot>) -> IpcResponse {
+            // Without a state change the enclave doesn't generate a delta (and the delta it reports is empty).
+            let delta = if self.delta.value.is_empty() { None } else { Some(self.delta.into()) };
             let result = IpcResults::ComputeResult {
                 used_gas: self.used_gas,
                 output: self.output.to_hex(),
-                delta: self.delta.into(),
+                delta,
                 ethereum_address: self.eth_contract_addr.to_hex(),
                 ethereum_payload: self.eth_payload.to_hex(),
                 signature: self.signature.to_hex(),
+                result_signature: self.result_signature.to_hex(),
+                peak_memory_bytes: self.peak_memory_bytes,
+                snapshot,
             };
             IpcResponse::ComputeTask { result }
         }
 
-        pub fn into_deploy_response(self, bytecode: &[u8]) -> IpcResponse {
+        pub fn into_deploy_response(self, bytecode: &[u8], gas_multiplier: GasMultiplier, capabilities: HostCapabilities) -> IpcResponse {
             let result = IpcResults::DeployResult {
                 pre_code_hash: bytecode.keccak256().to_hex(),
                 used_gas: self.used_gas,
+                constructor_gas: self.constructor_gas,
+                storage_gas: self.storage_gas,
                 output: self.output.to_hex(), // TODO: Return output
                 delta: self.delta.into(),
                 ethereum_address: self.eth_contract_addr.to_hex(),
                 ethereum_payload: self.eth_payload.to_hex(),
                 signature: self.signature.to_hex(),
+                receipt_signature: self.result_signature.to_hex(),
+                settings: ContractSettings { gas_multiplier, capabilities, mac: self.settings_mac },
             };
             IpcResponse::DeploySecretContract { result }
         }
     }
 
     #[logfn(TRACE)]
-    pub fn get_registration_params(eid: sgx_enclave_id_t, spid: &str, retries: u32) -> ResponseResult {
+    pub fn get_registration_params(eid: sgx_enclave_id_t, attestation: &AttestationConfig) -> ResponseResult {
         let sigining_key = equote::get_register_signing_address(eid)?;
 
-        let enc_quote = equote_tools::retry_quote(eid, spid, 18)?;
+        let enc_quote = equote_tools::retry_quote(eid, &attestation.spid, 18)?;
 
         // *Important* `option_env!()` runs on *Compile* time.
         // This means that if you want Simulation mode you need to run `export SGX_MODE=SW` Before compiling.
-        let (signature, report_hex) = if option_env!("SGX_MODE").unwrap_or_default() == "SW" { // Simulation Mode
+        let (signature, report_hex, quote_status, warning) = if option_env!("SGX_MODE").unwrap_or_default() == "SW" { // Simulation Mode
             let report =  enc_quote.as_bytes().to_hex();
             let sig = String::new();
-            (sig, report)
+            (sig, report, None, None)
         } else { // Hardware Mode
-            let service: AttestationService = AttestationService::new_with_retries(ATTESTATION_SERVICE_URL, retries);
+            let service: AttestationService = AttestationService::from_config(attestation);
             let response = service.get_report(enc_quote)?;
+            let warning = response.result.report.check_quote_status(attestation.out_of_date_policy)?;
+            if let Some(warning) = &warning {
+                warn!("{}", warning);
+            }
+            let quote_status = Some(response.result.report.quote_status().to_string());
             let report = response.result.report_string.as_bytes().to_hex();
             let sig = response.result.signature;
-            (sig, report)
+            (sig, report, quote_status, warning)
         };
 
-        let result = IpcResults::RegistrationParams { signing_key: sigining_key.to_hex(), report: report_hex, signature };
+        let result = IpcResults::RegistrationParams { signing_key: sigining_key.to_hex(), report: report_hex, signature, quote_status, warning };
 
         Ok(IpcResponse::GetRegistrationParams { result })
     }
 
     #[logfn(TRACE)]
     pub fn get_tip(db: &DB, input: &str) -> ResponseResult {
-        let address = ContractAddress::from_hex(&input)?;
+        let address = ContractAddress::from_hex(&input).or_bad_request()?;
         let (tip_key, tip_data) = db.get_tip::<DeltaKey>(&address)?;
 
         let key = tip_key.key_type.unwrap_delta();
-        let delta = IpcDelta { contract_address: None, key, data: Some(tip_data) };
+        let delta = IpcDelta { contract_address: None, key, data: Some(tip_data), ..Default::default() };
         Ok(IpcResponse::GetTip { result: delta })
     }
 
     #[logfn(TRACE)]
     pub fn get_tips(db: &DB, input: &[String]) -> ResponseResult {
-        let mut tips_results = Vec::with_capacity(input.len());
-        let addresses : Vec<ContractAddress> = input.iter().map(|data| ContractAddress::from_hex(&data).unwrap()).collect();
-        let tips = db.get_tips::<DeltaKey>(&addresses)?;
-        for (key, data) in tips {
-            let delta = IpcDelta::from_delta_key(key, &data)?;
-            tips_results.push(delta);
+        let mut tips = Vec::with_capacity(input.len());
+        let mut missing = Vec::new();
+        for address in input {
+            let contract_address = ContractAddress::from_hex(&address).or_bad_request()?;
+            match db.get_tip::<DeltaKey>(&contract_address) {
+                Ok((key, data)) => tips.push(IpcDelta::from_delta_key(key, &data)?),
+                // A contract without deltas is reported by address instead of failing the whole request.
+                Err(e) => match errors::is_db_err_type(e)? {
+                    errors::DBErr { kind: errors::DBErrKind::MissingKey(_), .. } => missing.push(address.clone()),
+                    e => return Err(e.into()),
+                },
+            }
         }
-        Ok(IpcResponse::GetTips { result: IpcResults::Tips(tips_results) })
+        Ok(IpcResponse::GetTips { result: IpcResults::TipsByAddress { tips, missing } })
     }
 
     #[logfn(TRACE)]
@@ -192,17 +427,55 @@ pub(self) mod handling {
     #[logfn(TRACE)]
     pub fn get_delta(db: &DB, input: IpcDelta) -> ResponseResult {
         let address = input.contract_address.ok_or(P2PErr { cmd: "GetDelta".to_string(), msg: "Address Missing".to_string() })?;
-        let address = ContractAddress::from_hex(&address)?;
+        let address = ContractAddress::from_hex(&address).or_bad_request()?;
         let delta_key = DeltaKey::new(address, Stype::Delta(input.key));
         let delta = db.get_delta(delta_key)?;
         Ok(IpcResponse::GetDelta { result: IpcResults::Delta(delta.to_hex()) })
     }
 
+    /// Only for debugging, the enclave refuses to decode deltas in release builds.
+    #[logfn(TRACE)]
+    pub fn get_delta_decoded(db: &DB, address: &str, index: u32, eid: sgx_enclave_id_t) -> ResponseResult {
+        if !cfg!(debug_assertions) {
+            return Err(errors::InvalidRequest { msg: "GetDeltaDecoded is only available in debug builds".to_string() }.into());
+        }
+        let address = ContractAddress::from_hex(address).or_bad_request()?;
+        let delta = db.get_delta(DeltaKey::new(address, Stype::Delta(index)))?;
+        let decoded = km_u::decode_delta(eid, &address, index, &delta)?;
+        let operations: Vec<IpcPatchOperation> = serde_json::from_slice(&decoded)?;
+        Ok(IpcResponse::GetDeltaDecoded { result: IpcResults::PatchOperations(operations) })
+    }
+
+    /// Only for debugging, the enclave refuses to rebuild past states in release builds.
+    #[logfn(TRACE)]
+    pub fn get_state_at(db: &mut DB, address: &str, index: u32, eid: sgx_enclave_id_t) -> ResponseResult {
+        if !cfg!(debug_assertions) {
+            return Err(errors::InvalidRequest { msg: "GetStateAt is only available in debug builds".to_string() }.into());
+        }
+        let address = ContractAddress::from_hex(address).or_bad_request()?;
+        let state = km_u::get_state_at(db, eid, &address, index)?;
+        let state: Value = serde_json::from_slice(&state)?;
+        Ok(IpcResponse::GetStateAt { result: IpcResults::State(state) })
+    }
+
+    #[logfn(TRACE)]
+    pub fn state_fingerprint(db: &mut DB, address: &str, eid: sgx_enclave_id_t) -> ResponseResult {
+        let address = ContractAddress::from_hex(address).or_bad_request()?;
+        let (delta_hash, delta_index, state_hash) = km_u::state_fingerprint(db, eid, &address)?;
+        let result = IpcResults::StateFingerprint {
+            address: address.to_hex(),
+            delta_hash: delta_hash.to_hex(),
+            delta_index,
+            state_hash: state_hash.to_hex(),
+        };
+        Ok(IpcResponse::StateFingerprint { result })
+    }
+
     #[logfn(TRACE)]
     pub fn get_deltas(db: &DB, input: &[IpcDeltasRange]) -> ResponseResult {
         let mut results = Vec::with_capacity(input.len());
         for data in input {
-            let address = ContractAddress::from_hex(&data.address)?;
+            let address = ContractAddress::from_hex(&data.address).or_bad_request()?;
             let from = DeltaKey::new(address, Stype::Delta(data.from));
             let to = DeltaKey::new(address, Stype::Delta(data.to));
 
@@ -222,25 +495,85 @@ pub(self) mod handling {
 
     #[logfn(TRACE)]
     pub fn get_contract(db: &DB, input: &str) -> ResponseResult {
-        let address = ContractAddress::from_hex(&input)?;
+        let address = ContractAddress::from_hex(&input).or_bad_request()?;
         let data = db.get_contract(address).unwrap_or_default();
-        Ok(IpcResponse::GetContract { result: IpcResults::GetContract{address: address.to_hex(), bytecode: data} })
+        let settings = db.get_settings(address).ok();
+        Ok(IpcResponse::GetContract { result: IpcResults::GetContract { address: address.to_hex(), bytecode: data, settings } })
+    }
+
+    #[logfn(TRACE)]
+    pub fn get_contracts(db: &DB, addresses: &[String]) -> ResponseResult {
+        let mut contracts = BTreeMap::new();
+        for address in addresses {
+            let address = ContractAddress::from_hex(&address).or_bad_request()?;
+            // A missing contract is reported as null instead of failing the whole request, any other failure fails it.
+            let contract = match db.get_contract(address) {
+                Ok(bytecode) => Some(bytecode),
+                Err(e) => match errors::is_db_err_type(e)? {
+                    errors::DBErr { kind: errors::DBErrKind::MissingKey(_), .. } => None,
+                    e => return Err(e.into()),
+                },
+            };
+            contracts.insert(address.to_hex(), contract);
+        }
+        Ok(IpcResponse::GetContracts { result: IpcResults::Contracts(contracts) })
     }
 
     #[logfn(TRACE)]
-    pub fn update_new_contract(db: &mut DB, address: String, bytecode: &[u8]) -> ResponseResult {
-        let address_arr = ContractAddress::from_hex(&address)?;
+    pub fn get_contract_meta(db: &DB, input: &str) -> ResponseResult {
+        let address = ContractAddress::from_hex(&input).or_bad_request()?;
+        let key = DeltaKey::new(address, Stype::Metadata);
+        // Only a contract deployed without metadata is reported as missing it, any other failure is passed on.
+        let data = match db.read(&key) {
+            Ok(data) => data,
+            Err(e) => match errors::is_db_err_type(e)? {
+                errors::DBErr { kind: errors::DBErrKind::MissingKey(_), .. } =>
+                    return Err(errors::DBErr { command: "get_contract_meta".to_string(), kind: errors::DBErrKind::MissingKey(input.to_string()) }.into()),
+                e => return Err(e.into()),
+            },
+        };
+        let metadata: ContractMetadata = serde_json::from_slice(&data)?;
+        Ok(IpcResponse::GetContractMeta { result: IpcResults::ContractMeta { address: address.to_hex(), metadata } })
+    }
+
+    /// A contract that didn't run any task yet has all of its metrics at zero.
+    #[logfn(TRACE)]
+    pub fn get_metrics(input: &str) -> ResponseResult {
+        let address = ContractAddress::from_hex(&input).or_bad_request()?;
+        let metrics = metrics::METRICS.get(&address).unwrap_or_default();
+        let result = IpcResults::Metrics {
+            address: address.to_hex(),
+            tasks: metrics.tasks,
+            failed_tasks: metrics.failed_tasks,
+            replayed_tasks: metrics.replayed_tasks,
+            average_gas: metrics.average_gas(),
+            average_instructions: metrics.average_instructions(),
+            peak_memory_bytes: metrics.peak_memory_bytes,
+            average_delta_bytes: metrics.average_delta_bytes(),
+        };
+        Ok(IpcResponse::GetMetrics { result })
+    }
+
+    #[logfn(TRACE)]
+    pub fn update_new_contract(db: &mut DB, address: String, bytecode: &[u8], settings: Option<ContractSettings>) -> ResponseResult {
+        let address_arr = ContractAddress::from_hex(&address).or_bad_request()?;
+        ensure_not_destroyed(db, address_arr, "UpdateNewContract")?;
         let delta_key = DeltaKey::new(address_arr, Stype::ByteCode);
         db.force_update(&delta_key, bytecode)?;
+        if let Some(settings) = settings {
+            db.set_settings(address_arr, settings)?;
+        }
         Ok(IpcResponse::UpdateNewContract { address, result: IpcResults::Status(Status::Passed) })
     }
 
     #[logfn(TRACE)]
-    pub fn update_new_contract_on_deployment(db: &mut DB, address: String, bytecode: &str, delta: IpcDelta) -> ResponseResult {
+    pub fn update_new_contract_on_deployment(db: &mut DB, address: String, bytecode: &str, delta: IpcDelta,
+                                             settings: Option<ContractSettings>) -> ResponseResult {
         let mut tuples = Vec::with_capacity(DEPLOYMENT_VALS_LEN);
-        let address_arr = ContractAddress::from_hex(&address)?;
+        let address_arr = ContractAddress::from_hex(&address).or_bad_request()?;
+        ensure_not_destroyed(db, address_arr, "UpdateNewContractOnDeployment")?;
 
-        let bytecode = bytecode.from_hex()?;
+        let bytecode = bytecode.from_hex().or_bad_request()?;
         let bytecode_delta_key = DeltaKey::new(address_arr, Stype::ByteCode);
         tuples.push((bytecode_delta_key, &bytecode));
 
@@ -252,6 +585,8 @@ pub(self) mod handling {
         let mut status = Status::Passed;
         if results.into_iter().any(| result | result.is_err()) {
             status = Status::Failed;
+        } else if let Some(settings) = settings {
+            db.set_settings(address_arr, settings)?;
         }
         // since a new delta and bytecode were added, the state is no longer updated
         db.update_state_status(false);
@@ -261,7 +596,7 @@ pub(self) mod handling {
 
     #[logfn(TRACE)]
     pub fn remove_contract(db: &mut DB, address: String) -> ResponseResult {
-        let addr_arr = ContractAddress::from_hex(&address)?;
+        let addr_arr = ContractAddress::from_hex(&address).or_bad_request()?;
         // the key_type of dk is irrelevant since we are removing all the contract data
         let dk = DeltaKey::new(addr_arr, Stype::ByteCode);
         let result = match db.delete_contract(&dk) {
@@ -278,40 +613,116 @@ pub(self) mod handling {
     }
 
     #[logfn(TRACE)]
-    pub fn update_deltas(db: &mut DB, deltas: Vec<IpcDelta>) -> ResponseResult {
+    pub fn set_retention_policy(db: &mut DB, address: String, policy: RetentionPolicy) -> ResponseResult {
+        let addr_arr = ContractAddress::from_hex(&address).or_bad_request()?;
+        db.set_retention_policy(addr_arr, policy)?;
+        Ok(IpcResponse::SetRetentionPolicy { address, result: IpcResults::Status(Status::Passed) })
+    }
+
+    /// Only the deployer of a contract can destroy it, so a contract deployed without metadata can't be destroyed.
+    #[logfn(TRACE)]
+    pub fn destroy_contract(db: &mut DB, address: String, sig: &str) -> ResponseResult {
+        let addr_arr = ContractAddress::from_hex(&address).or_bad_request()?;
+        let metadata_key = DeltaKey::new(addr_arr, Stype::Metadata);
+        let metadata: ContractMetadata = match db.read(&metadata_key) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) => match errors::is_db_err_type(e)? {
+                errors::DBErr { kind: errors::DBErrKind::MissingKey(_), .. } =>
+                    return Err(P2PErr { cmd: "DestroyContract".to_string(), msg: "The contract has no deployer to authorize it".to_string() }.into()),
+                e => return Err(e.into()),
+            },
+        };
+        let (tip, _) = db.get_tip::<DeltaKey>(&addr_arr)?;
+        let sig: Vec<u8> = sig.from_hex().or_bad_request()?;
+        let deployer: Vec<u8> = metadata.deployer.from_hex()?;
+        if sig.len() != 65 || deployer.len() != 20 {
+            return Err(P2PErr { cmd: "DestroyContract".to_string(), msg: "Bad Signature Length".to_string() }.into());
+        }
+        let mut sig_arr = [0u8; 65];
+        sig_arr.copy_from_slice(&sig);
+        let mut deployer_arr = [0u8; 20];
+        deployer_arr.copy_from_slice(&deployer);
+        let message = destroy_contract_message(&addr_arr, tip.key_type.unwrap_delta());
+        if SignatureDomain::DestroyContract.verify(&message, sig_arr, &deployer_arr).is_err() {
+            return Err(P2PErr { cmd: "DestroyContract".to_string(), msg: "Not signed by the deployer".to_string() }.into());
+        }
+        db.destroy_contract(addr_arr)?;
+        Ok(IpcResponse::DestroyContract { address, result: IpcResults::Status(Status::Passed) })
+    }
+
+    #[logfn(TRACE)]
+    pub fn compact_db(db: &mut DB) -> ResponseResult {
+        let size_before = db.size_on_disk()?;
+        let size_after = db.compact()?;
+        Ok(IpcResponse::CompactDb { result: IpcResults::Compaction { size_before, size_after } })
+    }
+
+    #[logfn(TRACE)]
+    pub fn update_deltas(db: &mut DB, deltas: Vec<IpcDelta>, eid: sgx_enclave_id_t, trust_unsigned: bool) -> ResponseResult {
         let mut tuples = Vec::with_capacity(deltas.len());
+        let mut rejected = Vec::new();
 
         for delta in deltas.into_iter() {
             let address = delta.contract_address.ok_or(P2PErr { cmd: "UpdateDeltas".to_string(), msg: "Address Missing".to_string() })?;
-            let address = ContractAddress::from_hex(&address)?;
+            let address = ContractAddress::from_hex(&address).or_bad_request()?;
             let data =
                 delta.data.ok_or(P2PErr { cmd: "UpdateDeltas".to_string(), msg: "Delta Data Missing".to_string() })?;
             let delta_key = DeltaKey::new(address, Stype::Delta(delta.key));
-            tuples.push((delta_key, data));
+            let verified = ensure_not_destroyed(db, address, "UpdateDeltas")
+                .and_then(|_| verify_delta(eid, &delta_key, &data, delta.signature, trust_unsigned));
+            match verified {
+                Ok(()) => tuples.push((delta_key, data)),
+                Err(e) => {
+                    warn!("Rejected delta {} of {}: {}", delta.key, address.to_hex(), e);
+                    rejected.push(delta_key);
+                }
+            }
         }
         let results = db.insert_tuples(&tuples);
-        let mut errors = Vec::with_capacity(tuples.len());
-        let mut overall_status = Status::Passed;
+        let mut statuses = IpcStatusResultsBuilder::new();
+        for deltakey in rejected {
+            let key = Some(deltakey.key_type.unwrap_delta() as i64);
+            statuses.push(deltakey.contract_address.to_hex(), key, Status::Failed);
+        }
         for ((deltakey, _), res) in tuples.into_iter().zip(results.into_iter()) {
-            let status = if res.is_err() {
-                overall_status = Status::Failed;
-                Status::Failed
-            } else {
-                Status::Passed
-            };
+            let status = if res.is_err() { Status::Failed } else { Status::Passed };
             let key = Some(deltakey.key_type.unwrap_delta() as i64);
-            let address = deltakey.contract_address.to_hex();
-            let delta = IpcStatusResult { address, key, status };
-            errors.push(delta);
+            statuses.push(deltakey.contract_address.to_hex(), key, status);
         }
         // since a new delta was added the state is no longer updated
         db.update_state_status(false);
-        let result = IpcResults::DeltasResult { status: overall_status, errors };
+        let result = statuses.into_deltas_result();
         Ok(IpcResponse::UpdateDeltas {result})
     }
 
+    /// A sync mustn't bring back a destroyed contract, its tombstone stays after everything else was deleted.
+    fn ensure_not_destroyed(db: &DB, address: ContractAddress, cmd: &str) -> Result<(), Error> {
+        if db.is_destroyed(address)? {
+            return Err(P2PErr { cmd: cmd.to_string(), msg: format!("Contract {} was destroyed", address.to_hex()) }.into());
+        }
+        Ok(())
+    }
+
+    /// Unsigned deltas are accepted only if `trust_unsigned` is set,
+    /// a signed delta must be signed by a worker the KM node selected for its contract, the enclave knows which.
+    fn verify_delta(eid: sgx_enclave_id_t, delta_key: &DeltaKey, data: &[u8], signature: Option<String>,
+                    trust_unsigned: bool) -> Result<(), Error> {
+        let signature = match signature {
+            Some(signature) => signature,
+            None if trust_unsigned => return Ok(()),
+            None => return Err(P2PErr { cmd: "UpdateDeltas".to_string(), msg: "Delta Signature Missing".to_string() }.into()),
+        };
+        let signature: Vec<u8> = signature.from_hex().or_bad_request()?;
+        if signature.len() != 65 {
+            return Err(P2PErr { cmd: "UpdateDeltas".to_string(), msg: "Bad Delta Signature Length".to_string() }.into());
+        }
+        let mut sig = [0u8; 65];
+        sig.copy_from_slice(&signature);
+        km_u::verify_delta(eid, &delta_key.contract_address, delta_key.key_type.unwrap_delta(), data, &sig)
+    }
+
     fn delete_data_from_db(db: &mut DB, addr: &str, key_type: Stype) -> Result<IpcResults, Error> {
-        let addr_arr = ContractAddress::from_hex(addr)?;
+        let addr_arr = ContractAddress::from_hex(addr).or_bad_request()?;
         let dk = DeltaKey::new(addr_arr, key_type);
         match db.delete(&dk) {
             Ok(_) => Ok(IpcResults::Status(Status::Passed)),
@@ -326,35 +737,35 @@ pub(self) mod handling {
 
     #[logfn(TRACE)]
     pub fn remove_deltas(db: &mut DB, input: Vec<IpcDeltasRange>) -> ResponseResult {
-        let mut errors = Vec::new();
-        let mut overall_status = Status::Passed;
+        let mut statuses = IpcStatusResultsBuilder::new();
         for addr_deltas in input {
             for key in addr_deltas.from..addr_deltas.to {
                 let delta_res = delete_data_from_db(db,&addr_deltas.address.clone(), Stype::Delta(key))?;
-                if let IpcResults::Status(Status::Failed) = delta_res {
-                    let failed_delta = IpcStatusResult { address: addr_deltas.address.clone() , key: Some(key as i64), status: Status::Failed };
-                    errors.push(failed_delta);
-                    overall_status = Status::Failed;
+                if delta_res.has_failures() {
+                    statuses.push(addr_deltas.address.clone(), Some(key as i64), Status::Failed);
                 }
             }
             let status_res = delete_data_from_db(db,&addr_deltas.address, Stype::State)?;
-            if let IpcResults::Status(Status::Failed) = status_res {
-                let failed_delta = IpcStatusResult { address: addr_deltas.address.clone() , key: Some(FAILED_STATE), status: Status::Failed };
-                errors.push(failed_delta);
-                overall_status = Status::Failed;
+            if status_res.has_failures() {
+                statuses.push(addr_deltas.address.clone(), Some(FAILED_STATE), Status::Failed);
             }
         }
         db.update_state_status(false);
-        let result = IpcResults::DeltasResult { status: overall_status, errors };
+        let result = statuses.into_deltas_result();
         Ok(IpcResponse::RemoveDeltas {result})
     }
 
     #[logfn(TRACE)]
-    pub fn get_dh_user_key(_user_pubkey: &str, eid: sgx_enclave_id_t) -> ResponseResult {
+    pub fn get_dh_user_key(_user_pubkey: &str, sig_version: Option<u32>, eid: sgx_enclave_id_t) -> ResponseResult {
         let mut user_pubkey = [0u8; 64];
         user_pubkey.clone_from_slice(&_user_pubkey.from_hex().unwrap());
+        let sig_version = match sig_version {
+            Some(version) => UserKeySigVersion::from_u32(version)
+                .map_err(|_| errors::InvalidRequest { msg: format!("Unknown signature version {}", version) })?,
+            None => UserKeySigVersion::default(),
+        };
 
-        let (msg, sig) = km_u::get_user_key(eid, &user_pubkey)?;
+        let (msg, sig) = km_u::get_user_key(eid, &user_pubkey, sig_version)?;
 
         let mut des = Deserializer::new(&msg[..]);
         let res: Value = Deserialize::deserialize(&mut des).unwrap();
@@ -375,26 +786,28 @@ pub(self) mod handling {
 
     #[logfn(TRACE)]
     pub fn ptt_response(db: &mut DB, response: &PrincipalResponse, eid: sgx_enclave_id_t) -> ResponseResult {
-        let msg = response.response.from_hex()?;
+        let msg = response.response.from_hex().or_bad_request()?;
         km_u::ptt_res(eid, &msg)?;
         let res = km_u::ptt_build_state(db, eid)?;
         db.update_state_status(true);
-        let result: Vec<_> = res
+        let result = res
             .into_iter()
-            .map(|a| IpcStatusResult{ address: a.to_hex(), status: Status::Failed, key: None })
-            .collect();
-
-        let result = IpcResults::Errors(result);
+            .map(|a| (a.to_hex(), None, Status::Failed))
+            .collect::<IpcStatusResultsBuilder>()
+            .into_errors();
         Ok(IpcResponse::PTTResponse {result})
     }
 
     pub fn deploy_contract(db: &mut DB, input: IpcTask, eid: sgx_enclave_id_t) -> ResponseResult {
         let bytecode = input.pre_code.expect("Bytecode Missing");
-        let contract_address = ContractAddress::from_hex(&input.address)?;
-        let enc_args = input.encrypted_args.from_hex()?;
-        let constructor = input.encrypted_fn.from_hex()?;
+        let contract_address = ContractAddress::from_hex(&input.address).or_bad_request()?;
+        let enc_args = input.encrypted_args.from_hex().or_bad_request()?;
+        let constructor = input.encrypted_fn.from_hex().or_bad_request()?;
         let mut user_pubkey = [0u8; 64];
-        user_pubkey.clone_from_slice(&input.user_dhkey.from_hex()?);
+        user_pubkey.clone_from_slice(&input.user_dhkey.from_hex().or_bad_request()?);
+        let deployer = input.deployer()?;
+        let gas_multiplier = input.gas_multiplier.unwrap_or_default();
+        let capabilities = input.capabilities()?;
         let result = wasm::deploy(
             db,
             eid,
@@ -403,14 +816,22 @@ pub(self) mod handling {
             &enc_args,
             &contract_address,
             &user_pubkey,
-            input.gas_limit)?;
+            &deployer,
+            input.gas_limit(),
+            capabilities,
+            gas_multiplier)?;
+        metrics::METRICS.record(contract_address, &result);
 
         match result {
             WasmResult::WasmTaskResult(v) => {
                 // Save the ExeCode into the DB.
                 let key = DeltaKey::new(contract_address, Stype::ByteCode);
                 db.create(&key, &v.output)?;
-                let ipc_response = v.into_deploy_response(&bytecode);
+                if let Some(metadata) = input.metadata {
+                    let key = DeltaKey::new(contract_address, Stype::Metadata);
+                    db.create(&key, &serde_json::to_vec(&metadata)?)?;
+                }
+                let ipc_response = v.into_deploy_response(&bytecode, gas_multiplier, capabilities);
                 debug!("deploy_contract() => Ok({})", ipc_response.display_without_bytecode());
                 Ok(ipc_response)
             },
@@ -422,20 +843,41 @@ pub(self) mod handling {
         }
     }
 
+    /// A rejected bytecode is a valid response with the reason, not an error.
+    /// It's validated with the same deployment options a `DeploySecretContract` would use.
+    #[logfn(TRACE)]
+    pub fn validate_contract(bytecode: &[u8], forbid: &[Capability], gas_multiplier: Option<GasMultiplier>, eid: sgx_enclave_id_t) -> ResponseResult {
+        let capabilities = capabilities_without(forbid)?;
+        let gas_multiplier = match gas_multiplier {
+            Some(multiplier) => {
+                check_gas_multiplier(multiplier)?;
+                multiplier
+            }
+            None => GasMultiplier::default(),
+        };
+        let reason = wasm::validate_contract(eid, bytecode, gas_multiplier, capabilities)?;
+        let result = IpcResults::Validation { valid: reason.is_none(), reason };
+        Ok(IpcResponse::ValidateContract { result })
+    }
+
     #[logfn(DEBUG)]
-    pub fn compute_task(db: &mut DB, input: IpcTask, eid: sgx_enclave_id_t) -> ResponseResult {
-        let enc_args = input.encrypted_args.from_hex()?;
-        let address = ContractAddress::from_hex(&input.address)?;
-        let callable = input.encrypted_fn.from_hex()?;
+    pub fn compute_task(db: &mut DB, input: IpcTask, eid: sgx_enclave_id_t, snapshot_interval: u32) -> ResponseResult {
+        input.ensure_no_deploy_options()?;
+        let enc_args = input.encrypted_args.from_hex().or_bad_request()?;
+        let address = ContractAddress::from_hex(&input.address).or_bad_request()?;
+        let callable = input.encrypted_fn.from_hex().or_bad_request()?;
         let mut user_pubkey = [0u8; 64];
-        user_pubkey.clone_from_slice(&input.user_dhkey.from_hex()?);
+        user_pubkey.clone_from_slice(&input.user_dhkey.from_hex().or_bad_request()?);
+        let mut replay_pubkey = [0u8; 64];
+        replay_pubkey.clone_from_slice(&replay_dhkey.from_hex().or_bad_request()?);
+        let task_key = task_key.from_hex().or_bad_request()?;
+        let task_id = input.task_id()?;
+        let bytecode = deployed_bytecode(db, address, &input.address)?;
 
         if !db.get_state_status() {
             let _res = km_u::ptt_build_state(db, eid)?;
             db.update_state_status(true);
         }
-        let bytecode = db.get_contract(address)?;
-
 
         let result = wasm::execute(
             db,
@@ -445,11 +887,129 @@ pub(self) mod handling {
             &enc_args,
             &user_pubkey,
             &address,
-            input.gas_limit)?;
+            &task_id,
+            input.gas_limit())?;
+        metrics::METRICS.record(address, &result);
 
         match result {
-            WasmResult::WasmTaskResult(v) => Ok(v.into_execute_response()),
-            WasmResult::WasmTaskFailure(v) => Ok(v.into())
+            WasmResult::WasmTaskResult(v) => {
+                let snapshot = auto_snapshot(db, address, &v.delta, snapshot_interval)?;
+                apply_retention(db, address, &v.delta);
+                Ok(v.into_execute_response(snapshot))
+            }
+            WasmResult::WasmTaskFailure(v) => Ok(v.into_compute_response())
+        }
+    }
+
+    /// Replays the computation task `input` on the state it originally ran on and checks that it produces `claim`,
+    /// the DB isn't changed. `input` and `claim` are exactly what the worker got and returned, so the verdict is bound to its signed result.
+    /// `replay_dhkey` needs a fresh `NewTaskEncryptionKey` like any other task, `task_key` is the key of the task encrypted with the key it yields.
+    #[logfn(DEBUG)]
+    pub fn replay_compute(db: &mut DB, input: IpcTask, claim: IpcClaim, replay_dhkey: &str, task_key: &str, eid: sgx_enclave_id_t) -> ResponseResult {
+        input.ensure_no_deploy_options()?;
+        let enc_args = input.encrypted_args.from_hex().or_bad_request()?;
+        let address = ContractAddress::from_hex(&input.address).or_bad_request()?;
+        let callable = input.encrypted_fn.from_hex().or_bad_request()?;
+        let mut user_pubkey = [0u8; 64];
+        user_pubkey.clone_from_slice(&input.user_dhkey.from_hex().or_bad_request()?);
+        let mut replay_pubkey = [0u8; 64];
+        replay_pubkey.clone_from_slice(&replay_dhkey.from_hex().or_bad_request()?);
+        let task_key = task_key.from_hex().or_bad_request()?;
+        let task_id = input.task_id()?;
+        let bytecode = deployed_bytecode(db, address, &input.address)?;
+
+        let delta = match claim.delta {
+            Some(delta) => match delta.data {
+                Some(data) => Some(Delta { key: DeltaKey::new(address, Stype::Delta(delta.key)), value: data }),
+                None => bail!("The claimed delta {} of {} has no data", delta.key, input.address),
+            },
+            None => None,
+        };
+        let claim = ReplayClaim { output: claim.output.from_hex().or_bad_request()?.into_boxed_slice(), delta, used_gas: claim.used_gas };
+
+        if !db.get_state_status() {
+            let _res = km_u::ptt_build_state(db, eid)?;
+            db.update_state_status(true);
+        }
+
+        let (matches, signature, stats) =
+            wasm::replay_compute(db, eid, &bytecode, &callable, &enc_args, &user_pubkey, &replay_pubkey, &task_key,
+                                 &address, &task_id, input.gas_limit(), &claim)?;
+        metrics::METRICS.record_replay(address, &stats);
+        Ok(IpcResponse::ReplayCompute { result: IpcResults::Replay { matches, signature: signature.to_hex() } })
+    }
+
+    /// The bytecode is only stored once the contract is deployed, so without it there's nothing to compute on.
+    fn deployed_bytecode(db: &DB, address: ContractAddress, hex_address: &str) -> Result<Vec<u8>, Error> {
+        match db.get_contract(address) {
+            Ok(bytecode) => Ok(bytecode),
+            Err(e) => match errors::is_db_err_type(e)? {
+                // The DB error is kept as the cause, so the response reports it as a missing contract.
+                e @ errors::DBErr { kind: errors::DBErrKind::MissingKey(_), .. } => {
+                    if db.is_destroyed(address)? {
+                        Err(e.context(format!("Contract {} was destroyed", hex_address)).into())
+                    } else {
+                        Err(e.context(format!("Unknown contract {}, it was never deployed", hex_address)).into())
+                    }
+                }
+                e => Err(e.into()),
+            },
+        }
+    }
+
+    /// Deploy a contract and compute on it in one request, so the computation runs on the state the constructor left.
+    /// Either both succeed or neither leaves anything in the DB: if the deployment fails the computation doesn't run,
+    /// and if the computation fails or errors the deployment is undone. A failure is returned as is.
+    #[logfn(DEBUG)]
+    pub fn deploy_and_compute(db: &mut DB, deploy: IpcTask, mut compute: IpcTask, eid: sgx_enclave_id_t, snapshot_interval: u32) -> ResponseResult {
+        compute.address = deploy.address.clone();
+        let address = ContractAddress::from_hex(&deploy.address).or_bad_request()?;
+        let deploy = match deploy_contract(db, deploy, eid)? {
+            IpcResponse::DeploySecretContract { result } => result,
+            failure => return Ok(failure),
+        };
+        let compute = match compute_task(db, compute, eid, snapshot_interval) {
+            Ok(IpcResponse::ComputeTask { result }) => result,
+            Ok(IpcResponse::FailedTask { result }) => {
+                db.undo_deployment(address)?;
+                return Ok(IpcResponse::FailedTask { result });
+            }
+            Ok(other) => {
+                db.undo_deployment(address)?;
+                bail!("Unexpected response to the computation: {:?}", other)
+            }
+            Err(e) => {
+                db.undo_deployment(address)?;
+                return Err(e);
+            }
+        };
+        Ok(IpcResponse::DeployAndCompute { deploy, compute })
+    }
+
+    /// Every `snapshot_interval` deltas the state of the contract, which is stored next to its latest delta,
+    /// is returned as a snapshot so that peers don't need the whole delta chain to reconstruct it.
+    /// A `snapshot_interval` of 0 disables the snapshots.
+    fn auto_snapshot(db: &DB, address: ContractAddress, delta: &Delta, snapshot_interval: u32) -> Result<Option<IpcSnapshot>, Error> {
+        if snapshot_interval == 0 || delta.value.is_empty() {
+            return Ok(None);
+        }
+        let index = delta.key.key_type.unwrap_delta();
+        if index == 0 || index % snapshot_interval != 0 {
+            return Ok(None);
+        }
+        let state = db.read(&DeltaKey::new(address, Stype::State))?;
+        Ok(Some(IpcSnapshot { index, state: state.to_hex() }))
+    }
+
+    /// A computation that changed the state stores it together with the new delta,
+    /// so every delta before that one can be pruned according to the retention policy of the contract.
+    /// They're pruned in the background, failing to start it doesn't fail the computation, the deltas are pruned after the next one.
+    fn apply_retention(db: &mut DB, address: ContractAddress, delta: &Delta) {
+        if delta.value.is_empty() {
+            return;
+        }
+        if let Err(e) = db.prune_deltas_in_background(address, delta.key.key_type.unwrap_delta()) {
+            warn!("Failed pruning the deltas of {}: {}", address.to_hex(), e);
         }
     }
 
@@ -458,9 +1018,13 @@ pub(self) mod handling {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::db::{DeltaKey, P2PCalls, Stype, tests::create_test_db};
+    use crate::db::{CRUDInterface, DeltaKey, P2PCalls, RetentionPolicy, Stype, tests::create_test_db};
     use serde_json::Value;
     use enigma_types::ContractAddress;
+    use hex::ToHex;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
 
     pub const SPID: &str = "B0335FD3BC1CCA8F804EB98A6420592D";
     pub const RETRIES: u32 = 10;
@@ -507,9 +1071,139 @@ mod test {
             res.unwrap();
         }
 
+        let attestation = AttestationConfig { spid: SPID.to_string(), retries: RETRIES, ..Default::default() };
         let conn = "tcp://*:2456";
         let server = IpcListener::new(conn);
-        server.run(|multi| handle_message(&mut db, multi,  SPID, enclave.geteid(), RETRIES)).wait().unwrap();
+        server.run(|multi| handle_message(&mut db, multi, &attestation, enclave.geteid(), false, 0, GasLimits::default())).wait().unwrap();
+    }
+
+    fn multipart(payload: &[u8], threshold: usize) -> Multipart {
+        let mut request = Multipart::new();
+        for frame in framing::split(payload, threshold) {
+            request.push_back(Message::from(frame));
+        }
+        request
     }
 
+    #[test]
+    fn test_framed_request_and_response() {
+        let bytecode: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let respond = |chunk_size| {
+            let request = IpcRequest::GetContract { input: "00".repeat(32), chunk_size };
+            let request = serde_json::to_vec(&IpcMessageRequest::from_request(request, "framed".to_string())).unwrap();
+            assert!(framing::split(&request, 16).len() > 1);
+            let mut calls = 0;
+            let response = respond_to_each(multipart(&request, 16), |req| {
+                calls += 1;
+                assert!(match req { IpcRequest::GetContract { .. } => true, _ => false });
+                let result = IpcResults::GetContract { address: "00".repeat(32), bytecode: bytecode.clone(), settings: None };
+                Ok(IpcResponse::GetContract { result })
+            });
+            assert!(calls <= 1);
+            response
+        };
+
+        let response = respond(Some(1000));
+        assert!(response.len() > 1);
+        assert!(response.iter().all(|frame| frame.len() <= framing::HEADER_LEN + 1000));
+        let payloads = framing::join(response.iter().map(|msg| &msg[..]), framing::MAX_PAYLOAD_LEN).unwrap();
+        assert_eq!(payloads.len(), 1);
+        let response: Value = serde_json::from_slice(&payloads[0]).unwrap();
+        assert_eq!(response["id"], "framed");
+        let returned: Vec<u8> = serde_json::from_value(response["result"]["bytecode"].clone()).unwrap();
+        assert_eq!(returned, bytecode);
+
+        // Without a chunk size the response is a single message, however large.
+        let response = respond(None);
+        assert_eq!(response.len(), 1);
+        let response: Value = serde_json::from_slice(&response[0]).unwrap();
+        let returned: Vec<u8> = serde_json::from_value(response["result"]["bytecode"].clone()).unwrap();
+        assert_eq!(returned, bytecode);
+
+        let response = respond(Some(0));
+        assert_eq!(response.len(), 1);
+        let response: Value = serde_json::from_slice(&response[0]).unwrap();
+        assert_eq!(response["type"], "Error");
+    }
+
+    #[test]
+    fn test_malformed_request_is_answered() {
+        let request = br#"{"id":"bad","type":"ComputeTask","input":{"encryptedArgs":"","encryptedFn":"","userDHKey":"","forbid":["rand"]}}"#;
+        let mut request = multipart(request, request.len());
+        request.push_back(Message::from(&br#"{"id":"good","type":"GetAllTips"}"#[..]));
+        let mut calls = 0;
+        let response = respond_to_each(request, |_| {
+            calls += 1;
+            Ok(IpcResponse::GetAllTips { result: IpcResults::Tips(Vec::new()) })
+        });
+        // The malformed request doesn't stop the ones after it.
+        assert_eq!(calls, 1);
+        assert_eq!(response.len(), 2);
+        let bad: Value = serde_json::from_slice(&response[0]).unwrap();
+        assert_eq!((bad["id"].as_str(), bad["type"].as_str()), (Some("bad"), Some("Error")));
+        let good: Value = serde_json::from_slice(&response[1]).unwrap();
+        assert_eq!(good["id"], "good");
+    }
+
+    #[test]
+    fn test_read_requests_share_the_db() {
+        let (mut db, _dir) = create_test_db();
+        let address = ContractAddress::from([7u8; 32]);
+        db.create(&DeltaKey::new(address, Stype::Delta(0)), &b"delta 0"[..]).unwrap();
+        let db = Arc::new(ConcurrentDB::new(db));
+        let request = IpcMessageRequest::from_request(IpcRequest::GetTip { input: address.to_hex() }, "tip".to_string());
+        let request = serde_json::to_vec(&request).unwrap();
+
+        // A read request is answered while another reader holds the DB, it would wait forever for the write lock.
+        let _reader = db.db.read().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let handler_db = Arc::clone(&db);
+        thread::spawn(move || {
+            let response = handle_message_concurrent(&handler_db, multipart(&request, request.len()), &AttestationConfig::default(), 0, false, 0, GasLimits::default());
+            sender.send(response).unwrap();
+        });
+        let response = receiver.recv_timeout(Duration::from_secs(60)).expect("The read request waited for the write lock");
+        let response: Value = serde_json::from_slice(&response[0]).unwrap();
+        assert_eq!((response["id"].as_str(), response["result"]["key"].as_u64()), (Some("tip"), Some(0)));
+    }
+
+    #[test]
+    fn test_writes_to_different_contracts_run_concurrently() {
+        let (db, _dir) = create_test_db();
+        let db = Arc::new(ConcurrentDB::new(db));
+        let (busy, free) = (ContractAddress::from([1u8; 32]), ContractAddress::from([2u8; 32]));
+        let send_write = |address: ContractAddress| {
+            let request = IpcRequest::SetRetentionPolicy { address: address.to_hex(), policy: RetentionPolicy::KeepLast(2) };
+            let request = serde_json::to_vec(&IpcMessageRequest::from_request(request, address.to_hex())).unwrap();
+            let (sender, receiver) = mpsc::channel();
+            let handler_db = Arc::clone(&db);
+            thread::spawn(move || {
+                let response = handle_message_concurrent(&handler_db, multipart(&request, request.len()), &AttestationConfig::default(), 0, false, 0, GasLimits::default());
+                sender.send(response).unwrap();
+            });
+            receiver
+        };
+
+        // While a write to one contract holds its lock, a write to another contract goes through and one to the same contract waits.
+        let busy_lock = db.contract_lock(busy);
+        let guard = busy_lock.lock().unwrap();
+        let same = send_write(busy);
+        let other = send_write(free);
+        other.recv_timeout(Duration::from_secs(60)).expect("The write waited for a write to another contract");
+        assert!(same.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(guard);
+        same.recv_timeout(Duration::from_secs(60)).expect("The write wasn't done once the contract was released");
+
+        let db = db.db.read().unwrap();
+        for address in &[busy, free] {
+            assert_eq!(db.get_retention_policy(*address).unwrap(), RetentionPolicy::KeepLast(2));
+        }
+    }
+
+    #[test]
+    fn test_read_handler_rejects_other_requests() {
+        let (db, _dir) = create_test_db();
+        assert!(handle_read_request(&db, IpcRequest::CompactDb).is_err());
+        assert!(!IpcRequest::CompactDb.is_read_only());
+    }
 }
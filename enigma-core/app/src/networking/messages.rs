@@ -1,28 +1,118 @@
 use serde_json;
+use std::collections::BTreeMap;
 use serde_repr::{Serialize_repr, Deserialize_repr};
 use zmq::Message;
-use crate::db::{Delta, Stype, DeltaKey};
-use hex::ToHex;
+use crate::db::{ContractSettings, Delta, Stype, DeltaKey, RetentionPolicy};
+use hex::{ToHex, FromHex};
 use failure::Error;
+use enigma_types::{GasMultiplier, HostCapabilities};
+use crate::common_u::errors::{DBErr, DBErrKind, EnclaveFailError, InvalidRequest, InvalidTaskField, P2PErr};
+use enigma_types::EnclaveReturn;
+use crate::networking::sequence::SequenceTracker;
+
+/// The default of `--min-gas-limit`, anything lower runs out of gas before doing any work.
+pub const MIN_GAS_LIMIT: u64 = 100;
+/// The default of `--max-gas-limit`, so a contract can't run effectively unbounded.
+pub const MAX_GAS_LIMIT: u64 = 10_000_000_000;
+/// The default of `--default-gas-limit`, the gas limit of the tasks that don't set one.
+pub const DEFAULT_GAS_LIMIT: u64 = 100_000_000;
+/// The shortest encrypted value, an empty plaintext still has a 16 bytes MAC tag and a 12 bytes IV.
+pub const MIN_ENCRYPTED_LEN: usize = 28;
+/// The length of the user's public DH key.
+pub const USER_DHKEY_LEN: usize = 64;
+/// The version of the IPC protocol this core speaks, a message without a `version` is assumed to be of this version.
+pub const IPC_PROTOCOL_VERSION: u32 = 1;
+
+fn current_protocol_version() -> u32 { IPC_PROTOCOL_VERSION }
 
 // These attributes enable the status to be casted as an i8 object as well
-#[derive(Serialize_repr, Deserialize_repr, Clone, Debug)]
+#[derive(Serialize_repr, Deserialize_repr, Clone, Debug, PartialEq)]
 #[repr(i8)]
 pub enum Status {
     Failed = -1,
     Passed = 0,
 }
 
+/// The class of an `IpcResponse::Error`, so clients can tell errors apart without parsing their messages.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    /// The request is malformed or can't be accepted (e.g. an invalid task field, another protocol version or a replay).
+    BadRequest,
+    /// The request refers to something the core doesn't have (e.g. a contract that was never deployed).
+    NotFound,
+    /// The enclave doesn't have the state key of the contract, it needs to be provisioned again.
+    KeyMissing,
+    /// Anything else, e.g. a failure inside the enclave or of the DB.
+    Internal,
+}
+
+impl Default for ErrorCode {
+    fn default() -> Self { ErrorCode::Internal }
+}
+
+impl ErrorCode {
+    /// The code of `err` by the first error in its chain of causes that has a specific one.
+    pub fn of(err: &Error) -> ErrorCode {
+        for cause in err.iter_chain() {
+            if cause.downcast_ref::<InvalidRequest>().is_some() || cause.downcast_ref::<InvalidTaskField>().is_some() ||
+               cause.downcast_ref::<P2PErr>().is_some() {
+                return ErrorCode::BadRequest;
+            }
+            if let Some(DBErr { kind: DBErrKind::MissingKey(_), .. }) = cause.downcast_ref::<DBErr>() {
+                return ErrorCode::NotFound;
+            }
+            if let Some(e) = cause.downcast_ref::<EnclaveFailError>() {
+                if e.err == EnclaveReturn::KeysError || e.err == EnclaveReturn::KeyProvisionError {
+                    return ErrorCode::KeyMissing;
+                }
+            }
+        }
+        ErrorCode::Internal
+    }
+}
+
+/// Turns the error of decoding a part of a request into an `InvalidRequest`, so it's answered with `ErrorCode::BadRequest`.
+/// Only for what the client sent, the same errors on data the core stored or computed are internal.
+pub trait OrBadRequest<T> {
+    fn or_bad_request(self) -> Result<T, Error>;
+}
+
+impl<T, E: std::fmt::Display> OrBadRequest<T> for Result<T, E> {
+    fn or_bad_request(self) -> Result<T, Error> {
+        self.map_err(|e| InvalidRequest { msg: e.to_string() }.into())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IpcMessageRequest {
     pub id: String,
+    #[serde(default = "current_protocol_version")]
+    pub version: u32,
+    /// Identifies the client whose requests `sequence` numbers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// Has to increase with every request of `client`, see `networking::sequence`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// The signature of `client` over the request, in hex, see `networking::sequence::sequence_message`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
     #[serde(flatten)]
     pub request: IpcRequest
 }
 
+/// Only the version of a message, so it can be checked before the rest of the message is parsed.
+#[derive(Deserialize)]
+struct MessageVersion {
+    #[serde(default = "current_protocol_version")]
+    version: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IpcMessageResponse {
     pub id: String,
+    #[serde(default = "current_protocol_version")]
+    pub version: u32,
     #[serde(flatten)]
     pub response: IpcResponse
 }
@@ -32,24 +122,40 @@ pub struct IpcMessageResponse {
 pub enum IpcResponse {
     GetRegistrationParams { #[serde(flatten)] result: IpcResults },
     GetTip { result: IpcDelta },
-    GetTips { result: IpcResults },
+    GetTips { #[serde(flatten)] result: IpcResults },
     GetAllTips { result: IpcResults },
     GetAllAddrs { result: IpcResults },
     GetDelta { result: IpcResults },
     GetDeltas { result: IpcResults },
     GetContract { #[serde(flatten)] result: IpcResults },
+    GetContracts { result: IpcResults },
+    GetContractMeta { #[serde(flatten)] result: IpcResults },
     UpdateNewContract { address: String, result: IpcResults },
     UpdateNewContractOnDeployment { address: String, result: IpcResults },
     RemoveContract { address: String, result: IpcResults },
+    SetRetentionPolicy { address: String, result: IpcResults },
+    DestroyContract { address: String, result: IpcResults },
+    CompactDb { #[serde(flatten)] result: IpcResults },
     UpdateDeltas { #[serde(flatten)] result: IpcResults },
     RemoveDeltas { #[serde(flatten)] result: IpcResults},
     NewTaskEncryptionKey { #[serde(flatten)] result: IpcResults },
     DeploySecretContract { #[serde(flatten)] result: IpcResults},
     ComputeTask { #[serde(flatten)] result: IpcResults },
+    ReplayCompute { #[serde(flatten)] result: IpcResults },
     FailedTask { #[serde(flatten)] result: IpcResults },
     GetPTTRequest { #[serde(flatten)] result: IpcResults },
     PTTResponse { result: IpcResults },
-    Error { msg: String },
+    GetDeltaDecoded { result: IpcResults },
+    GetStateAt { result: IpcResults },
+    StateFingerprint { result: IpcResults },
+    DeployAndCompute { deploy: IpcResults, compute: IpcResults },
+    GetMetrics { result: IpcResults },
+    ValidateContract { result: IpcResults },
+    Error {
+        msg: String,
+        #[serde(default)]
+        code: ErrorCode,
+    },
 }
 
 impl IpcResponse {
@@ -58,12 +164,14 @@ impl IpcResponse {
             IpcResponse::DeploySecretContract {result: e} => {
                 match e {
                     IpcResults::DeployResult {  used_gas,
+                                                constructor_gas,
+                                                storage_gas,
                                                 delta,
                                                 ethereum_address,
                                                 ethereum_payload,
                                                 signature, .. } =>
-                        format!("IpcResponse {{ used_gas: {}, delta: {:?}, ethereum_address: {}, ethereum_payload: {}, signature: {} }}",
-                        used_gas, delta, ethereum_address, ethereum_payload, signature),
+                        format!("IpcResponse {{ used_gas: {}, constructor_gas: {}, storage_gas: {}, delta: {:?}, ethereum_address: {}, ethereum_payload: {}, signature: {} }}",
+                        used_gas, constructor_gas, storage_gas, delta, ethereum_address, ethereum_payload, signature),
                     _ => "".to_string(),
                 }
             },
@@ -98,26 +206,121 @@ pub enum IpcResults {
     GetContract {
         address: String,
         bytecode: Vec<u8>,
+        /// The settings the contract was deployed with, another node needs them (and their MAC) to run tasks of the contract.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        settings: Option<ContractSettings>,
+    },
+    /// The bytecode of every requested contract by its address, `None` for the contracts that weren't found.
+    Contracts(BTreeMap<String, Option<Vec<u8>>>),
+    #[serde(rename = "result")]
+    ContractMeta {
+        address: String,
+        metadata: ContractMetadata,
     },
     Status(Status),
     Tips(Vec<IpcDelta>),
+    /// The tip of every requested contract that has one, each with its address, and the contracts that have none.
+    #[serde(rename = "result")]
+    TipsByAddress { tips: Vec<IpcDelta>, missing: Vec<String> },
+    PatchOperations(Vec<IpcPatchOperation>),
+    State(serde_json::Value),
+    #[serde(rename = "result")]
+    StateFingerprint {
+        address: String,
+        #[serde(rename = "deltaHash")]
+        delta_hash: String,
+        #[serde(rename = "deltaIndex")]
+        delta_index: u32,
+        #[serde(rename = "stateHash")]
+        state_hash: String,
+    },
+    #[serde(rename = "result")]
+    Validation {
+        valid: bool,
+        /// Why the enclave rejected the bytecode, only when it isn't valid.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    #[serde(rename = "result")]
+    Metrics {
+        address: String,
+        tasks: u64,
+        #[serde(rename = "failedTasks")]
+        failed_tasks: u64,
+        #[serde(rename = "replayedTasks")]
+        replayed_tasks: u64,
+        #[serde(rename = "averageGas")]
+        average_gas: u64,
+        /// The average instructions of the tasks that didn't fail, see `ExecuteResult::instructions`.
+        #[serde(rename = "averageInstructions")]
+        average_instructions: u64,
+        #[serde(rename = "peakMemoryBytes")]
+        peak_memory_bytes: u64,
+        #[serde(rename = "averageDeltaBytes")]
+        average_delta_bytes: u64,
+    },
+    #[serde(rename = "result")]
+    Compaction {
+        #[serde(rename = "sizeBefore")]
+        size_before: u64,
+        #[serde(rename = "sizeAfter")]
+        size_after: u64,
+    },
+    #[serde(rename = "result")]
+    Replay {
+        /// Whether replaying the task produced the claimed output, delta and used gas.
+        matches: bool,
+        /// The enclave's signature over the verdict, see `ReplayVerdict`.
+        signature: String,
+    },
     #[serde(rename = "result")]
     DeltasResult { status: Status, errors: Vec<IpcStatusResult> },
     #[serde(rename = "result")]
     DHKey { #[serde(rename = "workerEncryptionKey")] dh_key: String, #[serde(rename = "workerSig")] sig: String },
     #[serde(rename = "result")]
-    RegistrationParams { #[serde(rename = "signingKey")] signing_key: String, report: String, signature: String },
+    RegistrationParams {
+        #[serde(rename = "signingKey")]
+        signing_key: String,
+        report: String,
+        signature: String,
+        /// The `isvEnclaveQuoteStatus` of the report, `None` in simulation mode where there's no report.
+        #[serde(rename = "quoteStatus")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        quote_status: Option<String>,
+        /// Set when the status was accepted despite not being `OK`, see `OutOfDatePolicy`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        warning: Option<String>,
+    },
     #[serde(rename = "result")]
     ComputeResult {
         #[serde(rename = "usedGas")]
         used_gas: u64,
+        /// The output of the function encrypted with the key the enclave shares with the user (derived from `userDHKey`),
+        /// the user decrypts it with `symmetric::decrypt`.
         output: String,
-        delta: IpcDelta,
+        /// `None` when the computation didn't change the state, then no delta index is used.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        delta: Option<IpcDelta>,
         #[serde(rename = "ethereumAddress")]
         ethereum_address: String,
         #[serde(rename = "ethereumPayload")]
         ethereum_payload: String,
         signature: String,
+        /// A signature over (output, delta hash, used gas, task id, status), see `compute_result_message`.
+        #[serde(rename = "resultSignature")]
+        #[serde(default)]
+        result_signature: String,
+        #[serde(rename = "peakMemoryBytes")]
+        #[serde(default)]
+        peak_memory_bytes: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        snapshot: Option<IpcSnapshot>,
     },
     #[serde(rename = "result")]
     DeployResult {
@@ -125,6 +328,10 @@ pub enum IpcResults {
         pre_code_hash: String,
         #[serde(rename = "usedGas")]
         used_gas: u64,
+        #[serde(rename = "constructorGas")]
+        constructor_gas: u64,
+        #[serde(rename = "storageGas")]
+        storage_gas: u64,
         output: String,
         delta: IpcDelta,
         #[serde(rename = "ethereumAddress")]
@@ -132,6 +339,13 @@ pub enum IpcResults {
         #[serde(rename = "ethereumPayload")]
         ethereum_payload: String,
         signature: String,
+        /// A signature over (address, preCodeHash, deployer, delta hash, gas multiplier, capabilities), see `DeployReceipt`.
+        #[serde(rename = "receiptSignature")]
+        #[serde(default)]
+        receipt_signature: String,
+        /// The settings of the contract with the enclave's MAC over them, sent with the contract to the other nodes.
+        #[serde(default)]
+        settings: ContractSettings,
     },
     #[serde(rename = "result")]
     FailedTask {
@@ -139,6 +353,11 @@ pub enum IpcResults {
         #[serde(rename = "usedGas")]
         used_gas: u64,
         signature: String,
+        /// Only on a failed computation, a signature over (output, zero delta hash, used gas, task id, status).
+        #[serde(rename = "resultSignature")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        result_signature: Option<String>,
     },
 }
 
@@ -151,18 +370,85 @@ pub enum IpcRequest {
     GetAllTips,
     GetAllAddrs,
     GetDelta { input: IpcDelta },
-    GetDeltas { input: Vec<IpcDeltasRange> },
-    GetContract { input: String },
-    UpdateNewContract { address: String, bytecode: Vec<u8> },
-    UpdateNewContractOnDeployment {address: String, bytecode: String, delta: IpcDelta},
+    /// If `chunk_size` is set and the response is larger, it's split into frames of up to `chunk_size` bytes (see `networking::framing`).
+    GetDeltas {
+        input: Vec<IpcDeltasRange>,
+        #[serde(rename = "chunkSize")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        chunk_size: Option<usize>,
+    },
+    /// Like `GetDeltas` the response is split into frames when `chunk_size` is set.
+    GetContract {
+        input: String,
+        #[serde(rename = "chunkSize")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        chunk_size: Option<usize>,
+    },
+    /// The bytecode of several contracts in a single request (i.e. when syncing a new node),
+    /// like `GetDeltas` the response is split into frames when `chunk_size` is set.
+    GetContracts {
+        addresses: Vec<String>,
+        #[serde(rename = "chunkSize")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        chunk_size: Option<usize>,
+    },
+    GetContractMeta { address: String },
+    /// `settings` are the ones the contract was deployed with (see `IpcResults::GetContract`), without them the contract can't run tasks.
+    UpdateNewContract { address: String, bytecode: Vec<u8>, #[serde(default)] settings: Option<ContractSettings> },
+    UpdateNewContractOnDeployment {address: String, bytecode: String, delta: IpcDelta, #[serde(default)] settings: Option<ContractSettings>},
     RemoveContract { address: String },
+    /// Sets which deltas of a contract are kept, the older ones are pruned once the state doesn't depend on them.
+    SetRetentionPolicy { address: String, policy: RetentionPolicy },
+    /// Deletes everything stored for a contract and marks it as destroyed, so later tasks on it fail.
+    /// `sig` is the deployer in the metadata of the contract signing its address and tip, see `destroy_contract_message`.
+    DestroyContract { address: String, #[serde(rename = "deployerSig")] sig: String },
+    /// Compacts the DB now instead of waiting for the compaction trigger, returns its size before and after (in bytes).
+    CompactDb,
     UpdateDeltas { deltas: Vec<IpcDelta> },
     RemoveDeltas { input: Vec<IpcDeltasRange> },
-    NewTaskEncryptionKey { #[serde(rename = "userPubKey")] user_pubkey: String },
+    /// `sigVersion` is how the key is signed (see `UserKeySigVersion`), 2 tags it with its signature domain.
+    /// If it's omitted the key is signed as before the signature domains, which existing clients verify.
+    NewTaskEncryptionKey { #[serde(rename = "userPubKey")] user_pubkey: String, #[serde(rename = "sigVersion", default)] sig_version: Option<u32> },
     DeploySecretContract { input: IpcTask},
     ComputeTask { input: IpcTask },
+    /// Executes a computation task again without storing anything and checks the result another worker claimed for it.
+    /// `input` is the task as the worker got it, its DH key is sent as `taskKey`, encrypted with the key of a fresh `replayDHKey`.
+    ReplayCompute {
+        input: IpcTask,
+        claim: IpcClaim,
+        #[serde(rename = "replayDHKey")]
+        replay_dhkey: String,
+        #[serde(rename = "taskKey")]
+        task_key: String,
+    },
     GetPTTRequest,
     PTTResponse {  input: PrincipalResponse },
+    /// Debug only, returns the decrypted json patch operations of a delta.
+    GetDeltaDecoded { address: String, index: u32 },
+    /// Debug only, returns the decrypted state of a contract as it was after the delta `index`.
+    GetStateAt { address: String, index: u32 },
+    /// Returns the delta hash and index of the latest state of a contract and a keyed hash of the state,
+    /// so workers can find out if their states diverged without exchanging them.
+    StateFingerprint { address: String },
+    /// Deploys a contract and then runs `compute` on it, the address of `compute` is taken from `deploy`.
+    DeployAndCompute { deploy: IpcTask, compute: IpcTask },
+    /// The aggregates of the tasks a contract ran since the core started, see `wasm_u::metrics`.
+    GetMetrics { address: String },
+    /// Checks that the bytecode would pass the module creation and the instantiation of a deployment
+    /// with the given forbidden capabilities and gas multiplier, nothing is stored or run.
+    ValidateContract {
+        bytecode: Vec<u8>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        #[serde(default)]
+        forbid: Vec<Capability>,
+        #[serde(rename = "gasMultiplier")]
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[serde(default)]
+        gas_multiplier: Option<GasMultiplier>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -176,10 +462,210 @@ pub struct IpcTask {
     pub encrypted_fn: String,
     #[serde(rename = "userDHKey")]
     pub user_dhkey: String,
+    /// If it's omitted the default gas limit of the core is used, see `IpcRequest::apply_default_gas_limit`.
     #[serde(rename = "gasLimit")]
-    pub gas_limit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub gas_limit: Option<u64>,
     #[serde(rename = "contractAddress")]
+    #[serde(default)]
     pub address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub metadata: Option<ContractMetadata>,
+    /// Host capabilities the contract isn't allowed to use, only used in DeploySecretContract.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub forbid: Vec<Capability>,
+    /// The price of the contract relative to the base costs of the instructions, only used in DeploySecretContract.
+    #[serde(rename = "gasMultiplier")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub gas_multiplier: Option<GasMultiplier>,
+    /// The id of a computation task, the enclave signs it together with the results.
+    #[serde(rename = "taskID")]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    #[serde(default)]
+    pub task_id: String,
+}
+
+impl IpcTask {
+    /// The gas limit of the task, the configured default is applied to the tasks without one before they're handled
+    /// (see `IpcRequest::apply_default_gas_limit`), so this panics if it wasn't.
+    pub fn gas_limit(&self) -> u64 {
+        self.gas_limit.expect("The default gas limit wasn't applied to the task")
+    }
+
+    /// Identifies the task in logs, a task without an id (i.e. a deployment) is described by its contract address.
+    pub fn task_description(&self) -> String {
+        if self.task_id.is_empty() {
+            format!("<no id> of contract {}", self.address)
+        } else {
+            self.task_id.clone()
+        }
+    }
+
+    /// The task id as 32 bytes, only a deployment can leave it out so computations must have one.
+    pub fn task_id(&self) -> Result<[u8; 32], Error> {
+        if self.task_id.is_empty() {
+            return Err(InvalidTaskField { field: "taskID", msg: "a computation needs a task id".to_string() }.into());
+        }
+        let mut task_id = [0u8; 32];
+        let bytes: Vec<u8> = self.task_id.from_hex()
+            .map_err(|e| InvalidTaskField { field: "taskID", msg: format!("not a valid hex: {}", e) })?;
+        if bytes.len() != task_id.len() {
+            return Err(InvalidTaskField { field: "taskID", msg: format!("expected 32 bytes, got {}", bytes.len()) }.into());
+        }
+        task_id.copy_from_slice(&bytes);
+        Ok(task_id)
+    }
+
+    /// The Ethereum address of the deployer from the metadata of the task, zeros if it has no metadata.
+    pub fn deployer(&self) -> Result<[u8; 20], Error> {
+        let mut deployer = [0u8; 20];
+        if let Some(metadata) = &self.metadata {
+            let bytes: Vec<u8> = metadata.deployer.from_hex()
+                .map_err(|e| InvalidTaskField { field: "metadata", msg: format!("the deployer is not a valid hex: {}", e) })?;
+            if bytes.len() != deployer.len() {
+                return Err(InvalidTaskField { field: "metadata", msg: format!("expected a deployer of 20 bytes, got {}", bytes.len()) }.into());
+            }
+            deployer.copy_from_slice(&bytes);
+        }
+        Ok(deployer)
+    }
+
+    /// The host capabilities left after removing the forbidden ones.
+    pub fn capabilities(&self) -> Result<HostCapabilities, Error> {
+        capabilities_without(&self.forbid)
+    }
+
+    /// Checks that the hex fields of the task decode to values of a plausible length,
+    /// so a malformed task is rejected with the offending field before it's processed.
+    /// The address and the task id are optional here, but must be 32 bytes if they're set.
+    pub fn validate(&self) -> Result<(), Error> {
+        check_hex_len("encryptedArgs", &self.encrypted_args, |len| len >= MIN_ENCRYPTED_LEN, "at least 28 bytes")?;
+        check_hex_len("encryptedFn", &self.encrypted_fn, |len| len >= MIN_ENCRYPTED_LEN, "at least 28 bytes")?;
+        check_hex_len("userDHKey", &self.user_dhkey, |len| len == USER_DHKEY_LEN, "64 bytes")?;
+        if !self.address.is_empty() {
+            check_hex_len("contractAddress", &self.address, |len| len == 32, "32 bytes")?;
+        }
+        if !self.task_id.is_empty() {
+            check_hex_len("taskID", &self.task_id, |len| len == 32, "32 bytes")?;
+        }
+        if let Some(multiplier) = self.gas_multiplier {
+            check_gas_multiplier(multiplier)?;
+        }
+        self.deployer()?;
+        Ok(())
+    }
+
+    /// Fails if the task forbids capabilities or sets a gas multiplier,
+    /// they're only applied when deploying so in a computation they'd be silently ignored.
+    pub fn ensure_no_deploy_options(&self) -> Result<(), Error> {
+        if !self.forbid.is_empty() {
+            let msg = format!("capabilities can only be forbidden when deploying a contract, got {:?} in a computation", self.forbid);
+            return Err(InvalidTaskField { field: "forbid", msg }.into());
+        }
+        if let Some(multiplier) = self.gas_multiplier {
+            let msg = format!("the gas multiplier can only be set when deploying a contract, got {:?} in a computation", multiplier);
+            return Err(InvalidTaskField { field: "gasMultiplier", msg }.into());
+        }
+        Ok(())
+    }
+}
+
+fn check_hex_len<F: Fn(usize) -> bool>(field: &'static str, value: &str, valid_len: F, expected: &str) -> Result<(), Error> {
+    let bytes: Vec<u8> = value.from_hex()
+        .map_err(|e| InvalidTaskField { field, msg: format!("not a valid hex: {}", e) })?;
+    if !valid_len(bytes.len()) {
+        return Err(InvalidTaskField { field, msg: format!("expected {}, got {}", expected, bytes.len()) }.into());
+    }
+    Ok(())
+}
+
+/// The host capabilities left after removing `forbid`, a capability can only be forbidden once.
+pub fn capabilities_without(forbid: &[Capability]) -> Result<HostCapabilities, Error> {
+    let mut capabilities = HostCapabilities::ALL;
+    for (i, capability) in forbid.iter().enumerate() {
+        if forbid[..i].contains(capability) {
+            return Err(InvalidTaskField { field: "forbid", msg: format!("the capability {:?} is forbidden more than once", capability) }.into());
+        }
+        capabilities = capabilities.without((*capability).into());
+    }
+    Ok(capabilities)
+}
+
+/// The gas limits the core accepts for tasks, and the one it gives the tasks that don't set one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GasLimits {
+    pub min: u64,
+    pub max: u64,
+    pub default: u64,
+}
+
+impl Default for GasLimits {
+    fn default() -> Self {
+        GasLimits { min: MIN_GAS_LIMIT, max: MAX_GAS_LIMIT, default: DEFAULT_GAS_LIMIT }
+    }
+}
+
+impl GasLimits {
+    /// Fails unless the range isn't empty and the default is within it.
+    pub fn new(min: u64, max: u64, default: u64) -> Result<Self, String> {
+        if min > max {
+            return Err(format!("The minimum gas limit {} is above the maximum {}", min, max));
+        }
+        let limits = GasLimits { min, max, default };
+        limits.check(default).map_err(|e| format!("Bad default gas limit: {}", e))?;
+        Ok(limits)
+    }
+
+    /// Fails unless `gas_limit` is within [`min`, `max`].
+    pub fn check(&self, gas_limit: u64) -> Result<(), String> {
+        if gas_limit < self.min || gas_limit > self.max {
+            return Err(format!("The gas limit {} is out of the allowed range [{}, {}]", gas_limit, self.min, self.max));
+        }
+        Ok(())
+    }
+}
+
+/// Fails unless both parts of the gas multiplier are positive.
+pub fn check_gas_multiplier(multiplier: GasMultiplier) -> Result<(), Error> {
+    if multiplier.mul == 0 || multiplier.div == 0 {
+        return Err(InvalidTaskField { field: "gasMultiplier", msg: format!("expected a positive ratio, got {}/{}", multiplier.mul, multiplier.div) }.into());
+    }
+    Ok(())
+}
+
+/// A host capability that a deployment can forbid the contract from using.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Capability {
+    Randomness,
+    EthereumBridge,
+}
+
+impl From<Capability> for HostCapabilities {
+    fn from(capability: Capability) -> Self {
+        match capability {
+            Capability::Randomness => HostCapabilities::RANDOMNESS,
+            Capability::EthereumBridge => HostCapabilities::ETHEREUM_BRIDGE,
+        }
+    }
+}
+
+/// Human readable information about a contract, saved on deployment (if supplied) next to the bytecode.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ContractMetadata {
+    pub deployer: String,
+    pub deployment_block: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub abi: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -190,6 +676,71 @@ pub struct IpcStatusResult {
     pub status: Status,
 }
 
+/// Collects the `(address, key, status)` of a batch of per-address operations into `IpcResults::Errors` or `IpcResults::DeltasResult`.
+#[derive(Debug, Default)]
+pub struct IpcStatusResultsBuilder {
+    results: Vec<IpcStatusResult>,
+}
+
+impl IpcStatusResultsBuilder {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn push(&mut self, address: String, key: Option<i64>, status: Status) -> &mut Self {
+        self.results.push(IpcStatusResult { address, key, status });
+        self
+    }
+
+    /// True if any of the collected statuses is `Failed`.
+    pub fn has_failures(&self) -> bool { self.results.iter().any(|r| r.status == Status::Failed) }
+
+    /// `Failed` if any of the collected statuses failed, otherwise `Passed`.
+    pub fn overall_status(&self) -> Status {
+        if self.has_failures() { Status::Failed } else { Status::Passed }
+    }
+
+    pub fn into_errors(self) -> IpcResults { IpcResults::Errors(self.results) }
+
+    pub fn into_deltas_result(self) -> IpcResults {
+        IpcResults::DeltasResult { status: self.overall_status(), errors: self.results }
+    }
+}
+
+impl std::iter::FromIterator<(String, Option<i64>, Status)> for IpcStatusResultsBuilder {
+    fn from_iter<I: IntoIterator<Item = (String, Option<i64>, Status)>>(iter: I) -> Self {
+        let mut builder = Self::new();
+        for (address, key, status) in iter {
+            builder.push(address, key, status);
+        }
+        builder
+    }
+}
+
+impl IpcResults {
+    /// True if this is a batch of statuses (or a single status) and any of them is `Failed`.
+    pub fn has_failures(&self) -> bool {
+        match self {
+            IpcResults::Errors(results) | IpcResults::DeltasResult { errors: results, .. } => {
+                results.iter().any(|r| r.status == Status::Failed)
+            }
+            IpcResults::Status(status) => *status == Status::Failed,
+            _ => false,
+        }
+    }
+}
+
+/// The result a worker claimed for a computation task, as returned in its `ComputeResult`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpcClaim {
+    /// Hex of the output, as the worker returned it.
+    pub output: String,
+    /// `None` when the computation didn't change the state.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub delta: Option<IpcDelta>,
+    #[serde(rename = "usedGas")]
+    pub used_gas: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct IpcDelta {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -198,6 +749,26 @@ pub struct IpcDelta {
     pub key: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<Vec<u8>>,
+    /// Hex of the signature of the worker that generated the delta, only used in UpdateDeltas.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// The encrypted state of a contract after applying all the deltas up to and including `index`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IpcSnapshot {
+    pub index: u32,
+    pub state: String,
+}
+
+/// A single json patch operation of a decoded delta, `value` is missing in `remove` operations.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IpcPatchOperation {
+    pub op: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub value: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -226,12 +797,150 @@ impl std::ops::Deref for Addresses {
 
 impl IpcMessageResponse {
     pub fn from_response(response: IpcResponse, id: String) -> Self {
-        Self { id, response }
+        Self { id, version: IPC_PROTOCOL_VERSION, response }
     }
 }
 impl IpcMessageRequest {
     pub fn from_request(request: IpcRequest, id: String) -> Self {
-        Self { id, request }
+        Self { id, version: IPC_PROTOCOL_VERSION, client: None, sequence: None, signature: None, request }
+    }
+
+    /// Parses a request off the wire, a malformed one is an error to respond with and not a reason to stop the core.
+    /// A message of another version may mean something else than what this core understands from it, so it's rejected,
+    /// its version is checked first since the rest of it may not parse at all (e.g. a type this core doesn't know).
+    pub fn parse(msg: &[u8]) -> Result<Self, Error> {
+        if let Ok(MessageVersion { version }) = serde_json::from_slice(msg) {
+            if version != IPC_PROTOCOL_VERSION {
+                let msg = format!("Unsupported IPC protocol version {}, this core supports version {}", version, IPC_PROTOCOL_VERSION);
+                return Err(InvalidRequest { msg }.into());
+            }
+        }
+        serde_json::from_slice(msg).or_bad_request()
+    }
+
+    /// The id of a message that may not parse as a request, so the error response can still be matched to it.
+    /// Empty if the message doesn't have one.
+    pub fn id_of(msg: &[u8]) -> String {
+        match serde_json::from_slice::<serde_json::Value>(msg) {
+            Ok(serde_json::Value::Object(map)) => map.get("id").and_then(|id| id.as_str()).unwrap_or_default().to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Rejects a request that isn't signed by its client or isn't numbered after the last one of it, it may be a replay.
+    /// Once clients are registered, a request that changes the DB has to come from one of them. `payload` is the request as received.
+    pub fn ensure_in_sequence(&self, payload: &[u8], sequences: &SequenceTracker) -> Result<(), Error> {
+        match (&self.client, self.sequence, &self.signature) {
+            (Some(client), Some(sequence), Some(signature)) => {
+                let signature: Vec<u8> = signature.from_hex().or_bad_request()?;
+                if signature.len() != 65 {
+                    return Err(InvalidRequest { msg: format!("The signature is {} bytes instead of 65", signature.len()) }.into());
+                }
+                let mut sig = [0u8; 65];
+                sig.copy_from_slice(&signature);
+                // The keys of a `Value` are sorted, so this is the same body the client signed whatever order it sent the keys in.
+                let mut body: serde_json::Value = serde_json::from_slice(payload).or_bad_request()?;
+                if let Some(body) = body.as_object_mut() {
+                    body.remove("signature");
+                }
+                sequences.check(client, sequence, &serde_json::to_vec(&body)?, sig)
+            }
+            (None, None, None) if sequences.is_enforced() && !self.request.is_read_only() => {
+                Err(InvalidRequest { msg: "Requests that change the DB have to be signed by a registered client".to_string() }.into())
+            }
+            (None, None, None) => Ok(()),
+            _ => Err(InvalidRequest { msg: "A request with a sequence number has to name its client and be signed by it".to_string() }.into()),
+        }
+    }
+}
+
+impl IpcRequest {
+    /// The size of the frames the response is split into (see `networking::framing`), only requests that can return
+    /// whole contracts or many deltas can ask for it. Without it the response is a single message, as old clients expect.
+    pub fn chunk_size(&self) -> Result<Option<usize>, Error> {
+        match self {
+            IpcRequest::GetDeltas { chunk_size, .. } | IpcRequest::GetContract { chunk_size, .. } |
+            IpcRequest::GetContracts { chunk_size, .. } => match chunk_size {
+                Some(0) => Err(InvalidRequest { msg: "The chunk size must be positive".to_string() }.into()),
+                size => Ok(*size),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Requests that only read from the DB, and therefore can run concurrently with each other.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            IpcRequest::GetTip { .. } | IpcRequest::GetTips { .. } | IpcRequest::GetAllTips | IpcRequest::GetAllAddrs |
+            IpcRequest::GetDelta { .. } | IpcRequest::GetDeltas { .. } | IpcRequest::GetContract { .. } |
+            IpcRequest::GetContracts { .. } | IpcRequest::GetContractMeta { .. } | IpcRequest::GetMetrics { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The contract of a request that writes to it and to no other contract, such requests on different contracts can run concurrently.
+    /// The requests that make the states need rebuilding aren't included, rebuilding them writes to every contract.
+    pub fn contract(&self) -> Option<&str> {
+        match self {
+            IpcRequest::UpdateNewContract { address, .. } | IpcRequest::RemoveContract { address } |
+            IpcRequest::SetRetentionPolicy { address, .. } | IpcRequest::DestroyContract { address, .. } |
+            IpcRequest::GetDeltaDecoded { address, .. } | IpcRequest::GetStateAt { address, .. } |
+            IpcRequest::StateFingerprint { address } => Some(address),
+            IpcRequest::DeploySecretContract { input } | IpcRequest::ComputeTask { input } |
+            IpcRequest::ReplayCompute { input, .. } => Some(&input.address),
+            IpcRequest::DeployAndCompute { deploy, .. } => Some(&deploy.address),
+            _ => None,
+        }
+    }
+
+    /// Requests that call into the enclave to run.
+    pub fn enters_enclave(&self) -> bool {
+        match self {
+            IpcRequest::GetRegistrationParams | IpcRequest::UpdateDeltas { .. } | IpcRequest::NewTaskEncryptionKey { .. } |
+            IpcRequest::DeploySecretContract { .. } | IpcRequest::ValidateContract { .. } | IpcRequest::ComputeTask { .. } |
+            IpcRequest::ReplayCompute { .. } | IpcRequest::GetPTTRequest | IpcRequest::PTTResponse { .. } |
+            IpcRequest::GetDeltaDecoded { .. } | IpcRequest::GetStateAt { .. } | IpcRequest::StateFingerprint { .. } |
+            IpcRequest::DeployAndCompute { .. } => true,
+            _ => false,
+        }
+    }
+
+    fn tasks(&self) -> Vec<&IpcTask> {
+        match self {
+            IpcRequest::DeploySecretContract { input } | IpcRequest::ComputeTask { input } |
+            IpcRequest::ReplayCompute { input, .. } => vec![input],
+            IpcRequest::DeployAndCompute { deploy, compute } => vec![deploy, compute],
+            _ => Vec::new(),
+        }
+    }
+
+    fn tasks_mut(&mut self) -> Vec<&mut IpcTask> {
+        match self {
+            IpcRequest::DeploySecretContract { input } | IpcRequest::ComputeTask { input } |
+            IpcRequest::ReplayCompute { input, .. } => vec![input],
+            IpcRequest::DeployAndCompute { deploy, compute } => vec![deploy, compute],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Sets the gas limit of the tasks that don't have one to `default`, it's checked like any other gas limit afterwards.
+    pub fn apply_default_gas_limit(&mut self, default: u64) {
+        for task in self.tasks_mut() {
+            task.gas_limit.get_or_insert(default);
+        }
+    }
+
+    /// Reject tasks with a gas limit outside of the range of `limits` before they reach the enclave.
+    pub fn check_gas_limits(&self, limits: &GasLimits) -> Result<(), Error> {
+        for task in self.tasks() {
+            limits.check(task.gas_limit()).map_err(|msg| InvalidTaskField { field: "gasLimit", msg })?;
+        }
+        Ok(())
+    }
+
+    /// Validates every task in the request, see [`IpcTask::validate`].
+    pub fn validate_tasks(&self) -> Result<(), Error> {
+        self.tasks().into_iter().try_for_each(IpcTask::validate)
     }
 }
 
@@ -240,7 +949,7 @@ impl IpcMessageRequest {
 impl IpcDelta {
     pub fn from_delta_key(k: DeltaKey, v: &[u8]) -> Result<Self, Error> {
         if let Stype::Delta(indx) = k.key_type {
-            Ok( IpcDelta { contract_address: Some(k.contract_address.to_hex()), key: indx, data: Some(v.to_vec()) } )
+            Ok( IpcDelta { contract_address: Some(k.contract_address.to_hex()), key: indx, data: Some(v.to_vec()), ..Default::default() } )
         } else {
             bail!("This isn't a delta")
         }
@@ -252,15 +961,7 @@ impl From<Delta> for IpcDelta {
         let data = if delta.value.len() == 0 { None } else { Some ( delta.value ) };
         let key = delta.key.key_type.unwrap_delta();
 
-        IpcDelta { contract_address: None, key, data }
-    }
-}
-
-impl From<Message> for IpcMessageRequest {
-    fn from(msg: Message) -> Self {
-        let msg_str = msg.as_str().unwrap();
-        let req: Self = serde_json::from_str(msg_str).expect(msg_str);
-        req
+        IpcDelta { contract_address: None, key, data, ..Default::default() }
     }
 }
 
@@ -275,14 +976,240 @@ pub(crate) trait UnwrapError<T> {
     fn unwrap_or_error(self) -> T;
 }
 
-impl<E: std::fmt::Display> UnwrapError<IpcResponse> for Result<IpcResponse, E> {
+impl UnwrapError<IpcResponse> for Result<IpcResponse, Error> {
     fn unwrap_or_error(self) -> IpcResponse {
         match self {
             Ok(m) => m,
             Err(e) => {
                 error!("Unwrapped p2p Message failed: {}", e);
-                IpcResponse::Error {msg: format!("{}", e)}
+                IpcResponse::Error { msg: format!("{}", e), code: ErrorCode::of(&e) }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use failure::Fail;
+
+    fn parse_task(forbid: &str) -> Result<IpcTask, serde_json::Error> {
+        let task = format!(r#"{{"encryptedArgs":"","encryptedFn":"","userDHKey":"","gasLimit":1000,"forbid":{}}}"#, forbid);
+        serde_json::from_str(&task)
+    }
+
+    #[test]
+    fn test_forbid_capabilities() {
+        let task = parse_task(r#"["randomness"]"#).unwrap();
+        assert_eq!(task.forbid, vec![Capability::Randomness]);
+        assert_eq!(task.capabilities().unwrap(), HostCapabilities::ALL.without(HostCapabilities::RANDOMNESS));
+        assert!(task.ensure_no_deploy_options().is_err());
+
+        let task = parse_task("[]").unwrap();
+        assert_eq!(task.capabilities().unwrap(), HostCapabilities::ALL);
+        task.ensure_no_deploy_options().unwrap();
+        let task = IpcTask { gas_multiplier: Some(GasMultiplier { mul: 2, div: 1 }), ..task };
+        assert!(task.ensure_no_deploy_options().is_err());
+    }
+
+    #[test]
+    fn test_forbid_invalid_capabilities() {
+        assert!(parse_task(r#"["rand"]"#).is_err());
+        let task = parse_task(r#"["ethereumBridge", "ethereumBridge"]"#).unwrap();
+        assert!(task.capabilities().is_err());
+    }
+
+    fn valid_task() -> IpcTask {
+        IpcTask {
+            pre_code: None,
+            encrypted_args: [1u8; MIN_ENCRYPTED_LEN].to_hex(),
+            encrypted_fn: [2u8; 40].to_hex(),
+            user_dhkey: [3u8; USER_DHKEY_LEN].to_hex(),
+            gas_limit: Some(1000),
+            address: [4u8; 32].to_hex(),
+            metadata: None,
+            forbid: Vec::new(),
+            gas_multiplier: None,
+            task_id: [5u8; 32].to_hex(),
+        }
+    }
+
+    #[test]
+    fn test_default_gas_limit() {
+        let task = r#"{"encryptedArgs":"","encryptedFn":"","userDHKey":""}"#;
+        let mut request = IpcRequest::ComputeTask { input: serde_json::from_str(task).unwrap() };
+        request.apply_default_gas_limit(5000);
+        match &request {
+            IpcRequest::ComputeTask { input } => assert_eq!(input.gas_limit, Some(5000)),
+            _ => unreachable!(),
+        }
+
+        // An explicit gas limit is kept, and both are checked against the bounds.
+        let mut request = IpcRequest::DeployAndCompute { deploy: valid_task(), compute: IpcTask { gas_limit: None, ..valid_task() } };
+        request.apply_default_gas_limit(MAX_GAS_LIMIT + 1);
+        match &request {
+            IpcRequest::DeployAndCompute { deploy, compute } => assert_eq!((deploy.gas_limit(), compute.gas_limit()), (1000, MAX_GAS_LIMIT + 1)),
+            _ => unreachable!(),
+        }
+        let limits = GasLimits::default();
+        assert!(request.check_gas_limits(&limits).is_err());
+        assert!(limits.check(MIN_GAS_LIMIT).is_ok() && limits.check(MAX_GAS_LIMIT).is_ok());
+        assert!(limits.check(MIN_GAS_LIMIT - 1).is_err());
+        // Raising the maximum accepts it.
+        assert!(request.check_gas_limits(&GasLimits { max: MAX_GAS_LIMIT + 1, ..limits }).is_ok());
+    }
+
+    #[test]
+    fn test_configured_gas_limits() {
+        let limits = GasLimits::new(1000, 5000, 2000).unwrap();
+        assert!(limits.check(1000).is_ok() && limits.check(5000).is_ok());
+        assert!(limits.check(999).is_err() && limits.check(5001).is_err());
+        // The default has to be within the range, which can't be empty.
+        assert!(GasLimits::new(1000, 5000, 999).is_err());
+        assert!(GasLimits::new(1000, 5000, 5001).is_err());
+        assert!(GasLimits::new(5000, 1000, 2000).is_err());
+        assert!(GasLimits::new(1000, 1000, 1000).is_ok());
+        assert_eq!(GasLimits::new(MIN_GAS_LIMIT, MAX_GAS_LIMIT, DEFAULT_GAS_LIMIT).unwrap(), GasLimits::default());
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let code = |res: Result<IpcResponse, Error>| match res.unwrap_or_error() {
+            IpcResponse::Error { code, .. } => code,
+            other => panic!("Expected an error, got {:?}", other),
+        };
+        assert_eq!(code(Err(InvalidTaskField { field: "gasLimit", msg: String::new() }.into())), ErrorCode::BadRequest);
+        assert_eq!(code(Err(InvalidRequest { msg: String::new() }.into())), ErrorCode::BadRequest);
+        let missing = DBErr { command: "read".to_string(), kind: DBErrKind::MissingKey("key".to_string()) };
+        assert_eq!(code(Err(missing.context("Unknown contract").into())), ErrorCode::NotFound);
+        let keys = EnclaveFailError { err: EnclaveReturn::KeysError, status: sgx_types::sgx_status_t::SGX_SUCCESS };
+        assert_eq!(code(Err(keys.context("The state key of the contract is missing").into())), ErrorCode::KeyMissing);
+        let sgx = EnclaveFailError { err: EnclaveReturn::SgxError, status: sgx_types::sgx_status_t::SGX_SUCCESS };
+        assert_eq!(code(Err(sgx.into())), ErrorCode::Internal);
+        assert_eq!(code(Err(format_err!("anything else"))), ErrorCode::Internal);
+        // Malformed data is only the client's fault when it's part of the request.
+        let corrupt = serde_json::from_slice::<ContractMetadata>(b"{").unwrap_err();
+        assert_eq!(code(Err(corrupt.into())), ErrorCode::Internal);
+        assert_eq!(code(serde_json::from_slice::<ContractMetadata>(b"{").or_bad_request()), ErrorCode::BadRequest);
+        assert_eq!(code("zz".from_hex().or_bad_request()), ErrorCode::BadRequest);
+
+        // The code is sent next to the message, and a response without one is an internal error.
+        let response = IpcMessageResponse::from_response(IpcResponse::Error { msg: "bad".to_string(), code: ErrorCode::BadRequest }, "1".to_string());
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["code"], serde_json::json!("BadRequest"));
+        match serde_json::from_str::<IpcMessageResponse>(r#"{"id":"1","type":"Error","msg":"bad"}"#).unwrap().response {
+            IpcResponse::Error { code, .. } => assert_eq!(code, ErrorCode::Internal),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    fn invalid_field(task: &IpcTask) -> &'static str {
+        task.validate().unwrap_err().downcast::<InvalidTaskField>().unwrap().field
+    }
+
+    #[test]
+    fn test_validate_task() {
+        valid_task().validate().unwrap();
+        // The address and the task id can be left out.
+        let task = IpcTask { address: String::new(), task_id: String::new(), ..valid_task() };
+        task.validate().unwrap();
+    }
+
+    #[test]
+    fn test_validate_task_malformed_fields() {
+        let short: String = [0u8; MIN_ENCRYPTED_LEN - 1].to_hex();
+        assert_eq!(invalid_field(&IpcTask { encrypted_args: "zz".to_string(), ..valid_task() }), "encryptedArgs");
+        assert_eq!(invalid_field(&IpcTask { encrypted_args: short.clone(), ..valid_task() }), "encryptedArgs");
+        assert_eq!(invalid_field(&IpcTask { encrypted_fn: "0".to_string(), ..valid_task() }), "encryptedFn");
+        assert_eq!(invalid_field(&IpcTask { encrypted_fn: short, ..valid_task() }), "encryptedFn");
+        assert_eq!(invalid_field(&IpcTask { user_dhkey: [3u8; 65].to_hex(), ..valid_task() }), "userDHKey");
+        assert_eq!(invalid_field(&IpcTask { user_dhkey: String::new(), ..valid_task() }), "userDHKey");
+        assert_eq!(invalid_field(&IpcTask { address: [4u8; 20].to_hex(), ..valid_task() }), "contractAddress");
+        assert_eq!(invalid_field(&IpcTask { task_id: "0x05".to_string(), ..valid_task() }), "taskID");
+        assert_eq!(invalid_field(&IpcTask { gas_multiplier: Some(GasMultiplier { mul: 1, div: 0 }), ..valid_task() }), "gasMultiplier");
+    }
+
+    #[test]
+    fn test_task_id_required() {
+        assert_eq!(valid_task().task_id().unwrap().to_hex(), valid_task().task_id);
+        let err = IpcTask { task_id: String::new(), ..valid_task() }.task_id().unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidTaskField>().unwrap().field, "taskID");
+    }
+
+    #[test]
+    fn test_deployer_from_metadata() {
+        let metadata = |deployer: &str| Some(ContractMetadata { deployer: deployer.to_string(), deployment_block: 1, abi: None, name: None });
+        assert_eq!(valid_task().deployer().unwrap(), [0u8; 20]);
+        let task = IpcTask { metadata: metadata(&[6u8; 20].to_hex()), ..valid_task() };
+        assert_eq!(task.deployer().unwrap(), [6u8; 20]);
+        task.validate().unwrap();
+        assert_eq!(invalid_field(&IpcTask { metadata: metadata("0x06"), ..valid_task() }), "metadata");
+        assert_eq!(invalid_field(&IpcTask { metadata: metadata(&[6u8; 32].to_hex()), ..valid_task() }), "metadata");
+    }
+
+    #[test]
+    fn test_validate_tasks_of_request() {
+        let bad = IpcTask { user_dhkey: "00".to_string(), ..valid_task() };
+        IpcRequest::ComputeTask { input: valid_task() }.validate_tasks().unwrap();
+        assert!(IpcRequest::ComputeTask { input: bad.clone() }.validate_tasks().is_err());
+        assert!(IpcRequest::DeployAndCompute { deploy: valid_task(), compute: bad }.validate_tasks().is_err());
+        IpcRequest::GetAllTips.validate_tasks().unwrap();
+    }
+
+    #[test]
+    fn test_status_results_builder() {
+        let builder: IpcStatusResultsBuilder = vec![
+            ("aa".to_string(), Some(1), Status::Passed),
+            ("bb".to_string(), None, Status::Failed),
+            ("aa".to_string(), Some(2), Status::Passed),
+        ].into_iter().collect();
+        assert!(builder.has_failures());
+        let errors = builder.into_errors();
+        assert!(errors.has_failures());
+        match &errors {
+            IpcResults::Errors(results) => {
+                assert_eq!(results.len(), 3);
+                assert_eq!(results[1].address, "bb");
+                assert_eq!(results[1].status, Status::Failed);
+            }
+            _ => panic!("Expected Errors, got {:?}", errors),
+        }
+        let json = serde_json::to_value(&errors).unwrap();
+        assert_eq!(json["errors"][1], serde_json::json!({"address": "bb", "status": -1}));
+
+        let mut passed = IpcStatusResultsBuilder::new();
+        passed.push("aa".to_string(), Some(1), Status::Passed).push("cc".to_string(), Some(0), Status::Passed);
+        assert!(!passed.has_failures());
+        match passed.into_deltas_result() {
+            IpcResults::DeltasResult { status, errors } => {
+                assert_eq!(status, Status::Passed);
+                assert_eq!(errors.len(), 2);
+            }
+            other => panic!("Expected DeltasResult, got {:?}", other),
+        }
+        assert!(!IpcStatusResultsBuilder::new().into_errors().has_failures());
+    }
+
+    #[test]
+    fn test_parse_malformed_request() {
+        let unknown = br#"{"id":"7","type":"ComputeTask","input":{"encryptedArgs":"","encryptedFn":"","userDHKey":"","forbid":["rand"]}}"#;
+        let err = IpcMessageRequest::parse(unknown).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::BadRequest);
+        assert_eq!(IpcMessageRequest::id_of(unknown), "7");
+        assert!(IpcMessageRequest::parse(b"\xff not json").is_err());
+        assert_eq!(IpcMessageRequest::id_of(b"\xff not json"), "");
+        assert_eq!(IpcMessageRequest::id_of(br#"{"id":7}"#), "");
+        assert!(IpcMessageRequest::parse(br#"{"id":"8","type":"GetAllTips"}"#).is_ok());
+    }
+
+    #[test]
+    fn test_parse_checks_version_first() {
+        let next = IPC_PROTOCOL_VERSION + 1;
+        // A type this core doesn't know is reported as another version rather than as malformed.
+        let msg = format!(r#"{{"id":"9","version":{},"type":"SomeFutureRequest"}}"#, next);
+        let err = IpcMessageRequest::parse(msg.as_bytes()).unwrap_err();
+        assert!(err.downcast_ref::<InvalidRequest>().unwrap().msg.starts_with(&format!("Unsupported IPC protocol version {}", next)));
+        let msg = format!(r#"{{"id":"9","version":{},"type":"SomeFutureRequest"}}"#, IPC_PROTOCOL_VERSION);
+        assert!(IpcMessageRequest::parse(msg.as_bytes()).unwrap_err().downcast_ref::<InvalidRequest>().is_none());
+    }
+}
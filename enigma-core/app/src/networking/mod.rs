@@ -1,4 +1,6 @@
+pub mod framing;
 pub mod ipc_listener;
 pub mod messages;
+pub mod sequence;
 
-pub use self::ipc_listener::IpcListener;
+pub use self::ipc_listener::{ConcurrentDB, ConcurrentIpcListener, IpcListener};
@@ -0,0 +1,150 @@
+//! # Request Sequences.
+//! A client registered with `--client-key` numbers its requests with an increasing sequence and signs each of them,
+//! together with its name and number, with its key (see `sequence_message`). Every request of it whose number isn't larger
+//! than the last one the core accepted from it is rejected, so a request captured off the wire can't be replayed,
+//! and since the signature covers the name and the number they can't be changed to get around it. <br>
+//! Once a client is registered, requests that change the DB are only accepted from registered clients,
+//! so dropping the sequence doesn't get around it either. Without registered clients nothing is enforced, as before. <br>
+//! The sequences are kept in memory for the registered clients only, so they start over when the core restarts.
+
+use crate::common_u::errors::InvalidRequest;
+use enigma_crypto::{hash::prepare_hash_multiple, KeyPair};
+use enigma_types::PubKey;
+use failure::Error;
+use hex::FromHex;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError, RwLock};
+
+lazy_static! {
+    /// The registered clients of this core and their sequences.
+    pub static ref SEQUENCES: SequenceTracker = SequenceTracker::default();
+}
+
+/// A client and the public key its requests are signed with, given as `<name>:<hex public key>`.
+#[derive(Debug, Clone)]
+pub struct ClientKey {
+    pub name: String,
+    pub pubkey: PubKey,
+}
+
+impl FromStr for ClientKey {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let (name, key) = match (parts.next(), parts.next()) {
+            (Some(name), Some(key)) if !name.is_empty() => (name, key),
+            _ => return Err(format!("Expected <name>:<hex public key>, got {:?}", s)),
+        };
+        let key: Vec<u8> = key.from_hex().map_err(|e| format!("The public key of client {} isn't hex: {}", name, e))?;
+        if key.len() != 64 {
+            return Err(format!("The public key of client {} is {} bytes instead of 64", name, key.len()));
+        }
+        let mut pubkey = [0u8; 64];
+        pubkey.copy_from_slice(&key);
+        Ok(ClientKey { name: name.to_string(), pubkey })
+    }
+}
+
+/// The message a client signs for the request numbered `sequence`, `body` is the request without its signature
+/// as compact JSON with the keys of every object sorted.
+pub fn sequence_message(client: &str, sequence: u64, body: &[u8]) -> Vec<u8> {
+    prepare_hash_multiple(&[client.as_bytes(), &sequence.to_be_bytes(), body])
+}
+
+#[derive(Default)]
+pub struct SequenceTracker {
+    keys: RwLock<HashMap<String, PubKey>>,
+    last: Mutex<HashMap<String, u64>>,
+}
+
+impl SequenceTracker {
+    pub fn register(&self, client: ClientKey) {
+        self.keys.write().unwrap_or_else(PoisonError::into_inner).insert(client.name, client.pubkey);
+    }
+
+    /// Whether any client is registered, then only registered clients may change the DB.
+    pub fn is_enforced(&self) -> bool {
+        !self.keys.read().unwrap_or_else(PoisonError::into_inner).is_empty()
+    }
+
+    /// Accepts `sequence` if `signature` is the registered key of `client` over `sequence_message`,
+    /// and `sequence` is larger than the last sequence accepted from `client`.
+    pub fn check(&self, client: &str, sequence: u64, body: &[u8], signature: [u8; 65]) -> Result<(), Error> {
+        let pubkey = match self.keys.read().unwrap_or_else(PoisonError::into_inner).get(client) {
+            Some(pubkey) => *pubkey,
+            None => return Err(InvalidRequest { msg: format!("Client {} isn't registered", client) }.into()),
+        };
+        match KeyPair::recover(&sequence_message(client, sequence, body), signature) {
+            Ok(signer) if signer[..] == pubkey[..] => (),
+            _ => return Err(InvalidRequest { msg: format!("The request isn't signed by client {}", client) }.into()),
+        }
+        let mut last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&previous) = last.get(client) {
+            if sequence <= previous {
+                let msg = format!("Sequence number {} of client {} isn't larger than its last one ({}), the request may be a replay", sequence, client, previous);
+                return Err(InvalidRequest { msg }.into());
+            }
+        }
+        last.insert(client.to_string(), sequence);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sequence_message, ClientKey, SequenceTracker};
+    use enigma_crypto::KeyPair;
+    use hex::ToHex;
+
+    fn register(tracker: &SequenceTracker, name: &str) -> KeyPair {
+        let keys = KeyPair::new().unwrap();
+        tracker.register(format!("{}:{}", name, keys.get_pubkey().to_hex()).parse::<ClientKey>().unwrap());
+        keys
+    }
+
+    fn sign(keys: &KeyPair, client: &str, sequence: u64, body: &[u8]) -> [u8; 65] {
+        keys.sign(&sequence_message(client, sequence, body)).unwrap()
+    }
+
+    #[test]
+    fn test_sequence_per_client() {
+        let tracker = SequenceTracker::default();
+        assert!(!tracker.is_enforced());
+        let (a, b) = (register(&tracker, "a"), register(&tracker, "b"));
+        assert!(tracker.is_enforced());
+        tracker.check("a", 1, b"x", sign(&a, "a", 1, b"x")).unwrap();
+        tracker.check("a", 5, b"x", sign(&a, "a", 5, b"x")).unwrap();
+        assert!(tracker.check("a", 5, b"x", sign(&a, "a", 5, b"x")).is_err());
+        assert!(tracker.check("a", 2, b"x", sign(&a, "a", 2, b"x")).is_err());
+        // Every client has its own sequence.
+        tracker.check("b", 1, b"x", sign(&b, "b", 1, b"x")).unwrap();
+        tracker.check("a", 6, b"x", sign(&a, "a", 6, b"x")).unwrap();
+    }
+
+    #[test]
+    fn test_sequence_signed_by_client() {
+        let tracker = SequenceTracker::default();
+        let a = register(&tracker, "a");
+        let signature = sign(&a, "a", 7, b"body");
+        // The name, the number and the body are all covered by the signature.
+        assert!(tracker.check("b", 7, b"body", signature).is_err());
+        assert!(tracker.check("a", 8, b"body", signature).is_err());
+        assert!(tracker.check("a", 7, b"other", signature).is_err());
+        assert!(tracker.check("a", 7, b"body", sign(&KeyPair::new().unwrap(), "a", 7, b"body")).is_err());
+        tracker.check("a", 7, b"body", signature).unwrap();
+        // Only registered clients are tracked.
+        let c = KeyPair::new().unwrap();
+        assert!(tracker.check("c", 1, b"body", sign(&c, "c", 1, b"body")).is_err());
+    }
+
+    #[test]
+    fn test_parse_client_key() {
+        let pubkey = KeyPair::new().unwrap().get_pubkey();
+        let key: ClientKey = format!("p2p-1:{}", pubkey.to_hex()).parse().unwrap();
+        assert_eq!((key.name.as_str(), &key.pubkey[..]), ("p2p-1", &pubkey[..]));
+        assert!(format!(":{}", pubkey.to_hex()).parse::<ClientKey>().is_err());
+        assert!("p2p-1:abcd".parse::<ClientKey>().is_err());
+        assert!("p2p-1".parse::<ClientKey>().is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! # Execution Metrics.
+//! Aggregates of the tasks every contract ran since the core started (including the ones replayed with `ReplayCompute`):
+//! the gas they used, the instructions they executed, the peak memory of their instances and the size of the deltas they produced.
+//! The instructions are counted in the enclave by the gas counter, see `ExecuteResult::instructions`.
+//! The metrics are kept in memory, so they start over when the core restarts.
+
+use super::{WasmResult, WasmTaskFailure, WasmTaskResult};
+use enigma_types::{ContractAddress, ReplayStats};
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+
+lazy_static! {
+    /// The metrics of the tasks this core ran.
+    pub static ref METRICS: MetricsCollector = MetricsCollector::default();
+}
+
+/// The totals of the tasks of a single contract.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ContractMetrics {
+    /// Every task that reached the enclave, including the failed and the replayed ones.
+    pub tasks: u64,
+    pub failed_tasks: u64,
+    pub replayed_tasks: u64,
+    pub total_gas: u64,
+    /// The instructions of the tasks that didn't fail, failed tasks don't report them.
+    pub total_instructions: u64,
+    /// The largest peak memory of a single task, failed and replayed tasks don't report it.
+    pub peak_memory_bytes: u64,
+    /// The bytes of the deltas of the successful tasks that weren't replays (a replay doesn't store its delta).
+    pub total_delta_bytes: u64,
+    /// The tasks `total_delta_bytes` is made of.
+    pub stored_tasks: u64,
+}
+
+impl ContractMetrics {
+    pub fn average_gas(&self) -> u64 { average(self.total_gas, self.tasks) }
+
+    pub fn average_instructions(&self) -> u64 { average(self.total_instructions, self.tasks - self.failed_tasks) }
+
+    pub fn average_delta_bytes(&self) -> u64 { average(self.total_delta_bytes, self.stored_tasks) }
+
+    fn add_success(&mut self, result: &WasmTaskResult) {
+        self.add_task(result.used_gas, result.instructions);
+        self.peak_memory_bytes = self.peak_memory_bytes.max(result.peak_memory_bytes);
+        self.total_delta_bytes = self.total_delta_bytes.saturating_add(result.delta.value.len() as u64);
+        self.stored_tasks += 1;
+    }
+
+    fn add_failure(&mut self, failure: &WasmTaskFailure) {
+        self.add_task(failure.used_gas, 0);
+        self.failed_tasks += 1;
+    }
+
+    fn add_replay(&mut self, stats: &ReplayStats) {
+        self.add_task(stats.used_gas, stats.instructions);
+        self.replayed_tasks += 1;
+        if stats.failed {
+            self.failed_tasks += 1;
+        }
+    }
+
+    fn add_task(&mut self, used_gas: u64, instructions: u64) {
+        self.tasks += 1;
+        self.total_gas = self.total_gas.saturating_add(used_gas);
+        self.total_instructions = self.total_instructions.saturating_add(instructions);
+    }
+}
+
+fn average(total: u64, count: u64) -> u64 {
+    if count == 0 { 0 } else { total / count }
+}
+
+#[derive(Default)]
+pub struct MetricsCollector {
+    contracts: Mutex<HashMap<ContractAddress, ContractMetrics>>,
+}
+
+impl MetricsCollector {
+    /// Adds a deployment or a computation of `address` to the metrics of the contract.
+    pub fn record(&self, address: ContractAddress, result: &WasmResult) {
+        self.update(address, |metrics| match result {
+            WasmResult::WasmTaskResult(result) => metrics.add_success(result),
+            WasmResult::WasmTaskFailure(failure) => metrics.add_failure(failure),
+        });
+    }
+
+    /// Adds a replay of a computation of `address` to the metrics of the contract, whether it matched the claim or not.
+    pub fn record_replay(&self, address: ContractAddress, stats: &ReplayStats) {
+        self.update(address, |metrics| metrics.add_replay(stats));
+    }
+
+    fn update<F: FnOnce(&mut ContractMetrics)>(&self, address: ContractAddress, f: F) {
+        let mut contracts = self.contracts.lock().unwrap_or_else(PoisonError::into_inner);
+        f(contracts.entry(address).or_insert_with(ContractMetrics::default));
+    }
+
+    /// The metrics of `address`, `None` if it didn't run any task.
+    pub fn get(&self, address: &ContractAddress) -> Option<ContractMetrics> {
+        self.contracts.lock().unwrap_or_else(PoisonError::into_inner).get(address).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn success(used_gas: u64, instructions: u64, peak_memory_bytes: u64, delta_len: usize) -> WasmResult {
+        let mut result = WasmTaskResult::default();
+        result.used_gas = used_gas;
+        result.instructions = instructions;
+        result.peak_memory_bytes = peak_memory_bytes;
+        result.delta.value = vec![0; delta_len];
+        WasmResult::WasmTaskResult(result)
+    }
+
+    #[test]
+    fn test_aggregates_per_contract() {
+        let collector = MetricsCollector::default();
+        let (a, b) = (ContractAddress::from([1u8; 32]), ContractAddress::from([2u8; 32]));
+        assert!(collector.get(&a).is_none());
+
+        collector.record(a, &success(100, 40, 1000, 10));
+        collector.record(a, &success(300, 80, 4000, 30));
+        let mut failure = WasmTaskFailure::default();
+        failure.used_gas = 200;
+        collector.record(a, &WasmResult::WasmTaskFailure(failure));
+        collector.record_replay(a, &ReplayStats { used_gas: 300, instructions: 90, failed: false });
+        collector.record_replay(a, &ReplayStats { used_gas: 100, instructions: 0, failed: true });
+        collector.record(b, &success(7, 7, 7, 7));
+
+        let metrics = collector.get(&a).unwrap();
+        let expected = ContractMetrics {
+            tasks: 5,
+            failed_tasks: 2,
+            replayed_tasks: 2,
+            total_gas: 1000,
+            total_instructions: 210,
+            peak_memory_bytes: 4000,
+            total_delta_bytes: 40,
+            stored_tasks: 2,
+        };
+        assert_eq!(metrics, expected);
+        assert_eq!(metrics.average_gas(), 200);
+        // Only the tasks that didn't fail report their instructions.
+        assert_eq!(metrics.average_instructions(), 70);
+        // Only the stored results have deltas.
+        assert_eq!(metrics.average_delta_bytes(), 20);
+        assert_eq!(collector.get(&b).unwrap().tasks, 1);
+    }
+}
@@ -1,10 +1,14 @@
 pub mod wasm;
+pub mod metrics;
 
 use crate::common_u::errors::EnclaveFailError;
 use crate::db::{Delta, DeltaKey, Stype};
 use std::{fmt, convert::TryFrom};
-use enigma_types::{EnclaveReturn, ExecuteResult, ContractAddress};
-use failure::Error;
+use enigma_types::{EnclaveReturn, ExecuteResult, ContractAddress, DhKey};
+use enigma_crypto::symmetric;
+use ethabi::{ParamType, Token};
+use crate::km_u::fetch_output;
+use failure::{Error, Fail};
 use sgx_types::*;
 
 #[derive(Clone)]
@@ -16,12 +20,27 @@ pub struct WasmTaskResult {
     pub eth_contract_addr: [u8; 20],
     pub signature: [u8; 65],
     pub used_gas: u64,
+    pub constructor_gas: u64,
+    pub storage_gas: u64,
+    pub peak_memory_bytes: u64,
+    pub instructions: u64, // See `ExecuteResult::instructions`
+    pub result_signature: [u8; 65],
+    pub settings_mac: [u8; 32], // Only on Deploy
 }
 
 pub struct WasmTaskFailure {
     pub output: Box<[u8]>,
     pub signature: [u8; 65],
     pub used_gas: u64,
+    pub result_signature: [u8; 65],
+}
+
+/// The result a worker claimed for a compute task, checked by replaying the task (see `wasm::replay_compute`).
+#[derive(Clone, Debug, Default)]
+pub struct ReplayClaim {
+    pub output: Box<[u8]>, // Encrypted with the key of the task, as the worker returned it
+    pub delta: Option<Delta>, // None if the task didn't change the state
+    pub used_gas: u64,
 }
 
 #[derive(Debug)]
@@ -39,7 +58,13 @@ impl Default for WasmTaskResult {
             eth_payload: Default::default(),
             eth_contract_addr: Default::default(),
             signature: [0u8; 65],
-            used_gas: Default::default()
+            used_gas: Default::default(),
+            constructor_gas: Default::default(),
+            storage_gas: Default::default(),
+            peak_memory_bytes: Default::default(),
+            instructions: Default::default(),
+            result_signature: [0u8; 65],
+            settings_mac: Default::default(),
         }
     }
 }
@@ -49,7 +74,8 @@ impl Default for  WasmTaskFailure {
         WasmTaskFailure {
             output: Default::default(),
             signature: [0u8; 65],
-            used_gas: Default::default()
+            used_gas: Default::default(),
+            result_signature: [0u8; 65],
         }
     }
 }
@@ -64,6 +90,12 @@ impl fmt::Debug for WasmTaskResult {
         debug_builder.field("eth_contract_addr", &self.eth_contract_addr);
         debug_builder.field("signature", &(&self.signature[..]));
         debug_builder.field("used_gas", &self.used_gas);
+        debug_builder.field("constructor_gas", &self.constructor_gas);
+        debug_builder.field("storage_gas", &self.storage_gas);
+        debug_builder.field("peak_memory_bytes", &self.peak_memory_bytes);
+        debug_builder.field("instructions", &self.instructions);
+        debug_builder.field("result_signature", &(&self.result_signature[..]));
+        debug_builder.field("settings_mac", &self.settings_mac);
         debug_builder.finish()
     }
 }
@@ -74,61 +106,123 @@ impl fmt::Debug for WasmTaskFailure{
         debug_builder.field("output", &self.output);
         debug_builder.field("signature", &(&self.signature[..]));
         debug_builder.field("used_gas", &self.used_gas);
+        debug_builder.field("result_signature", &(&self.result_signature[..]));
         debug_builder.finish()
     }
 }
 
-impl TryFrom<(ExecuteResult, ContractAddress, EnclaveReturn, sgx_status_t)> for WasmResult {
+/// Decrypts the output of a task with the key the enclave shares with the user and decodes the ABI values of `types` in it.
+/// A function returning a tuple returns every element of it as a separate value, so it has a type for every element.
+pub fn decode_output(output: &[u8], key: &DhKey, types: &[ParamType]) -> Result<Vec<Token>, Error> {
+    let output = symmetric::decrypt(output, key)?;
+    ethabi::decode(types, &output).map_err(|e| format_err!("Failed decoding the output of the task: {}", e))
+}
+
+/// Describes why a Deploy/Compute ecall failed without a signed result, this ends up as the message of
+/// `IpcResponse::Error` so the caller can react accordingly (i.e. provision the state keys again when they're missing).
+/// A failure of the task itself (out of gas, a trap in the contract etc.) is a signed `WasmTaskFailure` and not an error.
+pub fn failure_message(ret: EnclaveReturn, status: sgx_status_t) -> &'static str {
+    if status != sgx_status_t::SGX_SUCCESS {
+        return "The ecall failed with an SGX error";
+    }
+    match ret {
+        EnclaveReturn::KeysError | EnclaveReturn::KeyProvisionError => "The state key of the contract is missing, it needs to be provisioned again",
+        EnclaveReturn::TaskFailure => "The execution of the task failed",
+        EnclaveReturn::EncryptionError => "Failed decrypting the task's inputs or encrypting its results",
+        EnclaveReturn::StateError => "Failed loading the state of the contract",
+        EnclaveReturn::OcallError | EnclaveReturn::OcallDBError => "Failed reading from or writing to the DB",
+        EnclaveReturn::LockError => "A lock in the enclave is poisoned or stayed held for too long",
+        _ => "The enclave failed executing the task",
+    }
+}
+
+/// Fetches an output of `ExecuteResult` the enclave kept for the app (see `km_u::fetch_output`), a handle of 0 is an empty output.
+fn fetch_result_output(eid: sgx_enclave_id_t, handle: u64, len: u64) -> Result<Box<[u8]>, Error> {
+    let mut output = vec![0u8; len as usize];
+    if handle != 0 {
+        fetch_output(eid, handle, &mut output)?;
+    }
+    Ok(output.into_boxed_slice())
+}
+
+impl TryFrom<(ExecuteResult, ContractAddress, EnclaveReturn, sgx_status_t, sgx_enclave_id_t)> for WasmResult {
     type Error = Error;
-    fn try_from(exec: (ExecuteResult, ContractAddress, EnclaveReturn, sgx_status_t)) -> Result<Self, Self::Error> {
-        let get_output  = |exec_result: ExecuteResult| -> Result<Box<[u8]>, Self::Error> {
-            if exec_result.output.is_null() {
-                bail!("The 'output' pointer in ExecuteResult is null: {:?}", exec_result);
-            }
-            let box_ptr = exec.0.output as *mut Box<[u8]>;
-            let output = unsafe { Box::from_raw(box_ptr) };
-            Ok(*output)
-        };
+    fn try_from(exec: (ExecuteResult, ContractAddress, EnclaveReturn, sgx_status_t, sgx_enclave_id_t)) -> Result<Self, Self::Error> {
+        let eid = exec.4;
         if exec.2 == EnclaveReturn::TaskFailure {
             let mut result: WasmTaskFailure = Default::default();
-            result.output = get_output(exec.0)?;
+            result.output = fetch_result_output(eid, exec.0.output_handle, exec.0.output_len)?;
             result.signature = exec.0.signature;
             result.used_gas = exec.0.used_gas;
+            result.result_signature = exec.0.result_signature;
             Ok(WasmResult::WasmTaskFailure(result))
         }
         else if exec.2 != EnclaveReturn::Success || exec.3 != sgx_status_t::SGX_SUCCESS {
-            Err(EnclaveFailError { err: exec.2, status: exec.3 }.into())
+            Err(EnclaveFailError { err: exec.2, status: exec.3 }.context(failure_message(exec.2, exec.3)).into())
         }
         else {
-            if exec.0.ethereum_payload_ptr.is_null() || exec.0.delta_ptr.is_null() {
-                bail!("One of the pointers in ExecuteResult is null: {:?}", exec.0);
-            }
+            // All of them are fetched before failing on any, so the enclave doesn't keep the others.
+            let output = fetch_result_output(eid, exec.0.output_handle, exec.0.output_len);
+            let delta_data = fetch_result_output(eid, exec.0.delta_handle, exec.0.delta_len);
+            let eth_payload = fetch_result_output(eid, exec.0.ethereum_payload_handle, exec.0.ethereum_payload_len);
 
             let mut result: WasmTaskResult = Default::default();
-            // If execution does not return any result, then `output` points to empty array []
-            result.output = get_output(exec.0)?;
+            // If execution does not return any result, then `output` is empty
+            result.output = output?;
             result.signature = exec.0.signature;
             result.used_gas = exec.0.used_gas;
+            result.constructor_gas = exec.0.constructor_gas;
+            result.storage_gas = exec.0.storage_gas;
+            result.peak_memory_bytes = exec.0.peak_memory_bytes;
+            result.instructions = exec.0.instructions;
+            result.result_signature = exec.0.result_signature;
+            result.settings_mac = exec.0.settings_mac;
 
             // If there is no call to any ethereum contract in the execution, then
             // `eth_contract_addr` is all zeros
             result.eth_contract_addr = exec.0.ethereum_address;
 
             // If there is no call to any ethereum contract in the execution, then
-            // `ethereum_payload_ptr` points to empty array []
-            let box_payload_ptr = exec.0.ethereum_payload_ptr as *mut Box<[u8]>;
-            let payload = unsafe { Box::from_raw(box_payload_ptr) };
-            result.eth_payload = *payload;
+            // `eth_payload` is empty
+            result.eth_payload = eth_payload?;
 
             // If state was not changed by the execution (which means that delta is empty),
-            // then `delta_ptr` points to empty array []
-            let box_ptr = exec.0.delta_ptr as *mut Box<[u8]>;
-            let delta_data = unsafe { Box::from_raw(box_ptr) };
-
-            result.delta.value = delta_data.to_vec();
+            // then `delta_data` is empty
+            result.delta.value = delta_data?.to_vec();
             result.delta.key = DeltaKey::new(exec.1, Stype::Delta(exec.0.delta_index));
 
             Ok(WasmResult::WasmTaskResult(result))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_messages() {
+        let messages = [
+            (EnclaveReturn::KeysError, "The state key of the contract is missing, it needs to be provisioned again"),
+            (EnclaveReturn::KeyProvisionError, "The state key of the contract is missing, it needs to be provisioned again"),
+            (EnclaveReturn::TaskFailure, "The execution of the task failed"),
+            (EnclaveReturn::EncryptionError, "Failed decrypting the task's inputs or encrypting its results"),
+            (EnclaveReturn::StateError, "Failed loading the state of the contract"),
+            (EnclaveReturn::OcallDBError, "Failed reading from or writing to the DB"),
+            (EnclaveReturn::LockError, "A lock in the enclave is poisoned or stayed held for too long"),
+            (EnclaveReturn::SgxError, "The enclave failed executing the task"),
+        ];
+        for (ret, msg) in messages.iter() {
+            assert_eq!(failure_message(*ret, sgx_status_t::SGX_SUCCESS), *msg);
+        }
+        assert_eq!(failure_message(EnclaveReturn::Success, sgx_status_t::SGX_ERROR_UNEXPECTED), "The ecall failed with an SGX error");
+    }
+
+    #[test]
+    fn test_failure_message_in_error() {
+        let exec = (ExecuteResult::default(), ContractAddress::default(), EnclaveReturn::KeysError, sgx_status_t::SGX_SUCCESS, 0);
+        let err = WasmResult::try_from(exec).unwrap_err();
+        // This is the message that `unwrap_or_error` puts in `IpcResponse::Error`.
+        assert_eq!(format!("{}", err), "The state key of the contract is missing, it needs to be provisioned again");
+    }
+}
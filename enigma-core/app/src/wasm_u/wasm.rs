@@ -1,14 +1,21 @@
-use enigma_types::{ContractAddress, EnclaveReturn, ExecuteResult, PubKey, RawPointer, traits::SliceCPtr};
-use super::WasmResult;
-use crate::db::DB;
+use enigma_types::{ContractAddress, EnclaveReturn, ExecuteResult, GasMultiplier, HostCapabilities, PubKey, RawPointer, ReplayStats, traits::SliceCPtr};
+use super::{ReplayClaim, WasmResult};
+use crate::db::{ContractSettings, DB};
 use std::convert::TryInto;
 use failure::Error;
 use sgx_types::*;
-use crate::auto_ffi::{ecall_deploy, ecall_execute};
-
+use crate::auto_ffi::{ecall_deploy, ecall_execute, ecall_replay_compute, ecall_set_error_details, ecall_set_max_json_depth, ecall_set_max_state_size,
+                      ecall_set_native_stack_budget, ecall_validate_contract};
+use crate::common_u::errors::EnclaveFailError;
+use crate::km_u::fetch_output;
+use enigma_tools_u::esgx::output_u::{ecall_output, DEFAULT_OUTPUT_CAP};
+
+/// `gas_multiplier` is the price of the contract and `capabilities` the host functions it may import, they're used for the constructor
+/// and on success they're stored with the MAC the enclave produced over them (`DB::set_settings`), the computations read them from there.
 #[logfn(TRACE)]
 pub fn deploy(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], constructor: &[u8], args: &[u8],
-              contract_address: &ContractAddress, user_pubkey: &PubKey, gas_limit: u64)-> Result<WasmResult, Error> {
+              contract_address: &ContractAddress, user_pubkey: &PubKey, deployer: &[u8; 20], gas_limit: u64,
+              capabilities: HostCapabilities, gas_multiplier: GasMultiplier)-> Result<WasmResult, Error> {
     let mut retval = EnclaveReturn::Success;
     let mut result = ExecuteResult::default();
     let db_ptr = unsafe { RawPointer::new_mut(db) };
@@ -24,18 +31,26 @@ pub fn deploy(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], constructor:
                      args.len(),
                      contract_address,
                      user_pubkey.as_ptr() as _,
+                     deployer.as_ptr() as _,
                      &gas_limit as *const u64,
+                     &gas_multiplier as *const GasMultiplier,
+                     &capabilities as *const HostCapabilities,
                      &db_ptr as *const RawPointer,
                      &mut result)
     };
-    (result, *contract_address, retval, status).try_into()
+    let result: WasmResult = (result, *contract_address, retval, status, eid).try_into()?;
+    if let WasmResult::WasmTaskResult(res) = &result {
+        db.set_settings(*contract_address, ContractSettings { gas_multiplier, capabilities, mac: res.settings_mac })?;
+    }
+    Ok(result)
 }
 
 #[logfn(TRACE)]
 pub fn execute(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], callable: &[u8], args: &[u8],
-               user_pubkey: &PubKey, contract_address: &ContractAddress, gas_limit: u64)-> Result<WasmResult,Error> {
+               user_pubkey: &PubKey, contract_address: &ContractAddress, task_id: &[u8; 32], gas_limit: u64)-> Result<WasmResult,Error> {
     let mut retval = EnclaveReturn::Success;
     let mut result = ExecuteResult::default();
+    let settings = db.get_settings(*contract_address)?;
     let db_ptr = unsafe { RawPointer::new_mut(db) };
 
     let status = unsafe {
@@ -49,12 +64,137 @@ pub fn execute(db: &mut DB, eid: sgx_enclave_id_t,  bytecode: &[u8], callable: &
                       args.len(),
                       user_pubkey.as_ptr() as _,
                       contract_address,
+                      task_id.as_ptr() as _,
                       &gas_limit as *const u64,
+                      &settings.gas_multiplier as *const GasMultiplier,
+                      &settings.capabilities as *const HostCapabilities,
+                      settings.mac.as_ptr() as _,
                       &db_ptr as *const RawPointer,
                       &mut result)
     };
 
-    (result, *contract_address, retval, status).try_into()
+    (result, *contract_address, retval, status, eid).try_into()
+}
+
+/// Executes the compute task again without storing anything and checks whether it produces the result in `claim`.
+/// Returns whether it matched, the enclave's signature over that verdict (see `ReplayVerdict`) and what the replay cost.
+/// `callable`, `args`, `user_pubkey` and `task_id` are the ones the worker executed, so the verdict is bound to its signed result.
+/// The key of the task is sent as `task_key`, encrypted with a fresh key exchange with `replay_pubkey`.
+#[logfn(TRACE)]
+pub fn replay_compute(db: &mut DB, eid: sgx_enclave_id_t, bytecode: &[u8], callable: &[u8], args: &[u8], user_pubkey: &PubKey,
+                      replay_pubkey: &PubKey, task_key: &[u8], contract_address: &ContractAddress, task_id: &[u8; 32],
+                      gas_limit: u64, claim: &ReplayClaim)
+    -> Result<(bool, [u8; 65], ReplayStats), Error> {
+    let mut retval = EnclaveReturn::Success;
+    let (mut matches, mut sig, mut stats) = (0u8, [0u8; 65], ReplayStats::default());
+    let settings = db.get_settings(*contract_address)?;
+    let db_ptr = unsafe { RawPointer::new_mut(db) };
+    let (claimed_index, claimed_delta) = match &claim.delta {
+        Some(delta) => (delta.key.key_type.unwrap_delta(), &delta.value[..]),
+        None => (0, &[][..]),
+    };
+
+    let status = unsafe {
+        ecall_replay_compute(eid,
+                             &mut retval,
+                             bytecode.as_c_ptr() as *const u8,
+                             bytecode.len(),
+                             callable.as_c_ptr() as *const u8,
+                             callable.len(),
+                             args.as_c_ptr() as *const u8,
+                             args.len(),
+                             user_pubkey.as_ptr() as _,
+                             replay_pubkey.as_ptr() as _,
+                             task_key.as_c_ptr() as *const u8,
+                             task_key.len(),
+                             contract_address,
+                             task_id.as_ptr() as _,
+                             &gas_limit as *const u64,
+                             &settings.gas_multiplier as *const GasMultiplier,
+                             &settings.capabilities as *const HostCapabilities,
+                             settings.mac.as_ptr() as _,
+                             &db_ptr as *const RawPointer,
+                             claimed_delta.as_c_ptr() as *const u8,
+                             claimed_delta.len(),
+                             claimed_index,
+                             claim.output.as_c_ptr() as *const u8,
+                             claim.output.len(),
+                             claim.used_gas,
+                             &mut matches,
+                             &mut sig,
+                             &mut stats)
+    };
+    if retval != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: retval, status }.into());
+    }
+    Ok((matches == 1, sig, stats))
+}
+
+/// Checks that `bytecode` would pass the module creation and the instantiation of a deployment with `gas_multiplier` and `capabilities`
+/// (the gas and stack height instrumentation, the memory limit and the imports) without storing it or running its constructor.
+/// Returns the reason the enclave rejected the bytecode, `None` if it's valid.
+#[logfn(TRACE)]
+pub fn validate_contract(eid: sgx_enclave_id_t, bytecode: &[u8], gas_multiplier: GasMultiplier, capabilities: HostCapabilities)
+    -> Result<Option<String>, Error> {
+    let reason = ecall_output(DEFAULT_OUTPUT_CAP, |output, len, handle| {
+        let mut retval = EnclaveReturn::Success;
+        let status = unsafe {
+            ecall_validate_contract(eid, &mut retval, bytecode.as_c_ptr(), bytecode.len(), &gas_multiplier, &capabilities,
+                                    output.as_mut_ptr(), output.len(), len, handle)
+        };
+        if retval != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+            return Err(EnclaveFailError { err: retval, status }.into());
+        }
+        Ok(())
+    }, |handle, output| fetch_output(eid, handle, output))?;
+    if reason.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(reason)?))
+}
+
+/// Sets how many bytes of the enclave thread's stack the executions that start afterwards may use,
+/// an execution that goes over it (e.g. by writing a deeply nested value to the state) fails instead of crashing the enclave.
+#[logfn(TRACE)]
+pub fn set_native_stack_budget(eid: sgx_enclave_id_t, budget: usize) -> Result<(), Error> {
+    let status = unsafe { ecall_set_native_stack_budget(eid, budget) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: EnclaveReturn::Success, status }.into());
+    }
+    Ok(())
+}
+
+/// Sets the deepest nesting of arrays and objects a contract may write to its state (or read from it),
+/// going deeper fails the task instead of risking the recursion of the JSON handling inside the enclave.
+#[logfn(TRACE)]
+pub fn set_max_json_depth(eid: sgx_enclave_id_t, depth: usize) -> Result<(), Error> {
+    let status = unsafe { ecall_set_max_json_depth(eid, depth) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: EnclaveReturn::Success, status }.into());
+    }
+    Ok(())
+}
+
+/// Sets the largest serialized state (in bytes) a contract may have (0 for no limit),
+/// a task that leaves the state larger than that fails so no delta is generated.
+#[logfn(TRACE)]
+pub fn set_max_state_size(eid: sgx_enclave_id_t, size: usize) -> Result<(), Error> {
+    let status = unsafe { ecall_set_max_state_size(eid, size) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: EnclaveReturn::Success, status }.into());
+    }
+    Ok(())
+}
+
+/// Makes the enclave show the fields of errors that may hold secret material (decrypted inputs, state, reasons of reverts),
+/// they're redacted by default since the errors are logged and returned over IPC. Only for debugging.
+#[logfn(TRACE)]
+pub fn set_error_details(eid: sgx_enclave_id_t, enabled: bool) -> Result<(), Error> {
+    let status = unsafe { ecall_set_error_details(eid, enabled as u8) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: EnclaveReturn::Success, status }.into());
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -64,20 +204,26 @@ mod tests {
 
     use self::cross_test_utils::{generate_contract_address, sign_message, generate_user_address, get_bytecode_from_path};
     use crate::esgx::general::init_enclave_wrapper;
+    use crate::esgx::ocalls_u::write_ocalls_count;
+    use crate::esgx::equote::get_register_signing_address;
     use crate::km_u::tests::exchange_keys;
     use crate::km_u::tests::instantiate_encryption_key;
-    use crate::db::{DB, tests::create_test_db};
-    use crate::wasm_u::wasm;
+    use crate::db::{DB, Delta, DeltaKey, P2PCalls, tests::create_test_db};
+    use crate::wasm_u::{decode_output, wasm};
     use self::ethabi::{Contract, Token, token::{LenientTokenizer, Tokenizer}};
-    use enigma_types::{ContractAddress, DhKey, PubKey};
-    use enigma_crypto::symmetric;
+    use enigma_types::{ContractAddress, DhKey, GasMultiplier, HostCapabilities, PubKey, ResultStatus};
+    use enigma_crypto::{hash::{prepare_hash_multiple, Keccak256}, symmetric};
+    use enigma_tools_m::primitives::{signature_domain::SignatureDomain, task_primitives::{compute_result_message, DeployReceipt, ReplayVerdict}};
+    use enigma_tools_m::utils::EthereumAddress;
     use hex::FromHex;
     use sgx_types::*;
     use std::fs::File;
-    use wasm_u::{WasmResult, WasmTaskResult};
+    use wasm_u::{ReplayClaim, WasmResult, WasmTaskResult};
     use self::ethabi::Uint;
 
     pub const GAS_LIMIT: u64 = 100_000_000;
+    pub const TASK_ID: [u8; 32] = [7u8; 32];
+    pub const DEPLOYER: [u8; 20] = [9u8; 20];
 
     impl WasmResult {
         fn unwrap_result(self) -> WasmTaskResult {
@@ -92,7 +238,7 @@ mod tests {
         let wasm_code = get_bytecode_from_path(test_path);
         println!("Bytecode size: {}KB\n", wasm_code.len() / 1024);
 
-        wasm::deploy(db, eid, &wasm_code, constructor, args, &contract_address, &user_pubkey, GAS_LIMIT).expect("Deploy Failed")
+        wasm::deploy(db, eid, &wasm_code, constructor, args, &contract_address, &user_pubkey, &DEPLOYER, GAS_LIMIT, HostCapabilities::default(), GasMultiplier::default()).expect("Deploy Failed")
     }
 
     fn compile_deploy_execute(db: &mut DB,
@@ -132,6 +278,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &contract_address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -187,6 +334,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &contract_address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -222,6 +370,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -240,12 +389,669 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
         assert!(used_gas_for_write_new_value - result.used_gas >= 1);
     }
 
+    #[test]
+    fn test_gas_multiplier() {
+        let (mut db, _dir) = create_test_db();
+        let enclave = init_enclave_wrapper().unwrap();
+        let wasm_code = get_bytecode_from_path("../../examples/eng_wasm_contracts/simplest");
+
+        let mut used_gas = |mul: u32| {
+            let address = generate_contract_address();
+            let multiplier = GasMultiplier { mul, div: 1 };
+            instantiate_encryption_key(vec![address], enclave.geteid());
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_construct = symmetric::encrypt(b"construct(uint)", &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(1.into())]), &shared_key).unwrap();
+            let deploy_res = wasm::deploy(&mut db, enclave.geteid(), &wasm_code, &encrypted_construct, &encrypted_args, &address,
+                                          &keys.get_pubkey(), &DEPLOYER, GAS_LIMIT, HostCapabilities::default(), multiplier).expect("Deploy Failed").unwrap_result();
+            let (result, _) = compile_compute_task_execute(&mut db, &enclave, &deploy_res, "addition(uint256,uint256)",
+                                                           &[Token::Uint(100.into()), Token::Uint(100.into())], address);
+            result.used_gas
+        };
+        let (once, twice, thrice) = (used_gas(1), used_gas(2), used_gas(3));
+        // Only the instructions are priced by the multiplier, the rest of the costs (e.g. the writes) are the same for all of them.
+        assert!(twice > once);
+        assert_eq!(thrice - once, 2 * (twice - once));
+    }
+
+    #[test]
+    fn test_forged_gas_multiplier() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let (enclave, contract_code, _, _) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+            "get_last_sum()",
+            &[]
+        );
+        // The DB is untrusted, a cheaper price with the MAC of the real one is refused.
+        let mut settings = db.get_settings(address).unwrap();
+        assert_eq!(settings.gas_multiplier, GasMultiplier::default());
+        settings.gas_multiplier = GasMultiplier { mul: 1, div: 100 };
+        db.set_settings(address, settings).unwrap();
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"get_last_sum()", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+        assert!(wasm::execute(&mut db, enclave.geteid(), &contract_code, &encrypted_callable, &encrypted_args,
+                              &keys.get_pubkey(), &address, &TASK_ID, GAS_LIMIT).is_err());
+    }
+
+    #[test]
+    fn test_peak_memory_bytes() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+
+        let (enclave, contract_code, baseline, _) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+            "get_last_sum()",
+            &[]
+        );
+        assert!(baseline.peak_memory_bytes > 0);
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"grow_memory(uint256)", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint((4 * 1024 * 1024).into())]), &shared_key).unwrap();
+        let result = wasm::execute(
+            &mut db,
+            enclave.geteid(),
+            &contract_code,
+            &encrypted_callable,
+            &encrypted_args,
+            &keys.get_pubkey(),
+            &address,
+            &TASK_ID,
+            GAS_LIMIT
+        ).expect("Execution failed").unwrap_result();
+
+        assert!(result.peak_memory_bytes > baseline.peak_memory_bytes);
+        assert!(result.peak_memory_bytes <= 128 * 64 * 1024);
+    }
+
+    #[test]
+    fn test_result_signature() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let (enclave, _, result, _) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+            "addition(uint,uint)",
+            &[Token::Uint(2.into()), Token::Uint(3.into())]
+        );
+        let signer = get_register_signing_address(enclave.geteid()).unwrap();
+
+        let delta_hash = result.delta.value.keccak256();
+        let message = compute_result_message(&result.output, &delta_hash, result.used_gas, &TASK_ID, ResultStatus::Ok);
+        SignatureDomain::Output.verify(&message, result.result_signature, &signer).unwrap();
+        // It's only valid as a signature over an output.
+        assert!(SignatureDomain::DeployReceipt.verify(&message, result.result_signature, &signer).is_err());
+
+        // The same output and signature with another task id don't verify.
+        let message = compute_result_message(&result.output, &delta_hash, result.used_gas, &[0u8; 32], ResultStatus::Ok);
+        assert!(SignatureDomain::Output.verify(&message, result.result_signature, &signer).is_err());
+    }
+
+    #[test]
+    fn test_result_signature_altered_delta() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let (enclave, _, result, _) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+            "addition(uint,uint)",
+            &[Token::Uint(2.into()), Token::Uint(3.into())]
+        );
+        let signer = get_register_signing_address(enclave.geteid()).unwrap();
+
+        let mut delta_hash = result.delta.value.keccak256();
+        delta_hash[0] ^= 1;
+        let message = compute_result_message(&result.output, &delta_hash, result.used_gas, &TASK_ID, ResultStatus::Ok);
+        assert!(SignatureDomain::Output.verify(&message, result.result_signature, &signer).is_err());
+    }
+
+    fn deploy_with_receipt(db: &mut DB) -> ([u8; 20], DeployReceipt, [u8; 65]) {
+        let address = generate_contract_address();
+        let enclave = init_enclave_wrapper().unwrap();
+        instantiate_encryption_key(vec![address], enclave.geteid());
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_construct = symmetric::encrypt(b"construct(uint)", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(1.into())]), &shared_key).unwrap();
+        let wasm_code = get_bytecode_from_path("../../examples/eng_wasm_contracts/simplest");
+        let result = wasm::deploy(db, enclave.geteid(), &wasm_code, &encrypted_construct, &encrypted_args, &address,
+                                  &keys.get_pubkey(), &DEPLOYER, GAS_LIMIT, HostCapabilities::default(), GasMultiplier::default()).expect("Deploy Failed").unwrap_result();
+
+        let signer = get_register_signing_address(enclave.geteid()).unwrap();
+        let receipt = DeployReceipt {
+            address,
+            precode_hash: wasm_code.keccak256(),
+            deployer: DEPLOYER,
+            delta_hash: result.delta.value.keccak256(),
+            gas_multiplier: GasMultiplier::default(),
+            capabilities: HostCapabilities::default(),
+        };
+        (signer, receipt, result.result_signature)
+    }
+
+    #[test]
+    fn test_deploy_receipt() {
+        let (mut db, _dir) = create_test_db();
+        let (signer, receipt, signature) = deploy_with_receipt(&mut db);
+        SignatureDomain::DeployReceipt.verify(&receipt.to_sign(), signature, &signer).unwrap();
+    }
+
+    #[test]
+    fn test_deploy_receipt_altered_address() {
+        let (mut db, _dir) = create_test_db();
+        let (signer, mut receipt, signature) = deploy_with_receipt(&mut db);
+        receipt.address = generate_contract_address();
+        assert!(SignatureDomain::DeployReceipt.verify(&receipt.to_sign(), signature, &signer).is_err());
+    }
+
+    #[test]
+    fn test_deploy_receipt_altered_deployer() {
+        let (mut db, _dir) = create_test_db();
+        let (signer, mut receipt, signature) = deploy_with_receipt(&mut db);
+        receipt.deployer = [8u8; 20];
+        assert!(SignatureDomain::DeployReceipt.verify(&receipt.to_sign(), signature, &signer).is_err());
+    }
+
+    #[test]
+    fn test_deploy_receipt_altered_gas_multiplier() {
+        let (mut db, _dir) = create_test_db();
+        let (signer, mut receipt, signature) = deploy_with_receipt(&mut db);
+        receipt.gas_multiplier = GasMultiplier { mul: 1, div: 2 };
+        assert!(SignatureDomain::DeployReceipt.verify(&receipt.to_sign(), signature, &signer).is_err());
+    }
+
+    #[test]
+    fn test_deploy_receipt_altered_capabilities() {
+        let (mut db, _dir) = create_test_db();
+        let (signer, mut receipt, signature) = deploy_with_receipt(&mut db);
+        receipt.capabilities = HostCapabilities::ALL.without(HostCapabilities::RANDOMNESS);
+        assert!(SignatureDomain::DeployReceipt.verify(&receipt.to_sign(), signature, &signer).is_err());
+    }
+
+    #[test]
+    fn test_big_integer_state() {
+        let (mut db, _dir) = create_test_db();
+        let big = Uint::MAX - Uint::from(1);
+        let (_, _, result, shared_key) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            generate_contract_address(),
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+            "store_big(uint256)",
+            &[Token::Uint(big)]
+        );
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        assert_eq!(ethabi::decode(&[ethabi::ParamType::Uint(256)], &output).unwrap()[0], Token::Uint(big));
+    }
+
+    #[test]
+    fn test_multiple_return_values() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+        );
+        let types = [
+            ethabi::ParamType::Array(Box::new(ethabi::ParamType::FixedBytes(32))),
+            ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256))),
+        ];
+        let expected = vec![
+            Token::Array(vec![Token::FixedBytes(vec![1u8; 32]), Token::FixedBytes(vec![2u8; 32])]),
+            Token::Array(vec![Token::Uint(10.into()), Token::Uint(20.into()), Token::Uint(30.into())]),
+        ];
+
+        for func in &["get_parties()", "get_parties_manually()"] {
+            let (result, shared_key) = compile_compute_task_execute(&mut db, &enclave, &deploy_res, func, &[], address);
+            assert_eq!(decode_output(&result.output, &shared_key, &types).unwrap(), expected, "{}", func);
+            // Only the user can decode it.
+            assert!(decode_output(&result.output, &[0u8; 32], &types).is_err());
+        }
+    }
+
+    #[test]
+    fn test_namespaced_state() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+        );
+        let args = [Token::Uint(5.into()), Token::Uint(7.into())];
+        let (result, shared_key) = compile_compute_task_execute(&mut db, &enclave, &deploy_res, "write_namespaces(uint256,uint256)", &args, address);
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        let types = [ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256)))];
+        let expected = vec![Token::Array(vec![Token::Uint(5.into()), Token::Uint(7.into())])];
+        assert_eq!(ethabi::decode(&types, &output).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_next_counter() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+        );
+        let types = [ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256)))];
+        let counters = |values: &[u64]| vec![Token::Array(values.iter().map(|v| Token::Uint((*v).into())).collect())];
+
+        let (result, shared_key) = compile_compute_task_execute(&mut db, &enclave, &deploy_res, "next_counters()", &[], address);
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        assert_eq!(ethabi::decode(&types, &output).unwrap(), counters(&[0, 1, 2]));
+
+        // The counter was committed to the state, so the next computation continues from it.
+        let (result, shared_key) = compile_compute_task_execute(&mut db, &enclave, &deploy_res, "next_counters()", &[], address);
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        assert_eq!(ethabi::decode(&types, &output).unwrap(), counters(&[3, 4, 5]));
+    }
+
+    #[test]
+    fn test_u128() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+        );
+        let types = [ethabi::ParamType::Uint(128)];
+        let big = (Uint::from(1) << 127) + Uint::from(12345);
+
+        let (result, shared_key) = compile_compute_task_execute(&mut db, &enclave, &deploy_res, "store_u128(uint128)", &[Token::Uint(big)], address);
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        assert_eq!(ethabi::decode(&types, &output).unwrap(), vec![Token::Uint(big)]);
+        let (result, shared_key) = compile_compute_task_execute(&mut db, &enclave, &deploy_res, "get_u128()", &[], address);
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        assert_eq!(ethabi::decode(&types, &output).unwrap(), vec![Token::Uint(big)]);
+
+        // 2^128 doesn't fit in a u128, the task fails and the stored value is kept.
+        let (tip_before, _) = db.get_tip::<DeltaKey>(&address).unwrap();
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"store_u128(uint128)", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(Uint::from(1) << 128)]), &shared_key).unwrap();
+        let result = wasm::execute(&mut db, enclave.geteid(), &deploy_res.output, &encrypted_callable, &encrypted_args,
+                                   &keys.get_pubkey(), &address, &TASK_ID, GAS_LIMIT).expect("Execution failed");
+        match result {
+            WasmResult::WasmTaskFailure(_) => (),
+            WasmResult::WasmTaskResult(_) => panic!("Expected decoding the argument to fail"),
+        }
+        let (tip_after, _) = db.get_tip::<DeltaKey>(&address).unwrap();
+        assert_eq!(tip_after, tip_before);
+    }
+
+    #[test]
+    fn test_refund_for_removed_state() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+
+        let (enclave, contract_code, _, _) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+            "fill_state()",
+            &[]
+        );
+
+        let mut clear_state = || {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_callable = symmetric::encrypt(b"clear_state()", &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+            wasm::execute(
+                &mut db,
+                enclave.geteid(),
+                &contract_code,
+                &encrypted_callable,
+                &encrypted_args,
+                &keys.get_pubkey(),
+                &address,
+                &TASK_ID,
+                GAS_LIMIT
+            ).expect("Execution failed").unwrap_result()
+        };
+        // The first call removes the large value, the second one is a no-op since the key is already gone.
+        let removed = clear_state();
+        let no_op = clear_state();
+
+        assert!(!removed.delta.value.is_empty());
+        assert!(no_op.delta.value.is_empty());
+        // "enigma" * 200 serialized as a json string is 1202 bytes.
+        assert_eq!(no_op.used_gas - removed.used_gas, 1202);
+    }
+
+    #[test]
+    fn test_write_invalid_bytes_to_state() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+        );
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"write_invalid_bytes()", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+        // The runtime should fail the task cleanly instead of panicking inside the enclave.
+        let result = wasm::execute(
+            &mut db,
+            enclave.geteid(),
+            &deploy_res.output,
+            &encrypted_callable,
+            &encrypted_args,
+            &keys.get_pubkey(),
+            &address,
+            &TASK_ID,
+            GAS_LIMIT
+        ).expect("Execution failed");
+
+        match result {
+            WasmResult::WasmTaskFailure(_) => (),
+            WasmResult::WasmTaskResult(_) => panic!("Expected the task to fail"),
+        }
+    }
+
+    #[test]
+    fn test_native_stack_budget() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+        );
+        let execute_nested = |db: &mut DB| {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_callable = symmetric::encrypt(b"write_nested(uint)", &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(100.into())]), &shared_key).unwrap();
+            wasm::execute(
+                db,
+                enclave.geteid(),
+                &deploy_res.output,
+                &encrypted_callable,
+                &encrypted_args,
+                &keys.get_pubkey(),
+                &address,
+                &TASK_ID,
+                GAS_LIMIT
+            ).expect("Execution failed")
+        };
+
+        // Going over the budget fails the task, and the enclave keeps working afterwards.
+        wasm::set_native_stack_budget(enclave.geteid(), 1024).unwrap();
+        match execute_nested(&mut db) {
+            WasmResult::WasmTaskFailure(_) => (),
+            WasmResult::WasmTaskResult(_) => panic!("Expected the task to fail"),
+        }
+        wasm::set_native_stack_budget(enclave.geteid(), 128 * 1024).unwrap();
+        execute_nested(&mut db).unwrap_result();
+    }
+
+    #[test]
+    fn test_max_json_depth() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+        );
+        let execute_nested = |db: &mut DB, depth: u64| {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_callable = symmetric::encrypt(b"write_nested(uint)", &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(depth.into())]), &shared_key).unwrap();
+            wasm::execute(
+                db,
+                enclave.geteid(),
+                &deploy_res.output,
+                &encrypted_callable,
+                &encrypted_args,
+                &keys.get_pubkey(),
+                &address,
+                &TASK_ID,
+                GAS_LIMIT
+            ).expect("Execution failed")
+        };
+
+        // Nesting past the limit is a failed task, not a crashed enclave.
+        wasm::set_max_json_depth(enclave.geteid(), 16).unwrap();
+        match execute_nested(&mut db, 17) {
+            WasmResult::WasmTaskFailure(_) => (),
+            WasmResult::WasmTaskResult(_) => panic!("Expected the task to fail"),
+        }
+        execute_nested(&mut db, 16).unwrap_result();
+        wasm::set_max_json_depth(enclave.geteid(), 127).unwrap();
+        execute_nested(&mut db, 17).unwrap_result();
+    }
+
+    #[test]
+    fn test_max_state_size() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(1.into())],
+        );
+        let fill_state = |db: &mut DB| {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_callable = symmetric::encrypt(b"fill_state()", &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+            wasm::execute(
+                db,
+                enclave.geteid(),
+                &deploy_res.output,
+                &encrypted_callable,
+                &encrypted_args,
+                &keys.get_pubkey(),
+                &address,
+                &TASK_ID,
+                GAS_LIMIT
+            ).expect("Execution failed")
+        };
+
+        // fill_state writes 1200 bytes, leaving the state past the limit fails the task and leaves no delta.
+        wasm::set_max_state_size(enclave.geteid(), 1000).unwrap();
+        let result = fill_state(&mut db);
+        wasm::set_max_state_size(enclave.geteid(), 0).unwrap();
+        match result {
+            WasmResult::WasmTaskFailure(_) => (),
+            WasmResult::WasmTaskResult(_) => panic!("Expected the task to fail"),
+        }
+        let (tip_key, _) = db.get_tip::<DeltaKey>(&address).unwrap();
+        assert_eq!(tip_key, deploy_res.delta.key);
+
+        // Without the limit the same write goes through.
+        let result = fill_state(&mut db).unwrap_result();
+        let (tip_key, _) = db.get_tip::<DeltaKey>(&address).unwrap();
+        assert_eq!(tip_key, result.delta.key);
+    }
+
+    #[test]
+    fn test_deploy_with_forbidden_import() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let enclave = init_enclave_wrapper().unwrap();
+        instantiate_encryption_key(vec![address], enclave.geteid());
+        // The simplest contract imports `rand` (in `choose_rand_color`).
+        let wasm_code = get_bytecode_from_path("../../examples/eng_wasm_contracts/simplest");
+
+        let mut deploy = |capabilities| {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_construct = symmetric::encrypt(b"construct(uint)", &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(1.into())]), &shared_key).unwrap();
+            wasm::deploy(
+                &mut db,
+                enclave.geteid(),
+                &wasm_code,
+                &encrypted_construct,
+                &encrypted_args,
+                &address,
+                &keys.get_pubkey(),
+                &DEPLOYER,
+                GAS_LIMIT,
+                capabilities,
+                GasMultiplier::default()
+            ).expect("Deploy Failed")
+        };
+
+        match deploy(HostCapabilities::ALL.without(HostCapabilities::RANDOMNESS)) {
+            WasmResult::WasmTaskFailure(_) => (),
+            WasmResult::WasmTaskResult(_) => panic!("Expected the deployment to fail"),
+        }
+        // Forbidding a capability the contract doesn't use doesn't affect it.
+        deploy(HostCapabilities::ALL.without(HostCapabilities::ETHEREUM_BRIDGE)).unwrap_result();
+    }
+
+    #[test]
+    fn test_deploy_memory_limit_exceeded() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let enclave = init_enclave_wrapper().unwrap();
+        instantiate_encryption_key(vec![address], enclave.geteid());
+        // A module with a memory of 200 pages, the constructor built of it imports `env.memory` with as many.
+        let large_memory = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x00, 0xc8, 0x01];
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_construct = symmetric::encrypt(b"construct()", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+        let result = wasm::deploy(
+            &mut db,
+            enclave.geteid(),
+            &large_memory,
+            &encrypted_construct,
+            &encrypted_args,
+            &address,
+            &keys.get_pubkey(),
+            &DEPLOYER,
+            GAS_LIMIT,
+            HostCapabilities::ALL,
+            GasMultiplier::default()
+        ).expect("Deploy Failed");
+
+        let failure = match result {
+            WasmResult::WasmTaskFailure(failure) => failure,
+            WasmResult::WasmTaskResult(_) => panic!("Expected the deployment to fail"),
+        };
+        let error = String::from_utf8(symmetric::decrypt(&failure.output, &shared_key).unwrap()).unwrap();
+        assert!(error.contains("200 pages") && error.contains("at most 128"), "unexpected error: {}", error);
+    }
+
+    #[test]
+    fn test_compute_with_forbidden_import() {
+        let (mut db, _dir) = create_test_db();
+        let (address, rand_address) = (generate_contract_address(), generate_contract_address());
+        let enclave = init_enclave_wrapper().unwrap();
+        instantiate_encryption_key(vec![address, rand_address], enclave.geteid());
+        let no_randomness = HostCapabilities::ALL.without(HostCapabilities::RANDOMNESS);
+        let deploy = |db: &mut DB, path: &str, address: ContractAddress, constructor: &str, args: &[Token], capabilities| {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_construct = symmetric::encrypt(constructor.as_bytes(), &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(args), &shared_key).unwrap();
+            wasm::deploy(db, enclave.geteid(), &get_bytecode_from_path(path), &encrypted_construct, &encrypted_args, &address,
+                         &keys.get_pubkey(), &DEPLOYER, GAS_LIMIT, capabilities, GasMultiplier::default()).expect("Deploy Failed").unwrap_result()
+        };
+        // A contract without randomness, deployed without it.
+        deploy(&mut db, "../../examples/eng_wasm_contracts/contract_with_eth_calls", address, "construct()", &[], no_randomness);
+        assert_eq!(db.get_settings(address).unwrap().capabilities, no_randomness);
+        // The deployed bytecode of a contract that imports `rand`.
+        let rand_code = deploy(&mut db, "../../examples/eng_wasm_contracts/simplest", rand_address, "construct(uint)",
+                               &[Token::Uint(1.into())], HostCapabilities::ALL).output;
+
+        let compute = |db: &mut DB| {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_callable = symmetric::encrypt(b"choose_rand_color()", &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+            wasm::execute(db, enclave.geteid(), &rand_code, &encrypted_callable, &encrypted_args, &keys.get_pubkey(), &address, &TASK_ID, GAS_LIMIT)
+        };
+        // The bytecode of a computation comes from the untrusted side, so its imports are checked against the deployment's capabilities.
+        if let Ok(WasmResult::WasmTaskResult(_)) = compute(&mut db) {
+            panic!("Expected the computation to fail");
+        }
+        // And the capabilities can't be changed in the DB.
+        let mut settings = db.get_settings(address).unwrap();
+        settings.capabilities = HostCapabilities::ALL;
+        db.set_settings(address, settings).unwrap();
+        assert!(compute(&mut db).is_err());
+    }
+
+    #[test]
+    fn test_validate_contract() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let wasm_code = get_bytecode_from_path("../../examples/eng_wasm_contracts/simplest");
+        let validate = |code: &[u8], capabilities| wasm::validate_contract(enclave.geteid(), code, GasMultiplier::default(), capabilities).unwrap();
+        assert_eq!(validate(&wasm_code[..], HostCapabilities::ALL), None);
+
+        // A module with a memory section of a single page instead of importing `env.memory`.
+        let internal_memory = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01];
+        let reason = validate(&internal_memory[..], HostCapabilities::ALL).unwrap();
+        assert!(reason.contains("internal memory"), "{}", reason);
+
+        let reason = validate(&b"not wasm"[..], HostCapabilities::ALL).unwrap();
+        assert!(reason.contains("deserialization"), "{}", reason);
+
+        // A module that imports `env.memory` with 200 pages, more than a contract may have.
+        let large_memory = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            0x02, 0x12, 0x01, 0x03, b'e', b'n', b'v', 0x06,
+            b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x01, 0xc8, 0x01, 0xc8, 0x01,
+        ];
+        let reason = validate(&large_memory[..], HostCapabilities::ALL).unwrap();
+        assert!(reason.contains("200 pages"), "{}", reason);
+
+        // The simplest contract imports `rand`, so it can't be deployed without the randomness capability.
+        assert!(validate(&wasm_code[..], HostCapabilities::ALL.without(HostCapabilities::RANDOMNESS)).is_some());
+    }
+
     #[test]
     fn test_flip() {
         let (mut db, _dir) = create_test_db();
@@ -275,6 +1081,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -289,6 +1096,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -348,6 +1156,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &contract_address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed");
 
@@ -405,6 +1214,7 @@ mod tests {
             &encrypted_args2,
             &keys2.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -424,6 +1234,7 @@ mod tests {
             &encrypted_args3,
             &keys3.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -443,6 +1254,7 @@ mod tests {
             &encrypted_args4,
             &keys4.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -504,6 +1316,228 @@ mod tests {
         assert_eq!(symmetric::decrypt(&result.output, &shared_key).unwrap(), *addr);
     }
 
+    #[test]
+    fn test_eth_address() {
+        let (mut db, _dir) = create_test_db();
+        // ethereum addresses are 20 bytes, unlike our protocol addresses
+        let addr = ethabi::Address::from_slice(&generate_user_address().0[12..]);
+        let (_, _, result, shared_key) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            generate_contract_address(),
+            "construct(uint)",
+            &[Token::Uint(100.into())],
+            "check_eth_address(address)",
+            &[Token::Address(addr)]
+        );
+
+        let encoded_output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        let decoded_output = ethabi::decode(&[ethabi::ParamType::Address], &encoded_output).unwrap();
+        assert_eq!(decoded_output[0], Token::Address(addr));
+    }
+
+    #[test]
+    fn test_get_caller() {
+        let (mut db, _dir) = create_test_db();
+        let contract_address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            contract_address,
+            "construct(uint)",
+            &[Token::Uint(100.into())],
+        );
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let user: [u8; 20] = keys.get_pubkey().address();
+        let encrypted_callable = symmetric::encrypt(b"store_caller()", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+        let result = wasm::execute(&mut db, enclave.geteid(), &deploy_res.output, &encrypted_callable, &encrypted_args,
+                                   &keys.get_pubkey(), &contract_address, &TASK_ID, GAS_LIMIT).expect("Execution failed").unwrap_result();
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        assert_eq!(ethabi::decode(&[ethabi::ParamType::Address], &output).unwrap()[0], Token::Address(user.into()));
+
+        // The caller was written to the state, and another user reads the address of the first one.
+        let (result, shared_key) = compile_compute_task_execute(&mut db, &enclave, &deploy_res, "get_stored_caller()", &[], contract_address);
+        let output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        assert_eq!(ethabi::decode(&[ethabi::ParamType::Address], &output).unwrap()[0], Token::Address(user.into()));
+    }
+
+    #[test]
+    fn test_replay_compute() {
+        let (mut db, _dir) = create_test_db();
+        let contract_address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            contract_address,
+            "construct(uint)",
+            &[Token::Uint(100.into())],
+        );
+        // The task uses randomness, so replaying it only matches if the randomness is seeded by the task.
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"choose_rand_color()", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+        let result = wasm::execute(&mut db, enclave.geteid(), &deploy_res.output, &encrypted_callable, &encrypted_args,
+                                   &keys.get_pubkey(), &contract_address, &TASK_ID, GAS_LIMIT).expect("Execution failed").unwrap_result();
+        let inputs_hash = prepare_hash_multiple(&[&encrypted_callable[..], &encrypted_args[..], &contract_address[..], &keys.get_pubkey()[..]]).keccak256();
+
+        // The replay gets the inputs the worker executed and the key of the task, sent over a fresh key exchange.
+        let replay = |db: &mut DB, output: Box<[u8]>, delta: Delta| {
+            let (replay_keys, replay_shared_key, _, _) = exchange_keys(enclave.geteid());
+            let task_key = symmetric::encrypt(&shared_key, &replay_shared_key).unwrap();
+            let claim = ReplayClaim { output, delta: Some(delta), used_gas: result.used_gas };
+            let (matches, sig, stats) = wasm::replay_compute(db, enclave.geteid(), &deploy_res.output, &encrypted_callable, &encrypted_args,
+                                                             &keys.get_pubkey(), &replay_keys.get_pubkey(), &task_key,
+                                                             &contract_address, &TASK_ID, GAS_LIMIT, &claim).unwrap();
+            let verdict = ReplayVerdict {
+                task_id: TASK_ID,
+                inputs_hash,
+                exe_code_hash: deploy_res.output.keccak256(),
+                gas_limit: GAS_LIMIT,
+                claimed_delta_hash: claim.delta.as_ref().unwrap().value.keccak256(),
+                claimed_output_hash: claim.output.keccak256(),
+                claimed_gas: claim.used_gas,
+                matches,
+            };
+            let signer = get_register_signing_address(enclave.geteid()).unwrap();
+            SignatureDomain::Replay.verify(&verdict.to_sign(), sig, &signer).unwrap();
+            // The verdict can't be presented for other inputs.
+            let other_inputs = ReplayVerdict { inputs_hash: prepare_hash_multiple(&[&encrypted_args[..]]).keccak256(), ..verdict };
+            assert!(SignatureDomain::Replay.verify(&other_inputs.to_sign(), sig, &signer).is_err());
+            assert!(!stats.failed);
+            (matches, stats)
+        };
+
+        // The verdict is over the output and the delta exactly as the worker returned and signed them.
+        let (matches, stats) = replay(&mut db, result.output.clone(), result.delta.clone());
+        assert!(matches);
+        // The replay ran exactly what the task did.
+        assert_eq!((stats.used_gas, stats.instructions), (result.used_gas, result.instructions));
+        assert_ne!(stats.instructions, 0);
+        let mut tampered = result.delta.clone();
+        let last = tampered.value.len() - 1;
+        tampered.value[last] ^= 1;
+        assert!(!replay(&mut db, result.output.clone(), tampered).0);
+        // An output encrypted properly with the key of the task but that isn't the one the task produced.
+        let other_output = symmetric::encrypt(&ethabi::encode(&[Token::Uint(1.into())]), &shared_key).unwrap().into_boxed_slice();
+        assert!(!replay(&mut db, other_output, result.delta.clone()).0);
+        // Replaying didn't store anything.
+        let (tip_key, _) = db.get_tip::<DeltaKey>(&contract_address).unwrap();
+        assert_eq!(tip_key.key_type, result.delta.key.key_type);
+    }
+
+    #[test]
+    fn test_trap_discards_state() {
+        let (mut db, _dir) = create_test_db();
+        let contract_address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            contract_address,
+            "construct(uint)",
+            &[Token::Uint(100.into())],
+        );
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"write_and_panic()", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+        let result = wasm::execute(
+            &mut db,
+            enclave.geteid(),
+            &deploy_res.output,
+            &encrypted_callable,
+            &encrypted_args,
+            &keys.get_pubkey(),
+            &contract_address,
+            &TASK_ID,
+            GAS_LIMIT
+        ).expect("Execution failed");
+
+        match result {
+            WasmResult::WasmTaskFailure(_) => (),
+            WasmResult::WasmTaskResult(_) => panic!("Expected the task to fail"),
+        }
+        // The only delta is the one generated by the deployment.
+        let (tip_key, _) = db.get_tip::<DeltaKey>(&contract_address).unwrap();
+        assert_eq!(tip_key, deploy_res.delta.key);
+    }
+
+    #[test]
+    fn test_revert_reason() {
+        let (mut db, _dir) = create_test_db();
+        let contract_address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            contract_address,
+            "construct(uint)",
+            &[Token::Uint(100.into())],
+        );
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"write_and_revert(uint)", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(17.into())]), &shared_key).unwrap();
+        let result = wasm::execute(
+            &mut db,
+            enclave.geteid(),
+            &deploy_res.output,
+            &encrypted_callable,
+            &encrypted_args,
+            &keys.get_pubkey(),
+            &contract_address,
+            &TASK_ID,
+            GAS_LIMIT
+        ).expect("Execution failed");
+
+        let failure = match result {
+            WasmResult::WasmTaskFailure(failure) => failure,
+            WasmResult::WasmTaskResult(_) => panic!("Expected the task to revert"),
+        };
+        let error = symmetric::decrypt(&failure.output, &shared_key).unwrap();
+        let error = String::from_utf8(error).unwrap();
+        assert!(error.contains("x must be smaller than 10"), "unexpected error: {}", error);
+        assert_ne!(failure.used_gas, 0);
+        // The write before the revert is discarded.
+        let (tip_key, _) = db.get_tip::<DeltaKey>(&contract_address).unwrap();
+        assert_eq!(tip_key, deploy_res.delta.key);
+    }
+
+    #[test]
+    fn test_single_write_ocall_per_computation() {
+        let (mut db, _dir) = create_test_db();
+        let contract_address = generate_contract_address();
+        let (enclave, deploy_res) = compile_deploy_contract_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            contract_address,
+            "construct(uint)",
+            &[Token::Uint(100.into())],
+        );
+        let ocalls_before = write_ocalls_count(&contract_address);
+
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"addition(uint256,uint256)", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(1.into()), Token::Uint(2.into())]), &shared_key).unwrap();
+        let result = wasm::execute(
+            &mut db,
+            enclave.geteid(),
+            &deploy_res.output,
+            &encrypted_callable,
+            &encrypted_args,
+            &keys.get_pubkey(),
+            &contract_address,
+            &TASK_ID,
+            GAS_LIMIT
+        ).expect("Execution failed").unwrap_result();
+
+        // The delta and the new state are stored together with one ocall.
+        assert!(!result.delta.value.is_empty());
+        assert_eq!(write_ocalls_count(&contract_address) - ocalls_before, 1);
+        let (tip_key, _) = db.get_tip::<DeltaKey>(&contract_address).unwrap();
+        assert_eq!(tip_key, result.delta.key);
+    }
+
     #[test]
     fn test_dynamic_types() {
         let (mut db, _dir) = create_test_db();
@@ -566,6 +1600,44 @@ mod tests {
         assert_ne!(&res_output[..], &(*zeros));
     }
 
+    #[test]
+    fn test_rand_seeded_by_task_id() {
+        let (mut db, _dir) = create_test_db();
+        let address = generate_contract_address();
+        let (enclave, exe_code, result, shared_key) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            address,
+            "construct(uint)",
+            &[Token::Uint(100.into())],
+            "get_scrambled_vec()",
+            &[]
+        );
+        let first = symmetric::decrypt(&result.output, &shared_key).unwrap();
+
+        let mut scramble = |task_id: &[u8; 32]| {
+            let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+            let encrypted_callable = symmetric::encrypt(b"get_scrambled_vec()", &shared_key).unwrap();
+            let encrypted_args = symmetric::encrypt(&ethabi::encode(&[]), &shared_key).unwrap();
+            let result = wasm::execute(
+                &mut db,
+                enclave.geteid(),
+                &exe_code,
+                &encrypted_callable,
+                &encrypted_args,
+                &keys.get_pubkey(),
+                &address,
+                task_id,
+                GAS_LIMIT
+            ).expect("Execution failed").unwrap_result();
+            symmetric::decrypt(&result.output, &shared_key).unwrap()
+        };
+
+        assert_eq!(scramble(&TASK_ID), first);
+        // There are 10! permutations, another task getting the same one is negligible.
+        assert_ne!(scramble(&[8u8; 32]), first);
+    }
+
     #[test]
     fn test_multiple_addresses() {
         let (mut db, _dir) = create_test_db();
@@ -588,6 +1660,56 @@ mod tests {
         assert_eq!(decoded_output,&expected_output);
     }
 
+    #[test]
+    fn test_check_addresses_fixed_bytes() {
+        let (mut db, _dir) = create_test_db();
+        // They differ only in their first and last bytes, so swapped or shifted words won't go unnoticed.
+        let mut addr1 = [0x11u8; 32];
+        let mut addr2 = [0x11u8; 32];
+        addr1[0] = 0xaa;
+        addr2[31] = 0xbb;
+        let addresses = [Token::FixedBytes(addr1.to_vec()), Token::FixedBytes(addr2.to_vec())];
+        let contract_address = generate_contract_address();
+        let (enclave, exe_code, result, shared_key) = compile_deploy_execute(
+            &mut db,
+            "../../examples/eng_wasm_contracts/simplest",
+            contract_address,
+            "construct(uint)",
+            &[Token::Uint(1025.into())],
+            "check_addresses(bytes32,bytes32)",
+            &addresses
+        );
+
+        let encoded_output = symmetric::decrypt(&result.output, &shared_key).unwrap();
+        let decoded_output = ethabi::decode(&[ethabi::ParamType::Array(Box::new(ethabi::ParamType::FixedBytes(32)))], &encoded_output).unwrap();
+        assert_eq!(decoded_output, vec![Token::Array(addresses.to_vec())]);
+
+        // Arguments cut short of a whole word are rejected rather than decoded into a different H256.
+        let mut truncated = ethabi::encode(&addresses);
+        truncated.pop();
+        let (keys, shared_key, _, _) = exchange_keys(enclave.geteid());
+        let encrypted_callable = symmetric::encrypt(b"check_addresses(bytes32,bytes32)", &shared_key).unwrap();
+        let encrypted_args = symmetric::encrypt(&truncated, &shared_key).unwrap();
+        let result = wasm::execute(
+            &mut db,
+            enclave.geteid(),
+            &exe_code,
+            &encrypted_callable,
+            &encrypted_args,
+            &keys.get_pubkey(),
+            &contract_address,
+            &TASK_ID,
+            GAS_LIMIT
+        ).expect("Execution failed");
+        let failure = match result {
+            WasmResult::WasmTaskFailure(failure) => failure,
+            WasmResult::WasmTaskResult(_) => panic!("Expected the truncated arguments to fail the task"),
+        };
+        // The contract reverts with a reason instead of trapping.
+        let error = String::from_utf8(symmetric::decrypt(&failure.output, &shared_key).unwrap()).unwrap();
+        assert!(error.contains("the arguments are not ABI encoded"), "unexpected error: {}", error);
+    }
+
     #[test]
     fn test_construct_erc20() {
         let (mut db, _dir) = create_test_db();
@@ -641,6 +1763,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -683,6 +1806,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -731,6 +1855,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed");
 
@@ -745,6 +1870,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -762,6 +1888,7 @@ mod tests {
             &encrypted_args,
             &keys.get_pubkey(),
             &address,
+            &TASK_ID,
             GAS_LIMIT
         ).expect("Execution failed").unwrap_result();
 
@@ -13,18 +13,26 @@ extern crate futures;
 extern crate dirs;
 extern crate rand;
 extern crate tempfile;
+extern crate lazy_static;
 
 use self::cross_test_utils::{generate_contract_address, generate_user_address, make_encrypted_response,
-                             get_fake_state_key, get_bytecode_from_path, ContractAddress,
-                             ERC20UserAddress, sign_message};
+                             get_fake_state_key, get_fake_settings_mac, get_bytecode_from_path, ContractAddress,
+                             ERC20UserAddress, GasMultiplier, HostCapabilities, sign_message};
 use self::app::*;
 use self::futures::Future;
 use self::app::networking::*;
+use self::app::networking::messages::GasLimits;
+use self::app::enigma_tools_u::attestation_service::service::AttestationConfig;
+use self::app::sgx_types::sgx_enclave_id_t;
+use self::app::enigma_tools_m::primitives::{km_primitives::PttRequest, signature_domain::SignatureDomain,
+                                             task_primitives::destroy_contract_message};
 use self::serde::{Deserialize, Serialize};
 use self::rmps::{Deserializer, Serializer};
 use self::app::serde_json;
 use app::serde_json::*;
 use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use self::lazy_static::lazy_static;
 use self::regex::Regex;
 use self::hex::{ToHex, FromHex};
 use self::ethabi::{Token};
@@ -42,19 +50,45 @@ pub fn create_test_db() -> (DB, TempDir) {
 }
 
 pub fn run_core(port: &'static str) {
-    thread::spawn(move || {
-        let enclave = esgx::general::init_enclave_wrapper().expect("Init Enclave Failed");
-        let eid = enclave.geteid();
-
-        let (mut db, _datadir) = create_test_db();
-        let server = IpcListener::new(&format!("tcp://*:{}", port));
-        let spid = "B0335FD3BC1CCA8F804EB98A6420592D";
-        let retries = 10;
-        server
-            .run(move |multi| ipc_listener::handle_message(&mut db, multi, spid, eid, retries))
+    run_core_with_snapshot_interval(port, 0)
+}
+
+pub fn run_core_with_snapshot_interval(port: &'static str, snapshot_interval: u32) {
+    run_core_with(port, snapshot_interval, GasLimits::default())
+}
+
+pub fn run_core_with_gas_limits(port: &'static str, gas_limits: GasLimits) {
+    run_core_with(port, 0, gas_limits)
+}
+
+fn run_core_with(port: &'static str, snapshot_interval: u32, gas_limits: GasLimits) {
+    spawn_core(move |eid, mut db, attestation, trust_unsigned| {
+        IpcListener::new(&format!("tcp://*:{}", port))
+            .run(move |multi| ipc_listener::handle_message(&mut db, multi, &attestation, eid, trust_unsigned, snapshot_interval, gas_limits))
             .wait()
             .unwrap();
+    });
+}
+
+pub fn run_core_concurrent(port: &'static str, threads: usize) {
+    spawn_core(move |eid, db, attestation, trust_unsigned| {
+        let db = ConcurrentDB::new(db);
+        ConcurrentIpcListener::new(&format!("tcp://*:{}", port), threads)
+            .run(move |multi| ipc_listener::handle_message_concurrent(&db, multi, &attestation, eid, trust_unsigned, 0, GasLimits::default()))
+            .wait()
+            .unwrap();
+    });
+}
 
+/// Starts an enclave and a fresh DB on a new thread and serves them with `serve`.
+fn spawn_core<F>(serve: F)
+where F: FnOnce(sgx_enclave_id_t, DB, AttestationConfig, bool) + Send + 'static {
+    thread::spawn(move || {
+        let enclave = esgx::general::init_enclave_wrapper().expect("Init Enclave Failed");
+        let (db, _datadir) = create_test_db();
+        // The tests that send deltas don't sign them.
+        let trust_unsigned = true;
+        serve(enclave.geteid(), db, AttestationConfig::default(), trust_unsigned);
     });
 }
 
@@ -69,19 +103,54 @@ pub fn is_hex(msg: &str) -> bool {
     re.is_match(msg)
 }
 
-pub fn conn_and_call_ipc(msg: &str, port: &'static str) -> Value {
-    const TIMEOUT: i32 = 30_000; // Socket timeout - 30 seconds.
-    let context = zmq::Context::new();
-    let requester = context.socket(zmq::REQ).unwrap();
-    requester.set_rcvtimeo(TIMEOUT).unwrap();
-    requester.set_sndtimeo(TIMEOUT).unwrap();
-    assert!(requester.connect(&format!("tcp://localhost:{}", port)).is_ok());
+pub const IPC_TIMEOUT: i32 = 30_000; // Socket timeout - 30 seconds.
+
+/// How many contexts the helpers created for the IPC calls, all of them are made by `new_ipc_context`.
+pub static IPC_CONTEXTS_CREATED: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// A context starts its own I/O threads, so every call shares this one and only the sockets are per call.
+    static ref IPC_CONTEXT: zmq::Context = new_ipc_context();
+}
+
+/// The only way the helpers create a context, so `IPC_CONTEXTS_CREATED` counts every one of them.
+fn new_ipc_context() -> zmq::Context {
+    IPC_CONTEXTS_CREATED.fetch_add(1, Ordering::SeqCst);
+    zmq::Context::new()
+}
+
+/// A socket for a single call to the core listening on `port`, from the shared context.
+fn ipc_requester(port: &str, timeout: i32) -> std::result::Result<zmq::Socket, zmq::Error> {
+    let requester = IPC_CONTEXT.socket(zmq::REQ)?;
+    requester.set_rcvtimeo(timeout)?;
+    requester.set_sndtimeo(timeout)?;
+    // Don't keep unsent messages around, otherwise closing the socket will block.
+    requester.set_linger(0)?;
+    requester.connect(&format!("tcp://localhost:{}", port))?;
+    Ok(requester)
+}
 
+pub fn conn_and_call_ipc(msg: &str, port: &'static str) -> Value {
+    conn_and_call_ipc_with_timeout(msg, port, IPC_TIMEOUT).unwrap()
+}
+
+/// Sends `msg` to the core listening on `port` and waits up to `timeout` milliseconds for the reply.
+/// If the core doesn't answer in time this returns `zmq::Error::EAGAIN` instead of blocking forever.
+pub fn conn_and_call_ipc_with_timeout(msg: &str, port: &str, timeout: i32) -> std::result::Result<Value, zmq::Error> {
+    let requester = ipc_requester(port, timeout)?;
+    requester.send(msg, 0)?;
+    // A reply that was split into frames is reassembled into the single message they make.
+    let payloads = framing::join(requester.recv_multipart(0)?, framing::MAX_PAYLOAD_LEN).unwrap();
+    assert_eq!(payloads.len(), 1, "Expected a single message in the reply");
+    Ok(serde_json::from_slice(&payloads[0]).unwrap())
+}
+/// Like `conn_and_call_ipc` but returns the frames of the reply as they were received, for responses that are split into frames.
+pub fn conn_and_call_ipc_multipart(msg: &str, port: &'static str) -> Vec<Vec<u8>> {
+    let requester = ipc_requester(port, IPC_TIMEOUT).unwrap();
     requester.send(msg, 0).unwrap();
-    let mut msg = zmq::Message::new();
-    requester.recv(&mut msg, 0).unwrap();
-    serde_json::from_str(msg.as_str().unwrap()).unwrap()
+    requester.recv_multipart(0).unwrap()
 }
+
 pub fn get_simple_msg_format(msg_type: &str) -> Value {
     json!({"id": &generate_job_id(), "type": msg_type})
 }
@@ -115,6 +184,10 @@ pub fn get_compute_msg(task_id: &str, callable: &str, args: &str, user_pubkey: &
     "encryptedFn": callable, "userDHKey": user_pubkey, "gasLimit": gas_limit, "contractAddress": con_addr}})
 }
 
+pub fn get_contract_meta_msg(addr: &str) -> Value {
+    json!({"id": &generate_job_id(), "type": "GetContractMeta", "address": addr})
+}
+
 pub fn get_get_tips_msg(input: &[String]) -> Value {
     json!({"id": &generate_job_id(), "type": "GetTips", "input": input.to_vec()})
 }
@@ -128,14 +201,22 @@ pub fn deltas_msg(input: &[(String, u64, u64)], msg_type: &str) -> Value {
     json!({"id": &generate_job_id(), "type": msg_type, "input": input})
 }
 
+/// The default settings with the MAC the enclave has over them once it's given the fake state key of `addr` (see `run_ptt_round`).
+pub fn get_fake_settings(addr: &str) -> Value {
+    let address = ContractAddress::from_hex(addr).unwrap();
+    let (gas_multiplier, capabilities) = (GasMultiplier::default(), HostCapabilities::default());
+    json!({"gasMultiplier": gas_multiplier, "capabilities": capabilities, "mac": get_fake_settings_mac(address, gas_multiplier, capabilities)})
+}
+
 pub fn get_msg_format_update_contract(addr: &str, bytecode: Vec<u8>) -> Value {
-    json!({"id": &generate_job_id(), "type": "UpdateNewContract", "address": addr, "bytecode": bytecode})
+    json!({"id": &generate_job_id(), "type": "UpdateNewContract", "address": addr, "bytecode": bytecode, "settings": get_fake_settings(addr)})
 }
 
 pub fn get_msg_format_update_contract_on_deployment(addr: &str, bytecode: &str, delta: &(String, u64, Vec<u8>)) -> Value {
     let (address, key, data) = delta;
     let delta_json = json!({"address": address, "key": key, "data": data});
-    json!({"id": &generate_job_id(), "type": "UpdateNewContractOnDeployment", "address": addr, "bytecode": bytecode, "delta": delta_json})
+    json!({"id": &generate_job_id(), "type": "UpdateNewContractOnDeployment", "address": addr, "bytecode": bytecode, "delta": delta_json,
+           "settings": get_fake_settings(addr)})
 }
 
 pub fn get_update_deltas_msg(_input: &[(String, u64, Vec<u8>)]) -> Value {
@@ -143,19 +224,27 @@ pub fn get_update_deltas_msg(_input: &[(String, u64, Vec<u8>)]) -> Value {
     json!({"id": &generate_job_id(), "type": "UpdateDeltas", "deltas": input})
 }
 
-pub fn parse_packed_msg(msg: &str) -> Value {
+/// The input is (address, key, data, signature)
+pub fn get_update_signed_deltas_msg(_input: &[(String, u64, Vec<u8>, String)]) -> Value {
+    let input: Vec<Value> = _input.iter().map(|(addr, key, data, sig)| {
+        json!({"address": addr, "key": key, "data": data, "signature": sig})
+    }).collect();
+    json!({"id": &generate_job_id(), "type": "UpdateDeltas", "deltas": input})
+}
+
+/// Signs a delta the same way a worker does, returns the hex of the signature.
+pub fn sign_delta(keys: &KeyPair, address: &ContractAddress, key: u32, data: &[u8]) -> String {
+    keys.sign_multiple(&[&address[..], &key.to_be_bytes()[..], data]).unwrap().to_hex()
+}
+
+pub fn parse_ptt_request(msg: &str) -> PttRequest {
     let msg_bytes = msg.from_hex().unwrap();
-    let mut _de = Deserializer::new(&msg_bytes[..]);
-    Deserialize::deserialize(&mut Deserializer::new(&msg_bytes[..])).unwrap()
+    PttRequest::from_message(&msg_bytes).unwrap()
 }
 
 pub fn mock_principal_res(msg: &str, addrs: Vec<ContractAddress>) -> Vec<u8> {
-    let unpacked_msg: Value = parse_packed_msg(msg);
-    let enc_response: Value = make_encrypted_response(&unpacked_msg, addrs, None);
-
-    let mut serialized_enc_response = Vec::new();
-    enc_response.serialize(&mut Serializer::new(&mut serialized_enc_response)).unwrap();
-    serialized_enc_response
+    let req = parse_ptt_request(msg);
+    make_encrypted_response(&req, addrs, None)
 }
 
 pub fn run_ptt_round(port: &'static str, addrs: Vec<ContractAddress>) -> Value {
@@ -223,6 +312,10 @@ pub fn erc20_deployment_without_ptt_to_addr(port: &'static str, _address: &str)
 }
 
 pub fn full_simple_deployment(port: &'static str) -> (Value, [u8; 32]) {
+    full_simple_deployment_with_meta(port, None)
+}
+
+pub fn full_simple_deployment_with_meta(port: &'static str, metadata: Option<Value>) -> (Value, [u8; 32]) {
     // address generation and ptt
     let address = generate_contract_address();
     let _ = run_ptt_round(port, vec![address]);
@@ -236,8 +329,11 @@ pub fn full_simple_deployment(port: &'static str) -> (Value, [u8; 32]) {
     let (encrypted_callable, encrypted_args) = encrypt_args(&args_deploy, fn_deploy, shared_key);
     let gas_limit = 100_000_000;
 
-    let msg = get_deploy_msg(&pre_code, &encrypted_args.to_hex(),
+    let mut msg = get_deploy_msg(&pre_code, &encrypted_args.to_hex(),
                              &encrypted_callable.to_hex(), &user_pubkey.to_hex(), gas_limit, &address.to_hex());
+    if let Some(metadata) = metadata {
+        msg["input"]["metadata"] = metadata;
+    }
     let v: Value = conn_and_call_ipc(&msg.to_string(), port);
 
     (v, address.into())
@@ -251,6 +347,30 @@ pub fn full_addition_compute(port: &'static str,  a: u64, b: u64) -> (Value, [u8
     (result, key, contract_addr)
 }
 
+/// Deploys the simplest contract and computes `addition(a, b)` on it in a single `DeployAndCompute` request.
+pub fn simple_deploy_and_compute(port: &'static str, a: u64, b: u64) -> (Value, [u8; 32], [u8; 32]) {
+    simple_deploy_and_compute_with_gas(port, a, b, 100_000_000)
+}
+
+/// Like `simple_deploy_and_compute`, with `compute_gas_limit` as the gas limit of the computation.
+pub fn simple_deploy_and_compute_with_gas(port: &'static str, a: u64, b: u64, compute_gas_limit: u64) -> (Value, [u8; 32], [u8; 32]) {
+    let address = generate_contract_address();
+    let _ = run_ptt_round(port, vec![address]);
+
+    let pre_code = get_bytecode_from_path("../../examples/eng_wasm_contracts/simplest");
+    let (deploy_key, deploy_pubkey) = produce_shared_key(port);
+    let (deploy_callable, deploy_args) = encrypt_args(&[Token::Uint(17.into())], "construct(uint)", deploy_key);
+    let (compute_key, compute_pubkey) = produce_shared_key(port);
+    let (compute_callable, compute_args) = encrypt_args(&[Token::Uint(a.into()), Token::Uint(b.into())], "addition(uint,uint)", compute_key);
+    let gas_limit = 100_000_000;
+
+    let deploy = get_deploy_msg(&pre_code, &deploy_args.to_hex(), &deploy_callable.to_hex(), &deploy_pubkey.to_hex(), gas_limit, &address.to_hex());
+    let msg = json!({"id": &generate_job_id(), "type": "DeployAndCompute", "deploy": deploy["input"],
+        "compute": {"taskID": generate_contract_address().to_hex(), "encryptedArgs": compute_args.to_hex(), "encryptedFn": compute_callable.to_hex(),
+                    "userDHKey": compute_pubkey.to_hex(), "gasLimit": compute_gas_limit}});
+    (conn_and_call_ipc(&msg.to_string(), port), compute_key, address.into())
+}
+
 pub fn full_mint_compute(port: &'static str,  user_addr: ERC20UserAddress, amount: u64) -> (Value,  [u8;32], [u8; 32]) {
     let (owner, owner_keys) = generate_user_address();
     let (_, _, contract_addr): (_, _, [u8; 32]) = full_erc20_deployment(port, owner, None, None);
@@ -272,16 +392,44 @@ pub fn full_supply_compute(port: &'static str, supply: u64) -> (Value,  [u8;32],
 }
 
 pub fn contract_compute(port: &'static str,  contract_addr: [u8; 32], args: &[Token], callable: &str) -> (Value, [u8; 32]) {
+    contract_compute_with_gas_limit(port, contract_addr, args, callable, Some(100_000_000))
+}
+
+/// Without a `gas_limit` the task is sent without one, so the core's default applies.
+pub fn contract_compute_with_gas_limit(port: &'static str,  contract_addr: [u8; 32], args: &[Token], callable: &str,
+                                       gas_limit: Option<u64>) -> (Value, [u8; 32]) {
+    let (msg, shared_key) = get_contract_compute_msg(port, contract_addr, args, callable, gas_limit);
+    (conn_and_call_ipc(&msg.to_string(), port), shared_key)
+}
+
+/// The `ComputeTask` message `contract_compute_with_gas_limit` sends and the key its inputs are encrypted with.
+pub fn get_contract_compute_msg(port: &'static str,  contract_addr: [u8; 32], args: &[Token], callable: &str,
+                                gas_limit: Option<u64>) -> (Value, [u8; 32]) {
     // WUKE- get the arguments encryption key
     let (shared_key, user_pubkey) = produce_shared_key(port);
 
     let task_id: String = generate_contract_address().to_hex();
     let (encrypted_callable, encrypted_args) = encrypt_args(args, callable, shared_key);
-    let gas_limit = 100_000_000;
 
-    let msg = get_compute_msg(&task_id, &encrypted_callable.to_hex(), &encrypted_args.to_hex(),
-                              &user_pubkey.to_hex(), gas_limit, &contract_addr.to_hex());
-    (conn_and_call_ipc(&msg.to_string(), port), shared_key)
+    let mut msg = get_compute_msg(&task_id, &encrypted_callable.to_hex(), &encrypted_args.to_hex(),
+                                  &user_pubkey.to_hex(), gas_limit.unwrap_or_default(), &contract_addr.to_hex());
+    if gas_limit.is_none() {
+        msg["input"].as_object_mut().unwrap().remove("gasLimit");
+    }
+    (msg, shared_key)
+}
+
+/// Replays the computation `compute_msg` that returned `compute_res` and claims its result as it was returned.
+/// `compute_key` is the key of the task, it's sent encrypted with a fresh key exchange.
+pub fn replay_compute(port: &'static str, compute_msg: &Value, compute_res: &Value, compute_key: &[u8; 32]) -> Value {
+    let (replay_key, replay_pubkey) = produce_shared_key(port);
+    let task_key = symmetric::encrypt(compute_key, &replay_key).unwrap();
+
+    let msg = json!({"id": &generate_job_id(), "type": "ReplayCompute", "input": compute_msg["input"],
+                     "claim": {"output": compute_res["result"]["output"], "delta": compute_res["result"]["delta"],
+                               "usedGas": compute_res["result"]["usedGas"]},
+                     "replayDHKey": replay_pubkey.to_hex(), "taskKey": task_key.to_hex()});
+    conn_and_call_ipc(&msg.to_string(), port)
 }
 
 fn encrypt_args( args:&[Token], callable: &str, key: [u8;32]) -> (Vec<u8>, Vec<u8>) {
@@ -345,6 +493,14 @@ pub fn remove_contract(port: &'static str, addr: &str) -> Value {
     conn_and_call_ipc(&msg.to_string(), port)
 }
 
+/// `keys` sign the destruction as the deployer of the contract, at the delta `tip`.
+pub fn destroy_contract(port: &'static str, addr: &[u8; 32], tip: u32, keys: &KeyPair) -> Value {
+    let message = destroy_contract_message(&(*addr).into(), tip);
+    let sig = keys.sign(&SignatureDomain::DestroyContract.tagged(&message)).unwrap();
+    let msg = json!({"id": &generate_job_id(), "type": "DestroyContract", "address": addr.to_hex(), "deployerSig": sig.to_hex()});
+    conn_and_call_ipc(&msg.to_string(), port)
+}
+
 pub fn remove_deltas(port: &'static str, input: &[(String, u64, u64)]) -> Value {
     let msg = deltas_msg(input, "RemoveDeltas");
     conn_and_call_ipc(&msg.to_string(), port)
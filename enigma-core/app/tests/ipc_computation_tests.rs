@@ -8,9 +8,13 @@ extern crate enigma_types;
 use integration_utils::{conn_and_call_ipc, is_hex, run_core, get_encryption_msg, full_simple_deployment,
                         send_update_contract, run_ptt_round, contract_compute, get_update_deltas_msg,
                         decrypt_addr_delta, encrypt_addr_delta, replace_previous_hash_in_delta_data,
-                        full_supply_compute, full_addition_compute, decrypt_output_to_uint};
-use cross_test_utils::generate_contract_address;
+                        full_supply_compute, full_addition_compute, decrypt_output_to_uint,
+                        run_core_with_snapshot_interval, decrypt_delta_to_value, simple_deploy_and_compute, simple_deploy_and_compute_with_gas,
+                        get_compute_msg, generate_job_id, get_msg_format_with_input, get_delta_msg,
+                        run_core_with_gas_limits, contract_compute_with_gas_limit, get_contract_compute_msg, replay_compute};
+use cross_test_utils::{generate_contract_address, get_bytecode_from_path};
 use self::app::serde_json;
+use app::networking::messages::GasLimits;
 use app::serde_json::*;
 use hex::{ToHex, FromHex};
 use integration_utils::ethabi::{Token};
@@ -30,6 +34,15 @@ fn test_new_task_encryption_key(){
 
     assert!(is_hex(result_key));
     assert!(is_hex(result_sig));
+
+    // A client may ask for the signature tagged with its domain, but not for a version that doesn't exist.
+    let mut msg = get_encryption_msg(keys.get_pubkey());
+    msg["sigVersion"] = json!(2);
+    let v: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert!(is_hex(v["result"]["workerSig"].as_str().unwrap()));
+    msg["sigVersion"] = json!(9);
+    let v: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(v["code"].as_str().unwrap(), "BadRequest");
 }
 
 #[test]
@@ -46,6 +59,19 @@ fn test_deploy_secret_contract() {
     assert!(accepted_used_gas > 0);
 }
 
+#[test]
+fn test_deploy_gas_breakdown() {
+    let port =  "5581";
+    run_core(port);
+
+    let (res, _): (Value, _) = full_simple_deployment(port);
+    let used_gas = res["result"]["usedGas"].as_u64().unwrap();
+    let constructor_gas = res["result"]["constructorGas"].as_u64().unwrap();
+    let storage_gas = res["result"]["storageGas"].as_u64().unwrap();
+    assert!(storage_gas > 0);
+    assert_eq!(constructor_gas + storage_gas, used_gas);
+}
+
 #[test]
 fn test_compute_task() {
     let port =  "5557";
@@ -61,6 +87,76 @@ fn test_compute_task() {
     assert_eq!("ComputeTask", type_accepted);
 }
 
+#[test]
+fn test_deploy_and_compute() {
+    let port =  "5586";
+    run_core(port);
+
+    let (a, b) : (u64, u64) = (24, 67);
+    let (res, key, _): (Value, [u8;32], _) = simple_deploy_and_compute(port, a, b);
+
+    assert_eq!("DeployAndCompute", res["type"].as_str().unwrap());
+    assert!(res["deploy"]["result"]["usedGas"].as_u64().unwrap() > 0);
+    assert!(is_hex(res["deploy"]["result"]["preCodeHash"].as_str().unwrap()));
+    let output: String = serde_json::from_value(res["compute"]["result"]["output"].clone()).unwrap();
+    let accepted_sum: Token = decrypt_output_to_uint(&output.from_hex().unwrap(), &key);
+    assert_eq!(accepted_sum.to_uint().unwrap().as_u64(), a + b);
+    // The computation ran on the state the constructor created, so its delta follows the deployment delta.
+    assert_eq!(res["compute"]["result"]["delta"]["key"].as_u64().unwrap(), 1);
+}
+
+#[test]
+fn test_failed_deploy_and_compute_undoes_the_deployment() {
+    let port =  "5603";
+    run_core(port);
+
+    // The smallest gas limit that's accepted isn't enough for the computation, so it runs out of gas.
+    let (res, _, address): (Value, _, [u8;32]) = simple_deploy_and_compute_with_gas(port, 24, 67, 100);
+    assert_eq!("FailedTask", res["type"].as_str().unwrap());
+    // Nothing of the deployment is left, so the contract is unknown and can be deployed again.
+    let contract: Value = conn_and_call_ipc(&get_msg_format_with_input("GetContract", &address.to_hex()).to_string(), port);
+    assert_eq!("Error", contract["type"].as_str().unwrap());
+    let tip: Value = conn_and_call_ipc(&get_msg_format_with_input("GetTip", &address.to_hex()).to_string(), port);
+    assert_eq!("Error", tip["type"].as_str().unwrap());
+}
+
+#[test]
+fn test_compute_task_zero_gas_limit() {
+    let port =  "5587";
+    run_core(port);
+
+    let msg = get_compute_msg(&generate_contract_address().to_hex(), "00", "00", "00", 0, &generate_contract_address().to_hex());
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!("Error", res["type"].as_str().unwrap());
+    assert!(res["msg"].as_str().unwrap().contains("gas limit 0"));
+    assert_eq!(res["code"].as_str().unwrap(), "BadRequest");
+}
+
+#[test]
+fn test_compute_task_without_task_id() {
+    let port = "5606";
+    run_core(port);
+
+    let mut msg = get_compute_msg("", &[0u8; 28].to_hex(), &[0u8; 28].to_hex(), &[0u8; 64].to_hex(), 1000, &generate_contract_address().to_hex());
+    msg["input"].as_object_mut().unwrap().remove("taskID");
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!("Error", res["type"].as_str().unwrap());
+    assert!(res["msg"].as_str().unwrap().contains("taskID"));
+    assert_eq!(res["code"].as_str().unwrap(), "BadRequest");
+}
+
+#[test]
+fn test_compute_task_gas_limit_above_max() {
+    let port =  "5588";
+    run_core(port);
+
+    let gas_limit = app::networking::messages::MAX_GAS_LIMIT + 1;
+    let msg = get_compute_msg(&generate_contract_address().to_hex(), "00", "00", "00", gas_limit, &generate_contract_address().to_hex());
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!("Error", res["type"].as_str().unwrap());
+    assert!(res["msg"].as_str().unwrap().contains(&format!("gas limit {}", gas_limit)));
+}
+
 #[test]
 fn test_compute_task_no_delta() {
     let port =  "5560";
@@ -80,6 +176,34 @@ fn test_compute_task_no_delta() {
     assert_eq!("ComputeTask", type_accepted);
 }
 
+#[test]
+fn test_compute_task_auto_snapshot() {
+    let port =  "5585";
+    let snapshot_interval = 2;
+    run_core_with_snapshot_interval(port, snapshot_interval);
+
+    let (_, contract_addr): (_, [u8; 32]) = full_simple_deployment(port);
+    let mut results = Vec::new();
+    for i in 1..=3u64 {
+        let args = [Token::Uint(i.into()), Token::Uint(i.into())];
+        let (res, _) = contract_compute(port, contract_addr, &args, "addition(uint,uint)");
+        results.push(res);
+    }
+
+    // Only the second computation reaches `snapshot_interval` deltas since the deployment.
+    assert!(results[0]["result"].get("snapshot").is_none());
+    assert!(results[2]["result"].get("snapshot").is_none());
+    let snapshot = &results[1]["result"]["snapshot"];
+    assert_eq!(snapshot["index"].as_u64().unwrap(), snapshot_interval as u64);
+    assert_eq!(results[1]["result"]["delta"]["key"], snapshot["index"]);
+
+    let state_data = snapshot["state"].as_str().unwrap().from_hex().unwrap();
+    let state = decrypt_delta_to_value(contract_addr, &state_data);
+    // The state is serialized as [json, delta_hash, delta_index].
+    assert_eq!(state[0]["curr_sum"], 4);
+    assert_eq!(state[2], snapshot_interval);
+}
+
 #[test]
 fn test_execute_on_existing_contract_with_constructor() {
     let port =  "5572";
@@ -135,4 +259,199 @@ fn test_execute_on_existing_contract_with_constructor() {
     assert_eq!(last_delta_key, expected_key + 1);
 
 
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_get_state_at() {
+    let port = "5590";
+    run_core(port);
+
+    let (_, contract_address): (_, [u8; 32]) = full_simple_deployment(port);
+    // The deployment is delta 0, every addition adds another delta.
+    for i in 1..=10u64 {
+        let (res, _) = contract_compute(port, contract_address, &[Token::Uint(i.into()), Token::Uint(i.into())], "addition(uint,uint)");
+        assert_eq!(res["result"]["delta"]["key"].as_u64().unwrap(), i);
+    }
+
+    let msg = json!({"id": &generate_job_id(), "type": "GetStateAt", "address": contract_address.to_hex(), "index": 4});
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "GetStateAt");
+    assert_eq!(res["result"]["state"], json!({"1": 17, "curr_sum": 8}));
+
+    let msg = json!({"id": &generate_job_id(), "type": "GetStateAt", "address": contract_address.to_hex(), "index": 11});
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+}
+
+#[test]
+fn test_state_fingerprint() {
+    let port = "5591";
+    run_core(port);
+
+    let (_, contract_address): (_, [u8; 32]) = full_simple_deployment(port);
+    let fingerprint = |port| {
+        let msg = json!({"id": &generate_job_id(), "type": "StateFingerprint", "address": contract_address.to_hex()});
+        let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+        assert_eq!(res["type"].as_str().unwrap(), "StateFingerprint");
+        res["result"].clone()
+    };
+    let initial = fingerprint(port);
+    assert_eq!(initial["deltaIndex"].as_u64().unwrap(), 0);
+    assert_eq!(initial, fingerprint(port));
+
+    let (res, _) = contract_compute(port, contract_address, &[Token::Uint(1.into()), Token::Uint(2.into())], "addition(uint,uint)");
+    let after = fingerprint(port);
+    assert_eq!(after["deltaIndex"].as_u64().unwrap(), 1);
+    assert_eq!(after["deltaHash"].as_str().unwrap(), res["result"]["delta"]["data"].as_array().map(|data| {
+        let data: Vec<u8> = data.iter().map(|b| b.as_u64().unwrap() as u8).collect();
+        data.keccak256()[..].to_hex()
+    }).unwrap());
+    assert_ne!(after["stateHash"], initial["stateHash"]);
+    assert_eq!(after["stateHash"].as_str().unwrap().len(), 64);
+}
+
+#[test]
+fn test_compute_without_state_change() {
+    let port = "5593";
+    run_core(port);
+
+    let (deployed_res, contract_address) = full_simple_deployment(port);
+    assert_eq!(deployed_res["result"]["delta"]["key"].as_u64().unwrap(), 0);
+
+    // Reading the state doesn't change it, so there's no delta at all.
+    let (res, _) = contract_compute(port, contract_address, &[], "get_last_sum()");
+    assert_eq!(res["type"].as_str().unwrap(), "ComputeTask");
+    assert!(res["result"].get("delta").is_none());
+    let msg = get_msg_format_with_input("GetTip", &contract_address.to_hex());
+    let tip: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(tip["result"]["key"].as_u64().unwrap(), 0);
+
+    // And the next delta gets the index right after the deployment.
+    let (res, _) = contract_compute(port, contract_address, &[Token::Uint(1.into()), Token::Uint(2.into())], "addition(uint,uint)");
+    assert_eq!(res["result"]["delta"]["key"].as_u64().unwrap(), 1);
+}
+
+#[test]
+fn test_state_rebuilt_after_pruning() {
+    let port = "5604";
+    run_core(port);
+
+    let (_, contract_address) = full_simple_deployment(port);
+    let msg = json!({"id": generate_job_id(), "type": "SetRetentionPolicy", "address": contract_address.to_hex(), "policy": {"keepLast": 1}});
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "SetRetentionPolicy");
+
+    for i in 1..=3u64 {
+        let args = [Token::Uint(i.into()), Token::Uint(i.into())];
+        let (res, _) = contract_compute(port, contract_address, &args, "addition(uint,uint)");
+        assert_eq!(res["result"]["delta"]["key"].as_u64().unwrap(), i);
+    }
+    // Every pruning waits for the one before it, so by now the deltas of the first two are gone.
+    for key in 0..=1 {
+        let res: Value = conn_and_call_ipc(&get_delta_msg(&contract_address.to_hex(), key).to_string(), port);
+        assert_eq!(res["type"].as_str().unwrap(), "Error");
+    }
+
+    // The state is still built from the stored snapshot without them.
+    let (res, key) = contract_compute(port, contract_address, &[], "get_last_sum()");
+    assert_eq!(res["type"].as_str().unwrap(), "ComputeTask");
+    let output: String = serde_json::from_value(res["result"]["output"].clone()).unwrap();
+    let last_sum: Token = decrypt_output_to_uint(&output.from_hex().unwrap(), &key);
+    assert_eq!(last_sum.to_uint().unwrap().as_u64(), 6);
+}
+
+#[test]
+fn test_get_metrics() {
+    let port = "5597";
+    run_core(port);
+
+    let (deploy_res, contract_address): (_, [u8; 32]) = full_simple_deployment(port);
+    let delta_bytes = |res: &Value| res["result"]["delta"]["data"].as_array().unwrap().len() as u64;
+    let mut total_gas = deploy_res["result"]["usedGas"].as_u64().unwrap();
+    let mut total_delta_bytes = delta_bytes(&deploy_res);
+    let args = [Token::Uint(3.into()), Token::Uint(3.into())];
+    let mut last = None;
+    for _ in 0..3 {
+        let (msg, key) = get_contract_compute_msg(port, contract_address, &args, "addition(uint,uint)", Some(100_000_000));
+        let res = conn_and_call_ipc(&msg.to_string(), port);
+        assert_eq!(res["type"].as_str().unwrap(), "ComputeTask");
+        total_gas += res["result"]["usedGas"].as_u64().unwrap();
+        total_delta_bytes += delta_bytes(&res);
+        last = Some((msg, res, key));
+    }
+    // The replay runs the same computation again, so it uses the same gas.
+    let (msg, last, key) = last.unwrap();
+    let replay = replay_compute(port, &msg, &last, &key);
+    assert_eq!(replay["type"].as_str().unwrap(), "ReplayCompute");
+    assert!(replay["result"]["matches"].as_bool().unwrap());
+    total_gas += last["result"]["usedGas"].as_u64().unwrap();
+
+    let msg = json!({"id": &generate_job_id(), "type": "GetMetrics", "address": contract_address.to_hex()});
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "GetMetrics");
+    let metrics = &res["result"];
+    // The deployment, the three computations and the replay.
+    assert_eq!(metrics["tasks"].as_u64().unwrap(), 5);
+    assert_eq!(metrics["failedTasks"].as_u64().unwrap(), 0);
+    assert_eq!(metrics["replayedTasks"].as_u64().unwrap(), 1);
+    assert_eq!(metrics["averageGas"].as_u64().unwrap(), total_gas / 5);
+    assert!(metrics["averageInstructions"].as_u64().unwrap() > 0);
+    assert!(metrics["peakMemoryBytes"].as_u64().unwrap() > 0);
+    // The replay doesn't store a delta.
+    assert_eq!(metrics["averageDeltaBytes"].as_u64().unwrap(), total_delta_bytes / 4);
+
+    let msg = json!({"id": &generate_job_id(), "type": "GetMetrics", "address": generate_contract_address().to_hex()});
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["result"]["tasks"].as_u64().unwrap(), 0);
+}
+
+#[test]
+fn test_validate_contract() {
+    let port = "5598";
+    run_core(port);
+
+    let bytecode = get_bytecode_from_path("../../examples/eng_wasm_contracts/simplest");
+    let msg = json!({"id": &generate_job_id(), "type": "ValidateContract", "bytecode": bytecode});
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "ValidateContract");
+    assert_eq!(res["result"], json!({"valid": true}));
+
+    // A module with a memory section instead of importing `env.memory`.
+    let internal_memory = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01];
+    let msg = json!({"id": &generate_job_id(), "type": "ValidateContract", "bytecode": internal_memory});
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["result"]["valid"], json!(false));
+    assert!(res["result"]["reason"].as_str().unwrap().contains("internal memory"));
+}
+
+#[test]
+fn test_compute_task_default_gas_limit() {
+    let port = "5599";
+    // Too little for the computation, the deployment sets its own gas limit.
+    run_core_with_gas_limits(port, GasLimits { default: app::networking::messages::MIN_GAS_LIMIT, ..GasLimits::default() });
+
+    let (_, contract_address): (_, [u8; 32]) = full_simple_deployment(port);
+    let args = [Token::Uint(1.into()), Token::Uint(2.into())];
+    let (res, _) = contract_compute_with_gas_limit(port, contract_address, &args, "addition(uint,uint)", None);
+    assert_eq!(res["type"].as_str().unwrap(), "FailedTask");
+
+    let (res, _) = contract_compute_with_gas_limit(port, contract_address, &args, "addition(uint,uint)", Some(100_000_000));
+    assert_eq!(res["type"].as_str().unwrap(), "ComputeTask");
+}
+
+#[test]
+fn test_compute_task_configured_gas_limits() {
+    let port = "5605";
+    run_core_with_gas_limits(port, GasLimits::new(1000, 200_000_000, 100_000_000).unwrap());
+
+    let (_, contract_address): (_, [u8; 32]) = full_simple_deployment(port);
+    let args = [Token::Uint(1.into()), Token::Uint(2.into())];
+    // Within the default bounds but above the configured maximum.
+    let (res, _) = contract_compute_with_gas_limit(port, contract_address, &args, "addition(uint,uint)", Some(300_000_000));
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    assert!(res["msg"].as_str().unwrap().contains("[1000, 200000000]"));
+    assert_eq!(res["code"].as_str().unwrap(), "BadRequest");
+
+    let (res, _) = contract_compute_with_gas_limit(port, contract_address, &args, "addition(uint,uint)", None);
+    assert_eq!(res["type"].as_str().unwrap(), "ComputeTask");
+}
@@ -3,9 +3,12 @@ pub extern crate enigma_core_app as app;
 extern crate cross_test_utils;
 extern crate rustc_hex;
 extern crate ethabi;
+extern crate zmq;
 
-use integration_utils::{get_simple_msg_format, conn_and_call_ipc, is_hex, run_core, erc20_deployment_without_ptt_to_addr,
-                        run_ptt_round, contract_compute, full_simple_deployment, full_erc20_deployment};
+use integration_utils::{get_simple_msg_format, conn_and_call_ipc, conn_and_call_ipc_with_timeout, is_hex, run_core, erc20_deployment_without_ptt_to_addr,
+                        run_ptt_round, contract_compute, full_simple_deployment, full_erc20_deployment, IPC_CONTEXTS_CREATED,
+                        conn_and_call_ipc_multipart, IPC_TIMEOUT};
+use std::sync::atomic::Ordering;
 use cross_test_utils::generate_contract_address;
 use rustc_hex::{ToHex, FromHex};
 use std::str::from_utf8;
@@ -13,6 +16,7 @@ use ethabi::Token;
 use app::serde_json::*;
 use cross_test_utils::generate_user_address;
 use integration_utils::enigma_crypto::symmetric;
+use app::networking::messages::IPC_PROTOCOL_VERSION;
 
 #[test]
 fn test_registration_params() {
@@ -34,6 +38,15 @@ fn test_registration_params() {
     assert!(is_hex(result_sig));
 }
 
+#[test]
+fn test_call_without_core_times_out() {
+    // Nothing is listening on this port, so the request should time out instead of hanging.
+    let port = "5571";
+    let msg = get_simple_msg_format("GetAllTips");
+    let res = conn_and_call_ipc_with_timeout(&msg.to_string(), port, 500);
+    assert_eq!(res.unwrap_err(), zmq::Error::EAGAIN);
+}
+
 #[test]
 fn test_deploy_with_no_ptt() {
     let port = "5575";
@@ -53,7 +66,8 @@ fn test_compute_on_empty_address() {
     let callable  = "mint(bytes32,uint256)";
     let (_val,_) = contract_compute(port, _address.into(), &args, callable);
     let accepted_err =  _val["msg"].as_str().unwrap();
-    assert_eq!(accepted_err.to_string(), format!("Error while trying to get_contract, Because: The following Key doesn\'t exist: {}", _address.to_hex()));
+    assert_eq!(accepted_err.to_string(), format!("Unknown contract {}, it was never deployed", _address.to_hex()));
+    assert_eq!(_val["code"].as_str().unwrap(), "NotFound");
 }
 
 #[test]
@@ -104,4 +118,43 @@ fn test_out_of_gas() {
     assert_eq!("Invocation resulted in gas limit violated", from_utf8(&dec).unwrap());
     assert_eq!(200, used_gas);
 
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_unsupported_protocol_version() {
+    let port = "5592";
+    run_core(port);
+    let mut msg = get_simple_msg_format("GetAllTips");
+    msg["version"] = json!(IPC_PROTOCOL_VERSION + 1);
+    let v: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(v["type"].as_str().unwrap(), "Error");
+    let expected = format!("Unsupported IPC protocol version {}, this core supports version {}", IPC_PROTOCOL_VERSION + 1, IPC_PROTOCOL_VERSION);
+    assert_eq!(v["msg"].as_str().unwrap(), expected);
+    assert_eq!(v["code"].as_str().unwrap(), "BadRequest");
+    // The version is checked before the type, which another version may have and this one not.
+    msg["type"] = json!("SomeFutureRequest");
+    let v: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(v["msg"].as_str().unwrap(), expected);
+
+    // Without a version the message is of the current one.
+    let v: Value = conn_and_call_ipc(&get_simple_msg_format("GetAllTips").to_string(), port);
+    assert_eq!(v["type"].as_str().unwrap(), "GetAllTips");
+    assert_eq!(v["version"].as_u64().unwrap(), u64::from(IPC_PROTOCOL_VERSION));
+}
+
+#[test]
+fn test_calls_share_a_context() {
+    let port = "5601";
+    run_core(port);
+    let msg = get_simple_msg_format("GetAllTips").to_string();
+    // Every helper that calls the core, many times each.
+    for _ in 0..50 {
+        let v = conn_and_call_ipc(&msg, port);
+        assert_eq!(v["type"].as_str().unwrap(), "GetAllTips");
+        let v = conn_and_call_ipc_with_timeout(&msg, port, IPC_TIMEOUT).unwrap();
+        assert_eq!(v["type"].as_str().unwrap(), "GetAllTips");
+        assert!(!conn_and_call_ipc_multipart(&msg, port).is_empty());
+    }
+    // The other tests of this binary call the core too, they all share the same context.
+    assert_eq!(IPC_CONTEXTS_CREATED.load(Ordering::SeqCst), 1);
+}
@@ -4,7 +4,7 @@ pub extern crate cross_test_utils;
 extern crate rustc_hex as hex;
 
 use integration_utils::{conn_and_call_ipc, is_hex, run_core, run_ptt_round,
-                        get_ptt_req_msg, parse_packed_msg};
+                        get_ptt_req_msg, parse_ptt_request};
 use self::cross_test_utils::{generate_contract_address};
 use self::app::serde_json;
 use app::serde_json::*;
@@ -19,9 +19,8 @@ fn test_get_ptt_request() {
 
     let packed_msg = v["result"]["request"].as_str().unwrap();
     let result_sig = v["result"]["workerSig"].as_str().unwrap();
-    let msg = parse_packed_msg(packed_msg);
-    assert!(msg["data"]["Request"].is_null());
-    assert_eq!(msg["pubkey"].as_array().unwrap().len(), 64);
+    let req = parse_ptt_request(packed_msg);
+    assert_eq!(req.pubkey.len(), 64);
     assert!(is_hex(result_sig));
 }
 
@@ -1,15 +1,19 @@
 pub mod integration_utils;
 
-use integration_utils::{run_core, full_simple_deployment, deploy_and_compute_few_contracts,
+use integration_utils::{run_core, run_core_concurrent, full_simple_deployment, deploy_and_compute_few_contracts,
                         conn_and_call_ipc, get_msg_format_with_input, get_get_tips_msg, get_delta_msg,
-                        deltas_msg, get_simple_msg_format, decrypt_delta_to_value};
+                        deltas_msg, get_simple_msg_format, decrypt_delta_to_value,
+                        full_simple_deployment_with_meta, get_contract_meta_msg, send_update_contract,
+                        conn_and_call_ipc_multipart, generate_job_id};
 pub extern crate enigma_core_app as app;
 extern crate serde;
 extern crate rustc_hex as hex;
 
 use self::app::serde_json;
+use app::networking::framing;
 use app::serde_json::*;
 use hex::{ToHex, FromHex};
+use std::thread;
 
 #[test]
 fn test_ipc_get_tip() {
@@ -29,6 +33,60 @@ fn test_ipc_get_tip() {
     assert_eq!(key, 0);
 }
 
+#[test]
+fn test_ipc_get_contract_in_chunks() {
+    let port =  "5589";
+    run_core(port);
+
+    let address = [7u8; 32].to_hex();
+    let bytecode: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    send_update_contract(port, &address, bytecode.clone());
+
+    let chunk_size = 16 * 1024;
+    let msg = json!({"id": &generate_job_id(), "type": "GetContract", "input": &address, "chunkSize": chunk_size});
+    let frames = conn_and_call_ipc_multipart(&msg.to_string(), port);
+    assert!(frames.len() > 1);
+    assert!(frames.iter().all(|frame| frame[0] == framing::FRAME_MARKER && frame.len() <= framing::HEADER_LEN + chunk_size));
+
+    let payloads = framing::join(&frames, framing::MAX_PAYLOAD_LEN).unwrap();
+    assert_eq!(payloads.len(), 1);
+    let res: Value = serde_json::from_slice(&payloads[0]).unwrap();
+    assert_eq!(res["type"].as_str().unwrap(), "GetContract");
+    let reassembled: Vec<u8> = serde_json::from_value(res["result"]["bytecode"].clone()).unwrap();
+    assert_eq!(reassembled, bytecode);
+
+    // Without a chunk size the whole contract is returned in a single frame.
+    let msg = get_msg_format_with_input("GetContract", &address);
+    let frames = conn_and_call_ipc_multipart(&msg.to_string(), port);
+    assert_eq!(frames.len(), 1);
+    let res: Value = serde_json::from_slice(&frames[0]).unwrap();
+    let whole: Vec<u8> = serde_json::from_value(res["result"]["bytecode"].clone()).unwrap();
+    assert_eq!(whole, bytecode);
+}
+
+#[test]
+fn test_ipc_get_contracts() {
+    let port =  "5595";
+    run_core(port);
+
+    let first = [8u8; 32].to_hex();
+    let second = [9u8; 32].to_hex();
+    let missing = [10u8; 32].to_hex();
+    send_update_contract(port, &first, vec![1, 2, 3]);
+    send_update_contract(port, &second, vec![4, 5]);
+
+    let msg = json!({"id": &generate_job_id(), "type": "GetContracts", "addresses": [&first, &missing, &second]});
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "GetContracts");
+    let contracts = res["result"]["contracts"].as_object().unwrap();
+    assert_eq!(contracts.len(), 3);
+    let first_code: Vec<u8> = serde_json::from_value(contracts[&first].clone()).unwrap();
+    let second_code: Vec<u8> = serde_json::from_value(contracts[&second].clone()).unwrap();
+    assert_eq!(first_code, vec![1, 2, 3]);
+    assert_eq!(second_code, vec![4, 5]);
+    assert!(contracts[&missing].is_null());
+}
+
 #[test]
 fn test_ipc_get_tips() {
     let port =  "5562";
@@ -37,7 +95,10 @@ fn test_ipc_get_tips() {
     let mut _addresses = deploy_and_compute_few_contracts(port);
 
     let missing_addr = _addresses.pop().unwrap().to_hex();
-    let _addresses: Vec<String> = _addresses.iter().map(|addr| addr.to_hex()).collect();
+    let mut _addresses: Vec<String> = _addresses.iter().map(|addr| addr.to_hex()).collect();
+    // A contract that was never deployed sits between the existing ones.
+    let nonexistent_addr = [0xabu8; 32].to_hex();
+    _addresses.insert(1, nonexistent_addr.clone());
     let _msg = get_get_tips_msg(&_addresses);
     let res: Value = conn_and_call_ipc(&_msg.to_string(), port);
 
@@ -48,7 +109,8 @@ fn test_ipc_get_tips() {
         assert_eq!(val["key"].as_u64().unwrap(), 1);
         accepted_addrs.push(val["address"].as_str().unwrap())
     }
-    assert_eq!(tips.len(), 2);
+    assert_eq!(accepted_addrs, vec![_addresses[0].as_str(), _addresses[2].as_str()]);
+    assert_eq!(res["result"]["missing"], json!([nonexistent_addr]));
     // make sure that the address we didn't send does not exist in the result
     assert_eq!(accepted_addrs.iter().find(|&&addr| addr == missing_addr), None);
 }
@@ -143,3 +205,43 @@ fn test_ipc_get_contract() {
     assert_eq!(type_accepted, type_msg);
     assert_eq!(deployed_bytecode, accepted_bytecode.to_hex());
 }
+
+#[test]
+fn test_ipc_get_contract_meta() {
+    let port =  "5582";
+    run_core(port);
+
+    let metadata = json!({"deployer": "627306090abab3a6e1400e9345bc60c78a8bef57", "deploymentBlock": 42, "name": "simplest"});
+    let (_, address) = full_simple_deployment_with_meta(port, Some(metadata.clone()));
+    let msg = get_contract_meta_msg(&address.to_hex());
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+
+    assert_eq!(res["type"].as_str().unwrap(), "GetContractMeta");
+    assert_eq!(res["result"]["address"].as_str().unwrap(), address.to_hex());
+    assert_eq!(res["result"]["metadata"], metadata);
+}
+
+#[test]
+fn test_ipc_concurrent_get_tip() {
+    let port =  "5583";
+    let threads = 4;
+    let requests = 100;
+    run_core_concurrent(port, threads);
+
+    let (_, contract_address): (_, [u8; 32]) = full_simple_deployment(port);
+    let msg = get_msg_format_with_input("GetTip", &contract_address.to_hex()).to_string();
+
+    // That reads don't wait for each other is tested in ipc_listener, this checks they're all answered correctly.
+    let handles: Vec<_> = (0..threads).map(|_| {
+        let msg = msg.clone();
+        thread::spawn(move || {
+            for _ in 0..requests / threads {
+                let res: Value = conn_and_call_ipc(&msg, port);
+                assert_eq!(res["result"]["key"].as_u64().unwrap(), 0);
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
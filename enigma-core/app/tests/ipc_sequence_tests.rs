@@ -0,0 +1,65 @@
+//! Registering a client makes every core of the process require signed requests,
+//! so these tests run in their own process, apart from the tests of the unsigned requests.
+pub mod integration_utils;
+pub extern crate enigma_core_app as app;
+pub extern crate ethabi;
+extern crate rustc_hex as hex;
+extern crate cross_test_utils;
+
+use integration_utils::{conn_and_call_ipc, run_core, full_simple_deployment, get_compute_msg, get_msg_format_with_input, produce_shared_key};
+use cross_test_utils::generate_contract_address;
+use app::networking::sequence::{sequence_message, ClientKey, SEQUENCES};
+use app::serde_json::{self, Value};
+use hex::ToHex;
+use integration_utils::ethabi::Token;
+use integration_utils::enigma_crypto::{asymmetric::KeyPair, symmetric};
+
+/// Numbers `msg` with `sequence` of `client` and signs it with `keys`.
+fn sign_request(mut msg: Value, client: &str, sequence: u64, keys: &KeyPair) -> Value {
+    msg["client"] = client.into();
+    msg["sequence"] = sequence.into();
+    let signature = keys.sign(&sequence_message(client, sequence, &serde_json::to_vec(&msg).unwrap())).unwrap();
+    msg["signature"] = signature.to_hex().into();
+    msg
+}
+
+#[test]
+fn test_replayed_compute_rejected() {
+    let port = "5596";
+    run_core(port);
+
+    let (_, contract_address): (_, [u8; 32]) = full_simple_deployment(port);
+    let (shared_key, user_pubkey) = produce_shared_key(port);
+    let keys = KeyPair::new().unwrap();
+    SEQUENCES.register(format!("p2p-1:{}", keys.get_pubkey().to_hex()).parse::<ClientKey>().unwrap());
+
+    let encrypted_callable = symmetric::encrypt(b"addition(uint,uint)", &shared_key).unwrap();
+    let encrypted_args = symmetric::encrypt(&ethabi::encode(&[Token::Uint(1.into()), Token::Uint(2.into())]), &shared_key).unwrap();
+    let unsigned = get_compute_msg(&generate_contract_address().to_hex(), &encrypted_callable.to_hex(), &encrypted_args.to_hex(),
+                                   &user_pubkey.to_hex(), 100_000_000, &contract_address.to_hex());
+    let msg = sign_request(unsigned.clone(), "p2p-1", 1, &keys);
+
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "ComputeTask");
+    assert_eq!(res["result"]["delta"]["key"].as_u64().unwrap(), 1);
+
+    // The same request again is a replay, it's rejected before it's computed.
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    assert!(res["msg"].as_str().unwrap().contains("replay"));
+    assert_eq!(res["code"].as_str().unwrap(), "BadRequest");
+
+    // Neither dropping the sequence, nor raising it or renaming the client without the client's key gets it through.
+    let mut raised = msg.clone();
+    raised["sequence"] = 2.into();
+    let mut renamed = msg.clone();
+    renamed["client"] = "p2p-2".into();
+    let forged = sign_request(unsigned.clone(), "p2p-1", 3, &KeyPair::new().unwrap());
+    for replay in &[unsigned, raised, renamed, forged] {
+        let res: Value = conn_and_call_ipc(&replay.to_string(), port);
+        assert_eq!(res["type"].as_str().unwrap(), "Error");
+        assert_eq!(res["code"].as_str().unwrap(), "BadRequest");
+    }
+    let tip: Value = conn_and_call_ipc(&get_msg_format_with_input("GetTip", &contract_address.to_hex()).to_string(), port);
+    assert_eq!(tip["result"]["key"].as_u64().unwrap(), 1);
+}
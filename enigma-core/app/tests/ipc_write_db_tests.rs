@@ -2,7 +2,9 @@ pub mod integration_utils;
 
 use integration_utils::{run_core, full_simple_deployment, conn_and_call_ipc,
                         send_update_contract, get_update_deltas_msg, contract_compute,
-                        send_update_contract_on_deployment, remove_contract, remove_deltas};
+                        send_update_contract_on_deployment, remove_contract, remove_deltas,
+                        get_update_signed_deltas_msg, sign_delta, destroy_contract, full_simple_deployment_with_meta,
+                        get_msg_format_with_input, get_contract_meta_msg, deltas_msg, run_ptt_round};
 pub extern crate enigma_core_app as app;
 extern crate serde;
 extern crate rustc_hex as hex;
@@ -11,7 +13,8 @@ extern crate ethabi;
 use self::app::serde_json;
 use app::serde_json::*;
 use hex::{ToHex, FromHex};
-use integration_utils::cross_test_utils::{generate_contract_address};
+use integration_utils::cross_test_utils::{generate_contract_address, get_fake_worker};
+use integration_utils::enigma_crypto::{asymmetric::KeyPair, hash::Keccak256};
 use ethabi::Token::Uint;
 
 #[test]
@@ -114,6 +117,43 @@ fn test_ipc_update_deltas() {
     assert_eq!(updated, 0);
 }
 
+#[test]
+fn test_ipc_update_deltas_bad_signature() {
+    let port = "5584";
+    run_core(port);
+
+    let (deployed_res, _) = full_simple_deployment(port);
+    let deployed_delta = deployed_res["result"]["delta"].as_object().unwrap();
+    let key = deployed_delta["key"].as_u64().unwrap();
+    let data: Vec<u8> = serde_json::from_value(deployed_delta["data"].clone()).unwrap();
+
+    // The mocked KM node selects `get_fake_worker` for every contract in the PTT round.
+    let (good_addr, bad_addr, forged_addr) = (generate_contract_address(), generate_contract_address(), generate_contract_address());
+    run_ptt_round(port, vec![good_addr, bad_addr, forged_addr]);
+    let good_sig = sign_delta(&get_fake_worker(good_addr), &good_addr, key as u32, &data);
+    // Signed over a different address, so it doesn't match the delta it's attached to.
+    let bad_sig = sign_delta(&get_fake_worker(bad_addr), &generate_contract_address(), key as u32, &data);
+    // Properly signed, but by a worker that wasn't selected for the contract.
+    let forged_sig = sign_delta(&KeyPair::new().unwrap(), &forged_addr, key as u32, &data);
+
+    let deltas = vec![
+        (good_addr.to_hex(), key, data.clone(), good_sig),
+        (bad_addr.to_hex(), key, data.clone(), bad_sig),
+        (forged_addr.to_hex(), key, data, forged_sig),
+    ];
+    let msg = get_update_signed_deltas_msg(&deltas);
+    let res: Value = conn_and_call_ipc(&msg.to_string(), port);
+
+    let status: i64 = serde_json::from_value(res["result"]["status"].clone()).unwrap();
+    assert_eq!(status, -1);
+    let errors = res["result"]["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 3);
+    for err in errors {
+        let expected = if err["address"].as_str().unwrap() == good_addr.to_hex() { 0 } else { -1 };
+        assert_eq!(err["status"].as_i64().unwrap(), expected);
+    }
+}
+
 #[test]
 fn test_ipc_remove_deltas() {
     let port = "5577";
@@ -136,4 +176,61 @@ fn test_ipc_remove_deltas() {
     let status = res["result"]["status"].as_u64().unwrap();
     assert_eq!(errors.len(), 0);
     assert_eq!(status, 0);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_ipc_destroy_contract() {
+    let port = "5602";
+    run_core(port);
+
+    let deployer = KeyPair::new().unwrap();
+    let metadata = json!({"deployer": deployer.get_pubkey().keccak256()[12..].to_hex(), "deploymentBlock": 7});
+    let (_, address) = full_simple_deployment_with_meta(port, Some(metadata));
+    let (res, _) = contract_compute(port, address, &[Uint(1.into()), Uint(2.into())], "addition(uint,uint)");
+    assert_eq!(res["result"]["delta"]["key"].as_u64().unwrap(), 1);
+
+    // Only the deployer can destroy the contract.
+    let res = destroy_contract(port, &address, 1, &KeyPair::new().unwrap());
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    assert_eq!(res["code"].as_str().unwrap(), "BadRequest");
+    // An approval signed at an older tip can't be replayed.
+    let res = destroy_contract(port, &address, 0, &deployer);
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    let (res, _) = contract_compute(port, address, &[Uint(1.into()), Uint(2.into())], "addition(uint,uint)");
+    assert_eq!(res["type"].as_str().unwrap(), "ComputeTask");
+    let delta = res["result"]["delta"].clone();
+
+    let res = destroy_contract(port, &address, 2, &deployer);
+    assert_eq!(res["type"].as_str().unwrap(), "DestroyContract");
+    assert_eq!(res["address"].as_str().unwrap(), address.to_hex());
+    assert_eq!(res["result"]["status"].as_i64().unwrap(), 0);
+
+    // The bytecode, deltas and metadata are all gone.
+    let res = conn_and_call_ipc(&get_msg_format_with_input("GetContract", &address.to_hex()).to_string(), port);
+    let bytecode: Vec<u8> = serde_json::from_value(res["result"]["bytecode"].clone()).unwrap();
+    assert!(bytecode.is_empty());
+    let res = conn_and_call_ipc(&get_msg_format_with_input("GetTip", &address.to_hex()).to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    let res = conn_and_call_ipc(&deltas_msg(&[(address.to_hex(), 0, 3)], "GetDeltas").to_string(), port);
+    for delta in res["result"]["deltas"].as_array().unwrap() {
+        assert!(delta.get("data").is_none());
+    }
+    let res = conn_and_call_ipc(&get_contract_meta_msg(&address.to_hex()).to_string(), port);
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+
+    let (res, _) = contract_compute(port, address, &[Uint(1.into()), Uint(2.into())], "addition(uint,uint)");
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    assert!(res["msg"].as_str().unwrap().contains(&format!("Contract {} was destroyed", address.to_hex())));
+    assert_eq!(res["code"].as_str().unwrap(), "NotFound");
+
+    // A sync can't bring it back.
+    let res = send_update_contract(port, &address.to_hex(), b"bytecode".to_vec());
+    assert_eq!(res["type"].as_str().unwrap(), "Error");
+    let data: Vec<u8> = serde_json::from_value(delta["data"].clone()).unwrap();
+    let msg = get_update_deltas_msg(&[(address.to_hex(), delta["key"].as_u64().unwrap(), data)]);
+    let res = conn_and_call_ipc(&msg.to_string(), port);
+    assert_eq!(res["result"]["status"].as_i64().unwrap(), -1);
+    let res = conn_and_call_ipc(&get_msg_format_with_input("GetContract", &address.to_hex()).to_string(), port);
+    let bytecode: Vec<u8> = serde_json::from_value(res["result"]["bytecode"].clone()).unwrap();
+    assert!(bytecode.is_empty());
+}
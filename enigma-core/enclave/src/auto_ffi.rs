@@ -26,9 +26,24 @@ extern "C" {
         delta_index: *mut u32,
     ) -> sgx_status_t;
 }
+extern "C" {
+    pub fn ocall_save_delta_and_state(
+        retval: *mut EnclaveReturn,
+        db_ptr: *const RawPointer,
+        contract_address: *const ContractAddress,
+        delta_index: *mut u32,
+        enc_delta: *const u8,
+        delta_len: usize,
+        enc_state: *const u8,
+        state_len: usize,
+    ) -> sgx_status_t;
+}
 extern "C" {
     pub fn ocall_save_to_memory(retval: *mut u64, data_ptr: *const u8, data_len: usize) -> sgx_status_t;
 }
+extern "C" {
+    pub fn ocall_log(level: u32, msg_ptr: *const u8, msg_len: usize) -> sgx_status_t;
+}
 extern "C" {
     pub fn ocall_get_deltas_sizes(
         retval: *mut EnclaveReturn,
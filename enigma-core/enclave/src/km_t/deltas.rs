@@ -0,0 +1,188 @@
+//! Authentication of the deltas received from other workers.
+//! A delta is signed by the worker that computed it over `address`, the delta index and the encrypted delta,
+//! and only deltas signed by a worker the KM node selected for the contract are accepted (see `SELECTED_WORKERS`),
+//! so a peer can't inject fabricated deltas into the DB without the key of a selected worker.
+use super::SELECTED_WORKERS;
+use enigma_crypto::{asymmetric::KeyPair, hash::{prepare_hash_multiple, Keccak256}, symmetric::derive_key, Encryption};
+use enigma_runtime_t::data::{to_canonical_json, ContractState, DeltasInterface, EncryptedPatch, StatePatch};
+use enigma_runtime_t::ocalls_t as runtime_ocalls_t;
+use enigma_tools_m::utils::{EthereumAddress, LockExpectRwLock};
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveSystemError};
+use enigma_types::{ContractAddress, Hash256, RawPointer, StateKey};
+use std::string::ToString;
+use std::vec::Vec;
+
+/// The message a worker signs for a delta.
+pub fn delta_message(address: &ContractAddress, key: u32, data: &[u8]) -> Vec<u8> {
+    prepare_hash_multiple(&[&address[..], &key.to_be_bytes()[..], data])
+}
+
+/// Verifies that the delta `key` of `address` was signed by a worker the KM node selected for the contract.
+pub(crate) fn ecall_verify_delta_internal(address: &ContractAddress, key: u32, data: &[u8], sig: [u8; 65]) -> Result<(), EnclaveError> {
+    let pubkey = KeyPair::recover(&delta_message(address, key, data), sig)?;
+    let recovered: [u8; 20] = pubkey.address();
+    let selected = SELECTED_WORKERS.read_or_error("Selected Workers")?;
+    match selected.get(address) {
+        Some(workers) if workers.contains(&recovered) => Ok(()),
+        Some(_) => {
+            let err = format!("delta {} of {:?} was signed by {:?} which wasn't selected for the contract", key, address, recovered);
+            Err(EnclaveError::SystemError(EnclaveSystemError::WorkerAuthError { err }))
+        }
+        None => {
+            let err = format!("no selected worker is known for {:?}, can't verify delta {}", address, key);
+            Err(EnclaveError::SystemError(EnclaveSystemError::WorkerAuthError { err }))
+        }
+    }
+}
+
+/// Decrypts the delta `key` of `address` and returns its json patch operations serialized as JSON.
+/// This reveals the contents of the delta, so the ecall only allows it in debug builds.
+pub(crate) fn ecall_decode_delta_internal(address: ContractAddress, key: u32, data: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let state_key = super::get_state_key(address)?;
+    let enc = EncryptedPatch::from_stored(data.to_vec(), address, key);
+    let delta = StatePatch::decrypt(enc, &state_key)?;
+    serde_json::to_vec(&delta.patch).map_err(|e| EnclaveError::SystemError(EnclaveSystemError::StateError { err: e.to_string() }))
+}
+
+/// Rebuilds the state of `address` as it was after the delta `index` by replaying the deltas `0..=index` from the DB.
+pub(crate) fn state_at(db_ptr: *const RawPointer, address: ContractAddress, index: u32) -> Result<ContractState, EnclaveError> {
+    let state_key = super::get_state_key(address)?;
+    let end = index.checked_add(1).ok_or_else(|| EnclaveError::SystemError(EnclaveSystemError::StateError { err: "Delta index overflow".to_string() }))?;
+    let deltas = runtime_ocalls_t::get_deltas(db_ptr, address, 0, end)?;
+    if deltas.len() != end as usize {
+        let err = format!("Missing deltas of {:?}, found {} out of {}", address, deltas.len(), end);
+        return Err(EnclaveError::SystemError(EnclaveSystemError::StateError { err }));
+    }
+    let mut state = ContractState::new(address);
+    for delta in deltas {
+        state.apply_delta(delta, &state_key)?;
+    }
+    Ok(state)
+}
+
+/// Returns the state of `address` after the delta `index` (see `state_at`) serialized as JSON.
+/// Like decoding a delta this reveals the state, so it's only allowed in debug builds.
+pub(crate) fn ecall_get_state_at_internal(db_ptr: *const RawPointer, address: ContractAddress, index: u32) -> Result<Vec<u8>, EnclaveError> {
+    let state = state_at(db_ptr, address, index)?;
+    serde_json::to_vec(&state.json).map_err(|e| EnclaveError::SystemError(EnclaveSystemError::StateError { err: e.to_string() }))
+}
+
+/// A hash of the whole state that workers can compare without revealing it.
+/// It's keyed with a key derived from the state key, so it can't be used to guess the state by someone without that key,
+/// and the JSON is serialized canonically (see `to_canonical_json`) so equal states always hash the same.
+pub fn state_fingerprint(state: &ContractState, state_key: &StateKey) -> Result<Hash256, EnclaveError> {
+    let fingerprint_key = derive_key(state_key, &state.contract_address[..], b"state fingerprint");
+    let json = to_canonical_json(&state.json)?;
+    Ok(prepare_hash_multiple(&[&fingerprint_key[..], &json[..]]).keccak256())
+}
+
+/// Returns the delta hash, the delta index and the fingerprint (see `state_fingerprint`) of the latest state of `address` in the DB.
+pub(crate) fn ecall_state_fingerprint_internal(db_ptr: *const RawPointer, address: ContractAddress) -> Result<(Hash256, u32, Hash256), EnclaveError> {
+    let state_key = super::get_state_key(address)?;
+    let enc_state = runtime_ocalls_t::get_state(db_ptr, address)?;
+    let state = ContractState::decrypt(enc_state, &state_key)?;
+    let fingerprint = state_fingerprint(&state, &state_key)?;
+    Ok((state.delta_hash, state.delta_index, fingerprint))
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+    use crate::km_t::{SELECTED_WORKERS, STATE_KEYS};
+    use enigma_crypto::hash::Sha256;
+    use enigma_tools_m::utils::LockExpectRwLock;
+    use serde_json::Value;
+
+    pub fn test_verify_delta_signature() {
+        let worker = KeyPair::new().unwrap();
+        let address = b"Enigma".sha256();
+        let data = [1u8, 2, 3, 4, 5];
+        let sig = worker.sign(&delta_message(&address, 1, &data)).unwrap();
+        // Before the KM node selected a worker for the contract nothing is accepted.
+        assert!(ecall_verify_delta_internal(&address, 1, &data, sig).is_err());
+        SELECTED_WORKERS.write_expect("Selected Workers").insert(address, vec![worker.get_pubkey().address()]);
+
+        assert!(ecall_verify_delta_internal(&address, 1, &data, sig).is_ok());
+        // A different index or data mustn't pass with the same signature.
+        assert!(ecall_verify_delta_internal(&address, 2, &data, sig).is_err());
+        assert!(ecall_verify_delta_internal(&address, 1, &data[1..], sig).is_err());
+        // Neither does it for another contract.
+        assert!(ecall_verify_delta_internal(&b"other".sha256(), 1, &data, sig).is_err());
+        SELECTED_WORKERS.write_expect("Selected Workers").remove(&address);
+    }
+
+    pub fn test_verify_delta_forged_signer() {
+        let selected = KeyPair::new().unwrap();
+        let forger = KeyPair::new().unwrap();
+        let address = b"forged signer".sha256();
+        let data = [6u8, 7, 8];
+        SELECTED_WORKERS.write_expect("Selected Workers").insert(address, vec![selected.get_pubkey().address()]);
+
+        // A validly signed delta from a worker that wasn't selected is rejected,
+        // whatever address the peer that sent it claims the signer has.
+        let sig = forger.sign(&delta_message(&address, 0, &data)).unwrap();
+        match ecall_verify_delta_internal(&address, 0, &data, sig) {
+            Err(EnclaveError::SystemError(EnclaveSystemError::WorkerAuthError { .. })) => (),
+            res => panic!("a forged signer was accepted: {:?}", res),
+        }
+        let sig = selected.sign(&delta_message(&address, 0, &data)).unwrap();
+        assert!(ecall_verify_delta_internal(&address, 0, &data, sig).is_ok());
+        SELECTED_WORKERS.write_expect("Selected Workers").remove(&address);
+    }
+
+    pub fn test_state_fingerprint() {
+        let address = b"fingerprint".sha256();
+        let state_key = *b"fingerprint_key".sha256();
+        // The original worker builds the state while generating the deltas.
+        let mut original = ContractState::new(address);
+        let mut deltas = Vec::new();
+        for i in 0..5u32 {
+            let old = original.clone();
+            original.json = json!({"counter": i, "history": (0..=i).collect::<Vec<u32>>(), "owner": "Enigma"});
+            deltas.push(ContractState::generate_delta_and_update_state(&old, &mut original, &state_key).unwrap());
+        }
+        // Another worker builds it independently by applying the same deltas.
+        let mut replayed = ContractState::new(address);
+        for delta in deltas.iter().cloned() {
+            replayed.apply_delta(delta, &state_key).unwrap();
+        }
+        assert_eq!(replayed.delta_index, original.delta_index);
+        assert_eq!(replayed.delta_hash, original.delta_hash);
+        assert_eq!(state_fingerprint(&replayed, &state_key).unwrap(), state_fingerprint(&original, &state_key).unwrap());
+
+        // A different history at the same index diverges.
+        let mut diverged = replayed.clone();
+        diverged.json["owner"] = json!("Someone else");
+        assert_ne!(state_fingerprint(&diverged, &state_key).unwrap(), state_fingerprint(&original, &state_key).unwrap());
+        // A number written in another form is the same state.
+        let mut reformatted = original.clone();
+        reformatted.json["owner"] = json!(1.0);
+        let mut integral = original.clone();
+        integral.json["owner"] = json!(1);
+        assert_eq!(state_fingerprint(&reformatted, &state_key).unwrap(), state_fingerprint(&integral, &state_key).unwrap());
+        // The fingerprint is keyed, the same state under another key doesn't match.
+        assert_ne!(state_fingerprint(&original, &*b"other_key".sha256()).unwrap(), state_fingerprint(&original, &state_key).unwrap());
+    }
+
+    pub fn test_decode_delta() {
+        let address = b"decode_delta".sha256();
+        let state_key = b"decode_delta_key".sha256();
+        STATE_KEYS.write_expect("State Keys").insert(address, *state_key);
+        // The patch of `test_diff_patch`.
+        let ops = json!([
+            {"op": "replace", "path": "/author/name2", "value": "Lennon"},
+            {"op": "add", "path": "/tags/2", "value": "third"},
+            {"op": "remove", "path": "/title"}
+        ]);
+        let delta = StatePatch { patch: serde_json::from_value(ops.clone()).unwrap(), previous_hash: [0u8; 32].into(), contract_address: address, index: 1 };
+        let enc = delta.encrypt(&state_key).unwrap();
+
+        let decoded = ecall_decode_delta_internal(address, 1, &enc.data).unwrap();
+        let decoded: Value = serde_json::from_slice(&decoded).unwrap();
+        assert_eq!(decoded, ops);
+        assert_eq!(decoded.as_array().unwrap().len(), 3);
+        // Without the state key of the contract it can't be decoded.
+        assert!(ecall_decode_delta_internal(b"other".sha256(), 1, &enc.data).is_err());
+        STATE_KEYS.write_expect("State Keys").remove(&address);
+    }
+}
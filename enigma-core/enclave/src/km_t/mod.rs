@@ -1,24 +1,46 @@
+pub(crate) mod deltas;
 pub(crate) mod principal;
+pub(crate) mod signing;
 pub(crate) mod users;
 
+pub(crate) use self::deltas::{ecall_decode_delta_internal, ecall_get_state_at_internal, ecall_state_fingerprint_internal, ecall_verify_delta_internal};
 pub(crate) use self::principal::{ecall_build_state_internal, ecall_ptt_req_internal, ecall_ptt_res_internal};
 pub(crate) use self::users::ecall_get_user_key_internal;
 
 use enigma_runtime_t::data::{ContractState, EncryptedContractState};
 use enigma_runtime_t::ocalls_t as runtime_ocalls_t;
-use enigma_tools_t::common::errors_t::EnclaveError;
-use enigma_tools_m::utils::LockExpectMutex;
-use enigma_crypto::{Encryption, CryptoError};
-use enigma_types::{ContractAddress, RawPointer, StateKey};
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveSystemError};
+use enigma_tools_m::utils::{LockExpectRwLock, DEFAULT_LOCK_ATTEMPTS};
+use enigma_crypto::{symmetric, Encryption, CryptoError};
+use enigma_tools_m::primitives::task_primitives::contract_settings_message;
+use enigma_types::{ContractAddress, GasMultiplier, HostCapabilities, RawPointer, StateKey};
 use std::collections::HashMap;
-use std::sync::SgxMutex;
+use std::string::ToString;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{SgxRwLock, SgxRwLockReadGuard};
+use std::vec::Vec;
 
 lazy_static! {
-    pub static ref STATE_KEYS: SgxMutex<HashMap<ContractAddress, StateKey>> = SgxMutex::new(HashMap::new());
+    /// Read by every computation while only provisioning new keys writes, so readers share the lock instead of taking turns.
+    /// The enclave has a single TCS (see `Enclave.config.xml`), so for now only one thread is ever inside it to read.
+    pub static ref STATE_KEYS: SgxRwLock<HashMap<ContractAddress, StateKey>> = SgxRwLock::new(HashMap::new());
+    /// The workers the KM node selected for every contract we were provisioned a key for, over all the PTT rounds.
+    /// Only deltas signed by one of them are accepted (see `ecall_verify_delta_internal`).
+    pub static ref SELECTED_WORKERS: SgxRwLock<HashMap<ContractAddress, Vec<[u8; 20]>>> = SgxRwLock::new(HashMap::new());
+}
+
+static STATE_KEYS_LOCK_ATTEMPTS: AtomicU32 = AtomicU32::new(DEFAULT_LOCK_ATTEMPTS);
+
+/// Sets how many times reading the state keys tries to take their lock before failing with a `LockError`.
+pub fn set_state_keys_lock_attempts(attempts: u32) { STATE_KEYS_LOCK_ATTEMPTS.store(attempts, Ordering::SeqCst) }
+
+/// Reads the state keys, a lock that stays held for `STATE_KEYS_LOCK_ATTEMPTS` attempts fails the call instead of blocking it forever.
+fn read_state_keys() -> Result<SgxRwLockReadGuard<'static, HashMap<ContractAddress, StateKey>>, EnclaveError> {
+    Ok(STATE_KEYS.read_with_attempts("State Keys", STATE_KEYS_LOCK_ATTEMPTS.load(Ordering::SeqCst))?)
 }
 
 pub fn get_state_key(address: ContractAddress) -> Result<StateKey, EnclaveError> {
-    let statekeys_guard = STATE_KEYS.lock_expect("State Keys");
+    let statekeys_guard = read_state_keys()?;
     statekeys_guard
         .get(&address)
         .copied()
@@ -26,19 +48,159 @@ pub fn get_state_key(address: ContractAddress) -> Result<StateKey, EnclaveError>
 }
 
 pub fn encrypt_state(state: ContractState) -> Result<EncryptedContractState<u8>, EnclaveError> {
-    let state_keys_guard = STATE_KEYS.lock_expect("State Keys");
+    let state_keys_guard = read_state_keys()?;
     let key = state_keys_guard
         .get(&state.contract_address)
         .ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?;
     state.encrypt(&key)
 }
 
+/// The key of the MACs over the settings of `address`, derived from its state key so every worker of the contract can check them.
+fn settings_key(address: ContractAddress) -> Result<[u8; 32], EnclaveError> {
+    let state_key = get_state_key(address)?;
+    Ok(symmetric::derive_key(&state_key, &address[..], b"contract settings"))
+}
+
+/// The MAC over the settings `address` is deployed with, the untrusted side stores it and presents it with every task of the contract.
+pub fn settings_mac(address: ContractAddress, gas_multiplier: GasMultiplier, capabilities: HostCapabilities) -> Result<[u8; 32], EnclaveError> {
+    Ok(symmetric::mac(&settings_key(address)?, &contract_settings_message(&address, gas_multiplier, capabilities)))
+}
+
+/// Checks that `address` was deployed with `gas_multiplier` and `capabilities`,
+/// so the untrusted side can't change the price of a contract or let it use host functions it was denied.
+pub fn verify_settings(address: ContractAddress, gas_multiplier: GasMultiplier, capabilities: HostCapabilities,
+                       mac: &[u8; 32]) -> Result<(), EnclaveError> {
+    symmetric::verify_mac(&settings_key(address)?, &contract_settings_message(&address, gas_multiplier, capabilities), mac)?;
+    Ok(())
+}
+
 pub fn get_state(db_ptr: *const RawPointer, addr: ContractAddress) -> Result<ContractState, EnclaveError> {
-    let guard = STATE_KEYS.lock_expect("State Keys");
+    let guard = read_state_keys()?;
     let key = guard.get(&addr).ok_or(CryptoError::MissingKeyError { key_type: "State Key" })?;
 
-    let enc_state = runtime_ocalls_t::get_state(db_ptr, addr)?;
-    let state = ContractState::decrypt(enc_state, key)?;
+    // A state that exists but can't be decrypted is an error, only a contract without a state and without deltas starts from scratch.
+    // Every delta is saved with its state, so a contract with deltas but no state must be built from them first.
+    match runtime_ocalls_t::try_get_state(db_ptr, addr)? {
+        Some(enc_state) => ContractState::decrypt(enc_state, key),
+        None => match runtime_ocalls_t::get_deltas(db_ptr, addr, 0, 1) {
+            Ok(ref deltas) if !deltas.is_empty() => {
+                Err(EnclaveError::SystemError(EnclaveSystemError::StateError { err: "The contract has deltas but no state".to_string() }))
+            }
+            _ => Ok(ContractState::new(addr)),
+        },
+    }
+}
 
+/// Installs the state of `addr` that `ContractState::export` exported on another node, so a migrated contract doesn't need its deltas.
+/// The state key of `addr` has to be provisioned already, and a state this node already has isn't overwritten.
+pub unsafe fn import_state(db_ptr: *const RawPointer, blob: &[u8], addr: ContractAddress) -> Result<ContractState, EnclaveError> {
+    let key = get_state_key(addr)?;
+    if runtime_ocalls_t::try_get_state(db_ptr, addr)?.is_some() {
+        return Err(EnclaveError::SystemError(EnclaveSystemError::StateError { err: "The contract already has a state".to_string() }));
+    }
+    let state = ContractState::from_export(blob, addr, &key)?;
+    runtime_ocalls_t::save_state(db_ptr, &state.clone().encrypt(&key)?)?;
     Ok(state)
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+    use enigma_crypto::hash::Sha256;
+    use enigma_runtime_t::data::{DeltasInterface, IOInterface};
+    use enigma_types::EnclaveReturn;
+
+    pub fn test_get_state_without_state(db_ptr: *const RawPointer) {
+        let address = b"provisioned before deployment".sha256();
+        STATE_KEYS.write_expect("State Keys").insert(address, *b"fresh key".sha256());
+
+        let state = get_state(db_ptr, address).unwrap();
+        assert_eq!(state, ContractState::new(address));
+        // Only the lenient variant treats it as a fresh state.
+        assert!(runtime_ocalls_t::get_state(db_ptr, address).is_err());
+    }
+
+    pub fn test_get_state_with_deltas_without_state(db_ptr: *const RawPointer) {
+        let address = b"lost its state".sha256();
+        let key = *b"lost key".sha256();
+        STATE_KEYS.write_expect("State Keys").insert(address, key);
+        let mut state = ContractState::new(address);
+        state.write_key("key", &json!("value")).unwrap();
+        let delta = ContractState::generate_delta_and_update_state(&ContractState::new(address), &mut state, &key).unwrap();
+        runtime_ocalls_t::save_delta(db_ptr, &delta).unwrap();
+
+        // Building on an empty state would silently drop what the deltas did.
+        assert!(get_state(db_ptr, address).is_err());
+    }
+
+    pub unsafe fn test_import_state(db_ptr: *const RawPointer) {
+        let address = b"migrated contract".sha256();
+        let key = *b"migrated key".sha256();
+        STATE_KEYS.write_expect("State Keys").insert(address, key);
+        let mut state = ContractState::new(address);
+        state.write_key("key", &json!("value")).unwrap();
+        state.delta_index = 3;
+        state.delta_hash = b"delta".sha256();
+        let blob = state.export(&key).unwrap();
+
+        assert_eq!(import_state(db_ptr, &blob, address).unwrap(), state);
+        assert_eq!(get_state(db_ptr, address).unwrap(), state);
+        // The installed state isn't overwritten.
+        assert!(import_state(db_ptr, &blob, address).is_err());
+        // Without the state key there's nothing to install it with.
+        assert!(import_state(db_ptr, &blob, b"not provisioned".sha256()).is_err());
+    }
+
+    pub unsafe fn test_get_state_wrong_key(db_ptr: *const RawPointer) {
+        let address = b"state of another key".sha256();
+        let mut state = ContractState::new(address);
+        state.write_key("key", &json!("value")).unwrap();
+        let enc_state = state.encrypt(&*b"right key".sha256()).unwrap();
+        runtime_ocalls_t::save_state(db_ptr, &enc_state).unwrap();
+        STATE_KEYS.write_expect("State Keys").insert(address, *b"wrong key".sha256());
+
+        assert!(get_state(db_ptr, address).is_err());
+    }
+
+    pub fn test_state_keys_lock_timeout() {
+        let address = b"state keys under contention".sha256();
+        STATE_KEYS.write_expect("State Keys").insert(address, *b"some key".sha256());
+        {
+            // The enclave runs the tests in a single thread, so holding the write lock here is enough contention.
+            let _writer = STATE_KEYS.write_expect("State Keys");
+            set_state_keys_lock_attempts(1000);
+            let err = get_state_key(address).unwrap_err();
+            set_state_keys_lock_attempts(DEFAULT_LOCK_ATTEMPTS);
+            match err {
+                EnclaveError::SystemError(EnclaveSystemError::LockError { .. }) => (),
+                other => panic!("Expected a lock error, got: {:?}", other),
+            }
+            assert_eq!(EnclaveReturn::from(Err::<(), _>(err)), EnclaveReturn::LockError);
+        }
+        assert_eq!(get_state_key(address).unwrap(), *b"some key".sha256());
+        STATE_KEYS.write_expect("State Keys").remove(&address);
+    }
+
+    pub fn test_settings_mac() {
+        let (address, other) = (b"priced contract".sha256(), b"another priced contract".sha256());
+        STATE_KEYS.write_expect("State Keys").insert(address, *b"priced key".sha256());
+        STATE_KEYS.write_expect("State Keys").insert(other, *b"priced key".sha256());
+        let multiplier = GasMultiplier { mul: 5, div: 2 };
+        let capabilities = HostCapabilities::ALL.without(HostCapabilities::RANDOMNESS);
+
+        let mac = settings_mac(address, multiplier, capabilities).unwrap();
+        assert!(verify_settings(address, multiplier, capabilities, &mac).is_ok());
+        // Neither a cheaper price, more capabilities nor the MAC of another contract passes.
+        assert!(verify_settings(address, GasMultiplier::default(), capabilities, &mac).is_err());
+        assert!(verify_settings(address, multiplier, HostCapabilities::ALL, &mac).is_err());
+        assert!(verify_settings(other, multiplier, capabilities, &mac).is_err());
+        let others_mac = settings_mac(other, GasMultiplier::default(), HostCapabilities::ALL).unwrap();
+        assert!(verify_settings(address, GasMultiplier::default(), HostCapabilities::ALL, &others_mac).is_err());
+        assert!(verify_settings(address, multiplier, capabilities, &[0u8; 32]).is_err());
+
+        STATE_KEYS.write_expect("State Keys").remove(&address);
+        STATE_KEYS.write_expect("State Keys").remove(&other);
+        // Without the state key the settings can't be checked.
+        assert!(verify_settings(address, multiplier, capabilities, &mac).is_err());
+    }
 }
\ No newline at end of file
@@ -1,14 +1,14 @@
-use super::STATE_KEYS;
+use super::{SELECTED_WORKERS, STATE_KEYS};
 use crate::SIGNING_KEY;
 use enigma_runtime_t::data::{ContractState, DeltasInterface};
 use enigma_runtime_t::ocalls_t as runtime_ocalls_t;
 use enigma_tools_t::common::errors_t::EnclaveError;
-use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_m::utils::{LockExpectMutex, LockExpectRwLock};
 use enigma_crypto::asymmetric::KeyPair;
 use enigma_crypto::{Encryption, CryptoError};
 use enigma_tools_m::primitives::km_primitives::MsgID;
-use enigma_tools_m::primitives::km_primitives::{PrincipalMessage, PrincipalMessageType};
-use enigma_types::{ContractAddress, StateKey, RawPointer};
+use enigma_tools_m::primitives::km_primitives::{PrincipalMessage, PttRequest};
+use enigma_types::{ContractAddress, RawPointer};
 use std::collections::HashMap;
 use std::sync::SgxMutex;
 use std::u32;
@@ -20,9 +20,8 @@ lazy_static! {
 
 pub(crate) unsafe fn ecall_ptt_req_internal(sig: &mut [u8; 65]) -> Result<Vec<u8>, EnclaveError> {
     let keys = KeyPair::new()?;
-    let data = PrincipalMessageType::Request;
-    let req = PrincipalMessage::new(data, keys.get_pubkey())?;
-    let id = req.get_id();
+    let req = PttRequest::new(keys.get_pubkey())?;
+    let id = req.id;
     *sig = SIGNING_KEY.sign(&req.to_sign()?)?;
     let msg = req.into_message()?;
     DH_KEYS.lock_expect("DH Keys").insert(id, keys);
@@ -34,44 +33,62 @@ pub(crate) fn ecall_ptt_res_internal(msg_slice: &[u8]) -> Result<(), EnclaveErro
 
     let mut guard = DH_KEYS.lock_expect("DH Keys");
     let id = res.get_id();
-    let msg;
+    let response;
     {
         let keys = guard.get(&id).ok_or(CryptoError::MissingKeyError { key_type: "DH Keys" })?;
         let aes = keys.derive_key(&res.get_pubkey())?;
-        msg = PrincipalMessage::decrypt(res, &aes)?;
+        response = res.decrypt_ptt_response(&aes)?;
     }
-    if let PrincipalMessageType::Response(v) = msg.data {
-        for (addr, key) in v {
-            STATE_KEYS.lock_expect("state keys").insert(addr, key);
+    let mut state_keys = STATE_KEYS.write_or_error("State Keys")?;
+    let mut selected = SELECTED_WORKERS.write_or_error("Selected Workers")?;
+    for (address, key, worker) in response.keys {
+        state_keys.insert(address, key);
+        let workers = selected.entry(address).or_insert_with(Vec::new);
+        if !workers.contains(&worker) {
+            workers.push(worker);
         }
-    } else {
-        unreachable!() // This should never execute. // TODO: Replace with an error.
     }
     guard.remove(&id);
     Ok(())
 }
 
-pub(crate) unsafe fn ecall_build_state_internal(db_ptr: *const RawPointer) -> Result<Vec<ContractAddress>, EnclaveError> {
-    let guard = STATE_KEYS.lock_expect("State Keys");
-    let mut failed_contracts = Vec::with_capacity(guard.len());
-    debug_println!("building state for {} contracts", guard.len());
+/// The cursor `ecall_build_state_internal` returns once every state is built.
+pub const BUILD_STATE_DONE: u32 = u32::MAX;
+/// How many deltas are fetched from the DB at once.
+const DELTAS_BATCH: u32 = 500;
 
-    'contract: for (addrs, key) in guard.iter() {
+/// Builds the states of the contracts we have keys for, starting at the contract with index `cursor` (in the order of their addresses).
+/// At most `max_deltas` deltas are applied (0 means no limit) so a single call doesn't occupy the enclave for too long,
+/// the progress is saved in the states so the next call continues from the returned cursor, or is `BUILD_STATE_DONE` if there's nothing left.
+/// Also returns the contracts whose state failed building.
+pub(crate) unsafe fn ecall_build_state_internal(db_ptr: *const RawPointer, max_deltas: u32, cursor: u32) -> Result<(Vec<ContractAddress>, u32), EnclaveError> {
+    let guard = STATE_KEYS.read_or_error("State Keys")?;
+    // The order of a HashMap isn't stable, so the cursor is an index into the sorted addresses.
+    let mut contracts: Vec<_> = guard.iter().collect();
+    contracts.sort_unstable_by_key(|(addrs, _)| **addrs);
+    let mut failed_contracts = Vec::new();
+    let mut remaining = if max_deltas == 0 { u32::MAX } else { max_deltas };
+    debug_println!("building state for {} contracts from {}", contracts.len(), cursor);
+
+    'contract: for (index, (addrs, key)) in contracts.into_iter().enumerate().skip(cursor as usize) {
+        if remaining == 0 {
+            return Ok((failed_contracts, index as u32));
+        }
         // Get the state and decrypt it.
         // if no state exists create a new one and if failed decrypting, push to failed_contracts and move on.
-        let (mut start, mut state ) = match runtime_ocalls_t::get_state(db_ptr, *addrs) {
-            Ok(enc_state) => match ContractState::decrypt(enc_state, &key) {
+        let (mut start, mut state ) = match runtime_ocalls_t::try_get_state(db_ptr, *addrs) {
+            Ok(Some(enc_state)) => match ContractState::decrypt(enc_state, &key) {
                 Ok(state) => (state.delta_index+1, state),
                 Err(_) => {
                     failed_contracts.push(*addrs);
                     continue 'contract;
                 }
             }, // don't throw error if only one failed, somehow tell that but continue
-            Err(_) => (0, ContractState::new(*addrs)),
+            Ok(None) | Err(_) => (0, ContractState::new(*addrs)),
         };
 
         'deltas: while start < u32::MAX {
-            let end = start + 500;
+            let end = start.saturating_add(DELTAS_BATCH.min(remaining));
             // Get deltas from start to end, if fails save the latest state and move on.
             let deltas = match runtime_ocalls_t::get_deltas(db_ptr, *addrs, start, end) {
                 Ok(deltas) => deltas,
@@ -112,7 +129,22 @@ pub(crate) unsafe fn ecall_build_state_internal(db_ptr: *const RawPointer) -> Re
                     }
                 };
             }
+            if max_deltas != 0 {
+                remaining -= deltas_len as u32;
+            }
             if deltas_len == (end - start) as usize {
+                if remaining == 0 {
+                    // Out of deltas for this call, save what was built so far and continue from this contract in the next one.
+                    let enc = match state.encrypt(key) {
+                        Ok(d) => d,
+                        Err(_) => {
+                            failed_contracts.push(*addrs);
+                            continue 'contract;
+                        }
+                    };
+                    runtime_ocalls_t::save_state(db_ptr, &enc)?;
+                    return Ok((failed_contracts, index as u32));
+                }
                 start = end;
             } else {
                 start = u32::MAX;
@@ -129,7 +161,7 @@ pub(crate) unsafe fn ecall_build_state_internal(db_ptr: *const RawPointer) -> Re
         };
         runtime_ocalls_t::save_state(db_ptr, &enc)?;
     }
-    Ok(failed_contracts)
+    Ok((failed_contracts, BUILD_STATE_DONE))
 }
 
 #[cfg(debug_assertions)]
@@ -139,8 +171,8 @@ pub mod tests {
     use enigma_runtime_t::data::{EncryptedContractState, EncryptedPatch};
     use enigma_crypto::hash::Sha256;
     use enigma_crypto::asymmetric::KeyPair;
-    use enigma_tools_m::primitives::km_primitives::{PrincipalMessage, PrincipalMessageType};
-    use enigma_types::{ContractAddress, RawPointer};
+    use enigma_tools_m::primitives::km_primitives::{ProvisionedKey, PttRequest, PttResponse};
+    use enigma_types::{ContractAddress, RawPointer, StateKey};
     use std::string::ToString;
 
 
@@ -161,23 +193,51 @@ pub mod tests {
         // Generating the request
         let mut _sig = [0u8; 65];
         let req_msg = ecall_ptt_req_internal(&mut _sig).unwrap();
-        let req_obj = PrincipalMessage::from_message(&req_msg).unwrap();
+        let req_obj = PttRequest::from_message(&req_msg).unwrap();
 
         // Mimicking the Principal/KM Node
         let km_node_keys = KeyPair::new().unwrap();
-        let restype: Vec<(ContractAddress, StateKey)> = address.clone().into_iter().zip(state_keys.into_iter()).collect();
+        let keys: Vec<ProvisionedKey> = address.clone().into_iter().zip(state_keys.into_iter()).map(|(a, k)| (a, k, [0u8; 20])).collect();
 
-        let res_obj = PrincipalMessage::new_id(PrincipalMessageType::Response(restype), req_obj.get_id(), km_node_keys.get_pubkey());
+        let res_obj = PttResponse::new(&req_obj, keys, km_node_keys.get_pubkey());
         let dh_key = km_node_keys.derive_key(&req_obj.get_pubkey()).unwrap();
-        let enc_req = res_obj.encrypt(&dh_key).unwrap();
-
-        let enc_res_slice = enc_req.into_message().unwrap();
+        let enc_res_slice = res_obj.into_message(&dh_key).unwrap();
 
         // Enclave Process Response
         ecall_ptt_res_internal(&enc_res_slice).unwrap();
 
         // Initiate the building
-        assert_eq!(ecall_build_state_internal(db_ptr).unwrap(), vec![address[2]])
+        assert_eq!(ecall_build_state_internal(db_ptr, 0, 0).unwrap(), (vec![address[2]], BUILD_STATE_DONE))
+    }
+
+    pub unsafe fn test_build_state_bounded(db_ptr: *const RawPointer) {
+        let address = b"bounded".sha256();
+        let key = *b"bounded_key".sha256();
+        let mut state = ContractState::new(address);
+        for i in 0..30 {
+            let old_state = state.clone();
+            state.write_key(&i.to_string(), &json!(i)).unwrap();
+            let delta = ContractState::generate_delta_and_update_state(&old_state, &mut state, &key).unwrap();
+            runtime_ocalls_t::save_delta(db_ptr, &delta).unwrap();
+        }
+        STATE_KEYS.write_expect("State Keys").insert(address, key);
+
+        // Every call applies at most 7 deltas, so building 30 deltas takes at least 5 calls.
+        let mut cursor = 0;
+        let mut calls = 0;
+        while cursor != BUILD_STATE_DONE {
+            let (failed, next) = ecall_build_state_internal(db_ptr, 7, cursor).unwrap();
+            assert!(!failed.contains(&address));
+            cursor = next;
+            calls += 1;
+            assert!(calls < 100, "build_state doesn't make progress");
+        }
+        assert!(calls >= 5, "took only {} calls", calls);
+
+        let built = ContractState::decrypt(runtime_ocalls_t::get_state(db_ptr, address).unwrap(), &key).unwrap();
+        assert_eq!(built.delta_index, 29);
+        assert_eq!(built.delta_hash, state.delta_hash);
+        assert_eq!(built.json, state.json);
     }
 
     fn get_states_deltas(address: &[ContractAddress], keys: &[StateKey]) -> Vec<Vec<EncryptedPatch>> {
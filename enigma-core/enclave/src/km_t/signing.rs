@@ -0,0 +1,77 @@
+//! Domain separated signing keys.
+//! Every key here is derived from the sealed master key (`SIGNING_KEY`) with its own derivation path,
+//! so if one signing context is attacked the signatures made in the other contexts are not affected. <br>
+//! Their addresses are exposed with `ecall_get_derived_signing_address`, so verifiers can learn them before the protocol switches to them.
+use crate::SIGNING_KEY;
+use enigma_crypto::asymmetric::KeyPair;
+use enigma_tools_t::common::errors_t::EnclaveError;
+
+/// The context a derived signing key is bound to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SigningContext {
+    /// Signs registration related messages (i.e. the user DH key messages and the PTT requests).
+    Registration,
+    /// Signs the results of deploy/compute tasks.
+    TaskResult,
+}
+
+impl SigningContext {
+    /// The context of the code the untrusted side passes, 0 for `Registration` and 1 for `TaskResult`.
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(SigningContext::Registration),
+            1 => Some(SigningContext::TaskResult),
+            _ => None,
+        }
+    }
+
+    /// The derivation path of the context.
+    pub fn path(self) -> &'static [u8] {
+        match self {
+            SigningContext::Registration => b"enigma/signing/registration",
+            SigningContext::TaskResult => b"enigma/signing/task-result",
+        }
+    }
+
+    /// The key of the context, derived once.
+    pub fn key(self) -> &'static KeyPair {
+        match self {
+            SigningContext::Registration => &REGISTRATION_KEY,
+            SigningContext::TaskResult => &TASK_RESULT_KEY,
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref REGISTRATION_KEY: KeyPair = get_signing_key(SigningContext::Registration).expect("Failed deriving the registration key");
+    pub static ref TASK_RESULT_KEY: KeyPair = get_signing_key(SigningContext::TaskResult).expect("Failed deriving the task result key");
+}
+
+/// Derive the signing key of `context` from the sealed master key.
+pub fn get_signing_key(context: SigningContext) -> Result<KeyPair, EnclaveError> {
+    Ok(SIGNING_KEY.derive_child(context.path())?)
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+    use enigma_crypto::asymmetric::KeyPair;
+
+    pub fn test_derived_signing_keys() {
+        let registration = get_signing_key(SigningContext::Registration).unwrap();
+        let task_result = get_signing_key(SigningContext::TaskResult).unwrap();
+        assert_ne!(&registration.get_pubkey()[..], &task_result.get_pubkey()[..]);
+        assert_eq!(&registration.get_pubkey()[..], &REGISTRATION_KEY.get_pubkey()[..]);
+        assert_eq!(&task_result.get_pubkey()[..], &TASK_RESULT_KEY.get_pubkey()[..]);
+        assert_eq!(SigningContext::from_code(0), Some(SigningContext::Registration));
+        assert_eq!(SigningContext::from_code(1), Some(SigningContext::TaskResult));
+        assert_eq!(SigningContext::from_code(2), None);
+
+        let msg = b"Enigma";
+        let registration_sig = registration.sign(msg).unwrap();
+        let task_result_sig = task_result.sign(msg).unwrap();
+        assert_eq!(&KeyPair::recover(msg, registration_sig).unwrap()[..], &registration.get_pubkey()[..]);
+        assert_eq!(&KeyPair::recover(msg, task_result_sig).unwrap()[..], &task_result.get_pubkey()[..]);
+        assert_ne!(&KeyPair::recover(msg, registration_sig).unwrap()[..], &task_result.get_pubkey()[..]);
+    }
+}
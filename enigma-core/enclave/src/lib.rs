@@ -25,28 +25,32 @@ extern crate serde_json;
 extern crate lazy_static;
 
 mod km_t;
+mod output_t;
 
 use crate::{
-    km_t::{ecall_build_state_internal, ecall_get_user_key_internal, ecall_ptt_req_internal, ecall_ptt_res_internal},
+    km_t::{ecall_build_state_internal, ecall_decode_delta_internal, ecall_get_state_at_internal, ecall_state_fingerprint_internal, ecall_get_user_key_internal, ecall_ptt_req_internal,
+           ecall_ptt_res_internal, ecall_verify_delta_internal},
 };
-use enigma_crypto::{asymmetric, hash::Keccak256, symmetric, CryptoError};
+use enigma_crypto::{asymmetric, hash::Keccak256, symmetric, CryptoError, Encryption};
 use enigma_runtime_t::{
-    data::{ContractState, EncryptedPatch},
+    data::{ContractState, EncryptedPatch, StatePatch},
     wasm_execution::WasmEngine,
-    EthereumData,
+    EthereumData, RuntimeResult,
 };
-use enigma_tools_m::utils::{EthereumAddress, LockExpectMutex};
+use enigma_tools_m::{primitives::{signature_domain::SignatureDomain, task_primitives::{compute_result_message, DeployReceipt, ReplayVerdict}}, utils::{EthereumAddress, LockExpectMutex}};
 use enigma_tools_t::{
     build_arguments_g::*,
     common::errors_t::{
         EnclaveError::{self, *},
         FailedTaskError::*,
     },
+    document_storage_t,
     esgx::ocalls_t,
     quote_t, storage_t,
 };
 use enigma_types::{
-    ContractAddress, DhKey, EnclaveReturn, ExecuteResult, Hash256, PubKey, RawPointer, ResultStatus,
+    ContractAddress, DhKey, EnclaveReturn, ExecuteResult, GasMultiplier, Hash256, HostCapabilities, PubKey, RawPointer, ReplayStats,
+    ResultStatus,
 };
 
 use sgx_types::*;
@@ -69,6 +73,18 @@ pub extern "C" fn ecall_get_registration_quote(target_info: &sgx_target_info_t,
 #[no_mangle]
 pub extern "C" fn ecall_get_signing_address(pubkey: &mut [u8; 20]) { pubkey.copy_from_slice(&SIGNING_KEY.get_pubkey().address()); }
 
+#[no_mangle]
+/// Ecall for getting the address of the signing key derived for the context with the code `context`, see `km_t::signing::SigningContext`.
+pub extern "C" fn ecall_get_derived_signing_address(context: u8, address: &mut [u8; 20]) -> EnclaveReturn {
+    match km_t::signing::SigningContext::from_code(context) {
+        Some(context) => {
+            address.copy_from_slice(&context.key().get_pubkey().address());
+            EnclaveReturn::Success
+        }
+        None => EnclaveReturn::KeysError,
+    }
+}
+
 #[no_mangle]
 /// Ecall for invocation of the external function `callable` of deployed contract with code `bytecode`.
 /// arguments:
@@ -80,7 +96,11 @@ pub extern "C" fn ecall_get_signing_address(pubkey: &mut [u8; 20]) { pubkey.copy
 /// * `args_len` - the length of the `args`
 /// * `user_key` - the DH key of the user to decrypt `callable` and `args`
 /// * `contract_address` - the address of the deployed contract with code `bytecode`
+/// * `task_id` - the id of the task, signed together with the results in `result_signature`
 /// * `gas_limit` - the gas limit for the function execution
+/// * `gas_multiplier` - the price of the contract, chosen when it was deployed
+/// * `capabilities` - the host functions the contract was allowed to import when it was deployed
+/// * `settings_mac` - the MAC over `gas_multiplier` and `capabilities` the deployment produced, see `ExecuteResult::settings_mac`
 /// * `result` - the result of the function invocation
 // TODO: add arguments of callable.
 pub unsafe extern "C" fn ecall_execute(
@@ -92,7 +112,11 @@ pub unsafe extern "C" fn ecall_execute(
     args_len: usize,
     user_key: &[u8; 64],
     contract_address: &ContractAddress,
+    task_id: &[u8; 32],
     gas_limit: *const u64,
+    gas_multiplier: &GasMultiplier,
+    capabilities: &HostCapabilities,
+    settings_mac: &[u8; 32],
     db_ptr: *const RawPointer,
     result: &mut ExecuteResult,
 ) -> EnclaveReturn
@@ -116,17 +140,132 @@ pub unsafe extern "C" fn ecall_execute(
         user_key,
         &io_key,
         (*contract_address).into(),
+        task_id,
         *gas_limit,
+        *gas_multiplier,
+        *capabilities,
+        settings_mac,
         db_ptr,
         result,
     );
     if let Err(e) = &internal_result {
         debug_println!("Error in execution of secret contract function: {}", e);
-        internal_result = output_task_failure(&pre_execution_data, *gas_limit, e, result, &io_key);
+        internal_result = output_task_failure(&pre_execution_data, *gas_limit, e, result, &io_key, Some(task_id));
     }
     internal_result.into()
 }
 
+#[no_mangle]
+/// Ecall for replaying a computation task and checking the result another worker claimed for it.
+/// The task is executed again on the state it ran on (with the same task seeded randomness) and nothing is stored.
+/// The verdict is signed over the same inputs, delta and output hashes the worker signed in its `ExecuteResult`,
+/// so it can only be presented for that result.
+/// arguments:
+/// * `bytecode`, `callable`, `args`, `user_key`, `contract_address`, `task_id`, `gas_limit`, `gas_multiplier`, `capabilities` and `settings_mac` -
+///   the inputs of the task as the worker got them, as in `ecall_execute`
+/// * `replay_key` - a DH public key of a fresh key exchange with this enclave
+/// * `task_key` - the DH key the task was encrypted with, encrypted with the key of `replay_key`
+/// * `task_key_len` - the length of the `task_key`
+/// * `claimed_delta` - the encrypted delta that was claimed for the task
+/// * `claimed_delta_len` - the length of the `claimed_delta`
+/// * `claimed_index` - the index of the claimed delta, 0 if the task didn't change the state
+/// * `claimed_output` - the output that was claimed for the task, as the worker returned it
+/// * `claimed_output_len` - the length of the `claimed_output`
+/// * `claimed_gas` - the gas that was claimed for the task
+/// * `matches` - set to 1 if the replay produced the claimed result, 0 otherwise
+/// * `sig` - the signature over the verdict, see `ReplayVerdict`
+/// * `stats` - what the replay cost, it isn't covered by the signature
+pub unsafe extern "C" fn ecall_replay_compute(
+    bytecode: *const u8,
+    bytecode_len: usize,
+    callable: *const u8,
+    callable_len: usize,
+    args: *const u8,
+    args_len: usize,
+    user_key: &[u8; 64],
+    replay_key: &[u8; 64],
+    task_key: *const u8,
+    task_key_len: usize,
+    contract_address: &ContractAddress,
+    task_id: &[u8; 32],
+    gas_limit: *const u64,
+    gas_multiplier: &GasMultiplier,
+    capabilities: &HostCapabilities,
+    settings_mac: &[u8; 32],
+    db_ptr: *const RawPointer,
+    claimed_delta: *const u8,
+    claimed_delta_len: usize,
+    claimed_index: u32,
+    claimed_output: *const u8,
+    claimed_output_len: usize,
+    claimed_gas: u64,
+    matches: &mut u8,
+    sig: &mut [u8; 65],
+    stats: &mut ReplayStats,
+) -> EnclaveReturn
+{
+    let bytecode = slice::from_raw_parts(bytecode, bytecode_len);
+    let callable = slice::from_raw_parts(callable, callable_len);
+    let args = slice::from_raw_parts(args, args_len);
+    let claimed_output = slice::from_raw_parts(claimed_output, claimed_output_len);
+    let address: ContractAddress = (*contract_address).into();
+    let claimed_delta = if claimed_index == 0 {
+        None
+    } else {
+        let data = slice::from_raw_parts(claimed_delta, claimed_delta_len).to_vec();
+        Some(EncryptedPatch::from_stored(data, address, claimed_index))
+    };
+
+    let task_key = slice::from_raw_parts(task_key, task_key_len);
+    let io_key = match get_io_key(replay_key).and_then(|replay_io_key| decrypt_task_key(task_key, &replay_io_key)) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+    let verdict = ecall_replay_compute_internal(
+        bytecode,
+        callable,
+        args,
+        user_key,
+        &io_key,
+        address,
+        task_id,
+        *gas_limit,
+        *gas_multiplier,
+        *capabilities,
+        settings_mac,
+        db_ptr,
+        &claimed_delta,
+        claimed_output,
+        claimed_gas,
+        stats,
+    );
+    let verdict = match verdict {
+        Ok(v) => v,
+        Err(e) => {
+            debug_println!("Error in replay of secret contract function: {}", e);
+            return e.into();
+        }
+    };
+    // Signing: S(taskId, inputsHash, exeCodeHash, gasLimit, claimedDeltaHash, claimedOutputHash, claimedGas, matches),
+    // the hashes are computed as in the signature of `ecall_execute`, so they're the ones the worker signed.
+    let message = ReplayVerdict {
+        task_id: *task_id,
+        inputs_hash: enigma_crypto::hash::prepare_hash_multiple(&[callable, args, &*address, user_key]).keccak256(),
+        exe_code_hash: bytecode.keccak256(),
+        gas_limit: *gas_limit,
+        claimed_delta_hash: get_enc_delta(&claimed_delta),
+        claimed_output_hash: claimed_output.keccak256(),
+        claimed_gas,
+        matches: verdict,
+    }.to_sign();
+    *sig = match SIGNING_KEY.sign(&SignatureDomain::Replay.tagged(&message)) {
+        Ok(v) => v,
+        Err(e) => return EnclaveError::from(e).into(),
+    };
+    *matches = verdict as u8;
+    EnclaveReturn::Success
+}
+
 #[no_mangle]
 /// Ecall for deploying contract.
 /// arguments:
@@ -138,8 +277,11 @@ pub unsafe extern "C" fn ecall_execute(
 /// * `args_len` - the length of `args`
 /// * `address` - the address of the contract to be deployed
 /// * `user_key` - the DH key of the user to decrypt `constructor` and `args`
+/// * `deployer` - the Ethereum address of the deployer, signed in the `DeployReceipt` (zeros if it isn't known)
 /// * `gas_limit` - the gas limit for the constructor execution
-/// * `result` - the result of the deployment
+/// * `gas_multiplier` - the price of the contract, its computations are given the same one
+/// * `capabilities` - the host functions the contract is allowed to import
+/// * `result` - the result of the deployment, including the MAC over `gas_multiplier` and `capabilities` its computations need
 pub unsafe extern "C" fn ecall_deploy(
     bytecode: *const u8,
     bytecode_len: usize,
@@ -149,7 +291,10 @@ pub unsafe extern "C" fn ecall_deploy(
     args_len: usize,
     address: &ContractAddress,
     user_key: &PubKey,
+    deployer: &[u8; 20],
     gas_limit: *const u64,
+    gas_multiplier: &GasMultiplier,
+    capabilities: &HostCapabilities,
     db_ptr: *const RawPointer,
     result: &mut ExecuteResult,
 ) -> EnclaveReturn
@@ -170,29 +315,87 @@ pub unsafe extern "C" fn ecall_deploy(
         args,
         (*address).into(),
         user_key,
+        *deployer,
         &io_key,
         *gas_limit,
+        *gas_multiplier,
+        *capabilities,
         db_ptr,
         result,
     );
     if let Err(e) = &internal_result {
         debug_println!("Error in deployment of secret contract function: {}", e);
-        internal_result = output_task_failure(&pre_execution_data, *gas_limit, e, result, &io_key);
+        internal_result = output_task_failure(&pre_execution_data, *gas_limit, e, result, &io_key, None);
     }
     internal_result.into()
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn ecall_ptt_req(sig: &mut [u8; 65], serialized_ptr: *mut u64) -> EnclaveReturn {
+/// Ecall for setting how many bytes of native stack a contract execution may use, see `enigma_runtime_t::native_stack`.
+pub extern "C" fn ecall_set_native_stack_budget(budget: usize) {
+    enigma_runtime_t::native_stack::set_budget(budget);
+}
+
+#[no_mangle]
+/// Ecall for setting the deepest nesting of arrays and objects a value in a contract's state may have, see `enigma_runtime_t::data::set_max_json_depth`.
+pub extern "C" fn ecall_set_max_json_depth(depth: usize) {
+    enigma_runtime_t::data::set_max_json_depth(depth);
+}
+
+#[no_mangle]
+/// Ecall for setting the largest serialized state a contract may have, see `enigma_runtime_t::data::set_max_state_size`.
+pub extern "C" fn ecall_set_max_state_size(size: usize) {
+    enigma_runtime_t::data::set_max_state_size(size);
+}
+
+#[no_mangle]
+/// Ecall for showing the fields of errors that may hold secret material, see `enigma_tools_t::common::errors_t::set_error_details`.
+pub extern "C" fn ecall_set_error_details(enabled: u8) {
+    enigma_tools_t::common::errors_t::set_error_details(enabled != 0);
+}
+
+#[no_mangle]
+/// Ecall for setting how many times reading the state keys tries to take their lock before failing, see `km_t::set_state_keys_lock_attempts`.
+pub extern "C" fn ecall_set_state_keys_lock_attempts(attempts: u32) {
+    km_t::set_state_keys_lock_attempts(attempts);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_ptt_req(sig: &mut [u8; 65], output: *mut u8, output_cap: usize, output_len: &mut u64, output_handle: &mut u64) -> EnclaveReturn {
     let msg = match ecall_ptt_req_internal(sig) {
         Ok(msg) => msg,
         Err(e) => return e.into(),
     };
-    *serialized_ptr = match ocalls_t::save_to_untrusted_memory(&msg[..]) {
-        Ok(ptr) => ptr,
+    output_t::write_output(msg, output, output_cap, output_len, output_handle).into()
+}
+
+#[no_mangle]
+/// Ecall for checking that `bytecode` would pass the module creation and instantiation of a deployment with
+/// `gas_multiplier` and `capabilities` without deploying it, see `WasmEngine::validate`.
+/// The output is the reason the bytecode was rejected, empty if it's valid.
+pub unsafe extern "C" fn ecall_validate_contract(
+    bytecode: *const u8,
+    bytecode_len: usize,
+    gas_multiplier: &GasMultiplier,
+    capabilities: &HostCapabilities,
+    output: *mut u8,
+    output_cap: usize,
+    output_len: &mut u64,
+    output_handle: &mut u64,
+) -> EnclaveReturn {
+    let bytecode = slice::from_raw_parts(bytecode, bytecode_len);
+    let reason = match WasmEngine::validate(bytecode, *gas_multiplier, *capabilities) {
+        Ok(()) => Vec::new(),
+        Err(FailedTaskError(e)) => format!("{}", e).into_bytes(),
         Err(e) => return e.into(),
     };
-    EnclaveReturn::Success
+    output_t::write_output(reason, output, output_cap, output_len, output_handle).into()
+}
+
+#[no_mangle]
+/// Ecall for fetching an output that didn't fit into the buffer of the ecall that produced it, see `output_t`.
+pub unsafe extern "C" fn ecall_fetch_output(handle: u64, output: *mut u8, output_len: usize) -> EnclaveReturn {
+    output_t::fetch_output(handle, output, output_len).into()
 }
 
 #[no_mangle]
@@ -202,30 +405,116 @@ pub unsafe extern "C" fn ecall_ptt_res(msg_ptr: *const u8, msg_len: usize) -> En
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn ecall_build_state(db_ptr: *const RawPointer, failed_ptr: *mut u64) -> EnclaveReturn {
-    let failed_contracts = match ecall_build_state_internal(db_ptr) {
-        Ok(c) => c,
+/// Ecall for verifying that the delta `key` of `address` was signed by a worker the KM node selected for the contract.
+pub unsafe extern "C" fn ecall_verify_delta(
+    address: &ContractAddress,
+    key: u32,
+    data: *const u8,
+    data_len: usize,
+    sig: &[u8; 65],
+) -> EnclaveReturn {
+    let data = slice::from_raw_parts(data, data_len);
+    ecall_verify_delta_internal(address, key, data, *sig).into()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_decode_delta(
+    address: &ContractAddress,
+    key: u32,
+    data: *const u8,
+    data_len: usize,
+    output: *mut u8,
+    output_cap: usize,
+    output_len: &mut u64,
+    output_handle: &mut u64,
+) -> EnclaveReturn {
+    // This reveals the contents of the delta, it's only meant for debugging contracts.
+    if !cfg!(debug_assertions) {
+        return EnclaveReturn::PermissionError;
+    }
+    let data = slice::from_raw_parts(data, data_len);
+    let decoded = match ecall_decode_delta_internal(*address, key, data) {
+        Ok(decoded) => decoded,
         Err(e) => return e.into(),
     };
-    let flatten = failed_contracts.iter().flat_map(|a| a.iter()).cloned().collect::<Vec<u8>>();
-    *failed_ptr = match ocalls_t::save_to_untrusted_memory(&flatten) {
-        Ok(ptr) => ptr,
+    output_t::write_output(decoded, output, output_cap, output_len, output_handle).into()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_get_state_at(
+    db_ptr: *const RawPointer,
+    address: &ContractAddress,
+    index: u32,
+    output: *mut u8,
+    output_cap: usize,
+    output_len: &mut u64,
+    output_handle: &mut u64,
+) -> EnclaveReturn {
+    // This reveals the state of the contract, it's only meant for debugging contracts.
+    if !cfg!(debug_assertions) {
+        return EnclaveReturn::PermissionError;
+    }
+    let state = match ecall_get_state_at_internal(db_ptr, *address, index) {
+        Ok(state) => state,
         Err(e) => return e.into(),
     };
+    output_t::write_output(state, output, output_cap, output_len, output_handle).into()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_state_fingerprint(
+    db_ptr: *const RawPointer,
+    address: &ContractAddress,
+    delta_hash: &mut [u8; 32],
+    delta_index: &mut u32,
+    fingerprint: &mut [u8; 32],
+) -> EnclaveReturn {
+    let (hash, index, state_fingerprint) = match ecall_state_fingerprint_internal(db_ptr, *address) {
+        Ok(res) => res,
+        Err(e) => return e.into(),
+    };
+    delta_hash.copy_from_slice(&hash[..]);
+    *delta_index = index;
+    fingerprint.copy_from_slice(&state_fingerprint[..]);
     EnclaveReturn::Success
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn ecall_get_user_key(sig: &mut [u8; 65], user_pubkey: &PubKey, serialized_ptr: *mut u64) -> EnclaveReturn {
-    let msg = match ecall_get_user_key_internal(sig, user_pubkey) {
-        Ok(msg) => msg,
+pub unsafe extern "C" fn ecall_build_state(
+    db_ptr: *const RawPointer,
+    max_deltas: u32,
+    cursor: &mut u32,
+    failed: *mut u8,
+    failed_cap: usize,
+    failed_len: &mut u64,
+    failed_handle: &mut u64,
+) -> EnclaveReturn {
+    let failed_contracts = match ecall_build_state_internal(db_ptr, max_deltas, *cursor) {
+        Ok((c, next)) => {
+            *cursor = next;
+            c
+        }
         Err(e) => return e.into(),
     };
-    *serialized_ptr = match ocalls_t::save_to_untrusted_memory(&msg[..]) {
-        Ok(ptr) => ptr,
+    let flatten = failed_contracts.iter().flat_map(|a| a.iter()).cloned().collect::<Vec<u8>>();
+    output_t::write_output(flatten, failed, failed_cap, failed_len, failed_handle).into()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_get_user_key(
+    sig: &mut [u8; 65],
+    user_pubkey: &PubKey,
+    sig_version: u32,
+    output: *mut u8,
+    output_cap: usize,
+    output_len: &mut u64,
+    output_handle: &mut u64,
+) -> EnclaveReturn {
+    let msg = match ecall_get_user_key_internal(sig, user_pubkey, sig_version) {
+        Ok(msg) => msg,
         Err(e) => return e.into(),
     };
-    EnclaveReturn::Success
+    output_t::write_output(msg, output, output_cap, output_len, output_handle).into()
 }
 
 fn get_io_key(user_key: &PubKey) -> Result<DhKey, EnclaveError> {
@@ -236,6 +525,17 @@ fn get_io_key(user_key: &PubKey) -> Result<DhKey, EnclaveError> {
     Ok(io_key)
 }
 
+/// The DH key of a task that is replayed, the one who asked for the replay sends it encrypted with a fresh key exchange.
+fn decrypt_task_key(task_key: &[u8], replay_io_key: &DhKey) -> Result<DhKey, EnclaveError> {
+    let decrypted = symmetric::decrypt(task_key, replay_io_key)?;
+    if decrypted.len() != 32 {
+        return Err(CryptoError::ImproperEncryption.into());
+    }
+    let mut io_key = DhKey::default();
+    io_key.copy_from_slice(&decrypted);
+    Ok(io_key)
+}
+
 fn decrypt_inputs(callable: &[u8], args: &[u8], inputs_key: &DhKey) -> Result<(Vec<u8>, String), EnclaveError> {
     let decrypted_callable = decrypt_callable(callable, &inputs_key)?;
     let decrypted_args = decrypt_args(&args, &inputs_key)?;
@@ -263,10 +563,8 @@ unsafe fn store_delta_and_state(
     match delta {
         Some(d) => {
             let enc_state = km_t::encrypt_state(state.clone())?;
-            enigma_runtime_t::ocalls_t::save_delta(db_ptr, d)?;
-            // if the state isn't able to be stored, then remove the delta as well and fail the task
-            enigma_runtime_t::ocalls_t::save_state(db_ptr, &enc_state).
-                or_else(|_| enigma_runtime_t::ocalls_t::remove_delta(db_ptr, d))
+            // both are written in a single ocall, so either both are stored or none of them.
+            enigma_runtime_t::ocalls_t::save_delta_and_state(db_ptr, d, &enc_state)
         }
         None => Ok(()),
     }
@@ -286,8 +584,11 @@ fn output_task_failure(
     err: &EnclaveError,
     result: &mut ExecuteResult,
     key: &DhKey,
+    task_id: Option<&[u8; 32]>,
 ) -> Result<(), EnclaveError>
 {
+    // The outputs of the execution (if it got that far) are replaced by the failure.
+    discard_outputs(result);
     // Signing: S(pre-execution data, usedGas, Failure)
     result.used_gas = 0;
     let return_error = match err {
@@ -307,9 +608,16 @@ fn output_task_failure(
     to_sign.push(&used_gas);
     to_sign.push(&failure);
     result.signature = SIGNING_KEY.sign_multiple(&to_sign)?;
-    let error_text = format!("{}", return_error);
+    // The text is encrypted to the user, so it has every detail of the error.
+    let error_text = format!("{:#}", return_error);
     let encrypted_result = symmetric::encrypt(error_text.as_bytes(), &key)?;
-    result.output = ocalls_t::save_to_untrusted_memory(&encrypted_result)? as *const u8;
+    if let Some(task_id) = task_id {
+        let message = compute_result_message(&encrypted_result, &Hash256::default(), result.used_gas, task_id, ResultStatus::Failure);
+        result.result_signature = SIGNING_KEY.sign(&SignatureDomain::Output.tagged(&message))?;
+    }
+    let (output_handle, output_len) = output_t::keep_output(encrypted_result)?;
+    result.output_handle = output_handle;
+    result.output_len = output_len;
     Err(return_error)
 }
 
@@ -321,13 +629,18 @@ unsafe fn ecall_execute_internal(
     user_key: &PubKey,
     io_key: &DhKey,
     address: ContractAddress,
+    task_id: &[u8; 32],
     gas_limit: u64,
+    gas_multiplier: GasMultiplier,
+    capabilities: HostCapabilities,
+    settings_mac: &[u8; 32],
     db_ptr: *const RawPointer,
     result: &mut ExecuteResult,
 ) -> Result<(), EnclaveError>
 {
     // TODO: make sure the state is up to date.
     // TODO: Should this be here or on the untrusted side via build_state?;
+    km_t::verify_settings(address, gas_multiplier, capabilities, settings_mac)?;
 
     let inputs_hash = enigma_crypto::hash::prepare_hash_multiple(&[callable, args, &*address, user_key]).keccak256();
     let exe_code_hash = bytecode.keccak256();
@@ -340,13 +653,16 @@ unsafe fn ecall_execute_internal(
 
     let state_key = km_t::get_state_key(address)?;
     let mut engine =
-        WasmEngine::new_compute(&bytecode, gas_limit, decrypted_args.clone(), pre_execution_state.clone(), function_name, state_key)?;
+        WasmEngine::new_compute(&bytecode, gas_limit, decrypted_args.clone(), pre_execution_state.clone(), function_name, state_key, task_id, capabilities, gas_multiplier)?;
+    engine.runtime.set_caller(user_key.address());
     engine.compute()?;
     let exec_res = engine.into_result()?;
 
     let delta_hash = get_enc_delta(&exec_res.state_delta);
     let encrypted_output = symmetric::encrypt(&exec_res.result, io_key)?;
     prepare_wasm_result(&exec_res.state_delta, &encrypted_output, exec_res.ethereum_bridge.clone(), exec_res.used_gas, result)?;
+    result.peak_memory_bytes = exec_res.peak_memory_bytes;
+    result.instructions = exec_res.instructions;
 
     let (ethereum_payload, ethereum_address) = create_eth_data_to_sign(exec_res.ethereum_bridge);
     // Signing: S(exeCodeHash, inputsHash, delta(X-1)Hash, deltaXHash, outputHash, gasLimit, usedGas, optionalEthereumData, Success)
@@ -365,10 +681,82 @@ unsafe fn ecall_execute_internal(
         &[ResultStatus::Ok as u8],
     ];
     result.signature = SIGNING_KEY.sign_multiple(to_sign)?;
+    // Signing: S(output, deltaXHash, usedGas, taskId, Success), so the whole result is bound to the task.
+    let message = compute_result_message(&encrypted_output, &delta_hash, result.used_gas, task_id, ResultStatus::Ok);
+    result.result_signature = SIGNING_KEY.sign(&SignatureDomain::Output.tagged(&message))?;
     store_delta_and_state(db_ptr, &exec_res.state_delta, &exec_res.updated_state)?;
     Ok(())
 }
 
+/// Executes the task again and returns whether it produced `claimed_delta`, `claimed_output` and `claimed_gas`.
+/// The deltas and the output are compared decrypted, because both are encrypted with a random IV.
+/// A task that fails on replay doesn't match, only a system error is returned as an error.
+unsafe fn ecall_replay_compute_internal(
+    bytecode: &[u8],
+    callable: &[u8],
+    args: &[u8],
+    user_key: &PubKey,
+    io_key: &DhKey,
+    address: ContractAddress,
+    task_id: &[u8; 32],
+    gas_limit: u64,
+    gas_multiplier: GasMultiplier,
+    capabilities: HostCapabilities,
+    settings_mac: &[u8; 32],
+    db_ptr: *const RawPointer,
+    claimed_delta: &Option<EncryptedPatch>,
+    claimed_output: &[u8],
+    claimed_gas: u64,
+    stats: &mut ReplayStats,
+) -> Result<bool, EnclaveError>
+{
+    km_t::verify_settings(address, gas_multiplier, capabilities, settings_mac)?;
+    let state_key = km_t::get_state_key(address)?;
+    // The task ran on the state right before its delta, or on the latest state if it didn't change it.
+    let pre_execution_state = match claimed_delta {
+        Some(delta) => km_t::deltas::state_at(db_ptr, address, delta.index - 1)?,
+        None => km_t::get_state(db_ptr, address)?,
+    };
+
+    let replay = || -> Result<RuntimeResult, EnclaveError> {
+        let (decrypted_args, function_name) =
+            decrypt_inputs(callable, args, io_key).map_err(|e| FailedTaskError(InputError { message: format!("{}", e) }))?;
+        let mut engine = WasmEngine::new_compute(bytecode, gas_limit, decrypted_args, pre_execution_state, function_name, state_key, task_id, capabilities, gas_multiplier)?;
+        engine.runtime.set_caller(user_key.address());
+        engine.compute()?;
+        engine.into_result()
+    };
+    let exec_res = match replay() {
+        Ok(res) => res,
+        Err(SystemError(e)) => return Err(SystemError(e)),
+        Err(e) => {
+            // Only a failure with the gas it used reports it, the others fail before executing the contract.
+            if let FailedTaskErrorWithGas { used_gas, .. } = e {
+                stats.used_gas = used_gas;
+            }
+            stats.failed = true;
+            return Ok(false);
+        }
+    };
+    stats.used_gas = exec_res.used_gas;
+    stats.instructions = exec_res.instructions;
+
+    let decrypt_delta = |delta: &Option<EncryptedPatch>| match delta {
+        Some(d) => StatePatch::decrypt(d.clone(), &state_key).map(Some),
+        None => Ok(None),
+    };
+    let deltas_match = match (decrypt_delta(claimed_delta), decrypt_delta(&exec_res.state_delta)?) {
+        (Ok(claimed), replayed) => claimed == replayed,
+        // A delta that doesn't decrypt with the state key can't be the one the task produced.
+        (Err(_), _) => false,
+    };
+    let outputs_match = match symmetric::decrypt(claimed_output, io_key) {
+        Ok(output) => output == exec_res.result,
+        Err(_) => false,
+    };
+    Ok(deltas_match && outputs_match && exec_res.used_gas == claimed_gas)
+}
+
 unsafe fn ecall_deploy_internal(
     pre_execution_data: &mut Vec<Box<[u8]>>,
     bytecode: &[u8],
@@ -376,8 +764,11 @@ unsafe fn ecall_deploy_internal(
     args: &[u8],
     address: ContractAddress,
     user_key: &PubKey,
+    deployer: [u8; 20],
     io_key: &DhKey,
     gas_limit: u64,
+    gas_multiplier: GasMultiplier,
+    capabilities: HostCapabilities,
     db_ptr: *const RawPointer,
     result: &mut ExecuteResult,
 ) -> Result<(), EnclaveError>
@@ -392,7 +783,8 @@ unsafe fn ecall_deploy_internal(
     let state = ContractState::new(address);
 
     let state_key = km_t::get_state_key(address)?;
-    let mut engine = WasmEngine::new_deploy(bytecode, gas_limit, decrypted_args.clone(), state, function_name, state_key)?;
+    let mut engine = WasmEngine::new_deploy(bytecode, gas_limit, decrypted_args.clone(), state, function_name, state_key, capabilities, gas_multiplier)?;
+    engine.runtime.set_caller(user_key.address());
     engine.deploy()?;
     let exec_res = engine.into_result()?;
 
@@ -401,6 +793,13 @@ unsafe fn ecall_deploy_internal(
     let delta_hash = get_enc_delta(&exec_res.state_delta);
 
     prepare_wasm_result(&exec_res.state_delta, exe_code, exec_res.ethereum_bridge.clone(), exec_res.used_gas, result)?;
+    let receipt = DeployReceipt { address, precode_hash: pre_code_hash, deployer, delta_hash, gas_multiplier, capabilities };
+    result.result_signature = SIGNING_KEY.sign(&SignatureDomain::DeployReceipt.tagged(&receipt.to_sign()))?;
+    result.settings_mac = km_t::settings_mac(address, gas_multiplier, capabilities)?;
+    // `used_gas` stays the sum of both parts so the signed value doesn't change.
+    result.storage_gas = exec_res.storage_gas;
+    result.constructor_gas = exec_res.used_gas.saturating_sub(exec_res.storage_gas);
+    result.instructions = exec_res.instructions;
 
     // Signing: S(inputsHash, exeCodeHash, delta0Hash, gasLimit, usedGas, optionalEthereumData, Success)
     let used_gas = result.used_gas.to_be_bytes();
@@ -420,7 +819,7 @@ unsafe fn ecall_deploy_internal(
     Ok(())
 }
 
-unsafe fn prepare_wasm_result(
+fn prepare_wasm_result(
     delta_option: &Option<EncryptedPatch>,
     execute_result: &[u8],
     ethereum_bridge: Option<EthereumData>,
@@ -428,44 +827,55 @@ unsafe fn prepare_wasm_result(
     result: &mut ExecuteResult,
 ) -> Result<(), EnclaveError>
 {
-    result.output = ocalls_t::save_to_untrusted_memory(&execute_result)? as *const u8;
     result.used_gas = used_gas;
     match delta_option {
         Some(enc_delta) => {
-            result.delta_ptr = ocalls_t::save_to_untrusted_memory(&enc_delta.data)? as *const u8;
+            let (delta_handle, delta_len) = output_t::keep_output(enc_delta.data.clone())?;
+            result.delta_handle = delta_handle;
+            result.delta_len = delta_len;
             result.delta_index = enc_delta.index;
         }
-        None => {
-            result.delta_ptr = ocalls_t::save_to_untrusted_memory(&[])? as *const u8;
-            result.delta_index = 0;
-        }
+        None => result.delta_index = 0,
     }
 
     match ethereum_bridge {
         Some(ethereum_bridge) => {
-            result.ethereum_payload_ptr = ocalls_t::save_to_untrusted_memory(&ethereum_bridge.ethereum_payload)? as *const u8;
+            let (payload_handle, payload_len) = output_t::keep_output(ethereum_bridge.ethereum_payload)?;
+            result.ethereum_payload_handle = payload_handle;
+            result.ethereum_payload_len = payload_len;
             result.ethereum_address.clone_from_slice(&ethereum_bridge.ethereum_contract_addr);
         }
-        None => {
-            result.ethereum_payload_ptr = ocalls_t::save_to_untrusted_memory(&[])? as *const u8;
-            result.ethereum_address = [0u8; 20];
-        }
+        None => result.ethereum_address = [0u8; 20],
     }
+    let (output_handle, output_len) = output_t::keep_output(execute_result.to_vec())?;
+    result.output_handle = output_handle;
+    result.output_len = output_len;
     Ok(())
 }
 
+/// Drops the outputs of `result` that are kept for fetching, for a task that failed after they were kept.
+fn discard_outputs(result: &mut ExecuteResult) {
+    output_t::discard_output(result.output_handle);
+    output_t::discard_output(result.delta_handle);
+    output_t::discard_output(result.ethereum_payload_handle);
+    result.output_handle = 0;
+    result.delta_handle = 0;
+    result.ethereum_payload_handle = 0;
+    result.output_len = 0;
+    result.delta_len = 0;
+    result.ethereum_payload_len = 0;
+}
+
 fn get_sealed_keys_wrapper() -> asymmetric::KeyPair {
     // Get Home path via Ocall
     let mut path_buf = ocalls_t::get_home_path().unwrap();
     // add the filename to the path: `keypair.sealed`
     path_buf.push("keypair.sealed");
-    let sealed_path = path_buf.to_str().unwrap();
 
-    // TODO: Decide what to do if failed to obtain keys.
-    match storage_t::get_sealed_keys(&sealed_path) {
-        Ok(key) => key,
-        Err(err) => panic!("Failed obtaining keys: {:?}", err),
-    }
+    // The signing address is registered, so the key must stay the same across restarts.
+    let keys = document_storage_t::get_or_create_sealed_keypair(&path_buf);
+    enclave_assert!(keys.is_ok(), "Failed obtaining keys: {:?}", keys.as_ref().err());
+    keys.unwrap()
 }
 
 fn get_ethereum_keys_wrapper() -> asymmetric::KeyPair {
@@ -476,10 +886,9 @@ fn get_ethereum_keys_wrapper() -> asymmetric::KeyPair {
     let sealed_path = path_buf.to_str().unwrap();
 
     // TODO: Decide what to do if failed to obtain keys.
-    match storage_t::get_sealed_keys(&sealed_path) {
-        Ok(key) => key,
-        Err(err) => panic!("Failed obtaining keys: {:?}", err),
-    }
+    let keys = storage_t::get_sealed_keys(&sealed_path);
+    enclave_assert!(keys.is_ok(), "Failed obtaining keys: {:?}", keys.as_ref().err());
+    keys.unwrap()
 }
 
 pub mod tests {
@@ -491,8 +900,16 @@ pub mod tests {
         extern crate sgx_tunittest;
 
         use self::sgx_tunittest::*;
+        use crate::km_t::deltas::tests::*;
         use crate::km_t::principal::tests::*;
-        use enigma_runtime_t::{data::tests::*, ocalls_t::tests::*, wasm_execution::tests::*};
+        use crate::km_t::signing::tests::*;
+        use crate::km_t::tests::*;
+        use crate::output_t::tests::*;
+        use enigma_runtime_t::{data::tests::*, native_stack::tests::*, ocalls_t::tests::*, test_vectors::tests::*, wasm_execution::tests::*, tests::*};
+        use enigma_tools_t::common::errors_t::tests::*;
+        use enigma_tools_t::esgx::assert_t::tests::*;
+        use enigma_tools_t::eth_events::tests::*;
+        use enigma_tools_t::document_storage_t::tests::*;
         use enigma_tools_t::storage_t::tests::*;
         use enigma_types::{RawPointer, ResultStatus};
         use std::{panic::UnwindSafe, string::String, vec::Vec};
@@ -504,24 +921,67 @@ pub mod tests {
 
             // The reason I had to make our own tests is because baidu's unittest lib supports only static functions that get no inputs.
             core_unitests(&mut ctr, &mut failures, test_full_sealing_storage, "test_full_sealing_storage");
+            core_unitests(&mut ctr, &mut failures, test_sealed_keypair_survives_restart, "test_sealed_keypair_survives_restart");
+            core_unitests(&mut ctr, &mut failures, test_redacted_display, "test_redacted_display");
             core_unitests(&mut ctr, &mut failures, test_encrypt_state, "test_encrypt_state");
             core_unitests(&mut ctr, &mut failures, test_decrypt_state, "test_decrypt_state");
             core_unitests(&mut ctr, &mut failures, test_encrypt_decrypt_state, "test_encrypt_decrypt_state");
             core_unitests(&mut ctr, &mut failures, test_write_state, "test_write_state");
             core_unitests(&mut ctr, &mut failures, test_read_state, "test_read_state");
+            core_unitests(&mut ctr, &mut failures, test_read_state_wrong_type, "test_read_state_wrong_type");
+            core_unitests(&mut ctr, &mut failures, test_native_stack_guards_deep_values, "test_native_stack_guards_deep_values");
+            core_unitests(&mut ctr, &mut failures, test_max_json_depth, "test_max_json_depth");
+            core_unitests(&mut ctr, &mut failures, test_max_state_size, "test_max_state_size");
+            core_unitests(&mut ctr, &mut failures, test_big_integers_state, "test_big_integers_state");
+            core_unitests(&mut ctr, &mut failures, test_export_import_state, "test_export_import_state");
+            core_unitests(&mut ctr, &mut failures, test_epoch_keys, "test_epoch_keys");
             core_unitests(&mut ctr, &mut failures, test_diff_patch, "test_diff_patch");
+            core_unitests(&mut ctr, &mut failures, test_export_delta, "test_export_delta");
             core_unitests(&mut ctr, &mut failures, test_encrypt_patch, "test_encrypt_patch");
             core_unitests(&mut ctr, &mut failures, test_decrypt_patch, "test_decrypt_patch");
+            core_unitests(&mut ctr, &mut failures, test_peek_encrypted_patch, "test_peek_encrypted_patch");
+            core_unitests(&mut ctr, &mut failures, test_state_vector_round_trip, "test_state_vector_round_trip");
+            core_unitests(&mut ctr, &mut failures, test_patch_vector_round_trip, "test_patch_vector_round_trip");
             core_unitests(&mut ctr, &mut failures, test_encrypt_decrypt_patch, "test_encrypt_decrypt_patch");
             core_unitests(&mut ctr, &mut failures, test_apply_delta, "test_apply_delta");
             core_unitests(&mut ctr, &mut failures, test_generate_delta, "test_generate_delta");
+            core_unitests(&mut ctr, &mut failures, test_generate_apply_delta_round_trip, "test_generate_apply_delta_round_trip");
+            core_unitests(&mut ctr, &mut failures, test_native_stack_budget, "test_native_stack_budget");
             core_unitests(&mut ctr, &mut failures, || test_me(db_ptr), "test_me");
+            core_unitests(&mut ctr, &mut failures, test_unresolved_import, "test_unresolved_import");
+            core_unitests(&mut ctr, &mut failures, test_memory_limit_exceeded, "test_memory_limit_exceeded");
+            core_unitests(&mut ctr, &mut failures, test_validate_internal_memory, "test_validate_internal_memory");
+            core_unitests(&mut ctr, &mut failures, test_validate_memory_limit_exceeded, "test_validate_memory_limit_exceeded");
             core_unitests(&mut ctr, &mut failures, test_execute_contract, "test_execute_contract");
+            core_unitests(&mut ctr, &mut failures, test_decode_revert_reason, "test_decode_revert_reason");
+            core_unitests(&mut ctr, &mut failures, test_charge_for_rand, "test_charge_for_rand");
+            core_unitests(&mut ctr, &mut failures, test_count_instructions, "test_count_instructions");
+            core_unitests(&mut ctr, &mut failures, test_seeded_rng, "test_seeded_rng");
+            core_unitests(&mut ctr, &mut failures, test_seed_randomness, "test_seed_randomness");
+            core_unitests(&mut ctr, &mut failures, test_get_caller, "test_get_caller");
+            core_unitests(&mut ctr, &mut failures, test_next_counter, "test_next_counter");
             core_unitests(&mut ctr, &mut failures, || test_get_deltas(db_ptr), "test_get_deltas");
             core_unitests(&mut ctr, &mut failures, || test_get_deltas_more(db_ptr), "test_get_deltas_more");
             core_unitests(&mut ctr, &mut failures, || test_state_internal(db_ptr), "test_state_internal");
+            core_unitests(&mut ctr, &mut failures, || test_build_state_bounded(db_ptr), "test_build_state_bounded");
+            core_unitests(&mut ctr, &mut failures, || test_get_state_without_state(db_ptr), "test_get_state_without_state");
+            core_unitests(&mut ctr, &mut failures, || test_get_state_with_deltas_without_state(db_ptr), "test_get_state_with_deltas_without_state");
+            core_unitests(&mut ctr, &mut failures, || test_get_state_wrong_key(db_ptr), "test_get_state_wrong_key");
+            core_unitests(&mut ctr, &mut failures, || test_import_state(db_ptr), "test_import_state");
+            core_unitests(&mut ctr, &mut failures, test_state_keys_lock_timeout, "test_state_keys_lock_timeout");
+            core_unitests(&mut ctr, &mut failures, test_settings_mac, "test_settings_mac");
             core_unitests(&mut ctr, &mut failures, || test_state(db_ptr), "test_state");
             core_unitests(&mut ctr, &mut failures, || {test_remove_delta(db_ptr)}, "test_remove_delta");
+            core_unitests(&mut ctr, &mut failures, test_derived_signing_keys, "test_derived_signing_keys");
+            core_unitests(&mut ctr, &mut failures, test_event_topic_erc20_transfer, "test_event_topic_erc20_transfer");
+            core_unitests(&mut ctr, &mut failures, test_verify_delta_signature, "test_verify_delta_signature");
+            core_unitests(&mut ctr, &mut failures, test_verify_delta_forged_signer, "test_verify_delta_forged_signer");
+            core_unitests(&mut ctr, &mut failures, test_decode_delta, "test_decode_delta");
+            core_unitests(&mut ctr, &mut failures, test_state_fingerprint, "test_state_fingerprint");
+            core_unitests(&mut ctr, &mut failures, test_canonical_json, "test_canonical_json");
+            core_unitests(&mut ctr, &mut failures, test_assert_failure_logs_location, "test_assert_failure_logs_location");
+            core_unitests(&mut ctr, &mut failures, test_output_two_phases, "test_output_two_phases");
+            core_unitests(&mut ctr, &mut failures, test_pending_outputs_limit, "test_pending_outputs_limit");
             let result = failures.is_empty();
             rsgx_unit_test_end(ctr, failures);
             result.into()
@@ -0,0 +1,121 @@
+//! # Ecall Outputs.
+//! The enclave's side of the two phase protocol of the ecalls with a variable length output (see `enigma_tools_u::esgx::output_u`).
+//! The output is written into the caller's buffer if it fits, otherwise it's kept here until `ecall_fetch_output` takes it.
+//! Deploy and compute have several outputs and no buffer, so all of them are kept and their handles are returned in `ExecuteResult`.
+
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveSystemError};
+use std::collections::BTreeMap;
+use std::string::ToString;
+use std::sync::SgxMutex;
+use std::{ptr, vec::Vec};
+
+/// The most outputs that are kept for fetching, an output that doesn't fit fails the ecall instead of dropping another one.
+const MAX_PENDING_OUTPUTS: usize = 64;
+
+#[derive(Default)]
+struct PendingOutputs {
+    last_handle: u64,
+    outputs: BTreeMap<u64, Vec<u8>>,
+}
+
+lazy_static! {
+    static ref PENDING_OUTPUTS: SgxMutex<PendingOutputs> = SgxMutex::new(PendingOutputs::default());
+}
+
+/// Writes `output` into `buf` if it fits in its `cap` bytes, otherwise keeps it for `fetch_output`.
+/// Either way `len` gets the length of the output, and `handle` gets what to fetch it with (0 if it fit).
+pub(crate) unsafe fn write_output(output: Vec<u8>, buf: *mut u8, cap: usize, len: &mut u64, handle: &mut u64) -> Result<(), EnclaveError> {
+    *len = output.len() as u64;
+    if output.len() <= cap {
+        if !output.is_empty() {
+            ptr::copy_nonoverlapping(output.as_ptr(), buf, output.len());
+        }
+        *handle = 0;
+        return Ok(());
+    }
+    *handle = keep_output(output)?.0;
+    Ok(())
+}
+
+/// Keeps `output` for `fetch_output` and returns its handle and its length, the handle is 0 if it's empty since there's nothing to fetch.
+/// Fails if too many outputs are already waiting to be fetched.
+pub(crate) fn keep_output(output: Vec<u8>) -> Result<(u64, u64), EnclaveError> {
+    let len = output.len() as u64;
+    if output.is_empty() {
+        return Ok((0, 0));
+    }
+    let mut pending = PENDING_OUTPUTS.lock_expect("Pending Outputs");
+    if pending.outputs.len() >= MAX_PENDING_OUTPUTS {
+        return Err(EnclaveError::SystemError(EnclaveSystemError::MessagingError { err: "Too many outputs are waiting to be fetched".to_string() }));
+    }
+    pending.last_handle += 1;
+    let id = pending.last_handle;
+    pending.outputs.insert(id, output);
+    Ok((id, len))
+}
+
+/// Drops the output kept under `handle`, for an ecall that kept an output and then failed.
+pub(crate) fn discard_output(handle: u64) {
+    if handle != 0 {
+        PENDING_OUTPUTS.lock_expect("Pending Outputs").outputs.remove(&handle);
+    }
+}
+
+/// Copies the output kept under `handle` into `buf`, `len` must be the length `write_output` reported.
+pub(crate) unsafe fn fetch_output(handle: u64, buf: *mut u8, len: usize) -> Result<(), EnclaveError> {
+    let mut pending = PENDING_OUTPUTS.lock_expect("Pending Outputs");
+    let output = pending.outputs.remove(&handle)
+        .ok_or_else(|| EnclaveError::SystemError(EnclaveSystemError::MessagingError { err: "There's no output with this handle".to_string() }))?;
+    if output.len() != len {
+        pending.outputs.insert(handle, output);
+        return Err(EnclaveError::SystemError(EnclaveSystemError::MessagingError { err: "The buffer isn't of the length of the output".to_string() }));
+    }
+    ptr::copy_nonoverlapping(output.as_ptr(), buf, len);
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::{discard_output, fetch_output, keep_output, write_output, MAX_PENDING_OUTPUTS};
+    use std::vec::Vec;
+
+    pub fn test_output_two_phases() {
+        let (mut len, mut handle) = (0u64, 0u64);
+        let mut buf = [0u8; 16];
+
+        // A short output is written right away.
+        unsafe { write_output(b"short".to_vec(), buf.as_mut_ptr(), buf.len(), &mut len, &mut handle) }.unwrap();
+        assert_eq!((len, handle), (5, 0));
+        assert_eq!(&buf[..5], b"short");
+
+        // A long one is kept until it's fetched into a buffer of its length.
+        let long: Vec<u8> = (0..100u8).collect();
+        unsafe { write_output(long.clone(), buf.as_mut_ptr(), buf.len(), &mut len, &mut handle) }.unwrap();
+        assert_eq!(len, 100);
+        assert_ne!(handle, 0);
+        let mut fetched = vec![0u8; 99];
+        assert!(unsafe { fetch_output(handle, fetched.as_mut_ptr(), fetched.len()) }.is_err());
+        let mut fetched = vec![0u8; len as usize];
+        unsafe { fetch_output(handle, fetched.as_mut_ptr(), fetched.len()) }.unwrap();
+        assert_eq!(fetched, long);
+        // It can only be fetched once.
+        assert!(unsafe { fetch_output(handle, fetched.as_mut_ptr(), fetched.len()) }.is_err());
+    }
+
+    pub fn test_pending_outputs_limit() {
+        assert_eq!(keep_output(Vec::new()).unwrap(), (0, 0));
+        let handles: Vec<u64> = (0..MAX_PENDING_OUTPUTS).map(|i| keep_output(vec![i as u8]).unwrap().0).collect();
+        // Once it's full a new output fails, and none of the kept ones is dropped for it.
+        assert!(keep_output(b"one more".to_vec()).is_err());
+        let mut fetched = [0u8; 1];
+        unsafe { fetch_output(handles[0], fetched.as_mut_ptr(), 1) }.unwrap();
+        assert_eq!(fetched, [0]);
+        let handle = keep_output(b"one more".to_vec()).unwrap().0;
+        for handle in handles.into_iter().skip(1).chain(Some(handle)) {
+            discard_output(handle);
+        }
+        let mut fetched = [0u8; 8];
+        assert!(unsafe { fetch_output(handle, fetched.as_mut_ptr(), fetched.len()) }.is_err());
+    }
+}
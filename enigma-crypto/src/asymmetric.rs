@@ -53,6 +53,35 @@ impl KeyPair {
         Ok(KeyPair { privkey, pubkey })
     }
 
+    /// Deterministically derive a `KeyPair` from a 32 bytes seed (e.g. a sealed one).
+    /// The private key is `Keccak256(seed)`, and if that isn't a valid secp256k1 key we keep hashing it until it is,
+    /// so unlike `KeyPair::from_slice()` this never fails. The same seed always results in the same keys.
+    pub fn from_seed(seed: &[u8; 32]) -> KeyPair {
+        let mut privkey = seed.keccak256();
+        loop {
+            if let Ok(keys) = KeyPair::from_slice(&privkey) {
+                return keys;
+            }
+            privkey = privkey.keccak256();
+        }
+    }
+
+    /// Derive a child `KeyPair` from this one that is bound to `path`.
+    /// The child private key is `Keccak256(len(privkey)+privkey, len(path)+path)`,
+    /// and if that isn't a valid secp256k1 key we keep hashing it until it is.
+    ///
+    /// Different paths result in unrelated keys, so a signature made under one path can't be used under another one.
+    #[cfg(any(feature = "sgx", feature = "std"))]
+    pub fn derive_child(&self, path: &[u8]) -> Result<KeyPair, CryptoError> {
+        let mut seed = crate::hash::prepare_hash_multiple(&[&self.get_privkey()[..], path]).keccak256();
+        loop {
+            if let Ok(child) = KeyPair::from_slice(&seed) {
+                return Ok(child);
+            }
+            seed = seed.keccak256();
+        }
+    }
+
     /// This function does an ECDH(point multiplication) between one's private key and the other one's public key.
     ///
     pub fn derive_key(&self, _pubarr: &PubKey) -> Result<DhKey, CryptoError> {
@@ -140,8 +169,9 @@ impl KeyPair {
     /// let recovered_pubkey = KeyPair::recover(msg, sig).unwrap();
     /// ```
     pub fn recover(message: &[u8], sig: [u8;65]) -> Result<[u8; 64], CryptoError> {
-        let recovery = RecoveryId::parse(sig[64] -27)
-            .map_err(|_| CryptoError::ParsingError { sig })?;
+        let recovery = sig[64].checked_sub(27)
+            .and_then(|v| RecoveryId::parse(v).ok())
+            .ok_or(CryptoError::ParsingError { sig })?;
         let signature = Signature::parse_slice(&sig[..64])
             .map_err(|_| CryptoError::ParsingError { sig } )?;
         let hashed_msg = message.keccak256();
@@ -192,9 +222,48 @@ mod tests {
         let _priv: [u8; 32] = [205, 189, 133, 79, 16, 70, 59, 246, 123, 227, 66, 64, 244, 188, 188, 147, 233, 252, 213, 133, 44, 157, 173, 141, 50, 93, 40, 130, 44, 99, 43, 205];
         let k1 = KeyPair::new().unwrap();
         let msg = b"EnigmaMPC";
-        let sig = k1.sign(msg).unwrap();
+        let mut sig = k1.sign(msg).unwrap();
         let recover_pub = KeyPair::recover(msg, sig).unwrap();
         assert_eq!(&k1.get_pubkey()[..], &recover_pub[..]);
+        // A recovery byte below 27 is rejected instead of underflowing.
+        sig[64] = 0;
+        assert!(KeyPair::recover(msg, sig).is_err());
+    }
+
+    #[test]
+    fn test_derive_child() {
+        let _priv: [u8; 32] = [205, 189, 133, 79, 16, 70, 59, 246, 123, 227, 66, 64, 244, 188, 188, 147, 233, 252, 213, 133, 44, 157, 173, 141, 50, 93, 40, 130, 44, 99, 43, 205];
+        let master = KeyPair::from_slice(&_priv).unwrap();
+        let child_a = master.derive_child(b"first").unwrap();
+        let child_b = master.derive_child(b"second").unwrap();
+        assert_ne!(&child_a.get_pubkey()[..], &child_b.get_pubkey()[..]);
+        assert_ne!(&child_a.get_pubkey()[..], &master.get_pubkey()[..]);
+        // The derivation is deterministic.
+        assert_eq!(&child_a.get_pubkey()[..], &master.derive_child(b"first").unwrap().get_pubkey()[..]);
+    }
+
+    #[test]
+    fn test_from_seed() {
+        let seed: [u8; 32] = [205, 189, 133, 79, 16, 70, 59, 246, 123, 227, 66, 64, 244, 188, 188, 147, 233, 252, 213, 133, 44, 157, 173, 141, 50, 93, 40, 130, 44, 99, 43, 205];
+        let k1 = KeyPair::from_seed(&seed);
+        let k2 = KeyPair::from_seed(&seed);
+        assert_eq!(&k1.get_privkey()[..], &k2.get_privkey()[..]);
+        assert_eq!(&k1.get_pubkey()[..], &k2.get_pubkey()[..]);
+        // The seed is hashed, so it isn't used as the private key itself.
+        assert_ne!(&k1.get_privkey()[..], &seed[..]);
+        assert_ne!(&k1.get_pubkey()[..], &KeyPair::from_seed(&[1u8; 32]).get_pubkey()[..]);
+    }
+
+    #[test]
+    fn test_from_seed_valid_keys() {
+        // A zeroed array isn't a valid private key, but it's still a valid seed.
+        for seed in &[[0u8; 32], [0xffu8; 32], [7u8; 32]] {
+            let keys = KeyPair::from_seed(seed);
+            let msg = b"EnigmaMPC";
+            let sig = keys.sign(msg).unwrap();
+            assert_eq!(&KeyPair::recover(msg, sig).unwrap()[..], &keys.get_pubkey()[..]);
+            assert_eq!(&KeyPair::from_slice(&keys.get_privkey()).unwrap().get_pubkey()[..], &keys.get_pubkey()[..]);
+        }
     }
 
     #[test]
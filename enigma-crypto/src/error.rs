@@ -43,6 +43,10 @@ pub enum CryptoError {
     ///
     /// This error means that the public key can't be recovered from that message & signature.
     RecoveryError { sig: [u8; 65] },
+    /// The `VerificationError` error.
+    ///
+    /// This error means that the signature doesn't match the message and the public key.
+    VerificationError,
     /// The `KeyError` error.
     ///
     /// This error means that a key wasn't vaild.
@@ -73,6 +77,7 @@ impl fmt::Display for CryptoError {
             SigningError { hashed_msg } => write!(f, "Signing the message failed, msg hash: {:?}", hashed_msg),
             ParsingError { sig } => write!(f, "Parsing the signature failed, sig: {:?}", &sig[..]),
             RecoveryError { sig } => write!(f, "Recovering the pubkey failed using the sig: {:?}", &sig[..]),
+            VerificationError => write!(f, "The signature doesn't match the message and the public key"),
             #[cfg(any(feature = "std", feature = "sgx"))]
             RandomError{ err } => write!(f, "Failed Generating a random. Error: {:?}", err),
         }
@@ -127,6 +132,10 @@ impl fmt::Debug for CryptoError {
                 debug_builder.field("self_key", &&sig[..]);
                 debug_builder.finish()
             },
+            VerificationError => {
+                let mut debug_builder = f.debug_tuple("VerificationError");
+                debug_builder.finish()
+            },
             #[cfg(any(feature = "std", feature = "sgx"))]
             RandomError{ ref err } => {
                 let mut debug_builder = f.debug_struct("RandomError");
@@ -21,6 +21,9 @@ pub mod rand;
 #[cfg(feature = "symmetric")]
 pub mod symmetric;
 
+#[cfg(all(feature = "asymmetric", any(feature = "std", feature = "sgx")))]
+pub mod signature;
+
 #[cfg(feature = "sgx")]
 use {
     sgx_tstd as localstd,
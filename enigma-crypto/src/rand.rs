@@ -21,4 +21,134 @@ pub fn random(rand: &mut [u8]) -> Result<(), crate::CryptoError> {
     use sgx_trts::trts::rsgx_read_rand;
     rsgx_read_rand(rand)
         .map_err(|e| crate::CryptoError::RandomError { err: e } )
-}
\ No newline at end of file
+}
+
+/// A source of random bytes, so the code that needs randomness can be handed a deterministic one in tests.
+pub trait Rng {
+    /// Fills `dest` with random bytes.
+    fn fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), crate::CryptoError>;
+}
+
+#[cfg(any(feature = "std", feature = "sgx"))]
+/// The randomness source of production code, it fills through [`random`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EntropyRng;
+
+#[cfg(any(feature = "std", feature = "sgx"))]
+impl Rng for EntropyRng {
+    fn fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), crate::CryptoError> { random(dest) }
+}
+
+#[cfg(all(test, any(feature = "std", feature = "sgx")))]
+/// A source that fails every time, like a broken entropy source would.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FailingRng;
+
+#[cfg(all(test, feature = "std", not(feature = "sgx")))]
+impl Rng for FailingRng {
+    fn fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), crate::CryptoError> {
+        let err = rand_std::Error::new(rand_std::ErrorKind::Unavailable, "FailingRng");
+        Err(crate::CryptoError::RandomError { err })
+    }
+}
+
+#[cfg(all(test, feature = "sgx", not(feature = "std")))]
+impl Rng for FailingRng {
+    fn fill_bytes(&mut self, _dest: &mut [u8]) -> Result<(), crate::CryptoError> {
+        Err(crate::CryptoError::RandomError { err: sgx_types::sgx_status_t::SGX_ERROR_UNEXPECTED })
+    }
+}
+
+/// A deterministic stream (SplitMix64) derived from a seed, the same seed always produces the same bytes.
+/// **It's not secure, and is only meant for tests.**
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeededRng {
+    state: u64,
+}
+
+impl SeededRng {
+    /// Creates a stream that starts from `seed`.
+    pub fn new(seed: u64) -> Self { SeededRng { state: seed } }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl Rng for SeededRng {
+    fn fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), crate::CryptoError> {
+        for chunk in dest.chunks_mut(8) {
+            let block = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "std", feature = "sgx"))]
+/// Either of the sources above, for holders that need to be `Clone` and `Debug` (which a `Box<dyn Rng>` isn't).
+#[derive(Debug, Clone, Copy)]
+pub enum RngSource {
+    /// Real entropy, see [`EntropyRng`].
+    Entropy(EntropyRng),
+    /// A deterministic stream for tests, see [`SeededRng`].
+    Seeded(SeededRng),
+    /// A source that always fails, see [`FailingRng`].
+    #[cfg(test)]
+    Failing(FailingRng),
+}
+
+#[cfg(any(feature = "std", feature = "sgx"))]
+impl Default for RngSource {
+    fn default() -> Self { RngSource::Entropy(EntropyRng) }
+}
+
+#[cfg(any(feature = "std", feature = "sgx"))]
+impl Rng for RngSource {
+    fn fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), crate::CryptoError> {
+        match self {
+            RngSource::Entropy(rng) => rng.fill_bytes(dest),
+            RngSource::Seeded(rng) => rng.fill_bytes(dest),
+            #[cfg(test)]
+            RngSource::Failing(rng) => rng.fill_bytes(dest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FailingRng, Rng, RngSource, SeededRng};
+
+    #[test]
+    fn test_seeded_rng_is_deterministic() {
+        let mut a = [0u8; 20];
+        let mut b = [0u8; 20];
+        SeededRng::new(7).fill_bytes(&mut a).unwrap();
+        SeededRng::new(7).fill_bytes(&mut b).unwrap();
+        assert_eq!(a, b);
+        SeededRng::new(8).fill_bytes(&mut b).unwrap();
+        assert_ne!(a, b);
+
+        // The stream continues across calls.
+        let mut rng = SeededRng::new(7);
+        let mut first = [0u8; 8];
+        let mut second = [0u8; 8];
+        rng.fill_bytes(&mut first).unwrap();
+        rng.fill_bytes(&mut second).unwrap();
+        assert_eq!(first[..], a[..8]);
+        assert_eq!(second[..], a[8..16]);
+    }
+
+    #[test]
+    fn test_failing_rng() {
+        let mut buf = [0u8; 8];
+        match RngSource::Failing(FailingRng).fill_bytes(&mut buf) {
+            Err(crate::CryptoError::RandomError { .. }) => (),
+            other => panic!("Expected a RandomError, got: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! # Signature Schemes.
+//! This module lets a signer choose the scheme it signs with. <br>
+//! By default it's secp256k1 ECDSA like [`crate::KeyPair`] (which is what Ethereum verifies),
+//! and Ed25519 is available for systems that expect EdDSA. <br>
+//! The scheme is recorded in the [`SchemePublicKey`] so the verifier knows how to check the signatures.
+
+use crate::asymmetric::KeyPair;
+use crate::error::CryptoError;
+use crate::localstd::vec::Vec;
+use ring::signature::{self, Ed25519KeyPair, KeyPair as _};
+
+/// The signature schemes a [`SigningKey`] can sign with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// ECDSA over secp256k1, the signature is 65 bytes `r || s || v` (see [`KeyPair::sign`]).
+    Secp256k1,
+    /// EdDSA over Curve25519, the signature is 64 bytes.
+    Ed25519,
+}
+
+impl Default for SignatureScheme {
+    fn default() -> Self { SignatureScheme::Secp256k1 }
+}
+
+/// A public key together with the scheme of the signatures it verifies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemePublicKey {
+    /// The scheme of the key.
+    pub scheme: SignatureScheme,
+    /// The key itself, 64 bytes for secp256k1 and 32 bytes for Ed25519.
+    pub key: Vec<u8>,
+}
+
+enum Signer {
+    Secp256k1(KeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+/// A private key of one of the [`SignatureScheme`]s.
+pub struct SigningKey {
+    signer: Signer,
+}
+
+impl SigningKey {
+    /// Generates a fresh key for `scheme` using the randomness from [`crate::rand`].
+    pub fn new(scheme: SignatureScheme) -> Result<SigningKey, CryptoError> {
+        match scheme {
+            SignatureScheme::Secp256k1 => Ok(SigningKey { signer: Signer::Secp256k1(KeyPair::new()?) }),
+            SignatureScheme::Ed25519 => {
+                let mut seed = [0u8; 32];
+                crate::rand::random(&mut seed)?;
+                Self::from_seed(scheme, &seed)
+            }
+        }
+    }
+
+    /// Deterministically derives a key for `scheme` from a 32 bytes seed, the same seed always results in the same key.
+    pub fn from_seed(scheme: SignatureScheme, seed: &[u8; 32]) -> Result<SigningKey, CryptoError> {
+        let signer = match scheme {
+            SignatureScheme::Secp256k1 => Signer::Secp256k1(KeyPair::from_seed(seed)),
+            SignatureScheme::Ed25519 => {
+                let keys = Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(&seed[..]))
+                    .map_err(|_| CryptoError::KeyError { key_type: "Ed25519 Private Key", err: None })?;
+                Signer::Ed25519(keys)
+            }
+        };
+        Ok(SigningKey { signer })
+    }
+
+    /// The scheme this key signs with.
+    pub fn scheme(&self) -> SignatureScheme {
+        match self.signer {
+            Signer::Secp256k1(_) => SignatureScheme::Secp256k1,
+            Signer::Ed25519(_) => SignatureScheme::Ed25519,
+        }
+    }
+
+    /// The public key that verifies this key's signatures, tagged with the scheme.
+    pub fn public_key(&self) -> SchemePublicKey {
+        let key = match &self.signer {
+            Signer::Secp256k1(keys) => keys.get_pubkey().to_vec(),
+            Signer::Ed25519(keys) => keys.public_key().as_ref().to_vec(),
+        };
+        SchemePublicKey { scheme: self.scheme(), key }
+    }
+
+    /// Signs `message`, with secp256k1 the message is hashed with keccak256 first like in [`KeyPair::sign`].
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        match &self.signer {
+            Signer::Secp256k1(keys) => Ok(keys.sign(message)?.to_vec()),
+            Signer::Ed25519(keys) => Ok(keys.sign(message).as_ref().to_vec()),
+        }
+    }
+}
+
+/// Verifies that `sig` is a signature over `message` by `public_key`, using the scheme of the public key.
+pub fn verify(public_key: &SchemePublicKey, message: &[u8], sig: &[u8]) -> Result<(), CryptoError> {
+    match public_key.scheme {
+        SignatureScheme::Secp256k1 => {
+            // The recovery byte is 27 or 28 (see `KeyPair::sign`).
+            if sig.len() != 65 || (sig[64] != 27 && sig[64] != 28) {
+                return Err(CryptoError::VerificationError);
+            }
+            let mut sig_arr = [0u8; 65];
+            sig_arr.copy_from_slice(sig);
+            let recovered = KeyPair::recover(message, sig_arr)?;
+            if recovered[..] != public_key.key[..] {
+                return Err(CryptoError::VerificationError);
+            }
+            Ok(())
+        }
+        SignatureScheme::Ed25519 => signature::verify(
+            &signature::ED25519,
+            untrusted::Input::from(&public_key.key),
+            untrusted::Input::from(message),
+            untrusted::Input::from(sig),
+        )
+        .map_err(|_| CryptoError::VerificationError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let msg = b"EnigmaMPC";
+        for &scheme in &[SignatureScheme::Secp256k1, SignatureScheme::Ed25519] {
+            let keys = SigningKey::new(scheme).unwrap();
+            assert_eq!(keys.scheme(), scheme);
+            let sig = keys.sign(msg).unwrap();
+            verify(&keys.public_key(), msg, &sig).unwrap();
+            assert!(verify(&keys.public_key(), b"Other message", &sig).is_err());
+        }
+        assert_eq!(SignatureScheme::default(), SignatureScheme::Secp256k1);
+    }
+
+    #[test]
+    fn test_from_seed() {
+        let seed = [7u8; 32];
+        for &scheme in &[SignatureScheme::Secp256k1, SignatureScheme::Ed25519] {
+            let first = SigningKey::from_seed(scheme, &seed).unwrap();
+            assert_eq!(first.public_key(), SigningKey::from_seed(scheme, &seed).unwrap().public_key());
+        }
+        assert_eq!(SigningKey::from_seed(SignatureScheme::Secp256k1, &seed).unwrap().public_key().key.len(), 64);
+        assert_eq!(SigningKey::from_seed(SignatureScheme::Ed25519, &seed).unwrap().public_key().key.len(), 32);
+    }
+
+    #[test]
+    fn test_cross_scheme_verification_fails() {
+        let msg = b"EnigmaMPC";
+        let secp = SigningKey::from_seed(SignatureScheme::Secp256k1, &[1u8; 32]).unwrap();
+        let ed = SigningKey::from_seed(SignatureScheme::Ed25519, &[1u8; 32]).unwrap();
+        let secp_sig = secp.sign(msg).unwrap();
+        let ed_sig = ed.sign(msg).unwrap();
+
+        assert!(verify(&secp.public_key(), msg, &ed_sig).is_err());
+        assert!(verify(&ed.public_key(), msg, &secp_sig).is_err());
+        // Relabeling a key with the other scheme doesn't make the signatures verify either.
+        let relabeled = SchemePublicKey { scheme: SignatureScheme::Ed25519, key: secp.public_key().key };
+        assert!(verify(&relabeled, msg, &secp_sig).is_err());
+        let relabeled = SchemePublicKey { scheme: SignatureScheme::Secp256k1, key: ed.public_key().key };
+        assert!(verify(&relabeled, msg, &ed_sig).is_err());
+    }
+
+    #[test]
+    fn test_verify_recovery_byte_out_of_range() {
+        let msg = b"EnigmaMPC";
+        let keys = SigningKey::new(SignatureScheme::Secp256k1).unwrap();
+        let mut sig = keys.sign(msg).unwrap();
+        for &v in &[0u8, 1, 26, 29, 255] {
+            sig[64] = v;
+            assert!(verify(&keys.public_key(), msg, &sig).is_err());
+        }
+    }
+}
@@ -9,11 +9,13 @@
 use enigma_types::SymmetricKey;
 use crate::error::CryptoError;
 use ring::aead::{self, Nonce, Aad};
+use ring::{digest, hkdf, hmac};
 use crate::localstd::borrow::ToOwned;
+use crate::localstd::collections::btree_map::{BTreeMap, Entry};
 use crate::localstd::option::Option;
 use crate::localstd::vec::Vec;
 use crate::localstd::vec;
-use crate::rand;
+use crate::rand::{EntropyRng, Rng};
 
 static AES_MODE: &aead::Algorithm = &aead::AES_256_GCM;
 
@@ -26,6 +28,84 @@ type IV = [u8; IV_SIZE];
 /// the IV/nonce is appended to the cipher text after the MAC tag.
 pub fn encrypt(message: &[u8], key: &SymmetricKey) -> Result<Vec<u8>, CryptoError> { encrypt_with_nonce(message, key, None) }
 
+/// The same as [`self::encrypt`] but the IV is taken from `rng`, this lets tests produce known cipher texts.
+pub fn encrypt_with_rng<R: Rng + ?Sized>(message: &[u8], key: &SymmetricKey, rng: &mut R) -> Result<Vec<u8>, CryptoError> {
+    let mut iv = [0u8; IV_SIZE];
+    rng.fill_bytes(&mut iv)?;
+    encrypt_with_nonce(message, key, Some(iv))
+}
+
+/// Counts the IVs used under a single key, so an IV never repeats under that key.
+/// Random IVs may repeat once a key encrypted around 2^32 messages (the birthday bound of a 96 bit IV),
+/// a counter doesn't, but only as long as its count is never reset. A count the host stores (even sealed) can be rolled back
+/// to an older copy, so the counter is only for keys that don't outlive it, like the key of a single session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NonceCounter {
+    next: u64,
+}
+
+impl NonceCounter {
+    /// A counter whose next IV is the `next`th one, for restoring a counter that was persisted with [`Self::count`].
+    pub fn starting_at(next: u64) -> Self { NonceCounter { next } }
+
+    /// How many IVs were taken from the counter, this is what has to be persisted.
+    pub fn count(&self) -> u64 { self.next }
+
+    /// Takes the next IV, it's the count in big endian (padded with zeros on the left).
+    pub fn next_iv(&mut self) -> Result<IV, CryptoError> {
+        let mut iv = [0u8; IV_SIZE];
+        iv[IV_SIZE - 8..].copy_from_slice(&self.next.to_be_bytes());
+        self.next = self.next.checked_add(1).ok_or(CryptoError::EncryptionError)?;
+        Ok(iv)
+    }
+}
+
+/// How the IV of an encryption is picked.
+#[derive(Debug)]
+pub enum NonceMode<'a> {
+    /// A random IV, like [`self::encrypt`].
+    Random,
+    /// The next IV of a counter that belongs to the key.
+    Counter(&'a mut NonceCounter),
+}
+
+/// The same as [`self::encrypt`] but with a choice of how the IV is picked.
+pub fn encrypt_with_mode(message: &[u8], key: &SymmetricKey, mode: NonceMode) -> Result<Vec<u8>, CryptoError> {
+    match mode {
+        NonceMode::Random => encrypt(message, key),
+        NonceMode::Counter(counter) => encrypt_with_nonce(message, key, Some(counter.next_iv()?)),
+    }
+}
+
+/// Encrypts so that the same message under the same key always results in the same cipher text, useful for deduplication
+/// (unlike a random IV this reveals when two cipher texts hold the same message). <br>
+/// Every distinct message takes the next IV of a [`NonceCounter`], and the IVs already taken are remembered by the HMAC of their message,
+/// so an IV is never used for two different messages. Like the counter, this only holds as long as the cipher lives,
+/// so it's only for keys that don't outlive it.
+pub struct DeterministicCipher {
+    key: SymmetricKey,
+    mac_key: SymmetricKey,
+    counter: NonceCounter,
+    ivs: BTreeMap<[u8; 32], IV>,
+}
+
+impl DeterministicCipher {
+    pub fn new(key: SymmetricKey) -> Self {
+        let mac_key = derive_key(&key, &[], b"deterministic iv");
+        DeterministicCipher { key, mac_key, counter: NonceCounter::default(), ivs: BTreeMap::new() }
+    }
+
+    /// Encrypts `message` with the IV it got the first time, or the next IV of the counter if it's new.
+    /// The cipher text is decrypted with [`self::decrypt`] like any other.
+    pub fn encrypt_deterministic(&mut self, message: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let iv = match self.ivs.entry(mac(&self.mac_key, message)) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => *entry.insert(self.counter.next_iv()?),
+        };
+        encrypt_with_nonce(message, &self.key, Some(iv))
+    }
+}
+
 //#[deprecated(note = "This function shouldn't be called directly unless you're implementing the Encryption trait, please use `encrypt()` instead")]
 /// This function does the same as [`self::encrypt`] but accepts an IV.
 /// it *shouldn't* be called directly. only from tests or [`crate::Encryption::encrypt_with_nonce`] implementations.
@@ -34,7 +114,7 @@ pub fn encrypt_with_nonce(message: &[u8], key: &SymmetricKey, _iv: Option<IV>) -
         Some(x) => x,
         None => {
             let mut _tmp_iv = [0; 12];
-            rand::random(&mut _tmp_iv)?;
+            EntropyRng.fill_bytes(&mut _tmp_iv)?;
             _tmp_iv
         }
     };
@@ -59,19 +139,52 @@ pub fn encrypt_with_nonce(message: &[u8], key: &SymmetricKey, _iv: Option<IV>) -
 /// Because it will try to get the IV from the last 12 bytes in the cipher text,
 /// then ring will take the last 16 bytes as a MAC to check the integrity of the cipher text.
 pub fn decrypt(cipheriv: &[u8], key: &SymmetricKey) -> Result<Vec<u8>, CryptoError> {
-    if cipheriv.len() < IV_SIZE {
+    let mut buf = cipheriv.to_owned();
+    decrypt_in_place(&mut buf, key)?;
+    Ok(buf)
+}
+
+/// The same as [`self::decrypt`] but decrypts the caller's buffer instead of copying it,
+/// on success `buf` is truncated to the plain text, on failure its content is unspecified.
+pub fn decrypt_in_place(buf: &mut Vec<u8>, key: &SymmetricKey) -> Result<(), CryptoError> {
+    if buf.len() < IV_SIZE {
         return Err(CryptoError::ImproperEncryption);
     }
     let aes_decrypt = aead::OpeningKey::new(&AES_MODE, key)
         .map_err(|_| CryptoError::KeyError { key_type: "Decryption", err: None })?;
 
-    let (ciphertext, iv) = cipheriv.split_at(cipheriv.len()-12);
-    let nonce = aead::Nonce::try_assume_unique_for_key(&iv).unwrap(); // This Cannot fail because split_at promises that iv.len()==12
-    let mut ciphertext = ciphertext.to_owned();
-    let decrypted_data = aead::open_in_place(&aes_decrypt, nonce, Aad::empty(), 0, &mut ciphertext);
-    let decrypted_data = decrypted_data.map_err(|_| CryptoError::DecryptionError)?;
+    let mut iv: IV = [0u8; IV_SIZE];
+    iv.copy_from_slice(&buf[buf.len()-IV_SIZE..]);
+    let nonce = aead::Nonce::assume_unique_for_key(iv);
+    let ciphertext_len = buf.len() - IV_SIZE;
+    buf.truncate(ciphertext_len);
+    let plaintext_len = aead::open_in_place(&aes_decrypt, nonce, Aad::empty(), 0, buf)
+        .map_err(|_| CryptoError::DecryptionError)?
+        .len();
+    buf.truncate(plaintext_len);
+    Ok(())
+}
+
+/// Derive a key from the input keying material `ikm` with HKDF-SHA256,
+/// keys derived with different `salt` or `info` are unrelated to each other and to `ikm`.
+pub fn derive_key(ikm: &[u8], salt: &[u8], info: &[u8]) -> SymmetricKey {
+    let salt = hmac::SigningKey::new(&digest::SHA256, salt);
+    let mut key: SymmetricKey = [0u8; 32];
+    hkdf::extract_and_expand(&salt, ikm, info, &mut key);
+    key
+}
 
-    Ok(decrypted_data.to_vec())
+/// HMAC-SHA256 of `message` under `key`.
+pub fn mac(key: &SymmetricKey, message: &[u8]) -> [u8; 32] {
+    let tag = hmac::sign(&hmac::SigningKey::new(&digest::SHA256, key), message);
+    let mut res = [0u8; 32];
+    res.copy_from_slice(tag.as_ref());
+    res
+}
+
+/// Checks in constant time that `tag` is the [`self::mac`] of `message` under `key`.
+pub fn verify_mac(key: &SymmetricKey, message: &[u8], tag: &[u8]) -> Result<(), CryptoError> {
+    hmac::verify_with_own_key(&hmac::SigningKey::new(&digest::SHA256, key), message, tag).map_err(|_| CryptoError::VerificationError)
 }
 
 #[cfg(test)]
@@ -79,7 +192,7 @@ mod tests {
     use crate::rand;
     use rustc_hex::{ToHex, FromHex};
     use crate::hash::Sha256;
-    use super::{decrypt, encrypt_with_nonce};
+    use super::{decrypt, decrypt_in_place, encrypt, derive_key, encrypt_with_nonce, encrypt_with_rng, encrypt_with_mode, mac, verify_mac, DeterministicCipher, NonceCounter, NonceMode};
 
     #[test]
     fn test_rand_encrypt_decrypt() {
@@ -119,4 +232,108 @@ mod tests {
 //        let enc = encrypt_with_nonce(&msg, &key, Some(iv)).unwrap();
 
     }
+
+    #[test]
+    fn test_decrypt_in_place() {
+        let key = b"EnigmaMPC".sha256();
+        let mut msg = [0u8; 4096];
+        rand::random(&mut msg).unwrap();
+        let ciphertext = encrypt(&msg, &key).unwrap();
+
+        let mut buf = ciphertext.clone();
+        decrypt_in_place(&mut buf, &key).unwrap();
+        assert_eq!(buf, decrypt(&ciphertext, &key).unwrap());
+        assert_eq!(&buf[..], &msg[..]);
+
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert!(decrypt_in_place(&mut tampered, &key).is_err());
+        assert!(decrypt_in_place(&mut vec![0u8; 11], &key).is_err());
+    }
+
+    #[test]
+    fn test_derive_key() {
+        // RFC 5869 test case 1, the first 32 bytes of the OKM.
+        let ikm = [0x0b; 22];
+        let salt: Vec<u8> = (0x00..=0x0c).collect();
+        let info: Vec<u8> = (0xf0..=0xf9).collect();
+        let key = derive_key(&ikm, &salt, &info);
+        assert_eq!(key.to_hex::<String>(), "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf");
+        assert_ne!(derive_key(&ikm, &salt, b"other"), key);
+    }
+
+    #[test]
+    fn test_mac() {
+        // RFC 4231 test case 2, with the key padded by zeros (as HMAC does to short keys).
+        let mut key = [0u8; 32];
+        key[..4].copy_from_slice(b"Jefe");
+        let tag = mac(&key, b"what do ya want for nothing?");
+        assert_eq!(tag.to_hex::<String>(), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert!(verify_mac(&key, b"what do ya want for nothing?", &tag).is_ok());
+        assert!(verify_mac(&key, b"what do ya want for nothing!", &tag).is_err());
+        assert!(verify_mac(&[1u8; 32], b"what do ya want for nothing?", &tag).is_err());
+        assert!(verify_mac(&key, b"what do ya want for nothing?", &tag[..31]).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_deterministic() {
+        let key = b"EnigmaMPC".sha256();
+        let mut cipher = DeterministicCipher::new(key);
+        let first = cipher.encrypt_deterministic(b"This Is Enigma").unwrap();
+        assert_eq!(first, cipher.encrypt_deterministic(b"This Is Enigma").unwrap());
+        assert_eq!(decrypt(&first, &key).unwrap(), b"This Is Enigma".to_vec());
+
+        let other = cipher.encrypt_deterministic(b"This Is Enigma!").unwrap();
+        assert_ne!(first, other);
+        assert_ne!(first[first.len() - 12..], other[other.len() - 12..]);
+        assert_eq!(first, cipher.encrypt_deterministic(b"This Is Enigma").unwrap());
+        assert_ne!(first, DeterministicCipher::new(b"Other key".sha256()).encrypt_deterministic(b"This Is Enigma").unwrap());
+    }
+
+    #[test]
+    fn test_deterministic_nonces_are_unique() {
+        use std::collections::HashSet;
+        let mut cipher = DeterministicCipher::new(b"EnigmaMPC".sha256());
+        let mut nonces = HashSet::new();
+        for i in 0..10_000u32 {
+            let ciphertext = cipher.encrypt_deterministic(&i.to_be_bytes()).unwrap();
+            assert!(nonces.insert(ciphertext[ciphertext.len() - 12..].to_vec()));
+        }
+        // The IVs come from the counter, in the order the messages were first seen.
+        let ciphertext = cipher.encrypt_deterministic(&0u32.to_be_bytes()).unwrap();
+        assert_eq!(ciphertext[ciphertext.len() - 12..], [0u8; 12]);
+    }
+
+    #[test]
+    fn test_encrypt_with_seeded_rng() {
+        use crate::rand::SeededRng;
+        let key = [1u8; 32];
+        let ciphertext = encrypt_with_rng(b"Enigma", &key, &mut SeededRng::new(7)).unwrap();
+        assert_eq!(ciphertext[ciphertext.len() - 12..].to_hex::<String>(), "d70d3259e4e1cb631c663cf4");
+        assert_eq!(ciphertext.to_hex::<String>(), "90e7beaf7b72cc33ef25621cbf43e9fce5ca09d757bad70d3259e4e1cb631c663cf4");
+        assert_eq!(encrypt_with_rng(b"Enigma", &key, &mut SeededRng::new(7)).unwrap(), ciphertext);
+        assert_eq!(decrypt(&ciphertext, &key).unwrap(), b"Enigma".to_vec());
+    }
+
+    #[test]
+    fn test_counter_nonces_are_unique() {
+        use std::collections::HashSet;
+        let key = b"EnigmaMPC".sha256();
+        let mut counter = NonceCounter::default();
+        let mut nonces = HashSet::new();
+        for _ in 0..10_000 {
+            let ciphertext = encrypt_with_mode(b"This Is Enigma", &key, NonceMode::Counter(&mut counter)).unwrap();
+            assert_eq!(decrypt(&ciphertext, &key).unwrap(), b"This Is Enigma".to_vec());
+            assert!(nonces.insert(ciphertext[ciphertext.len() - 12..].to_vec()));
+        }
+        assert_eq!(counter.count(), 10_000);
+
+        // A restored counter continues where the persisted one stopped.
+        let mut restored = NonceCounter::starting_at(counter.count());
+        let ciphertext = encrypt_with_mode(b"This Is Enigma", &key, NonceMode::Counter(&mut restored)).unwrap();
+        assert!(nonces.insert(ciphertext[ciphertext.len() - 12..].to_vec()));
+
+        let mut exhausted = NonceCounter::starting_at(u64::max_value());
+        assert!(exhausted.next_iv().is_err());
+    }
 }
@@ -63,6 +63,10 @@ pub struct Opt {
     /// Optional: change the minimum log level
     #[structopt(short = "l", long = "log-level", default_value = "info")]
     pub log_level: String,
+
+    /// Optional: drop the messages logged by the enclave
+    #[structopt(long = "silence-enclave-logs")]
+    pub silence_enclave_logs: bool,
 }
 
 // fn all_colours() {
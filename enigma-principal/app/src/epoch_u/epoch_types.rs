@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use rustc_hex::ToHex;
 
 use enigma_tools_m::keeper_types::InputWorkerParams;
-use ethabi::{Event, EventParam, ParamType};
+use ethabi::{Event, EventParam, Log, LogParam, ParamType, Token};
 use failure::Error;
 pub use rlp::{decode, Encodable, encode, RlpStream};
 use serde::{Deserialize, Serialize};
@@ -112,3 +112,65 @@ impl WorkersParameterizedEvent {
         })
     }
 }
+
+/// The values of a parsed event log, ordered like the event inputs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogWrapper(pub Log);
+
+impl Into<Vec<Token>> for LogWrapper {
+    fn into(self) -> Vec<Token> {
+        self.0.params.into_iter().map(|param| param.value).collect()
+    }
+}
+
+impl LogWrapper {
+    /// Reverses the conversion into tokens, the parameter names and types are taken from `event`.
+    pub fn from_tokens(event: &Event, tokens: Vec<Token>) -> Result<Log, Error> {
+        if event.inputs.len() != tokens.len() {
+            bail!("The {} event has {} parameters, got {} tokens", event.name, event.inputs.len(), tokens.len());
+        }
+        let mut params = Vec::with_capacity(tokens.len());
+        for (input, value) in event.inputs.iter().zip(tokens) {
+            if !value.type_check(&input.kind) {
+                bail!("The {} parameter of the {} event should be {:?}, got {:?}", input.name, event.name, input.kind, value);
+            }
+            params.push(LogParam { name: input.name.clone(), value });
+        }
+        Ok(Log { params })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_log_tokens_round_trip() {
+        let event = WorkersParameterizedEvent::new().0;
+        let values = vec![
+            Token::Uint(1234.into()),
+            Token::Uint(10.into()),
+            Token::Uint(11.into()),
+            Token::Array(vec![Token::Address([1u8; 20].into()), Token::Address([2u8; 20].into())]),
+            Token::Array(vec![Token::Uint(100.into()), Token::Uint(200.into())]),
+            Token::Uint(0.into()),
+        ];
+        let params = event.inputs.iter().zip(values.iter())
+            .map(|(input, value)| LogParam { name: input.name.clone(), value: value.clone() })
+            .collect();
+        let log = Log { params };
+
+        let tokens: Vec<Token> = LogWrapper(log.clone()).into();
+        assert_eq!(tokens, values);
+        assert_eq!(LogWrapper::from_tokens(&event, tokens).unwrap(), log);
+    }
+
+    #[test]
+    fn test_log_from_bad_tokens() {
+        let event = WorkersParameterizedEvent::new().0;
+        assert!(LogWrapper::from_tokens(&event, vec![Token::Uint(1.into())]).is_err());
+        let mut tokens = vec![Token::Uint(0.into()); 6];
+        tokens[3] = Token::Bool(true);
+        assert!(LogWrapper::from_tokens(&event, tokens).is_err());
+    }
+}
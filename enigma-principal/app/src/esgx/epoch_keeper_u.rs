@@ -6,12 +6,12 @@ use web3::types::{Bytes, U256};
 
 use common_u::errors::EnclaveFailError;
 use enigma_types::{EnclaveReturn, traits::SliceCPtr};
-use epoch_u::epoch_types::{encode, EpochState};
+use epoch_u::epoch_types::EpochState;
 
 extern "C" {
     fn ecall_set_worker_params(
         eid: sgx_enclave_id_t, retval: &mut EnclaveReturn, worker_params_rlp: *const u8, worker_params_rlp_len: usize,
-        seed_in: &[u8; 32], nonce_in: &[u8; 32],
+        seed_in: &[u8; 32], nonce_in: &[u8; 32], external_seed_in: &[u8; 32], external_seed_sig_in: &[u8; 65],
         rand_out: &mut [u8; 32], nonce_out: &mut [u8; 32], sig_out: &mut [u8; 65],
     ) -> sgx_status_t;
 }
@@ -34,6 +34,29 @@ extern "C" {
 /// ```
 #[logfn(DEBUG)]
 pub fn set_or_verify_worker_params(eid: sgx_enclave_id_t, worker_params: &InputWorkerParams, epoch_state: Option<EpochState>) -> Result<EpochState, Error> {
+    call_set_worker_params(eid, worker_params, epoch_state, [0; 32], [0; 65])
+}
+
+/// Creates a new `Epoch` whose seed is `seed` instead of one generated by the enclave (e.g. the output of a VRF),
+/// the enclave signs the `Epoch` with it just like it does with its own seeds.
+/// The enclave only accepts the seed if it's signed by the seed beacon it was built with, for the nonce of the new `Epoch`.
+///
+/// # Arguments
+/// * `eid` - The Enclave Id
+/// * `worker_params` - The `InputWorkerParams` to store in an `Epoch`
+/// * `seed` - The seed of the new `Epoch`, it can't be zero
+/// * `seed_sig` - The seed beacon's signature over `seed` and the nonce of the new `Epoch`
+#[logfn(DEBUG)]
+pub fn set_worker_params_with_seed(eid: sgx_enclave_id_t, worker_params: &InputWorkerParams, seed: U256,
+                                   seed_sig: [u8; 65]) -> Result<EpochState, Error> {
+    if seed.is_zero() {
+        bail!("The external seed of an epoch can't be zero");
+    }
+    call_set_worker_params(eid, worker_params, None, seed.into(), seed_sig)
+}
+
+fn call_set_worker_params(eid: sgx_enclave_id_t, worker_params: &InputWorkerParams, epoch_state: Option<EpochState>,
+                          external_seed_in: [u8; 32], external_seed_sig_in: [u8; 65]) -> Result<EpochState, Error> {
     let mut retval: EnclaveReturn = EnclaveReturn::Success;
     let (nonce_in, seed_in) = match epoch_state.clone() {
         Some(e) => (e.nonce.into(), e.seed.into()),
@@ -42,8 +65,8 @@ pub fn set_or_verify_worker_params(eid: sgx_enclave_id_t, worker_params: &InputW
     debug!("Calling enclave set_worker_params with nonce/seed: {:?}/{:?}", nonce_in.to_vec().to_hex(), seed_in.to_vec().to_hex());
     let (mut nonce_out, mut rand_out) = ([0; 32], [0; 32]);
     let mut sig_out: [u8; 65] = [0; 65];
-    // Serialize the InputWorkerParams into RLP
-    let worker_params_rlp = encode(worker_params);
+    // Serialize the InputWorkerParams into RLP, keeping the order of the workers
+    let worker_params_rlp = worker_params.rlp_in_order();
     let status = unsafe {
         ecall_set_worker_params(
             eid,
@@ -52,6 +75,8 @@ pub fn set_or_verify_worker_params(eid: sgx_enclave_id_t, worker_params: &InputW
             worker_params_rlp.len(),
             &seed_in,
             &nonce_in,
+            &external_seed_in,
+            &external_seed_sig_in,
             &mut rand_out,
             &mut nonce_out,
             &mut sig_out,
@@ -107,6 +132,24 @@ pub mod tests {
         enclave.destroy();
     }
 
+    #[test]
+    fn test_set_worker_params_with_seed() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let workers: Vec<[u8; 20]> = vec![
+            [156, 26, 193, 252, 165, 167, 191, 244, 251, 126, 53, 154, 158, 14, 64, 194, 164, 48, 231, 179],
+        ];
+        let stakes: Vec<u64> = vec![90000000000];
+        let worker_params = get_worker_params(1, workers, stakes);
+        let seed = U256::from_big_endian(&[42; 32]);
+        // The seed isn't signed by the seed beacon so the enclave rejects it
+        assert!(set_worker_params_with_seed(enclave.geteid(), &worker_params, seed, [1; 65]).is_err());
+        assert!(set_worker_params_with_seed(enclave.geteid(), &worker_params, U256::zero(), [1; 65]).is_err());
+        // And still creates epochs from its own randomness
+        let epoch_state = set_or_verify_worker_params(enclave.geteid(), &worker_params, None).unwrap();
+        assert_ne!(epoch_state.seed, seed);
+        enclave.destroy();
+    }
+
     #[test]
     fn test_set_mock_worker_params_above_cap() {
         let enclave = init_enclave_wrapper().unwrap();
@@ -42,7 +42,7 @@ use cli::options::Opt;
 use enigma_tools_u::common_u::logging;
 use enigma_tools_u::common_u::os;
 
-pub use enigma_tools_u::esgx::ocalls_u::{ocall_get_home, ocall_save_to_memory};
+pub use enigma_tools_u::esgx::ocalls_u::{ocall_get_home, ocall_log, ocall_save_to_memory};
 use structopt::StructOpt;
 
 // enigma modules
@@ -60,6 +60,7 @@ fn main() {
     // let datadir = opt.data_dir.clone().unwrap_or_else(|| dirs::home_dir().unwrap().join(".enigma"));
     let hostname = os::hostname();
     let _handler = logging::init_logger(log_level, &datadir, hostname);
+    enigma_tools_u::esgx::ocalls_u::silence_enclave_logs(opt.silence_enclave_logs);
 
     debug!("CLI params: {:?}", opt);
 
@@ -3,12 +3,12 @@ use core::clone::Clone;
 use enigma_tools_m::keeper_types::{decode, EPOCH_CAP, InputWorkerParams, RawEncodable};
 use enigma_tools_m::utils::LockExpectMutex;
 use ethereum_types::{H256, U256, BigEndianHash};
-use rustc_hex::ToHex;
+use rustc_hex::{FromHex, ToHex};
 use sgx_trts::trts::rsgx_read_rand;
 use sgx_types::*;
-use std::{collections::HashMap, path, str, string::String, sync::SgxMutex};
+use std::{collections::HashMap, path, str, string::{String, ToString}, sync::SgxMutex, vec::Vec};
 
-use enigma_crypto::hash::Keccak256;
+use enigma_crypto::{asymmetric::KeyPair, hash::{prepare_hash_multiple, Keccak256}};
 use enigma_tools_t::{
     common::{
         errors_t::{
@@ -18,6 +18,7 @@ use enigma_tools_t::{
     },
     document_storage_t::{is_document, load_sealed_document, save_sealed_document, SEAL_LOG_SIZE, SealedDocumentStorage},
 };
+use enigma_tools_m::utils::EthereumAddress;
 use enigma_types::{ContractAddress, Hash256};
 use epoch_keeper_t::epoch_t::{Epoch, EpochMarker, EpochNonce};
 use ocalls_t;
@@ -107,9 +108,56 @@ fn store_epoch(epoch: Epoch) -> Result<(), EnclaveError> {
     Ok(())
 }
 
+/// The address of the beacon that signs external seeds (e.g. a VRF), given in hex when the enclave is built (`SEED_BEACON_ADDRESS`)
+/// so it's part of the enclave's measurement. Without it (or if it isn't a valid address) no external seed is accepted.
+fn get_seed_beacon() -> Option<[u8; 20]> {
+    let hex = option_env!("SEED_BEACON_ADDRESS")?;
+    let bytes: Vec<u8> = hex.trim_start_matches("0x").from_hex().ok()?;
+    if bytes.len() != 20 {
+        return None;
+    }
+    let mut beacon = [0u8; 20];
+    beacon.copy_from_slice(&bytes);
+    Some(beacon)
+}
+
+/// The message the seed beacon signs to give `seed` to the epoch of `nonce`,
+/// it's bound to the nonce so a seed can't be replayed in another epoch.
+pub fn external_seed_message(nonce: U256, seed: &[u8; 32]) -> Vec<u8> {
+    prepare_hash_multiple(&[&H256::from_uint(&nonce).0[..], &seed[..]])
+}
+
+/// Verifies that `beacon` signed `seed` for the epoch of `nonce`.
+fn verify_external_seed(nonce: U256, seed: &[u8; 32], sig: &[u8; 65], beacon: Option<[u8; 20]>) -> Result<(), EnclaveError> {
+    let beacon = match beacon {
+        Some(beacon) => beacon,
+        None => return Err(SystemError(WorkerAuthError { err: "No seed beacon is configured, external seeds can't be verified".to_string() })),
+    };
+    let signer = KeyPair::recover(&external_seed_message(nonce, seed), *sig)?.address();
+    if signer != beacon {
+        return Err(SystemError(WorkerAuthError {
+            err: format!("The external seed of epoch {:?} was signed by {}, not the seed beacon {}",
+                         nonce, signer.to_hex::<String>(), beacon.to_hex::<String>()),
+        }));
+    }
+    Ok(())
+}
+
+/// Creates a new `Epoch`, or recovers the one of `seed_in` and `nonce_in` from its sealed marker, and signs it.
+/// The seed of a new `Epoch` is `external_seed_in` if it isn't empty (e.g. an externally agreed VRF output) and
+/// `external_seed_sig_in` is the seed beacon's signature over it (see `external_seed_message`),
+/// otherwise it's generated by the enclave. Either way it's returned in `rand_out` and covered by the signature.
 pub(crate) fn ecall_set_worker_params_internal(worker_params_rlp: &[u8], seed_in: &[u8; 32], nonce_in: &[u8; 32],
+                                               external_seed_in: &[u8; 32], external_seed_sig_in: &[u8; 65],
                                                rand_out: &mut [u8; 32], nonce_out: &mut [u8; 32],
                                                sig_out: &mut [u8; 65]) -> Result<(), EnclaveError> {
+    set_worker_params(worker_params_rlp, seed_in, nonce_in, external_seed_in, external_seed_sig_in, get_seed_beacon(),
+                      rand_out, nonce_out, sig_out)
+}
+
+fn set_worker_params(worker_params_rlp: &[u8], seed_in: &[u8; 32], nonce_in: &[u8; 32],
+                     external_seed_in: &[u8; 32], external_seed_sig_in: &[u8; 65], seed_beacon: Option<[u8; 20]>,
+                     rand_out: &mut [u8; 32], nonce_out: &mut [u8; 32], sig_out: &mut [u8; 65]) -> Result<(), EnclaveError> {
     // RLP decoding the necessary data
     let worker_params: InputWorkerParams = decode(worker_params_rlp);
     const EMPTY_SLICE: [u8; 32] = [0; 32];
@@ -117,6 +165,9 @@ pub(crate) fn ecall_set_worker_params_internal(worker_params_rlp: &[u8], seed_in
     // If the seed input is not an empty slice, recover an `Epoch` from the sealed marker
     // Verifying the seed only because a nonce input of 0 is also an empty slice
     if seed_in != &EMPTY_SLICE {
+        if external_seed_in != &EMPTY_SLICE {
+            return Err(SystemError(WorkerAuthError { err: "An external seed can only be given for a new epoch".to_string() }));
+        }
         let seed = U256::from(seed_in);
         let nonce = U256::from(nonce_in);
         // Get the epoch marker values (nonce + H(`Epoch`) fr
@@ -148,8 +199,13 @@ pub(crate) fn ecall_set_worker_params_internal(worker_params_rlp: &[u8], seed_in
                 Some(nonce) => nonce + 1,
                 None => INIT_NONCE.into(),
             };
+            if external_seed_in != &EMPTY_SLICE {
+                verify_external_seed(nonce, external_seed_in, external_seed_sig_in, seed_beacon)?;
+                rand_out.copy_from_slice(external_seed_in);
+            } else {
+                rsgx_read_rand(&mut rand_out[..])?;
+            }
             *nonce_out = EpochNonce::from(nonce);
-            rsgx_read_rand(&mut rand_out[..])?;
             let seed = U256::from(rand_out.as_ref());
             let epoch = Epoch { nonce, seed, worker_params };
             debug_println!("Creating new epoch with nonce {:?} and seed: {:?}", nonce, seed);
@@ -206,6 +262,46 @@ pub mod tests {
         let worker = epoch.get_selected_worker(sc_addr).unwrap();
     }
 
+    pub fn test_set_worker_params_external_seed() {
+        let worker_params = InputWorkerParams {
+            km_block_number: U256::from(1),
+            workers: vec![H160::from([1u8; 20])],
+            stakes: vec![U256::from(1)],
+        };
+        let rlp = worker_params.rlp_in_order();
+        let external_seed = [7u8; 32];
+        let (mut rand_out, mut nonce_out, mut sig_out) = ([0u8; 32], [0u8; 32], [0u8; 65]);
+        let beacon = KeyPair::new().unwrap();
+        let next_nonce = match EPOCH.lock_expect("Epoch").keys().max() {
+            Some(nonce) => nonce + 1,
+            None => INIT_NONCE.into(),
+        };
+        let seed_sig = beacon.sign(&external_seed_message(next_nonce, &external_seed)).unwrap();
+
+        // A seed that isn't signed by the seed beacon is rejected, and so is one signed for another epoch.
+        let impostor_sig = KeyPair::new().unwrap().sign(&external_seed_message(next_nonce, &external_seed)).unwrap();
+        assert!(ecall_set_worker_params_internal(&rlp, &[0; 32], &[0; 32], &external_seed, &impostor_sig, &mut rand_out, &mut nonce_out, &mut sig_out).is_err());
+        assert!(set_worker_params(&rlp, &[0; 32], &[0; 32], &external_seed, &impostor_sig, Some(beacon.get_pubkey().address()), &mut rand_out, &mut nonce_out, &mut sig_out).is_err());
+        let replayed_sig = beacon.sign(&external_seed_message(next_nonce + 1, &external_seed)).unwrap();
+        assert!(set_worker_params(&rlp, &[0; 32], &[0; 32], &external_seed, &replayed_sig, Some(beacon.get_pubkey().address()), &mut rand_out, &mut nonce_out, &mut sig_out).is_err());
+        assert!(set_worker_params(&rlp, &[0; 32], &[0; 32], &external_seed, &seed_sig, None, &mut rand_out, &mut nonce_out, &mut sig_out).is_err());
+        assert!(get_epoch_from_cache(&EPOCH.lock_expect("Epoch"), next_nonce).is_err());
+
+        set_worker_params(&rlp, &[0; 32], &[0; 32], &external_seed, &seed_sig, Some(beacon.get_pubkey().address()), &mut rand_out, &mut nonce_out, &mut sig_out).unwrap();
+        assert_eq!(rand_out, external_seed);
+        assert_eq!(U256::from(nonce_out.as_ref()), next_nonce);
+
+        // The enclave signed the epoch with the external seed.
+        let epoch = get_epoch_from_cache(&EPOCH.lock_expect("Epoch"), U256::from(nonce_out.as_ref())).unwrap();
+        assert_eq!(epoch.seed, U256::from(external_seed.as_ref()));
+        let signer = KeyPair::recover(&epoch.encode_for_hashing(), sig_out).unwrap();
+        assert_eq!(signer.to_vec(), SIGNING_KEY.get_pubkey().to_vec());
+
+        // An existing epoch can't be given another seed.
+        let (seed_in, nonce_in) = (rand_out, nonce_out);
+        assert!(set_worker_params(&rlp, &seed_in, &nonce_in, &external_seed, &seed_sig, Some(beacon.get_pubkey().address()), &mut rand_out, &mut nonce_out, &mut sig_out).is_err());
+    }
+
     pub fn test_create_epoch_image() {
         let expected_image1: Vec<u8> = vec![0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 98, 42, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
         let worker_params1 = InputWorkerParams {
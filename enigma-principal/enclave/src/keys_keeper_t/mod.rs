@@ -1,5 +1,5 @@
 use enigma_tools_m::{
-    primitives::km_primitives::{PrincipalMessage, PrincipalMessageType},
+    primitives::km_primitives::{PrincipalMessage, PrincipalMessageType, ProvisionedKey},
     utils::EthereumAddress,
 };
 use sgx_trts::trts::rsgx_read_rand;
@@ -104,8 +104,9 @@ fn new_state_keys(keys_map: &mut HashMap<ContractAddress, StateKey>,
     Ok(results)
 }
 
-fn build_get_state_keys_response(sc_addrs: Vec<ContractAddress>) -> Result<Vec<(ContractAddress, StateKey)>, EnclaveError> {
-    let mut response_data: Vec<(ContractAddress, StateKey)> = Vec::new();
+/// `worker` is the worker selected for all of `sc_addrs`, the core enclave only accepts their deltas when signed by it.
+fn build_get_state_keys_response(sc_addrs: Vec<ContractAddress>, worker: [u8; 20]) -> Result<Vec<ProvisionedKey>, EnclaveError> {
+    let mut response_data: Vec<ProvisionedKey> = Vec::new();
     if sc_addrs.is_empty() {
         return Ok(response_data);
     }
@@ -122,7 +123,7 @@ fn build_get_state_keys_response(sc_addrs: Vec<ContractAddress>) -> Result<Vec<(
     // Now we have keys for all addresses in cache
     for addr in sc_addrs {
         match guard.get(&addr) {
-            Some(&key) => response_data.push((addr, key)),
+            Some(&key) => response_data.push((addr, key, worker)),
             None => {
                 return Err(SystemError(KeyProvisionError { err: format!("State key not found in cache: {:?}", addr.to_hex::<String>()) }));
             }
@@ -151,7 +152,7 @@ pub(crate) fn ecall_get_enc_state_keys_internal(
             }));
         }
     }
-    let response_data = build_get_state_keys_response(sc_addrs)?;
+    let response_data = build_get_state_keys_response(sc_addrs, recovered_addr)?;
 
     // Generate the encryption key material
     let key_pair = KeyPair::new()?;
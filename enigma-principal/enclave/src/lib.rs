@@ -93,12 +93,13 @@ fn get_ethereum_keys_wrapper() -> asymmetric::KeyPair {
 #[no_mangle]
 pub unsafe extern "C" fn ecall_set_worker_params(worker_params_rlp: *const u8, worker_params_rlp_len: usize,
                                                  seed_in: &[u8; 32], nonce_in: &[u8; 32],
+                                                 external_seed_in: &[u8; 32], external_seed_sig_in: &[u8; 65],
                                                  rand_out: &mut [u8; 32], nonce_out: &mut [u8; 32],
                                                  sig_out: &mut [u8; 65]) -> EnclaveReturn {
     // Assembling byte arrays with the RLP data
     let worker_params_rlp = slice::from_raw_parts(worker_params_rlp, worker_params_rlp_len);
 
-    match ecall_set_worker_params_internal(worker_params_rlp, seed_in, nonce_in, rand_out, nonce_out, sig_out) {
+    match ecall_set_worker_params_internal(worker_params_rlp, seed_in, nonce_in, external_seed_in, external_seed_sig_in, rand_out, nonce_out, sig_out) {
         Ok(_) => EnclaveReturn::Success,
         Err(err) => err.into(),
     }
@@ -140,6 +141,7 @@ pub mod tests {
             test_full_sealing_storage,
             test_document_sealing_storage,
             test_get_epoch_worker_internal,
+            test_set_worker_params_external_seed,
             test_state_keys_storage,
             test_create_epoch_image,
             test_u256_nested,
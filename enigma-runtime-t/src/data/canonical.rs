@@ -0,0 +1,66 @@
+//! Canonical JSON, so equal states always serialize (and hash) to the same bytes.
+//! Object keys are sorted, there's no insignificant whitespace and every number has a single form:
+//! an integral float is written as an integer (`1.0` as `1`, `-0.0` as `0`), any other number as serde_json writes it.
+use super::state::MAX_SAFE_INTEGER;
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::SystemError, EnclaveSystemError::StateError};
+use native_stack;
+use serde::Serialize;
+use serde_json::{Number, Value};
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+/// Serializes `value` as canonical JSON, this is what should be hashed whenever a hash of the state is needed.
+/// Recursing on the state is bounded by the native stack budget like the other walks over it (see `native_stack`).
+pub fn to_canonical_json(value: &Value) -> Result<Vec<u8>, EnclaveError> {
+    let mut buf = Vec::new();
+    write_canonical(value, &mut buf)?;
+    Ok(buf)
+}
+
+fn write_canonical(value: &Value, buf: &mut Vec<u8>) -> Result<(), EnclaveError> {
+    native_stack::check()?;
+    match value {
+        Value::Number(n) => write_compact(&canonical_number(n), buf)?,
+        Value::Array(arr) => {
+            buf.push(b'[');
+            for (i, v) in arr.iter().enumerate() {
+                if i > 0 {
+                    buf.push(b',');
+                }
+                write_canonical(v, buf)?;
+            }
+            buf.push(b']');
+        }
+        Value::Object(obj) => {
+            // Don't rely on the order of the map, it depends on the features serde_json was built with.
+            let mut entries: Vec<(&String, &Value)> = obj.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            buf.push(b'{');
+            for (i, (key, v)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    buf.push(b',');
+                }
+                write_compact(key, buf)?;
+                buf.push(b':');
+                write_canonical(v, buf)?;
+            }
+            buf.push(b'}');
+        }
+        // Null, booleans and strings have a single compact form.
+        _ => write_compact(value, buf)?,
+    }
+    Ok(())
+}
+
+fn canonical_number(n: &Number) -> Number {
+    match n.as_f64() {
+        Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER as f64 => Number::from(f as i64),
+        _ => n.clone(),
+    }
+}
+
+fn write_compact<T: Serialize + ?Sized>(value: &T, buf: &mut Vec<u8>) -> Result<(), EnclaveError> {
+    let bytes = serde_json::to_vec(value).map_err(|e| SystemError(StateError { err: e.to_string() }))?;
+    buf.extend_from_slice(&bytes);
+    Ok(())
+}
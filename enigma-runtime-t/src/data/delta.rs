@@ -1,10 +1,12 @@
-use enigma_tools_t::common::errors_t::EnclaveError;
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::SystemError, EnclaveSystemError::StateError};
 use enigma_crypto::hash::Keccak256;
 use enigma_crypto::{symmetric, Encryption};
 use enigma_types::{Hash256, ContractAddress, StateKey};
 use json_patch;
 use rmps::{Deserializer, Serializer};
-use serde::{Deserialize, Serialize};
+use rustc_hex::ToHex;
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use std::string::{String, ToString};
 use std::vec::Vec;
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -19,15 +21,171 @@ pub struct StatePatch {
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
 pub struct EncryptedPatch {
+    /// The ciphertext, followed by the epoch nonce and `EPOCH_TRAILER` if `epoch` is set.
+    /// This is what the DB stores and peers sync, so the epoch survives a reload (see `from_stored`).
     pub data: Vec<u8>,
     pub contract_address: ContractAddress,
     pub index: u32,
+    /// The nonce of the epoch whose key encrypted the patch (see `epoch_key`), `None` if the state key did.
+    #[serde(default)]
+    pub epoch: Option<[u8; 32]>,
+}
+
+/// The plaintext fields of a serialized `EncryptedPatch`, the encrypted data is skipped without being copied.
+#[derive(Deserialize)]
+struct PatchMetadata {
+    #[serde(rename = "data")]
+    _data: IgnoredAny,
+    contract_address: ContractAddress,
+    index: u32,
+    #[serde(default, rename = "epoch")]
+    _epoch: Option<IgnoredAny>,
+}
+
+/// Binds the keys derived by `epoch_key` to their use for deltas.
+const EPOCH_KEY_INFO: &[u8] = b"enigma delta epoch key";
+
+/// Ends the data of a patch that was encrypted with the key of an epoch, right after the epoch nonce.
+/// A ciphertext ends with its random IV, so one without an epoch ends with these 16 bytes only with a negligible probability.
+const EPOCH_TRAILER: &[u8; 16] = b"enigma epoch v1\0";
+/// The epoch nonce and `EPOCH_TRAILER`.
+const EPOCH_TRAILER_LEN: usize = 32 + 16;
+
+/// Derive the key of the epoch with `epoch_nonce` from the state key with HKDF,
+/// so a leaked epoch key doesn't expose the deltas of the other epochs.
+pub fn epoch_key(key: &StateKey, epoch_nonce: &[u8; 32]) -> StateKey {
+    symmetric::derive_key(key, epoch_nonce, EPOCH_KEY_INFO)
 }
 
 impl EncryptedPatch {
+    /// A patch from the data the DB stores for it, the epoch it was encrypted for is read from the end of `data`.
+    pub fn from_stored(data: Vec<u8>, contract_address: ContractAddress, index: u32) -> Self {
+        let mut epoch = None;
+        if data.len() >= EPOCH_TRAILER_LEN && data.ends_with(EPOCH_TRAILER) {
+            let mut nonce = [0u8; 32];
+            nonce.copy_from_slice(&data[data.len() - EPOCH_TRAILER_LEN..data.len() - EPOCH_TRAILER.len()]);
+            epoch = Some(nonce);
+        }
+        EncryptedPatch { data, contract_address, index, epoch }
+    }
+
+    /// The ciphertext of the patch, without the epoch trailer.
+    pub fn ciphertext(&self) -> &[u8] {
+        match self.epoch {
+            Some(_) => &self.data[..self.data.len().saturating_sub(EPOCH_TRAILER_LEN)],
+            None => &self.data,
+        }
+    }
+
     pub fn keccak256_patch(&self) -> Hash256 {
         self.data.keccak256()
     }
+
+    /// Read the contract address and the index of a msgpack serialized `EncryptedPatch`
+    /// without the key, so encrypted patches can be routed and sorted by nodes that can't decrypt them.
+    pub fn peek(bytes: &[u8]) -> Result<(ContractAddress, u32), EnclaveError> {
+        let mut des = Deserializer::new(bytes);
+        let meta: PatchMetadata = Deserialize::deserialize(&mut des)?;
+        Ok((meta.contract_address, meta.index))
+    }
+}
+
+/// The version of the envelope `StatePatch::to_export` writes, `StatePatch::from_export` rejects any other.
+pub const DELTA_EXPORT_VERSION: u32 = 1;
+
+/// A delta in plaintext for tools outside of Enigma, it's a JSON object:
+/// ```json
+/// {
+///   "version": 1,
+///   "contractId": "<the contract address, 64 hex digits>",
+///   "index": <the index of the delta>,
+///   "previousHash": "<the hash of the previous delta, 64 hex digits>",
+///   "epoch": "<the nonce of the epoch the delta was encrypted for, 64 hex digits, omitted if there's none>",
+///   "patch": [<the RFC 6902 operations of the delta>]
+/// }
+/// ```
+/// It isn't the wire format, the deltas are still stored and sent as msgpack encoded `EncryptedPatch`es.
+/// It's only a library API for now, no ecall or IPC request exports or imports deltas.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeltaExport {
+    version: u32,
+    contract_id: String,
+    index: u32,
+    previous_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    epoch: Option<String>,
+    patch: json_patch::Patch,
+}
+
+fn export_error(err: String) -> EnclaveError { SystemError(StateError { err }) }
+
+fn hash_from_hex(field: &str, hex: &str) -> Result<Hash256, EnclaveError> {
+    Hash256::from_hex(hex).map_err(|e| export_error(format!("the {} of the exported delta isn't valid: {}", field, e)))
+}
+
+/// Decrypts `delta` and writes it in the export envelope (see `DeltaExport`), with the epoch it was encrypted for.
+pub fn export_delta(delta: EncryptedPatch, key: &StateKey) -> Result<Vec<u8>, EnclaveError> {
+    let epoch = delta.epoch;
+    StatePatch::decrypt(delta, key)?.export_envelope(epoch)
+}
+
+/// Reads a delta from the export envelope and encrypts it with `key`,
+/// or with the key of its epoch if it was exported from a delta that was encrypted for one.
+pub fn import_delta(export: &[u8], key: &StateKey) -> Result<EncryptedPatch, EnclaveError> {
+    match StatePatch::parse_export(export)? {
+        (patch, Some(epoch_nonce)) => patch.encrypt_for_epoch(key, epoch_nonce, None),
+        (patch, None) => patch.encrypt(key),
+    }
+}
+
+impl StatePatch {
+    /// Serializes the patch and its metadata in the export envelope (see `DeltaExport`).
+    pub fn to_export(&self) -> Result<Vec<u8>, EnclaveError> { self.export_envelope(None) }
+
+    /// Parses a patch from the export envelope (see `DeltaExport`), the epoch isn't part of a `StatePatch` so it's ignored.
+    pub fn from_export(bytes: &[u8]) -> Result<StatePatch, EnclaveError> { Ok(Self::parse_export(bytes)?.0) }
+
+    fn export_envelope(&self, epoch: Option<[u8; 32]>) -> Result<Vec<u8>, EnclaveError> {
+        let export = DeltaExport {
+            version: DELTA_EXPORT_VERSION,
+            contract_id: self.contract_address[..].to_hex(),
+            index: self.index,
+            previous_hash: self.previous_hash[..].to_hex(),
+            epoch: epoch.map(|nonce| nonce[..].to_hex()),
+            patch: self.patch.clone(),
+        };
+        serde_json::to_vec(&export).map_err(|e| export_error(e.to_string()))
+    }
+
+    fn parse_export(bytes: &[u8]) -> Result<(StatePatch, Option<[u8; 32]>), EnclaveError> {
+        let export: DeltaExport = serde_json::from_slice(bytes).map_err(|e| export_error(format!("the exported delta isn't valid: {}", e)))?;
+        if export.version != DELTA_EXPORT_VERSION {
+            return Err(export_error(format!("unsupported version {} of an exported delta, expected {}", export.version, DELTA_EXPORT_VERSION)));
+        }
+        let epoch = match export.epoch {
+            Some(nonce) => Some(*hash_from_hex("epoch", &nonce)?),
+            None => None,
+        };
+        let patch = StatePatch {
+            patch: export.patch,
+            previous_hash: hash_from_hex("previousHash", &export.previous_hash)?,
+            contract_address: hash_from_hex("contractId", &export.contract_id)?,
+            index: export.index,
+        };
+        Ok((patch, epoch))
+    }
+
+    /// Encrypt the patch with the key of the epoch with `epoch_nonce` instead of the state key itself.
+    /// The epoch is recorded in the `EncryptedPatch` and at the end of its data, so `decrypt` with the state key
+    /// derives the same key, also after the patch was stored and read back.
+    pub fn encrypt_for_epoch(self, key: &StateKey, epoch_nonce: [u8; 32], iv: Option<[u8; 12]>) -> Result<EncryptedPatch, EnclaveError> {
+        let mut enc = self.encrypt_with_nonce(&epoch_key(key, &epoch_nonce), iv)?;
+        enc.data.extend_from_slice(&epoch_nonce);
+        enc.data.extend_from_slice(EPOCH_TRAILER);
+        enc.epoch = Some(epoch_nonce);
+        Ok(enc)
+    }
 }
 
 impl<'a> Encryption<&'a StateKey, EnclaveError, EncryptedPatch, [u8; 12]> for StatePatch {
@@ -37,11 +195,21 @@ impl<'a> Encryption<&'a StateKey, EnclaveError, EncryptedPatch, [u8; 12]> for St
         let data = symmetric::encrypt_with_nonce(&buf, key, _iv)?;
         let contract_address = self.contract_address;
         let index = self.index;
-        Ok(EncryptedPatch { data, contract_address, index })
+        Ok(EncryptedPatch { data, contract_address, index, epoch: None })
     }
 
     fn decrypt(enc: EncryptedPatch, key: &StateKey) -> Result<Self, EnclaveError> {
-        let dec = symmetric::decrypt(&enc.data, key)?;
+        // Deltas can be large, so they're decrypted without copying.
+        let mut dec = enc.data;
+        match enc.epoch {
+            Some(epoch_nonce) => {
+                let len = dec.len().checked_sub(EPOCH_TRAILER_LEN)
+                    .ok_or_else(|| export_error("the patch is shorter than its epoch trailer".to_string()))?;
+                dec.truncate(len);
+                symmetric::decrypt_in_place(&mut dec, &epoch_key(key, &epoch_nonce))?
+            }
+            None => symmetric::decrypt_in_place(&mut dec, key)?,
+        };
         let mut des = Deserializer::new(&dec[..]);
         let mut back: Self = Deserialize::deserialize(&mut des)?;
         back.contract_address = enc.contract_address;
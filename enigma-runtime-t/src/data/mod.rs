@@ -1,13 +1,16 @@
+mod canonical;
 mod delta;
 mod state;
 
-pub use data::delta::{EncryptedPatch, StatePatch};
-pub use data::state::{ContractState, EncryptedContractState};
+pub use data::canonical::to_canonical_json;
+pub use data::delta::{epoch_key, export_delta, import_delta, EncryptedPatch, StatePatch, DELTA_EXPORT_VERSION};
+pub use data::state::{max_json_depth, max_state_size, set_max_json_depth, set_max_state_size, ContractState, EncryptedContractState,
+                      BIG_INTEGER_TAG, DEFAULT_MAX_JSON_DEPTH, DEFAULT_MAX_STATE_SIZE, MAX_SAFE_INTEGER};
 use serde::Deserialize;
-use serde_json::{Error, Value};
+use serde_json::Value;
 
 pub trait IOInterface<E, U> {
-    fn read_key<T>(&self, key: &str) -> Result<T, Error> where for<'de> T: Deserialize<'de>;
+    fn read_key<T>(&self, key: &str) -> Result<T, E> where for<'de> T: Deserialize<'de>;
     fn write_key(&mut self, key: &str, value: &Value) -> Result<(), E>;
     fn remove_key(&mut self, key: &str);
 }
@@ -20,12 +23,20 @@ pub trait DeltasInterface<E, T, K> {
 #[cfg(debug_assertions)]
 pub mod tests {
     use crate::data::*;
+    use crate::data::state::preserve_big_integers;
+    use crate::native_stack;
+    use crate::test_vectors::{load_patch, load_state};
+    use enigma_crypto::symmetric;
     use enigma_crypto::hash::Sha256;
     use enigma_crypto::Encryption;
+    use enigma_tools_t::common::errors_t::{EnclaveError::*, EnclaveSystemError::*, FailedTaskError::{JsonTooDeep, NativeStackOverflow, StateTooLarge}};
     use enigma_types::ContractAddress;
     use json_patch;
+    use rmps::Serializer;
+    use serde::Serialize;
     use serde_json::{self, Map, Value};
-    use std::string::String;
+    use std::string::{String, ToString};
+    use std::vec::Vec;
 
     pub fn test_encrypt_state() {
         let contract_address = b"Enigma".sha256();
@@ -95,6 +106,167 @@ pub mod tests {
         assert_eq!(con.read_key::<Map<String, Value>>("payload").unwrap()["features"], json!(["serde", "json"]));
     }
 
+    pub fn test_big_integers_state() {
+        let mut con = ContractState::new(b"Enigma".sha256());
+        // The limbs of a U256 near its max, as `[u64; 4]`.
+        let limbs = [u64::max_value() - 1, u64::max_value(), u64::max_value(), u64::max_value()];
+        con.write_key("big", &json!(limbs)).unwrap();
+        con.write_key("small", &json!(MAX_SAFE_INTEGER)).unwrap();
+        con.write_key("negative", &json!(i64::min_value())).unwrap();
+
+        assert_eq!(con.json["big"][0], json!({ BIG_INTEGER_TAG: "18446744073709551614" }));
+        assert_eq!(con.json["small"], json!(MAX_SAFE_INTEGER));
+        assert_eq!(con.read_key::<[u64; 4]>("big").unwrap(), limbs);
+        assert_eq!(con.read_key::<u64>("small").unwrap(), MAX_SAFE_INTEGER);
+        assert_eq!(con.read_key::<i64>("negative").unwrap(), i64::min_value());
+        // A string that only looks like a number is still a string, also next to a big integer.
+        con.write_key("string", &json!("18446744073709551615")).unwrap();
+        assert_eq!(con.read_key::<String>("string").unwrap(), "18446744073709551615");
+        con.write_key("mixed", &json!(["18446744073709551615", u64::max_value()])).unwrap();
+        assert_eq!(con.read_key::<(String, u64)>("mixed").unwrap(), ("18446744073709551615".to_string(), u64::max_value()));
+        // The contract gets the numbers back whatever it reads them as.
+        assert_eq!(con.read_value("mixed").unwrap(), json!(["18446744073709551615", u64::max_value()]));
+        con.json["forged"] = json!({ BIG_INTEGER_TAG: "not a number" });
+        assert!(con.read_value("forged").is_err());
+    }
+
+    pub fn test_read_state_wrong_type() {
+        let mut con = ContractState::new(b"Enigma".sha256());
+        // Arbitrary bytes are stored as a json array of numbers, which isn't a valid String.
+        let bytes: Vec<u8> = vec![0xff, 0xfe, 0x00, 0xc3, 0x28];
+        con.write_key("bytes", &json!(bytes)).unwrap();
+
+        match con.read_key::<String>("bytes") {
+            Err(SystemError(StateError { err })) => assert!(err.contains("bytes")),
+            other => panic!("Expected a StateError, got: {:?}", other),
+        }
+        assert_eq!(con.read_key::<Vec<u8>>("bytes").unwrap(), bytes);
+    }
+
+    fn nested(depth: usize) -> Value {
+        let mut value = json!(1);
+        for _ in 0..depth {
+            value = json!({ "inner": value });
+        }
+        value
+    }
+
+    pub fn test_native_stack_guards_deep_values() {
+        // Recursing on a value this deep overflows the 256KiB stack of the enclave thread without the guard.
+        let mut deep = json!(1);
+        for _ in 0..100_000 {
+            deep = Value::Array(vec![deep]);
+        }
+        let res = {
+            let _guard = native_stack::enter();
+            preserve_big_integers(&mut deep)
+        };
+        // Dropping it as a whole would recurse just as deep, so it's taken apart one level at a time.
+        while let Value::Array(mut arr) = deep {
+            deep = arr.pop().unwrap_or(Value::Null);
+        }
+        match res {
+            Err(FailedTaskError(NativeStackOverflow { budget })) => assert_eq!(budget, native_stack::budget()),
+            other => panic!("Expected a NativeStackOverflow, got: {:?}", other),
+        }
+    }
+
+    pub fn test_max_json_depth() {
+        let mut con = ContractState::new(b"Enigma".sha256());
+        assert_eq!(max_json_depth(), DEFAULT_MAX_JSON_DEPTH);
+        con.write_key("shallow", &nested(10)).unwrap();
+
+        set_max_json_depth(10);
+        let deep = con.write_key("deep", &nested(11));
+        let read = con.read_key::<Value>("shallow");
+        let exact = con.write_key("exact", &nested(10));
+        set_max_json_depth(DEFAULT_MAX_JSON_DEPTH);
+
+        match deep {
+            Err(FailedTaskError(JsonTooDeep { max_depth })) => assert_eq!(max_depth, 10),
+            other => panic!("Expected a JsonTooDeep, got: {:?}", other),
+        }
+        // The rejected value isn't written.
+        assert_eq!(con.json["deep"], Value::Null);
+        assert_eq!(read.unwrap(), nested(10));
+        exact.unwrap();
+
+        // With the default limit the whole state, the object the values are in included, can be parsed by serde_json.
+        let mut con = ContractState::new(b"Enigma".sha256());
+        con.write_key("deepest", &nested(DEFAULT_MAX_JSON_DEPTH)).unwrap();
+        let serialized = serde_json::to_string(&con.json).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(&serialized).unwrap(), con.json);
+    }
+
+    pub fn test_max_state_size() {
+        let mut con = ContractState::new(b"Enigma".sha256());
+        assert_eq!(max_state_size(), DEFAULT_MAX_STATE_SIZE);
+        con.write_key("large", &json!("enigma".repeat(200))).unwrap();
+        con.check_size().unwrap();
+
+        set_max_state_size(1000);
+        let over = con.check_size();
+        con.remove_key("large");
+        let under = con.check_size();
+        set_max_state_size(DEFAULT_MAX_STATE_SIZE);
+
+        match over {
+            Err(FailedTaskError(StateTooLarge { size, max_size })) => assert_eq!((size, max_size), (1212, 1000)),
+            other => panic!("Expected a StateTooLarge, got: {:?}", other),
+        }
+        under.unwrap();
+    }
+
+    pub fn test_epoch_keys() {
+        let vector = load_patch();
+        let (epoch_a, epoch_b) = ([1u8; 32], [2u8; 32]);
+        let enc_a = vector.patch().encrypt_for_epoch(&vector.key, epoch_a, Some(vector.iv)).unwrap();
+        let enc_b = vector.patch().encrypt_for_epoch(&vector.key, epoch_b, Some(vector.iv)).unwrap();
+        assert_eq!(enc_a.epoch, Some(epoch_a));
+        assert_ne!(enc_a.ciphertext(), enc_b.ciphertext());
+        assert_ne!(enc_a.ciphertext(), &vector.ciphertext[..]);
+
+        // Decrypting with the state key derives the key of the recorded epoch.
+        assert_eq!(StatePatch::decrypt(enc_a.clone(), &vector.key).unwrap(), vector.patch());
+        assert_eq!(StatePatch::decrypt(enc_b.clone(), &vector.key).unwrap(), vector.patch());
+
+        // Each patch only decrypts with the key of its own epoch.
+        let (key_a, key_b) = (epoch_key(&vector.key, &epoch_a), epoch_key(&vector.key, &epoch_b));
+        assert!(symmetric::decrypt(enc_a.ciphertext(), &key_a).is_ok());
+        assert!(symmetric::decrypt(enc_a.ciphertext(), &key_b).is_err());
+        assert!(symmetric::decrypt(enc_b.ciphertext(), &key_b).is_ok());
+        assert!(symmetric::decrypt(enc_b.ciphertext(), &key_a).is_err());
+        assert!(symmetric::decrypt(enc_a.ciphertext(), &vector.key).is_err());
+        assert!(StatePatch::decrypt(EncryptedPatch { epoch: Some(epoch_b), ..enc_a.clone() }, &vector.key).is_err());
+
+        // The epoch is read back from the stored data, a patch without one stays without one.
+        let stored = EncryptedPatch::from_stored(enc_a.data.clone(), enc_a.contract_address, enc_a.index);
+        assert_eq!(stored, enc_a);
+        assert_eq!(StatePatch::decrypt(stored, &vector.key).unwrap(), vector.patch());
+        let plain = EncryptedPatch::from_stored(vector.ciphertext.clone(), vector.contract_address, vector.index);
+        assert_eq!(plain, vector.encrypted_patch());
+    }
+
+    pub fn test_export_import_state() {
+        let vector = load_state();
+        let mut state = vector.state();
+        state.delta_hash = b"delta".sha256();
+        state.delta_index = 7;
+        let blob = state.export(&vector.key).unwrap();
+
+        // The destination starts without the state, it only needs the address and the state key.
+        let imported = ContractState::from_export(&blob, vector.contract_address, &vector.key).unwrap();
+        assert_eq!(imported, state);
+        assert_eq!(imported.delta_index, 7);
+        assert_eq!(imported.delta_hash, b"delta".sha256());
+
+        match ContractState::from_export(&blob, b"Other".sha256(), &vector.key) {
+            Err(SystemError(StateError { .. })) => (),
+            other => panic!("Expected a StateError, got: {:?}", other),
+        }
+        assert!(ContractState::from_export(&blob, vector.contract_address, &b"Wrong key".sha256()).is_err());
+    }
+
     pub fn test_diff_patch() {
         let before = json!({ "title": "Goodbye!","author" : { "name1" : "John", "name2" : "Doe"}, "tags":[ "first", "second" ] });
         let after = json!({ "author" : {"name1" : "John", "name2" : "Lennon"},"tags": [ "first", "second", "third"] });
@@ -103,6 +275,41 @@ pub mod tests {
         assert_eq!(serde_json::to_string(&patch.patch).unwrap(), "[{\"op\":\"replace\",\"path\":\"/author/name2\",\"value\":\"Lennon\"},{\"op\":\"add\",\"path\":\"/tags/2\",\"value\":\"third\"},{\"op\":\"remove\",\"path\":\"/title\"}]");
     }
 
+    pub fn test_export_delta() {
+        let before = json!({ "title": "Goodbye!","author" : { "name1" : "John", "name2" : "Doe"}, "tags":[ "first", "second" ] });
+        let after = json!({ "author" : {"name1" : "John", "name2" : "Lennon"},"tags": [ "first", "second", "third"] });
+        let patch =
+            StatePatch { patch: json_patch::diff(&before, &after), previous_hash: [7u8; 32].into(), contract_address: [1u8; 32].into(), index: 3 };
+
+        let export = patch.to_export().unwrap();
+        let envelope: Value = serde_json::from_slice(&export).unwrap();
+        assert_eq!(envelope["version"], json!(DELTA_EXPORT_VERSION));
+        assert_eq!(envelope["contractId"], json!("01".repeat(32)));
+        assert_eq!(envelope["index"], json!(3));
+        assert_eq!(envelope["previousHash"], json!("07".repeat(32)));
+        assert_eq!(serde_json::to_string(&envelope["patch"]).unwrap(), "[{\"op\":\"replace\",\"path\":\"/author/name2\",\"value\":\"Lennon\"},{\"op\":\"add\",\"path\":\"/tags/2\",\"value\":\"third\"},{\"op\":\"remove\",\"path\":\"/title\"}]");
+        assert_eq!(StatePatch::from_export(&export).unwrap(), patch);
+
+        // Through the encrypted form, like a delta from the DB.
+        let key = b"EnigmaMPC".sha256();
+        let export = export_delta(patch.clone().encrypt(&key).unwrap(), &key).unwrap();
+        assert_eq!(StatePatch::decrypt(import_delta(&export, &key).unwrap(), &key).unwrap(), patch);
+        // A delta encrypted for an epoch is imported for the same epoch.
+        let for_epoch = patch.clone().encrypt_for_epoch(&key, [9u8; 32], None).unwrap();
+        let export = export_delta(for_epoch, &key).unwrap();
+        assert_eq!(serde_json::from_slice::<Value>(&export).unwrap()["epoch"], json!("09".repeat(32)));
+        let imported = import_delta(&export, &key).unwrap();
+        assert_eq!(imported.epoch, Some([9u8; 32]));
+        assert_eq!(StatePatch::decrypt(imported, &key).unwrap(), patch);
+
+        let mut other_version = envelope.clone();
+        other_version["version"] = json!(DELTA_EXPORT_VERSION + 1);
+        assert!(StatePatch::from_export(&serde_json::to_vec(&other_version).unwrap()).is_err());
+        let mut bad_hash = envelope;
+        bad_hash["previousHash"] = json!("07");
+        assert!(StatePatch::from_export(&serde_json::to_vec(&bad_hash).unwrap()).is_err());
+    }
+
     pub fn test_encrypt_patch() {
         let s = "[{\"op\":\"replace\",\"path\":\"/author/name2\",\"value\":\"Lennon\"},{\"op\":\"add\",\"path\":\"/tags/2\",\"value\":\"third\"},{\"op\":\"remove\",\"path\":\"/title\"}]";
         let contract_address: ContractAddress = [1u8; 32].into();
@@ -113,7 +320,7 @@ pub mod tests {
         let iv = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
 
         let enc_data = vec![196, 39, 143, 237, 10, 117, 249, 235, 174, 84, 130, 219, 214, 92, 182, 148, 87, 171, 131, 69, 32, 201, 192, 190, 253, 176, 230, 5, 20, 221, 171, 31, 37, 51, 29, 231, 134, 147, 234, 255, 104, 144, 161, 110, 192, 28, 187, 143, 184, 188, 211, 219, 36, 117, 28, 51, 160, 204, 97, 250, 153, 193, 86, 194, 169, 111, 124, 202, 195, 44, 170, 109, 98, 164, 203, 177, 27, 246, 129, 8, 132, 12, 232, 104, 130, 98, 155, 7, 137, 89, 113, 187, 197, 211, 191, 246, 97, 112, 71, 240, 162, 35, 176, 216, 26, 97, 90, 218, 197, 244, 94, 225, 184, 235, 75, 198, 205, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
-        let enc_patch = EncryptedPatch { data: enc_data, contract_address, index };
+        let enc_patch = EncryptedPatch { data: enc_data, contract_address, index, epoch: None };
         let a = patch.encrypt_with_nonce(&key, Some(iv)).unwrap();
         assert_eq!(a, enc_patch)
     }
@@ -126,11 +333,36 @@ pub mod tests {
         let key = b"EnigmaMPC".sha256();
         let enc_data = vec![196, 39, 143, 237, 10, 117, 249, 235, 174, 84, 130, 219, 214, 92, 182, 148, 87, 171, 131, 69, 32, 201, 192, 190, 253, 176, 230, 5, 20, 221, 171, 31, 37, 51, 29, 231, 134, 147, 234, 255, 104, 144, 161, 110, 192, 28, 187, 143, 184, 188, 211, 219, 36, 117, 28, 51, 160, 204, 97, 250, 153, 193, 86, 194, 169, 111, 124, 202, 195, 44, 170, 109, 98, 164, 203, 177, 27, 246, 129, 8, 132, 12, 232, 104, 130, 98, 155, 7, 137, 89, 113, 187, 197, 211, 191, 246, 97, 112, 71, 240, 162, 35, 176, 216, 26, 97, 90, 218, 197, 244, 94, 225, 184, 235, 75, 198, 205, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
 
-        let enc_patch = EncryptedPatch { data: enc_data, contract_address, index: 0 };
+        let enc_patch = EncryptedPatch { data: enc_data, contract_address, index: 0, epoch: None };
         let dec = StatePatch::decrypt(enc_patch, &key).unwrap();
         assert_eq!(patch, dec)
     }
 
+    pub fn test_canonical_json() {
+        use enigma_crypto::hash::Keccak256;
+        // The same state, with other key order, whitespace and number forms.
+        let first: Value = serde_json::from_str(r#"{"b":{"y":[1,2.0,-0.0],"x":"a\u0062"},"a":1.5,"c":null}"#).unwrap();
+        let second: Value = serde_json::from_str("{ \"a\": 1.5, \"c\": null,\n  \"b\": { \"x\": \"ab\", \"y\": [1.0, 2, 0] } }").unwrap();
+        let canonical = to_canonical_json(&first).unwrap();
+        assert_eq!(String::from_utf8(canonical.clone()).unwrap(), r#"{"a":1.5,"b":{"x":"ab","y":[1,2,0]},"c":null}"#);
+        assert_eq!(canonical.keccak256(), to_canonical_json(&second).unwrap().keccak256());
+
+        let different: Value = serde_json::from_str(r#"{"a":1.5,"b":{"x":"ab","y":[1,2,1]},"c":null}"#).unwrap();
+        assert_ne!(canonical.keccak256(), to_canonical_json(&different).unwrap().keccak256());
+    }
+
+    pub fn test_peek_encrypted_patch() {
+        let s = "[{\"op\":\"replace\",\"path\":\"/author/name2\",\"value\":\"Lennon\"},{\"op\":\"add\",\"path\":\"/tags/2\",\"value\":\"third\"},{\"op\":\"remove\",\"path\":\"/title\"}]";
+        let contract_address: ContractAddress = b"peek".sha256();
+        let patch = StatePatch { patch: serde_json::from_str(s).unwrap(), previous_hash: [0u8; 32].into(), contract_address, index: 17 };
+        let enc = patch.encrypt(&b"EnigmaMPC".sha256()).unwrap();
+
+        let mut bytes = Vec::new();
+        enc.serialize(&mut Serializer::new(&mut bytes)).unwrap();
+        assert_eq!(EncryptedPatch::peek(&bytes).unwrap(), (contract_address, 17));
+        assert!(EncryptedPatch::peek(&bytes[..10]).is_err());
+    }
+
     pub fn test_encrypt_decrypt_patch() {
         let s = "[{\"op\":\"replace\",\"path\":\"/author/name2\",\"value\":\"Lennon\"},{\"op\":\"add\",\"path\":\"/tags/2\",\"value\":\"third\"},{\"op\":\"remove\",\"path\":\"/title\"}]";
         let patch = StatePatch { patch: serde_json::from_str(s).unwrap(), previous_hash: [0u8; 32].into(), contract_address: [1u8; 32].into(), index: 0 };
@@ -189,4 +421,114 @@ pub mod tests {
         let delta = StatePatch::decrypt(delta, &key).unwrap();
         assert_eq!(delta, result);
     }
+
+    /// A small xorshift generator, so the random cases are reproducible from their seed.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize { (self.next() % n as u64) as usize }
+    }
+
+    fn random_json(rng: &mut XorShift, depth: usize) -> Value {
+        match rng.below(if depth == 0 { 4 } else { 6 }) {
+            0 => Value::Null,
+            1 => json!(rng.below(2) == 0),
+            2 => json!(rng.below(1000)),
+            3 => json!(format!("s{}", rng.below(100))),
+            4 => Value::Array((0..rng.below(5)).map(|_| random_json(rng, depth - 1)).collect()),
+            _ => Value::Object((0..rng.below(5)).map(|_| (format!("k{}", rng.below(8)), random_json(rng, depth - 1))).collect()),
+        }
+    }
+
+    /// Randomly inserts, removes or replaces something in `value` or in one of its children.
+    fn mutate_json(rng: &mut XorShift, value: &mut Value) {
+        let action = rng.below(4);
+        let mutated = match value {
+            Value::Array(array) => match action {
+                0 if !array.is_empty() => {
+                    let index = rng.below(array.len());
+                    array.remove(index);
+                    true
+                }
+                1 => {
+                    let index = rng.below(array.len() + 1);
+                    array.insert(index, random_json(rng, 2));
+                    true
+                }
+                2 if !array.is_empty() => {
+                    let index = rng.below(array.len());
+                    mutate_json(rng, &mut array[index]);
+                    true
+                }
+                _ => false,
+            },
+            Value::Object(map) => match action {
+                0 if !map.is_empty() => {
+                    let key = map.keys().nth(rng.below(map.len())).cloned().unwrap();
+                    map.remove(&key);
+                    true
+                }
+                1 => {
+                    let key = format!("k{}", rng.below(8));
+                    map.insert(key, random_json(rng, 2));
+                    true
+                }
+                2 if !map.is_empty() => {
+                    let index = rng.below(map.len());
+                    mutate_json(rng, map.values_mut().nth(index).unwrap());
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        if !mutated {
+            *value = random_json(rng, 2);
+        }
+    }
+
+    fn remove_and_insert(rng: &mut XorShift, array: &mut Vec<Value>) {
+        let index = rng.below(array.len());
+        array.remove(index);
+        let index = rng.below(array.len() + 1);
+        let element = random_json(rng, 2);
+        array.insert(index, element);
+    }
+
+    pub fn test_generate_apply_delta_round_trip() {
+        let contract_address = b"Enigma".sha256();
+        let key = [1u8; 32];
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        for case in 0..64 {
+            let mut json = random_json(&mut rng, 3);
+            if !json.is_object() {
+                json = json!({ "value": json });
+            }
+            // Every case shifts the indices of an array, that's where json_patch diffs tend to go wrong.
+            json["array"] = Value::Array((0..3 + rng.below(6)).map(|_| random_json(&mut rng, 2)).collect());
+            let before = ContractState { contract_address, json, delta_hash: [4u8; 32].into(), delta_index: 0 };
+
+            let mut after = before.clone();
+            remove_and_insert(&mut rng, after.json["array"].as_array_mut().unwrap());
+            for _ in 0..1 + rng.below(5) {
+                mutate_json(&mut rng, &mut after.json);
+            }
+            if !after.json.is_object() {
+                after.json = json!({ "value": after.json });
+            }
+
+            let delta = ContractState::generate_delta_and_update_state(&before, &mut after, &key).unwrap();
+            let mut applied = before.clone();
+            applied.apply_delta(delta, &key).unwrap();
+            assert_eq!(applied.json, after.json, "case {}: from {} to {}", case, before.json, after.json);
+            assert_eq!(applied.delta_hash, after.delta_hash, "case {}", case);
+        }
+    }
 }
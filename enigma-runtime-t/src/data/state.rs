@@ -1,15 +1,101 @@
 use crate::data::{DeltasInterface, IOInterface, StatePatch};
-use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::*, EnclaveSystemError::*};
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::*, EnclaveSystemError::*, FailedTaskError};
 use enigma_types::{ContractAddress, StateKey};
 use enigma_crypto::{symmetric, Encryption};
 use enigma_types::Hash256;
 use json_patch;
 use rmps::{Deserializer, Serializer};
 use serde::{Deserialize, Serialize};
-use serde_json::{from_value, Error, Value};
-use std::string::ToString;
+use serde_json::{from_value, Value};
+use std::string::{String, ToString};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::vec::Vec;
 use data::EncryptedPatch;
+use native_stack;
+
+/// The largest integer a JSON number keeps exactly when it's read as a double (2^53 - 1).
+pub const MAX_SAFE_INTEGER: u64 = (1 << 53) - 1;
+
+/// The deepest nesting of arrays and objects the serde_json parser accepts.
+const SERDE_JSON_RECURSION_LIMIT: usize = 128;
+
+/// The default of the deepest nesting of arrays and objects a value in the state may have.
+/// The value is nested in the state object, so the whole state stays within the recursion limit of the serde_json parser.
+pub const DEFAULT_MAX_JSON_DEPTH: usize = SERDE_JSON_RECURSION_LIMIT - 1;
+
+static MAX_JSON_DEPTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_JSON_DEPTH);
+
+/// Sets the deepest nesting of arrays and objects that `write_key` accepts and `read_key` returns.
+pub fn set_max_json_depth(depth: usize) { MAX_JSON_DEPTH.store(depth, Ordering::SeqCst) }
+
+pub fn max_json_depth() -> usize { MAX_JSON_DEPTH.load(Ordering::SeqCst) }
+
+/// The default of the largest serialized state a contract may have, 0 for no limit.
+pub const DEFAULT_MAX_STATE_SIZE: usize = 0;
+
+static MAX_STATE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_STATE_SIZE);
+
+/// Sets the largest serialized state (in bytes) that `ContractState::check_size` accepts, 0 for no limit.
+pub fn set_max_state_size(size: usize) { MAX_STATE_SIZE.store(size, Ordering::SeqCst) }
+
+pub fn max_state_size() -> usize { MAX_STATE_SIZE.load(Ordering::SeqCst) }
+
+/// Fails if `value` nests arrays and objects deeper than `max_json_depth()`, a scalar has a depth of 0.
+/// The walk stops as soon as it's past the limit, so its own recursion is bounded by the limit and not by the value.
+fn check_json_depth(value: &Value) -> Result<(), EnclaveError> {
+    fn exceeds(value: &Value, remaining: usize) -> bool {
+        match value {
+            Value::Array(arr) => remaining == 0 || arr.iter().any(|v| exceeds(v, remaining - 1)),
+            Value::Object(obj) => remaining == 0 || obj.values().any(|v| exceeds(v, remaining - 1)),
+            _ => false,
+        }
+    }
+    let max_depth = max_json_depth();
+    if exceeds(value, max_depth) {
+        return Err(EnclaveError::FailedTaskError(FailedTaskError::JsonTooDeep { max_depth }));
+    }
+    Ok(())
+}
+
+/// The only key of the object a big integer is stored as, e.g. `{"$bigint": "18446744073709551615"}`.
+pub const BIG_INTEGER_TAG: &str = "$bigint";
+
+/// Integers that a double can't hold exactly are stored as their decimal string tagged with `BIG_INTEGER_TAG`,
+/// so whoever reads the state as plain JSON (where every number is a double) doesn't silently lose precision.
+/// The tag keeps them apart from the strings the contract wrote, which are never turned into numbers.
+/// Recursing on a value the contract wrote is bounded by the native stack budget of the execution (see `native_stack`).
+pub(super) fn preserve_big_integers(value: &mut Value) -> Result<(), EnclaveError> {
+    native_stack::check()?;
+    let replacement = match value {
+        Value::Number(n) if n.as_u64().map_or(false, |n| n > MAX_SAFE_INTEGER)
+            || n.as_i64().map_or(false, |n| n < -(MAX_SAFE_INTEGER as i64)) => json!({ BIG_INTEGER_TAG: n.to_string() }),
+        Value::Array(arr) => return arr.iter_mut().try_for_each(preserve_big_integers),
+        Value::Object(obj) => return obj.iter_mut().try_for_each(|(_, v)| preserve_big_integers(v)),
+        _ => return Ok(()),
+    };
+    *value = replacement;
+    Ok(())
+}
+
+/// The reverse of `preserve_big_integers`, only the tagged integers are turned back into numbers.
+fn restore_big_integers(value: &mut Value) -> Result<(), EnclaveError> {
+    native_stack::check()?;
+    let replacement: Value = match value {
+        Value::Object(obj) if obj.len() == 1 && obj.contains_key(BIG_INTEGER_TAG) => {
+            let tagged = obj[BIG_INTEGER_TAG].as_str().unwrap_or_default();
+            match (tagged.parse::<u64>(), tagged.parse::<i64>()) {
+                (Ok(n), _) => n.into(),
+                (_, Ok(n)) => n.into(),
+                _ => return Err(SystemError(StateError { err: format!("'{}' isn't a tagged integer", tagged) })),
+            }
+        }
+        Value::Array(arr) => return arr.iter_mut().try_for_each(restore_big_integers),
+        Value::Object(obj) => return obj.iter_mut().try_for_each(|(_, v)| restore_big_integers(v)),
+        _ => return Ok(()),
+    };
+    *value = replacement;
+    Ok(())
+}
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
 pub struct ContractState {
@@ -20,6 +106,14 @@ pub struct ContractState {
     pub delta_index: u32,
 }
 
+/// The plaintext of an exported state, unlike `ContractState` it carries the contract address,
+/// so the blob can be checked against the contract it's imported into.
+#[derive(Serialize, Deserialize)]
+struct StateExport {
+    contract_address: ContractAddress,
+    state: ContractState,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct EncryptedContractState<T> {
     pub contract_address: ContractAddress,
@@ -35,16 +129,75 @@ impl ContractState {
     pub fn is_initial(&self) -> bool{
         self.delta_index == 0 && self.delta_hash.is_zero()
     }
+
+    /// Fails if the JSON of the state serializes to more than `max_state_size()` bytes.
+    pub fn check_size(&self) -> Result<(), EnclaveError> {
+        let max_size = max_state_size();
+        if max_size == 0 {
+            return Ok(());
+        }
+        let size = serde_json::to_vec(&self.json).map_err(|e| SystemError(StateError { err: e.to_string() }))?.len();
+        if size > max_size {
+            return Err(EnclaveError::FailedTaskError(FailedTaskError::StateTooLarge { size, max_size }));
+        }
+        Ok(())
+    }
+
+    /// Export the full state (json, `delta_index` and `delta_hash`) and the contract address
+    /// as a single encrypted blob, for migrating a contract to another node without replaying its deltas.
+    pub fn export(&self, key: &StateKey) -> Result<Vec<u8>, EnclaveError> {
+        let export = StateExport { contract_address: self.contract_address, state: self.clone() };
+        let mut buf = Vec::new();
+        export.serialize(&mut Serializer::new(&mut buf))?;
+        Ok(symmetric::encrypt(&buf, key)?)
+    }
+
+    /// Decodes a blob produced by `export`, checking it belongs to `contract_address` and that its delta metadata is consistent.
+    /// It doesn't store anything, the enclave installs the state it returns (see `km_t::import_state`).
+    pub fn from_export(blob: &[u8], contract_address: ContractAddress, key: &StateKey) -> Result<ContractState, EnclaveError> {
+        let dec = symmetric::decrypt(blob, key)?;
+        let mut des = Deserializer::new(&dec[..]);
+        let export: StateExport = Deserialize::deserialize(&mut des)?;
+        if export.contract_address != contract_address {
+            return Err(SystemError(StateError {
+                err: format!("The exported state belongs to {:?} and not to {:?}", export.contract_address, contract_address)
+            }));
+        }
+        if export.state.delta_hash.is_zero() && export.state.delta_index != 0 {
+            return Err(SystemError(StateError {
+                err: format!("The exported state has no delta hash but is at delta index {}", export.state.delta_index)
+            }));
+        }
+        if !export.state.json.is_object() {
+            return Err(SystemError(StateError { err: "The exported state isn't a json object".to_string() }));
+        }
+        Ok(ContractState { contract_address, ..export.state })
+    }
+}
+
+impl ContractState {
+    /// The value of `key` as the contract wrote it, with its big integers restored (see `preserve_big_integers`).
+    pub fn read_value(&self, key: &str) -> Result<Value, EnclaveError> {
+        check_json_depth(&self.json[key])?;
+        let mut value = self.json[key].clone();
+        restore_big_integers(&mut value)?;
+        Ok(value)
+    }
 }
 
 impl IOInterface<EnclaveError, u8> for ContractState {
-    fn read_key<T>(&self, key: &str) -> Result<T, Error>
+    fn read_key<T>(&self, key: &str) -> Result<T, EnclaveError>
     where for<'de> T: Deserialize<'de> {
-        from_value(self.json[key].clone())
+        from_value(self.read_value(key)?).map_err(|e| SystemError(StateError {
+            err: format!("the value of '{}' can't be decoded as the requested type: {}", key, e)
+        }))
     }
 
     fn write_key(&mut self, key: &str, value: &Value) -> Result<(), EnclaveError> {
-        self.json[key] = value.clone();
+        check_json_depth(value)?;
+        let mut value = value.clone();
+        preserve_big_integers(&mut value)?;
+        self.json[key] = value;
         Ok(())
     }
 
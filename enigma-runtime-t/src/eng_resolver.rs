@@ -2,7 +2,10 @@
 /// This is Enigma glue for wasmi interpreter
 extern crate wasmi;
 use std::borrow::ToOwned;
+use std::boxed::Box;
 use std::cell::RefCell;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError, WasmError};
+use enigma_types::HostCapabilities;
 
 pub use wasmi::{memory_units, Error, FuncInstance, FuncRef, MemoryDescriptor, MemoryInstance, MemoryRef, ModuleImportResolver, Signature};
 
@@ -22,12 +25,17 @@ pub mod ids {
     pub const RAND_FUNC: usize = 15;
     pub const ENCRYPT_FUNC: usize = 16;
     pub const DECRYPT_FUNC: usize = 17;
+    pub const TRUE_RAND_FUNC: usize = 18;
+    pub const REVERT_FUNC: usize = 19;
+    pub const CALLER_FUNC: usize = 20;
+    pub const NEXT_COUNTER_FUNC: usize = 21;
 }
 
 pub mod signatures {
     use wasmi::ValueType::*;
     use wasmi::{self, ValueType};
 
+    #[derive(Clone, Copy)]
     pub struct StaticSignature(pub &'static [ValueType], pub Option<ValueType>);
 
     pub const RET: StaticSignature = StaticSignature(&[I32, I32], None);
@@ -60,11 +68,23 @@ pub mod signatures {
 
     pub const DECRYPT: StaticSignature = StaticSignature(&[I32, I32, I32, I32], None);
 
+    pub const REVERT: StaticSignature = StaticSignature(&[I32, I32], None);
+
+    pub const CALLER: StaticSignature = StaticSignature(&[I32], None);
+
+    pub const NEXT_COUNTER: StaticSignature = StaticSignature(&[I32], None);
+
     impl Into<wasmi::Signature> for StaticSignature {
         fn into(self) -> wasmi::Signature { wasmi::Signature::new(self.0, self.1) }
     }
 }
 
+/// The size of a WASM memory page (64KB).
+pub const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+/// The most pages a 32 bit WASM memory can have (4GB).
+pub const WASM_MAX_PAGES: u32 = 65536;
+
 /// Import resolver for wasmi
 /// Maps all functions that runtime support to the corresponding contract import
 /// entries.
@@ -73,11 +93,21 @@ pub mod signatures {
 pub struct ImportResolver {
     max_memory: u32,
     memory: RefCell<Option<MemoryRef>>,
+    capabilities: HostCapabilities,
 }
 
 impl ImportResolver {
     /// New import resolver with specifed maximum amount of inital memory (in wasm pages = 64kb)
-    pub fn with_limit(max_memory: u32) -> ImportResolver { ImportResolver { max_memory, memory: RefCell::new(None) } }
+    pub fn with_limit(max_memory: u32) -> ImportResolver {
+        ImportResolver { max_memory, memory: RefCell::new(None), capabilities: HostCapabilities::default() }
+    }
+
+    /// Only resolve the host functions that are allowed by `capabilities`,
+    /// importing any other one fails the instantiation of the module.
+    pub fn with_capabilities(mut self, capabilities: HostCapabilities) -> ImportResolver {
+        self.capabilities = capabilities;
+        self
+    }
 
     /// Returns memory that was instantiated during the contract module
     /// start. If contract does not use memory at all, the dummy memory of length (0, 0)
@@ -99,28 +129,55 @@ impl ImportResolver {
 
     /// Returns memory size module initially requested
     pub fn memory_size(&self) -> Result<u32, Error> { Ok(self.memory_ref().current_size().0 as u32) }
+
+    /// Returns the peak size of the memory in bytes,
+    /// WASM memory can only grow so this is the size it reached by the end of the execution.
+    pub fn peak_memory_bytes(&self) -> u64 {
+        self.memory_ref().current_size().0 as u64 * WASM_PAGE_SIZE
+    }
 }
 
+/// The host functions a contract can import from the `env` module, with their signatures and ids.
+/// The resolver only resolves the functions in here, so this is also the list of the imports a contract may have.
+pub const HOST_FUNCTIONS: [(&str, signatures::StaticSignature, usize); 19] = [
+    ("ret", signatures::RET, ids::RET_FUNC),
+    ("write_state", signatures::WRITE_STATE, ids::WRITE_STATE_FUNC),
+    ("read_state_len", signatures::READ_STATE_LEN, ids::READ_STATE_LEN_FUNC),
+    ("read_state", signatures::READ_STATE, ids::READ_STATE_FUNC),
+    ("remove_from_state", signatures::REMOVE_STATE, ids::REMOVE_STATE_FUNC),
+    ("eprint", signatures::EPRINT, ids::EPRINT_FUNC),
+    ("fetch_function_name_length", signatures::NAME_LENGTH, ids::NAME_LENGTH_FUNC),
+    ("fetch_function_name", signatures::NAME, ids::NAME_FUNC),
+    ("fetch_args_length", signatures::ARGS_LENGTH, ids::ARGS_LENGTH_FUNC),
+    ("fetch_args", signatures::ARGS, ids::ARGS_FUNC),
+    ("write_eth_bridge", signatures::WRITE_ETH_BRIDGE, ids::WRITE_ETH_BRIDGE_FUNC),
+    ("gas", signatures::GAS, ids::GAS_FUNC),
+    ("rand", signatures::RAND, ids::RAND_FUNC),
+    ("true_rand", signatures::RAND, ids::TRUE_RAND_FUNC),
+    ("encrypt", signatures::ENCRYPT, ids::ENCRYPT_FUNC),
+    ("decrypt", signatures::DECRYPT, ids::DECRYPT_FUNC),
+    ("revert", signatures::REVERT, ids::REVERT_FUNC),
+    ("get_caller", signatures::CALLER, ids::CALLER_FUNC),
+    ("next_counter", signatures::NEXT_COUNTER, ids::NEXT_COUNTER_FUNC),
+];
+
+/// Whether `name` is a host function a contract can import (see `HOST_FUNCTIONS`).
+pub fn is_host_function(name: &str) -> bool { HOST_FUNCTIONS.iter().any(|(host_name, _, _)| *host_name == name) }
+
 impl ModuleImportResolver for ImportResolver {
     fn resolve_func(&self, field_name: &str, _signature: &Signature) -> Result<FuncRef, Error> {
-        let (signature, id) = match field_name {
-            "ret" => (signatures::RET, ids::RET_FUNC),
-            "write_state" => (signatures::WRITE_STATE, ids::WRITE_STATE_FUNC),
-            "read_state_len" => (signatures::READ_STATE_LEN, ids::READ_STATE_LEN_FUNC),
-            "read_state" => (signatures::READ_STATE, ids::READ_STATE_FUNC),
-            "remove_from_state" => (signatures::REMOVE_STATE, ids::REMOVE_STATE_FUNC),
-            "eprint" => (signatures::EPRINT, ids::EPRINT_FUNC),
-            "fetch_function_name_length" => (signatures::NAME_LENGTH, ids::NAME_LENGTH_FUNC),
-            "fetch_function_name" => (signatures::NAME, ids::NAME_FUNC),
-            "fetch_args_length" => (signatures::ARGS_LENGTH, ids::ARGS_LENGTH_FUNC),
-            "fetch_args" => (signatures::ARGS, ids::ARGS_FUNC),
-            "write_eth_bridge" => (signatures::WRITE_ETH_BRIDGE, ids::WRITE_ETH_BRIDGE_FUNC),
-            "gas" => (signatures::GAS, ids::GAS_FUNC),
-            "rand" => (signatures::RAND, ids::RAND_FUNC),
-            "encrypt" => (signatures::ENCRYPT, ids::ENCRYPT_FUNC),
-            "decrypt" => (signatures::DECRYPT, ids::DECRYPT_FUNC),
-            _ => return Err(wasmi::Error::Instantiation(format!("Export {} not found", field_name))),
+        let (signature, id) = match HOST_FUNCTIONS.iter().find(|(name, _, _)| *name == field_name) {
+            Some((_, signature, id)) => (*signature, *id),
+            None => return Err(wasmi::Error::Instantiation(format!("Export {} not found", field_name))),
+        };
+        let required = match id {
+            ids::RAND_FUNC | ids::TRUE_RAND_FUNC => HostCapabilities::RANDOMNESS,
+            ids::WRITE_ETH_BRIDGE_FUNC => HostCapabilities::ETHEREUM_BRIDGE,
+            _ => HostCapabilities::NONE,
         };
+        if !self.capabilities.contains(required) {
+            return Err(wasmi::Error::Instantiation(format!("Import {} is forbidden for this contract", field_name)));
+        }
 
         let func_ref = FuncInstance::alloc_host(signature.into(), id);
         Ok(func_ref)
@@ -130,7 +187,10 @@ impl ModuleImportResolver for ImportResolver {
         if field_name == "memory" {
             let effective_max = descriptor.maximum().unwrap_or(self.max_memory + 1);
             if descriptor.initial() > self.max_memory || effective_max > self.max_memory {
-                Err(Error::Instantiation("Module requested too much memory".to_owned()))
+                // Without a maximum the memory may grow as far as WASM allows.
+                let requested = descriptor.maximum().unwrap_or(WASM_MAX_PAGES).max(descriptor.initial());
+                let err = FailedTaskError::MemoryLimitExceeded { requested, allowed: self.max_memory };
+                Err(Error::Host(Box::new(WasmError::EnclaveError(EnclaveError::FailedTaskError(err)))))
             } else {
                 let mem = MemoryInstance::alloc(
                     memory_units::Pages(descriptor.initial() as usize),
@@ -1,5 +1,6 @@
 
 pub use pwasm_utils::{inject_gas_counter, rules};
+use enigma_types::GasMultiplier;
 
 /// Wasm cost table
 pub struct WasmCosts {
@@ -48,6 +49,20 @@ impl Default for WasmCosts {
     }
 }
 
+impl WasmCosts {
+    /// The costs of a contract with its own price, scales the costs `gas_rules` charges for the instructions.
+    pub fn with_multiplier(self, multiplier: GasMultiplier) -> Self {
+        WasmCosts {
+            regular: multiplier.apply(self.regular),
+            div: multiplier.apply(self.div),
+            mul: multiplier.apply(self.mul),
+            mem: multiplier.apply(self.mem),
+            grow_mem: multiplier.apply(self.grow_mem),
+            ..self
+        }
+    }
+}
+
 pub fn gas_rules(wasm_costs: &WasmCosts) -> rules::Set {
     rules::Set::new(wasm_costs.regular, {
         let mut vals = ::std::collections::BTreeMap::new();
@@ -67,6 +82,12 @@ pub struct RuntimeWasmCosts {
     pub write_additional_byte: u64,
     pub deploy_byte: u64,
     pub execution: u64,
+    /// Gas charged for every call to `rand` or `true_rand`, randomness isn't free even though it runs no contract code.
+    pub rand: u64,
+    /// Gas charged for every byte `rand` or `true_rand` generates, on top of `rand`.
+    pub rand_byte: u64,
+    /// The refund for the bytes freed from the state is capped at `counter / max_refund_quotient`, a quotient of 0 disables it.
+    pub max_refund_quotient: u64,
 }
 
 impl Default for RuntimeWasmCosts {
@@ -76,10 +97,20 @@ impl Default for RuntimeWasmCosts {
             write_additional_byte: 1,
             deploy_byte: 1,
             execution: 10_000,
+            rand: 500,
+            rand_byte: 1,
+            max_refund_quotient: 2,
         }
     }
 }
 
+impl RuntimeWasmCosts {
+    /// The gas a call to `rand` or `true_rand` that generates `len` bytes costs.
+    pub fn rand_cost(&self, len: u32) -> u64 {
+        self.rand.saturating_add(self.rand_byte.saturating_mul(u64::from(len)))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RuntimeGas {
     pub counter: u64,
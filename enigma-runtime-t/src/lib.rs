@@ -18,6 +18,7 @@ extern crate rmp_serde as rmps;
 extern crate serde;
 extern crate wasmi;
 extern crate parity_wasm;
+extern crate rustc_hex;
 /// This module builds Wasm code for contract deployment from the Wasm contract.
 /// The contract should be written in rust and then compiled to Wasm with wasm32-unknown-unknown target.
 /// The code is based on Parity wasm_utils::cli.
@@ -25,19 +26,23 @@ extern crate pwasm_utils;
 
 use crate::data::{ContractState, DeltasInterface, IOInterface, EncryptedPatch};
 use enigma_types::{StateKey, SymmetricKey, SYMMETRIC_KEY_SIZE};
-use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::*, EnclaveSystemError::*, WasmError};
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::*, FailedTaskError::{InputError, Reverted}, WasmError};
 
-use std::{str, vec::Vec};
+use std::{cmp, str, vec::Vec};
 use std::string::{String, ToString};
 use wasmi::{MemoryRef, RuntimeArgs, RuntimeValue};
-use sgx_trts::trts::rsgx_read_rand;
-use enigma_crypto::symmetric::{encrypt, decrypt};
+use enigma_crypto::rand::{Rng, RngSource};
+use enigma_crypto::symmetric::{encrypt, decrypt, derive_key};
+use enigma_crypto::hash::{prepare_hash_multiple, Keccak256};
 
 pub mod data;
 pub mod eng_resolver;
 pub mod ocalls_t;
 pub mod gas;
+pub mod native_stack;
 pub mod wasm_execution;
+#[cfg(debug_assertions)]
+pub mod test_vectors;
 
 pub use gas::*;
 
@@ -54,6 +59,12 @@ pub struct RuntimeResult {
     pub result: Vec<u8>,
     pub ethereum_bridge: Option<EthereumData>,
     pub used_gas: u64,
+    /// The gas charged for storing the deployed bytecode, this is 0 for computations.
+    pub storage_gas: u64,
+    /// The largest the WASM memory of the contract got during the execution.
+    pub peak_memory_bytes: u64,
+    /// The instructions the contract executed, as the gas the injected counter charged for them (see `ExecuteResult::instructions`).
+    pub instructions: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -62,26 +73,71 @@ pub struct Runtime {
     function_name: String,
     args: Vec<u8>,
     result: RuntimeResult,
+    /// The state as it was before the execution, this is what gets committed to if the execution fails.
     pre_execution_state: ContractState,
-    post_execution_state: ContractState,
+    /// Staging buffer for the state mutations made by the contract,
+    /// it's only merged into the committed state by `into_result`.
+    staged_state: ContractState,
     key: StateKey,
     gas : RuntimeGas,
+    /// When set `rand` returns a deterministic stream derived from it instead of fresh entropy.
+    rand_seed: Option<SymmetricKey>,
+    /// The index of the next block of the `rand_seed` stream.
+    rand_counter: u64,
+    /// The Ethereum address of the user that sent the task, see `set_caller`.
+    caller: Option<[u8; 20]>,
+    /// The source of the fresh entropy of `rand` and `true_rand`, replaced by a seeded one in tests (see `set_rng`).
+    rng: RngSource,
 }
 
 type Result<T> = ::std::result::Result<T, WasmError>;
 
+/// The state key of the counter of `next_counter`, the contract can't write or remove it directly.
+pub const COUNTER_STATE_KEY: &str = "__enigma_counter";
+
+/// The selector of `Error(string)`, a contract reverts with an ABI encoded call to it (like Solidity's `revert(reason)`).
+pub const REVERT_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+
+/// Decodes the reason out of the payload of a revert,
+/// a payload that isn't a valid `Error(string)` call is taken as the reason itself.
+pub fn decode_revert_reason(payload: &[u8]) -> String {
+    // An ABI word holding a length or an offset, anything that doesn't fit in a u64 is invalid anyway.
+    let word_to_usize = |word: &[u8]| -> Option<usize> {
+        if word.len() != 32 || word[..24].iter().any(|b| *b != 0) {
+            return None;
+        }
+        let mut be = [0u8; 8];
+        be.copy_from_slice(&word[24..]);
+        Some(u64::from_be_bytes(be) as usize)
+    };
+    let decode = || -> Option<&[u8]> {
+        if payload.len() < 4 || payload[..4] != REVERT_SELECTOR {
+            return None;
+        }
+        let data = &payload[4..];
+        let offset = word_to_usize(data.get(..32)?)?;
+        let start = offset.checked_add(32)?;
+        let len = word_to_usize(data.get(offset..start)?)?;
+        data.get(start..start.checked_add(len)?)
+    };
+    String::from_utf8_lossy(decode().unwrap_or(payload)).into_owned()
+}
+
 impl Runtime {
 
     pub fn new(memory: MemoryRef, gas_limit: u64, args: Vec<u8>, state: ContractState,
                           function_name: String, key: StateKey, costs: RuntimeWasmCosts) -> Runtime {
         let pre_execution_state = state.clone();
-        let post_execution_state = state;
+        let staged_state = state;
         let result = RuntimeResult {
             result: Vec::new(),
             state_delta: None,
             updated_state: Default::default(),
             ethereum_bridge: Default::default(),
             used_gas: 0,
+            storage_gas: 0,
+            peak_memory_bytes: 0,
+            instructions: 0,
         };
         let gas = RuntimeGas{
             counter: 0,
@@ -89,7 +145,21 @@ impl Runtime {
             refund: 0,
             costs,
         };
-        Runtime { memory, function_name, args, result, pre_execution_state, post_execution_state, key, gas }
+        Runtime { memory, function_name, args, result, pre_execution_state, staged_state, key, gas, rand_seed: None, rand_counter: 0, caller: None, rng: RngSource::default() }
+    }
+
+    /// Sets the caller that `get_caller` returns to the contract, this must be the user the key of the task was derived with
+    /// (the inputs only decrypt with that key, so a client can't claim to be another user).
+    pub fn set_caller(&mut self, caller: [u8; 20]) { self.caller = Some(caller); }
+
+    /// Replaces the source of fresh entropy, so tests can get reproducible `rand` and `true_rand` outputs.
+    pub fn set_rng(&mut self, rng: RngSource) { self.rng = rng; }
+
+    /// Derives the randomness of the contract from the state key and `task_id`,
+    /// so any worker with the state key that runs the same task on the same contract gets the same random stream.
+    pub fn seed_randomness(&mut self, task_id: &[u8; 32]) {
+        self.rand_seed = Some(derive_key(&self.key[..], task_id, b"contract randomness"));
+        self.rand_counter = 0;
     }
 
     pub fn get_used_gas(&self) -> u64 {
@@ -105,6 +175,17 @@ impl Runtime {
         Ok(())
     }
 
+    /// Writes the 20 bytes address of the caller (see `set_caller`) to the memory.
+    fn get_caller(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        let caller = match self.caller {
+            Some(caller) => caller,
+            None => return Err(FailedTaskError(InputError { message: "The caller of the task isn't known".to_string() }).into()),
+        };
+        self.memory.set(ptr, &caller)?;
+        Ok(())
+    }
+
     fn fetch_function_name_length(&mut self) -> RuntimeValue { RuntimeValue::I32(self.function_name.len() as i32) }
 
     fn fetch_function_name(&mut self, args: RuntimeArgs) -> Result<()> {
@@ -128,10 +209,13 @@ impl Runtime {
     pub fn read_state_len(&self, args: RuntimeArgs) -> Result<i32> {
         // TODO: Handle the error here, should we return len=0?;
         let key = self.read_state_key_from_memory(&args, 0, 1)?;
-        let value_vec =
-            serde_json::to_vec(&self.post_execution_state.json[&key]).
-                expect("Failed converting Value to vec in Runtime while reading state");
-        Ok(value_vec.len() as i32)
+        Ok(self.state_value_vec(&key)?.len() as i32)
+    }
+
+    /// The value of `key` as the contract reads it, with the big integers the state keeps tagged restored into numbers.
+    fn state_value_vec(&self, key: &str) -> Result<Vec<u8>> {
+        let value = self.staged_state.read_value(key)?;
+        Ok(serde_json::to_vec(&value).expect("Failed converting Value to vec in Runtime while reading state"))
     }
 
 
@@ -146,12 +230,19 @@ impl Runtime {
         let key = self.read_state_key_from_memory(&args, 0, 1)?;
         let value_holder: u32 = args.nth_checked(2)?;
 
-        let value_vec =
-            serde_json::to_vec(&self.post_execution_state.json[key]).expect("Failed converting Value to vec in Runtime while reading state");
+        let value_vec = self.state_value_vec(&key)?;
         self.memory.set(value_holder, &value_vec)?;
         Ok(())
     }
 
+    /// Fails if `key` is one that only the runtime manages (see `COUNTER_STATE_KEY`).
+    fn check_writable_key(key: &str) -> Result<()> {
+        if key == COUNTER_STATE_KEY {
+            return Err(FailedTaskError(InputError { message: format!("the state key '{}' is reserved for the counter", key) }).into());
+        }
+        Ok(())
+    }
+
     /// args:
     /// * `key` - the start address of key in memory
     /// * `key_len` - the length of key
@@ -159,8 +250,11 @@ impl Runtime {
     /// Read `key` from the memory, then remove the `key` from the state
     pub fn remove_from_state(&mut self, args: RuntimeArgs) -> Result<()> {
         let key = self.read_state_key_from_memory(&args, 0, 1)?;
+        Self::check_writable_key(&key)?;
 
-        self.post_execution_state.remove_key(&key);
+        let old_value_len = self.state_value_len(&key);
+        self.refund_gas(old_value_len);
+        self.staged_state.remove_key(&key);
         Ok(())
     }
 
@@ -174,6 +268,7 @@ impl Runtime {
     /// the cost of writing into the state is calculated by `calculate_gas_for_writing`
     pub fn write_state(&mut self, args: RuntimeArgs) -> Result<()> {
         let key = self.read_state_key_from_memory(&args, 0, 1)?;
+        Self::check_writable_key(&key)?;
         let value: u32 = args.nth_checked(2)?;
         let value_len: u32 = args.nth_checked(3)?;
 
@@ -182,9 +277,28 @@ impl Runtime {
         self.charge_gas(gas_amount)?;
         self.memory.get_into(value, &mut val[..])?;
 
-        let value: serde_json::Value =
-            serde_json::from_slice(&val).expect("Failed converting into Value while writing state in Runtime");
-        self.post_execution_state.write_key(&key, &value)?;
+        let value: serde_json::Value = serde_json::from_slice(&val).map_err(|e| FailedTaskError(InputError {
+            message: format!("the value written to '{}' isn't valid JSON: {}", key, e)
+        }))?;
+        self.staged_state.write_key(&key, &value)?;
+        Ok(())
+    }
+
+    /// Writes the current value of the counter of the contract to the memory (a big endian u64) and increments it.
+    /// The counter is kept in the state under `COUNTER_STATE_KEY`, so it persists across tasks and starts at 0.
+    pub fn next_counter(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        let current: u64 = self.staged_state.read_key::<Option<u64>>(COUNTER_STATE_KEY)?.unwrap_or(0);
+        let next = match current.checked_add(1) {
+            Some(next) => serde_json::Value::from(next),
+            None => return Err(FailedTaskError(InputError { message: "the counter of the contract overflowed".to_string() }).into()),
+        };
+        let next_len = serde_json::to_vec(&next).expect("Failed converting Value to vec in Runtime while writing state").len();
+        let gas_amount = self.calculate_gas_for_writing(next_len as u64, COUNTER_STATE_KEY)?;
+        self.charge_gas(gas_amount)?;
+
+        self.staged_state.write_key(COUNTER_STATE_KEY, &next)?;
+        self.memory.set(ptr, &current.to_be_bytes())?;
         Ok(())
     }
 
@@ -216,14 +330,7 @@ impl Runtime {
    /// If the new value is smaller than the old one, then the gas is returned for the removed bytes.
     fn calculate_gas_for_writing(&mut self, new_value_len: u64, key: &str) -> Result<u64> {
         let mut result = Some(0);
-        let val = self.post_execution_state.json[key].clone();
-        let mut old_value_len = 0;
-        // forcing the length of Null value to be 0, since it is not 0.
-        if !val.is_null() {
-            let old_value_vec =
-                serde_json::to_vec(&val).expect("Failed converting Value to vec in Runtime while reading state");
-            old_value_len = old_value_vec.len() as u64;
-        }
+        let old_value_len = self.state_value_len(key);
         // If the new value is larger than the old one, the gas should be charged
         if new_value_len >= old_value_len {
             let checked_val = (new_value_len - old_value_len).checked_mul(self.gas.costs.write_additional_byte);
@@ -232,17 +339,32 @@ impl Runtime {
             self.treat_gas_overflow(&result)?;
         } // If the new value is smaller than the old one, the gas should be returned
         else {
-            let decrease_cost = (old_value_len - new_value_len).checked_mul(self.gas.costs.write_additional_byte);
-            let (underflow, _val) = self.treat_gas_underflow(&decrease_cost);
+            self.refund_gas(old_value_len - new_value_len);
+        }
+        Ok(result.unwrap())
+    }
+
+    /// The length of the serialized value under `key` in the staged state, 0 if there's none.
+    fn state_value_len(&self, key: &str) -> u64 {
+        let val = &self.staged_state.json[key];
+        // forcing the length of Null value to be 0, since it is not 0.
+        if val.is_null() {
+            return 0;
+        }
+        serde_json::to_vec(val).expect("Failed converting Value to vec in Runtime while reading state").len() as u64
+    }
+
+    /// Returns the gas for `freed_bytes` bytes removed from the state.
+    fn refund_gas(&mut self, freed_bytes: u64) {
+        let decrease_cost = freed_bytes.checked_mul(self.gas.costs.write_additional_byte);
+        let (underflow, _val) = self.treat_gas_underflow(&decrease_cost);
+        if !underflow {
+            let tmp = self.gas.refund.checked_add(decrease_cost.unwrap());
+            let (underflow, val) = self.treat_gas_underflow(&tmp);
             if !underflow {
-                let tmp = self.gas.refund.checked_add(decrease_cost.unwrap());
-                let (underflow, val) = self.treat_gas_underflow(&tmp);
-                if !underflow {
-                    self.gas.refund = val;
-                }
+                self.gas.refund = val;
             }
         }
-        Ok(result.unwrap())
     }
 
     /// args:
@@ -277,37 +399,76 @@ impl Runtime {
         Ok(())
     }
 
+    /// Fills the memory with the seeded random stream if there's a seed (see `seed_randomness`),
+    /// otherwise with fresh entropy like `true_rand`.
     pub fn rand(&mut self, args: RuntimeArgs) -> Result<()> {
         let ptr: u32 = args.nth_checked(0)?;
         let len: u32 = args.nth_checked(1)?;
+        self.charge_gas(self.gas.costs.rand_cost(len))?;
 
         let mut buf = vec![0u8; len as usize];
-        match rsgx_read_rand(&mut buf[..]) {
-            Ok(_) => {
-                self.memory.set(ptr, &buf[..])?;
-                Ok(())
+        match self.rand_seed {
+            Some(seed) => {
+                // Every block is keccak256(seed, counter), the stream continues across calls.
+                for chunk in buf.chunks_mut(32) {
+                    let block = prepare_hash_multiple(&[&seed[..], &self.rand_counter.to_be_bytes()[..]]).keccak256();
+                    chunk.copy_from_slice(&block[..chunk.len()]);
+                    self.rand_counter += 1;
+                }
             }
-            Err(e) => Err(SystemError(SgxError { err: format!("{}", e), description: e.__description().to_string() }))?
+            None => self.read_true_rand(&mut buf)?,
         }
+        self.memory.set(ptr, &buf[..])?;
+        Ok(())
+    }
+
+    /// Fills the memory with fresh entropy from the enclave, for contracts that need real randomness (i.e. generating keys).
+    pub fn true_rand(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        let len: u32 = args.nth_checked(1)?;
+        self.charge_gas(self.gas.costs.rand_cost(len))?;
+
+        let mut buf = vec![0u8; len as usize];
+        self.read_true_rand(&mut buf)?;
+        self.memory.set(ptr, &buf[..])?;
+        Ok(())
     }
 
-    /// Destroy the runtime, create state delta, update the used gas and return result of the execution
+    fn read_true_rand(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.rng.fill_bytes(buf)?;
+        Ok(())
+    }
+
+    /// Drop all the state mutations made so far, called when the execution traps
+    /// so that a partially mutated state is never committed.
+    pub fn discard_staged_state(&mut self) {
+        self.staged_state = self.pre_execution_state.clone();
+    }
+
+    /// Destroy the runtime, commit the staged state, create state delta, update the used gas and return result of the execution
     pub fn into_result(mut self) -> ::std::result::Result<RuntimeResult, EnclaveError> {
-        if self.gas.counter >= self.gas.refund {
-            self.result.used_gas = self.gas.counter - self.gas.refund;
-        } else {
-            self.result.used_gas = 0;
+        // The size is checked once the execution is done, since checking it serializes the whole state.
+        if self.pre_execution_state != self.staged_state {
+            if let Err(e) = self.staged_state.check_size() {
+                return Err(match e {
+                    FailedTaskError(err) => FailedTaskErrorWithGas { used_gas: self.get_used_gas(), err },
+                    e => e,
+                });
+            }
         }
+        // Like the EVM does for clearing storage, the refund can't exceed a fraction of the gas used.
+        let max_refund = self.gas.counter.checked_div(self.gas.costs.max_refund_quotient).unwrap_or(0);
+        self.result.used_gas = self.gas.counter - cmp::min(self.gas.refund, max_refund);
         self.result.state_delta = {
             // The delta is always generated after a deployment.
             // The delta is generated after an execution only if there is a state change.
-            if (&self.pre_execution_state != &self.post_execution_state) || (self.pre_execution_state.is_initial()) {
-                Some(ContractState::generate_delta_and_update_state(&self.pre_execution_state, &mut self.post_execution_state, &self.key)?)
+            if (&self.pre_execution_state != &self.staged_state) || (self.pre_execution_state.is_initial()) {
+                Some(ContractState::generate_delta_and_update_state(&self.pre_execution_state, &mut self.staged_state, &self.key)?)
             } else {
                 None
             }
         };
-        self.result.updated_state = self.post_execution_state;
+        self.result.updated_state = self.staged_state;
         Ok(self.result)
     }
 
@@ -321,15 +482,29 @@ impl Runtime {
         Ok(())
     }
 
+    /// Stops the execution with the reason the contract gave, the state changes are discarded like on any other trap.
+    pub fn revert(&mut self, args: RuntimeArgs) -> Result<()> {
+        let ptr: u32 = args.nth_checked(0)?;
+        let len: u32 = args.nth_checked(1)?;
+        let payload = self.memory.get(ptr, len as usize)?;
+        Err(FailedTaskError(Reverted { reason: decode_revert_reason(&payload) }).into())
+    }
+
+    /// Charges for the instructions of the block the contract is entering, the injected gas counter calls it with their cost.
     pub fn gas(&mut self, args: RuntimeArgs) -> Result<()> {
         let amount: u32 = args.nth_checked(0)?;
-        self.charge_gas(amount as u64)
+        self.charge_gas(amount as u64)?;
+        self.result.instructions = self.result.instructions.saturating_add(amount as u64);
+        Ok(())
     }
 
     pub fn charge_deployment(&mut self) -> Result<()> {
         let deployed_bytecode_len = self.result.result.len() as u64;
         let gas_for_byte = self.gas.costs.deploy_byte;
-        self.charge_gas(deployed_bytecode_len * gas_for_byte)
+        let storage_gas = deployed_bytecode_len * gas_for_byte;
+        self.charge_gas(storage_gas)?;
+        self.result.storage_gas = storage_gas;
+        Ok(())
     }
 
     pub fn charge_execution(&mut self) -> Result<()> {
@@ -392,11 +567,13 @@ impl Runtime {
 }
 
 mod ext_impl {
-    use super::{eng_resolver, Runtime};
+    use super::{eng_resolver, native_stack, Runtime};
+    use enigma_tools_t::common::errors_t::WasmError;
     use wasmi::{Externals, RuntimeArgs, RuntimeValue, Trap};
 
     impl Externals for Runtime {
         fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+            native_stack::check().map_err(WasmError::from)?;
             match index {
                 eng_resolver::ids::RET_FUNC => {
                     Runtime::ret(self, args)?;
@@ -452,6 +629,16 @@ mod ext_impl {
                     Ok(None)
                 }
 
+                eng_resolver::ids::TRUE_RAND_FUNC => {
+                    Runtime::true_rand(self, args)?;
+                    Ok(None)
+                }
+
+                eng_resolver::ids::REVERT_FUNC => {
+                    Runtime::revert(self, args)?;
+                    Ok(None)
+                }
+
                 eng_resolver::ids::ENCRYPT_FUNC => {
                     Runtime::encrypt(self, args)?;
                     Ok(None)
@@ -462,8 +649,154 @@ mod ext_impl {
                     Ok(None)
                 }
 
+                eng_resolver::ids::CALLER_FUNC => {
+                    Runtime::get_caller(self, args)?;
+                    Ok(None)
+                }
+
+                eng_resolver::ids::NEXT_COUNTER_FUNC => {
+                    Runtime::next_counter(self, args)?;
+                    Ok(None)
+                }
+
                 _ => unimplemented!("Unimplemented function at {}", index),
             }
         }
     }
 }
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+    use enigma_crypto::hash::Sha256;
+    use enigma_crypto::rand::SeededRng;
+    use wasmi::{memory_units::Pages, MemoryInstance};
+
+    fn runtime_with_gas_limit(gas_limit: u64) -> Runtime {
+        let memory = MemoryInstance::alloc(Pages(1), None).unwrap();
+        let address = b"rand".sha256();
+        Runtime::new(memory, gas_limit, Vec::new(), ContractState::new(address), String::new(), *b"key".sha256(), RuntimeWasmCosts::default())
+    }
+
+    pub fn test_charge_for_rand() {
+        let cost = RuntimeWasmCosts::default().rand_cost(16);
+        let args = [RuntimeValue::I32(0), RuntimeValue::I32(16)];
+
+        let mut runtime = runtime_with_gas_limit(cost - 1);
+        match runtime.rand(RuntimeArgs::from(&args[..])) {
+            Err(WasmError::GasLimit) => (),
+            other => panic!("Expected running out of gas, got: {:?}", other),
+        }
+        let mut runtime = runtime_with_gas_limit(cost - 1);
+        assert!(runtime.true_rand(RuntimeArgs::from(&args[..])).is_err());
+
+        let mut runtime = runtime_with_gas_limit(2 * cost);
+        runtime.seed_randomness(&[7u8; 32]);
+        runtime.rand(RuntimeArgs::from(&args[..])).unwrap();
+        runtime.true_rand(RuntimeArgs::from(&args[..])).unwrap();
+        assert_eq!(runtime.gas.counter, 2 * cost);
+
+        // Longer outputs cost more.
+        let long_cost = RuntimeWasmCosts::default().rand_cost(64);
+        assert!(long_cost > cost);
+        let mut runtime = runtime_with_gas_limit(long_cost - 1);
+        assert!(runtime.true_rand(RuntimeArgs::from(&[RuntimeValue::I32(0), RuntimeValue::I32(64)][..])).is_err());
+        let mut runtime = runtime_with_gas_limit(long_cost);
+        runtime.true_rand(RuntimeArgs::from(&[RuntimeValue::I32(0), RuntimeValue::I32(64)][..])).unwrap();
+        assert_eq!(runtime.gas.counter, long_cost);
+    }
+
+    pub fn test_count_instructions() {
+        let cost = RuntimeWasmCosts::default().rand_cost(16);
+        let mut runtime = runtime_with_gas_limit(cost + 100);
+        runtime.seed_randomness(&[7u8; 32], &[8u8; 32]);
+        runtime.gas(RuntimeArgs::from(&[RuntimeValue::I32(30)][..])).unwrap();
+        runtime.rand(RuntimeArgs::from(&[RuntimeValue::I32(0), RuntimeValue::I32(16)][..])).unwrap();
+        runtime.gas(RuntimeArgs::from(&[RuntimeValue::I32(12)][..])).unwrap();
+        // A block the gas doesn't cover isn't executed, so it doesn't count.
+        assert!(runtime.gas(RuntimeArgs::from(&[RuntimeValue::I32(100)][..])).is_err());
+        // Only the instructions count, not the host functions.
+        assert_eq!(runtime.into_result().unwrap().instructions, 42);
+    }
+
+    pub fn test_seeded_rng() {
+        let args = [RuntimeValue::I32(0), RuntimeValue::I32(16)];
+        let run = |seed| {
+            let mut runtime = runtime_with_gas_limit(RuntimeWasmCosts::default().rand_cost(16));
+            runtime.set_rng(RngSource::Seeded(SeededRng::new(seed)));
+            runtime.true_rand(RuntimeArgs::from(&args[..])).unwrap();
+            runtime.memory.get(0, 16).unwrap()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        let mut expected = [0u8; 16];
+        SeededRng::new(7).fill_bytes(&mut expected).unwrap();
+        assert_eq!(run(7), expected.to_vec());
+    }
+
+    pub fn test_seed_randomness() {
+        let args = [RuntimeValue::I32(0), RuntimeValue::I32(16)];
+        let run = |task_id, rng_seed| {
+            let mut runtime = runtime_with_gas_limit(RuntimeWasmCosts::default().rand_cost(16));
+            runtime.set_rng(RngSource::Seeded(SeededRng::new(rng_seed)));
+            runtime.seed_randomness(&task_id);
+            runtime.rand(RuntimeArgs::from(&args[..])).unwrap();
+            runtime.memory.get(0, 16).unwrap()
+        };
+        // The seeded stream doesn't use the entropy source, so the same task always gets the same stream.
+        assert_eq!(run([7u8; 32], 1), run([7u8; 32], 2));
+        assert_ne!(run([7u8; 32], 1), run([6u8; 32], 1));
+    }
+
+    pub fn test_get_caller() {
+        let args = [RuntimeValue::I32(0)];
+        let mut runtime = runtime_with_gas_limit(0);
+        match runtime.get_caller(RuntimeArgs::from(&args[..])) {
+            Err(WasmError::EnclaveError(FailedTaskError(InputError { .. }))) => (),
+            other => panic!("Expected an unknown caller, got: {:?}", other),
+        }
+        runtime.set_caller([0x42; 20]);
+        runtime.get_caller(RuntimeArgs::from(&args[..])).unwrap();
+        assert_eq!(runtime.memory.get(0, 20).unwrap(), vec![0x42; 20]);
+    }
+
+    pub fn test_next_counter() {
+        let args = [RuntimeValue::I32(0)];
+        let mut runtime = runtime_with_gas_limit(10 * RuntimeWasmCosts::default().write_value);
+        for expected in 0..3u64 {
+            runtime.next_counter(RuntimeArgs::from(&args[..])).unwrap();
+            assert_eq!(runtime.memory.get(0, 8).unwrap(), expected.to_be_bytes().to_vec());
+        }
+        assert_eq!(runtime.staged_state.json[COUNTER_STATE_KEY], json!(3));
+
+        // The contract can only change the counter through `next_counter`.
+        runtime.memory.set(0, COUNTER_STATE_KEY.as_bytes()).unwrap();
+        let key_args = [RuntimeValue::I32(0), RuntimeValue::I32(COUNTER_STATE_KEY.len() as i32)];
+        match runtime.remove_from_state(RuntimeArgs::from(&key_args[..])) {
+            Err(WasmError::EnclaveError(FailedTaskError(InputError { .. }))) => (),
+            other => panic!("Expected a reserved key error, got: {:?}", other),
+        }
+        assert_eq!(runtime.staged_state.json[COUNTER_STATE_KEY], json!(3));
+    }
+
+    pub fn test_decode_revert_reason() {
+        // Solidity's encoding of `revert("Not enough balance")`.
+        let mut payload = REVERT_SELECTOR.to_vec();
+        let mut word = [0u8; 32];
+        word[31] = 0x20;
+        payload.extend_from_slice(&word);
+        word[31] = 18;
+        payload.extend_from_slice(&word);
+        payload.extend_from_slice(b"Not enough balance");
+        payload.extend_from_slice(&[0u8; 14]);
+        assert_eq!(decode_revert_reason(&payload), "Not enough balance");
+
+        // A length that runs out of the payload isn't decoded.
+        payload[4 + 32 + 31] = 200;
+        assert_eq!(decode_revert_reason(&payload), String::from_utf8_lossy(&payload));
+
+        // Neither is a payload without the selector.
+        assert_eq!(decode_revert_reason(b"plain reason"), "plain reason");
+        assert_eq!(decode_revert_reason(&REVERT_SELECTOR[..2]), String::from_utf8_lossy(&REVERT_SELECTOR[..2]));
+    }
+}
@@ -0,0 +1,99 @@
+//! A budget for the native stack of the enclave thread that executes a contract.
+//! The WASM stack height limiter only bounds the calls of the contract itself,
+//! but the host functions recurse on data the contract controls (e.g. the nesting of a JSON value it writes),
+//! and overflowing the stack of an enclave thread crashes the whole enclave instead of failing the task.
+//! So an execution records where the stack was when it started, and recursive host code checks it stays within the budget.
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The threads of the enclave have a 256KiB stack (`StackMaxSize` in Enclave.config.xml),
+/// the rest is left for the ecall itself and for the frames of the interpreter.
+pub const DEFAULT_NATIVE_STACK_BUDGET: usize = 128 * 1024;
+
+static BUDGET: AtomicUsize = AtomicUsize::new(DEFAULT_NATIVE_STACK_BUDGET);
+
+thread_local! {
+    /// The lowest stack address the current execution may reach, 0 when nothing is executing on this thread.
+    static LIMIT: Cell<usize> = Cell::new(0);
+}
+
+/// Sets how many bytes of native stack an execution may use, it applies to the executions that start afterwards.
+pub fn set_budget(bytes: usize) { BUDGET.store(bytes, Ordering::SeqCst) }
+
+pub fn budget() -> usize { BUDGET.load(Ordering::SeqCst) }
+
+#[inline(never)]
+fn stack_pointer() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+/// Ends the budget of an execution when it's dropped.
+pub struct NativeStackGuard {
+    previous: usize,
+}
+
+/// Starts an execution that may use `budget()` bytes of stack below the current frame (the stack grows down).
+pub fn enter() -> NativeStackGuard {
+    let limit = stack_pointer().saturating_sub(budget());
+    let previous = LIMIT.with(|l| l.replace(limit));
+    NativeStackGuard { previous }
+}
+
+impl Drop for NativeStackGuard {
+    fn drop(&mut self) { LIMIT.with(|l| l.set(self.previous)) }
+}
+
+/// Fails the task if the current execution went deeper than its budget, outside of an execution it always passes.
+pub fn check() -> Result<(), EnclaveError> {
+    let limit = LIMIT.with(Cell::get);
+    if limit != 0 && stack_pointer() < limit {
+        return Err(EnclaveError::FailedTaskError(FailedTaskError::NativeStackOverflow { budget: budget() }));
+    }
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+    use crate::data::{ContractState, IOInterface};
+    use enigma_crypto::hash::Sha256;
+    use serde_json::Value;
+    use std::vec::Vec;
+
+    fn nested(depth: usize) -> Value {
+        let mut value = json!(1);
+        for _ in 0..depth {
+            value = Value::Array(vec![value]);
+        }
+        value
+    }
+
+    pub fn test_native_stack_budget() {
+        let mut state = ContractState::new(b"native stack".sha256());
+        // Outside of an execution there's no limit.
+        state.write_key("nested", &nested(100)).unwrap();
+
+        let default = budget();
+        {
+            let _guard = enter();
+            state.write_key("nested", &nested(100)).unwrap();
+        }
+
+        set_budget(512);
+        let res = {
+            let _guard = enter();
+            state.write_key("nested", &nested(100))
+        };
+        set_budget(default);
+        match res {
+            Err(EnclaveError::FailedTaskError(FailedTaskError::NativeStackOverflow { budget })) => assert_eq!(budget, 512),
+            other => panic!("Expected a NativeStackOverflow, got: {:?}", other),
+        }
+        // The guard is gone with the execution.
+        check().unwrap();
+        let read: Vec<Value> = state.read_key("nested").unwrap();
+        assert_eq!(read.len(), 1);
+    }
+}
@@ -15,6 +15,10 @@ extern "C" {
     fn ocall_new_delta(retval: *mut EnclaveReturn, db_ptr: *const RawPointer,
                        enc_delta: *const u8, delta_len: usize,
                        contract_address: &ContractAddress, delta_index_: *const u32) -> sgx_status_t;
+    fn ocall_save_delta_and_state(retval: *mut EnclaveReturn, db_ptr: *const RawPointer,
+                                  contract_address: &ContractAddress, delta_index_: *const u32,
+                                  enc_delta: *const u8, delta_len: usize,
+                                  enc_state: *const u8, state_len: usize) -> sgx_status_t;
     fn ocall_remove_delta(retval: *mut EnclaveReturn, db_ptr: *const RawPointer,
                        contract_address: &ContractAddress, delta_index_: *const u32) -> sgx_status_t;
 
@@ -55,6 +59,26 @@ pub fn save_delta(db_ptr: *const RawPointer, enc: &EncryptedPatch) -> Result<(),
     }
 }
 
+/// Save the delta and the updated state of a computation in a single ocall,
+/// the untrusted side writes them in one batch, and only if both were added to it, so either both are stored or none of them.
+pub fn save_delta_and_state(db_ptr: *const RawPointer, delta: &EncryptedPatch, state: &EncryptedContractState<u8>) -> Result<(), EnclaveError> {
+    let mut res = EnclaveReturn::default();
+    let res_status = unsafe {
+        ocall_save_delta_and_state(&mut res, db_ptr, &delta.contract_address, &delta.index as *const u32,
+                                   delta.data.as_c_ptr(), delta.data.len(), state.json.as_c_ptr(), state.json.len())
+    };
+
+    match res {
+        EnclaveReturn::Success => (), // 0 is the OK result
+        _ => return Err(SystemError(OcallError { command: "ocall_save_delta_and_state".to_string(), err: format!("return result is: {}", &res) })),
+    }
+
+    match res_status {
+        sgx_status_t::SGX_SUCCESS => Ok(()),
+        _ => Err(SystemError(OcallError { command: "ocall_save_delta_and_state".to_string(), err: res_status.__description().to_string() })),
+    }
+}
+
 pub fn remove_delta(db_ptr: *const RawPointer, enc: &EncryptedPatch) -> Result<(), EnclaveError> {
     let mut res = EnclaveReturn::default();
     let res_status =
@@ -75,6 +99,13 @@ pub fn remove_delta(db_ptr: *const RawPointer, enc: &EncryptedPatch) -> Result<(
 }
 
 pub fn get_state(db_ptr: *const RawPointer, contract_address: ContractAddress) -> Result<EncryptedContractState<u8>, EnclaveError> {
+    try_get_state(db_ptr, contract_address)?.ok_or_else(|| {
+        SystemError(OcallError { command: "get_state".to_string(), err: "The contract has no state yet".to_string() })
+    })
+}
+
+/// Same as `get_state` but returns `None` when the contract has no state yet (its key was provisioned before it was deployed).
+pub fn try_get_state(db_ptr: *const RawPointer, contract_address: ContractAddress) -> Result<Option<EncryptedContractState<u8>>, EnclaveError> {
     let mut retval = EnclaveReturn::default();
     let mut state_len = 0usize;
     let status = unsafe { ocall_get_state_size(&mut retval, db_ptr, &contract_address, &mut state_len) };
@@ -84,6 +115,9 @@ pub fn get_state(db_ptr: *const RawPointer, contract_address: ContractAddress) -
             err: format!("Error with SGX, retval: {}, status: {:?}", retval, status),
         }));
     }
+    if state_len == 0 {
+        return Ok(None);
+    }
     let mut state = vec![0u8; state_len];
     let status = unsafe { ocall_get_state(&mut retval, db_ptr, &contract_address, state.as_mut_ptr(), state_len) };
     if retval != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
@@ -93,7 +127,7 @@ pub fn get_state(db_ptr: *const RawPointer, contract_address: ContractAddress) -
         }));
     }
 
-    Ok(EncryptedContractState { contract_address, json: state })
+    Ok(Some(EncryptedContractState { contract_address, json: state }))
 }
 
 pub fn get_deltas(db_ptr: *const RawPointer, contract_address: ContractAddress, start: u32, end: u32) -> Result<Vec<EncryptedPatch>, EnclaveError> {
@@ -125,7 +159,7 @@ pub fn get_deltas(db_ptr: *const RawPointer, contract_address: ContractAddress,
         if tmp_slices.0.is_empty() {
             continue;
         }
-        let delta = EncryptedPatch { data: tmp_slices.0.to_vec(), contract_address, index: start + i as u32 };
+        let delta = EncryptedPatch::from_stored(tmp_slices.0.to_vec(), contract_address, start + i as u32);
         result.push(delta);
         iteration = tmp_slices.1;
     }
@@ -153,7 +187,8 @@ pub mod tests {
         let enc_patch = EncryptedPatch {
             data: vec![197, 39, 187, 56, 29, 96, 229, 230, 172, 82, 74, 89, 152, 72, 183, 136, 80, 182, 222, 4, 47, 197, 200, 233, 105, 90, 207, 14, 20, 220, 170, 226, 21, 241, 24, 231, 69, 27, 177, 234, 110, 132, 253, 115, 87, 205, 167, 142, 163, 170, 37, 239, 240, 98, 20, 49, 185, 223, 162, 115, 194, 220, 75, 218, 160, 17, 83, 134, 247, 239, 213, 207, 59, 32, 76, 204, 206, 134, 80, 234, 88, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
             contract_address: [181, 71, 210, 141, 65, 214, 242, 119, 127, 212, 100, 4, 19, 131, 252, 56, 173, 224, 167, 158, 196, 65, 19, 33, 251, 198, 129, 58, 247, 127, 88, 162].into(),
-            index: 57
+            index: 57,
+            epoch: None,
         };
         save_delta(db_ptr, &enc_patch).unwrap();
     }
@@ -198,7 +233,7 @@ pub mod tests {
         for i in start..end {
             let mut delta_data = b"data".sha256().to_vec();
             delta_data.push(i as u8);
-            let delta = EncryptedPatch { data: delta_data, contract_address: *contract_address, index: i };
+            let delta = EncryptedPatch { data: delta_data, contract_address: *contract_address, index: i, epoch: None };
             deltas.push(delta.clone());
             save_delta(db_ptr, &delta).unwrap();
         }
@@ -0,0 +1,77 @@
+//! Test vectors for the encrypted state and deltas.
+//! The vectors are JSON files under `tests/vectors`, each one holds the plaintext object, the key and IV
+//! and the expected ciphertext. If the serialization or encryption format changes intentionally,
+//! regenerate them with the generator in `test-vectors-gen` instead of editing the bytes by hand.
+use crate::data::{ContractState, EncryptedContractState, EncryptedPatch, StatePatch};
+use enigma_types::{ContractAddress, StateKey};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::vec::Vec;
+
+pub const STATE_VECTOR: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vectors/state.json"));
+pub const PATCH_VECTOR: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/vectors/patch.json"));
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TestVector<T> {
+    pub key: StateKey,
+    pub iv: [u8; 12],
+    pub contract_address: ContractAddress,
+    pub index: u32,
+    pub plaintext: T,
+    pub ciphertext: Vec<u8>,
+}
+
+impl<T: DeserializeOwned> TestVector<T> {
+    pub fn parse(vector: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(vector)
+    }
+}
+
+impl TestVector<ContractState> {
+    /// The plaintext state, with the fields that aren't serialized restored from the vector.
+    pub fn state(&self) -> ContractState {
+        ContractState { contract_address: self.contract_address, ..self.plaintext.clone() }
+    }
+
+    pub fn encrypted_state(&self) -> EncryptedContractState<u8> {
+        EncryptedContractState { contract_address: self.contract_address, json: self.ciphertext.clone() }
+    }
+}
+
+impl TestVector<StatePatch> {
+    /// The plaintext patch, with the fields that aren't serialized restored from the vector.
+    pub fn patch(&self) -> StatePatch {
+        StatePatch { contract_address: self.contract_address, index: self.index, ..self.plaintext.clone() }
+    }
+
+    pub fn encrypted_patch(&self) -> EncryptedPatch {
+        EncryptedPatch { data: self.ciphertext.clone(), contract_address: self.contract_address, index: self.index, epoch: None }
+    }
+}
+
+pub fn load_state() -> TestVector<ContractState> {
+    TestVector::parse(STATE_VECTOR).expect("Failed parsing the state test vector")
+}
+
+pub fn load_patch() -> TestVector<StatePatch> {
+    TestVector::parse(PATCH_VECTOR).expect("Failed parsing the patch test vector")
+}
+
+pub mod tests {
+    use super::*;
+    use enigma_crypto::Encryption;
+
+    pub fn test_state_vector_round_trip() {
+        let vector = load_state();
+        let enc = vector.state().encrypt_with_nonce(&vector.key, Some(vector.iv)).unwrap();
+        assert_eq!(enc, vector.encrypted_state());
+        assert_eq!(ContractState::decrypt(enc, &vector.key).unwrap(), vector.state());
+    }
+
+    pub fn test_patch_vector_round_trip() {
+        let vector = load_patch();
+        let enc = vector.patch().encrypt_with_nonce(&vector.key, Some(vector.iv)).unwrap();
+        assert_eq!(enc, vector.encrypted_patch());
+        assert_eq!(StatePatch::decrypt(enc, &vector.key).unwrap(), vector.patch());
+    }
+}
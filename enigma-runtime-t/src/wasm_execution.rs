@@ -1,45 +1,79 @@
 
 use parity_wasm::io::Cursor;
-use parity_wasm::elements::{self, Deserialize};
+use parity_wasm::elements::{self, Deserialize, External};
 use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveError::*, FailedTaskError, FailedTaskError::*};
 use RuntimeResult;
 use wasmi::{ImportsBuilder, Module, ModuleInstance, ModuleRef};
 pub use gas::{gas_rules, WasmCosts, RuntimeWasmCosts};
 use eng_resolver;
+use native_stack;
 use Runtime;
 use crate::data::{ContractState};
-use enigma_types::StateKey;
+use enigma_types::{GasMultiplier, HostCapabilities, StateKey};
 
 use std::boxed::Box;
 use std::vec::Vec;
 use std::string::{String, ToString};
 
+/// The most memory a contract may request, in WASM pages (8MB).
+pub const MAX_MEMORY_PAGES: u32 = 128;
+
 pub struct WasmEngine {
     pub instance: ModuleRef,
     pub runtime: Runtime,
+    resolver: eng_resolver::ImportResolver,
 }
 
 impl WasmEngine {
-    pub fn new(code: &[u8], gas_limit: u64, args: Vec<u8>, state: ContractState, function_name: String,key: StateKey) -> Result<WasmEngine, EnclaveError> {
-        let module = Self::create_module(code)?;
-        let instantiation_resolver = eng_resolver::ImportResolver::with_limit(128);
-        let imports = ImportsBuilder::new().with_resolver("env", &instantiation_resolver);
-        // TODO: Change the assert here: https://github.com/paritytech/wasmi/issues/172
-        let instance = ModuleInstance::new(&module, &imports)?.assert_no_start();
+    /// `gas_multiplier` is the price of the contract, see `WasmCosts::with_multiplier`.
+    pub fn new(code: &[u8], gas_limit: u64, args: Vec<u8>, state: ContractState, function_name: String, key: StateKey,
+               capabilities: HostCapabilities, gas_multiplier: GasMultiplier) -> Result<WasmEngine, EnclaveError> {
+        let module = Self::create_module(code, gas_multiplier)?;
+        let instantiation_resolver = eng_resolver::ImportResolver::with_limit(MAX_MEMORY_PAGES).with_capabilities(capabilities);
+        let instance = {
+            let imports = ImportsBuilder::new().with_resolver("env", &instantiation_resolver);
+            // TODO: Change the assert here: https://github.com/paritytech/wasmi/issues/172
+            ModuleInstance::new(&module, &imports)?.assert_no_start()
+        };
         let runtime = Runtime::new(instantiation_resolver.memory_ref(), gas_limit, args, state, function_name, key, RuntimeWasmCosts::default());
-        Ok(WasmEngine { instance, runtime })
+        Ok(WasmEngine { instance, runtime, resolver: instantiation_resolver })
     }
 
-    pub fn new_deploy(code: &[u8], gas_limit: u64, args: Vec<u8>, state: ContractState, function_name: String, key: StateKey) -> Result<WasmEngine, EnclaveError>{
+    /// The imports of the constructor are checked against `capabilities`, the computations check the deployed bytecode against them again
+    /// because it's given by the untrusted side.
+    pub fn new_deploy(code: &[u8], gas_limit: u64, args: Vec<u8>, state: ContractState, function_name: String, key: StateKey,
+                      capabilities: HostCapabilities, gas_multiplier: GasMultiplier) -> Result<WasmEngine, EnclaveError>{
         let deploy_bytecode = Self::build_constructor(code)?;
-        Self::new(&deploy_bytecode, gas_limit, args, state, function_name, key)
+        Self::new(&deploy_bytecode, gas_limit, args, state, function_name, key, capabilities, gas_multiplier)
+    }
+
+    /// The randomness of a computation is seeded by `task_id`, so running the same task again gives the same result.
+    /// `capabilities` are the ones the contract was deployed with.
+    pub fn new_compute(code: &[u8], gas_limit: u64, args: Vec<u8>, state: ContractState, function_name: String,key: StateKey,
+                       task_id: &[u8; 32], capabilities: HostCapabilities, gas_multiplier: GasMultiplier) -> Result<WasmEngine, EnclaveError>{
+        let mut engine = Self::new(code, gas_limit, args, state, function_name, key, capabilities, gas_multiplier)?;
+        engine.runtime.seed_randomness(task_id);
+        Ok(engine)
     }
 
-    pub fn new_compute(code: &[u8], gas_limit: u64, args: Vec<u8>, state: ContractState, function_name: String,key: StateKey) -> Result<WasmEngine, EnclaveError>{
-        Self::new(code, gas_limit, args, state, function_name, key)
+    /// Checks that `code` would pass the module creation and the instantiation of a deployment with `gas_multiplier` and `capabilities`,
+    /// both as given and as the constructor that `build_constructor` makes of it. The imports are resolved, so the memory limit
+    /// and the capabilities are applied, but nothing is run.
+    pub fn validate(code: &[u8], gas_multiplier: GasMultiplier, capabilities: HostCapabilities) -> Result<(), EnclaveError> {
+        Self::resolve_imports(&Self::create_module(code, gas_multiplier)?, capabilities)?;
+        Self::resolve_imports(&Self::create_module(&Self::build_constructor(code)?, gas_multiplier)?, capabilities)?;
+        Ok(())
+    }
+
+    /// Instantiates `module` the way `new` does, without running its start function or anything else.
+    fn resolve_imports(module: &Module, capabilities: HostCapabilities) -> Result<(), EnclaveError> {
+        let resolver = eng_resolver::ImportResolver::with_limit(MAX_MEMORY_PAGES).with_capabilities(capabilities);
+        let imports = ImportsBuilder::new().with_resolver("env", &resolver);
+        ModuleInstance::new(module, &imports)?;
+        Ok(())
     }
 
-    fn create_module(code: &[u8]) -> ::std::result::Result<Box<Module>, EnclaveError> {
+    fn create_module(code: &[u8], gas_multiplier: GasMultiplier) -> ::std::result::Result<Box<Module>, EnclaveError> {
         let mut cursor = Cursor::new(&code[..]);
         let deserialized_module = elements::Module::deserialize(&mut cursor)?;
         if deserialized_module.memory_section().map_or(false, |ms| ms.entries().len() > 0) {
@@ -50,7 +84,8 @@ impl WasmEngine {
                 err: "Malformed wasm module: internal memory".to_string()
             }));
         }
-        let wasm_costs = WasmCosts::default();
+        Self::check_imports(&deserialized_module)?;
+        let wasm_costs = WasmCosts::default().with_multiplier(gas_multiplier);
         let contract_module = pwasm_utils::inject_gas_counter(deserialized_module, &gas_rules(&wasm_costs))?;
         let limited_module = pwasm_utils::stack_height::inject_limiter(contract_module, wasm_costs.max_stack_height)?;
 
@@ -58,6 +93,29 @@ impl WasmEngine {
         Ok(Box::new(module))
     }
 
+    /// Fails with the names of all the imports the resolver can't provide,
+    /// instead of the first one failing the instantiation after the module was already instrumented.
+    fn check_imports(module: &elements::Module) -> Result<(), EnclaveError> {
+        let unresolved: Vec<String> = module.import_section().map_or(Vec::new(), |section| {
+            section.entries().iter().filter(|entry| {
+                let resolvable = match entry.external() {
+                    External::Function(_) => eng_resolver::is_host_function(entry.field()),
+                    External::Memory(_) => entry.field() == "memory",
+                    _ => false,
+                };
+                entry.module() != "env" || !resolvable
+            }).map(|entry| format!("{}.{}", entry.module(), entry.field())).collect()
+        });
+        if unresolved.is_empty() {
+            Ok(())
+        } else {
+            Err(FailedTaskError(WasmModuleCreationError {
+                code: "creation of WASM module".to_string(),
+                err: format!("Unresolved imports: {}", unresolved.join(", ")),
+            }))
+        }
+    }
+
     /// Builds Wasm code for contract deployment from the Wasm contract.
     /// Gets byte vector with Wasm code.
     /// Created code contains one function `call`, which invokes `deploy`.
@@ -78,21 +136,13 @@ impl WasmEngine {
             false,
         ) {
             Ok(v) => v,
-            Err(e) => panic!("build_constructor: {:?}", e), // TODO: Return error
+            Err(e) => return Err(FailedTaskError(WasmModuleCreationError {
+                code: "building the constructor".to_string(),
+                err: format!("{:?}", e),
+            })),
         };
 
-        let result;
-
-        if let Some(ctor_module) = ctor_module {
-            result = parity_wasm::serialize(ctor_module); /*.map_err(Error::Encoding)*/
-        } else {
-            result = parity_wasm::serialize(module); /*.map_err(Error::Encoding)*/
-        }
-
-        match result {
-            Ok(v) => Ok(v),
-            Err(e) => panic!("build_constructor: {:?}", e), // TODO: Return Error
-        }
+        Ok(parity_wasm::serialize(ctor_module.unwrap_or(module))?)
     }
 
     pub fn deploy(&mut self) -> Result<(), EnclaveError> {
@@ -103,6 +153,7 @@ impl WasmEngine {
     }
 
     fn execute_function(&mut self) -> Result<(), EnclaveError> {
+        let _native_stack = native_stack::enter();
         self.instance.invoke_export("call", &[], &mut self.runtime)?;
         Ok(())
     }
@@ -116,6 +167,10 @@ impl WasmEngine {
 
     pub fn execute(&mut self) -> Result<(), EnclaveError> {
         let result = self.execute_function();
+        if result.is_err() {
+            // A trap must not leave a partially mutated state behind.
+            self.runtime.discard_staged_state();
+        }
         if let Err(FailedTaskError(e)) = result {
                 return Err(self.treat_failed_task_error(e))
          }
@@ -128,7 +183,10 @@ impl WasmEngine {
 
     /// Destroy the engine instance and return the result of the execution from runtime
     pub fn into_result(self) -> Result<RuntimeResult, EnclaveError> {
-        self.runtime.into_result()
+        let peak_memory_bytes = self.resolver.peak_memory_bytes();
+        let mut result = self.runtime.into_result()?;
+        result.peak_memory_bytes = peak_memory_bytes;
+        Ok(result)
     }
 }
 
@@ -141,7 +199,67 @@ pub mod tests {
     use enigma_crypto::hash::Sha256;
     use std::string::ToString;
     use enigma_crypto::Encryption;
-    use wasm_execution::WasmEngine;
+    use wasm_execution::{WasmEngine, MAX_MEMORY_PAGES};
+    use std::vec::Vec;
+    use enigma_types::{GasMultiplier, HostCapabilities};
+    use enigma_tools_t::common::errors_t::{EnclaveError::FailedTaskError, FailedTaskError::{MemoryLimitExceeded, WasmModuleCreationError}};
+
+    pub fn test_unresolved_import() {
+        // A module with a single function type and a single import: `env.nonexistent`
+        let code = vec![
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x02, 0x13, 0x01, 0x03, b'e', b'n', b'v', 0x0b,
+            b'n', b'o', b'n', b'e', b'x', b'i', b's', b't', b'e', b'n', b't', 0x00, 0x00,
+        ];
+        match WasmEngine::create_module(&code, GasMultiplier::default()) {
+            Err(FailedTaskError(WasmModuleCreationError { err, .. })) => assert!(err.contains("env.nonexistent"), "{}", err),
+            Err(e) => panic!("Expected a WasmModuleCreationError, got: {:?}", e),
+            Ok(_) => panic!("A module importing an unknown function was created"),
+        }
+    }
+
+    pub fn test_memory_limit_exceeded() {
+        // A module that only imports `env.memory` with 200 pages, initial and maximum.
+        let code = vec![
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            0x02, 0x12, 0x01, 0x03, b'e', b'n', b'v', 0x06,
+            b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x01, 0xc8, 0x01, 0xc8, 0x01,
+        ];
+        let state = ContractState::new(b"enigma".sha256());
+        match WasmEngine::new(&code, 100_000, Vec::new(), state, "call".to_string(), [1u8; 32], HostCapabilities::default(), GasMultiplier::default()) {
+            Err(FailedTaskError(MemoryLimitExceeded { requested, allowed })) => assert_eq!((requested, allowed), (200, MAX_MEMORY_PAGES)),
+            Err(e) => panic!("Expected a MemoryLimitExceeded, got: {:?}", e),
+            Ok(_) => panic!("A module requesting too much memory was instantiated"),
+        }
+    }
+
+    pub fn test_validate_internal_memory() {
+        // A module with a memory section of a single page instead of importing `env.memory`.
+        let code = vec![
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            0x05, 0x03, 0x01, 0x00, 0x01,
+        ];
+        match WasmEngine::validate(&code, GasMultiplier::default(), HostCapabilities::default()) {
+            Err(FailedTaskError(WasmModuleCreationError { err, .. })) => assert!(err.contains("internal memory"), "{}", err),
+            Err(e) => panic!("Expected a WasmModuleCreationError, got: {:?}", e),
+            Ok(_) => panic!("A module with an internal memory was validated"),
+        }
+    }
+
+    pub fn test_validate_memory_limit_exceeded() {
+        // A module that only imports `env.memory` with 200 pages, initial and maximum.
+        let code = vec![
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            0x02, 0x12, 0x01, 0x03, b'e', b'n', b'v', 0x06,
+            b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x01, 0xc8, 0x01, 0xc8, 0x01,
+        ];
+        match WasmEngine::validate(&code, GasMultiplier::default(), HostCapabilities::default()) {
+            Err(FailedTaskError(MemoryLimitExceeded { requested, allowed })) => assert_eq!((requested, allowed), (200, MAX_MEMORY_PAGES)),
+            Err(e) => panic!("Expected a MemoryLimitExceeded, got: {:?}", e),
+            Ok(_) => panic!("A module requesting too much memory was validated"),
+        }
+    }
 
     pub fn test_execute_contract() {
         let addr = b"enigma".sha256();
@@ -149,7 +267,7 @@ pub mod tests {
         let bytecode = vec![0, 97, 115, 109, 1, 0, 0, 0, 1, 147, 1, 22, 96, 1, 127, 0, 96, 3, 127, 127, 127, 1, 127, 96, 2, 127, 127, 1, 127, 96, 2, 127, 127, 0, 96, 0, 1, 127, 96, 4, 127, 127, 127, 127, 0, 96, 0, 0, 96, 3, 127, 127, 127, 0, 96, 1, 127, 1, 127, 96, 1, 124, 1, 127, 96, 3, 127, 127, 126, 0, 96, 2, 124, 127, 1, 127, 96, 5, 127, 127, 127, 127, 127, 0, 96, 7, 126, 126, 126, 127, 127, 127, 127, 1, 126, 96, 2, 126, 127, 0, 96, 4, 126, 126, 126, 127, 1, 126, 96, 6, 127, 127, 127, 127, 127, 127, 1, 127, 96, 7, 127, 127, 127, 127, 127, 127, 127, 1, 127, 96, 5, 127, 127, 127, 127, 127, 1, 127, 96, 1, 127, 1, 126, 96, 5, 127, 126, 126, 126, 126, 0, 96, 4, 127, 126, 126, 127, 0, 2, 144, 1, 7, 3, 101, 110, 118, 26, 102, 101, 116, 99, 104, 95, 102, 117, 110, 99, 116, 105, 111, 110, 95, 110, 97, 109, 101, 95, 108, 101, 110, 103, 116, 104, 0, 4, 3, 101, 110, 118, 19, 102, 101, 116, 99, 104, 95, 102, 117, 110, 99, 116, 105, 111, 110, 95, 110, 97, 109, 101, 0, 0, 3, 101, 110, 118, 17, 102, 101, 116, 99, 104, 95, 97, 114, 103, 115, 95, 108, 101, 110, 103, 116, 104, 0, 4, 3, 101, 110, 118, 10, 102, 101, 116, 99, 104, 95, 97, 114, 103, 115, 0, 0, 3, 101, 110, 118, 11, 119, 114, 105, 116, 101, 95, 115, 116, 97, 116, 101, 0, 5, 3, 101, 110, 118, 3, 114, 101, 116, 0, 3, 3, 101, 110, 118, 6, 109, 101, 109, 111, 114, 121, 2, 1, 30, 32, 3, 152, 1, 150, 1, 5, 7, 0, 6, 0, 0, 3, 5, 3, 3, 3, 3, 2, 8, 0, 9, 1, 2, 10, 10, 11, 3, 3, 8, 0, 0, 5, 3, 8, 6, 6, 0, 0, 2, 1, 3, 2, 2, 3, 7, 3, 12, 3, 3, 2, 8, 6, 3, 3, 3, 0, 0, 0, 0, 7, 0, 2, 1, 6, 2, 2, 3, 2, 2, 1, 2, 7, 2, 2, 2, 2, 2, 2, 0, 8, 8, 0, 0, 0, 0, 13, 14, 2, 15, 0, 2, 0, 8, 6, 0, 0, 3, 3, 2, 6, 16, 5, 17, 2, 2, 2, 0, 18, 0, 1, 1, 2, 2, 2, 2, 7, 2, 2, 2, 0, 3, 0, 2, 2, 0, 19, 2, 0, 0, 3, 7, 0, 3, 0, 0, 6, 3, 2, 0, 0, 8, 3, 1, 3, 3, 0, 0, 5, 1, 1, 1, 20, 20, 21, 21, 4, 5, 1, 112, 1, 35, 35, 6, 9, 1, 127, 1, 65, 128, 128, 192, 0, 11, 7, 9, 1, 4, 99, 97, 108, 108, 0, 136, 1, 9, 40, 1, 0, 65, 1, 11, 34, 104, 77, 105, 106, 115, 65, 66, 73, 74, 75, 71, 78, 91, 124, 50, 39, 76, 61, 70, 68, 69, 107, 118, 127, 109, 111, 113, 123, 120, 110, 112, 114, 125, 126, 10, 160, 176, 2, 150, 1, 139, 6, 1, 12, 127, 35, 0, 65, 32, 107, 34, 4, 36, 0, 32, 1, 40, 2, 0, 65, 210, 128, 192, 0, 65, 1, 16, 7, 32, 4, 65, 3, 58, 0, 16, 32, 2, 32, 3, 106, 33, 5, 32, 3, 65, 127, 115, 33, 6, 32, 2, 65, 127, 106, 33, 7, 32, 4, 65, 16, 106, 16, 8, 65, 0, 33, 8, 32, 4, 65, 5, 106, 33, 9, 32, 2, 33, 10, 2, 64, 2, 64, 2, 64, 3, 64, 32, 5, 32, 10, 107, 33, 14, 65, 0, 33, 13, 3, 64, 32, 14, 32, 13, 70, 13, 2, 32, 10, 32, 13, 106, 33, 11, 32, 13, 65, 1, 106, 33, 13, 32, 11, 45, 0, 0, 34, 12, 65, 219, 130, 192, 0, 106, 45, 0, 0, 34, 11, 69, 13, 0, 11, 2, 64, 32, 8, 32, 13, 106, 34, 14, 65, 127, 106, 34, 15, 32, 8, 77, 13, 0, 32, 4, 32, 3, 54, 2, 4, 32, 4, 32, 2, 54, 2, 0, 32, 4, 32, 8, 54, 2, 8, 32, 4, 32, 15, 54, 2, 12, 2, 64, 32, 8, 69, 13, 0, 32, 8, 32, 3, 70, 13, 0, 32, 8, 32, 3, 79, 13, 4, 32, 2, 32, 8, 106, 44, 0, 0, 65, 191, 127, 76, 13, 4, 11, 2, 64, 32, 6, 32, 8, 106, 32, 13, 106, 69, 13, 0, 32, 15, 32, 3, 79, 13, 4, 32, 7, 32, 8, 106, 32, 13, 106, 44, 0, 0, 65, 191, 127, 76, 13, 4, 11, 32, 1, 40, 2, 0, 32, 2, 32, 8, 106, 32, 13, 65, 127, 106, 16, 7, 32, 4, 65, 3, 58, 0, 16, 32, 4, 65, 16, 106, 16, 8, 11, 2, 64, 2, 64, 2, 64, 2, 64, 32, 11, 65, 146, 127, 106, 34, 8, 65, 7, 75, 13, 0, 2, 64, 2, 64, 2, 64, 2, 64, 32, 8, 14, 8, 0, 7, 7, 7, 2, 7, 3, 1, 0, 11, 65, 216, 128, 192, 0, 33, 11, 12, 4, 11, 32, 4, 65, 4, 106, 32, 12, 65, 4, 118, 65, 203, 130, 192, 0, 106, 45, 0, 0, 58, 0, 0, 32, 9, 32, 12, 65, 15, 113, 65, 203, 130, 192, 0, 106, 45, 0, 0, 58, 0, 0, 32, 4, 65, 220, 234, 193, 129, 3, 54, 0, 0, 32, 1, 40, 2, 0, 32, 4, 65, 6, 16, 7, 12, 4, 11, 65, 214, 128, 192, 0, 33, 11, 12, 2, 11, 65, 212, 128, 192, 0, 33, 11, 12, 1, 11, 2, 64, 32, 11, 65, 230, 0, 71, 13, 0, 65, 218, 128, 192, 0, 33, 11, 12, 1, 11, 2, 64, 32, 11, 65, 220, 0, 71, 13, 0, 65, 222, 128, 192, 0, 33, 11, 12, 1, 11, 2, 64, 32, 11, 65, 226, 0, 71, 13, 0, 65, 220, 128, 192, 0, 33, 11, 12, 1, 11, 32, 11, 65, 34, 71, 13, 2, 65, 224, 128, 192, 0, 33, 11, 11, 32, 1, 40, 2, 0, 32, 11, 65, 2, 16, 7, 11, 32, 4, 65, 3, 58, 0, 16, 32, 10, 32, 13, 106, 33, 10, 32, 4, 65, 16, 106, 16, 8, 32, 14, 33, 8, 12, 1, 11, 11, 16, 9, 0, 11, 2, 64, 32, 8, 32, 3, 70, 13, 0, 32, 4, 32, 3, 54, 2, 4, 32, 4, 32, 2, 54, 2, 0, 32, 4, 32, 8, 54, 2, 8, 32, 4, 32, 3, 54, 2, 12, 2, 64, 32, 8, 69, 13, 0, 32, 8, 32, 3, 79, 13, 3, 32, 2, 32, 8, 106, 34, 2, 44, 0, 0, 65, 191, 127, 76, 13, 3, 11, 32, 1, 40, 2, 0, 32, 2, 32, 3, 32, 8, 107, 16, 7, 32, 4, 65, 3, 58, 0, 16, 32, 4, 65, 16, 106, 16, 8, 11, 32, 4, 65, 3, 58, 0, 16, 32, 4, 65, 16, 106, 16, 8, 32, 1, 40, 2, 0, 65, 210, 128, 192, 0, 65, 1, 16, 7, 32, 4, 65, 3, 58, 0, 16, 32, 4, 65, 16, 106, 16, 8, 32, 0, 65, 3, 58, 0, 0, 32, 4, 65, 32, 106, 36, 0, 15, 11, 32, 4, 32, 4, 65, 8, 106, 54, 2, 20, 32, 4, 32, 4, 54, 2, 16, 32, 4, 32, 4, 65, 12, 106, 54, 2, 24, 32, 4, 65, 16, 106, 16, 10, 0, 11, 32, 4, 32, 4, 65, 8, 106, 54, 2, 20, 32, 4, 32, 4, 54, 2, 16, 32, 4, 32, 4, 65, 12, 106, 54, 2, 24, 32, 4, 65, 16, 106, 16, 11, 0, 11, 41, 1, 1, 127, 32, 0, 32, 2, 16, 41, 32, 0, 32, 0, 40, 2, 8, 34, 3, 32, 2, 106, 54, 2, 8, 32, 3, 32, 0, 40, 2, 0, 106, 32, 1, 32, 2, 16, 149, 1, 26, 11, 19, 0, 2, 64, 32, 0, 45, 0, 0, 65, 3, 70, 13, 0, 32, 0, 16, 30, 11, 11, 10, 0, 65, 248, 253, 192, 0, 16, 38, 0, 11, 38, 1, 1, 127, 32, 0, 40, 2, 0, 34, 1, 40, 2, 0, 32, 1, 40, 2, 4, 32, 0, 40, 2, 4, 40, 2, 0, 32, 0, 40, 2, 8, 40, 2, 0, 16, 32, 0, 11, 38, 1, 1, 127, 32, 0, 40, 2, 0, 34, 1, 40, 2, 0, 32, 1, 40, 2, 4, 32, 0, 40, 2, 4, 40, 2, 0, 32, 0, 40, 2, 8, 40, 2, 0, 16, 32, 0, 11, 15, 0, 32, 0, 32, 1, 65, 236, 253, 192, 0, 65, 1, 16, 13, 11, 17, 0, 32, 1, 32, 2, 32, 3, 16, 7, 32, 0, 65, 3, 58, 0, 0, 11, 15, 0, 32, 0, 32, 1, 65, 238, 253, 192, 0, 65, 4, 16, 13, 11, 15, 0, 32, 0, 32, 1, 65, 211, 128, 192, 0, 65, 1, 16, 13, 11, 15, 0, 32, 0, 32, 1, 65, 237, 253, 192, 0, 65, 1, 16, 13, 11, 15, 0, 32, 0, 32, 1, 65, 226, 128, 192, 0, 65, 1, 16, 13, 11, 206, 8, 3, 2, 127, 1, 124, 2, 127, 35, 0, 65, 128, 1, 107, 34, 2, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 45, 0, 0, 65, 127, 106, 34, 3, 65, 4, 75, 13, 0, 2, 64, 32, 3, 14, 5, 0, 2, 3, 4, 5, 0, 11, 32, 1, 40, 2, 0, 65, 225, 253, 192, 0, 65, 229, 253, 192, 0, 32, 0, 45, 0, 1, 34, 3, 27, 65, 4, 65, 5, 32, 3, 27, 16, 7, 32, 2, 65, 3, 58, 0, 72, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 3, 54, 2, 32, 32, 3, 13, 10, 32, 2, 65, 32, 106, 16, 20, 12, 8, 11, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 14, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 3, 54, 2, 32, 32, 3, 13, 9, 32, 2, 65, 32, 106, 16, 20, 12, 7, 11, 32, 0, 65, 8, 106, 40, 2, 0, 34, 3, 65, 1, 70, 13, 3, 32, 3, 65, 2, 71, 13, 4, 32, 0, 65, 16, 106, 43, 3, 0, 34, 4, 16, 21, 65, 255, 1, 113, 65, 1, 75, 13, 5, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 14, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 3, 54, 2, 32, 32, 3, 13, 8, 32, 2, 65, 32, 106, 16, 20, 12, 6, 11, 32, 1, 32, 0, 65, 4, 106, 40, 2, 0, 32, 0, 65, 12, 106, 40, 2, 0, 16, 22, 33, 3, 12, 7, 11, 32, 1, 32, 0, 65, 4, 106, 16, 23, 33, 3, 12, 6, 11, 2, 64, 32, 0, 65, 12, 106, 40, 2, 0, 69, 13, 0, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 16, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 3, 54, 2, 32, 32, 3, 13, 6, 32, 2, 65, 32, 106, 16, 20, 65, 1, 33, 5, 12, 5, 11, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 16, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 3, 54, 2, 32, 32, 3, 13, 5, 32, 2, 65, 32, 106, 16, 20, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 12, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 3, 54, 2, 32, 32, 3, 13, 5, 32, 2, 65, 32, 106, 16, 20, 65, 0, 33, 5, 12, 4, 11, 32, 2, 65, 16, 106, 32, 2, 65, 200, 0, 106, 32, 0, 65, 16, 106, 41, 3, 0, 16, 24, 32, 1, 40, 2, 0, 32, 2, 40, 2, 16, 32, 2, 40, 2, 20, 16, 7, 32, 2, 65, 3, 58, 0, 32, 32, 2, 65, 32, 106, 16, 8, 32, 2, 65, 3, 58, 0, 72, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 3, 54, 2, 120, 32, 3, 13, 4, 32, 2, 65, 248, 0, 106, 16, 20, 12, 2, 11, 32, 2, 65, 8, 106, 32, 2, 65, 200, 0, 106, 32, 0, 65, 16, 106, 41, 3, 0, 16, 25, 32, 1, 40, 2, 0, 32, 2, 40, 2, 8, 32, 2, 40, 2, 12, 16, 7, 32, 2, 65, 3, 58, 0, 32, 32, 2, 65, 32, 106, 16, 8, 32, 2, 65, 3, 58, 0, 72, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 3, 54, 2, 120, 32, 3, 13, 3, 32, 2, 65, 248, 0, 106, 16, 20, 12, 1, 11, 32, 4, 32, 2, 65, 200, 0, 106, 16, 26, 33, 3, 32, 1, 40, 2, 0, 32, 2, 65, 200, 0, 106, 32, 3, 16, 7, 32, 2, 65, 3, 58, 0, 32, 32, 2, 32, 2, 65, 32, 106, 16, 19, 34, 3, 54, 2, 120, 32, 3, 13, 2, 32, 2, 65, 248, 0, 106, 16, 20, 11, 65, 0, 33, 3, 12, 1, 11, 32, 2, 65, 32, 106, 32, 0, 65, 4, 106, 16, 27, 32, 2, 65, 200, 0, 106, 32, 2, 65, 32, 106, 65, 36, 16, 149, 1, 26, 2, 64, 3, 64, 32, 2, 65, 24, 106, 32, 2, 65, 200, 0, 106, 16, 28, 32, 2, 40, 2, 24, 34, 0, 69, 13, 1, 32, 2, 40, 2, 28, 33, 6, 2, 64, 32, 5, 65, 255, 1, 113, 65, 1, 70, 13, 0, 32, 1, 40, 2, 0, 65, 234, 253, 192, 0, 65, 1, 16, 7, 11, 32, 2, 65, 3, 58, 0, 120, 32, 2, 32, 2, 65, 248, 0, 106, 16, 19, 34, 3, 54, 2, 116, 32, 3, 13, 2, 32, 2, 65, 244, 0, 106, 16, 20, 32, 2, 32, 1, 32, 0, 40, 2, 0, 32, 0, 40, 2, 8, 16, 22, 34, 3, 54, 2, 120, 32, 3, 13, 2, 32, 2, 65, 248, 0, 106, 16, 20, 32, 2, 65, 3, 58, 0, 120, 32, 2, 32, 2, 65, 248, 0, 106, 16, 19, 34, 3, 54, 2, 116, 32, 3, 13, 2, 32, 2, 65, 244, 0, 106, 16, 20, 32, 2, 65, 0, 54, 2, 68, 32, 2, 65, 196, 0, 106, 16, 20, 32, 1, 40, 2, 0, 65, 235, 253, 192, 0, 65, 1, 16, 7, 32, 2, 65, 3, 58, 0, 120, 32, 2, 32, 2, 65, 248, 0, 106, 16, 19, 34, 3, 54, 2, 116, 32, 3, 13, 2, 32, 2, 65, 244, 0, 106, 16, 20, 32, 2, 32, 6, 32, 1, 16, 18, 34, 3, 54, 2, 120, 32, 3, 13, 2, 32, 2, 65, 248, 0, 106, 16, 20, 32, 2, 65, 3, 58, 0, 120, 32, 2, 32, 2, 65, 248, 0, 106, 16, 19, 34, 3, 54, 2, 116, 32, 3, 13, 2, 32, 2, 65, 244, 0, 106, 16, 20, 32, 2, 65, 0, 54, 2, 68, 32, 2, 65, 196, 0, 106, 16, 20, 65, 2, 33, 5, 12, 0, 11, 11, 65, 0, 33, 3, 32, 5, 65, 255, 1, 113, 69, 13, 0, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 12, 32, 2, 32, 2, 65, 200, 0, 106, 16, 19, 34, 0, 54, 2, 32, 2, 64, 32, 0, 69, 13, 0, 32, 0, 33, 3, 12, 1, 11, 32, 2, 65, 32, 106, 16, 20, 11, 32, 2, 65, 128, 1, 106, 36, 0, 32, 3, 11, 64, 1, 1, 127, 35, 0, 65, 16, 107, 34, 1, 36, 0, 2, 64, 32, 0, 45, 0, 0, 65, 3, 71, 13, 0, 32, 1, 65, 16, 106, 36, 0, 65, 0, 15, 11, 32, 1, 32, 0, 41, 2, 0, 55, 3, 8, 32, 1, 65, 8, 106, 16, 29, 33, 0, 32, 1, 65, 16, 106, 36, 0, 32, 0, 11, 73, 1, 2, 127, 2, 64, 32, 0, 40, 2, 0, 34, 1, 69, 13, 0, 2, 64, 2, 64, 32, 1, 40, 2, 0, 34, 2, 65, 1, 70, 13, 0, 32, 2, 13, 1, 32, 1, 65, 8, 106, 40, 2, 0, 69, 13, 1, 32, 1, 40, 2, 4, 16, 31, 12, 1, 11, 32, 1, 65, 4, 106, 16, 30, 11, 32, 0, 40, 2, 0, 16, 31, 11, 11, 90, 1, 2, 126, 2, 64, 2, 64, 2, 64, 32, 0, 189, 34, 1, 66, 255, 255, 255, 255, 255, 255, 255, 255, 255, 0, 131, 80, 13, 0, 32, 1, 66, 128, 128, 128, 128, 128, 128, 128, 248, 255, 0, 131, 34, 2, 80, 13, 1, 32, 2, 66, 128, 128, 128, 128, 128, 128, 128, 248, 255, 0, 82, 13, 2, 32, 1, 66, 255, 255, 255, 255, 255, 255, 255, 7, 131, 80, 15, 11, 65, 2, 15, 11, 65, 3, 15, 11, 65, 4, 11, 63, 1, 1, 127, 35, 0, 65, 16, 107, 34, 3, 36, 0, 32, 3, 65, 8, 106, 32, 0, 32, 1, 32, 2, 16, 6, 32, 3, 32, 3, 65, 8, 106, 16, 19, 34, 0, 54, 2, 4, 2, 64, 32, 0, 13, 0, 32, 3, 65, 4, 106, 16, 20, 11, 32, 3, 65, 16, 106, 36, 0, 32, 0, 11, 138, 3, 1, 4, 127, 35, 0, 65, 16, 107, 34, 2, 36, 0, 32, 1, 40, 2, 0, 33, 3, 2, 64, 2, 64, 2, 64, 32, 1, 40, 2, 8, 34, 4, 69, 13, 0, 32, 2, 65, 8, 106, 32, 0, 40, 2, 0, 16, 15, 32, 2, 32, 2, 65, 8, 106, 16, 19, 34, 1, 54, 2, 4, 32, 1, 13, 2, 32, 2, 65, 4, 106, 16, 20, 65, 1, 33, 5, 12, 1, 11, 32, 2, 65, 8, 106, 32, 0, 40, 2, 0, 16, 15, 32, 2, 32, 2, 65, 8, 106, 16, 19, 34, 1, 54, 2, 4, 32, 1, 13, 1, 32, 2, 65, 4, 106, 16, 20, 32, 2, 65, 8, 106, 32, 0, 40, 2, 0, 16, 17, 32, 2, 32, 2, 65, 8, 106, 16, 19, 34, 1, 54, 2, 4, 32, 1, 13, 1, 32, 2, 65, 4, 106, 16, 20, 65, 0, 33, 5, 11, 32, 4, 65, 24, 108, 33, 4, 2, 64, 3, 64, 32, 4, 69, 13, 1, 2, 64, 32, 5, 65, 255, 1, 113, 65, 1, 70, 13, 0, 32, 0, 40, 2, 0, 65, 234, 253, 192, 0, 65, 1, 16, 7, 11, 32, 2, 65, 3, 58, 0, 8, 32, 2, 32, 2, 65, 8, 106, 16, 19, 34, 1, 54, 2, 4, 32, 1, 13, 2, 32, 2, 65, 4, 106, 16, 20, 32, 2, 32, 3, 32, 0, 16, 18, 34, 1, 54, 2, 8, 32, 1, 13, 2, 32, 2, 65, 8, 106, 16, 20, 32, 2, 65, 3, 58, 0, 8, 32, 2, 32, 2, 65, 8, 106, 16, 19, 34, 1, 54, 2, 4, 32, 1, 13, 2, 32, 3, 65, 24, 106, 33, 3, 32, 2, 65, 4, 106, 16, 20, 32, 2, 65, 0, 54, 2, 0, 32, 4, 65, 104, 106, 33, 4, 32, 2, 16, 20, 65, 2, 33, 5, 12, 0, 11, 11, 65, 0, 33, 1, 32, 5, 65, 255, 1, 113, 69, 13, 0, 32, 2, 65, 8, 106, 32, 0, 40, 2, 0, 16, 17, 32, 2, 32, 2, 65, 8, 106, 16, 19, 34, 3, 54, 2, 4, 2, 64, 32, 3, 69, 13, 0, 32, 3, 33, 1, 12, 1, 11, 32, 2, 65, 4, 106, 16, 20, 11, 32, 2, 65, 16, 106, 36, 0, 32, 1, 11, 197, 2, 4, 1, 126, 2, 127, 1, 126, 2, 127, 32, 2, 32, 2, 66, 63, 135, 34, 3, 124, 32, 3, 133, 33, 3, 65, 20, 33, 4, 2, 64, 3, 64, 32, 3, 66, 144, 206, 0, 84, 13, 1, 32, 1, 32, 4, 106, 34, 5, 65, 124, 106, 32, 3, 32, 3, 66, 144, 206, 0, 128, 34, 6, 66, 240, 177, 127, 126, 124, 167, 34, 7, 65, 228, 0, 110, 34, 8, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 5, 65, 126, 106, 32, 8, 65, 156, 127, 108, 32, 7, 106, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 4, 65, 124, 106, 33, 4, 32, 6, 33, 3, 12, 0, 11, 11, 2, 64, 32, 3, 167, 34, 5, 65, 228, 0, 72, 13, 0, 32, 1, 32, 4, 106, 65, 126, 106, 32, 3, 167, 34, 7, 65, 255, 255, 3, 113, 65, 228, 0, 110, 34, 5, 65, 156, 127, 108, 32, 7, 106, 65, 255, 255, 3, 113, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 4, 65, 126, 106, 33, 4, 11, 2, 64, 2, 64, 32, 5, 65, 9, 74, 13, 0, 32, 1, 32, 4, 65, 127, 106, 34, 4, 106, 32, 5, 65, 48, 106, 58, 0, 0, 12, 1, 11, 32, 1, 32, 4, 65, 126, 106, 34, 4, 106, 32, 5, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 11, 2, 64, 2, 64, 32, 2, 66, 0, 83, 13, 0, 32, 1, 32, 4, 106, 33, 5, 12, 1, 11, 32, 1, 32, 4, 65, 127, 106, 34, 4, 106, 34, 5, 65, 45, 58, 0, 0, 11, 32, 0, 32, 5, 54, 2, 0, 32, 0, 65, 20, 32, 4, 107, 54, 2, 4, 11, 145, 2, 3, 2, 127, 1, 126, 2, 127, 65, 20, 33, 3, 2, 64, 3, 64, 32, 2, 66, 144, 206, 0, 84, 13, 1, 32, 1, 32, 3, 106, 34, 4, 65, 124, 106, 32, 2, 32, 2, 66, 144, 206, 0, 128, 34, 5, 66, 240, 177, 127, 126, 124, 167, 34, 6, 65, 228, 0, 110, 34, 7, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 4, 65, 126, 106, 32, 7, 65, 156, 127, 108, 32, 6, 106, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 3, 65, 124, 106, 33, 3, 32, 5, 33, 2, 12, 0, 11, 11, 2, 64, 32, 2, 167, 34, 4, 65, 228, 0, 72, 13, 0, 32, 1, 32, 3, 106, 65, 126, 106, 32, 2, 167, 34, 6, 65, 255, 255, 3, 113, 65, 228, 0, 110, 34, 4, 65, 156, 127, 108, 32, 6, 106, 65, 255, 255, 3, 113, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 3, 65, 126, 106, 33, 3, 11, 2, 64, 2, 64, 32, 4, 65, 9, 74, 13, 0, 32, 1, 32, 3, 65, 127, 106, 34, 3, 106, 34, 6, 32, 4, 65, 48, 106, 58, 0, 0, 12, 1, 11, 32, 1, 32, 3, 65, 126, 106, 34, 3, 106, 34, 6, 32, 4, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 11, 32, 0, 32, 6, 54, 2, 0, 32, 0, 65, 20, 32, 3, 107, 54, 2, 4, 11, 160, 14, 6, 1, 127, 2, 126, 4, 127, 1, 126, 3, 127, 4, 126, 35, 0, 65, 16, 107, 34, 2, 36, 0, 32, 0, 189, 34, 3, 66, 255, 255, 255, 255, 255, 255, 255, 7, 131, 33, 4, 32, 3, 66, 52, 136, 167, 33, 5, 65, 0, 33, 6, 2, 64, 32, 3, 66, 127, 85, 13, 0, 32, 1, 65, 45, 58, 0, 0, 65, 1, 33, 6, 11, 32, 5, 65, 255, 15, 113, 33, 5, 2, 64, 2, 64, 32, 4, 66, 0, 82, 34, 7, 13, 0, 32, 5, 13, 0, 32, 1, 32, 6, 106, 34, 5, 65, 0, 47, 0, 216, 214, 64, 59, 0, 0, 32, 5, 65, 2, 106, 65, 0, 45, 0, 218, 214, 64, 58, 0, 0, 32, 3, 66, 63, 136, 167, 65, 3, 106, 33, 5, 12, 1, 11, 32, 7, 32, 5, 65, 2, 73, 114, 33, 8, 32, 4, 66, 128, 128, 128, 128, 128, 128, 128, 8, 132, 32, 4, 32, 5, 27, 34, 4, 66, 2, 134, 33, 3, 32, 4, 66, 1, 131, 33, 9, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 5, 65, 203, 119, 106, 65, 204, 119, 32, 5, 27, 34, 5, 65, 0, 72, 13, 0, 32, 4, 32, 5, 65, 193, 232, 4, 108, 65, 18, 118, 34, 7, 32, 5, 65, 3, 74, 34, 10, 107, 34, 11, 65, 4, 116, 34, 12, 65, 184, 137, 192, 0, 106, 41, 3, 0, 32, 12, 65, 192, 137, 192, 0, 106, 41, 3, 0, 32, 7, 65, 250, 0, 106, 32, 5, 32, 10, 106, 107, 32, 11, 65, 207, 166, 202, 0, 108, 65, 19, 118, 106, 32, 2, 32, 2, 65, 8, 106, 32, 8, 16, 86, 33, 4, 32, 11, 65, 22, 79, 13, 3, 32, 3, 66, 5, 130, 66, 0, 81, 13, 1, 32, 9, 66, 0, 82, 13, 2, 32, 3, 32, 8, 173, 66, 127, 133, 124, 33, 3, 65, 127, 33, 5, 3, 64, 32, 5, 65, 1, 106, 33, 5, 32, 3, 66, 5, 128, 34, 13, 66, 123, 126, 32, 3, 124, 33, 14, 32, 13, 33, 3, 32, 14, 167, 69, 13, 0, 11, 32, 5, 32, 11, 73, 13, 3, 65, 1, 33, 10, 65, 0, 33, 7, 12, 6, 11, 32, 4, 65, 0, 32, 5, 107, 32, 5, 65, 133, 162, 83, 108, 65, 20, 118, 32, 5, 65, 127, 71, 107, 34, 7, 107, 34, 10, 65, 4, 116, 34, 11, 65, 248, 173, 192, 0, 106, 41, 3, 0, 32, 11, 65, 128, 174, 192, 0, 106, 41, 3, 0, 32, 7, 65, 248, 0, 106, 32, 10, 65, 207, 166, 202, 0, 108, 65, 19, 118, 107, 32, 2, 32, 2, 65, 8, 106, 32, 8, 16, 86, 33, 4, 32, 7, 32, 5, 106, 33, 11, 2, 64, 32, 7, 65, 2, 79, 13, 0, 32, 9, 80, 69, 13, 4, 65, 1, 33, 7, 65, 1, 33, 10, 32, 8, 69, 13, 5, 12, 6, 11, 32, 7, 65, 63, 79, 13, 2, 32, 3, 66, 127, 32, 7, 65, 127, 106, 65, 63, 113, 173, 134, 66, 127, 133, 131, 80, 69, 13, 2, 12, 4, 11, 65, 127, 33, 5, 3, 64, 32, 5, 65, 1, 106, 33, 5, 32, 3, 66, 5, 128, 34, 13, 66, 123, 126, 32, 3, 124, 33, 14, 32, 13, 33, 3, 32, 14, 167, 69, 13, 0, 11, 32, 5, 32, 11, 73, 13, 1, 12, 3, 11, 32, 3, 66, 2, 132, 33, 3, 65, 127, 33, 5, 3, 64, 32, 5, 65, 1, 106, 33, 5, 32, 3, 66, 5, 128, 34, 13, 66, 123, 126, 32, 3, 124, 33, 14, 32, 13, 33, 3, 32, 14, 167, 69, 13, 0, 11, 32, 2, 32, 2, 41, 3, 0, 32, 5, 32, 11, 79, 173, 125, 55, 3, 0, 11, 65, 0, 33, 5, 2, 64, 2, 64, 32, 2, 41, 3, 0, 34, 3, 66, 228, 0, 128, 34, 14, 32, 2, 41, 3, 8, 34, 15, 66, 228, 0, 128, 34, 13, 88, 13, 0, 32, 2, 32, 13, 55, 3, 8, 32, 2, 32, 14, 55, 3, 0, 32, 4, 66, 228, 0, 128, 34, 3, 66, 156, 127, 126, 32, 4, 124, 167, 65, 49, 75, 33, 7, 65, 2, 33, 5, 12, 1, 11, 32, 15, 33, 13, 32, 3, 33, 14, 32, 4, 33, 3, 65, 0, 33, 7, 11, 2, 64, 3, 64, 32, 14, 66, 10, 128, 34, 4, 32, 13, 66, 10, 128, 34, 15, 88, 13, 1, 32, 5, 65, 1, 106, 33, 5, 32, 3, 66, 10, 128, 34, 16, 66, 118, 126, 32, 3, 124, 167, 65, 4, 75, 33, 7, 32, 15, 33, 13, 32, 4, 33, 14, 32, 16, 33, 3, 12, 0, 11, 11, 32, 2, 32, 14, 55, 3, 0, 32, 2, 32, 13, 55, 3, 8, 32, 7, 32, 3, 32, 13, 81, 114, 33, 8, 32, 3, 33, 4, 12, 3, 11, 32, 2, 32, 2, 41, 3, 0, 66, 127, 124, 55, 3, 0, 11, 65, 0, 33, 10, 65, 1, 33, 7, 11, 65, 0, 33, 8, 32, 2, 41, 3, 8, 33, 3, 32, 2, 41, 3, 0, 33, 13, 65, 0, 33, 5, 2, 64, 3, 64, 32, 13, 66, 10, 128, 34, 15, 32, 3, 66, 10, 128, 34, 14, 88, 13, 1, 32, 5, 65, 1, 106, 33, 5, 32, 8, 65, 255, 1, 113, 69, 32, 7, 113, 33, 7, 32, 10, 32, 14, 66, 118, 126, 32, 3, 124, 167, 69, 113, 33, 10, 32, 4, 66, 10, 128, 34, 16, 66, 118, 126, 32, 4, 124, 167, 33, 8, 32, 14, 33, 3, 32, 15, 33, 13, 32, 16, 33, 4, 12, 0, 11, 11, 32, 2, 32, 3, 55, 3, 8, 32, 2, 32, 13, 55, 3, 0, 2, 64, 32, 10, 65, 1, 113, 69, 13, 0, 2, 64, 3, 64, 32, 3, 66, 10, 128, 34, 14, 66, 118, 126, 32, 3, 124, 167, 13, 1, 32, 5, 65, 1, 106, 33, 5, 32, 13, 66, 10, 128, 33, 13, 32, 8, 65, 255, 1, 113, 69, 32, 7, 113, 33, 7, 32, 4, 66, 10, 128, 34, 15, 66, 118, 126, 32, 4, 124, 167, 33, 8, 32, 14, 33, 3, 32, 15, 33, 4, 12, 0, 11, 11, 32, 2, 32, 13, 55, 3, 0, 32, 2, 32, 3, 55, 3, 8, 11, 32, 8, 65, 255, 1, 113, 34, 8, 65, 4, 75, 32, 8, 65, 5, 70, 32, 7, 113, 32, 4, 66, 1, 131, 80, 113, 115, 32, 9, 66, 0, 82, 32, 10, 65, 127, 115, 114, 32, 4, 32, 3, 81, 113, 114, 33, 8, 11, 32, 5, 32, 11, 106, 33, 7, 65, 17, 33, 5, 2, 64, 32, 4, 32, 8, 173, 66, 1, 131, 124, 34, 3, 66, 255, 255, 131, 254, 166, 222, 225, 17, 86, 13, 0, 65, 16, 33, 5, 32, 3, 66, 255, 255, 153, 166, 234, 175, 227, 1, 86, 13, 0, 65, 15, 33, 5, 32, 3, 66, 255, 255, 232, 131, 177, 222, 22, 86, 13, 0, 65, 14, 33, 5, 32, 3, 66, 255, 191, 202, 243, 132, 163, 2, 86, 13, 0, 65, 13, 33, 5, 32, 3, 66, 255, 159, 148, 165, 141, 29, 86, 13, 0, 65, 12, 33, 5, 32, 3, 66, 255, 207, 219, 195, 244, 2, 86, 13, 0, 65, 11, 33, 5, 32, 3, 66, 255, 199, 175, 160, 37, 86, 13, 0, 65, 10, 33, 5, 32, 3, 66, 255, 147, 235, 220, 3, 86, 13, 0, 65, 9, 33, 5, 32, 3, 66, 255, 193, 215, 47, 86, 13, 0, 65, 8, 33, 5, 32, 3, 66, 255, 172, 226, 4, 86, 13, 0, 65, 7, 33, 5, 32, 3, 66, 191, 132, 61, 86, 13, 0, 65, 6, 33, 5, 32, 3, 66, 159, 141, 6, 86, 13, 0, 65, 5, 33, 5, 32, 3, 66, 143, 206, 0, 86, 13, 0, 65, 4, 33, 5, 32, 3, 66, 231, 7, 86, 13, 0, 65, 3, 33, 5, 32, 3, 66, 227, 0, 86, 13, 0, 65, 2, 65, 1, 32, 3, 66, 9, 86, 27, 33, 5, 11, 32, 5, 32, 7, 106, 33, 8, 2, 64, 32, 7, 65, 0, 72, 13, 0, 32, 8, 65, 17, 78, 13, 0, 32, 3, 32, 1, 32, 5, 32, 6, 106, 106, 16, 87, 32, 1, 32, 6, 106, 33, 7, 2, 64, 3, 64, 32, 5, 65, 255, 255, 255, 255, 7, 70, 13, 1, 32, 5, 32, 8, 78, 13, 1, 32, 7, 32, 5, 106, 65, 48, 58, 0, 0, 32, 5, 65, 1, 106, 33, 5, 12, 0, 11, 11, 32, 1, 32, 8, 32, 6, 106, 34, 5, 106, 65, 174, 224, 0, 59, 0, 0, 32, 5, 65, 2, 106, 33, 5, 12, 1, 11, 2, 64, 32, 8, 65, 127, 106, 34, 7, 65, 16, 79, 13, 0, 32, 3, 32, 1, 32, 5, 32, 6, 65, 1, 106, 34, 7, 106, 34, 5, 106, 16, 87, 32, 1, 32, 6, 106, 32, 1, 32, 7, 106, 32, 8, 16, 150, 1, 26, 32, 1, 32, 8, 32, 6, 106, 106, 65, 46, 58, 0, 0, 12, 1, 11, 2, 64, 32, 8, 65, 4, 106, 65, 4, 75, 13, 0, 32, 1, 32, 6, 106, 34, 10, 65, 176, 220, 0, 59, 0, 0, 65, 2, 33, 7, 65, 2, 32, 8, 107, 33, 8, 2, 64, 3, 64, 32, 7, 65, 255, 255, 255, 255, 7, 70, 13, 1, 32, 7, 32, 8, 78, 13, 1, 32, 10, 32, 7, 106, 65, 48, 58, 0, 0, 32, 7, 65, 1, 106, 33, 7, 12, 0, 11, 11, 32, 3, 32, 1, 32, 5, 32, 6, 106, 32, 8, 106, 34, 5, 106, 16, 87, 12, 1, 11, 2, 64, 32, 5, 65, 1, 71, 13, 0, 32, 1, 32, 6, 106, 34, 5, 65, 1, 106, 65, 229, 0, 58, 0, 0, 32, 5, 32, 3, 167, 65, 48, 106, 58, 0, 0, 32, 7, 32, 1, 32, 6, 65, 2, 114, 34, 5, 106, 16, 88, 32, 5, 106, 33, 5, 12, 1, 11, 32, 3, 32, 1, 32, 5, 32, 6, 106, 34, 5, 106, 65, 1, 106, 34, 8, 16, 87, 32, 1, 32, 6, 106, 34, 10, 65, 1, 106, 34, 6, 45, 0, 0, 33, 11, 32, 6, 65, 46, 58, 0, 0, 32, 10, 32, 11, 58, 0, 0, 32, 8, 65, 229, 0, 58, 0, 0, 32, 7, 32, 1, 32, 5, 65, 2, 106, 34, 5, 106, 16, 88, 32, 5, 106, 33, 5, 11, 32, 2, 65, 16, 106, 36, 0, 32, 5, 11, 162, 1, 1, 4, 127, 32, 1, 40, 2, 0, 34, 2, 33, 3, 32, 1, 40, 2, 4, 34, 4, 33, 5, 2, 64, 3, 64, 32, 5, 69, 13, 1, 32, 5, 65, 127, 106, 33, 5, 32, 3, 40, 2, 152, 3, 33, 3, 12, 0, 11, 11, 2, 64, 3, 64, 32, 2, 47, 1, 6, 33, 5, 32, 4, 69, 13, 1, 32, 4, 65, 127, 106, 33, 4, 32, 2, 32, 5, 65, 2, 116, 106, 65, 152, 3, 106, 40, 2, 0, 33, 2, 12, 0, 11, 11, 32, 0, 65, 0, 54, 2, 0, 32, 0, 32, 3, 54, 2, 4, 32, 0, 32, 1, 54, 2, 8, 32, 0, 66, 0, 55, 2, 12, 32, 0, 65, 20, 106, 32, 2, 54, 2, 0, 32, 0, 65, 24, 106, 32, 1, 54, 2, 0, 32, 0, 65, 28, 106, 32, 5, 54, 2, 0, 32, 0, 32, 1, 40, 2, 8, 54, 2, 32, 11, 248, 2, 3, 4, 127, 1, 126, 1, 127, 2, 64, 2, 64, 2, 64, 2, 64, 32, 1, 40, 2, 32, 34, 2, 69, 13, 0, 32, 1, 65, 32, 106, 32, 2, 65, 127, 106, 54, 2, 0, 32, 1, 40, 2, 12, 34, 3, 32, 1, 40, 2, 4, 34, 4, 47, 1, 6, 79, 13, 1, 32, 3, 65, 1, 106, 33, 5, 32, 4, 32, 3, 65, 12, 108, 106, 65, 8, 106, 33, 2, 32, 4, 32, 3, 65, 24, 108, 106, 65, 144, 1, 106, 33, 4, 12, 2, 11, 65, 0, 33, 2, 12, 2, 11, 32, 1, 40, 2, 8, 33, 3, 32, 1, 40, 2, 0, 33, 5, 2, 64, 2, 64, 32, 4, 40, 2, 0, 34, 2, 69, 13, 0, 32, 5, 65, 1, 106, 33, 5, 32, 4, 51, 1, 4, 66, 32, 134, 32, 3, 173, 132, 33, 6, 12, 1, 11, 32, 3, 173, 33, 6, 65, 0, 33, 2, 11, 2, 64, 3, 64, 32, 6, 66, 32, 136, 167, 34, 7, 32, 2, 34, 4, 47, 1, 6, 73, 13, 1, 65, 0, 33, 2, 32, 4, 40, 2, 0, 34, 3, 69, 13, 0, 32, 4, 51, 1, 4, 66, 32, 134, 32, 6, 66, 255, 255, 255, 255, 15, 131, 132, 33, 6, 32, 5, 65, 1, 106, 33, 5, 32, 3, 33, 2, 12, 0, 11, 11, 65, 1, 32, 5, 107, 33, 2, 32, 4, 32, 7, 65, 2, 116, 106, 65, 156, 3, 106, 33, 3, 2, 64, 3, 64, 32, 3, 40, 2, 0, 33, 3, 32, 2, 69, 13, 1, 32, 2, 65, 1, 106, 33, 2, 32, 3, 65, 152, 3, 106, 33, 3, 12, 0, 11, 11, 65, 0, 33, 5, 32, 1, 65, 0, 54, 2, 0, 32, 1, 65, 4, 106, 32, 3, 54, 2, 0, 32, 1, 65, 8, 106, 32, 6, 62, 2, 0, 32, 4, 32, 7, 65, 12, 108, 106, 65, 8, 106, 33, 2, 32, 4, 32, 7, 65, 24, 108, 106, 65, 144, 1, 106, 33, 4, 11, 32, 1, 65, 12, 106, 32, 5, 54, 2, 0, 11, 32, 0, 32, 4, 54, 2, 4, 32, 0, 32, 2, 54, 2, 0, 11, 49, 1, 1, 126, 32, 0, 41, 2, 0, 33, 1, 2, 64, 65, 20, 16, 34, 34, 0, 69, 13, 0, 32, 0, 32, 1, 55, 2, 4, 32, 0, 65, 1, 54, 2, 0, 32, 0, 66, 0, 55, 2, 12, 32, 0, 15, 11, 0, 0, 11, 71, 1, 1, 127, 2, 64, 32, 0, 45, 0, 0, 65, 2, 73, 13, 0, 32, 0, 65, 4, 106, 34, 1, 40, 2, 0, 34, 0, 40, 2, 0, 32, 0, 40, 2, 4, 40, 2, 0, 17, 0, 0, 2, 64, 32, 0, 40, 2, 4, 40, 2, 4, 69, 13, 0, 32, 0, 40, 2, 0, 16, 31, 11, 32, 1, 40, 2, 0, 16, 31, 11, 11, 191, 7, 1, 5, 127, 32, 0, 65, 120, 106, 34, 1, 32, 0, 65, 124, 106, 40, 2, 0, 34, 2, 65, 120, 113, 34, 0, 106, 33, 3, 2, 64, 2, 64, 32, 2, 65, 1, 113, 13, 0, 32, 2, 65, 3, 113, 69, 13, 1, 32, 1, 40, 2, 0, 34, 2, 32, 0, 106, 33, 0, 2, 64, 2, 64, 2, 64, 65, 0, 40, 2, 188, 147, 65, 32, 1, 32, 2, 107, 34, 1, 70, 13, 0, 32, 2, 65, 255, 1, 75, 13, 1, 32, 1, 40, 2, 12, 34, 4, 32, 1, 40, 2, 8, 34, 5, 70, 13, 2, 32, 5, 32, 4, 54, 2, 12, 32, 4, 32, 5, 54, 2, 8, 12, 3, 11, 32, 3, 40, 2, 4, 65, 3, 113, 65, 3, 71, 13, 2, 65, 0, 32, 0, 54, 2, 180, 147, 65, 32, 3, 65, 4, 106, 34, 3, 32, 3, 40, 2, 0, 65, 126, 113, 54, 2, 0, 32, 1, 32, 0, 65, 1, 114, 54, 2, 4, 32, 1, 32, 0, 106, 32, 0, 54, 2, 0, 15, 11, 32, 1, 16, 96, 12, 1, 11, 65, 0, 65, 0, 40, 2, 164, 144, 65, 65, 126, 32, 2, 65, 3, 118, 119, 113, 54, 2, 164, 144, 65, 11, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 3, 40, 2, 4, 34, 2, 65, 2, 113, 13, 0, 65, 0, 40, 2, 192, 147, 65, 32, 3, 70, 13, 1, 65, 0, 40, 2, 188, 147, 65, 32, 3, 70, 13, 2, 32, 2, 65, 120, 113, 34, 4, 32, 0, 106, 33, 0, 32, 4, 65, 255, 1, 75, 13, 3, 32, 3, 40, 2, 12, 34, 4, 32, 3, 40, 2, 8, 34, 3, 70, 13, 4, 32, 3, 32, 4, 54, 2, 12, 32, 4, 32, 3, 54, 2, 8, 12, 5, 11, 32, 3, 65, 4, 106, 32, 2, 65, 126, 113, 54, 2, 0, 32, 1, 32, 0, 65, 1, 114, 54, 2, 4, 32, 1, 32, 0, 106, 32, 0, 54, 2, 0, 12, 7, 11, 65, 0, 32, 1, 54, 2, 192, 147, 65, 65, 0, 65, 0, 40, 2, 184, 147, 65, 32, 0, 106, 34, 0, 54, 2, 184, 147, 65, 32, 1, 32, 0, 65, 1, 114, 54, 2, 4, 2, 64, 32, 1, 65, 0, 40, 2, 188, 147, 65, 71, 13, 0, 65, 0, 65, 0, 54, 2, 180, 147, 65, 65, 0, 65, 0, 54, 2, 188, 147, 65, 11, 65, 0, 40, 2, 220, 147, 65, 34, 2, 32, 0, 79, 13, 7, 65, 0, 40, 2, 192, 147, 65, 34, 0, 69, 13, 7, 2, 64, 65, 0, 40, 2, 184, 147, 65, 34, 4, 65, 41, 73, 13, 0, 65, 204, 147, 193, 0, 33, 1, 3, 64, 2, 64, 32, 1, 40, 2, 0, 34, 3, 32, 0, 75, 13, 0, 32, 3, 32, 1, 40, 2, 4, 106, 32, 0, 75, 13, 2, 11, 32, 1, 40, 2, 8, 34, 1, 13, 0, 11, 11, 65, 0, 40, 2, 212, 147, 65, 34, 0, 69, 13, 4, 65, 0, 33, 1, 3, 64, 32, 1, 65, 1, 106, 33, 1, 32, 0, 40, 2, 8, 34, 0, 13, 0, 11, 32, 1, 65, 255, 31, 32, 1, 65, 255, 31, 75, 27, 33, 1, 12, 5, 11, 65, 0, 32, 1, 54, 2, 188, 147, 65, 65, 0, 65, 0, 40, 2, 180, 147, 65, 32, 0, 106, 34, 0, 54, 2, 180, 147, 65, 32, 1, 32, 0, 65, 1, 114, 54, 2, 4, 32, 1, 32, 0, 106, 32, 0, 54, 2, 0, 15, 11, 32, 3, 16, 96, 12, 1, 11, 65, 0, 65, 0, 40, 2, 164, 144, 65, 65, 126, 32, 2, 65, 3, 118, 119, 113, 54, 2, 164, 144, 65, 11, 32, 1, 32, 0, 65, 1, 114, 54, 2, 4, 32, 1, 32, 0, 106, 32, 0, 54, 2, 0, 32, 1, 65, 0, 40, 2, 188, 147, 65, 71, 13, 2, 65, 0, 32, 0, 54, 2, 180, 147, 65, 15, 11, 65, 255, 31, 33, 1, 11, 65, 0, 32, 1, 54, 2, 228, 147, 65, 32, 4, 32, 2, 77, 13, 1, 65, 0, 65, 127, 54, 2, 220, 147, 65, 15, 11, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 65, 255, 1, 75, 13, 0, 32, 0, 65, 3, 118, 34, 3, 65, 3, 116, 65, 172, 144, 193, 0, 106, 33, 0, 65, 0, 40, 2, 164, 144, 65, 34, 2, 65, 1, 32, 3, 65, 31, 113, 116, 34, 3, 113, 69, 13, 1, 32, 0, 65, 8, 106, 33, 2, 32, 0, 40, 2, 8, 33, 3, 12, 2, 11, 32, 1, 32, 0, 16, 97, 65, 0, 65, 0, 40, 2, 228, 147, 65, 65, 127, 106, 34, 1, 54, 2, 228, 147, 65, 32, 1, 13, 4, 65, 0, 40, 2, 212, 147, 65, 34, 0, 69, 13, 2, 65, 0, 33, 1, 3, 64, 32, 1, 65, 1, 106, 33, 1, 32, 0, 40, 2, 8, 34, 0, 13, 0, 11, 32, 1, 65, 255, 31, 32, 1, 65, 255, 31, 75, 27, 33, 1, 12, 3, 11, 65, 0, 32, 2, 32, 3, 114, 54, 2, 164, 144, 65, 32, 0, 65, 8, 106, 33, 2, 32, 0, 33, 3, 11, 32, 2, 32, 1, 54, 2, 0, 32, 3, 32, 1, 54, 2, 12, 32, 1, 32, 0, 54, 2, 12, 32, 1, 32, 3, 54, 2, 8, 15, 11, 65, 255, 31, 33, 1, 11, 65, 0, 32, 1, 54, 2, 228, 147, 65, 11, 11, 172, 9, 1, 6, 127, 35, 0, 65, 240, 0, 107, 34, 4, 36, 0, 32, 4, 32, 3, 54, 2, 12, 32, 4, 32, 2, 54, 2, 8, 65, 1, 33, 5, 32, 1, 33, 6, 2, 64, 32, 1, 65, 129, 2, 73, 13, 0, 65, 0, 32, 1, 107, 33, 7, 65, 128, 2, 33, 8, 2, 64, 3, 64, 2, 64, 32, 8, 32, 1, 79, 13, 0, 32, 0, 32, 8, 106, 44, 0, 0, 65, 191, 127, 74, 13, 2, 11, 32, 8, 65, 127, 106, 33, 6, 65, 0, 33, 5, 32, 8, 65, 1, 70, 13, 2, 32, 7, 32, 8, 106, 33, 9, 32, 6, 33, 8, 32, 9, 65, 1, 71, 13, 0, 12, 2, 11, 11, 65, 0, 33, 5, 32, 8, 33, 6, 11, 32, 4, 32, 6, 54, 2, 20, 32, 4, 32, 0, 54, 2, 16, 32, 4, 65, 0, 65, 5, 32, 5, 27, 54, 2, 28, 32, 4, 65, 140, 252, 192, 0, 65, 221, 232, 192, 0, 32, 5, 27, 54, 2, 24, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 2, 32, 1, 75, 34, 8, 13, 0, 32, 3, 32, 1, 75, 13, 0, 32, 2, 32, 3, 75, 13, 4, 2, 64, 2, 64, 32, 2, 69, 13, 0, 32, 1, 32, 2, 70, 13, 0, 32, 1, 32, 2, 77, 13, 1, 32, 0, 32, 2, 106, 44, 0, 0, 65, 64, 72, 13, 1, 11, 32, 3, 33, 2, 11, 32, 4, 32, 2, 54, 2, 32, 32, 2, 69, 13, 1, 32, 2, 32, 1, 70, 13, 1, 32, 1, 65, 1, 106, 33, 9, 2, 64, 3, 64, 2, 64, 32, 2, 32, 1, 79, 13, 0, 32, 0, 32, 2, 106, 34, 6, 44, 0, 0, 65, 191, 127, 74, 13, 2, 11, 32, 2, 65, 127, 106, 33, 8, 32, 2, 65, 1, 70, 13, 4, 32, 9, 32, 2, 70, 33, 6, 32, 8, 33, 2, 32, 6, 69, 13, 0, 12, 4, 11, 11, 32, 2, 33, 8, 12, 3, 11, 32, 4, 32, 2, 32, 3, 32, 8, 27, 54, 2, 40, 32, 4, 65, 200, 0, 106, 65, 12, 106, 65, 1, 54, 2, 0, 32, 4, 65, 200, 0, 106, 65, 20, 106, 65, 1, 54, 2, 0, 32, 4, 65, 48, 106, 65, 12, 106, 65, 3, 54, 2, 0, 32, 4, 65, 48, 106, 65, 20, 106, 65, 3, 54, 2, 0, 32, 4, 65, 2, 54, 2, 76, 32, 4, 65, 136, 128, 193, 0, 54, 2, 48, 32, 4, 65, 3, 54, 2, 52, 32, 4, 65, 228, 232, 192, 0, 54, 2, 56, 32, 4, 32, 4, 65, 40, 106, 54, 2, 72, 32, 4, 32, 4, 65, 16, 106, 54, 2, 80, 32, 4, 32, 4, 65, 24, 106, 54, 2, 88, 32, 4, 32, 4, 65, 200, 0, 106, 54, 2, 64, 32, 4, 65, 48, 106, 65, 160, 128, 193, 0, 16, 67, 0, 11, 32, 2, 33, 8, 11, 32, 0, 32, 8, 106, 33, 6, 11, 32, 6, 32, 0, 32, 1, 106, 34, 2, 70, 13, 1, 65, 1, 33, 1, 65, 0, 33, 9, 2, 64, 2, 64, 32, 6, 44, 0, 0, 34, 6, 65, 0, 72, 13, 0, 32, 4, 32, 6, 65, 255, 1, 113, 54, 2, 36, 12, 1, 11, 32, 2, 33, 1, 2, 64, 32, 0, 32, 8, 106, 34, 0, 65, 1, 106, 32, 2, 70, 13, 0, 32, 0, 65, 2, 106, 33, 1, 32, 0, 65, 1, 106, 45, 0, 0, 65, 63, 113, 33, 9, 11, 32, 6, 65, 31, 113, 33, 0, 2, 64, 2, 64, 2, 64, 32, 6, 65, 255, 1, 113, 65, 224, 1, 73, 13, 0, 65, 0, 33, 5, 32, 2, 33, 7, 2, 64, 32, 1, 32, 2, 70, 13, 0, 32, 1, 65, 1, 106, 33, 7, 32, 1, 45, 0, 0, 65, 63, 113, 33, 5, 11, 32, 5, 32, 9, 65, 6, 116, 114, 33, 1, 32, 6, 65, 255, 1, 113, 65, 240, 1, 73, 13, 1, 65, 0, 33, 6, 2, 64, 32, 7, 32, 2, 70, 13, 0, 32, 7, 45, 0, 0, 65, 63, 113, 33, 6, 11, 32, 1, 65, 6, 116, 32, 0, 65, 18, 116, 65, 128, 128, 240, 0, 113, 114, 32, 6, 114, 34, 2, 65, 128, 128, 196, 0, 71, 13, 2, 12, 5, 11, 32, 9, 32, 0, 65, 6, 116, 114, 33, 2, 12, 1, 11, 32, 1, 32, 0, 65, 12, 116, 114, 33, 2, 11, 32, 4, 32, 2, 54, 2, 36, 65, 1, 33, 1, 32, 2, 65, 128, 1, 73, 13, 0, 65, 2, 33, 1, 32, 2, 65, 128, 16, 73, 13, 0, 65, 3, 65, 4, 32, 2, 65, 128, 128, 4, 73, 27, 33, 1, 11, 32, 4, 32, 8, 54, 2, 40, 32, 4, 32, 1, 32, 8, 106, 54, 2, 44, 32, 4, 65, 200, 0, 106, 65, 12, 106, 65, 3, 54, 2, 0, 32, 4, 65, 200, 0, 106, 65, 20, 106, 65, 4, 54, 2, 0, 32, 4, 65, 228, 0, 106, 65, 1, 54, 2, 0, 32, 4, 65, 236, 0, 106, 65, 1, 54, 2, 0, 32, 4, 65, 48, 106, 65, 12, 106, 65, 5, 54, 2, 0, 32, 4, 65, 48, 106, 65, 20, 106, 65, 5, 54, 2, 0, 32, 4, 65, 2, 54, 2, 76, 32, 4, 65, 224, 128, 193, 0, 54, 2, 48, 32, 4, 65, 5, 54, 2, 52, 32, 4, 65, 224, 234, 192, 0, 54, 2, 56, 32, 4, 32, 4, 65, 32, 106, 54, 2, 72, 32, 4, 32, 4, 65, 36, 106, 54, 2, 80, 32, 4, 32, 4, 65, 40, 106, 54, 2, 88, 32, 4, 32, 4, 65, 16, 106, 54, 2, 96, 32, 4, 32, 4, 65, 24, 106, 54, 2, 104, 32, 4, 32, 4, 65, 200, 0, 106, 54, 2, 64, 32, 4, 65, 48, 106, 65, 136, 129, 193, 0, 16, 67, 0, 11, 32, 4, 65, 200, 0, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 4, 65, 200, 0, 106, 65, 20, 106, 65, 1, 54, 2, 0, 32, 4, 65, 228, 0, 106, 65, 1, 54, 2, 0, 32, 4, 65, 48, 106, 65, 12, 106, 65, 4, 54, 2, 0, 32, 4, 65, 48, 106, 65, 20, 106, 65, 4, 54, 2, 0, 32, 4, 65, 2, 54, 2, 76, 32, 4, 65, 176, 128, 193, 0, 54, 2, 48, 32, 4, 65, 4, 54, 2, 52, 32, 4, 65, 208, 233, 192, 0, 54, 2, 56, 32, 4, 32, 4, 65, 8, 106, 54, 2, 72, 32, 4, 32, 4, 65, 12, 106, 54, 2, 80, 32, 4, 32, 4, 65, 16, 106, 54, 2, 88, 32, 4, 32, 4, 65, 24, 106, 54, 2, 96, 32, 4, 32, 4, 65, 200, 0, 106, 54, 2, 64, 32, 4, 65, 48, 106, 65, 208, 128, 193, 0, 16, 67, 0, 11, 65, 224, 129, 193, 0, 16, 79, 0, 11, 57, 1, 1, 127, 2, 64, 32, 1, 65, 127, 76, 13, 0, 2, 64, 2, 64, 32, 1, 69, 13, 0, 32, 1, 16, 34, 34, 2, 13, 1, 0, 0, 11, 65, 1, 33, 2, 11, 32, 0, 32, 1, 54, 2, 4, 32, 0, 32, 2, 54, 2, 0, 15, 11, 16, 35, 0, 11, 128, 27, 2, 9, 127, 1, 126, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 65, 244, 1, 75, 13, 0, 65, 0, 40, 2, 164, 144, 65, 34, 1, 65, 16, 32, 0, 65, 11, 106, 65, 120, 113, 32, 0, 65, 11, 73, 27, 34, 2, 65, 3, 118, 34, 3, 65, 31, 113, 34, 4, 118, 34, 0, 65, 3, 113, 69, 13, 1, 32, 0, 65, 127, 115, 65, 1, 113, 32, 3, 106, 34, 2, 65, 3, 116, 34, 4, 65, 180, 144, 193, 0, 106, 40, 2, 0, 34, 0, 65, 8, 106, 33, 5, 32, 0, 40, 2, 8, 34, 3, 32, 4, 65, 172, 144, 193, 0, 106, 34, 4, 70, 13, 2, 32, 3, 32, 4, 54, 2, 12, 32, 4, 65, 8, 106, 32, 3, 54, 2, 0, 12, 3, 11, 65, 0, 33, 3, 32, 0, 65, 64, 79, 13, 28, 32, 0, 65, 11, 106, 34, 0, 65, 120, 113, 33, 2, 65, 0, 40, 2, 168, 144, 65, 34, 6, 69, 13, 9, 65, 0, 33, 7, 2, 64, 32, 0, 65, 8, 118, 34, 0, 69, 13, 0, 65, 31, 33, 7, 32, 2, 65, 255, 255, 255, 7, 75, 13, 0, 32, 2, 65, 38, 32, 0, 103, 34, 0, 107, 65, 31, 113, 118, 65, 1, 113, 65, 31, 32, 0, 107, 65, 1, 116, 114, 33, 7, 11, 65, 0, 32, 2, 107, 33, 3, 32, 7, 65, 2, 116, 65, 180, 146, 193, 0, 106, 40, 2, 0, 34, 0, 69, 13, 6, 65, 0, 33, 4, 32, 2, 65, 0, 65, 25, 32, 7, 65, 1, 118, 107, 65, 31, 113, 32, 7, 65, 31, 70, 27, 116, 33, 1, 65, 0, 33, 5, 3, 64, 2, 64, 32, 0, 40, 2, 4, 65, 120, 113, 34, 8, 32, 2, 73, 13, 0, 32, 8, 32, 2, 107, 34, 8, 32, 3, 79, 13, 0, 32, 8, 33, 3, 32, 0, 33, 5, 32, 8, 69, 13, 6, 11, 32, 0, 65, 20, 106, 40, 2, 0, 34, 8, 32, 4, 32, 8, 32, 0, 32, 1, 65, 29, 118, 65, 4, 113, 106, 65, 16, 106, 40, 2, 0, 34, 0, 71, 27, 32, 4, 32, 8, 27, 33, 4, 32, 1, 65, 1, 116, 33, 1, 32, 0, 13, 0, 11, 32, 4, 69, 13, 5, 32, 4, 33, 0, 12, 7, 11, 32, 2, 65, 0, 40, 2, 180, 147, 65, 77, 13, 8, 32, 0, 69, 13, 2, 32, 0, 32, 4, 116, 65, 2, 32, 4, 116, 34, 0, 65, 0, 32, 0, 107, 114, 113, 34, 0, 65, 0, 32, 0, 107, 113, 104, 34, 3, 65, 3, 116, 34, 5, 65, 180, 144, 193, 0, 106, 40, 2, 0, 34, 0, 40, 2, 8, 34, 4, 32, 5, 65, 172, 144, 193, 0, 106, 34, 5, 70, 13, 10, 32, 4, 32, 5, 54, 2, 12, 32, 5, 65, 8, 106, 32, 4, 54, 2, 0, 12, 11, 11, 65, 0, 32, 1, 65, 126, 32, 2, 119, 113, 54, 2, 164, 144, 65, 11, 32, 0, 32, 2, 65, 3, 116, 34, 2, 65, 3, 114, 54, 2, 4, 32, 0, 32, 2, 106, 34, 0, 32, 0, 40, 2, 4, 65, 1, 114, 54, 2, 4, 32, 5, 15, 11, 65, 0, 40, 2, 168, 144, 65, 34, 0, 69, 13, 5, 32, 0, 65, 0, 32, 0, 107, 113, 104, 65, 2, 116, 65, 180, 146, 193, 0, 106, 40, 2, 0, 34, 1, 40, 2, 4, 65, 120, 113, 32, 2, 107, 33, 3, 32, 1, 33, 4, 32, 1, 40, 2, 16, 34, 0, 69, 13, 20, 65, 0, 33, 9, 12, 21, 11, 65, 0, 33, 3, 32, 0, 33, 5, 12, 2, 11, 32, 5, 13, 2, 11, 65, 0, 33, 5, 65, 2, 32, 7, 65, 31, 113, 116, 34, 0, 65, 0, 32, 0, 107, 114, 32, 6, 113, 34, 0, 69, 13, 2, 32, 0, 65, 0, 32, 0, 107, 113, 104, 65, 2, 116, 65, 180, 146, 193, 0, 106, 40, 2, 0, 34, 0, 69, 13, 2, 11, 3, 64, 32, 0, 40, 2, 4, 65, 120, 113, 34, 4, 32, 2, 79, 32, 4, 32, 2, 107, 34, 8, 32, 3, 73, 113, 33, 1, 2, 64, 32, 0, 40, 2, 16, 34, 4, 13, 0, 32, 0, 65, 20, 106, 40, 2, 0, 33, 4, 11, 32, 0, 32, 5, 32, 1, 27, 33, 5, 32, 8, 32, 3, 32, 1, 27, 33, 3, 32, 4, 33, 0, 32, 4, 13, 0, 11, 32, 5, 69, 13, 1, 11, 65, 0, 40, 2, 180, 147, 65, 34, 0, 32, 2, 73, 13, 1, 32, 3, 32, 0, 32, 2, 107, 73, 13, 1, 11, 2, 64, 2, 64, 2, 64, 2, 64, 65, 0, 40, 2, 180, 147, 65, 34, 3, 32, 2, 79, 13, 0, 65, 0, 40, 2, 184, 147, 65, 34, 0, 32, 2, 77, 13, 1, 65, 0, 32, 0, 32, 2, 107, 34, 3, 54, 2, 184, 147, 65, 65, 0, 65, 0, 40, 2, 192, 147, 65, 34, 0, 32, 2, 106, 34, 4, 54, 2, 192, 147, 65, 32, 4, 32, 3, 65, 1, 114, 54, 2, 4, 32, 0, 32, 2, 65, 3, 114, 54, 2, 4, 32, 0, 65, 8, 106, 15, 11, 65, 0, 40, 2, 188, 147, 65, 33, 0, 32, 3, 32, 2, 107, 34, 4, 65, 16, 79, 13, 1, 65, 0, 65, 0, 54, 2, 188, 147, 65, 65, 0, 65, 0, 54, 2, 180, 147, 65, 32, 0, 32, 3, 65, 3, 114, 54, 2, 4, 32, 0, 32, 3, 106, 34, 3, 65, 4, 106, 33, 2, 32, 3, 40, 2, 4, 65, 1, 114, 33, 3, 12, 2, 11, 65, 0, 33, 3, 32, 2, 65, 175, 128, 4, 106, 34, 4, 65, 16, 118, 64, 0, 34, 0, 65, 127, 70, 13, 20, 32, 0, 65, 16, 116, 34, 1, 69, 13, 20, 65, 0, 65, 0, 40, 2, 196, 147, 65, 32, 4, 65, 128, 128, 124, 113, 34, 8, 106, 34, 0, 54, 2, 196, 147, 65, 65, 0, 65, 0, 40, 2, 200, 147, 65, 34, 3, 32, 0, 32, 0, 32, 3, 73, 27, 54, 2, 200, 147, 65, 65, 0, 40, 2, 192, 147, 65, 34, 3, 69, 13, 9, 65, 204, 147, 193, 0, 33, 0, 3, 64, 32, 0, 40, 2, 0, 34, 4, 32, 0, 40, 2, 4, 34, 5, 106, 32, 1, 70, 13, 11, 32, 0, 40, 2, 8, 34, 0, 13, 0, 12, 19, 11, 11, 65, 0, 32, 4, 54, 2, 180, 147, 65, 65, 0, 32, 0, 32, 2, 106, 34, 1, 54, 2, 188, 147, 65, 32, 1, 32, 4, 65, 1, 114, 54, 2, 4, 32, 0, 32, 3, 106, 32, 4, 54, 2, 0, 32, 2, 65, 3, 114, 33, 3, 32, 0, 65, 4, 106, 33, 2, 11, 32, 2, 32, 3, 54, 2, 0, 32, 0, 65, 8, 106, 15, 11, 32, 5, 16, 96, 32, 3, 65, 15, 75, 13, 2, 32, 5, 32, 3, 32, 2, 106, 34, 0, 65, 3, 114, 54, 2, 4, 32, 5, 32, 0, 106, 34, 0, 32, 0, 40, 2, 4, 65, 1, 114, 54, 2, 4, 12, 12, 11, 65, 0, 32, 1, 65, 126, 32, 3, 119, 113, 54, 2, 164, 144, 65, 11, 32, 0, 65, 8, 106, 33, 4, 32, 0, 32, 2, 65, 3, 114, 54, 2, 4, 32, 0, 32, 2, 106, 34, 1, 32, 3, 65, 3, 116, 34, 3, 32, 2, 107, 34, 2, 65, 1, 114, 54, 2, 4, 32, 0, 32, 3, 106, 32, 2, 54, 2, 0, 65, 0, 40, 2, 180, 147, 65, 34, 0, 69, 13, 3, 32, 0, 65, 3, 118, 34, 5, 65, 3, 116, 65, 172, 144, 193, 0, 106, 33, 3, 65, 0, 40, 2, 188, 147, 65, 33, 0, 65, 0, 40, 2, 164, 144, 65, 34, 8, 65, 1, 32, 5, 65, 31, 113, 116, 34, 5, 113, 69, 13, 1, 32, 3, 40, 2, 8, 33, 5, 12, 2, 11, 32, 5, 32, 2, 65, 3, 114, 54, 2, 4, 32, 5, 32, 2, 106, 34, 0, 32, 3, 65, 1, 114, 54, 2, 4, 32, 0, 32, 3, 106, 32, 3, 54, 2, 0, 32, 3, 65, 255, 1, 75, 13, 5, 32, 3, 65, 3, 118, 34, 3, 65, 3, 116, 65, 172, 144, 193, 0, 106, 33, 2, 65, 0, 40, 2, 164, 144, 65, 34, 4, 65, 1, 32, 3, 65, 31, 113, 116, 34, 3, 113, 69, 13, 7, 32, 2, 65, 8, 106, 33, 4, 32, 2, 40, 2, 8, 33, 3, 12, 8, 11, 65, 0, 32, 8, 32, 5, 114, 54, 2, 164, 144, 65, 32, 3, 33, 5, 11, 32, 3, 65, 8, 106, 32, 0, 54, 2, 0, 32, 5, 32, 0, 54, 2, 12, 32, 0, 32, 3, 54, 2, 12, 32, 0, 32, 5, 54, 2, 8, 11, 65, 0, 32, 1, 54, 2, 188, 147, 65, 65, 0, 32, 2, 54, 2, 180, 147, 65, 32, 4, 15, 11, 2, 64, 2, 64, 65, 0, 40, 2, 224, 147, 65, 34, 0, 69, 13, 0, 32, 0, 32, 1, 77, 13, 1, 11, 65, 0, 32, 1, 54, 2, 224, 147, 65, 11, 65, 0, 33, 0, 65, 0, 32, 8, 54, 2, 208, 147, 65, 65, 0, 32, 1, 54, 2, 204, 147, 65, 65, 0, 65, 255, 31, 54, 2, 228, 147, 65, 65, 0, 65, 0, 54, 2, 216, 147, 65, 3, 64, 32, 0, 65, 180, 144, 193, 0, 106, 32, 0, 65, 172, 144, 193, 0, 106, 34, 3, 54, 2, 0, 32, 0, 65, 184, 144, 193, 0, 106, 32, 3, 54, 2, 0, 32, 0, 65, 8, 106, 34, 0, 65, 128, 2, 71, 13, 0, 11, 65, 0, 32, 1, 54, 2, 192, 147, 65, 65, 0, 32, 8, 65, 88, 106, 34, 0, 54, 2, 184, 147, 65, 32, 1, 32, 0, 65, 1, 114, 54, 2, 4, 32, 1, 32, 0, 106, 65, 40, 54, 2, 4, 65, 0, 65, 128, 128, 128, 1, 54, 2, 220, 147, 65, 12, 9, 11, 32, 0, 40, 2, 12, 69, 13, 1, 12, 7, 11, 32, 0, 32, 3, 16, 97, 12, 3, 11, 32, 1, 32, 3, 77, 13, 5, 32, 4, 32, 3, 75, 13, 5, 32, 0, 65, 4, 106, 32, 5, 32, 8, 106, 54, 2, 0, 65, 0, 65, 0, 40, 2, 192, 147, 65, 34, 0, 65, 15, 106, 65, 120, 113, 34, 3, 65, 120, 106, 34, 4, 54, 2, 192, 147, 65, 65, 0, 65, 0, 40, 2, 184, 147, 65, 32, 8, 106, 34, 1, 32, 0, 65, 8, 106, 32, 3, 107, 106, 34, 3, 54, 2, 184, 147, 65, 32, 4, 32, 3, 65, 1, 114, 54, 2, 4, 32, 0, 32, 1, 106, 65, 40, 54, 2, 4, 65, 0, 65, 128, 128, 128, 1, 54, 2, 220, 147, 65, 12, 6, 11, 65, 0, 32, 4, 32, 3, 114, 54, 2, 164, 144, 65, 32, 2, 65, 8, 106, 33, 4, 32, 2, 33, 3, 11, 32, 4, 32, 0, 54, 2, 0, 32, 3, 32, 0, 54, 2, 12, 32, 0, 32, 2, 54, 2, 12, 32, 0, 32, 3, 54, 2, 8, 11, 32, 5, 65, 8, 106, 33, 3, 12, 4, 11, 65, 1, 33, 9, 11, 3, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 9, 14, 11, 0, 1, 2, 4, 5, 6, 8, 9, 10, 7, 3, 3, 11, 32, 0, 40, 2, 4, 65, 120, 113, 32, 2, 107, 34, 1, 32, 3, 32, 1, 32, 3, 73, 34, 1, 27, 33, 3, 32, 0, 32, 4, 32, 1, 27, 33, 4, 32, 0, 34, 1, 40, 2, 16, 34, 0, 13, 10, 65, 1, 33, 9, 12, 17, 11, 32, 1, 65, 20, 106, 40, 2, 0, 34, 0, 13, 10, 65, 2, 33, 9, 12, 16, 11, 32, 4, 16, 96, 32, 3, 65, 16, 79, 13, 10, 65, 10, 33, 9, 12, 15, 11, 32, 4, 32, 3, 32, 2, 106, 34, 0, 65, 3, 114, 54, 2, 4, 32, 4, 32, 0, 106, 34, 0, 32, 0, 40, 2, 4, 65, 1, 114, 54, 2, 4, 12, 13, 11, 32, 4, 32, 2, 65, 3, 114, 54, 2, 4, 32, 4, 32, 2, 106, 34, 2, 32, 3, 65, 1, 114, 54, 2, 4, 32, 2, 32, 3, 106, 32, 3, 54, 2, 0, 65, 0, 40, 2, 180, 147, 65, 34, 0, 69, 13, 9, 65, 4, 33, 9, 12, 13, 11, 32, 0, 65, 3, 118, 34, 5, 65, 3, 116, 65, 172, 144, 193, 0, 106, 33, 1, 65, 0, 40, 2, 188, 147, 65, 33, 0, 65, 0, 40, 2, 164, 144, 65, 34, 8, 65, 1, 32, 5, 65, 31, 113, 116, 34, 5, 113, 69, 13, 9, 65, 5, 33, 9, 12, 12, 11, 32, 1, 40, 2, 8, 33, 5, 12, 9, 11, 65, 0, 32, 8, 32, 5, 114, 54, 2, 164, 144, 65, 32, 1, 33, 5, 65, 6, 33, 9, 12, 10, 11, 32, 1, 65, 8, 106, 32, 0, 54, 2, 0, 32, 5, 32, 0, 54, 2, 12, 32, 0, 32, 1, 54, 2, 12, 32, 0, 32, 5, 54, 2, 8, 65, 7, 33, 9, 12, 9, 11, 65, 0, 32, 2, 54, 2, 188, 147, 65, 65, 0, 32, 3, 54, 2, 180, 147, 65, 65, 8, 33, 9, 12, 8, 11, 32, 4, 65, 8, 106, 15, 11, 65, 0, 33, 9, 12, 6, 11, 65, 0, 33, 9, 12, 5, 11, 65, 3, 33, 9, 12, 4, 11, 65, 7, 33, 9, 12, 3, 11, 65, 9, 33, 9, 12, 2, 11, 65, 6, 33, 9, 12, 1, 11, 65, 8, 33, 9, 12, 0, 11, 11, 65, 0, 65, 0, 40, 2, 224, 147, 65, 34, 0, 32, 1, 32, 0, 32, 1, 73, 27, 54, 2, 224, 147, 65, 32, 1, 32, 8, 106, 33, 4, 65, 204, 147, 193, 0, 33, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 3, 64, 32, 0, 40, 2, 0, 32, 4, 70, 13, 1, 32, 0, 40, 2, 8, 34, 0, 13, 0, 12, 2, 11, 11, 32, 0, 40, 2, 12, 69, 13, 1, 11, 65, 204, 147, 193, 0, 33, 0, 2, 64, 3, 64, 2, 64, 32, 0, 40, 2, 0, 34, 4, 32, 3, 75, 13, 0, 32, 4, 32, 0, 40, 2, 4, 106, 34, 4, 32, 3, 75, 13, 2, 11, 32, 0, 40, 2, 8, 33, 0, 12, 0, 11, 11, 65, 0, 32, 1, 54, 2, 192, 147, 65, 65, 0, 32, 8, 65, 88, 106, 34, 0, 54, 2, 184, 147, 65, 32, 1, 32, 0, 65, 1, 114, 54, 2, 4, 32, 1, 32, 0, 106, 65, 40, 54, 2, 4, 65, 0, 65, 128, 128, 128, 1, 54, 2, 220, 147, 65, 32, 3, 32, 4, 65, 96, 106, 65, 120, 113, 65, 120, 106, 34, 0, 32, 0, 32, 3, 65, 16, 106, 73, 27, 34, 5, 65, 27, 54, 2, 4, 65, 0, 41, 2, 204, 147, 65, 33, 10, 32, 5, 65, 16, 106, 65, 0, 41, 2, 212, 147, 65, 55, 2, 0, 32, 5, 32, 10, 55, 2, 8, 65, 0, 32, 8, 54, 2, 208, 147, 65, 65, 0, 32, 1, 54, 2, 204, 147, 65, 65, 0, 32, 5, 65, 8, 106, 54, 2, 212, 147, 65, 65, 0, 65, 0, 54, 2, 216, 147, 65, 32, 5, 65, 28, 106, 33, 0, 3, 64, 32, 0, 65, 7, 54, 2, 0, 32, 4, 32, 0, 65, 4, 106, 34, 0, 75, 13, 0, 11, 32, 5, 32, 3, 70, 13, 3, 32, 5, 32, 5, 40, 2, 4, 65, 126, 113, 54, 2, 4, 32, 3, 32, 5, 32, 3, 107, 34, 0, 65, 1, 114, 54, 2, 4, 32, 5, 32, 0, 54, 2, 0, 2, 64, 32, 0, 65, 255, 1, 75, 13, 0, 32, 0, 65, 3, 118, 34, 4, 65, 3, 116, 65, 172, 144, 193, 0, 106, 33, 0, 65, 0, 40, 2, 164, 144, 65, 34, 1, 65, 1, 32, 4, 65, 31, 113, 116, 34, 4, 113, 69, 13, 2, 32, 0, 40, 2, 8, 33, 4, 12, 3, 11, 32, 3, 32, 0, 16, 97, 12, 3, 11, 32, 0, 32, 1, 54, 2, 0, 32, 0, 32, 0, 40, 2, 4, 32, 8, 106, 54, 2, 4, 32, 1, 32, 2, 65, 3, 114, 54, 2, 4, 32, 1, 32, 2, 106, 33, 0, 32, 4, 32, 1, 107, 32, 2, 107, 33, 2, 65, 0, 40, 2, 192, 147, 65, 32, 4, 70, 13, 4, 65, 0, 40, 2, 188, 147, 65, 32, 4, 70, 13, 5, 32, 4, 40, 2, 4, 34, 3, 65, 3, 113, 65, 1, 71, 13, 9, 32, 3, 65, 120, 113, 34, 5, 65, 255, 1, 75, 13, 6, 32, 4, 40, 2, 12, 34, 8, 32, 4, 40, 2, 8, 34, 7, 70, 13, 7, 32, 7, 32, 8, 54, 2, 12, 32, 8, 32, 7, 54, 2, 8, 12, 8, 11, 65, 0, 32, 1, 32, 4, 114, 54, 2, 164, 144, 65, 32, 0, 33, 4, 11, 32, 0, 65, 8, 106, 32, 3, 54, 2, 0, 32, 4, 32, 3, 54, 2, 12, 32, 3, 32, 0, 54, 2, 12, 32, 3, 32, 4, 54, 2, 8, 11, 65, 0, 33, 3, 65, 0, 40, 2, 184, 147, 65, 34, 0, 32, 2, 77, 13, 0, 65, 0, 32, 0, 32, 2, 107, 34, 3, 54, 2, 184, 147, 65, 65, 0, 65, 0, 40, 2, 192, 147, 65, 34, 0, 32, 2, 106, 34, 4, 54, 2, 192, 147, 65, 32, 4, 32, 3, 65, 1, 114, 54, 2, 4, 32, 0, 32, 2, 65, 3, 114, 54, 2, 4, 32, 0, 65, 8, 106, 15, 11, 32, 3, 15, 11, 65, 0, 32, 0, 54, 2, 192, 147, 65, 65, 0, 65, 0, 40, 2, 184, 147, 65, 32, 2, 106, 34, 2, 54, 2, 184, 147, 65, 32, 0, 32, 2, 65, 1, 114, 54, 2, 4, 12, 5, 11, 65, 0, 32, 0, 54, 2, 188, 147, 65, 65, 0, 65, 0, 40, 2, 180, 147, 65, 32, 2, 106, 34, 2, 54, 2, 180, 147, 65, 32, 0, 32, 2, 65, 1, 114, 54, 2, 4, 32, 0, 32, 2, 106, 32, 2, 54, 2, 0, 12, 4, 11, 32, 4, 16, 96, 12, 1, 11, 65, 0, 65, 0, 40, 2, 164, 144, 65, 65, 126, 32, 3, 65, 3, 118, 119, 113, 54, 2, 164, 144, 65, 11, 32, 5, 32, 2, 106, 33, 2, 32, 4, 32, 5, 106, 33, 4, 11, 32, 4, 32, 4, 40, 2, 4, 65, 126, 113, 54, 2, 4, 32, 0, 32, 2, 65, 1, 114, 54, 2, 4, 32, 0, 32, 2, 106, 32, 2, 54, 2, 0, 2, 64, 2, 64, 2, 64, 32, 2, 65, 255, 1, 75, 13, 0, 32, 2, 65, 3, 118, 34, 3, 65, 3, 116, 65, 172, 144, 193, 0, 106, 33, 2, 65, 0, 40, 2, 164, 144, 65, 34, 4, 65, 1, 32, 3, 65, 31, 113, 116, 34, 3, 113, 69, 13, 1, 32, 2, 65, 8, 106, 33, 4, 32, 2, 40, 2, 8, 33, 3, 12, 2, 11, 32, 0, 32, 2, 16, 97, 12, 2, 11, 65, 0, 32, 4, 32, 3, 114, 54, 2, 164, 144, 65, 32, 2, 65, 8, 106, 33, 4, 32, 2, 33, 3, 11, 32, 4, 32, 0, 54, 2, 0, 32, 3, 32, 0, 54, 2, 12, 32, 0, 32, 2, 54, 2, 12, 32, 0, 32, 3, 54, 2, 8, 11, 32, 1, 65, 8, 106, 11, 5, 0, 16, 36, 0, 11, 10, 0, 65, 176, 255, 192, 0, 16, 79, 0, 11, 20, 0, 2, 64, 32, 0, 40, 2, 4, 69, 13, 0, 32, 0, 40, 2, 0, 16, 31, 11, 11, 108, 1, 2, 127, 65, 1, 33, 1, 2, 64, 2, 64, 2, 64, 2, 64, 65, 0, 40, 2, 152, 144, 65, 65, 1, 71, 13, 0, 65, 0, 65, 0, 40, 2, 156, 144, 65, 65, 1, 106, 34, 1, 54, 2, 156, 144, 65, 32, 1, 65, 3, 73, 13, 1, 12, 2, 11, 65, 0, 66, 129, 128, 128, 128, 16, 55, 3, 152, 144, 65, 11, 65, 0, 40, 2, 160, 144, 65, 34, 2, 65, 127, 76, 13, 0, 65, 0, 32, 2, 54, 2, 160, 144, 65, 32, 1, 65, 2, 73, 13, 1, 11, 0, 0, 11, 16, 94, 0, 11, 16, 0, 32, 1, 32, 0, 40, 2, 0, 32, 0, 40, 2, 4, 16, 40, 11, 185, 10, 1, 12, 127, 35, 0, 65, 16, 107, 34, 3, 36, 0, 32, 0, 40, 2, 16, 33, 4, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 40, 2, 8, 34, 5, 65, 1, 71, 13, 0, 32, 4, 13, 1, 12, 8, 11, 32, 4, 69, 13, 1, 11, 32, 2, 69, 13, 1, 32, 1, 32, 2, 106, 33, 6, 32, 0, 65, 20, 106, 40, 2, 0, 33, 7, 32, 1, 65, 1, 106, 33, 4, 65, 0, 33, 8, 32, 1, 44, 0, 0, 34, 9, 65, 0, 78, 13, 4, 32, 6, 33, 10, 2, 64, 32, 2, 65, 1, 70, 13, 0, 32, 1, 65, 1, 106, 45, 0, 0, 65, 63, 113, 33, 8, 32, 1, 65, 2, 106, 34, 4, 33, 10, 11, 32, 9, 65, 255, 1, 113, 65, 224, 1, 73, 13, 4, 32, 10, 32, 6, 70, 13, 2, 32, 10, 45, 0, 0, 65, 63, 113, 33, 11, 32, 10, 65, 1, 106, 34, 4, 33, 10, 12, 3, 11, 32, 0, 40, 2, 24, 32, 1, 32, 2, 32, 0, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 4, 12, 7, 11, 65, 0, 33, 2, 32, 5, 13, 4, 12, 5, 11, 65, 0, 33, 11, 32, 6, 33, 10, 11, 32, 9, 65, 255, 1, 113, 65, 240, 1, 73, 13, 0, 32, 9, 65, 31, 113, 33, 12, 32, 11, 32, 8, 65, 6, 116, 114, 33, 8, 2, 64, 2, 64, 32, 10, 32, 6, 70, 13, 0, 32, 10, 65, 1, 106, 33, 4, 32, 10, 45, 0, 0, 65, 63, 113, 33, 9, 12, 1, 11, 65, 0, 33, 9, 11, 32, 8, 65, 6, 116, 32, 12, 65, 18, 116, 65, 128, 128, 240, 0, 113, 114, 32, 9, 114, 65, 128, 128, 196, 0, 70, 13, 1, 11, 2, 64, 2, 64, 2, 64, 2, 64, 32, 7, 69, 13, 0, 32, 4, 32, 1, 107, 33, 9, 3, 64, 32, 9, 33, 8, 32, 6, 32, 4, 34, 9, 70, 13, 5, 32, 9, 65, 1, 106, 33, 4, 2, 64, 32, 9, 44, 0, 0, 34, 10, 65, 0, 78, 13, 0, 2, 64, 2, 64, 32, 4, 32, 6, 70, 13, 0, 32, 4, 45, 0, 0, 65, 63, 113, 33, 13, 32, 9, 65, 2, 106, 34, 11, 33, 4, 12, 1, 11, 65, 0, 33, 13, 32, 6, 33, 11, 11, 32, 10, 65, 255, 1, 113, 34, 12, 65, 224, 1, 73, 13, 0, 2, 64, 2, 64, 32, 11, 32, 6, 70, 13, 0, 32, 11, 45, 0, 0, 65, 63, 113, 33, 14, 32, 11, 65, 1, 106, 34, 4, 33, 11, 32, 12, 65, 240, 1, 79, 13, 1, 12, 2, 11, 65, 0, 33, 14, 32, 6, 33, 11, 32, 12, 65, 240, 1, 73, 13, 1, 11, 32, 10, 65, 31, 113, 33, 10, 32, 14, 32, 13, 65, 6, 116, 114, 33, 12, 2, 64, 2, 64, 32, 11, 32, 6, 70, 13, 0, 32, 11, 65, 1, 106, 33, 4, 32, 11, 45, 0, 0, 65, 63, 113, 33, 11, 12, 1, 11, 65, 0, 33, 11, 11, 32, 12, 65, 6, 116, 32, 10, 65, 18, 116, 65, 128, 128, 240, 0, 113, 114, 32, 11, 114, 65, 128, 128, 196, 0, 70, 13, 6, 11, 32, 8, 32, 9, 107, 32, 4, 106, 33, 9, 32, 7, 65, 127, 106, 34, 7, 13, 0, 11, 32, 8, 69, 13, 2, 12, 1, 11, 65, 0, 33, 8, 65, 0, 69, 13, 1, 11, 32, 8, 32, 2, 70, 13, 0, 65, 0, 33, 4, 32, 8, 32, 2, 79, 13, 1, 32, 1, 32, 8, 106, 44, 0, 0, 65, 64, 72, 13, 1, 11, 32, 1, 33, 4, 11, 32, 8, 32, 2, 32, 4, 27, 33, 2, 32, 4, 32, 1, 32, 4, 27, 33, 1, 11, 32, 5, 69, 13, 1, 11, 65, 0, 33, 9, 2, 64, 32, 2, 69, 13, 0, 32, 2, 33, 8, 32, 1, 33, 4, 3, 64, 32, 9, 32, 4, 45, 0, 0, 65, 192, 1, 113, 65, 128, 1, 70, 106, 33, 9, 32, 4, 65, 1, 106, 33, 4, 32, 8, 65, 127, 106, 34, 8, 13, 0, 11, 11, 2, 64, 2, 64, 2, 64, 2, 64, 32, 2, 32, 9, 107, 32, 0, 65, 12, 106, 40, 2, 0, 34, 7, 79, 13, 0, 65, 0, 33, 9, 2, 64, 32, 2, 69, 13, 0, 65, 0, 33, 9, 32, 2, 33, 8, 32, 1, 33, 4, 3, 64, 32, 9, 32, 4, 45, 0, 0, 65, 192, 1, 113, 65, 128, 1, 70, 106, 33, 9, 32, 4, 65, 1, 106, 33, 4, 32, 8, 65, 127, 106, 34, 8, 13, 0, 11, 11, 32, 9, 32, 2, 107, 32, 7, 106, 33, 8, 65, 0, 32, 0, 45, 0, 48, 34, 4, 32, 4, 65, 3, 70, 27, 65, 3, 113, 34, 4, 69, 13, 1, 32, 4, 65, 2, 70, 13, 2, 65, 0, 33, 7, 12, 3, 11, 32, 0, 40, 2, 24, 32, 1, 32, 2, 32, 0, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 4, 12, 4, 11, 32, 8, 33, 7, 65, 0, 33, 8, 12, 1, 11, 32, 8, 65, 1, 106, 65, 1, 118, 33, 7, 32, 8, 65, 1, 118, 33, 8, 11, 32, 3, 65, 0, 54, 2, 12, 2, 64, 2, 64, 32, 0, 40, 2, 4, 34, 4, 65, 255, 0, 75, 13, 0, 32, 3, 32, 4, 58, 0, 12, 65, 1, 33, 9, 12, 1, 11, 2, 64, 32, 4, 65, 255, 15, 75, 13, 0, 32, 3, 32, 4, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 3, 32, 4, 65, 6, 118, 65, 31, 113, 65, 192, 1, 114, 58, 0, 12, 65, 2, 33, 9, 12, 1, 11, 2, 64, 32, 4, 65, 255, 255, 3, 75, 13, 0, 32, 3, 32, 4, 65, 63, 113, 65, 128, 1, 114, 58, 0, 14, 32, 3, 32, 4, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 3, 32, 4, 65, 12, 118, 65, 15, 113, 65, 224, 1, 114, 58, 0, 12, 65, 3, 33, 9, 12, 1, 11, 32, 3, 32, 4, 65, 18, 118, 65, 240, 1, 114, 58, 0, 12, 32, 3, 32, 4, 65, 63, 113, 65, 128, 1, 114, 58, 0, 15, 32, 3, 32, 4, 65, 12, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 3, 32, 4, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 14, 65, 4, 33, 9, 11, 65, 127, 33, 4, 2, 64, 2, 64, 2, 64, 3, 64, 32, 4, 65, 1, 106, 34, 4, 32, 8, 79, 13, 1, 32, 0, 65, 24, 106, 40, 2, 0, 32, 3, 65, 12, 106, 32, 9, 32, 0, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 69, 13, 0, 12, 2, 11, 11, 32, 0, 65, 24, 106, 34, 8, 40, 2, 0, 32, 1, 32, 2, 32, 0, 65, 28, 106, 34, 0, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 0, 65, 127, 33, 4, 3, 64, 32, 4, 65, 1, 106, 34, 4, 32, 7, 79, 13, 2, 32, 8, 40, 2, 0, 32, 3, 65, 12, 106, 32, 9, 32, 0, 40, 2, 0, 40, 2, 12, 17, 1, 0, 69, 13, 0, 11, 11, 65, 1, 33, 4, 12, 2, 11, 65, 0, 33, 4, 12, 1, 11, 32, 0, 40, 2, 24, 32, 1, 32, 2, 32, 0, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 4, 11, 32, 3, 65, 16, 106, 36, 0, 32, 4, 11, 126, 1, 2, 127, 2, 64, 32, 0, 40, 2, 4, 34, 2, 32, 0, 40, 2, 8, 34, 3, 107, 32, 1, 79, 13, 0, 2, 64, 2, 64, 32, 3, 32, 1, 106, 34, 1, 32, 3, 73, 13, 0, 32, 2, 65, 1, 116, 34, 3, 32, 1, 32, 1, 32, 3, 73, 27, 34, 1, 65, 0, 72, 13, 0, 2, 64, 2, 64, 32, 2, 69, 13, 0, 32, 0, 40, 2, 0, 32, 1, 16, 42, 34, 2, 69, 13, 1, 12, 3, 11, 32, 1, 65, 1, 16, 43, 34, 2, 13, 2, 11, 0, 0, 11, 16, 36, 0, 11, 32, 0, 32, 2, 54, 2, 0, 32, 0, 65, 4, 106, 32, 1, 54, 2, 0, 11, 11, 230, 5, 1, 8, 127, 65, 0, 33, 2, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 1, 65, 191, 127, 75, 13, 0, 65, 16, 32, 1, 65, 11, 106, 65, 120, 113, 32, 1, 65, 11, 73, 27, 33, 3, 32, 0, 65, 124, 106, 34, 4, 40, 2, 0, 34, 5, 65, 120, 113, 33, 6, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 5, 65, 3, 113, 69, 13, 0, 32, 0, 65, 120, 106, 34, 7, 32, 6, 106, 33, 8, 32, 6, 32, 3, 79, 13, 1, 65, 0, 40, 2, 192, 147, 65, 32, 8, 70, 13, 2, 65, 0, 40, 2, 188, 147, 65, 32, 8, 70, 13, 3, 32, 8, 40, 2, 4, 34, 5, 65, 2, 113, 13, 4, 32, 5, 65, 120, 113, 34, 9, 32, 6, 106, 34, 6, 32, 3, 73, 13, 4, 32, 6, 32, 3, 107, 33, 1, 32, 9, 65, 255, 1, 75, 13, 7, 32, 8, 40, 2, 12, 34, 2, 32, 8, 40, 2, 8, 34, 8, 70, 13, 8, 32, 8, 32, 2, 54, 2, 12, 32, 2, 32, 8, 54, 2, 8, 12, 9, 11, 32, 3, 65, 128, 2, 73, 13, 3, 32, 6, 32, 3, 65, 4, 114, 73, 13, 3, 32, 6, 32, 3, 107, 65, 129, 128, 8, 79, 13, 3, 32, 0, 15, 11, 2, 64, 32, 6, 32, 3, 107, 34, 1, 65, 16, 79, 13, 0, 32, 0, 15, 11, 32, 4, 32, 3, 32, 5, 65, 1, 113, 114, 65, 2, 114, 54, 2, 0, 32, 7, 32, 3, 106, 34, 2, 32, 1, 65, 3, 114, 54, 2, 4, 32, 8, 32, 8, 40, 2, 4, 65, 1, 114, 54, 2, 4, 32, 2, 32, 1, 16, 98, 32, 0, 15, 11, 65, 0, 40, 2, 184, 147, 65, 32, 6, 106, 34, 6, 32, 3, 77, 13, 1, 32, 4, 32, 3, 32, 5, 65, 1, 113, 114, 65, 2, 114, 54, 2, 0, 32, 7, 32, 3, 106, 34, 1, 32, 6, 32, 3, 107, 34, 2, 65, 1, 114, 54, 2, 4, 65, 0, 32, 2, 54, 2, 184, 147, 65, 65, 0, 32, 1, 54, 2, 192, 147, 65, 32, 0, 15, 11, 65, 0, 40, 2, 180, 147, 65, 32, 6, 106, 34, 6, 32, 3, 79, 13, 2, 11, 32, 1, 16, 34, 34, 3, 69, 13, 0, 32, 3, 32, 0, 32, 1, 32, 4, 40, 2, 0, 34, 2, 65, 120, 113, 65, 4, 65, 8, 32, 2, 65, 3, 113, 27, 107, 34, 2, 32, 2, 32, 1, 75, 27, 16, 149, 1, 33, 1, 32, 0, 16, 31, 32, 1, 33, 2, 11, 32, 2, 15, 11, 2, 64, 2, 64, 32, 6, 32, 3, 107, 34, 1, 65, 16, 79, 13, 0, 32, 4, 32, 5, 65, 1, 113, 32, 6, 114, 65, 2, 114, 54, 2, 0, 32, 7, 32, 6, 106, 34, 1, 32, 1, 40, 2, 4, 65, 1, 114, 54, 2, 4, 65, 0, 33, 1, 65, 0, 33, 2, 12, 1, 11, 32, 4, 32, 3, 32, 5, 65, 1, 113, 114, 65, 2, 114, 54, 2, 0, 32, 7, 32, 3, 106, 34, 2, 32, 1, 65, 1, 114, 54, 2, 4, 32, 7, 32, 6, 106, 34, 3, 32, 1, 54, 2, 0, 32, 3, 32, 3, 40, 2, 4, 65, 126, 113, 54, 2, 4, 11, 65, 0, 32, 2, 54, 2, 188, 147, 65, 65, 0, 32, 1, 54, 2, 180, 147, 65, 32, 0, 15, 11, 32, 8, 16, 96, 12, 1, 11, 65, 0, 65, 0, 40, 2, 164, 144, 65, 65, 126, 32, 5, 65, 3, 118, 119, 113, 54, 2, 164, 144, 65, 11, 2, 64, 32, 1, 65, 15, 75, 13, 0, 32, 4, 32, 6, 32, 4, 40, 2, 0, 65, 1, 113, 114, 65, 2, 114, 54, 2, 0, 32, 7, 32, 6, 106, 34, 1, 32, 1, 40, 2, 4, 65, 1, 114, 54, 2, 4, 32, 0, 15, 11, 32, 4, 32, 3, 32, 4, 40, 2, 0, 65, 1, 113, 114, 65, 2, 114, 54, 2, 0, 32, 7, 32, 3, 106, 34, 2, 32, 1, 65, 3, 114, 54, 2, 4, 32, 7, 32, 6, 106, 34, 3, 32, 3, 40, 2, 4, 65, 1, 114, 54, 2, 4, 32, 2, 32, 1, 16, 98, 32, 0, 11, 251, 2, 1, 5, 127, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 1, 65, 8, 77, 13, 0, 65, 0, 33, 2, 65, 64, 32, 1, 65, 16, 32, 1, 65, 16, 75, 27, 34, 1, 107, 32, 0, 77, 13, 4, 32, 1, 65, 16, 32, 0, 65, 11, 106, 65, 120, 113, 32, 0, 65, 11, 73, 27, 34, 3, 106, 65, 12, 106, 16, 34, 34, 0, 69, 13, 4, 32, 0, 65, 120, 106, 33, 2, 32, 1, 65, 127, 106, 34, 4, 32, 0, 113, 69, 13, 1, 32, 0, 65, 124, 106, 34, 5, 40, 2, 0, 34, 6, 65, 120, 113, 32, 4, 32, 0, 106, 65, 0, 32, 1, 107, 113, 65, 120, 106, 34, 0, 32, 0, 32, 1, 106, 32, 0, 32, 2, 107, 65, 16, 75, 27, 34, 1, 32, 2, 107, 34, 0, 107, 33, 4, 32, 6, 65, 3, 113, 69, 13, 2, 32, 1, 32, 4, 32, 1, 40, 2, 4, 65, 1, 113, 114, 65, 2, 114, 54, 2, 4, 32, 1, 32, 4, 106, 34, 4, 32, 4, 40, 2, 4, 65, 1, 114, 54, 2, 4, 32, 5, 32, 0, 32, 5, 40, 2, 0, 65, 1, 113, 114, 65, 2, 114, 54, 2, 0, 32, 1, 32, 1, 40, 2, 4, 65, 1, 114, 54, 2, 4, 32, 2, 32, 0, 16, 98, 12, 3, 11, 32, 0, 16, 34, 15, 11, 32, 2, 33, 1, 12, 1, 11, 32, 2, 40, 2, 0, 33, 2, 32, 1, 32, 4, 54, 2, 4, 32, 1, 32, 2, 32, 0, 106, 54, 2, 0, 11, 2, 64, 32, 1, 40, 2, 4, 34, 0, 65, 3, 113, 69, 13, 0, 32, 0, 65, 120, 113, 34, 2, 32, 3, 65, 16, 106, 77, 13, 0, 32, 1, 65, 4, 106, 32, 3, 32, 0, 65, 1, 113, 114, 65, 2, 114, 54, 2, 0, 32, 1, 32, 3, 106, 34, 0, 32, 2, 32, 3, 107, 34, 3, 65, 3, 114, 54, 2, 4, 32, 1, 32, 2, 106, 34, 2, 32, 2, 40, 2, 4, 65, 1, 114, 54, 2, 4, 32, 0, 32, 3, 16, 98, 11, 32, 1, 65, 8, 106, 33, 2, 11, 32, 2, 11, 124, 1, 2, 127, 2, 64, 32, 0, 40, 2, 4, 34, 2, 32, 0, 40, 2, 8, 34, 3, 107, 32, 1, 79, 13, 0, 2, 64, 2, 64, 32, 3, 32, 1, 106, 34, 1, 32, 3, 73, 13, 0, 32, 2, 65, 1, 116, 34, 3, 32, 1, 32, 1, 32, 3, 73, 27, 34, 1, 65, 0, 72, 13, 0, 2, 64, 2, 64, 32, 2, 69, 13, 0, 32, 0, 40, 2, 0, 32, 1, 16, 42, 34, 2, 69, 13, 1, 12, 3, 11, 32, 1, 16, 34, 34, 2, 13, 2, 11, 0, 0, 11, 16, 36, 0, 11, 32, 0, 32, 2, 54, 2, 0, 32, 0, 65, 4, 106, 32, 1, 54, 2, 0, 11, 11, 41, 1, 1, 127, 32, 0, 32, 2, 16, 44, 32, 0, 32, 0, 40, 2, 8, 34, 3, 32, 2, 106, 54, 2, 8, 32, 3, 32, 0, 40, 2, 0, 106, 32, 1, 32, 2, 16, 149, 1, 26, 11, 14, 0, 2, 64, 32, 1, 69, 13, 0, 32, 0, 16, 31, 11, 11, 57, 0, 2, 64, 2, 64, 32, 2, 32, 1, 73, 13, 0, 32, 4, 32, 2, 73, 13, 1, 32, 0, 32, 2, 32, 1, 107, 54, 2, 4, 32, 0, 32, 3, 32, 1, 106, 54, 2, 0, 15, 11, 32, 1, 32, 2, 16, 48, 0, 11, 32, 2, 32, 4, 16, 49, 0, 11, 137, 1, 1, 1, 127, 35, 0, 65, 48, 107, 34, 2, 36, 0, 32, 2, 32, 1, 54, 2, 4, 32, 2, 32, 0, 54, 2, 0, 32, 2, 65, 32, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 2, 65, 8, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 2, 65, 28, 106, 65, 2, 54, 2, 0, 32, 2, 65, 2, 54, 2, 36, 32, 2, 65, 232, 255, 192, 0, 54, 2, 8, 32, 2, 65, 2, 54, 2, 12, 32, 2, 65, 148, 251, 192, 0, 54, 2, 16, 32, 2, 32, 2, 54, 2, 32, 32, 2, 32, 2, 65, 4, 106, 54, 2, 40, 32, 2, 32, 2, 65, 32, 106, 54, 2, 24, 32, 2, 65, 8, 106, 65, 248, 255, 192, 0, 16, 67, 0, 11, 137, 1, 1, 1, 127, 35, 0, 65, 48, 107, 34, 2, 36, 0, 32, 2, 32, 1, 54, 2, 4, 32, 2, 32, 0, 54, 2, 0, 32, 2, 65, 32, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 2, 65, 8, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 2, 65, 28, 106, 65, 2, 54, 2, 0, 32, 2, 65, 2, 54, 2, 36, 32, 2, 65, 200, 255, 192, 0, 54, 2, 8, 32, 2, 65, 2, 54, 2, 12, 32, 2, 65, 148, 251, 192, 0, 54, 2, 16, 32, 2, 32, 2, 54, 2, 32, 32, 2, 32, 2, 65, 4, 106, 54, 2, 40, 32, 2, 32, 2, 65, 32, 106, 54, 2, 24, 32, 2, 65, 8, 106, 65, 216, 255, 192, 0, 16, 67, 0, 11, 140, 3, 1, 1, 127, 35, 0, 65, 16, 107, 34, 2, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 45, 0, 0, 65, 127, 106, 34, 0, 65, 4, 75, 13, 0, 2, 64, 32, 0, 14, 5, 0, 2, 3, 4, 5, 0, 11, 32, 2, 32, 1, 40, 2, 24, 65, 151, 130, 192, 0, 65, 10, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 58, 0, 8, 32, 2, 32, 1, 54, 2, 0, 32, 2, 65, 0, 54, 2, 4, 32, 2, 65, 0, 58, 0, 9, 12, 5, 11, 32, 2, 32, 1, 40, 2, 24, 65, 140, 130, 192, 0, 65, 11, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 58, 0, 8, 32, 2, 32, 1, 54, 2, 0, 32, 2, 65, 0, 54, 2, 4, 32, 2, 65, 0, 58, 0, 9, 12, 4, 11, 32, 2, 32, 1, 40, 2, 24, 65, 161, 130, 192, 0, 65, 10, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 58, 0, 8, 32, 2, 32, 1, 54, 2, 0, 32, 2, 65, 0, 54, 2, 4, 32, 2, 65, 0, 58, 0, 9, 12, 3, 11, 32, 2, 32, 1, 40, 2, 24, 65, 171, 130, 192, 0, 65, 13, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 58, 0, 8, 32, 2, 32, 1, 54, 2, 0, 32, 2, 65, 0, 54, 2, 4, 32, 2, 65, 0, 58, 0, 9, 12, 2, 11, 32, 2, 32, 1, 40, 2, 24, 65, 184, 130, 192, 0, 65, 14, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 58, 0, 8, 32, 2, 32, 1, 54, 2, 0, 32, 2, 65, 0, 54, 2, 4, 32, 2, 65, 0, 58, 0, 9, 12, 1, 11, 32, 2, 32, 1, 40, 2, 24, 65, 198, 130, 192, 0, 65, 5, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 58, 0, 8, 32, 2, 32, 1, 54, 2, 0, 32, 2, 65, 0, 54, 2, 4, 32, 2, 65, 0, 58, 0, 9, 11, 32, 2, 16, 51, 33, 1, 32, 2, 65, 16, 106, 36, 0, 32, 1, 11, 211, 1, 1, 3, 127, 32, 0, 45, 0, 8, 33, 1, 2, 64, 32, 0, 40, 2, 4, 34, 2, 69, 13, 0, 32, 1, 65, 255, 1, 113, 33, 3, 65, 1, 33, 1, 2, 64, 32, 3, 13, 0, 2, 64, 32, 0, 40, 2, 0, 34, 3, 45, 0, 0, 65, 4, 113, 69, 13, 0, 65, 1, 33, 1, 32, 3, 40, 2, 24, 65, 147, 238, 192, 0, 65, 1, 32, 3, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 1, 32, 0, 65, 4, 106, 40, 2, 0, 33, 2, 11, 2, 64, 32, 2, 65, 1, 71, 13, 0, 32, 0, 45, 0, 9, 69, 13, 0, 65, 1, 33, 1, 32, 0, 40, 2, 0, 34, 3, 40, 2, 24, 65, 234, 253, 192, 0, 65, 1, 32, 3, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 1, 11, 32, 0, 40, 2, 0, 34, 1, 40, 2, 24, 65, 154, 238, 192, 0, 65, 1, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 11, 32, 0, 65, 8, 106, 32, 1, 58, 0, 0, 11, 32, 1, 65, 255, 1, 113, 65, 0, 71, 11, 10, 0, 65, 136, 254, 192, 0, 16, 38, 0, 11, 98, 2, 3, 127, 1, 126, 32, 1, 40, 2, 8, 33, 2, 32, 1, 40, 2, 0, 33, 3, 2, 64, 2, 64, 32, 1, 40, 2, 4, 34, 1, 40, 2, 0, 34, 4, 69, 13, 0, 32, 3, 65, 1, 106, 33, 3, 32, 1, 51, 1, 4, 66, 32, 134, 32, 2, 173, 132, 33, 5, 12, 1, 11, 32, 2, 173, 33, 5, 65, 0, 33, 4, 11, 32, 1, 16, 31, 32, 0, 32, 4, 54, 2, 4, 32, 0, 32, 3, 54, 2, 0, 32, 0, 32, 5, 55, 2, 8, 11, 98, 2, 3, 127, 1, 126, 32, 1, 40, 2, 8, 33, 2, 32, 1, 40, 2, 0, 33, 3, 2, 64, 2, 64, 32, 1, 40, 2, 4, 34, 1, 40, 2, 0, 34, 4, 69, 13, 0, 32, 3, 65, 1, 106, 33, 3, 32, 1, 51, 1, 4, 66, 32, 134, 32, 2, 173, 132, 33, 5, 12, 1, 11, 32, 2, 173, 33, 5, 65, 0, 33, 4, 11, 32, 1, 16, 31, 32, 0, 32, 4, 54, 2, 4, 32, 0, 32, 3, 54, 2, 0, 32, 0, 32, 5, 55, 2, 8, 11, 126, 1, 2, 127, 2, 64, 32, 0, 40, 2, 4, 34, 2, 32, 0, 40, 2, 8, 34, 3, 107, 32, 1, 79, 13, 0, 2, 64, 2, 64, 32, 3, 32, 1, 106, 34, 1, 32, 3, 73, 13, 0, 32, 2, 65, 1, 116, 34, 3, 32, 1, 32, 1, 32, 3, 73, 27, 34, 1, 65, 0, 72, 13, 0, 2, 64, 2, 64, 32, 2, 69, 13, 0, 32, 0, 40, 2, 0, 32, 1, 16, 42, 34, 2, 69, 13, 1, 12, 3, 11, 32, 1, 65, 1, 16, 43, 34, 2, 13, 2, 11, 0, 0, 11, 16, 36, 0, 11, 32, 0, 32, 2, 54, 2, 0, 32, 0, 65, 4, 106, 32, 1, 54, 2, 0, 11, 11, 117, 1, 2, 127, 32, 0, 40, 2, 8, 65, 24, 108, 33, 1, 32, 0, 40, 2, 0, 33, 0, 2, 64, 3, 64, 32, 1, 69, 13, 1, 2, 64, 32, 0, 45, 0, 0, 34, 2, 65, 7, 113, 65, 3, 73, 13, 0, 2, 64, 2, 64, 32, 2, 65, 4, 70, 13, 0, 32, 2, 65, 3, 71, 13, 1, 32, 0, 65, 4, 106, 16, 57, 12, 2, 11, 32, 0, 65, 4, 106, 34, 2, 16, 56, 32, 2, 16, 58, 12, 1, 11, 32, 0, 65, 4, 106, 16, 59, 11, 32, 0, 65, 24, 106, 33, 0, 32, 1, 65, 104, 106, 33, 1, 12, 0, 11, 11, 11, 20, 0, 2, 64, 32, 0, 40, 2, 4, 69, 13, 0, 32, 0, 40, 2, 0, 16, 31, 11, 11, 20, 0, 2, 64, 32, 0, 40, 2, 4, 69, 13, 0, 32, 0, 40, 2, 0, 16, 31, 11, 11, 163, 7, 1, 11, 127, 35, 0, 65, 144, 1, 107, 34, 1, 36, 0, 32, 0, 40, 2, 8, 33, 2, 32, 0, 40, 2, 4, 33, 3, 2, 64, 3, 64, 32, 0, 40, 2, 0, 33, 0, 32, 3, 69, 13, 1, 32, 0, 65, 152, 3, 106, 33, 0, 32, 3, 65, 127, 106, 33, 3, 12, 0, 11, 11, 32, 1, 65, 232, 0, 106, 65, 16, 106, 33, 4, 65, 0, 33, 3, 65, 0, 33, 5, 2, 64, 2, 64, 3, 64, 32, 2, 69, 13, 1, 2, 64, 2, 64, 32, 3, 32, 0, 47, 1, 6, 79, 13, 0, 32, 1, 65, 48, 106, 65, 8, 106, 34, 6, 32, 0, 32, 3, 65, 12, 108, 106, 34, 7, 65, 16, 106, 40, 2, 0, 54, 2, 0, 32, 1, 32, 7, 65, 8, 106, 41, 2, 0, 55, 3, 48, 32, 1, 65, 208, 0, 106, 65, 16, 106, 34, 8, 32, 0, 32, 3, 65, 24, 108, 106, 34, 7, 65, 160, 1, 106, 41, 3, 0, 55, 3, 0, 32, 1, 65, 208, 0, 106, 65, 8, 106, 34, 9, 32, 7, 65, 152, 1, 106, 41, 3, 0, 55, 3, 0, 32, 1, 32, 7, 65, 144, 1, 106, 41, 3, 0, 55, 3, 80, 32, 1, 65, 232, 0, 106, 65, 8, 106, 32, 6, 40, 2, 0, 54, 2, 0, 32, 4, 32, 1, 41, 3, 80, 55, 3, 0, 32, 4, 65, 8, 106, 32, 9, 41, 3, 0, 55, 3, 0, 32, 4, 65, 16, 106, 32, 8, 41, 3, 0, 55, 3, 0, 32, 1, 32, 1, 41, 3, 48, 55, 3, 104, 32, 1, 65, 8, 106, 32, 1, 65, 232, 0, 106, 65, 40, 16, 149, 1, 26, 32, 3, 65, 1, 106, 33, 3, 12, 1, 11, 32, 1, 65, 232, 0, 106, 65, 8, 106, 34, 6, 32, 5, 54, 2, 0, 32, 1, 32, 0, 54, 2, 108, 32, 1, 65, 0, 54, 2, 104, 32, 1, 65, 48, 106, 32, 1, 65, 232, 0, 106, 16, 53, 2, 64, 3, 64, 32, 1, 65, 48, 106, 65, 8, 106, 40, 2, 0, 33, 5, 32, 1, 40, 2, 48, 33, 3, 32, 1, 65, 48, 106, 65, 12, 106, 40, 2, 0, 34, 7, 32, 1, 40, 2, 52, 34, 0, 47, 1, 6, 73, 13, 1, 32, 6, 32, 5, 54, 2, 0, 32, 1, 32, 0, 54, 2, 108, 32, 1, 32, 3, 54, 2, 104, 32, 1, 65, 48, 106, 32, 1, 65, 232, 0, 106, 16, 54, 12, 0, 11, 11, 32, 1, 65, 192, 0, 106, 65, 8, 106, 34, 9, 32, 0, 32, 7, 65, 12, 108, 106, 34, 8, 65, 16, 106, 40, 2, 0, 54, 2, 0, 32, 1, 32, 8, 65, 8, 106, 41, 2, 0, 55, 3, 64, 32, 1, 65, 208, 0, 106, 65, 16, 106, 34, 10, 32, 0, 32, 7, 65, 24, 108, 106, 34, 8, 65, 160, 1, 106, 41, 3, 0, 55, 3, 0, 32, 1, 65, 208, 0, 106, 65, 8, 106, 34, 11, 32, 8, 65, 152, 1, 106, 41, 3, 0, 55, 3, 0, 32, 1, 32, 8, 65, 144, 1, 106, 41, 3, 0, 55, 3, 80, 65, 1, 32, 3, 107, 33, 3, 32, 0, 32, 7, 65, 2, 116, 106, 65, 156, 3, 106, 33, 0, 2, 64, 3, 64, 32, 0, 40, 2, 0, 33, 0, 32, 3, 69, 13, 1, 32, 3, 65, 1, 106, 33, 3, 32, 0, 65, 152, 3, 106, 33, 0, 12, 0, 11, 11, 32, 4, 32, 1, 41, 3, 80, 55, 3, 0, 32, 6, 32, 9, 40, 2, 0, 54, 2, 0, 32, 4, 65, 8, 106, 32, 11, 41, 3, 0, 55, 3, 0, 32, 4, 65, 16, 106, 32, 10, 41, 3, 0, 55, 3, 0, 32, 1, 32, 1, 41, 3, 64, 55, 3, 104, 32, 1, 65, 8, 106, 32, 1, 65, 232, 0, 106, 65, 40, 16, 149, 1, 26, 65, 0, 33, 3, 11, 32, 1, 65, 8, 106, 65, 16, 106, 45, 0, 0, 65, 6, 70, 13, 2, 32, 2, 65, 127, 106, 33, 2, 32, 1, 65, 232, 0, 106, 32, 1, 65, 8, 106, 65, 40, 16, 149, 1, 26, 32, 1, 65, 232, 0, 106, 16, 83, 12, 0, 11, 11, 32, 1, 65, 24, 106, 65, 6, 58, 0, 0, 11, 32, 1, 65, 8, 106, 16, 84, 2, 64, 32, 0, 65, 200, 217, 192, 0, 70, 13, 0, 32, 1, 32, 0, 54, 2, 108, 32, 1, 65, 0, 54, 2, 104, 32, 1, 32, 5, 54, 2, 112, 32, 1, 65, 8, 106, 32, 1, 65, 232, 0, 106, 16, 53, 32, 1, 40, 2, 12, 69, 13, 0, 32, 1, 65, 48, 106, 65, 8, 106, 34, 3, 32, 1, 65, 8, 106, 65, 8, 106, 40, 2, 0, 54, 2, 0, 32, 1, 32, 1, 41, 3, 8, 55, 3, 48, 3, 64, 32, 1, 65, 208, 0, 106, 65, 8, 106, 32, 3, 40, 2, 0, 54, 2, 0, 32, 1, 32, 1, 41, 3, 48, 55, 3, 80, 32, 1, 65, 232, 0, 106, 32, 1, 65, 208, 0, 106, 16, 54, 32, 1, 40, 2, 108, 69, 13, 1, 32, 3, 32, 1, 65, 232, 0, 106, 65, 8, 106, 40, 2, 0, 54, 2, 0, 32, 1, 32, 1, 41, 3, 104, 55, 3, 48, 12, 0, 11, 11, 32, 1, 65, 144, 1, 106, 36, 0, 11, 41, 1, 1, 127, 32, 0, 32, 2, 16, 55, 32, 0, 32, 0, 40, 2, 8, 34, 3, 32, 2, 106, 54, 2, 8, 32, 3, 32, 0, 40, 2, 0, 106, 32, 1, 32, 2, 16, 149, 1, 26, 11, 2, 0, 11, 96, 1, 1, 127, 35, 0, 65, 32, 107, 34, 2, 36, 0, 32, 2, 32, 0, 54, 2, 4, 32, 2, 65, 8, 106, 65, 16, 106, 32, 1, 65, 16, 106, 41, 2, 0, 55, 3, 0, 32, 2, 65, 8, 106, 65, 8, 106, 32, 1, 65, 8, 106, 41, 2, 0, 55, 3, 0, 32, 2, 32, 1, 41, 2, 0, 55, 3, 8, 32, 2, 65, 4, 106, 65, 168, 254, 192, 0, 32, 2, 65, 8, 106, 16, 63, 33, 1, 32, 2, 65, 32, 106, 36, 0, 32, 1, 11, 160, 8, 1, 17, 127, 35, 0, 65, 192, 0, 107, 34, 3, 36, 0, 32, 3, 65, 8, 106, 65, 28, 106, 34, 4, 32, 1, 54, 2, 0, 32, 3, 65, 52, 106, 34, 5, 32, 2, 65, 20, 106, 40, 2, 0, 34, 6, 54, 2, 0, 32, 3, 65, 3, 58, 0, 56, 32, 3, 65, 8, 106, 65, 36, 106, 34, 7, 32, 2, 40, 2, 16, 34, 1, 32, 6, 65, 3, 116, 34, 6, 106, 54, 2, 0, 32, 3, 66, 128, 128, 128, 128, 128, 4, 55, 3, 8, 32, 3, 65, 0, 54, 2, 16, 32, 3, 65, 0, 54, 2, 24, 32, 3, 32, 0, 54, 2, 32, 32, 3, 32, 1, 54, 2, 40, 32, 3, 32, 1, 54, 2, 48, 32, 2, 40, 2, 4, 34, 8, 65, 3, 116, 33, 9, 32, 2, 40, 2, 0, 33, 10, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 2, 40, 2, 8, 34, 0, 69, 13, 0, 32, 0, 65, 28, 106, 33, 1, 32, 0, 32, 2, 65, 12, 106, 40, 2, 0, 65, 36, 108, 106, 33, 11, 32, 3, 65, 32, 106, 33, 12, 32, 3, 65, 56, 106, 33, 13, 32, 3, 65, 48, 106, 33, 14, 32, 3, 65, 8, 106, 65, 20, 106, 33, 15, 32, 3, 65, 24, 106, 33, 16, 32, 3, 65, 40, 106, 33, 17, 32, 9, 33, 8, 32, 10, 33, 2, 3, 64, 32, 0, 32, 11, 70, 13, 2, 32, 8, 69, 13, 4, 32, 12, 40, 2, 0, 32, 2, 40, 2, 0, 32, 2, 65, 4, 106, 40, 2, 0, 32, 4, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 3, 32, 13, 32, 0, 45, 0, 32, 58, 0, 0, 32, 3, 32, 0, 40, 2, 8, 54, 2, 12, 32, 3, 32, 0, 40, 2, 12, 54, 2, 8, 65, 0, 33, 6, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 40, 2, 24, 34, 18, 65, 1, 70, 13, 0, 2, 64, 32, 18, 65, 3, 70, 13, 0, 32, 18, 65, 2, 71, 13, 2, 32, 17, 40, 2, 0, 34, 19, 32, 7, 40, 2, 0, 70, 13, 0, 32, 17, 32, 19, 65, 8, 106, 54, 2, 0, 32, 19, 40, 2, 4, 65, 5, 71, 13, 4, 32, 19, 40, 2, 0, 40, 2, 0, 33, 18, 12, 3, 11, 12, 3, 11, 32, 1, 40, 2, 0, 34, 19, 32, 5, 40, 2, 0, 34, 18, 79, 13, 11, 32, 14, 40, 2, 0, 32, 19, 65, 3, 116, 106, 34, 19, 40, 2, 4, 65, 5, 71, 13, 2, 32, 19, 40, 2, 0, 40, 2, 0, 33, 18, 12, 1, 11, 32, 1, 40, 2, 0, 33, 18, 11, 65, 1, 33, 6, 11, 32, 3, 65, 8, 106, 65, 12, 106, 32, 18, 54, 2, 0, 32, 3, 65, 8, 106, 65, 8, 106, 32, 6, 54, 2, 0, 65, 0, 33, 6, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 40, 2, 16, 34, 18, 65, 1, 70, 13, 0, 2, 64, 32, 18, 65, 3, 70, 13, 0, 32, 18, 65, 2, 71, 13, 2, 32, 17, 40, 2, 0, 34, 19, 32, 7, 40, 2, 0, 70, 13, 0, 32, 17, 32, 19, 65, 8, 106, 54, 2, 0, 32, 19, 40, 2, 4, 65, 5, 71, 13, 4, 32, 19, 40, 2, 0, 40, 2, 0, 33, 18, 12, 3, 11, 12, 3, 11, 32, 1, 65, 120, 106, 40, 2, 0, 34, 19, 32, 5, 40, 2, 0, 34, 18, 79, 13, 12, 32, 14, 40, 2, 0, 32, 19, 65, 3, 116, 106, 34, 19, 40, 2, 4, 65, 5, 71, 13, 2, 32, 19, 40, 2, 0, 40, 2, 0, 33, 18, 12, 1, 11, 32, 1, 65, 120, 106, 40, 2, 0, 33, 18, 11, 65, 1, 33, 6, 11, 32, 15, 32, 18, 54, 2, 0, 32, 16, 32, 6, 54, 2, 0, 2, 64, 2, 64, 32, 0, 40, 2, 0, 65, 1, 71, 13, 0, 32, 1, 65, 104, 106, 40, 2, 0, 34, 6, 32, 5, 40, 2, 0, 34, 18, 79, 13, 8, 32, 14, 40, 2, 0, 32, 6, 65, 3, 116, 106, 33, 6, 12, 1, 11, 32, 17, 40, 2, 0, 34, 6, 32, 7, 40, 2, 0, 70, 13, 8, 32, 17, 32, 6, 65, 8, 106, 54, 2, 0, 11, 32, 0, 65, 36, 106, 33, 0, 32, 2, 65, 8, 106, 33, 2, 32, 1, 65, 36, 106, 33, 1, 32, 8, 65, 120, 106, 33, 8, 32, 6, 40, 2, 0, 32, 3, 65, 8, 106, 32, 6, 65, 4, 106, 40, 2, 0, 17, 2, 0, 69, 13, 0, 12, 3, 11, 11, 32, 8, 65, 3, 116, 33, 0, 32, 3, 65, 32, 106, 33, 17, 32, 10, 33, 2, 3, 64, 32, 6, 69, 13, 1, 32, 0, 69, 13, 3, 32, 17, 40, 2, 0, 32, 2, 40, 2, 0, 32, 2, 65, 4, 106, 40, 2, 0, 32, 4, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 2, 32, 6, 65, 120, 106, 33, 6, 32, 0, 65, 120, 106, 33, 0, 32, 2, 65, 8, 106, 33, 2, 32, 1, 40, 2, 0, 33, 8, 32, 1, 40, 2, 4, 33, 18, 32, 1, 65, 8, 106, 33, 1, 32, 8, 32, 3, 65, 8, 106, 32, 18, 17, 2, 0, 69, 13, 0, 12, 2, 11, 11, 32, 2, 32, 10, 32, 9, 106, 70, 13, 1, 32, 3, 65, 32, 106, 40, 2, 0, 32, 2, 40, 2, 0, 32, 2, 40, 2, 4, 32, 3, 65, 36, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 69, 13, 1, 11, 65, 1, 33, 0, 12, 1, 11, 65, 0, 33, 0, 11, 32, 3, 65, 192, 0, 106, 36, 0, 32, 0, 15, 11, 65, 248, 129, 193, 0, 32, 6, 32, 18, 16, 116, 0, 11, 65, 224, 129, 193, 0, 16, 79, 0, 11, 65, 208, 129, 193, 0, 32, 19, 32, 18, 16, 116, 0, 11, 65, 208, 129, 193, 0, 32, 19, 32, 18, 16, 116, 0, 11, 144, 1, 1, 1, 127, 35, 0, 65, 192, 0, 107, 34, 0, 36, 0, 32, 0, 65, 53, 54, 2, 12, 32, 0, 65, 219, 132, 192, 0, 54, 2, 8, 32, 0, 65, 40, 106, 65, 12, 106, 65, 6, 54, 2, 0, 32, 0, 65, 16, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 0, 65, 36, 106, 65, 2, 54, 2, 0, 32, 0, 65, 7, 54, 2, 44, 32, 0, 65, 176, 143, 193, 0, 54, 2, 16, 32, 0, 65, 2, 54, 2, 20, 32, 0, 65, 148, 251, 192, 0, 54, 2, 24, 32, 0, 32, 0, 65, 8, 106, 54, 2, 40, 32, 0, 32, 0, 65, 56, 106, 54, 2, 48, 32, 0, 32, 0, 65, 40, 106, 54, 2, 32, 32, 0, 65, 16, 106, 65, 192, 143, 193, 0, 16, 67, 0, 11, 28, 0, 32, 1, 40, 2, 24, 65, 142, 238, 192, 0, 65, 5, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 11, 16, 0, 32, 1, 32, 0, 40, 2, 0, 32, 0, 40, 2, 4, 16, 40, 11, 74, 2, 1, 127, 1, 126, 35, 0, 65, 32, 107, 34, 2, 36, 0, 32, 1, 41, 2, 0, 33, 3, 32, 2, 65, 20, 106, 32, 1, 41, 2, 8, 55, 2, 0, 32, 2, 65, 160, 143, 193, 0, 54, 2, 4, 32, 2, 65, 140, 252, 192, 0, 54, 2, 0, 32, 2, 32, 0, 54, 2, 8, 32, 2, 32, 3, 55, 2, 12, 32, 2, 16, 95, 0, 11, 183, 2, 1, 2, 127, 35, 0, 65, 16, 107, 34, 2, 36, 0, 32, 0, 40, 2, 0, 33, 0, 2, 64, 2, 64, 32, 1, 65, 128, 1, 79, 13, 0, 2, 64, 32, 0, 40, 2, 8, 34, 3, 32, 0, 40, 2, 4, 71, 13, 0, 32, 0, 65, 1, 16, 55, 32, 0, 65, 8, 106, 40, 2, 0, 33, 3, 11, 32, 0, 40, 2, 0, 32, 3, 106, 32, 1, 58, 0, 0, 32, 0, 65, 8, 106, 34, 1, 32, 1, 40, 2, 0, 65, 1, 106, 54, 2, 0, 12, 1, 11, 32, 2, 65, 0, 54, 2, 12, 2, 64, 2, 64, 32, 1, 65, 128, 16, 79, 13, 0, 32, 2, 32, 1, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 1, 65, 6, 118, 65, 31, 113, 65, 192, 1, 114, 58, 0, 12, 65, 2, 33, 1, 12, 1, 11, 2, 64, 32, 1, 65, 255, 255, 3, 75, 13, 0, 32, 2, 32, 1, 65, 63, 113, 65, 128, 1, 114, 58, 0, 14, 32, 2, 32, 1, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 1, 65, 12, 118, 65, 15, 113, 65, 224, 1, 114, 58, 0, 12, 65, 3, 33, 1, 12, 1, 11, 32, 2, 32, 1, 65, 18, 118, 65, 240, 1, 114, 58, 0, 12, 32, 2, 32, 1, 65, 63, 113, 65, 128, 1, 114, 58, 0, 15, 32, 2, 32, 1, 65, 12, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 1, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 14, 65, 4, 33, 1, 11, 32, 0, 32, 2, 65, 12, 106, 32, 1, 16, 60, 11, 32, 2, 65, 16, 106, 36, 0, 65, 0, 11, 88, 1, 1, 127, 35, 0, 65, 32, 107, 34, 2, 36, 0, 32, 0, 40, 2, 0, 33, 0, 32, 2, 65, 8, 106, 65, 16, 106, 32, 1, 65, 16, 106, 41, 2, 0, 55, 3, 0, 32, 2, 65, 8, 106, 65, 8, 106, 32, 1, 65, 8, 106, 41, 2, 0, 55, 3, 0, 32, 2, 32, 1, 41, 2, 0, 55, 3, 8, 32, 0, 32, 2, 65, 8, 106, 16, 62, 33, 1, 32, 2, 65, 32, 106, 36, 0, 32, 1, 11, 15, 0, 32, 0, 40, 2, 0, 32, 1, 32, 2, 16, 60, 65, 0, 11, 230, 11, 1, 2, 127, 35, 0, 65, 192, 0, 107, 34, 2, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 40, 2, 0, 34, 3, 40, 2, 0, 65, 127, 106, 34, 0, 65, 22, 75, 13, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 14, 23, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 22, 19, 20, 23, 0, 11, 32, 3, 45, 0, 4, 34, 0, 65, 3, 113, 65, 1, 70, 13, 23, 32, 0, 65, 2, 71, 13, 24, 32, 3, 65, 8, 106, 40, 2, 0, 34, 0, 40, 2, 0, 32, 1, 32, 0, 40, 2, 4, 40, 2, 28, 17, 2, 0, 33, 1, 12, 46, 11, 32, 1, 40, 2, 24, 65, 144, 133, 192, 0, 65, 24, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 45, 11, 32, 1, 40, 2, 24, 65, 168, 133, 192, 0, 65, 27, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 44, 11, 32, 1, 40, 2, 24, 65, 195, 133, 192, 0, 65, 26, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 43, 11, 32, 1, 40, 2, 24, 65, 221, 133, 192, 0, 65, 25, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 42, 11, 32, 1, 40, 2, 24, 65, 246, 133, 192, 0, 65, 12, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 41, 11, 32, 1, 40, 2, 24, 65, 130, 134, 192, 0, 65, 19, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 40, 11, 32, 1, 40, 2, 24, 65, 149, 134, 192, 0, 65, 19, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 39, 11, 32, 1, 40, 2, 24, 65, 168, 134, 192, 0, 65, 19, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 38, 11, 32, 1, 40, 2, 24, 65, 187, 134, 192, 0, 65, 14, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 37, 11, 32, 1, 40, 2, 24, 65, 201, 134, 192, 0, 65, 14, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 36, 11, 32, 1, 40, 2, 24, 65, 215, 134, 192, 0, 65, 15, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 35, 11, 32, 1, 40, 2, 24, 65, 230, 134, 192, 0, 65, 14, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 34, 11, 32, 1, 40, 2, 24, 65, 244, 134, 192, 0, 65, 14, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 33, 11, 32, 1, 40, 2, 24, 65, 130, 135, 192, 0, 65, 19, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 32, 11, 32, 1, 40, 2, 24, 65, 149, 135, 192, 0, 65, 26, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 31, 11, 32, 1, 40, 2, 24, 65, 175, 135, 192, 0, 65, 62, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 30, 11, 32, 1, 40, 2, 24, 65, 237, 135, 192, 0, 65, 20, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 29, 11, 32, 1, 40, 2, 24, 65, 129, 136, 192, 0, 65, 36, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 28, 11, 32, 1, 40, 2, 24, 65, 179, 136, 192, 0, 65, 19, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 27, 11, 32, 1, 40, 2, 24, 65, 198, 136, 192, 0, 65, 28, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 26, 11, 32, 1, 40, 2, 24, 32, 3, 40, 2, 4, 32, 3, 65, 8, 106, 40, 2, 0, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 25, 11, 32, 1, 40, 2, 24, 65, 165, 136, 192, 0, 65, 14, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 24, 11, 32, 1, 40, 2, 24, 65, 226, 136, 192, 0, 65, 24, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 12, 23, 11, 65, 16, 33, 0, 32, 3, 65, 5, 106, 45, 0, 0, 65, 127, 106, 34, 3, 65, 16, 75, 13, 1, 2, 64, 32, 3, 14, 17, 0, 3, 4, 5, 7, 8, 9, 10, 20, 11, 12, 13, 14, 15, 16, 17, 19, 0, 11, 65, 227, 216, 192, 0, 33, 3, 65, 17, 33, 0, 12, 21, 11, 32, 2, 32, 3, 65, 8, 106, 40, 2, 0, 54, 2, 4, 32, 2, 65, 8, 106, 65, 179, 217, 192, 0, 65, 20, 16, 72, 32, 2, 65, 24, 106, 65, 12, 106, 65, 8, 54, 2, 0, 32, 2, 65, 9, 54, 2, 28, 32, 1, 65, 28, 106, 40, 2, 0, 33, 0, 32, 2, 32, 2, 65, 8, 106, 54, 2, 24, 32, 2, 32, 2, 65, 4, 106, 54, 2, 32, 32, 1, 40, 2, 24, 33, 1, 32, 2, 65, 40, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 2, 65, 40, 106, 65, 20, 106, 65, 2, 54, 2, 0, 32, 2, 65, 3, 54, 2, 44, 32, 2, 65, 248, 254, 192, 0, 54, 2, 40, 32, 2, 65, 148, 251, 192, 0, 54, 2, 48, 32, 2, 32, 2, 65, 24, 106, 54, 2, 56, 32, 1, 32, 0, 32, 2, 65, 40, 106, 16, 63, 33, 1, 32, 2, 40, 2, 12, 69, 13, 21, 32, 2, 40, 2, 8, 16, 31, 12, 21, 11, 65, 244, 216, 192, 0, 33, 3, 12, 19, 11, 65, 209, 216, 192, 0, 33, 3, 12, 2, 11, 65, 193, 216, 192, 0, 33, 3, 12, 17, 11, 65, 175, 216, 192, 0, 33, 3, 11, 65, 18, 33, 0, 12, 15, 11, 65, 162, 216, 192, 0, 33, 3, 65, 13, 33, 0, 12, 14, 11, 65, 148, 216, 192, 0, 33, 3, 12, 9, 11, 65, 255, 215, 192, 0, 33, 3, 12, 11, 11, 65, 244, 215, 192, 0, 33, 3, 65, 11, 33, 0, 12, 11, 11, 65, 202, 215, 192, 0, 33, 3, 12, 9, 11, 65, 179, 215, 192, 0, 33, 3, 65, 23, 33, 0, 12, 9, 11, 65, 167, 215, 192, 0, 33, 3, 65, 12, 33, 0, 12, 8, 11, 65, 158, 215, 192, 0, 33, 3, 65, 9, 33, 0, 12, 7, 11, 65, 148, 215, 192, 0, 33, 3, 65, 10, 33, 0, 12, 6, 11, 65, 255, 214, 192, 0, 33, 3, 12, 4, 11, 65, 241, 214, 192, 0, 33, 3, 11, 65, 14, 33, 0, 12, 3, 11, 65, 219, 214, 192, 0, 33, 3, 65, 22, 33, 0, 12, 2, 11, 65, 223, 215, 192, 0, 33, 3, 11, 65, 21, 33, 0, 11, 32, 2, 32, 0, 54, 2, 28, 32, 2, 32, 3, 54, 2, 24, 32, 2, 65, 10, 54, 2, 12, 32, 1, 65, 28, 106, 40, 2, 0, 33, 0, 32, 2, 32, 2, 65, 24, 106, 54, 2, 8, 32, 1, 40, 2, 24, 33, 1, 32, 2, 65, 52, 106, 65, 1, 54, 2, 0, 32, 2, 65, 60, 106, 65, 1, 54, 2, 0, 32, 2, 65, 1, 54, 2, 44, 32, 2, 65, 144, 255, 192, 0, 54, 2, 40, 32, 2, 65, 132, 217, 192, 0, 54, 2, 48, 32, 2, 32, 2, 65, 8, 106, 54, 2, 56, 32, 1, 32, 0, 32, 2, 65, 40, 106, 16, 63, 33, 1, 11, 32, 2, 65, 192, 0, 106, 36, 0, 32, 1, 11, 206, 1, 1, 3, 127, 35, 0, 65, 16, 107, 34, 3, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 32, 2, 65, 127, 76, 13, 0, 2, 64, 2, 64, 32, 2, 69, 13, 0, 32, 2, 16, 34, 34, 4, 69, 13, 4, 32, 3, 32, 4, 54, 2, 0, 32, 3, 65, 0, 54, 2, 8, 32, 3, 32, 2, 54, 2, 4, 32, 3, 32, 2, 16, 99, 33, 4, 12, 1, 11, 32, 3, 66, 1, 55, 3, 0, 32, 3, 65, 0, 54, 2, 8, 32, 3, 65, 0, 16, 99, 33, 4, 11, 32, 4, 65, 255, 1, 113, 65, 2, 71, 13, 1, 32, 3, 65, 8, 106, 34, 4, 32, 4, 40, 2, 0, 34, 5, 32, 2, 106, 54, 2, 0, 32, 5, 32, 3, 40, 2, 0, 106, 32, 1, 32, 2, 16, 149, 1, 26, 32, 0, 65, 8, 106, 32, 4, 40, 2, 0, 54, 2, 0, 32, 0, 32, 3, 41, 3, 0, 55, 2, 0, 32, 3, 65, 16, 106, 36, 0, 15, 11, 16, 100, 0, 11, 32, 4, 65, 1, 113, 13, 1, 16, 36, 0, 11, 0, 0, 11, 65, 152, 255, 192, 0, 16, 79, 0, 11, 222, 2, 1, 7, 127, 35, 0, 65, 48, 107, 34, 2, 36, 0, 65, 39, 33, 3, 2, 64, 2, 64, 32, 0, 40, 2, 0, 34, 4, 32, 4, 65, 31, 117, 34, 0, 106, 32, 0, 115, 34, 0, 65, 144, 206, 0, 73, 13, 0, 65, 39, 33, 3, 3, 64, 32, 2, 65, 9, 106, 32, 3, 106, 34, 5, 65, 124, 106, 32, 0, 32, 0, 65, 144, 206, 0, 110, 34, 6, 65, 240, 177, 127, 108, 106, 34, 7, 65, 228, 0, 110, 34, 8, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 5, 65, 126, 106, 32, 7, 32, 8, 65, 156, 127, 108, 106, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 3, 65, 124, 106, 33, 3, 32, 0, 65, 255, 193, 215, 47, 75, 33, 5, 32, 6, 33, 0, 32, 5, 13, 0, 12, 2, 11, 11, 32, 0, 33, 6, 11, 2, 64, 2, 64, 32, 6, 65, 228, 0, 72, 13, 0, 32, 2, 65, 9, 106, 32, 3, 65, 126, 106, 34, 3, 106, 32, 6, 65, 255, 255, 3, 113, 65, 228, 0, 110, 34, 0, 65, 156, 127, 108, 32, 6, 106, 65, 255, 255, 3, 113, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 12, 1, 11, 32, 6, 33, 0, 11, 2, 64, 2, 64, 32, 0, 65, 9, 74, 13, 0, 32, 2, 65, 9, 106, 32, 3, 65, 127, 106, 34, 3, 106, 34, 6, 32, 0, 65, 48, 106, 58, 0, 0, 12, 1, 11, 32, 2, 65, 9, 106, 32, 3, 65, 126, 106, 34, 3, 106, 34, 6, 32, 0, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 11, 32, 1, 32, 4, 65, 127, 115, 65, 31, 118, 65, 140, 252, 192, 0, 65, 0, 32, 6, 65, 39, 32, 3, 107, 16, 101, 33, 0, 32, 2, 65, 48, 106, 36, 0, 32, 0, 11, 16, 0, 32, 1, 32, 0, 40, 2, 0, 32, 0, 40, 2, 8, 16, 40, 11, 16, 0, 32, 1, 32, 0, 40, 2, 0, 32, 0, 40, 2, 4, 16, 40, 11, 161, 3, 1, 4, 127, 35, 0, 65, 192, 0, 107, 34, 2, 36, 0, 32, 2, 32, 0, 40, 2, 0, 34, 0, 54, 2, 36, 32, 2, 65, 0, 54, 2, 8, 32, 2, 66, 1, 55, 3, 0, 32, 2, 65, 52, 106, 65, 1, 54, 2, 0, 32, 2, 65, 60, 106, 65, 1, 54, 2, 0, 32, 2, 65, 11, 54, 2, 28, 32, 2, 65, 144, 255, 192, 0, 54, 2, 40, 32, 2, 65, 1, 54, 2, 44, 32, 2, 65, 132, 217, 192, 0, 54, 2, 48, 32, 2, 32, 2, 65, 36, 106, 54, 2, 24, 32, 2, 32, 2, 65, 24, 106, 54, 2, 56, 2, 64, 2, 64, 32, 2, 32, 2, 65, 40, 106, 16, 62, 13, 0, 2, 64, 32, 2, 40, 2, 4, 34, 3, 32, 2, 65, 8, 106, 34, 4, 40, 2, 0, 34, 5, 70, 13, 0, 32, 3, 32, 5, 73, 13, 2, 2, 64, 2, 64, 32, 5, 69, 13, 0, 32, 2, 40, 2, 0, 32, 5, 16, 42, 34, 3, 13, 1, 0, 0, 11, 32, 2, 16, 57, 65, 0, 33, 5, 65, 1, 33, 3, 11, 32, 2, 32, 5, 54, 2, 4, 32, 2, 32, 3, 54, 2, 0, 11, 32, 2, 65, 24, 106, 65, 8, 106, 32, 4, 40, 2, 0, 54, 2, 0, 32, 2, 32, 2, 41, 3, 0, 55, 3, 24, 32, 2, 65, 12, 106, 65, 2, 54, 2, 0, 32, 2, 65, 20, 106, 65, 2, 54, 2, 0, 32, 2, 65, 12, 54, 2, 4, 32, 2, 32, 0, 65, 12, 106, 54, 2, 8, 32, 2, 32, 0, 65, 16, 106, 54, 2, 16, 32, 1, 65, 28, 106, 40, 2, 0, 33, 0, 32, 2, 32, 2, 65, 24, 106, 54, 2, 0, 32, 1, 40, 2, 24, 33, 1, 32, 2, 65, 40, 106, 65, 12, 106, 65, 3, 54, 2, 0, 32, 2, 65, 40, 106, 65, 20, 106, 65, 3, 54, 2, 0, 32, 2, 65, 4, 54, 2, 44, 32, 2, 65, 192, 254, 192, 0, 54, 2, 40, 32, 2, 65, 228, 232, 192, 0, 54, 2, 48, 32, 2, 32, 2, 54, 2, 56, 32, 1, 32, 0, 32, 2, 65, 40, 106, 16, 63, 33, 1, 32, 2, 65, 24, 106, 16, 57, 32, 2, 65, 192, 0, 106, 36, 0, 32, 1, 15, 11, 16, 64, 0, 11, 65, 224, 254, 192, 0, 16, 79, 0, 11, 203, 2, 1, 6, 127, 35, 0, 65, 48, 107, 34, 2, 36, 0, 65, 39, 33, 3, 2, 64, 2, 64, 32, 0, 40, 2, 0, 34, 0, 65, 144, 206, 0, 73, 13, 0, 65, 39, 33, 3, 3, 64, 32, 2, 65, 9, 106, 32, 3, 106, 34, 4, 65, 124, 106, 32, 0, 32, 0, 65, 144, 206, 0, 110, 34, 5, 65, 240, 177, 127, 108, 106, 34, 6, 65, 228, 0, 110, 34, 7, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 4, 65, 126, 106, 32, 6, 32, 7, 65, 156, 127, 108, 106, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 3, 65, 124, 106, 33, 3, 32, 0, 65, 255, 193, 215, 47, 75, 33, 4, 32, 5, 33, 0, 32, 4, 13, 0, 12, 2, 11, 11, 32, 0, 33, 5, 11, 2, 64, 2, 64, 32, 5, 65, 228, 0, 72, 13, 0, 32, 2, 65, 9, 106, 32, 3, 65, 126, 106, 34, 3, 106, 32, 5, 65, 255, 255, 3, 113, 65, 228, 0, 110, 34, 0, 65, 156, 127, 108, 32, 5, 106, 65, 255, 255, 3, 113, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 12, 1, 11, 32, 5, 33, 0, 11, 2, 64, 2, 64, 32, 0, 65, 9, 74, 13, 0, 32, 2, 65, 9, 106, 32, 3, 65, 127, 106, 34, 3, 106, 34, 5, 32, 0, 65, 48, 106, 58, 0, 0, 12, 1, 11, 32, 2, 65, 9, 106, 32, 3, 65, 126, 106, 34, 3, 106, 34, 5, 32, 0, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 11, 32, 1, 65, 1, 65, 140, 252, 192, 0, 65, 0, 32, 5, 65, 39, 32, 3, 107, 16, 101, 33, 0, 32, 2, 65, 48, 106, 36, 0, 32, 0, 11, 240, 9, 2, 16, 127, 1, 126, 35, 0, 65, 32, 107, 34, 2, 36, 0, 32, 0, 40, 2, 8, 33, 3, 32, 0, 40, 2, 0, 33, 4, 65, 1, 33, 5, 2, 64, 32, 1, 40, 2, 24, 65, 34, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 16, 17, 2, 0, 13, 0, 2, 64, 2, 64, 32, 3, 69, 13, 0, 32, 4, 32, 3, 106, 33, 6, 32, 1, 65, 24, 106, 33, 7, 32, 1, 65, 28, 106, 33, 8, 32, 4, 33, 9, 65, 0, 33, 10, 65, 0, 33, 0, 32, 4, 33, 11, 3, 64, 32, 9, 65, 1, 106, 33, 12, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 9, 44, 0, 0, 34, 13, 65, 0, 72, 13, 0, 32, 13, 65, 255, 1, 113, 33, 13, 12, 1, 11, 2, 64, 2, 64, 32, 12, 32, 6, 70, 13, 0, 32, 12, 45, 0, 0, 65, 63, 113, 33, 14, 32, 9, 65, 2, 106, 34, 9, 33, 12, 12, 1, 11, 65, 0, 33, 14, 32, 6, 33, 9, 11, 32, 13, 65, 31, 113, 33, 15, 2, 64, 2, 64, 2, 64, 32, 13, 65, 255, 1, 113, 34, 13, 65, 224, 1, 73, 13, 0, 32, 9, 32, 6, 70, 13, 1, 32, 9, 45, 0, 0, 65, 63, 113, 33, 16, 32, 9, 65, 1, 106, 34, 12, 33, 17, 12, 2, 11, 32, 14, 32, 15, 65, 6, 116, 114, 33, 13, 12, 2, 11, 65, 0, 33, 16, 32, 6, 33, 17, 11, 32, 16, 32, 14, 65, 6, 116, 114, 33, 14, 2, 64, 32, 13, 65, 240, 1, 73, 13, 0, 32, 17, 32, 6, 70, 13, 2, 32, 17, 65, 1, 106, 33, 9, 32, 17, 45, 0, 0, 65, 63, 113, 33, 13, 12, 3, 11, 32, 14, 32, 15, 65, 12, 116, 114, 33, 13, 11, 32, 12, 33, 9, 12, 2, 11, 65, 0, 33, 13, 32, 12, 33, 9, 11, 32, 14, 65, 6, 116, 32, 15, 65, 18, 116, 65, 128, 128, 240, 0, 113, 114, 32, 13, 114, 34, 13, 65, 128, 128, 196, 0, 70, 13, 3, 11, 65, 2, 33, 14, 2, 64, 2, 64, 2, 64, 2, 64, 32, 13, 65, 9, 70, 13, 0, 2, 64, 32, 13, 65, 10, 70, 13, 0, 2, 64, 2, 64, 32, 13, 65, 220, 0, 70, 13, 0, 32, 13, 65, 34, 70, 13, 0, 32, 13, 65, 39, 70, 13, 0, 32, 13, 65, 13, 71, 13, 1, 65, 242, 0, 33, 15, 12, 4, 11, 32, 13, 33, 15, 12, 3, 11, 2, 64, 32, 13, 16, 80, 13, 0, 32, 13, 16, 81, 13, 5, 11, 32, 13, 65, 1, 114, 103, 65, 2, 118, 65, 7, 115, 173, 66, 128, 128, 128, 128, 208, 0, 132, 33, 18, 65, 3, 33, 14, 32, 13, 33, 15, 12, 3, 11, 65, 238, 0, 33, 15, 12, 1, 11, 65, 244, 0, 33, 15, 11, 11, 32, 2, 32, 3, 54, 2, 4, 32, 2, 32, 4, 54, 2, 0, 32, 2, 32, 10, 54, 2, 8, 32, 2, 32, 0, 54, 2, 12, 2, 64, 32, 0, 32, 10, 73, 13, 0, 2, 64, 32, 10, 69, 13, 0, 32, 10, 32, 3, 70, 13, 0, 32, 10, 32, 3, 79, 13, 1, 32, 4, 32, 10, 106, 44, 0, 0, 65, 191, 127, 76, 13, 1, 11, 2, 64, 32, 0, 69, 13, 0, 32, 0, 32, 3, 70, 13, 0, 32, 0, 32, 3, 79, 13, 1, 32, 4, 32, 0, 106, 44, 0, 0, 65, 191, 127, 76, 13, 1, 11, 2, 64, 32, 7, 40, 2, 0, 32, 4, 32, 10, 106, 32, 0, 32, 10, 107, 32, 8, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 0, 3, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 14, 65, 3, 113, 34, 10, 65, 1, 70, 13, 0, 65, 220, 0, 33, 12, 2, 64, 32, 10, 65, 2, 70, 13, 0, 32, 10, 65, 3, 71, 13, 6, 32, 18, 66, 32, 136, 167, 65, 7, 113, 65, 127, 106, 34, 10, 65, 4, 75, 13, 6, 2, 64, 32, 10, 14, 5, 0, 6, 4, 5, 3, 0, 11, 32, 18, 66, 255, 255, 255, 255, 143, 96, 131, 33, 18, 65, 253, 0, 33, 12, 12, 7, 11, 65, 1, 33, 14, 12, 6, 11, 65, 0, 33, 14, 32, 15, 33, 12, 12, 5, 11, 32, 18, 66, 255, 255, 255, 255, 143, 96, 131, 66, 128, 128, 128, 128, 192, 0, 132, 33, 18, 12, 4, 11, 32, 18, 66, 255, 255, 255, 255, 143, 96, 131, 66, 128, 128, 128, 128, 32, 132, 33, 18, 65, 251, 0, 33, 12, 12, 3, 11, 32, 18, 66, 255, 255, 255, 255, 143, 96, 131, 66, 128, 128, 128, 128, 48, 132, 33, 18, 65, 245, 0, 33, 12, 12, 2, 11, 32, 15, 32, 18, 167, 34, 17, 65, 2, 116, 65, 28, 113, 118, 65, 15, 113, 34, 10, 65, 48, 114, 32, 10, 65, 215, 0, 106, 32, 10, 65, 10, 73, 27, 33, 12, 2, 64, 32, 17, 69, 13, 0, 32, 18, 66, 127, 124, 66, 255, 255, 255, 255, 15, 131, 32, 18, 66, 128, 128, 128, 128, 112, 131, 132, 33, 18, 12, 2, 11, 32, 18, 66, 255, 255, 255, 255, 143, 96, 131, 66, 128, 128, 128, 128, 16, 132, 33, 18, 12, 1, 11, 65, 1, 33, 10, 2, 64, 32, 13, 65, 128, 1, 73, 13, 0, 65, 2, 33, 10, 32, 13, 65, 128, 16, 73, 13, 0, 65, 3, 65, 4, 32, 13, 65, 128, 128, 4, 73, 27, 33, 10, 11, 32, 10, 32, 0, 106, 33, 10, 12, 4, 11, 32, 7, 40, 2, 0, 32, 12, 32, 8, 40, 2, 0, 40, 2, 16, 17, 2, 0, 69, 13, 0, 11, 11, 65, 1, 33, 5, 12, 5, 11, 32, 2, 32, 2, 65, 8, 106, 54, 2, 20, 32, 2, 32, 2, 54, 2, 16, 32, 2, 32, 2, 65, 12, 106, 54, 2, 24, 32, 2, 65, 16, 106, 16, 82, 0, 11, 32, 0, 32, 11, 107, 32, 9, 106, 33, 0, 32, 9, 33, 11, 32, 6, 32, 9, 71, 13, 0, 12, 2, 11, 11, 65, 0, 33, 10, 11, 32, 2, 32, 3, 54, 2, 4, 32, 2, 32, 4, 54, 2, 0, 32, 2, 32, 10, 54, 2, 8, 32, 2, 32, 3, 54, 2, 12, 2, 64, 2, 64, 32, 10, 69, 13, 0, 32, 3, 32, 10, 70, 13, 0, 2, 64, 32, 3, 32, 10, 77, 13, 0, 32, 4, 32, 10, 106, 34, 0, 44, 0, 0, 65, 191, 127, 74, 13, 2, 11, 32, 2, 32, 2, 65, 8, 106, 54, 2, 20, 32, 2, 32, 2, 54, 2, 16, 32, 2, 32, 2, 65, 12, 106, 54, 2, 24, 32, 2, 65, 16, 106, 16, 82, 0, 11, 32, 4, 32, 10, 106, 33, 0, 11, 32, 1, 65, 24, 106, 34, 13, 40, 2, 0, 32, 0, 32, 3, 32, 10, 107, 32, 1, 65, 28, 106, 34, 10, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 0, 32, 13, 40, 2, 0, 65, 34, 32, 10, 40, 2, 0, 40, 2, 16, 17, 2, 0, 33, 5, 11, 32, 2, 65, 32, 106, 36, 0, 32, 5, 11, 104, 2, 1, 127, 3, 126, 35, 0, 65, 48, 107, 34, 1, 36, 0, 32, 0, 41, 2, 16, 33, 2, 32, 0, 41, 2, 8, 33, 3, 32, 0, 41, 2, 0, 33, 4, 32, 1, 65, 20, 106, 65, 0, 54, 2, 0, 32, 1, 32, 4, 55, 3, 24, 32, 1, 66, 1, 55, 2, 4, 32, 1, 65, 140, 252, 192, 0, 54, 2, 16, 32, 1, 32, 1, 65, 24, 106, 54, 2, 0, 32, 1, 32, 3, 55, 3, 32, 32, 1, 32, 2, 55, 3, 40, 32, 1, 32, 1, 65, 32, 106, 16, 67, 0, 11, 150, 2, 1, 1, 127, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 65, 128, 16, 79, 13, 0, 32, 0, 65, 3, 118, 65, 248, 255, 255, 255, 1, 113, 65, 184, 130, 193, 0, 106, 33, 1, 12, 1, 11, 2, 64, 32, 0, 65, 128, 128, 4, 79, 13, 0, 32, 0, 65, 6, 118, 65, 96, 106, 34, 1, 65, 224, 7, 79, 13, 2, 32, 1, 65, 208, 132, 193, 0, 106, 45, 0, 0, 34, 1, 65, 201, 0, 75, 13, 3, 32, 1, 65, 3, 116, 65, 208, 238, 192, 0, 106, 33, 1, 12, 1, 11, 32, 0, 65, 12, 118, 65, 112, 106, 34, 1, 65, 128, 2, 79, 13, 3, 32, 1, 65, 176, 140, 193, 0, 106, 45, 0, 0, 65, 6, 116, 32, 0, 65, 6, 118, 65, 63, 113, 114, 34, 1, 65, 255, 3, 75, 13, 4, 32, 1, 65, 160, 243, 192, 0, 106, 45, 0, 0, 34, 1, 65, 54, 75, 13, 5, 32, 1, 65, 3, 116, 65, 160, 247, 192, 0, 106, 33, 1, 11, 32, 1, 41, 3, 0, 66, 1, 32, 0, 65, 63, 113, 173, 134, 131, 66, 0, 82, 15, 11, 65, 192, 142, 193, 0, 32, 1, 65, 224, 7, 16, 116, 0, 11, 65, 208, 142, 193, 0, 32, 1, 65, 202, 0, 16, 116, 0, 11, 65, 224, 142, 193, 0, 32, 1, 65, 128, 2, 16, 116, 0, 11, 65, 240, 142, 193, 0, 32, 1, 65, 128, 4, 16, 116, 0, 11, 65, 128, 143, 193, 0, 32, 1, 65, 55, 16, 116, 0, 11, 178, 1, 0, 2, 64, 32, 0, 65, 255, 255, 3, 75, 13, 0, 32, 0, 65, 230, 219, 192, 0, 65, 40, 65, 182, 220, 192, 0, 65, 175, 2, 65, 229, 222, 192, 0, 65, 188, 2, 16, 103, 15, 11, 2, 64, 32, 0, 65, 255, 255, 7, 75, 13, 0, 32, 0, 65, 161, 225, 192, 0, 65, 33, 65, 227, 225, 192, 0, 65, 158, 1, 65, 129, 227, 192, 0, 65, 253, 2, 16, 103, 15, 11, 2, 64, 32, 0, 65, 226, 139, 116, 106, 65, 226, 141, 44, 73, 13, 0, 32, 0, 65, 159, 168, 116, 106, 65, 159, 24, 73, 13, 0, 32, 0, 65, 222, 226, 116, 106, 65, 14, 73, 13, 0, 32, 0, 65, 254, 255, 255, 0, 113, 65, 158, 240, 10, 70, 13, 0, 32, 0, 65, 169, 178, 117, 106, 65, 41, 73, 13, 0, 32, 0, 65, 203, 145, 117, 106, 65, 10, 77, 13, 0, 32, 0, 65, 144, 252, 71, 106, 65, 143, 252, 11, 75, 15, 11, 65, 0, 11, 38, 1, 1, 127, 32, 0, 40, 2, 0, 34, 1, 40, 2, 0, 32, 1, 40, 2, 4, 32, 0, 40, 2, 4, 40, 2, 0, 32, 0, 40, 2, 8, 40, 2, 0, 16, 32, 0, 11, 75, 1, 1, 127, 32, 0, 16, 85, 2, 64, 32, 0, 45, 0, 16, 34, 1, 65, 7, 113, 65, 3, 73, 13, 0, 2, 64, 2, 64, 32, 1, 65, 4, 70, 13, 0, 32, 1, 65, 3, 71, 13, 1, 32, 0, 65, 20, 106, 16, 85, 15, 11, 32, 0, 65, 20, 106, 34, 0, 16, 56, 32, 0, 16, 58, 15, 11, 32, 0, 65, 20, 106, 16, 59, 11, 11, 19, 0, 2, 64, 32, 0, 45, 0, 16, 65, 6, 70, 13, 0, 32, 0, 16, 83, 11, 11, 6, 0, 32, 0, 16, 57, 11, 57, 0, 32, 4, 32, 0, 66, 2, 134, 34, 0, 66, 2, 132, 32, 1, 32, 2, 32, 3, 16, 89, 55, 3, 0, 32, 5, 32, 0, 32, 6, 173, 66, 127, 133, 124, 32, 1, 32, 2, 32, 3, 16, 89, 55, 3, 0, 32, 0, 32, 1, 32, 2, 32, 3, 16, 89, 11, 165, 3, 3, 1, 127, 1, 126, 4, 127, 2, 64, 2, 64, 32, 0, 66, 128, 128, 128, 128, 16, 84, 13, 0, 32, 1, 65, 120, 106, 34, 2, 32, 0, 66, 128, 194, 215, 47, 128, 34, 3, 66, 128, 190, 168, 80, 126, 32, 0, 124, 167, 34, 4, 65, 144, 206, 0, 110, 34, 5, 65, 144, 206, 0, 112, 34, 6, 65, 228, 0, 110, 34, 7, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 1, 65, 124, 106, 32, 5, 65, 240, 177, 127, 108, 32, 4, 106, 34, 4, 65, 228, 0, 110, 34, 5, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 1, 65, 122, 106, 32, 6, 32, 7, 65, 156, 127, 108, 106, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 1, 65, 126, 106, 32, 4, 32, 5, 65, 156, 127, 108, 106, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 12, 1, 11, 32, 1, 33, 2, 32, 0, 33, 3, 11, 32, 2, 65, 126, 106, 33, 2, 32, 3, 167, 33, 1, 2, 64, 3, 64, 32, 1, 65, 143, 206, 0, 77, 13, 1, 32, 2, 65, 126, 106, 32, 1, 65, 144, 206, 0, 110, 34, 4, 65, 240, 177, 127, 108, 32, 1, 106, 34, 1, 65, 228, 0, 110, 34, 5, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 2, 32, 1, 32, 5, 65, 156, 127, 108, 106, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 2, 65, 124, 106, 33, 2, 32, 4, 33, 1, 12, 0, 11, 11, 2, 64, 2, 64, 32, 1, 65, 227, 0, 77, 13, 0, 32, 2, 32, 1, 65, 255, 255, 3, 113, 65, 228, 0, 110, 34, 4, 65, 156, 127, 108, 32, 1, 106, 65, 255, 255, 3, 113, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 4, 33, 1, 12, 1, 11, 32, 2, 65, 2, 106, 33, 2, 11, 2, 64, 32, 1, 65, 10, 73, 13, 0, 32, 2, 65, 126, 106, 32, 1, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 15, 11, 32, 2, 65, 127, 106, 32, 1, 65, 48, 106, 58, 0, 0, 11, 162, 1, 1, 2, 127, 2, 64, 2, 64, 32, 0, 65, 127, 76, 13, 0, 32, 0, 33, 2, 12, 1, 11, 32, 1, 65, 45, 58, 0, 0, 65, 0, 32, 0, 107, 33, 2, 32, 1, 65, 1, 106, 33, 1, 11, 2, 64, 32, 2, 65, 227, 0, 76, 13, 0, 32, 1, 32, 2, 65, 228, 0, 110, 34, 3, 65, 48, 106, 58, 0, 0, 32, 1, 32, 2, 32, 3, 65, 156, 127, 108, 106, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 1, 32, 0, 65, 31, 118, 65, 3, 106, 15, 11, 2, 64, 32, 2, 65, 9, 76, 13, 0, 32, 1, 32, 2, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 0, 32, 0, 65, 31, 118, 65, 2, 114, 15, 11, 32, 1, 32, 2, 65, 48, 106, 58, 0, 0, 32, 0, 65, 31, 118, 65, 1, 106, 11, 115, 1, 1, 127, 35, 0, 65, 48, 107, 34, 4, 36, 0, 32, 4, 65, 32, 106, 32, 1, 66, 0, 32, 0, 66, 0, 16, 152, 1, 32, 4, 65, 16, 106, 32, 2, 66, 0, 32, 0, 66, 0, 16, 152, 1, 32, 4, 32, 4, 65, 32, 106, 65, 8, 106, 41, 3, 0, 34, 0, 32, 4, 41, 3, 16, 124, 34, 2, 32, 4, 65, 16, 106, 65, 8, 106, 41, 3, 0, 32, 2, 32, 0, 84, 173, 124, 32, 3, 65, 192, 0, 106, 65, 255, 0, 113, 16, 155, 1, 32, 4, 41, 3, 0, 33, 0, 32, 4, 65, 48, 106, 36, 0, 32, 0, 11, 137, 1, 1, 1, 127, 35, 0, 65, 48, 107, 34, 1, 36, 0, 32, 1, 65, 43, 54, 2, 4, 32, 1, 65, 182, 253, 192, 0, 54, 2, 0, 32, 1, 65, 32, 106, 65, 12, 106, 65, 13, 54, 2, 0, 32, 1, 65, 8, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 1, 65, 28, 106, 65, 2, 54, 2, 0, 32, 1, 65, 7, 54, 2, 36, 32, 1, 32, 0, 54, 2, 40, 32, 1, 65, 176, 143, 193, 0, 54, 2, 8, 32, 1, 65, 2, 54, 2, 12, 32, 1, 65, 148, 251, 192, 0, 54, 2, 16, 32, 1, 32, 1, 54, 2, 32, 32, 1, 32, 1, 65, 32, 106, 54, 2, 24, 32, 1, 65, 8, 106, 65, 192, 143, 193, 0, 16, 67, 0, 11, 227, 1, 1, 1, 127, 35, 0, 65, 16, 107, 34, 2, 36, 0, 32, 2, 32, 1, 40, 2, 24, 65, 158, 237, 192, 0, 65, 9, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 58, 0, 4, 32, 2, 32, 1, 54, 2, 0, 32, 2, 65, 0, 58, 0, 5, 32, 2, 32, 0, 54, 2, 12, 32, 2, 65, 167, 237, 192, 0, 65, 11, 32, 2, 65, 12, 106, 65, 152, 129, 193, 0, 16, 108, 33, 1, 32, 2, 32, 0, 65, 4, 106, 54, 2, 12, 32, 1, 65, 178, 237, 192, 0, 65, 9, 32, 2, 65, 12, 106, 65, 168, 129, 193, 0, 16, 108, 26, 32, 2, 45, 0, 4, 33, 1, 2, 64, 32, 2, 45, 0, 5, 69, 13, 0, 32, 1, 65, 255, 1, 113, 33, 0, 65, 1, 33, 1, 2, 64, 32, 0, 13, 0, 32, 2, 40, 2, 0, 34, 1, 40, 2, 24, 65, 149, 238, 192, 0, 65, 151, 238, 192, 0, 32, 1, 40, 2, 0, 65, 4, 113, 27, 65, 2, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 1, 11, 32, 2, 32, 1, 58, 0, 4, 11, 32, 2, 65, 16, 106, 36, 0, 32, 1, 65, 255, 1, 113, 65, 0, 71, 11, 49, 1, 1, 127, 35, 0, 65, 16, 107, 34, 1, 36, 0, 32, 0, 40, 2, 8, 16, 93, 26, 32, 1, 32, 0, 41, 2, 12, 55, 3, 0, 32, 1, 32, 0, 65, 20, 106, 41, 2, 0, 55, 3, 8, 32, 1, 16, 38, 0, 11, 21, 0, 2, 64, 32, 0, 69, 13, 0, 32, 0, 15, 11, 65, 224, 129, 193, 0, 16, 79, 0, 11, 4, 0, 0, 0, 11, 7, 0, 32, 0, 16, 92, 0, 11, 209, 2, 1, 5, 127, 32, 0, 40, 2, 24, 33, 1, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 40, 2, 12, 34, 2, 32, 0, 70, 13, 0, 32, 0, 40, 2, 8, 34, 3, 32, 2, 54, 2, 12, 32, 2, 32, 3, 54, 2, 8, 32, 1, 13, 1, 12, 2, 11, 2, 64, 32, 0, 65, 20, 65, 16, 32, 0, 65, 20, 106, 34, 2, 40, 2, 0, 34, 4, 27, 106, 40, 2, 0, 34, 3, 69, 13, 0, 32, 2, 32, 0, 65, 16, 106, 32, 4, 27, 33, 4, 2, 64, 3, 64, 32, 4, 33, 5, 2, 64, 32, 3, 34, 2, 65, 20, 106, 34, 4, 40, 2, 0, 34, 3, 69, 13, 0, 32, 3, 13, 1, 12, 2, 11, 32, 2, 65, 16, 106, 33, 4, 32, 2, 40, 2, 16, 34, 3, 13, 0, 11, 11, 32, 5, 65, 0, 54, 2, 0, 32, 1, 13, 1, 12, 2, 11, 65, 0, 33, 2, 32, 1, 69, 13, 1, 11, 2, 64, 2, 64, 32, 0, 40, 2, 28, 65, 2, 116, 65, 180, 146, 193, 0, 106, 34, 3, 40, 2, 0, 32, 0, 70, 13, 0, 32, 1, 65, 16, 65, 20, 32, 1, 40, 2, 16, 32, 0, 70, 27, 106, 32, 2, 54, 2, 0, 32, 2, 13, 1, 12, 2, 11, 32, 3, 32, 2, 54, 2, 0, 32, 2, 69, 13, 2, 11, 32, 2, 32, 1, 54, 2, 24, 2, 64, 32, 0, 40, 2, 16, 34, 3, 69, 13, 0, 32, 2, 32, 3, 54, 2, 16, 32, 3, 32, 2, 54, 2, 24, 11, 32, 0, 65, 20, 106, 40, 2, 0, 34, 3, 69, 13, 0, 32, 2, 65, 20, 106, 32, 3, 54, 2, 0, 32, 3, 32, 2, 54, 2, 24, 11, 15, 11, 65, 0, 65, 0, 40, 2, 168, 144, 65, 65, 126, 32, 0, 65, 28, 106, 40, 2, 0, 119, 113, 54, 2, 168, 144, 65, 11, 196, 2, 1, 4, 127, 65, 0, 33, 2, 2, 64, 32, 1, 65, 8, 118, 34, 3, 69, 13, 0, 65, 31, 33, 2, 32, 1, 65, 255, 255, 255, 7, 75, 13, 0, 32, 1, 65, 38, 32, 3, 103, 34, 2, 107, 65, 31, 113, 118, 65, 1, 113, 65, 31, 32, 2, 107, 65, 1, 116, 114, 33, 2, 11, 32, 0, 32, 2, 54, 2, 28, 32, 0, 66, 0, 55, 2, 16, 32, 2, 65, 2, 116, 65, 180, 146, 193, 0, 106, 33, 3, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 65, 0, 40, 2, 168, 144, 65, 34, 4, 65, 1, 32, 2, 65, 31, 113, 116, 34, 5, 113, 69, 13, 0, 32, 3, 40, 2, 0, 34, 4, 40, 2, 4, 65, 120, 113, 32, 1, 71, 13, 1, 32, 4, 33, 2, 12, 2, 11, 65, 0, 32, 4, 32, 5, 114, 54, 2, 168, 144, 65, 32, 3, 32, 0, 54, 2, 0, 32, 0, 32, 3, 54, 2, 24, 12, 3, 11, 32, 1, 65, 0, 65, 25, 32, 2, 65, 1, 118, 107, 65, 31, 113, 32, 2, 65, 31, 70, 27, 116, 33, 3, 3, 64, 32, 4, 32, 3, 65, 29, 118, 65, 4, 113, 106, 65, 16, 106, 34, 5, 40, 2, 0, 34, 2, 69, 13, 2, 32, 3, 65, 1, 116, 33, 3, 32, 2, 33, 4, 32, 2, 40, 2, 4, 65, 120, 113, 32, 1, 71, 13, 0, 11, 11, 32, 2, 40, 2, 8, 34, 3, 32, 0, 54, 2, 12, 32, 2, 32, 0, 54, 2, 8, 32, 0, 32, 2, 54, 2, 12, 32, 0, 32, 3, 54, 2, 8, 32, 0, 65, 0, 54, 2, 24, 15, 11, 32, 5, 32, 0, 54, 2, 0, 32, 0, 32, 4, 54, 2, 24, 11, 32, 0, 32, 0, 54, 2, 12, 32, 0, 32, 0, 54, 2, 8, 11, 150, 5, 1, 4, 127, 32, 0, 32, 1, 106, 33, 2, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 40, 2, 4, 34, 3, 65, 1, 113, 13, 0, 32, 3, 65, 3, 113, 69, 13, 1, 32, 0, 40, 2, 0, 34, 3, 32, 1, 106, 33, 1, 2, 64, 2, 64, 2, 64, 65, 0, 40, 2, 188, 147, 65, 32, 0, 32, 3, 107, 34, 0, 70, 13, 0, 32, 3, 65, 255, 1, 75, 13, 1, 32, 0, 40, 2, 12, 34, 4, 32, 0, 40, 2, 8, 34, 5, 70, 13, 2, 32, 5, 32, 4, 54, 2, 12, 32, 4, 32, 5, 54, 2, 8, 12, 3, 11, 32, 2, 40, 2, 4, 65, 3, 113, 65, 3, 71, 13, 2, 65, 0, 32, 1, 54, 2, 180, 147, 65, 32, 2, 65, 4, 106, 34, 3, 32, 3, 40, 2, 0, 65, 126, 113, 54, 2, 0, 32, 0, 32, 1, 65, 1, 114, 54, 2, 4, 32, 2, 32, 1, 54, 2, 0, 15, 11, 32, 0, 16, 96, 12, 1, 11, 65, 0, 65, 0, 40, 2, 164, 144, 65, 65, 126, 32, 3, 65, 3, 118, 119, 113, 54, 2, 164, 144, 65, 11, 2, 64, 2, 64, 32, 2, 40, 2, 4, 34, 3, 65, 2, 113, 13, 0, 65, 0, 40, 2, 192, 147, 65, 32, 2, 70, 13, 1, 65, 0, 40, 2, 188, 147, 65, 32, 2, 70, 13, 3, 32, 3, 65, 120, 113, 34, 4, 32, 1, 106, 33, 1, 32, 4, 65, 255, 1, 75, 13, 4, 32, 2, 40, 2, 12, 34, 4, 32, 2, 40, 2, 8, 34, 2, 70, 13, 6, 32, 2, 32, 4, 54, 2, 12, 32, 4, 32, 2, 54, 2, 8, 12, 7, 11, 32, 2, 65, 4, 106, 32, 3, 65, 126, 113, 54, 2, 0, 32, 0, 32, 1, 65, 1, 114, 54, 2, 4, 32, 0, 32, 1, 106, 32, 1, 54, 2, 0, 12, 7, 11, 65, 0, 32, 0, 54, 2, 192, 147, 65, 65, 0, 65, 0, 40, 2, 184, 147, 65, 32, 1, 106, 34, 1, 54, 2, 184, 147, 65, 32, 0, 32, 1, 65, 1, 114, 54, 2, 4, 32, 0, 65, 0, 40, 2, 188, 147, 65, 70, 13, 3, 11, 15, 11, 65, 0, 32, 0, 54, 2, 188, 147, 65, 65, 0, 65, 0, 40, 2, 180, 147, 65, 32, 1, 106, 34, 1, 54, 2, 180, 147, 65, 32, 0, 32, 1, 65, 1, 114, 54, 2, 4, 32, 0, 32, 1, 106, 32, 1, 54, 2, 0, 15, 11, 32, 2, 16, 96, 12, 2, 11, 65, 0, 65, 0, 54, 2, 180, 147, 65, 65, 0, 65, 0, 54, 2, 188, 147, 65, 15, 11, 65, 0, 65, 0, 40, 2, 164, 144, 65, 65, 126, 32, 3, 65, 3, 118, 119, 113, 54, 2, 164, 144, 65, 11, 32, 0, 32, 1, 65, 1, 114, 54, 2, 4, 32, 0, 32, 1, 106, 32, 1, 54, 2, 0, 32, 0, 65, 0, 40, 2, 188, 147, 65, 71, 13, 0, 65, 0, 32, 1, 54, 2, 180, 147, 65, 15, 11, 2, 64, 2, 64, 2, 64, 32, 1, 65, 255, 1, 75, 13, 0, 32, 1, 65, 3, 118, 34, 2, 65, 3, 116, 65, 172, 144, 193, 0, 106, 33, 1, 65, 0, 40, 2, 164, 144, 65, 34, 3, 65, 1, 32, 2, 65, 31, 113, 116, 34, 2, 113, 69, 13, 1, 32, 1, 40, 2, 8, 33, 2, 12, 2, 11, 32, 0, 32, 1, 16, 97, 15, 11, 65, 0, 32, 3, 32, 2, 114, 54, 2, 164, 144, 65, 32, 1, 33, 2, 11, 32, 1, 65, 8, 106, 32, 0, 54, 2, 0, 32, 2, 32, 0, 54, 2, 12, 32, 0, 32, 1, 54, 2, 12, 32, 0, 32, 2, 54, 2, 8, 11, 112, 1, 3, 127, 65, 2, 33, 2, 2, 64, 32, 0, 40, 2, 4, 34, 3, 32, 1, 79, 13, 0, 65, 0, 33, 2, 32, 3, 65, 1, 116, 34, 4, 32, 1, 32, 4, 32, 1, 75, 27, 34, 1, 65, 0, 72, 13, 0, 2, 64, 2, 64, 2, 64, 32, 3, 69, 13, 0, 32, 0, 40, 2, 0, 32, 1, 16, 42, 34, 2, 69, 13, 1, 12, 2, 11, 32, 1, 16, 34, 34, 2, 13, 1, 11, 0, 0, 11, 32, 0, 32, 2, 54, 2, 0, 32, 0, 65, 4, 106, 32, 1, 54, 2, 0, 65, 2, 33, 2, 11, 32, 2, 11, 5, 0, 16, 36, 0, 11, 128, 10, 1, 4, 127, 35, 0, 65, 32, 107, 34, 6, 36, 0, 32, 6, 32, 3, 54, 2, 4, 32, 6, 32, 2, 54, 2, 0, 32, 6, 65, 128, 128, 196, 0, 54, 2, 8, 2, 64, 2, 64, 2, 64, 32, 1, 69, 13, 0, 32, 0, 40, 2, 0, 34, 7, 65, 1, 113, 13, 1, 32, 5, 33, 8, 12, 2, 11, 32, 6, 65, 45, 54, 2, 8, 32, 5, 65, 1, 106, 33, 8, 32, 0, 40, 2, 0, 33, 7, 12, 1, 11, 32, 6, 65, 43, 54, 2, 8, 32, 5, 65, 1, 106, 33, 8, 11, 65, 0, 33, 1, 32, 6, 65, 0, 58, 0, 15, 2, 64, 32, 7, 65, 4, 113, 69, 13, 0, 32, 6, 65, 1, 58, 0, 15, 2, 64, 32, 3, 69, 13, 0, 65, 0, 33, 1, 32, 3, 33, 9, 3, 64, 32, 1, 32, 2, 45, 0, 0, 65, 192, 1, 113, 65, 128, 1, 70, 106, 33, 1, 32, 2, 65, 1, 106, 33, 2, 32, 9, 65, 127, 106, 34, 9, 13, 0, 11, 11, 32, 8, 32, 3, 106, 32, 1, 107, 33, 8, 11, 32, 0, 40, 2, 8, 33, 2, 32, 6, 32, 6, 65, 15, 106, 54, 2, 20, 32, 6, 32, 6, 65, 8, 106, 54, 2, 16, 32, 6, 32, 6, 54, 2, 24, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 2, 65, 1, 71, 13, 0, 32, 0, 65, 12, 106, 40, 2, 0, 34, 2, 32, 8, 77, 13, 1, 32, 7, 65, 8, 113, 13, 2, 32, 2, 32, 8, 107, 33, 9, 65, 1, 32, 0, 45, 0, 48, 34, 2, 32, 2, 65, 3, 70, 27, 65, 3, 113, 34, 2, 69, 13, 4, 32, 2, 65, 2, 70, 13, 3, 65, 0, 33, 3, 12, 5, 11, 32, 6, 65, 16, 106, 32, 0, 16, 117, 13, 12, 32, 0, 40, 2, 24, 32, 4, 32, 5, 32, 0, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 2, 12, 14, 11, 32, 6, 65, 16, 106, 32, 0, 16, 117, 13, 11, 32, 0, 40, 2, 24, 32, 4, 32, 5, 32, 0, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 2, 12, 13, 11, 32, 0, 65, 1, 58, 0, 48, 32, 0, 65, 48, 54, 2, 4, 32, 6, 65, 16, 106, 32, 0, 16, 117, 13, 10, 32, 2, 32, 8, 107, 33, 9, 65, 1, 32, 0, 65, 48, 106, 45, 0, 0, 34, 2, 32, 2, 65, 3, 70, 27, 65, 3, 113, 34, 2, 69, 13, 4, 32, 2, 65, 2, 70, 13, 3, 65, 0, 33, 3, 12, 5, 11, 32, 9, 65, 1, 106, 65, 1, 118, 33, 3, 32, 9, 65, 1, 118, 33, 9, 12, 1, 11, 32, 9, 33, 3, 65, 0, 33, 9, 11, 32, 6, 65, 0, 54, 2, 28, 2, 64, 32, 0, 40, 2, 4, 34, 2, 65, 255, 0, 75, 13, 0, 32, 6, 32, 2, 58, 0, 28, 65, 1, 33, 1, 12, 5, 11, 2, 64, 32, 2, 65, 255, 15, 75, 13, 0, 32, 6, 32, 2, 65, 63, 113, 65, 128, 1, 114, 58, 0, 29, 32, 6, 32, 2, 65, 6, 118, 65, 31, 113, 65, 192, 1, 114, 58, 0, 28, 65, 2, 33, 1, 12, 5, 11, 32, 2, 65, 255, 255, 3, 75, 13, 3, 32, 6, 32, 2, 65, 63, 113, 65, 128, 1, 114, 58, 0, 30, 32, 6, 32, 2, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 29, 32, 6, 32, 2, 65, 12, 118, 65, 15, 113, 65, 224, 1, 114, 58, 0, 28, 65, 3, 33, 1, 12, 4, 11, 32, 9, 65, 1, 106, 65, 1, 118, 33, 3, 32, 9, 65, 1, 118, 33, 9, 12, 1, 11, 32, 9, 33, 3, 65, 0, 33, 9, 11, 32, 6, 65, 0, 54, 2, 28, 2, 64, 32, 0, 65, 4, 106, 40, 2, 0, 34, 2, 65, 255, 0, 75, 13, 0, 32, 6, 32, 2, 58, 0, 28, 65, 1, 33, 1, 12, 4, 11, 32, 2, 65, 255, 15, 75, 13, 2, 32, 6, 32, 2, 65, 63, 113, 65, 128, 1, 114, 58, 0, 29, 32, 6, 32, 2, 65, 6, 118, 65, 31, 113, 65, 192, 1, 114, 58, 0, 28, 65, 2, 33, 1, 12, 3, 11, 32, 6, 32, 2, 65, 18, 118, 65, 240, 1, 114, 58, 0, 28, 32, 6, 32, 2, 65, 63, 113, 65, 128, 1, 114, 58, 0, 31, 32, 6, 32, 2, 65, 12, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 29, 32, 6, 32, 2, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 30, 65, 4, 33, 1, 11, 65, 127, 33, 2, 2, 64, 3, 64, 32, 2, 65, 1, 106, 34, 2, 32, 9, 79, 13, 1, 32, 0, 65, 24, 106, 40, 2, 0, 32, 6, 65, 28, 106, 32, 1, 32, 0, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 69, 13, 0, 12, 4, 11, 11, 32, 6, 65, 16, 106, 32, 0, 16, 117, 13, 2, 32, 0, 65, 24, 106, 34, 9, 40, 2, 0, 32, 4, 32, 5, 32, 0, 65, 28, 106, 34, 0, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 2, 65, 127, 33, 2, 3, 64, 32, 2, 65, 1, 106, 34, 2, 32, 3, 79, 13, 4, 32, 9, 40, 2, 0, 32, 6, 65, 28, 106, 32, 1, 32, 0, 40, 2, 0, 40, 2, 12, 17, 1, 0, 69, 13, 0, 12, 3, 11, 11, 2, 64, 32, 2, 65, 255, 255, 3, 75, 13, 0, 32, 6, 32, 2, 65, 63, 113, 65, 128, 1, 114, 58, 0, 30, 32, 6, 32, 2, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 29, 32, 6, 32, 2, 65, 12, 118, 65, 15, 113, 65, 224, 1, 114, 58, 0, 28, 65, 3, 33, 1, 12, 1, 11, 32, 6, 32, 2, 65, 18, 118, 65, 240, 1, 114, 58, 0, 28, 32, 6, 32, 2, 65, 63, 113, 65, 128, 1, 114, 58, 0, 31, 32, 6, 32, 2, 65, 12, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 29, 32, 6, 32, 2, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 30, 65, 4, 33, 1, 11, 65, 127, 33, 2, 2, 64, 3, 64, 32, 2, 65, 1, 106, 34, 2, 32, 9, 79, 13, 1, 32, 0, 65, 24, 106, 40, 2, 0, 32, 6, 65, 28, 106, 32, 1, 32, 0, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 69, 13, 0, 12, 2, 11, 11, 32, 0, 65, 24, 106, 34, 9, 40, 2, 0, 32, 4, 32, 5, 32, 0, 65, 28, 106, 34, 0, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 0, 65, 127, 33, 2, 3, 64, 32, 2, 65, 1, 106, 34, 2, 32, 3, 79, 13, 2, 32, 9, 40, 2, 0, 32, 6, 65, 28, 106, 32, 1, 32, 0, 40, 2, 0, 40, 2, 12, 17, 1, 0, 69, 13, 0, 11, 11, 65, 1, 33, 2, 12, 1, 11, 65, 0, 33, 2, 11, 32, 6, 65, 32, 106, 36, 0, 32, 2, 11, 195, 5, 1, 7, 127, 65, 0, 33, 4, 2, 64, 2, 64, 32, 2, 65, 3, 113, 34, 5, 69, 13, 0, 65, 4, 32, 5, 107, 34, 5, 69, 13, 0, 32, 2, 32, 3, 32, 5, 32, 5, 32, 3, 75, 27, 34, 4, 106, 33, 6, 65, 0, 33, 5, 32, 1, 65, 255, 1, 113, 33, 7, 32, 4, 33, 8, 32, 2, 33, 9, 2, 64, 2, 64, 3, 64, 32, 6, 32, 9, 107, 65, 3, 77, 13, 1, 32, 5, 32, 9, 45, 0, 0, 34, 10, 32, 7, 71, 106, 33, 5, 32, 10, 32, 7, 70, 13, 2, 32, 5, 32, 9, 65, 1, 106, 45, 0, 0, 34, 10, 32, 7, 71, 106, 33, 5, 32, 10, 32, 7, 70, 13, 2, 32, 5, 32, 9, 65, 2, 106, 45, 0, 0, 34, 10, 32, 7, 71, 106, 33, 5, 32, 10, 32, 7, 70, 13, 2, 32, 5, 32, 9, 65, 3, 106, 45, 0, 0, 34, 10, 32, 7, 71, 106, 33, 5, 32, 8, 65, 124, 106, 33, 8, 32, 9, 65, 4, 106, 33, 9, 32, 10, 32, 7, 71, 13, 0, 12, 2, 11, 11, 65, 0, 33, 7, 32, 1, 65, 255, 1, 113, 33, 6, 3, 64, 32, 8, 69, 13, 2, 32, 9, 32, 7, 106, 33, 10, 32, 8, 65, 127, 106, 33, 8, 32, 7, 65, 1, 106, 33, 7, 32, 10, 45, 0, 0, 34, 10, 32, 6, 71, 13, 0, 11, 32, 10, 32, 1, 65, 255, 1, 113, 70, 65, 1, 106, 65, 1, 113, 32, 5, 106, 32, 7, 106, 65, 127, 106, 33, 5, 11, 65, 1, 33, 9, 12, 1, 11, 32, 1, 65, 255, 1, 113, 33, 7, 2, 64, 2, 64, 32, 3, 65, 8, 73, 13, 0, 32, 4, 32, 3, 65, 120, 106, 34, 10, 75, 13, 0, 32, 7, 65, 129, 130, 132, 8, 108, 33, 5, 2, 64, 3, 64, 32, 2, 32, 4, 106, 34, 9, 65, 4, 106, 40, 2, 0, 32, 5, 115, 34, 8, 65, 127, 115, 32, 8, 65, 255, 253, 251, 119, 106, 113, 32, 9, 40, 2, 0, 32, 5, 115, 34, 9, 65, 127, 115, 32, 9, 65, 255, 253, 251, 119, 106, 113, 114, 65, 128, 129, 130, 132, 120, 113, 13, 1, 32, 4, 65, 8, 106, 34, 4, 32, 10, 77, 13, 0, 11, 11, 32, 4, 32, 3, 75, 13, 1, 11, 32, 2, 32, 4, 106, 33, 9, 32, 2, 32, 3, 106, 33, 2, 32, 3, 32, 4, 107, 33, 8, 65, 0, 33, 5, 2, 64, 2, 64, 2, 64, 3, 64, 32, 2, 32, 9, 107, 65, 3, 77, 13, 1, 32, 5, 32, 9, 45, 0, 0, 34, 10, 32, 7, 71, 106, 33, 5, 32, 10, 32, 7, 70, 13, 2, 32, 5, 32, 9, 65, 1, 106, 45, 0, 0, 34, 10, 32, 7, 71, 106, 33, 5, 32, 10, 32, 7, 70, 13, 2, 32, 5, 32, 9, 65, 2, 106, 45, 0, 0, 34, 10, 32, 7, 71, 106, 33, 5, 32, 10, 32, 7, 70, 13, 2, 32, 5, 32, 9, 65, 3, 106, 45, 0, 0, 34, 10, 32, 7, 71, 106, 33, 5, 32, 8, 65, 124, 106, 33, 8, 32, 9, 65, 4, 106, 33, 9, 32, 10, 32, 7, 71, 13, 0, 12, 2, 11, 11, 65, 0, 33, 7, 32, 1, 65, 255, 1, 113, 33, 2, 3, 64, 32, 8, 69, 13, 2, 32, 9, 32, 7, 106, 33, 10, 32, 8, 65, 127, 106, 33, 8, 32, 7, 65, 1, 106, 33, 7, 32, 10, 45, 0, 0, 34, 10, 32, 2, 71, 13, 0, 11, 32, 10, 32, 1, 65, 255, 1, 113, 70, 65, 1, 106, 65, 1, 113, 32, 5, 106, 32, 7, 106, 65, 127, 106, 33, 5, 11, 65, 1, 33, 9, 32, 5, 32, 4, 106, 33, 5, 12, 2, 11, 65, 0, 33, 9, 32, 5, 32, 7, 106, 32, 4, 106, 33, 5, 12, 1, 11, 32, 4, 32, 3, 16, 48, 0, 11, 32, 0, 32, 5, 54, 2, 4, 32, 0, 32, 9, 54, 2, 0, 11, 226, 2, 1, 6, 127, 32, 1, 32, 2, 65, 1, 116, 106, 33, 7, 32, 0, 65, 128, 254, 3, 113, 65, 8, 118, 33, 8, 65, 0, 33, 9, 32, 0, 65, 255, 1, 113, 33, 10, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 3, 64, 32, 1, 65, 2, 106, 33, 11, 32, 9, 32, 1, 45, 0, 1, 34, 2, 106, 33, 12, 2, 64, 2, 64, 32, 1, 45, 0, 0, 34, 1, 32, 8, 71, 13, 0, 32, 12, 32, 9, 73, 13, 6, 32, 12, 32, 4, 75, 13, 7, 32, 3, 32, 9, 106, 33, 1, 3, 64, 32, 2, 69, 13, 2, 32, 2, 65, 127, 106, 33, 2, 32, 1, 45, 0, 0, 33, 9, 32, 1, 65, 1, 106, 33, 1, 32, 9, 32, 10, 71, 13, 0, 12, 5, 11, 11, 32, 1, 32, 8, 75, 13, 2, 32, 12, 33, 9, 32, 11, 33, 1, 32, 11, 32, 7, 71, 13, 1, 12, 2, 11, 32, 12, 33, 9, 32, 11, 33, 1, 32, 11, 32, 7, 71, 13, 0, 11, 11, 32, 0, 65, 255, 255, 3, 113, 33, 10, 32, 5, 65, 1, 106, 33, 1, 32, 5, 32, 6, 106, 33, 12, 65, 1, 33, 2, 3, 64, 2, 64, 2, 64, 32, 5, 45, 0, 0, 34, 9, 65, 24, 116, 65, 24, 117, 34, 11, 65, 127, 76, 13, 0, 32, 1, 33, 5, 12, 1, 11, 32, 1, 32, 12, 70, 13, 6, 32, 1, 65, 1, 106, 33, 5, 32, 11, 65, 255, 0, 113, 65, 8, 116, 32, 1, 45, 0, 0, 114, 33, 9, 11, 32, 10, 32, 9, 107, 34, 10, 65, 0, 72, 13, 2, 32, 5, 65, 1, 106, 33, 1, 32, 2, 65, 1, 115, 33, 2, 32, 5, 32, 12, 71, 13, 0, 12, 2, 11, 11, 65, 0, 33, 2, 11, 32, 2, 65, 1, 113, 15, 11, 32, 9, 32, 12, 16, 48, 0, 11, 32, 12, 32, 4, 16, 49, 0, 11, 65, 224, 129, 193, 0, 16, 79, 0, 11, 16, 0, 32, 1, 32, 0, 40, 2, 0, 32, 0, 40, 2, 4, 16, 40, 11, 210, 4, 3, 3, 127, 1, 126, 2, 127, 65, 1, 33, 2, 2, 64, 32, 1, 40, 2, 24, 65, 39, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 16, 17, 2, 0, 13, 0, 65, 2, 33, 3, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 40, 2, 0, 34, 2, 65, 119, 106, 34, 0, 65, 30, 75, 13, 0, 65, 244, 0, 33, 4, 2, 64, 32, 0, 14, 31, 10, 0, 2, 2, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 6, 2, 2, 2, 2, 6, 10, 11, 65, 238, 0, 33, 4, 12, 3, 11, 32, 2, 65, 220, 0, 70, 13, 4, 11, 32, 2, 16, 80, 69, 13, 2, 32, 2, 65, 1, 114, 103, 65, 2, 118, 65, 7, 115, 173, 66, 128, 128, 128, 128, 208, 0, 132, 33, 5, 12, 5, 11, 65, 242, 0, 33, 4, 11, 12, 5, 11, 32, 2, 16, 81, 69, 13, 1, 65, 1, 33, 3, 11, 12, 2, 11, 32, 2, 65, 1, 114, 103, 65, 2, 118, 65, 7, 115, 173, 66, 128, 128, 128, 128, 208, 0, 132, 33, 5, 11, 65, 3, 33, 3, 11, 32, 2, 33, 4, 11, 32, 1, 65, 24, 106, 33, 0, 32, 1, 65, 28, 106, 33, 6, 3, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 3, 65, 3, 113, 34, 2, 65, 1, 70, 13, 0, 32, 2, 65, 2, 70, 13, 1, 32, 2, 65, 3, 71, 13, 7, 32, 5, 66, 32, 136, 167, 65, 7, 113, 65, 127, 106, 34, 2, 65, 4, 75, 13, 7, 2, 64, 32, 2, 14, 5, 0, 3, 4, 5, 6, 0, 11, 32, 5, 66, 255, 255, 255, 255, 143, 96, 131, 33, 5, 65, 253, 0, 33, 2, 12, 8, 11, 65, 0, 33, 3, 32, 4, 33, 2, 12, 7, 11, 65, 220, 0, 33, 2, 65, 1, 33, 3, 12, 6, 11, 32, 4, 32, 5, 167, 34, 7, 65, 2, 116, 65, 28, 113, 118, 65, 15, 113, 34, 2, 65, 48, 114, 32, 2, 65, 215, 0, 106, 32, 2, 65, 10, 73, 27, 33, 2, 32, 7, 69, 13, 3, 32, 5, 66, 127, 124, 66, 255, 255, 255, 255, 15, 131, 32, 5, 66, 128, 128, 128, 128, 112, 131, 132, 33, 5, 12, 5, 11, 32, 5, 66, 255, 255, 255, 255, 143, 96, 131, 66, 128, 128, 128, 128, 32, 132, 33, 5, 65, 251, 0, 33, 2, 12, 4, 11, 32, 5, 66, 255, 255, 255, 255, 143, 96, 131, 66, 128, 128, 128, 128, 48, 132, 33, 5, 65, 245, 0, 33, 2, 12, 3, 11, 32, 5, 66, 255, 255, 255, 255, 143, 96, 131, 66, 128, 128, 128, 128, 192, 0, 132, 33, 5, 65, 220, 0, 33, 2, 12, 2, 11, 32, 5, 66, 255, 255, 255, 255, 143, 96, 131, 66, 128, 128, 128, 128, 16, 132, 33, 5, 12, 1, 11, 32, 1, 65, 24, 106, 40, 2, 0, 65, 39, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 16, 17, 2, 0, 33, 2, 12, 2, 11, 32, 0, 40, 2, 0, 32, 2, 32, 6, 40, 2, 0, 40, 2, 16, 17, 2, 0, 69, 13, 0, 11, 65, 1, 15, 11, 32, 2, 11, 149, 1, 1, 1, 127, 35, 0, 65, 48, 107, 34, 2, 36, 0, 32, 2, 65, 8, 106, 65, 12, 106, 65, 14, 54, 2, 0, 32, 2, 65, 14, 54, 2, 12, 32, 2, 32, 0, 54, 2, 8, 32, 2, 32, 0, 65, 4, 106, 54, 2, 16, 32, 1, 65, 28, 106, 40, 2, 0, 33, 0, 32, 1, 40, 2, 24, 33, 1, 32, 2, 65, 24, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 2, 65, 44, 106, 65, 2, 54, 2, 0, 32, 2, 65, 2, 54, 2, 28, 32, 2, 65, 176, 142, 193, 0, 54, 2, 24, 32, 2, 65, 148, 251, 192, 0, 54, 2, 32, 32, 2, 32, 2, 65, 8, 106, 54, 2, 40, 32, 1, 32, 0, 32, 2, 65, 24, 106, 16, 63, 33, 1, 32, 2, 65, 48, 106, 36, 0, 32, 1, 11, 2, 0, 11, 187, 4, 3, 2, 127, 1, 126, 3, 127, 35, 0, 65, 224, 0, 107, 34, 5, 36, 0, 32, 5, 32, 2, 54, 2, 12, 32, 5, 32, 1, 54, 2, 8, 2, 64, 2, 64, 32, 0, 45, 0, 4, 13, 0, 32, 5, 65, 234, 253, 192, 0, 65, 159, 238, 192, 0, 32, 0, 45, 0, 5, 34, 1, 27, 34, 2, 54, 2, 16, 32, 5, 65, 1, 65, 2, 32, 1, 27, 34, 6, 54, 2, 20, 2, 64, 32, 0, 40, 2, 0, 34, 1, 45, 0, 0, 65, 4, 113, 13, 0, 32, 5, 65, 208, 0, 106, 65, 12, 106, 65, 1, 54, 2, 0, 32, 5, 65, 1, 54, 2, 84, 32, 1, 65, 28, 106, 40, 2, 0, 33, 2, 32, 5, 32, 5, 65, 16, 106, 54, 2, 80, 32, 5, 32, 5, 65, 8, 106, 54, 2, 88, 32, 1, 40, 2, 24, 33, 1, 32, 5, 65, 24, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 5, 65, 44, 106, 65, 2, 54, 2, 0, 32, 5, 65, 3, 54, 2, 28, 32, 5, 65, 160, 130, 193, 0, 54, 2, 24, 32, 5, 65, 148, 251, 192, 0, 54, 2, 32, 32, 5, 32, 5, 65, 208, 0, 106, 54, 2, 40, 32, 1, 32, 2, 32, 5, 65, 24, 106, 16, 63, 13, 1, 32, 3, 32, 0, 40, 2, 0, 32, 4, 40, 2, 12, 17, 2, 0, 33, 1, 12, 2, 11, 32, 5, 65, 0, 58, 0, 88, 32, 5, 32, 1, 41, 2, 24, 55, 3, 80, 32, 1, 41, 2, 0, 33, 7, 32, 5, 65, 24, 106, 65, 12, 106, 32, 1, 65, 12, 106, 40, 2, 0, 54, 2, 0, 32, 5, 65, 24, 106, 65, 20, 106, 32, 1, 65, 20, 106, 40, 2, 0, 54, 2, 0, 32, 5, 32, 1, 45, 0, 48, 58, 0, 72, 32, 5, 32, 7, 55, 3, 24, 32, 5, 32, 1, 40, 2, 8, 54, 2, 32, 32, 5, 32, 1, 40, 2, 16, 54, 2, 40, 32, 1, 65, 44, 106, 40, 2, 0, 33, 8, 32, 1, 65, 36, 106, 40, 2, 0, 33, 9, 32, 5, 32, 5, 65, 208, 0, 106, 54, 2, 48, 32, 1, 40, 2, 40, 33, 10, 32, 1, 40, 2, 32, 33, 1, 32, 5, 65, 52, 106, 65, 184, 129, 193, 0, 54, 2, 0, 32, 5, 32, 1, 54, 2, 56, 32, 5, 65, 24, 106, 65, 36, 106, 32, 9, 54, 2, 0, 32, 5, 32, 10, 54, 2, 64, 32, 5, 65, 24, 106, 65, 44, 106, 32, 8, 54, 2, 0, 32, 5, 65, 208, 0, 106, 32, 2, 32, 6, 16, 111, 13, 0, 32, 5, 65, 208, 0, 106, 65, 147, 238, 192, 0, 65, 1, 16, 111, 13, 0, 32, 5, 65, 208, 0, 106, 32, 5, 40, 2, 8, 32, 5, 40, 2, 12, 16, 111, 13, 0, 32, 5, 65, 208, 0, 106, 65, 237, 251, 192, 0, 65, 2, 16, 111, 13, 0, 32, 3, 32, 5, 65, 24, 106, 32, 4, 40, 2, 12, 17, 2, 0, 33, 1, 12, 1, 11, 65, 1, 33, 1, 11, 32, 0, 65, 5, 106, 65, 1, 58, 0, 0, 32, 0, 65, 4, 106, 32, 1, 58, 0, 0, 32, 5, 65, 224, 0, 106, 36, 0, 32, 0, 11, 2, 0, 11, 13, 0, 32, 0, 40, 2, 0, 32, 1, 32, 2, 16, 111, 11, 150, 5, 1, 13, 127, 35, 0, 65, 192, 0, 107, 34, 3, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 2, 69, 13, 0, 32, 3, 65, 56, 106, 33, 4, 32, 0, 65, 8, 106, 33, 5, 32, 3, 65, 44, 106, 33, 6, 32, 3, 65, 48, 106, 33, 7, 32, 3, 65, 52, 106, 33, 8, 32, 0, 65, 4, 106, 33, 9, 3, 64, 2, 64, 32, 5, 45, 0, 0, 69, 13, 0, 32, 0, 40, 2, 0, 65, 155, 238, 192, 0, 65, 4, 32, 9, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 3, 11, 32, 3, 65, 32, 106, 65, 8, 106, 34, 10, 65, 0, 54, 2, 0, 32, 6, 32, 2, 54, 2, 0, 32, 7, 66, 138, 128, 128, 128, 16, 55, 3, 0, 32, 4, 65, 10, 54, 2, 0, 32, 3, 32, 2, 54, 2, 36, 32, 3, 32, 1, 54, 2, 32, 32, 3, 65, 8, 106, 65, 10, 32, 1, 32, 2, 16, 102, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 3, 40, 2, 8, 65, 1, 71, 13, 0, 32, 3, 40, 2, 12, 33, 11, 3, 64, 32, 10, 32, 11, 32, 10, 40, 2, 0, 106, 65, 1, 106, 34, 11, 54, 2, 0, 2, 64, 2, 64, 32, 11, 32, 8, 40, 2, 0, 34, 12, 79, 13, 0, 32, 3, 40, 2, 36, 33, 13, 12, 1, 11, 32, 3, 40, 2, 36, 34, 13, 32, 11, 73, 13, 0, 32, 12, 65, 5, 79, 13, 5, 32, 3, 40, 2, 32, 32, 11, 32, 12, 107, 34, 14, 106, 34, 15, 32, 4, 70, 13, 4, 32, 15, 32, 4, 32, 12, 16, 151, 1, 69, 13, 4, 11, 32, 6, 40, 2, 0, 34, 15, 32, 11, 73, 13, 2, 32, 13, 32, 15, 73, 13, 2, 32, 3, 32, 3, 65, 32, 106, 32, 12, 106, 65, 23, 106, 45, 0, 0, 32, 3, 40, 2, 32, 32, 11, 106, 32, 15, 32, 11, 107, 16, 102, 32, 3, 40, 2, 4, 33, 11, 32, 3, 40, 2, 0, 65, 1, 70, 13, 0, 11, 11, 32, 10, 32, 6, 40, 2, 0, 54, 2, 0, 11, 32, 5, 65, 0, 58, 0, 0, 32, 2, 33, 11, 12, 2, 11, 32, 5, 65, 1, 58, 0, 0, 32, 14, 65, 1, 106, 33, 11, 12, 1, 11, 32, 12, 65, 4, 16, 49, 0, 11, 32, 9, 40, 2, 0, 33, 15, 32, 0, 40, 2, 0, 33, 12, 32, 3, 32, 1, 54, 2, 32, 32, 3, 32, 2, 54, 2, 36, 2, 64, 32, 11, 69, 32, 2, 32, 11, 70, 114, 34, 10, 13, 0, 32, 2, 32, 11, 77, 13, 5, 32, 1, 32, 11, 106, 44, 0, 0, 65, 191, 127, 76, 13, 5, 11, 32, 12, 32, 1, 32, 11, 32, 15, 40, 2, 12, 17, 1, 0, 13, 2, 32, 3, 32, 2, 54, 2, 20, 32, 3, 32, 1, 54, 2, 16, 32, 3, 32, 11, 54, 2, 24, 32, 3, 32, 2, 54, 2, 28, 2, 64, 32, 10, 69, 13, 0, 32, 1, 32, 11, 106, 33, 1, 32, 2, 32, 11, 107, 34, 2, 13, 1, 12, 2, 11, 32, 2, 32, 11, 77, 13, 5, 32, 1, 32, 11, 106, 34, 1, 44, 0, 0, 65, 191, 127, 76, 13, 5, 32, 2, 32, 11, 107, 34, 2, 13, 0, 11, 11, 65, 0, 33, 11, 12, 1, 11, 65, 1, 33, 11, 11, 32, 3, 65, 192, 0, 106, 36, 0, 32, 11, 15, 11, 32, 3, 65, 32, 106, 32, 11, 16, 121, 0, 11, 32, 3, 32, 3, 65, 24, 106, 54, 2, 36, 32, 3, 32, 3, 65, 16, 106, 54, 2, 32, 32, 3, 32, 3, 65, 28, 106, 54, 2, 40, 32, 3, 65, 32, 106, 16, 122, 0, 11, 11, 0, 32, 0, 40, 2, 0, 32, 1, 16, 113, 11, 249, 1, 1, 1, 127, 35, 0, 65, 16, 107, 34, 2, 36, 0, 32, 2, 65, 0, 54, 2, 12, 2, 64, 2, 64, 32, 1, 65, 255, 0, 75, 13, 0, 32, 2, 32, 1, 58, 0, 12, 65, 1, 33, 1, 12, 1, 11, 2, 64, 32, 1, 65, 255, 15, 75, 13, 0, 32, 2, 32, 1, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 1, 65, 6, 118, 65, 31, 113, 65, 192, 1, 114, 58, 0, 12, 65, 2, 33, 1, 12, 1, 11, 2, 64, 32, 1, 65, 255, 255, 3, 75, 13, 0, 32, 2, 32, 1, 65, 63, 113, 65, 128, 1, 114, 58, 0, 14, 32, 2, 32, 1, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 1, 65, 12, 118, 65, 15, 113, 65, 224, 1, 114, 58, 0, 12, 65, 3, 33, 1, 12, 1, 11, 32, 2, 32, 1, 65, 18, 118, 65, 240, 1, 114, 58, 0, 12, 32, 2, 32, 1, 65, 63, 113, 65, 128, 1, 114, 58, 0, 15, 32, 2, 32, 1, 65, 12, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 1, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 14, 65, 4, 33, 1, 11, 32, 0, 32, 2, 65, 12, 106, 32, 1, 16, 111, 33, 1, 32, 2, 65, 16, 106, 36, 0, 32, 1, 11, 99, 1, 1, 127, 35, 0, 65, 32, 107, 34, 2, 36, 0, 32, 2, 32, 0, 40, 2, 0, 54, 2, 4, 32, 2, 65, 8, 106, 65, 16, 106, 32, 1, 65, 16, 106, 41, 2, 0, 55, 3, 0, 32, 2, 65, 8, 106, 65, 8, 106, 32, 1, 65, 8, 106, 41, 2, 0, 55, 3, 0, 32, 2, 32, 1, 41, 2, 0, 55, 3, 8, 32, 2, 65, 4, 106, 65, 136, 130, 193, 0, 32, 2, 65, 8, 106, 16, 63, 33, 1, 32, 2, 65, 32, 106, 36, 0, 32, 1, 11, 8, 0, 32, 0, 32, 1, 16, 77, 11, 134, 1, 1, 1, 127, 35, 0, 65, 48, 107, 34, 3, 36, 0, 32, 3, 32, 2, 54, 2, 4, 32, 3, 32, 1, 54, 2, 0, 32, 3, 65, 32, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 3, 65, 8, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 3, 65, 28, 106, 65, 2, 54, 2, 0, 32, 3, 65, 2, 54, 2, 36, 32, 3, 65, 144, 143, 193, 0, 54, 2, 8, 32, 3, 65, 2, 54, 2, 12, 32, 3, 65, 148, 251, 192, 0, 54, 2, 16, 32, 3, 32, 3, 65, 4, 106, 54, 2, 32, 32, 3, 32, 3, 54, 2, 40, 32, 3, 32, 3, 65, 32, 106, 54, 2, 24, 32, 3, 65, 8, 106, 32, 0, 16, 67, 0, 11, 228, 2, 1, 5, 127, 35, 0, 65, 16, 107, 34, 2, 36, 0, 2, 64, 2, 64, 32, 0, 40, 2, 0, 40, 2, 0, 34, 3, 65, 128, 128, 196, 0, 70, 13, 0, 32, 1, 65, 28, 106, 40, 2, 0, 33, 4, 32, 1, 40, 2, 24, 33, 5, 32, 2, 65, 0, 54, 2, 12, 2, 64, 2, 64, 32, 3, 65, 255, 0, 75, 13, 0, 32, 2, 32, 3, 58, 0, 12, 65, 1, 33, 6, 12, 1, 11, 2, 64, 32, 3, 65, 255, 15, 75, 13, 0, 32, 2, 32, 3, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 3, 65, 6, 118, 65, 31, 113, 65, 192, 1, 114, 58, 0, 12, 65, 2, 33, 6, 12, 1, 11, 2, 64, 32, 3, 65, 255, 255, 3, 75, 13, 0, 32, 2, 32, 3, 65, 63, 113, 65, 128, 1, 114, 58, 0, 14, 32, 2, 32, 3, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 3, 65, 12, 118, 65, 15, 113, 65, 224, 1, 114, 58, 0, 12, 65, 3, 33, 6, 12, 1, 11, 32, 2, 32, 3, 65, 18, 118, 65, 240, 1, 114, 58, 0, 12, 32, 2, 32, 3, 65, 63, 113, 65, 128, 1, 114, 58, 0, 15, 32, 2, 32, 3, 65, 12, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 13, 32, 2, 32, 3, 65, 6, 118, 65, 63, 113, 65, 128, 1, 114, 58, 0, 14, 65, 4, 33, 6, 11, 65, 1, 33, 3, 32, 5, 32, 2, 65, 12, 106, 32, 6, 32, 4, 40, 2, 12, 17, 1, 0, 13, 1, 11, 2, 64, 32, 0, 40, 2, 4, 45, 0, 0, 69, 13, 0, 32, 1, 40, 2, 24, 32, 0, 40, 2, 8, 34, 0, 40, 2, 0, 32, 0, 40, 2, 4, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 3, 12, 1, 11, 65, 0, 33, 3, 11, 32, 2, 65, 16, 106, 36, 0, 32, 3, 11, 179, 2, 1, 3, 127, 35, 0, 65, 128, 1, 107, 34, 2, 36, 0, 32, 0, 40, 2, 0, 33, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 1, 40, 2, 0, 34, 3, 65, 16, 113, 13, 0, 32, 3, 65, 32, 113, 13, 1, 32, 0, 32, 1, 16, 77, 33, 0, 12, 2, 11, 32, 0, 40, 2, 0, 33, 3, 65, 0, 33, 0, 3, 64, 32, 2, 32, 0, 106, 65, 255, 0, 106, 32, 3, 65, 15, 113, 34, 4, 65, 48, 114, 32, 4, 65, 215, 0, 106, 32, 4, 65, 10, 73, 27, 58, 0, 0, 32, 0, 65, 127, 106, 33, 0, 32, 3, 65, 4, 118, 34, 3, 13, 0, 11, 32, 0, 65, 128, 1, 106, 34, 3, 65, 129, 1, 79, 13, 2, 32, 1, 65, 1, 65, 156, 218, 192, 0, 65, 2, 32, 2, 32, 0, 106, 65, 128, 1, 106, 65, 0, 32, 0, 107, 16, 101, 33, 0, 12, 1, 11, 32, 0, 40, 2, 0, 33, 3, 65, 0, 33, 0, 3, 64, 32, 2, 32, 0, 106, 65, 255, 0, 106, 32, 3, 65, 15, 113, 34, 4, 65, 48, 114, 32, 4, 65, 55, 106, 32, 4, 65, 10, 73, 27, 58, 0, 0, 32, 0, 65, 127, 106, 33, 0, 32, 3, 65, 4, 118, 34, 3, 13, 0, 11, 32, 0, 65, 128, 1, 106, 34, 3, 65, 129, 1, 79, 13, 2, 32, 1, 65, 1, 65, 156, 218, 192, 0, 65, 2, 32, 2, 32, 0, 106, 65, 128, 1, 106, 65, 0, 32, 0, 107, 16, 101, 33, 0, 11, 32, 2, 65, 128, 1, 106, 36, 0, 32, 0, 15, 11, 32, 3, 65, 128, 1, 16, 48, 0, 11, 32, 3, 65, 128, 1, 16, 48, 0, 11, 195, 3, 1, 3, 127, 35, 0, 65, 128, 1, 107, 34, 2, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 1, 40, 2, 0, 34, 3, 65, 16, 113, 13, 0, 32, 0, 45, 0, 0, 33, 0, 32, 3, 65, 32, 113, 13, 1, 32, 0, 65, 228, 0, 73, 13, 2, 32, 2, 32, 0, 32, 0, 65, 228, 0, 110, 34, 4, 65, 156, 127, 108, 106, 65, 255, 1, 113, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 37, 65, 37, 33, 3, 12, 3, 11, 32, 0, 45, 0, 0, 33, 3, 65, 0, 33, 0, 3, 64, 32, 2, 32, 0, 106, 65, 255, 0, 106, 32, 3, 65, 15, 113, 34, 4, 65, 48, 114, 32, 4, 65, 215, 0, 106, 32, 4, 65, 10, 73, 27, 58, 0, 0, 32, 0, 65, 127, 106, 33, 0, 32, 3, 65, 4, 118, 65, 15, 113, 34, 3, 13, 0, 11, 32, 0, 65, 128, 1, 106, 34, 3, 65, 129, 1, 79, 13, 6, 32, 1, 65, 1, 65, 156, 218, 192, 0, 65, 2, 32, 2, 32, 0, 106, 65, 128, 1, 106, 65, 0, 32, 0, 107, 16, 101, 33, 0, 12, 5, 11, 65, 0, 33, 3, 3, 64, 32, 2, 32, 3, 106, 65, 255, 0, 106, 32, 0, 65, 15, 113, 34, 4, 65, 48, 114, 32, 4, 65, 55, 106, 32, 4, 65, 10, 73, 27, 58, 0, 0, 32, 3, 65, 127, 106, 33, 3, 32, 0, 65, 4, 118, 65, 15, 113, 34, 0, 13, 0, 11, 32, 3, 65, 128, 1, 106, 34, 0, 65, 129, 1, 79, 13, 6, 32, 1, 65, 1, 65, 156, 218, 192, 0, 65, 2, 32, 2, 32, 3, 106, 65, 128, 1, 106, 65, 0, 32, 3, 107, 16, 101, 33, 0, 12, 4, 11, 65, 39, 33, 3, 32, 0, 65, 9, 75, 13, 1, 32, 0, 33, 4, 11, 32, 2, 32, 3, 106, 65, 127, 106, 34, 0, 32, 4, 65, 48, 106, 58, 0, 0, 65, 40, 32, 3, 107, 33, 3, 12, 1, 11, 32, 2, 32, 0, 65, 1, 116, 65, 158, 218, 192, 0, 106, 47, 0, 0, 59, 0, 37, 32, 2, 65, 37, 106, 33, 0, 65, 2, 33, 3, 11, 32, 1, 65, 1, 65, 140, 252, 192, 0, 65, 0, 32, 0, 32, 3, 16, 101, 33, 0, 11, 32, 2, 65, 128, 1, 106, 36, 0, 32, 0, 15, 11, 32, 3, 65, 128, 1, 16, 48, 0, 11, 32, 0, 65, 128, 1, 16, 48, 0, 11, 2, 0, 11, 19, 0, 32, 0, 40, 2, 0, 32, 0, 40, 2, 4, 65, 0, 32, 1, 16, 32, 0, 11, 38, 1, 1, 127, 32, 0, 40, 2, 0, 34, 1, 40, 2, 0, 32, 1, 40, 2, 4, 32, 0, 40, 2, 4, 40, 2, 0, 32, 0, 40, 2, 8, 40, 2, 0, 16, 32, 0, 11, 96, 1, 1, 127, 35, 0, 65, 32, 107, 34, 2, 36, 0, 32, 2, 32, 0, 54, 2, 4, 32, 2, 65, 8, 106, 65, 16, 106, 32, 1, 65, 16, 106, 41, 2, 0, 55, 3, 0, 32, 2, 65, 8, 106, 65, 8, 106, 32, 1, 65, 8, 106, 41, 2, 0, 55, 3, 0, 32, 2, 32, 1, 41, 2, 0, 55, 3, 8, 32, 2, 65, 4, 106, 65, 136, 130, 193, 0, 32, 2, 65, 8, 106, 16, 63, 33, 1, 32, 2, 65, 32, 106, 36, 0, 32, 1, 11, 172, 2, 1, 3, 127, 35, 0, 65, 128, 1, 107, 34, 2, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 1, 40, 2, 0, 34, 3, 65, 16, 113, 13, 0, 32, 3, 65, 32, 113, 13, 1, 32, 0, 32, 1, 16, 77, 33, 0, 12, 2, 11, 32, 0, 40, 2, 0, 33, 3, 65, 0, 33, 0, 3, 64, 32, 2, 32, 0, 106, 65, 255, 0, 106, 32, 3, 65, 15, 113, 34, 4, 65, 48, 114, 32, 4, 65, 215, 0, 106, 32, 4, 65, 10, 73, 27, 58, 0, 0, 32, 0, 65, 127, 106, 33, 0, 32, 3, 65, 4, 118, 34, 3, 13, 0, 11, 32, 0, 65, 128, 1, 106, 34, 3, 65, 129, 1, 79, 13, 2, 32, 1, 65, 1, 65, 156, 218, 192, 0, 65, 2, 32, 2, 32, 0, 106, 65, 128, 1, 106, 65, 0, 32, 0, 107, 16, 101, 33, 0, 12, 1, 11, 32, 0, 40, 2, 0, 33, 3, 65, 0, 33, 0, 3, 64, 32, 2, 32, 0, 106, 65, 255, 0, 106, 32, 3, 65, 15, 113, 34, 4, 65, 48, 114, 32, 4, 65, 55, 106, 32, 4, 65, 10, 73, 27, 58, 0, 0, 32, 0, 65, 127, 106, 33, 0, 32, 3, 65, 4, 118, 34, 3, 13, 0, 11, 32, 0, 65, 128, 1, 106, 34, 3, 65, 129, 1, 79, 13, 2, 32, 1, 65, 1, 65, 156, 218, 192, 0, 65, 2, 32, 2, 32, 0, 106, 65, 128, 1, 106, 65, 0, 32, 0, 107, 16, 101, 33, 0, 11, 32, 2, 65, 128, 1, 106, 36, 0, 32, 0, 15, 11, 32, 3, 65, 128, 1, 16, 48, 0, 11, 32, 3, 65, 128, 1, 16, 48, 0, 11, 2, 0, 11, 12, 0, 66, 234, 195, 252, 206, 228, 157, 170, 220, 2, 11, 209, 3, 1, 4, 127, 35, 0, 65, 208, 0, 107, 34, 2, 36, 0, 65, 1, 33, 3, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 40, 2, 0, 34, 0, 45, 0, 0, 65, 1, 71, 13, 0, 32, 1, 40, 2, 24, 65, 144, 251, 192, 0, 65, 4, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 3, 32, 0, 65, 1, 106, 33, 0, 32, 1, 40, 2, 0, 34, 3, 65, 4, 113, 13, 1, 65, 1, 33, 3, 32, 1, 65, 24, 106, 34, 4, 40, 2, 0, 65, 153, 238, 192, 0, 65, 1, 32, 1, 65, 28, 106, 34, 5, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 3, 32, 4, 40, 2, 0, 65, 140, 252, 192, 0, 65, 0, 32, 5, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 3, 32, 0, 32, 1, 16, 119, 13, 3, 12, 2, 11, 32, 1, 40, 2, 24, 65, 140, 251, 192, 0, 65, 4, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 3, 12, 2, 11, 32, 2, 65, 52, 106, 65, 184, 129, 193, 0, 54, 2, 0, 32, 2, 65, 24, 106, 65, 12, 106, 32, 1, 65, 12, 106, 41, 2, 0, 55, 2, 0, 32, 2, 65, 24, 106, 65, 20, 106, 32, 1, 65, 20, 106, 40, 2, 0, 54, 2, 0, 32, 2, 65, 0, 58, 0, 16, 32, 2, 32, 3, 54, 2, 24, 32, 2, 32, 1, 65, 24, 106, 41, 2, 0, 55, 3, 8, 32, 2, 32, 1, 45, 0, 48, 58, 0, 72, 32, 2, 32, 1, 41, 2, 4, 55, 2, 28, 32, 2, 32, 1, 41, 2, 40, 55, 3, 64, 32, 2, 32, 1, 41, 2, 32, 55, 3, 56, 32, 2, 32, 2, 65, 8, 106, 54, 2, 48, 65, 1, 33, 3, 32, 2, 65, 8, 106, 65, 153, 238, 192, 0, 65, 1, 16, 111, 13, 1, 32, 2, 65, 8, 106, 65, 147, 238, 192, 0, 65, 1, 16, 111, 13, 1, 65, 1, 33, 3, 32, 0, 32, 2, 65, 24, 106, 16, 119, 13, 1, 11, 2, 64, 32, 1, 45, 0, 0, 65, 4, 113, 69, 13, 0, 65, 1, 33, 3, 32, 1, 65, 24, 106, 40, 2, 0, 65, 147, 238, 192, 0, 65, 1, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 13, 1, 11, 32, 1, 65, 24, 106, 40, 2, 0, 65, 154, 238, 192, 0, 65, 1, 32, 1, 65, 28, 106, 40, 2, 0, 40, 2, 12, 17, 1, 0, 33, 3, 11, 32, 2, 65, 208, 0, 106, 36, 0, 32, 3, 11, 151, 1, 1, 1, 127, 35, 0, 65, 192, 0, 107, 34, 1, 36, 0, 32, 1, 65, 24, 54, 2, 12, 32, 1, 65, 158, 253, 192, 0, 54, 2, 8, 32, 1, 32, 0, 58, 0, 23, 32, 1, 65, 48, 106, 65, 12, 106, 65, 15, 54, 2, 0, 32, 1, 65, 24, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 1, 65, 44, 106, 65, 2, 54, 2, 0, 32, 1, 65, 16, 54, 2, 52, 32, 1, 65, 176, 143, 193, 0, 54, 2, 24, 32, 1, 65, 2, 54, 2, 28, 32, 1, 65, 148, 251, 192, 0, 54, 2, 32, 32, 1, 32, 1, 65, 8, 106, 54, 2, 48, 32, 1, 32, 1, 65, 23, 106, 54, 2, 56, 32, 1, 32, 1, 65, 48, 106, 54, 2, 40, 32, 1, 65, 24, 106, 65, 192, 143, 193, 0, 16, 67, 0, 11, 151, 1, 1, 1, 127, 35, 0, 65, 192, 0, 107, 34, 1, 36, 0, 32, 1, 65, 43, 54, 2, 12, 32, 1, 65, 182, 253, 192, 0, 54, 2, 8, 32, 1, 32, 0, 54, 2, 20, 32, 1, 65, 48, 106, 65, 12, 106, 65, 17, 54, 2, 0, 32, 1, 65, 24, 106, 65, 12, 106, 65, 2, 54, 2, 0, 32, 1, 65, 44, 106, 65, 2, 54, 2, 0, 32, 1, 65, 16, 54, 2, 52, 32, 1, 65, 176, 143, 193, 0, 54, 2, 24, 32, 1, 65, 2, 54, 2, 28, 32, 1, 65, 148, 251, 192, 0, 54, 2, 32, 32, 1, 32, 1, 65, 8, 106, 54, 2, 48, 32, 1, 32, 1, 65, 20, 106, 54, 2, 56, 32, 1, 32, 1, 65, 48, 106, 54, 2, 40, 32, 1, 65, 24, 106, 65, 192, 143, 193, 0, 16, 67, 0, 11, 197, 2, 1, 3, 127, 35, 0, 65, 192, 0, 107, 34, 2, 36, 0, 2, 64, 2, 64, 2, 64, 32, 1, 40, 2, 8, 34, 3, 65, 32, 106, 34, 4, 32, 1, 40, 2, 4, 77, 13, 0, 32, 0, 65, 129, 6, 59, 1, 0, 12, 1, 11, 32, 1, 65, 8, 106, 32, 4, 54, 2, 0, 32, 3, 65, 96, 79, 13, 1, 32, 1, 40, 2, 0, 33, 1, 32, 2, 65, 56, 106, 66, 0, 55, 3, 0, 32, 2, 65, 48, 106, 66, 0, 55, 3, 0, 32, 2, 65, 32, 106, 65, 8, 106, 66, 0, 55, 3, 0, 32, 2, 66, 0, 55, 3, 32, 32, 1, 32, 3, 106, 33, 4, 65, 31, 33, 1, 32, 2, 65, 32, 106, 33, 3, 2, 64, 3, 64, 32, 1, 65, 127, 70, 13, 1, 32, 3, 32, 4, 32, 1, 106, 45, 0, 0, 58, 0, 0, 32, 1, 65, 127, 106, 33, 1, 32, 3, 65, 1, 106, 33, 3, 12, 0, 11, 11, 32, 2, 65, 24, 106, 34, 1, 32, 2, 65, 32, 106, 65, 24, 106, 41, 3, 0, 55, 3, 0, 32, 2, 65, 16, 106, 34, 3, 32, 2, 65, 32, 106, 65, 16, 106, 41, 3, 0, 55, 3, 0, 32, 2, 65, 8, 106, 34, 4, 32, 2, 65, 32, 106, 65, 8, 106, 41, 3, 0, 55, 3, 0, 32, 2, 32, 2, 41, 3, 32, 55, 3, 0, 32, 0, 65, 0, 58, 0, 0, 32, 0, 65, 32, 106, 32, 1, 41, 3, 0, 55, 3, 0, 32, 0, 65, 24, 106, 32, 3, 41, 3, 0, 55, 3, 0, 32, 0, 65, 16, 106, 32, 4, 41, 3, 0, 55, 3, 0, 32, 0, 65, 8, 106, 32, 2, 41, 3, 0, 55, 3, 0, 11, 32, 2, 65, 192, 0, 106, 36, 0, 15, 11, 32, 3, 32, 4, 16, 48, 0, 11, 237, 1, 3, 2, 126, 1, 127, 5, 126, 32, 1, 41, 3, 8, 34, 3, 32, 2, 41, 3, 8, 124, 34, 4, 32, 3, 84, 33, 5, 32, 2, 41, 3, 24, 33, 6, 32, 2, 41, 3, 16, 33, 7, 32, 1, 41, 3, 24, 33, 3, 32, 1, 41, 3, 16, 33, 8, 2, 64, 2, 64, 32, 1, 41, 3, 0, 34, 9, 32, 2, 41, 3, 0, 124, 34, 10, 32, 9, 90, 13, 0, 32, 4, 66, 1, 124, 34, 9, 32, 4, 84, 32, 5, 106, 33, 5, 12, 1, 11, 32, 4, 33, 9, 11, 32, 8, 32, 7, 124, 34, 4, 32, 8, 84, 33, 1, 2, 64, 2, 64, 32, 5, 69, 13, 0, 32, 4, 32, 5, 173, 124, 34, 7, 32, 4, 84, 32, 1, 106, 33, 1, 12, 1, 11, 32, 4, 33, 7, 11, 32, 3, 32, 6, 124, 34, 8, 32, 3, 84, 33, 2, 2, 64, 2, 64, 2, 64, 32, 1, 69, 13, 0, 32, 8, 32, 1, 173, 124, 34, 3, 32, 8, 84, 32, 2, 106, 13, 1, 12, 2, 11, 32, 8, 33, 3, 32, 2, 69, 13, 1, 11, 65, 208, 143, 193, 0, 16, 79, 0, 11, 32, 0, 32, 9, 55, 3, 8, 32, 0, 32, 10, 55, 3, 0, 32, 0, 32, 7, 55, 3, 16, 32, 0, 32, 3, 55, 3, 24, 11, 216, 6, 1, 11, 127, 35, 0, 65, 32, 107, 34, 1, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 16, 0, 34, 2, 69, 13, 0, 32, 1, 65, 8, 106, 32, 2, 16, 133, 1, 65, 0, 33, 3, 2, 64, 3, 64, 32, 3, 32, 2, 78, 13, 1, 32, 3, 65, 1, 106, 33, 3, 32, 1, 65, 8, 106, 16, 134, 1, 12, 0, 11, 11, 32, 1, 40, 2, 8, 34, 2, 16, 1, 2, 64, 32, 1, 40, 2, 16, 34, 4, 69, 13, 0, 32, 4, 65, 121, 106, 65, 0, 32, 4, 65, 7, 75, 27, 33, 5, 65, 0, 33, 3, 3, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 2, 32, 3, 106, 34, 6, 45, 0, 0, 34, 7, 65, 24, 116, 65, 24, 117, 34, 8, 65, 0, 72, 13, 0, 32, 6, 65, 3, 113, 69, 13, 1, 32, 3, 65, 1, 106, 33, 3, 12, 5, 11, 65, 1, 33, 9, 32, 7, 65, 221, 230, 192, 0, 106, 45, 0, 0, 34, 6, 65, 4, 70, 13, 2, 32, 6, 65, 3, 70, 13, 1, 32, 6, 65, 2, 71, 13, 9, 32, 3, 65, 1, 106, 34, 6, 32, 4, 79, 13, 11, 65, 128, 2, 33, 7, 65, 1, 33, 9, 32, 2, 32, 6, 106, 45, 0, 0, 65, 192, 1, 113, 65, 128, 1, 70, 13, 3, 12, 15, 11, 2, 64, 32, 3, 32, 5, 79, 13, 0, 3, 64, 32, 2, 32, 3, 106, 34, 6, 65, 4, 106, 40, 2, 0, 32, 6, 40, 2, 0, 114, 65, 128, 129, 130, 132, 120, 113, 13, 1, 32, 3, 65, 8, 106, 34, 3, 32, 5, 73, 13, 0, 11, 11, 32, 3, 32, 4, 79, 13, 3, 3, 64, 32, 2, 32, 3, 106, 44, 0, 0, 65, 0, 72, 13, 4, 32, 3, 65, 1, 106, 34, 3, 32, 4, 73, 13, 0, 12, 4, 11, 11, 65, 0, 33, 7, 32, 3, 65, 1, 106, 34, 6, 32, 4, 79, 13, 10, 32, 2, 32, 6, 106, 45, 0, 0, 33, 6, 2, 64, 2, 64, 32, 8, 65, 96, 71, 13, 0, 32, 6, 65, 96, 113, 65, 255, 1, 113, 65, 160, 1, 70, 13, 1, 11, 2, 64, 32, 6, 65, 255, 1, 113, 34, 10, 65, 191, 1, 75, 34, 11, 13, 0, 32, 8, 65, 31, 106, 65, 255, 1, 113, 65, 11, 75, 13, 0, 32, 6, 65, 24, 116, 65, 24, 117, 65, 0, 72, 13, 1, 11, 2, 64, 32, 10, 65, 159, 1, 75, 13, 0, 32, 8, 65, 109, 71, 13, 0, 32, 6, 65, 24, 116, 65, 24, 117, 65, 0, 72, 13, 1, 11, 32, 11, 13, 8, 32, 8, 65, 254, 1, 113, 65, 238, 1, 71, 13, 8, 32, 6, 65, 24, 116, 65, 24, 117, 65, 0, 78, 13, 8, 11, 65, 0, 33, 9, 32, 3, 65, 2, 106, 34, 6, 32, 4, 79, 13, 13, 32, 2, 32, 6, 106, 45, 0, 0, 65, 192, 1, 113, 65, 128, 1, 70, 13, 1, 12, 8, 11, 65, 0, 33, 7, 32, 3, 65, 1, 106, 34, 6, 32, 4, 79, 13, 9, 32, 2, 32, 6, 106, 45, 0, 0, 33, 6, 2, 64, 2, 64, 32, 8, 65, 112, 71, 13, 0, 32, 6, 65, 240, 0, 106, 65, 255, 1, 113, 65, 47, 77, 13, 1, 11, 2, 64, 32, 6, 65, 255, 1, 113, 34, 10, 65, 191, 1, 75, 13, 0, 32, 8, 65, 15, 106, 65, 255, 1, 113, 65, 2, 75, 13, 0, 32, 6, 65, 24, 116, 65, 24, 117, 65, 0, 72, 13, 1, 11, 32, 10, 65, 143, 1, 75, 13, 7, 32, 8, 65, 116, 71, 13, 7, 32, 6, 65, 24, 116, 65, 24, 117, 65, 0, 78, 13, 7, 11, 32, 3, 65, 2, 106, 34, 6, 32, 4, 79, 13, 9, 32, 2, 32, 6, 106, 45, 0, 0, 65, 192, 1, 113, 65, 128, 1, 71, 13, 7, 65, 0, 33, 9, 32, 3, 65, 3, 106, 34, 6, 32, 4, 79, 13, 12, 32, 2, 32, 6, 106, 45, 0, 0, 65, 192, 1, 113, 65, 128, 1, 71, 13, 10, 11, 32, 6, 65, 1, 106, 33, 3, 11, 32, 3, 32, 4, 73, 13, 0, 11, 11, 32, 0, 32, 2, 32, 4, 16, 72, 32, 1, 65, 8, 106, 16, 37, 12, 1, 11, 32, 0, 65, 140, 252, 192, 0, 65, 0, 16, 72, 11, 32, 1, 65, 32, 106, 36, 0, 15, 11, 65, 128, 2, 33, 7, 12, 5, 11, 65, 128, 4, 33, 7, 12, 3, 11, 65, 0, 33, 7, 11, 65, 0, 33, 9, 12, 2, 11, 65, 128, 6, 33, 7, 11, 65, 1, 33, 9, 11, 32, 1, 32, 3, 54, 2, 24, 32, 1, 32, 7, 32, 9, 114, 54, 2, 28, 32, 1, 65, 24, 106, 16, 90, 0, 11, 52, 2, 1, 127, 1, 126, 35, 0, 65, 16, 107, 34, 2, 36, 0, 32, 2, 65, 8, 106, 32, 1, 16, 33, 32, 2, 41, 3, 8, 33, 3, 32, 0, 65, 0, 54, 2, 8, 32, 0, 32, 3, 55, 2, 0, 32, 2, 65, 16, 106, 36, 0, 11, 69, 1, 1, 127, 2, 64, 32, 0, 40, 2, 8, 34, 1, 32, 0, 40, 2, 4, 71, 13, 0, 32, 0, 65, 1, 16, 41, 32, 0, 65, 8, 106, 40, 2, 0, 33, 1, 11, 32, 0, 40, 2, 0, 32, 1, 106, 65, 0, 58, 0, 0, 32, 0, 65, 8, 106, 34, 0, 32, 0, 40, 2, 0, 65, 1, 106, 54, 2, 0, 11, 120, 1, 3, 127, 35, 0, 65, 16, 107, 34, 1, 36, 0, 2, 64, 2, 64, 16, 2, 34, 2, 69, 13, 0, 32, 1, 32, 2, 16, 133, 1, 65, 0, 33, 3, 2, 64, 3, 64, 32, 3, 32, 2, 78, 13, 1, 32, 3, 65, 1, 106, 33, 3, 32, 1, 16, 134, 1, 12, 0, 11, 11, 32, 1, 40, 2, 0, 16, 3, 32, 0, 65, 8, 106, 32, 1, 65, 8, 106, 40, 2, 0, 54, 2, 0, 32, 0, 32, 1, 41, 3, 0, 55, 2, 0, 12, 1, 11, 32, 0, 65, 0, 54, 2, 8, 32, 0, 66, 1, 55, 2, 0, 11, 32, 1, 65, 16, 106, 36, 0, 11, 171, 12, 3, 4, 127, 2, 126, 3, 127, 35, 0, 65, 128, 2, 107, 34, 0, 36, 0, 32, 0, 65, 24, 106, 16, 132, 1, 32, 0, 40, 2, 32, 33, 1, 32, 0, 40, 2, 24, 33, 2, 32, 0, 65, 40, 106, 16, 135, 1, 2, 64, 2, 64, 2, 64, 2, 64, 32, 1, 65, 8, 71, 13, 0, 32, 0, 40, 2, 48, 33, 1, 32, 0, 40, 2, 40, 33, 3, 2, 64, 32, 2, 65, 140, 252, 192, 0, 70, 13, 0, 32, 2, 41, 0, 0, 66, 225, 200, 145, 203, 198, 174, 218, 183, 238, 0, 82, 13, 1, 11, 32, 0, 32, 1, 54, 2, 60, 32, 0, 32, 3, 54, 2, 56, 65, 0, 33, 1, 32, 0, 65, 0, 54, 2, 64, 32, 0, 65, 136, 1, 106, 32, 0, 65, 56, 106, 16, 130, 1, 32, 0, 65, 200, 0, 106, 32, 0, 65, 136, 1, 106, 16, 137, 1, 32, 0, 65, 136, 1, 106, 32, 0, 65, 56, 106, 16, 130, 1, 32, 0, 65, 232, 0, 106, 32, 0, 65, 136, 1, 106, 16, 137, 1, 32, 0, 65, 208, 1, 106, 65, 24, 106, 32, 0, 65, 200, 0, 106, 65, 24, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 208, 1, 106, 65, 16, 106, 32, 0, 65, 200, 0, 106, 65, 16, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 208, 1, 106, 65, 8, 106, 32, 0, 65, 200, 0, 106, 65, 8, 106, 41, 3, 0, 55, 3, 0, 32, 0, 32, 0, 41, 3, 72, 55, 3, 208, 1, 32, 0, 65, 136, 1, 106, 65, 24, 106, 32, 0, 65, 232, 0, 106, 65, 24, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 136, 1, 106, 65, 16, 106, 32, 0, 65, 232, 0, 106, 65, 16, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 136, 1, 106, 65, 8, 106, 32, 0, 65, 232, 0, 106, 65, 8, 106, 41, 3, 0, 55, 3, 0, 32, 0, 32, 0, 41, 3, 104, 55, 3, 136, 1, 32, 0, 65, 176, 1, 106, 32, 0, 65, 208, 1, 106, 32, 0, 65, 136, 1, 106, 16, 131, 1, 32, 0, 41, 3, 176, 1, 34, 4, 66, 128, 128, 128, 128, 16, 90, 13, 1, 32, 0, 65, 176, 1, 106, 65, 8, 106, 33, 2, 2, 64, 3, 64, 32, 1, 65, 1, 106, 34, 1, 65, 3, 75, 13, 1, 32, 2, 41, 3, 0, 33, 5, 32, 2, 65, 8, 106, 33, 2, 32, 5, 80, 13, 0, 11, 65, 232, 143, 193, 0, 16, 79, 0, 11, 32, 0, 65, 144, 1, 106, 66, 0, 55, 3, 0, 32, 0, 65, 152, 1, 106, 32, 4, 66, 255, 255, 255, 255, 15, 131, 55, 3, 0, 32, 0, 65, 2, 58, 0, 136, 1, 32, 0, 65, 16, 106, 65, 128, 1, 16, 33, 32, 0, 65, 0, 54, 2, 216, 1, 32, 0, 32, 0, 41, 3, 16, 55, 3, 208, 1, 32, 0, 32, 0, 65, 208, 1, 106, 54, 2, 248, 1, 32, 0, 32, 0, 65, 136, 1, 106, 32, 0, 65, 248, 1, 106, 16, 138, 1, 34, 2, 54, 2, 252, 1, 32, 2, 13, 2, 32, 0, 65, 252, 1, 106, 16, 139, 1, 32, 0, 65, 0, 54, 2, 244, 1, 32, 0, 65, 244, 1, 106, 16, 139, 1, 32, 0, 40, 2, 208, 1, 33, 2, 32, 0, 32, 0, 41, 2, 212, 1, 34, 5, 55, 2, 212, 1, 32, 0, 32, 2, 54, 2, 208, 1, 65, 148, 252, 192, 0, 65, 4, 32, 2, 32, 5, 66, 32, 136, 167, 16, 4, 32, 0, 65, 208, 1, 106, 16, 37, 2, 64, 32, 0, 45, 0, 136, 1, 34, 2, 65, 7, 113, 65, 3, 73, 13, 0, 2, 64, 2, 64, 32, 2, 65, 4, 70, 13, 0, 32, 2, 65, 3, 71, 13, 1, 32, 0, 65, 136, 1, 106, 65, 4, 114, 16, 37, 12, 2, 11, 32, 0, 65, 136, 1, 106, 65, 4, 114, 34, 2, 16, 56, 32, 2, 16, 58, 12, 1, 11, 32, 0, 65, 136, 1, 106, 65, 4, 114, 16, 59, 11, 32, 0, 65, 208, 1, 106, 65, 24, 106, 34, 2, 32, 0, 65, 200, 0, 106, 65, 24, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 208, 1, 106, 65, 16, 106, 32, 0, 65, 200, 0, 106, 65, 16, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 208, 1, 106, 65, 8, 106, 34, 1, 32, 0, 65, 200, 0, 106, 65, 8, 106, 41, 3, 0, 55, 3, 0, 32, 0, 32, 0, 41, 3, 72, 55, 3, 208, 1, 32, 0, 65, 136, 1, 106, 65, 24, 106, 34, 3, 32, 0, 65, 232, 0, 106, 65, 24, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 136, 1, 106, 65, 16, 106, 34, 6, 32, 0, 65, 232, 0, 106, 65, 16, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 136, 1, 106, 65, 8, 106, 34, 7, 32, 0, 65, 232, 0, 106, 65, 8, 106, 41, 3, 0, 55, 3, 0, 32, 0, 32, 0, 41, 3, 104, 55, 3, 136, 1, 32, 0, 65, 176, 1, 106, 32, 0, 65, 208, 1, 106, 32, 0, 65, 136, 1, 106, 16, 131, 1, 32, 0, 65, 232, 0, 106, 65, 32, 16, 133, 1, 65, 32, 16, 34, 34, 8, 69, 13, 3, 32, 1, 66, 32, 55, 3, 0, 32, 2, 65, 0, 54, 2, 0, 32, 0, 32, 8, 54, 2, 212, 1, 32, 0, 65, 32, 54, 2, 208, 1, 32, 0, 66, 1, 55, 3, 224, 1, 32, 3, 32, 0, 65, 176, 1, 106, 65, 24, 106, 41, 3, 0, 55, 3, 0, 32, 6, 32, 0, 65, 176, 1, 106, 65, 16, 106, 41, 3, 0, 55, 3, 0, 32, 7, 32, 0, 65, 176, 1, 106, 65, 8, 106, 41, 3, 0, 55, 3, 0, 32, 0, 32, 0, 41, 3, 176, 1, 55, 3, 136, 1, 32, 0, 65, 208, 1, 106, 65, 4, 114, 65, 32, 16, 44, 32, 0, 40, 2, 212, 1, 32, 0, 65, 220, 1, 106, 40, 2, 0, 34, 7, 106, 33, 6, 65, 0, 33, 2, 2, 64, 3, 64, 32, 6, 32, 2, 106, 33, 3, 32, 2, 65, 1, 106, 34, 1, 65, 31, 75, 13, 1, 32, 3, 65, 0, 58, 0, 0, 32, 1, 33, 2, 12, 0, 11, 11, 32, 0, 65, 220, 1, 106, 32, 7, 32, 2, 106, 65, 1, 106, 34, 2, 54, 2, 0, 65, 0, 33, 1, 32, 3, 65, 0, 58, 0, 0, 32, 0, 65, 8, 106, 65, 0, 65, 32, 32, 0, 40, 2, 212, 1, 32, 2, 16, 47, 32, 0, 65, 160, 1, 106, 33, 3, 32, 0, 40, 2, 12, 33, 7, 32, 0, 40, 2, 8, 33, 8, 65, 3, 33, 6, 65, 0, 33, 2, 2, 64, 2, 64, 2, 64, 3, 64, 32, 2, 65, 3, 75, 13, 1, 32, 0, 32, 1, 32, 7, 32, 8, 32, 7, 16, 47, 32, 6, 65, 3, 75, 13, 2, 32, 0, 40, 2, 4, 65, 7, 77, 13, 3, 32, 2, 65, 1, 106, 33, 2, 32, 0, 40, 2, 0, 32, 3, 41, 3, 0, 34, 5, 66, 56, 134, 32, 5, 66, 40, 134, 66, 128, 128, 128, 128, 128, 128, 192, 255, 0, 131, 132, 32, 5, 66, 24, 134, 66, 128, 128, 128, 128, 128, 224, 63, 131, 32, 5, 66, 8, 134, 66, 128, 128, 128, 128, 240, 31, 131, 132, 132, 32, 5, 66, 8, 136, 66, 128, 128, 128, 248, 15, 131, 32, 5, 66, 24, 136, 66, 128, 128, 252, 7, 131, 132, 32, 5, 66, 40, 136, 66, 128, 254, 3, 131, 32, 5, 66, 56, 136, 132, 132, 132, 55, 0, 0, 32, 1, 65, 8, 106, 33, 1, 32, 3, 65, 120, 106, 33, 3, 32, 6, 65, 127, 106, 33, 6, 12, 0, 11, 11, 32, 0, 65, 216, 1, 106, 40, 2, 0, 33, 3, 32, 0, 65, 228, 1, 106, 40, 2, 0, 33, 6, 32, 0, 65, 224, 1, 106, 40, 2, 0, 33, 2, 32, 0, 40, 2, 212, 1, 33, 1, 32, 0, 65, 232, 0, 106, 32, 0, 65, 232, 1, 106, 40, 2, 0, 34, 7, 32, 0, 65, 220, 1, 106, 40, 2, 0, 34, 8, 106, 16, 44, 32, 0, 65, 232, 0, 106, 32, 1, 32, 8, 16, 45, 32, 0, 65, 232, 0, 106, 32, 2, 32, 7, 16, 45, 32, 2, 32, 6, 16, 46, 32, 1, 32, 3, 16, 46, 32, 0, 40, 2, 104, 32, 0, 40, 2, 112, 16, 5, 32, 0, 65, 232, 0, 106, 16, 37, 32, 0, 65, 40, 106, 16, 37, 32, 0, 65, 24, 106, 16, 37, 32, 0, 65, 128, 2, 106, 36, 0, 15, 11, 65, 152, 254, 192, 0, 32, 6, 65, 4, 16, 116, 0, 11, 16, 52, 0, 11, 65, 128, 144, 193, 0, 16, 79, 0, 11, 65, 232, 143, 193, 0, 16, 79, 0, 11, 32, 0, 65, 208, 1, 106, 16, 140, 1, 32, 2, 16, 129, 1, 0, 11, 0, 0, 11, 86, 0, 2, 64, 32, 1, 45, 0, 0, 65, 1, 70, 13, 0, 32, 0, 65, 24, 106, 32, 1, 65, 32, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 16, 106, 32, 1, 65, 24, 106, 41, 3, 0, 55, 3, 0, 32, 0, 65, 8, 106, 32, 1, 65, 16, 106, 41, 3, 0, 55, 3, 0, 32, 0, 32, 1, 65, 8, 106, 41, 3, 0, 55, 3, 0, 15, 11, 32, 1, 45, 0, 1, 16, 128, 1, 0, 11, 249, 8, 3, 2, 127, 1, 124, 2, 127, 35, 0, 65, 128, 1, 107, 34, 2, 36, 0, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 2, 64, 32, 0, 45, 0, 0, 65, 127, 106, 34, 3, 65, 4, 75, 13, 0, 2, 64, 32, 3, 14, 5, 0, 2, 3, 4, 5, 0, 11, 32, 1, 40, 2, 0, 65, 225, 253, 192, 0, 65, 229, 253, 192, 0, 32, 0, 45, 0, 1, 34, 3, 27, 65, 4, 65, 5, 32, 3, 27, 16, 7, 32, 2, 65, 3, 58, 0, 72, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 3, 54, 2, 32, 32, 3, 13, 10, 32, 2, 65, 32, 106, 16, 139, 1, 12, 8, 11, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 142, 1, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 3, 54, 2, 32, 32, 3, 13, 9, 32, 2, 65, 32, 106, 16, 139, 1, 12, 7, 11, 32, 0, 65, 8, 106, 40, 2, 0, 34, 3, 65, 1, 70, 13, 3, 32, 3, 65, 2, 71, 13, 4, 32, 0, 65, 16, 106, 43, 3, 0, 34, 4, 16, 21, 65, 255, 1, 113, 65, 1, 75, 13, 5, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 142, 1, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 3, 54, 2, 32, 32, 3, 13, 8, 32, 2, 65, 32, 106, 16, 139, 1, 12, 6, 11, 32, 1, 32, 0, 65, 4, 106, 40, 2, 0, 32, 0, 65, 12, 106, 40, 2, 0, 16, 143, 1, 33, 3, 12, 7, 11, 32, 1, 32, 0, 65, 4, 106, 16, 23, 33, 3, 12, 6, 11, 2, 64, 32, 0, 65, 12, 106, 40, 2, 0, 69, 13, 0, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 144, 1, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 3, 54, 2, 32, 32, 3, 13, 6, 32, 2, 65, 32, 106, 16, 139, 1, 65, 1, 33, 5, 12, 5, 11, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 144, 1, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 3, 54, 2, 32, 32, 3, 13, 5, 32, 2, 65, 32, 106, 16, 139, 1, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 145, 1, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 3, 54, 2, 32, 32, 3, 13, 5, 32, 2, 65, 32, 106, 16, 139, 1, 65, 0, 33, 5, 12, 4, 11, 32, 2, 65, 16, 106, 32, 2, 65, 200, 0, 106, 32, 0, 65, 16, 106, 41, 3, 0, 16, 24, 32, 1, 40, 2, 0, 32, 2, 40, 2, 16, 32, 2, 40, 2, 20, 16, 7, 32, 2, 65, 3, 58, 0, 32, 32, 2, 65, 32, 106, 16, 146, 1, 32, 2, 65, 3, 58, 0, 72, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 3, 54, 2, 120, 32, 3, 13, 4, 32, 2, 65, 248, 0, 106, 16, 139, 1, 12, 2, 11, 32, 2, 65, 8, 106, 32, 2, 65, 200, 0, 106, 32, 0, 65, 16, 106, 41, 3, 0, 16, 25, 32, 1, 40, 2, 0, 32, 2, 40, 2, 8, 32, 2, 40, 2, 12, 16, 7, 32, 2, 65, 3, 58, 0, 32, 32, 2, 65, 32, 106, 16, 146, 1, 32, 2, 65, 3, 58, 0, 72, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 3, 54, 2, 120, 32, 3, 13, 3, 32, 2, 65, 248, 0, 106, 16, 139, 1, 12, 1, 11, 32, 4, 32, 2, 65, 200, 0, 106, 16, 26, 33, 3, 32, 1, 40, 2, 0, 32, 2, 65, 200, 0, 106, 32, 3, 16, 7, 32, 2, 65, 3, 58, 0, 32, 32, 2, 32, 2, 65, 32, 106, 16, 141, 1, 34, 3, 54, 2, 120, 32, 3, 13, 2, 32, 2, 65, 248, 0, 106, 16, 139, 1, 11, 65, 0, 33, 3, 12, 1, 11, 32, 2, 65, 32, 106, 32, 0, 65, 4, 106, 16, 27, 32, 2, 65, 200, 0, 106, 32, 2, 65, 32, 106, 65, 36, 16, 149, 1, 26, 2, 64, 3, 64, 32, 2, 65, 24, 106, 32, 2, 65, 200, 0, 106, 16, 28, 32, 2, 40, 2, 24, 34, 0, 69, 13, 1, 32, 2, 40, 2, 28, 33, 6, 2, 64, 32, 5, 65, 255, 1, 113, 65, 1, 70, 13, 0, 32, 1, 40, 2, 0, 65, 234, 253, 192, 0, 65, 1, 16, 7, 11, 32, 2, 65, 3, 58, 0, 120, 32, 2, 32, 2, 65, 248, 0, 106, 16, 141, 1, 34, 3, 54, 2, 116, 32, 3, 13, 2, 32, 2, 65, 244, 0, 106, 16, 139, 1, 32, 2, 32, 1, 32, 0, 40, 2, 0, 32, 0, 40, 2, 8, 16, 143, 1, 34, 3, 54, 2, 120, 32, 3, 13, 2, 32, 2, 65, 248, 0, 106, 16, 139, 1, 32, 2, 65, 3, 58, 0, 120, 32, 2, 32, 2, 65, 248, 0, 106, 16, 141, 1, 34, 3, 54, 2, 116, 32, 3, 13, 2, 32, 2, 65, 244, 0, 106, 16, 139, 1, 32, 2, 65, 0, 54, 2, 68, 32, 2, 65, 196, 0, 106, 16, 139, 1, 32, 1, 40, 2, 0, 65, 235, 253, 192, 0, 65, 1, 16, 7, 32, 2, 65, 3, 58, 0, 120, 32, 2, 32, 2, 65, 248, 0, 106, 16, 141, 1, 34, 3, 54, 2, 116, 32, 3, 13, 2, 32, 2, 65, 244, 0, 106, 16, 139, 1, 32, 2, 32, 6, 32, 1, 16, 138, 1, 34, 3, 54, 2, 120, 32, 3, 13, 2, 32, 2, 65, 248, 0, 106, 16, 139, 1, 32, 2, 65, 3, 58, 0, 120, 32, 2, 32, 2, 65, 248, 0, 106, 16, 141, 1, 34, 3, 54, 2, 116, 32, 3, 13, 2, 32, 2, 65, 244, 0, 106, 16, 139, 1, 32, 2, 65, 0, 54, 2, 68, 32, 2, 65, 196, 0, 106, 16, 139, 1, 65, 2, 33, 5, 12, 0, 11, 11, 65, 0, 33, 3, 32, 5, 65, 255, 1, 113, 69, 13, 0, 32, 2, 65, 200, 0, 106, 32, 1, 40, 2, 0, 16, 145, 1, 32, 2, 32, 2, 65, 200, 0, 106, 16, 141, 1, 34, 0, 54, 2, 32, 2, 64, 32, 0, 69, 13, 0, 32, 0, 33, 3, 12, 1, 11, 32, 2, 65, 32, 106, 16, 139, 1, 11, 32, 2, 65, 128, 1, 106, 36, 0, 32, 3, 11, 74, 1, 2, 127, 2, 64, 32, 0, 40, 2, 0, 34, 1, 69, 13, 0, 2, 64, 2, 64, 32, 1, 40, 2, 0, 34, 2, 65, 1, 70, 13, 0, 32, 2, 13, 1, 32, 1, 65, 8, 106, 40, 2, 0, 69, 13, 1, 32, 1, 40, 2, 4, 16, 31, 12, 1, 11, 32, 1, 65, 4, 106, 16, 147, 1, 11, 32, 0, 40, 2, 0, 16, 31, 11, 11, 6, 0, 32, 0, 16, 37, 11, 64, 1, 1, 127, 35, 0, 65, 16, 107, 34, 1, 36, 0, 2, 64, 32, 0, 45, 0, 0, 65, 3, 71, 13, 0, 32, 1, 65, 16, 106, 36, 0, 65, 0, 15, 11, 32, 1, 32, 0, 41, 2, 0, 55, 3, 8, 32, 1, 65, 8, 106, 16, 29, 33, 0, 32, 1, 65, 16, 106, 36, 0, 32, 0, 11, 16, 0, 32, 0, 32, 1, 65, 238, 253, 192, 0, 65, 4, 16, 148, 1, 11, 65, 1, 1, 127, 35, 0, 65, 16, 107, 34, 3, 36, 0, 32, 3, 65, 8, 106, 32, 0, 32, 1, 32, 2, 16, 6, 32, 3, 32, 3, 65, 8, 106, 16, 141, 1, 34, 0, 54, 2, 4, 2, 64, 32, 0, 13, 0, 32, 3, 65, 4, 106, 16, 139, 1, 11, 32, 3, 65, 16, 106, 36, 0, 32, 0, 11, 16, 0, 32, 0, 32, 1, 65, 237, 253, 192, 0, 65, 1, 16, 148, 1, 11, 16, 0, 32, 0, 32, 1, 65, 236, 253, 192, 0, 65, 1, 16, 148, 1, 11, 20, 0, 2, 64, 32, 0, 45, 0, 0, 65, 3, 70, 13, 0, 32, 0, 16, 147, 1, 11, 11, 71, 1, 1, 127, 2, 64, 32, 0, 45, 0, 0, 65, 2, 73, 13, 0, 32, 0, 65, 4, 106, 34, 1, 40, 2, 0, 34, 0, 40, 2, 0, 32, 0, 40, 2, 4, 40, 2, 0, 17, 0, 0, 2, 64, 32, 0, 40, 2, 4, 40, 2, 4, 69, 13, 0, 32, 0, 40, 2, 0, 16, 31, 11, 32, 1, 40, 2, 0, 16, 31, 11, 11, 17, 0, 32, 1, 32, 2, 32, 3, 16, 7, 32, 0, 65, 3, 58, 0, 0, 11, 54, 1, 1, 127, 2, 64, 32, 2, 69, 13, 0, 32, 0, 33, 3, 3, 64, 32, 3, 32, 1, 45, 0, 0, 58, 0, 0, 32, 1, 65, 1, 106, 33, 1, 32, 3, 65, 1, 106, 33, 3, 32, 2, 65, 127, 106, 34, 2, 13, 0, 11, 11, 32, 0, 11, 105, 1, 1, 127, 2, 64, 2, 64, 32, 1, 32, 0, 79, 13, 0, 32, 2, 69, 13, 1, 3, 64, 32, 0, 32, 2, 106, 65, 127, 106, 32, 1, 32, 2, 106, 65, 127, 106, 45, 0, 0, 58, 0, 0, 32, 2, 65, 127, 106, 34, 2, 13, 0, 12, 2, 11, 11, 32, 2, 69, 13, 0, 32, 0, 33, 3, 3, 64, 32, 3, 32, 1, 45, 0, 0, 58, 0, 0, 32, 1, 65, 1, 106, 33, 1, 32, 3, 65, 1, 106, 33, 3, 32, 2, 65, 127, 106, 34, 2, 13, 0, 11, 11, 32, 0, 11, 68, 1, 3, 127, 2, 64, 2, 64, 32, 2, 69, 13, 0, 65, 0, 33, 3, 3, 64, 32, 0, 32, 3, 106, 45, 0, 0, 34, 4, 32, 1, 32, 3, 106, 45, 0, 0, 34, 5, 71, 13, 2, 32, 3, 65, 1, 106, 34, 3, 32, 2, 73, 13, 0, 11, 65, 0, 15, 11, 65, 0, 15, 11, 32, 4, 32, 5, 107, 11, 60, 1, 1, 127, 35, 0, 65, 16, 107, 34, 5, 36, 0, 32, 5, 32, 1, 32, 2, 32, 3, 32, 4, 16, 153, 1, 32, 5, 41, 3, 0, 33, 1, 32, 0, 32, 5, 65, 8, 106, 41, 3, 0, 55, 3, 8, 32, 0, 32, 1, 55, 3, 0, 32, 5, 65, 16, 106, 36, 0, 11, 117, 1, 2, 126, 32, 0, 32, 3, 66, 32, 136, 34, 5, 32, 1, 66, 32, 136, 34, 6, 126, 32, 3, 32, 2, 126, 124, 32, 4, 32, 1, 126, 124, 32, 3, 66, 255, 255, 255, 255, 15, 131, 34, 3, 32, 1, 66, 255, 255, 255, 255, 15, 131, 34, 1, 126, 34, 4, 66, 32, 136, 32, 3, 32, 6, 126, 124, 34, 3, 66, 32, 136, 124, 32, 3, 66, 255, 255, 255, 255, 15, 131, 32, 5, 32, 1, 126, 124, 34, 3, 66, 32, 136, 124, 55, 3, 8, 32, 0, 32, 3, 66, 32, 134, 32, 4, 66, 255, 255, 255, 255, 15, 131, 132, 55, 3, 0, 11, 87, 1, 1, 126, 2, 64, 2, 64, 32, 3, 65, 192, 0, 113, 13, 0, 32, 3, 69, 13, 1, 32, 1, 32, 3, 65, 63, 113, 173, 34, 4, 136, 32, 2, 65, 0, 32, 3, 107, 65, 63, 113, 173, 134, 132, 33, 1, 32, 2, 32, 4, 136, 33, 2, 12, 1, 11, 32, 2, 32, 3, 65, 63, 113, 173, 136, 33, 1, 66, 0, 33, 2, 11, 32, 0, 32, 1, 55, 3, 0, 32, 0, 32, 2, 55, 3, 8, 11, 58, 1, 1, 127, 35, 0, 65, 16, 107, 34, 4, 36, 0, 32, 4, 32, 1, 32, 2, 32, 3, 16, 154, 1, 32, 4, 41, 3, 0, 33, 1, 32, 0, 32, 4, 65, 8, 106, 41, 3, 0, 55, 3, 8, 32, 0, 32, 1, 55, 3, 0, 32, 4, 65, 16, 106, 36, 0, 11, 11, 254, 147, 1, 3, 0, 65, 128, 128, 192, 0, 11, 242, 125, 47, 114, 111, 111, 116, 47, 46, 99, 97, 114, 103, 111, 47, 114, 101, 103, 105, 115, 116, 114, 121, 47, 115, 114, 99, 47, 103, 105, 116, 104, 117, 98, 46, 99, 111, 109, 45, 49, 101, 99, 99, 54, 50, 57, 57, 100, 98, 57, 101, 99, 56, 50, 51, 47, 115, 101, 114, 100, 101, 95, 106, 115, 111, 110, 45, 49, 46, 48, 46, 51, 56, 47, 115, 114, 99, 47, 115, 101, 114, 46, 114, 115, 34, 91, 92, 116, 92, 114, 92, 110, 92, 102, 92, 98, 92, 92, 92, 34, 93, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 47, 114, 111, 111, 116, 47, 46, 99, 97, 114, 103, 111, 47, 114, 101, 103, 105, 115, 116, 114, 121, 47, 115, 114, 99, 47, 103, 105, 116, 104, 117, 98, 46, 99, 111, 109, 45, 49, 101, 99, 99, 54, 50, 57, 57, 100, 98, 57, 101, 99, 56, 50, 51, 47, 117, 105, 110, 116, 45, 48, 46, 51, 46, 48, 47, 115, 114, 99, 47, 117, 105, 110, 116, 46, 114, 115, 47, 114, 111, 111, 116, 47, 46, 99, 97, 114, 103, 111, 47, 114, 101, 103, 105, 115, 116, 114, 121, 47, 115, 114, 99, 47, 103, 105, 116, 104, 117, 98, 46, 99, 111, 109, 45, 49, 101, 99, 99, 54, 50, 57, 57, 100, 98, 57, 101, 99, 56, 50, 51, 47, 98, 121, 116, 101, 111, 114, 100, 101, 114, 45, 49, 46, 51, 46, 49, 47, 115, 114, 99, 47, 108, 105, 98, 46, 114, 115, 73, 110, 118, 97, 108, 105, 100, 66, 111, 111, 108, 73, 110, 118, 97, 108, 105, 100, 85, 51, 50, 73, 110, 118, 97, 108, 105, 100, 85, 54, 52, 85, 110, 101, 120, 112, 101, 99, 116, 101, 100, 69, 111, 102, 73, 110, 118, 97, 108, 105, 100, 80, 97, 100, 100, 105, 110, 103, 79, 116, 104, 101, 114, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 97, 98, 99, 100, 101, 102, 117, 117, 117, 117, 117, 117, 117, 117, 98, 116, 110, 117, 102, 114, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 117, 0, 0, 34, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 92, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 97, 32, 68, 105, 115, 112, 108, 97, 121, 32, 105, 109, 112, 108, 101, 109, 101, 110, 116, 97, 116, 105, 111, 110, 32, 114, 101, 116, 117, 114, 110, 32, 97, 110, 32, 101, 114, 114, 111, 114, 32, 117, 110, 101, 120, 112, 101, 99, 116, 101, 100, 108, 121, 69, 79, 70, 32, 119, 104, 105, 108, 101, 32, 112, 97, 114, 115, 105, 110, 103, 32, 97, 32, 108, 105, 115, 116, 69, 79, 70, 32, 119, 104, 105, 108, 101, 32, 112, 97, 114, 115, 105, 110, 103, 32, 97, 110, 32, 111, 98, 106, 101, 99, 116, 69, 79, 70, 32, 119, 104, 105, 108, 101, 32, 112, 97, 114, 115, 105, 110, 103, 32, 97, 32, 115, 116, 114, 105, 110, 103, 69, 79, 70, 32, 119, 104, 105, 108, 101, 32, 112, 97, 114, 115, 105, 110, 103, 32, 97, 32, 118, 97, 108, 117, 101, 101, 120, 112, 101, 99, 116, 101, 100, 32, 96, 58, 96, 101, 120, 112, 101, 99, 116, 101, 100, 32, 96, 44, 96, 32, 111, 114, 32, 96, 93, 96, 101, 120, 112, 101, 99, 116, 101, 100, 32, 96, 44, 96, 32, 111, 114, 32, 96, 125, 96, 101, 120, 112, 101, 99, 116, 101, 100, 32, 96, 123, 96, 32, 111, 114, 32, 96, 91, 96, 101, 120, 112, 101, 99, 116, 101, 100, 32, 105, 100, 101, 110, 116, 101, 120, 112, 101, 99, 116, 101, 100, 32, 118, 97, 108, 117, 101, 101, 120, 112, 101, 99, 116, 101, 100, 32, 115, 116, 114, 105, 110, 103, 105, 110, 118, 97, 108, 105, 100, 32, 101, 115, 99, 97, 112, 101, 105, 110, 118, 97, 108, 105, 100, 32, 110, 117, 109, 98, 101, 114, 110, 117, 109, 98, 101, 114, 32, 111, 117, 116, 32, 111, 102, 32, 114, 97, 110, 103, 101, 105, 110, 118, 97, 108, 105, 100, 32, 117, 110, 105, 99, 111, 100, 101, 32, 99, 111, 100, 101, 32, 112, 111, 105, 110, 116, 99, 111, 110, 116, 114, 111, 108, 32, 99, 104, 97, 114, 97, 99, 116, 101, 114, 32, 40, 92, 117, 48, 48, 48, 48, 45, 92, 117, 48, 48, 49, 70, 41, 32, 102, 111, 117, 110, 100, 32, 119, 104, 105, 108, 101, 32, 112, 97, 114, 115, 105, 110, 103, 32, 97, 32, 115, 116, 114, 105, 110, 103, 107, 101, 121, 32, 109, 117, 115, 116, 32, 98, 101, 32, 97, 32, 115, 116, 114, 105, 110, 103, 108, 111, 110, 101, 32, 108, 101, 97, 100, 105, 110, 103, 32, 115, 117, 114, 114, 111, 103, 97, 116, 101, 32, 105, 110, 32, 104, 101, 120, 32, 101, 115, 99, 97, 112, 101, 116, 114, 97, 105, 108, 105, 110, 103, 32, 99, 111, 109, 109, 97, 116, 114, 97, 105, 108, 105, 110, 103, 32, 99, 104, 97, 114, 97, 99, 116, 101, 114, 115, 117, 110, 101, 120, 112, 101, 99, 116, 101, 100, 32, 101, 110, 100, 32, 111, 102, 32, 104, 101, 120, 32, 101, 115, 99, 97, 112, 101, 114, 101, 99, 117, 114, 115, 105, 111, 110, 32, 108, 105, 109, 105, 116, 32, 101, 120, 99, 101, 101, 100, 101, 100, 69, 114, 114, 111, 114, 40, 44, 32, 108, 105, 110, 101, 58, 32, 44, 32, 99, 111, 108, 117, 109, 110, 58, 32, 84, 114, 105, 101, 100, 32, 116, 111, 32, 115, 104, 114, 105, 110, 107, 32, 116, 111, 32, 97, 32, 108, 97, 114, 103, 101, 114, 32, 99, 97, 112, 97, 99, 105, 116, 121, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 52, 51, 51, 51, 51, 51, 51, 51, 51, 51, 51, 51, 51, 51, 51, 3, 195, 245, 40, 92, 143, 194, 245, 40, 92, 143, 194, 245, 40, 92, 143, 2, 156, 196, 32, 176, 114, 104, 145, 237, 124, 63, 53, 94, 186, 73, 12, 2, 147, 58, 1, 77, 132, 13, 79, 175, 148, 101, 136, 99, 93, 220, 70, 3, 118, 200, 205, 112, 3, 62, 63, 140, 16, 30, 109, 28, 177, 22, 159, 2, 197, 6, 11, 39, 105, 254, 152, 214, 166, 177, 189, 22, 244, 222, 24, 2, 110, 164, 17, 216, 65, 202, 244, 240, 10, 233, 149, 87, 83, 254, 90, 3, 241, 233, 218, 172, 52, 8, 247, 243, 59, 135, 17, 70, 220, 49, 175, 2, 193, 84, 226, 35, 42, 160, 197, 92, 150, 210, 218, 4, 125, 193, 37, 2, 53, 33, 106, 57, 16, 205, 213, 250, 86, 183, 247, 58, 251, 155, 111, 3, 94, 231, 84, 148, 166, 61, 222, 251, 171, 146, 44, 47, 252, 175, 191, 2, 24, 185, 16, 221, 30, 254, 228, 47, 35, 66, 189, 37, 48, 243, 50, 2, 191, 193, 26, 200, 151, 150, 161, 76, 56, 208, 46, 9, 77, 184, 132, 3, 51, 206, 123, 6, 19, 18, 78, 61, 96, 115, 37, 212, 112, 147, 208, 2, 41, 216, 47, 5, 220, 116, 62, 100, 179, 194, 234, 220, 243, 117, 64, 2, 167, 38, 230, 161, 249, 186, 48, 109, 133, 55, 17, 251, 82, 86, 154, 3, 32, 82, 235, 231, 250, 251, 38, 36, 209, 146, 218, 200, 168, 222, 225, 2, 128, 14, 137, 185, 200, 252, 235, 28, 116, 117, 72, 58, 186, 75, 78, 2, 204, 176, 65, 143, 167, 199, 172, 148, 134, 85, 218, 246, 41, 121, 176, 3, 215, 243, 154, 114, 236, 210, 35, 170, 107, 68, 72, 146, 33, 148, 243, 2, 121, 41, 175, 91, 240, 219, 79, 187, 239, 105, 211, 65, 129, 118, 92, 2, 141, 117, 75, 44, 26, 147, 76, 197, 178, 220, 235, 2, 2, 36, 199, 3, 11, 94, 60, 240, 20, 220, 214, 157, 40, 74, 86, 2, 104, 182, 5, 3, 214, 228, 201, 89, 170, 73, 18, 75, 237, 212, 17, 53, 83, 248, 106, 2, 137, 212, 15, 246, 118, 15, 234, 68, 21, 187, 79, 187, 30, 90, 222, 3, 7, 170, 12, 248, 43, 217, 84, 106, 119, 98, 217, 149, 24, 72, 24, 3, 210, 84, 61, 147, 137, 122, 221, 33, 249, 129, 71, 222, 70, 211, 121, 2, 80, 33, 98, 184, 117, 42, 47, 54, 40, 3, 12, 202, 215, 30, 246, 3, 13, 129, 78, 96, 145, 187, 37, 248, 236, 104, 214, 212, 223, 75, 43, 3, 11, 52, 165, 230, 13, 150, 132, 198, 240, 83, 120, 221, 127, 9, 137, 2, 60, 195, 29, 82, 62, 171, 3, 210, 243, 15, 45, 177, 204, 58, 7, 2, 44, 5, 150, 182, 99, 120, 159, 233, 82, 230, 20, 181, 122, 196, 62, 3, 87, 55, 171, 43, 182, 198, 178, 135, 117, 235, 67, 247, 46, 157, 152, 2, 69, 44, 188, 239, 196, 107, 245, 210, 42, 137, 105, 95, 242, 176, 19, 2, 162, 19, 45, 25, 59, 121, 85, 30, 222, 65, 15, 255, 182, 180, 82, 3, 232, 66, 87, 71, 47, 148, 119, 75, 75, 206, 165, 101, 146, 144, 168, 2, 186, 104, 223, 5, 89, 67, 249, 213, 213, 113, 81, 81, 168, 115, 32, 2, 41, 65, 50, 214, 244, 158, 91, 86, 137, 28, 79, 181, 115, 31, 103, 3, 33, 52, 40, 120, 93, 178, 175, 222, 109, 176, 165, 42, 246, 229, 184, 2, 129, 246, 236, 44, 177, 142, 140, 24, 139, 243, 234, 238, 196, 132, 45, 2, 155, 189, 20, 123, 27, 177, 173, 141, 222, 184, 68, 126, 161, 7, 124, 3, 124, 100, 221, 200, 226, 192, 87, 113, 24, 199, 3, 101, 180, 159, 201, 2, 202, 182, 74, 58, 130, 205, 223, 141, 19, 108, 105, 234, 41, 230, 58, 2, 66, 241, 221, 246, 105, 226, 50, 22, 236, 172, 168, 16, 67, 112, 145, 3, 53, 244, 23, 95, 238, 129, 245, 68, 35, 87, 237, 166, 53, 192, 218, 2, 196, 41, 19, 76, 88, 206, 42, 55, 28, 172, 138, 133, 196, 153, 72, 2, 211, 66, 184, 121, 192, 227, 170, 190, 198, 121, 119, 162, 109, 92, 167, 3, 118, 53, 96, 97, 0, 131, 85, 101, 5, 251, 197, 78, 241, 73, 236, 2, 43, 145, 230, 77, 0, 156, 119, 183, 106, 98, 158, 216, 141, 161, 86, 2, 18, 181, 61, 22, 154, 249, 88, 242, 221, 3, 151, 90, 73, 207, 189, 3, 65, 247, 202, 17, 72, 97, 122, 91, 126, 105, 18, 226, 109, 63, 254, 2, 1, 249, 59, 14, 160, 26, 149, 175, 254, 237, 65, 27, 139, 255, 100, 2, 155, 193, 44, 125, 102, 247, 84, 127, 151, 73, 54, 197, 17, 204, 212, 3, 227, 154, 240, 48, 133, 95, 170, 50, 121, 212, 145, 106, 65, 163, 16, 3, 130, 21, 90, 90, 55, 25, 85, 245, 96, 16, 219, 238, 205, 181, 115, 2, 157, 85, 195, 195, 139, 91, 187, 187, 52, 26, 248, 74, 22, 86, 236, 3, 74, 17, 105, 105, 9, 22, 201, 47, 42, 72, 147, 213, 17, 120, 35, 3, 60, 116, 186, 186, 58, 171, 109, 89, 187, 57, 220, 170, 116, 198, 130, 2, 48, 144, 251, 46, 98, 239, 138, 71, 252, 250, 124, 85, 93, 56, 2, 2, 230, 25, 44, 75, 208, 75, 222, 216, 198, 196, 148, 85, 149, 192, 54, 3, 184, 20, 240, 8, 13, 163, 126, 173, 56, 106, 221, 170, 170, 51, 146, 2, 147, 16, 192, 160, 61, 79, 101, 36, 250, 84, 228, 187, 187, 143, 14, 2, 235, 128, 102, 52, 252, 177, 59, 58, 144, 33, 58, 198, 146, 127, 74, 3, 137, 205, 30, 93, 99, 142, 252, 148, 166, 231, 148, 158, 168, 255, 161, 2, 212, 10, 127, 74, 28, 165, 99, 170, 235, 82, 170, 75, 237, 50, 27, 2, 237, 170, 49, 119, 96, 59, 108, 221, 69, 30, 170, 18, 226, 183, 94, 3, 189, 136, 244, 248, 25, 201, 137, 23, 107, 75, 187, 219, 180, 44, 178, 2, 100, 109, 144, 45, 123, 58, 110, 172, 85, 60, 252, 226, 195, 35, 40, 2, 58, 226, 179, 21, 197, 144, 227, 19, 137, 96, 96, 158, 108, 108, 115, 3, 98, 27, 195, 119, 106, 13, 182, 220, 160, 179, 230, 177, 35, 189, 194, 2, 181, 226, 104, 249, 33, 113, 94, 125, 77, 41, 82, 142, 28, 100, 53, 2, 135, 55, 14, 143, 105, 27, 151, 200, 72, 117, 131, 176, 45, 160, 136, 3, 108, 44, 216, 216, 186, 226, 120, 160, 160, 42, 105, 192, 87, 179, 211, 2, 36, 189, 121, 173, 200, 27, 199, 230, 230, 238, 32, 205, 223, 245, 66, 2, 57, 200, 194, 72, 116, 44, 216, 10, 11, 75, 206, 225, 50, 86, 158, 3, 250, 108, 53, 58, 144, 35, 224, 59, 111, 162, 62, 78, 194, 17, 229, 2, 149, 189, 42, 200, 217, 130, 230, 47, 140, 27, 50, 216, 1, 219, 80, 2, 238, 200, 170, 166, 143, 4, 164, 76, 224, 248, 28, 141, 156, 145, 180, 3, 37, 7, 239, 30, 166, 3, 80, 61, 128, 45, 23, 164, 227, 218, 246, 2, 81, 159, 37, 127, 30, 54, 115, 151, 102, 36, 172, 233, 130, 21, 95, 2, 232, 254, 8, 101, 202, 137, 235, 139, 10, 7, 173, 66, 158, 85, 203, 3, 134, 101, 58, 183, 110, 161, 239, 111, 8, 108, 138, 104, 75, 17, 9, 3, 107, 132, 251, 248, 190, 26, 38, 243, 57, 35, 213, 134, 111, 167, 109, 2, 69, 58, 95, 142, 49, 145, 214, 81, 246, 209, 33, 62, 127, 165, 226, 3, 55, 200, 229, 113, 244, 64, 69, 14, 197, 116, 129, 254, 152, 183, 27, 3, 96, 211, 183, 244, 41, 103, 55, 216, 208, 195, 154, 203, 224, 146, 124, 2, 255, 30, 38, 33, 67, 216, 139, 243, 26, 6, 94, 223, 154, 132, 250, 3, 255, 75, 235, 128, 2, 173, 60, 41, 175, 209, 228, 229, 123, 208, 46, 3, 204, 60, 188, 0, 2, 36, 202, 237, 88, 65, 234, 183, 252, 217, 139, 2, 164, 48, 48, 154, 1, 80, 59, 190, 224, 205, 33, 147, 48, 123, 9, 2, 159, 26, 77, 144, 2, 128, 248, 201, 154, 124, 156, 30, 180, 94, 66, 3, 230, 174, 61, 64, 53, 51, 45, 59, 226, 150, 227, 126, 246, 126, 155, 2, 184, 88, 49, 0, 145, 194, 189, 149, 78, 18, 182, 152, 43, 255, 21, 2, 38, 193, 27, 205, 180, 157, 47, 137, 74, 29, 240, 141, 18, 101, 86, 3, 133, 154, 124, 10, 247, 74, 89, 7, 162, 74, 243, 215, 14, 132, 171, 2, 209, 174, 99, 8, 44, 111, 71, 108, 78, 213, 245, 223, 11, 208, 34, 2, 180, 23, 57, 218, 172, 126, 165, 19, 23, 34, 86, 102, 121, 230, 106, 3, 144, 172, 45, 72, 138, 152, 183, 15, 172, 129, 222, 81, 148, 235, 187, 2, 218, 86, 241, 108, 59, 173, 95, 217, 188, 103, 24, 219, 169, 239, 47, 2, 92, 241, 78, 174, 248, 225, 101, 245, 250, 165, 192, 145, 220, 229, 127, 3, 176, 90, 114, 139, 96, 78, 30, 145, 149, 81, 205, 167, 227, 183, 204, 2, 141, 72, 40, 9, 26, 165, 126, 218, 170, 167, 10, 83, 233, 95, 61, 2, 21, 116, 64, 168, 41, 8, 49, 247, 170, 114, 119, 30, 66, 102, 149, 3, 222, 92, 0, 237, 186, 57, 39, 44, 239, 142, 95, 24, 104, 235, 221, 2, 75, 74, 0, 36, 47, 46, 236, 188, 37, 63, 25, 173, 185, 34, 75, 2, 17, 170, 51, 211, 177, 22, 173, 148, 111, 203, 142, 174, 194, 106, 171, 3, 219, 84, 41, 220, 39, 18, 36, 170, 191, 162, 216, 190, 155, 136, 239, 2, 226, 67, 84, 227, 31, 168, 233, 84, 153, 232, 70, 50, 22, 58, 89, 2, 106, 57, 237, 158, 204, 217, 117, 33, 194, 13, 11, 234, 137, 246, 193, 3, 136, 199, 189, 24, 10, 123, 145, 231, 52, 62, 111, 238, 7, 146, 1, 3, 58, 57, 254, 70, 59, 47, 65, 185, 144, 254, 88, 88, 6, 168, 103, 2, 92, 40, 253, 215, 94, 24, 53, 245, 77, 151, 193, 243, 214, 12, 217, 3, 125, 83, 151, 121, 229, 121, 42, 196, 164, 18, 206, 143, 69, 10, 20, 3, 49, 169, 18, 46, 81, 46, 85, 3, 183, 219, 164, 12, 158, 110, 118, 2, 180, 14, 81, 227, 129, 176, 238, 158, 241, 197, 7, 225, 252, 176, 240, 3, 195, 11, 116, 79, 206, 38, 242, 75, 193, 4, 211, 128, 253, 243, 38, 3, 156, 60, 195, 114, 11, 31, 40, 163, 154, 208, 168, 0, 254, 143, 133, 2, 74, 253, 104, 245, 213, 24, 32, 28, 226, 166, 32, 154, 49, 115, 4, 2, 169, 251, 167, 136, 137, 244, 204, 249, 156, 164, 154, 246, 232, 81, 58, 3, 33, 150, 185, 211, 58, 93, 10, 251, 227, 182, 123, 248, 83, 14, 149, 2, 231, 68, 97, 169, 200, 125, 59, 47, 131, 197, 47, 45, 67, 216, 16, 2, 12, 59, 53, 66, 116, 252, 43, 229, 209, 8, 230, 225, 209, 38, 78, 3, 112, 98, 247, 52, 144, 99, 86, 183, 116, 109, 30, 27, 219, 235, 164, 2, 38, 181, 146, 93, 115, 28, 69, 44, 42, 241, 177, 21, 124, 137, 29, 2, 163, 238, 29, 252, 30, 199, 161, 19, 221, 129, 233, 85, 147, 117, 98, 3, 80, 37, 75, 99, 178, 5, 27, 118, 74, 206, 186, 68, 220, 42, 181, 2, 166, 234, 8, 233, 193, 55, 175, 145, 59, 216, 251, 54, 176, 136, 42, 2, 112, 119, 65, 219, 207, 242, 177, 130, 146, 243, 146, 241, 25, 65, 119, 3, 243, 197, 154, 226, 63, 194, 244, 206, 14, 246, 219, 90, 174, 205, 197, 2, 144, 209, 123, 232, 255, 52, 42, 63, 114, 94, 22, 175, 190, 215, 55, 2, 178, 181, 95, 218, 255, 135, 67, 152, 131, 253, 86, 75, 100, 140, 140, 3, 91, 145, 76, 72, 102, 6, 54, 224, 2, 254, 171, 162, 182, 214, 214, 2, 73, 116, 112, 211, 81, 56, 43, 128, 53, 203, 188, 27, 146, 120, 69, 2, 117, 32, 231, 235, 130, 192, 222, 153, 85, 120, 148, 95, 131, 90, 162, 3, 145, 179, 133, 137, 53, 205, 75, 174, 119, 147, 67, 25, 105, 72, 232, 2, 116, 92, 209, 58, 145, 10, 163, 190, 95, 220, 2, 225, 32, 109, 83, 2, 185, 96, 181, 247, 129, 170, 209, 253, 101, 45, 158, 1, 155, 174, 184, 3, 97, 77, 196, 95, 206, 238, 218, 151, 81, 36, 24, 206, 72, 37, 250, 2, 129, 215, 105, 25, 165, 88, 226, 223, 218, 233, 172, 113, 109, 183, 97, 2, 52, 191, 15, 143, 110, 39, 106, 153, 196, 15, 123, 79, 226, 139, 207, 3, 42, 204, 63, 63, 37, 185, 33, 225, 54, 166, 149, 63, 232, 111, 12, 3, 34, 112, 153, 50, 132, 250, 26, 180, 248, 132, 68, 153, 134, 89, 112, 2, 207, 25, 143, 234, 57, 247, 247, 236, 141, 161, 109, 40, 164, 245, 230, 3, 64, 174, 165, 187, 148, 146, 249, 35, 11, 78, 241, 185, 233, 42, 31, 3, 153, 190, 183, 47, 170, 219, 250, 79, 111, 62, 244, 199, 135, 85, 127, 2, 194, 253, 37, 25, 221, 197, 247, 127, 24, 151, 83, 166, 63, 239, 254, 3, 155, 100, 30, 20, 228, 55, 198, 204, 70, 223, 66, 184, 255, 88, 50, 3, 175, 131, 75, 67, 131, 249, 4, 215, 107, 127, 53, 96, 153, 173, 142, 2, 140, 156, 111, 207, 53, 97, 106, 18, 35, 153, 247, 76, 20, 190, 11, 2, 20, 148, 178, 24, 86, 104, 221, 131, 158, 142, 242, 71, 237, 252, 69, 3, 221, 220, 142, 224, 68, 32, 177, 156, 75, 165, 91, 6, 241, 99, 158, 2, 125, 125, 165, 179, 208, 25, 244, 22, 214, 29, 22, 5, 244, 79, 24, 2, 251, 251, 59, 236, 77, 41, 32, 139, 86, 201, 137, 110, 134, 25, 90, 3, 150, 201, 252, 188, 164, 186, 25, 60, 69, 212, 7, 242, 209, 122, 174, 2, 223, 58, 202, 48, 234, 46, 174, 201, 157, 118, 57, 91, 14, 47, 37, 2, 253, 42, 221, 26, 221, 23, 125, 15, 150, 138, 245, 145, 176, 177, 110, 3, 100, 34, 228, 123, 74, 70, 151, 63, 171, 59, 145, 65, 141, 244, 190, 2, 80, 232, 28, 99, 8, 5, 121, 204, 85, 201, 13, 206, 61, 93, 50, 2, 77, 13, 251, 4, 167, 161, 193, 224, 34, 66, 73, 227, 98, 200, 131, 3, 62, 164, 149, 157, 133, 180, 103, 77, 130, 206, 109, 79, 130, 211, 207, 2, 203, 233, 170, 23, 158, 195, 31, 113, 155, 11, 139, 63, 104, 220, 63, 2, 69, 169, 68, 140, 150, 210, 50, 232, 43, 172, 17, 204, 166, 96, 153, 3, 158, 186, 3, 61, 69, 117, 245, 236, 239, 188, 167, 9, 31, 26, 225, 2, 177, 251, 2, 100, 55, 196, 42, 87, 38, 151, 236, 58, 127, 174, 77, 2, 181, 146, 209, 108, 37, 109, 68, 88, 61, 88, 71, 94, 152, 125, 175, 3, 196, 219, 218, 35, 81, 87, 208, 121, 151, 70, 108, 75, 224, 202, 242, 2, 3, 227, 123, 233, 64, 172, 166, 148, 223, 158, 86, 60, 128, 213, 91, 2, 108, 158, 44, 15, 155, 19, 113, 135, 50, 254, 240, 198, 102, 34, 198, 3, 189, 126, 240, 216, 72, 169, 141, 159, 91, 203, 192, 56, 82, 232, 4, 3, 100, 101, 192, 224, 211, 237, 10, 230, 226, 213, 51, 250, 116, 83, 106, 2, 210, 59, 154, 103, 185, 175, 68, 163, 4, 35, 134, 195, 84, 82, 221, 3, 168, 252, 20, 134, 199, 191, 3, 233, 54, 79, 107, 156, 16, 117, 23, 3, 32, 202, 16, 56, 57, 102, 105, 186, 248, 216, 85, 176, 115, 42, 121, 2, 154, 118, 180, 89, 40, 61, 66, 42, 193, 244, 34, 26, 185, 16, 245, 3, 21, 146, 195, 71, 32, 100, 155, 238, 0, 247, 27, 72, 199, 115, 42, 3, 170, 65, 105, 57, 128, 182, 226, 190, 0, 44, 227, 108, 159, 92, 136, 2, 85, 1, 33, 97, 51, 197, 27, 255, 102, 86, 79, 138, 127, 176, 6, 2, 187, 155, 1, 53, 82, 8, 198, 49, 11, 87, 229, 118, 50, 231, 61, 3, 99, 73, 1, 196, 65, 160, 209, 39, 60, 223, 29, 95, 40, 236, 151, 2, 130, 7, 1, 208, 103, 179, 167, 236, 252, 24, 75, 127, 83, 35, 19, 2, 157, 165, 1, 128, 12, 31, 217, 173, 148, 193, 17, 50, 31, 210, 81, 3, 126, 132, 52, 51, 61, 127, 122, 241, 118, 52, 14, 40, 76, 219, 167, 2, 152, 3, 42, 92, 151, 50, 149, 39, 95, 144, 62, 83, 163, 226, 31, 2, 38, 108, 118, 147, 88, 183, 238, 216, 49, 26, 100, 184, 107, 55, 102, 3, 82, 35, 197, 66, 173, 146, 88, 122, 193, 225, 28, 45, 86, 44, 184, 2, 117, 79, 55, 2, 241, 14, 122, 251, 205, 231, 227, 240, 68, 240, 44, 2, 84, 178, 139, 3, 232, 23, 144, 197, 124, 12, 211, 231, 7, 26, 123, 3, 170, 142, 60, 105, 134, 121, 166, 55, 202, 214, 168, 236, 159, 225, 200, 2, 187, 11, 202, 237, 209, 250, 81, 249, 212, 171, 32, 138, 25, 78, 58, 2, 43, 121, 118, 124, 233, 42, 131, 40, 187, 223, 205, 169, 245, 124, 144, 3, 86, 199, 94, 48, 33, 239, 104, 32, 252, 178, 164, 84, 145, 253, 217, 2, 120, 159, 24, 141, 26, 140, 237, 25, 48, 143, 80, 221, 13, 254, 71, 2, 38, 255, 192, 225, 144, 70, 175, 92, 179, 126, 26, 98, 73, 99, 166, 3, 133, 50, 103, 129, 13, 210, 37, 74, 92, 101, 72, 27, 161, 130, 235, 2, 55, 245, 184, 154, 215, 116, 81, 59, 176, 234, 57, 124, 26, 2, 86, 2, 241, 33, 91, 196, 37, 238, 27, 146, 179, 170, 92, 96, 42, 208, 188, 3, 142, 129, 226, 105, 81, 139, 73, 219, 194, 187, 227, 25, 85, 115, 253, 2, 114, 52, 181, 84, 116, 111, 212, 21, 207, 47, 182, 20, 68, 92, 100, 2, 182, 32, 85, 84, 237, 75, 186, 239, 228, 178, 86, 84, 211, 198, 211, 3, 43, 26, 68, 16, 241, 111, 251, 242, 131, 245, 222, 169, 66, 210, 15, 3, 239, 20, 208, 217, 192, 140, 47, 143, 105, 196, 24, 187, 155, 14, 115, 2, 127, 33, 128, 92, 1, 174, 229, 177, 117, 109, 244, 196, 146, 74, 235, 3, 204, 26, 0, 74, 52, 139, 132, 193, 247, 189, 246, 3, 15, 162, 34, 3, 163, 72, 51, 59, 144, 162, 3, 206, 95, 254, 94, 54, 63, 27, 130, 2, 79, 109, 143, 98, 115, 232, 2, 216, 127, 203, 24, 197, 101, 175, 1, 2, 127, 72, 178, 157, 184, 64, 158, 89, 153, 69, 193, 161, 111, 229, 53, 3, 153, 211, 193, 23, 250, 102, 75, 225, 173, 55, 52, 78, 89, 132, 145, 2, 122, 220, 103, 121, 46, 31, 9, 129, 241, 146, 246, 164, 122, 3, 14, 2, 246, 147, 12, 143, 125, 203, 65, 155, 181, 132, 138, 161, 42, 159, 73, 3, 248, 15, 10, 12, 254, 213, 103, 175, 247, 54, 213, 26, 34, 76, 161, 2, 199, 12, 8, 112, 254, 119, 185, 242, 146, 197, 221, 123, 78, 163, 26, 2, 11, 174, 217, 76, 202, 140, 245, 29, 235, 8, 150, 44, 23, 210, 93, 3, 60, 139, 20, 215, 161, 112, 196, 228, 136, 109, 222, 86, 223, 116, 177, 2, 202, 213, 118, 18, 27, 90, 208, 131, 109, 36, 229, 171, 178, 144, 39, 2, 15, 86, 241, 131, 94, 195, 179, 159, 21, 7, 213, 223, 29, 129, 114, 3, 63, 171, 90, 54, 229, 53, 246, 178, 119, 210, 16, 179, 228, 0, 194, 2, 102, 239, 174, 94, 183, 196, 145, 245, 146, 219, 115, 194, 131, 205, 52, 2, 163, 24, 75, 100, 37, 161, 79, 239, 183, 197, 31, 55, 57, 175, 135, 3, 79, 173, 213, 233, 29, 180, 63, 140, 249, 106, 25, 44, 148, 242, 210, 2, 217, 189, 119, 33, 75, 195, 255, 60, 97, 34, 225, 188, 169, 91, 66, 2, 40, 150, 191, 104, 171, 107, 204, 148, 155, 208, 1, 251, 117, 95, 157, 3, 185, 17, 102, 237, 85, 137, 163, 16, 22, 218, 103, 98, 94, 76, 228, 2, 251, 218, 132, 87, 222, 109, 28, 218, 68, 123, 185, 78, 24, 61, 80, 2, 145, 145, 212, 88, 253, 226, 147, 246, 58, 197, 40, 177, 243, 148, 179, 3, 14, 14, 170, 224, 253, 27, 67, 197, 200, 157, 32, 244, 194, 16, 246, 2, 114, 62, 187, 179, 100, 22, 156, 106, 109, 177, 179, 41, 207, 115, 94, 2, 79, 202, 94, 236, 109, 189, 249, 16, 175, 232, 133, 15, 229, 82, 202, 3, 12, 213, 75, 240, 87, 100, 97, 218, 88, 237, 55, 217, 80, 66, 8, 3, 61, 119, 9, 141, 121, 131, 231, 225, 19, 241, 95, 71, 218, 1, 109, 2, 46, 37, 15, 72, 143, 5, 12, 3, 83, 27, 51, 114, 144, 156, 225, 3, 37, 132, 114, 6, 217, 106, 214, 104, 66, 124, 194, 193, 166, 227, 26, 3, 183, 105, 40, 5, 20, 239, 17, 135, 155, 150, 155, 52, 82, 233, 123, 2, 242, 117, 13, 213, 236, 228, 79, 11, 44, 36, 44, 84, 80, 117, 249, 3, 245, 247, 61, 119, 189, 80, 166, 162, 137, 182, 137, 118, 115, 247, 45, 3, 42, 147, 49, 44, 49, 218, 81, 181, 7, 146, 212, 94, 92, 44, 139, 2, 34, 220, 90, 35, 244, 20, 219, 93, 57, 219, 118, 229, 73, 240, 8, 2, 105, 147, 196, 107, 83, 238, 196, 47, 194, 94, 241, 59, 118, 128, 65, 3, 33, 169, 3, 35, 169, 190, 208, 191, 1, 127, 39, 99, 43, 205, 154, 2, 26, 84, 105, 130, 186, 203, 115, 153, 52, 255, 133, 130, 239, 112, 21, 2, 42, 32, 66, 106, 42, 121, 236, 91, 135, 203, 60, 55, 127, 129, 85, 3, 239, 76, 155, 238, 33, 148, 35, 227, 5, 214, 99, 95, 255, 205, 170, 2, 89, 10, 73, 37, 27, 16, 182, 181, 4, 120, 233, 229, 101, 62, 34, 2, 39, 170, 65, 213, 145, 230, 188, 34, 161, 89, 66, 214, 111, 253, 105, 3, 185, 33, 206, 221, 167, 235, 99, 181, 77, 225, 1, 69, 38, 49, 187, 2, 148, 180, 113, 177, 236, 34, 131, 247, 10, 129, 1, 4, 133, 90, 47, 2, 83, 135, 79, 130, 71, 158, 158, 37, 171, 1, 156, 57, 59, 247, 126, 3, 169, 210, 114, 155, 159, 126, 24, 30, 188, 103, 22, 46, 252, 248, 203, 2, 33, 66, 194, 226, 178, 203, 70, 75, 99, 185, 222, 36, 48, 199, 60, 2, 1, 157, 3, 158, 132, 223, 10, 18, 210, 91, 100, 161, 230, 113, 148, 3, 154, 125, 156, 177, 3, 230, 59, 219, 116, 73, 80, 180, 235, 39, 221, 2, 124, 100, 176, 39, 54, 235, 47, 124, 93, 212, 217, 41, 86, 134, 74, 2, 44, 7, 231, 165, 86, 120, 25, 45, 47, 186, 143, 220, 137, 112, 170, 3, 189, 5, 236, 183, 171, 198, 122, 138, 242, 148, 12, 74, 110, 192, 238, 2, 100, 209, 188, 44, 86, 5, 47, 213, 142, 221, 214, 212, 241, 153, 88, 2, 160, 232, 250, 173, 86, 213, 228, 33, 126, 98, 241, 186, 79, 246, 192, 3, 77, 237, 251, 87, 69, 68, 234, 231, 100, 232, 141, 149, 12, 197, 0, 3, 10, 241, 47, 19, 209, 105, 187, 236, 131, 83, 62, 17, 10, 4, 103, 2, 170, 129, 25, 133, 78, 169, 248, 173, 108, 82, 253, 129, 118, 6, 216, 3, 136, 52, 225, 208, 62, 84, 45, 139, 240, 65, 100, 206, 94, 56, 19, 3, 211, 246, 128, 13, 255, 220, 189, 213, 38, 155, 182, 62, 178, 198, 117, 2, 235, 138, 1, 124, 254, 199, 47, 137, 164, 94, 87, 100, 80, 164, 239, 3, 137, 213, 154, 201, 254, 159, 140, 58, 29, 178, 18, 29, 13, 29, 38, 3, 59, 17, 175, 7, 255, 127, 112, 200, 125, 142, 168, 125, 10, 228, 132, 2, 47, 116, 242, 210, 152, 153, 243, 57, 254, 113, 32, 254, 161, 233, 3, 2, 75, 32, 183, 132, 244, 40, 236, 143, 99, 182, 0, 253, 207, 117, 57, 3, 162, 230, 248, 54, 93, 186, 137, 217, 130, 43, 154, 253, 63, 94, 148, 2, 28, 82, 250, 43, 228, 97, 161, 71, 2, 86, 123, 100, 102, 75, 16, 2, 249, 28, 42, 19, 109, 105, 53, 12, 106, 86, 197, 160, 112, 69, 77, 3, 45, 23, 136, 66, 87, 84, 196, 9, 136, 171, 106, 77, 141, 55, 164, 2, 139, 18, 160, 155, 18, 221, 105, 161, 57, 137, 136, 215, 61, 249, 28, 2, 171, 29, 0, 249, 80, 251, 66, 2, 41, 117, 218, 88, 201, 142, 97, 3, 188, 23, 0, 148, 13, 201, 104, 155, 237, 144, 123, 173, 58, 114, 180, 2, 150, 172, 153, 169, 215, 160, 32, 73, 241, 115, 252, 189, 251, 244, 41, 2, 87, 71, 92, 15, 89, 1, 1, 117, 232, 31, 199, 252, 197, 84, 118, 3, 223, 5, 125, 63, 71, 52, 103, 42, 237, 127, 210, 99, 158, 16, 197, 2, 76, 158, 253, 101, 159, 246, 184, 238, 240, 255, 65, 182, 126, 64, 55, 2, 70, 253, 200, 60, 50, 36, 91, 228, 231, 255, 207, 86, 100, 154, 139, 3, 159, 202, 160, 48, 40, 80, 175, 182, 236, 255, 63, 18, 29, 21, 214, 2, 127, 8, 231, 38, 32, 64, 140, 248, 86, 102, 102, 219, 176, 221, 68, 2, 254, 115, 62, 62, 0, 205, 70, 39, 139, 112, 61, 146, 180, 98, 161, 3, 50, 195, 254, 100, 51, 215, 107, 31, 60, 141, 151, 14, 42, 130, 231, 2, 91, 207, 203, 80, 92, 223, 239, 229, 252, 112, 172, 11, 136, 206, 82, 2, 43, 178, 223, 26, 250, 254, 178, 60, 46, 27, 71, 172, 217, 176, 183, 3, 86, 91, 25, 175, 97, 50, 143, 48, 88, 175, 5, 189, 71, 90, 249, 2, 171, 226, 173, 37, 78, 40, 12, 90, 19, 89, 209, 48, 6, 21, 97, 2, 69, 4, 227, 213, 73, 13, 173, 41, 82, 91, 181, 231, 9, 136, 206, 3, 157, 54, 79, 222, 7, 113, 138, 84, 219, 21, 145, 236, 7, 160, 11, 3, 177, 43, 12, 229, 159, 141, 59, 221, 21, 171, 13, 138, 57, 179, 111, 2, 181, 18, 173, 161, 204, 21, 44, 149, 188, 17, 73, 67, 143, 235, 229, 3, 145, 168, 189, 231, 214, 119, 86, 119, 48, 14, 212, 53, 12, 86, 30, 3, 167, 83, 49, 134, 69, 198, 222, 197, 38, 216, 220, 247, 60, 171, 126, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 144, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 244, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 56, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 160, 134, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 72, 232, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 45, 49, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 120, 125, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 80, 214, 220, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 242, 5, 42, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 110, 135, 116, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 74, 169, 209, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 84, 206, 9, 35, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 233, 65, 204, 107, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 99, 82, 191, 198, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 8, 126, 147, 55, 28, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 138, 93, 120, 69, 99, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 236, 116, 214, 22, 188, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 208, 19, 9, 70, 142, 21, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 196, 88, 139, 215, 241, 90, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 245, 46, 110, 77, 174, 177, 1, 0, 0, 0, 0, 0, 0, 0, 0, 32, 89, 221, 100, 240, 12, 15, 1, 0, 0, 0, 0, 0, 0, 0, 0, 104, 175, 20, 126, 44, 208, 82, 1, 0, 0, 0, 0, 0, 0, 0, 0, 66, 219, 153, 157, 55, 132, 167, 1, 0, 0, 0, 0, 0, 0, 0, 64, 9, 41, 128, 194, 162, 178, 8, 1, 0, 0, 0, 0, 0, 0, 0, 144, 75, 51, 32, 115, 75, 223, 74, 1, 0, 0, 0, 0, 0, 0, 0, 116, 30, 64, 232, 79, 30, 151, 157, 1, 0, 0, 0, 0, 0, 0, 128, 8, 19, 40, 241, 241, 114, 126, 2, 1, 0, 0, 0, 0, 0, 0, 160, 202, 23, 114, 109, 174, 15, 30, 67, 1, 0, 0, 0, 0, 0, 0, 72, 189, 157, 206, 8, 154, 147, 229, 147, 1, 0, 0, 0, 0, 0, 0, 154, 44, 69, 2, 139, 128, 248, 222, 248, 1, 0, 0, 0, 0, 0, 64, 224, 59, 107, 225, 86, 80, 91, 139, 59, 1, 0, 0, 0, 0, 0, 80, 216, 10, 198, 153, 108, 36, 50, 110, 138, 1, 0, 0, 0, 0, 0, 100, 142, 141, 55, 192, 135, 173, 190, 9, 237, 1, 0, 0, 0, 0, 128, 254, 120, 184, 34, 216, 116, 44, 23, 38, 52, 1, 0, 0, 0, 0, 32, 62, 151, 102, 43, 14, 146, 247, 156, 47, 129, 1, 0, 0, 0, 0, 168, 13, 61, 64, 182, 145, 118, 53, 132, 123, 225, 1, 0, 0, 0, 0, 137, 40, 38, 232, 17, 27, 106, 161, 50, 237, 44, 1, 0, 0, 0, 64, 171, 178, 47, 98, 214, 161, 196, 73, 127, 40, 120, 1, 0, 0, 0, 16, 86, 159, 187, 250, 75, 202, 53, 28, 159, 50, 214, 1, 0, 0, 0, 202, 149, 67, 181, 124, 111, 158, 161, 113, 163, 223, 37, 1, 0, 0, 128, 60, 123, 148, 226, 91, 11, 6, 10, 78, 140, 87, 111, 1, 0, 0, 160, 11, 154, 57, 219, 50, 142, 135, 140, 97, 111, 45, 203, 1, 0, 0, 68, 71, 0, 4, 201, 223, 184, 212, 247, 156, 101, 252, 30, 1, 0, 0, 21, 89, 0, 69, 187, 23, 231, 201, 53, 4, 127, 187, 102, 1, 0, 64, 90, 111, 64, 22, 170, 221, 96, 60, 67, 197, 94, 106, 192, 1, 0, 104, 152, 69, 232, 77, 138, 138, 188, 5, 74, 59, 123, 66, 24, 1, 0, 130, 254, 86, 98, 225, 44, 173, 43, 135, 28, 10, 26, 83, 94, 1, 128, 34, 190, 236, 186, 25, 120, 152, 246, 168, 163, 140, 224, 231, 181, 1, 144, 213, 246, 211, 20, 16, 75, 31, 154, 73, 230, 87, 236, 176, 17, 1, 244, 138, 244, 8, 26, 212, 29, 167, 0, 220, 223, 109, 39, 29, 86, 1, 177, 173, 49, 139, 32, 73, 229, 208, 0, 211, 87, 73, 113, 164, 171, 1, 142, 12, 255, 86, 180, 77, 143, 130, 224, 227, 214, 205, 198, 70, 11, 1, 178, 207, 190, 108, 33, 33, 51, 163, 216, 156, 76, 129, 120, 24, 78, 1, 158, 131, 238, 199, 105, 233, 255, 203, 14, 196, 159, 161, 150, 158, 161, 1, 67, 18, 245, 28, 226, 241, 127, 63, 137, 218, 3, 37, 30, 3, 5, 1, 212, 86, 50, 164, 90, 238, 95, 143, 43, 209, 68, 174, 229, 67, 70, 1, 137, 236, 62, 77, 241, 233, 55, 115, 118, 5, 214, 25, 223, 212, 151, 1, 171, 167, 142, 160, 109, 228, 5, 16, 212, 134, 75, 224, 22, 202, 253, 1, 203, 40, 89, 132, 196, 174, 3, 138, 68, 52, 47, 76, 78, 158, 62, 1, 253, 114, 111, 165, 117, 154, 132, 172, 85, 1, 59, 223, 225, 69, 142, 1, 189, 79, 203, 14, 19, 193, 165, 23, 171, 193, 9, 87, 90, 215, 241, 1, 214, 17, 63, 233, 171, 152, 199, 238, 10, 25, 102, 118, 152, 38, 55, 1, 75, 214, 142, 227, 214, 126, 121, 170, 77, 159, 255, 147, 62, 240, 132, 1, 222, 139, 114, 156, 140, 222, 23, 21, 33, 135, 255, 56, 78, 44, 230, 1, 107, 151, 199, 225, 23, 235, 46, 173, 116, 180, 159, 227, 176, 219, 47, 1, 70, 125, 57, 218, 221, 165, 122, 216, 145, 161, 135, 28, 157, 210, 123, 1, 151, 220, 199, 80, 85, 79, 153, 78, 246, 137, 169, 99, 68, 199, 218, 1, 222, 233, 124, 82, 149, 209, 31, 241, 57, 246, 73, 190, 138, 188, 40, 1, 86, 36, 28, 167, 250, 197, 103, 109, 200, 115, 220, 109, 173, 235, 114, 1, 108, 45, 227, 80, 121, 183, 193, 136, 186, 144, 83, 201, 152, 166, 207, 1, 99, 252, 141, 210, 171, 18, 121, 149, 116, 58, 212, 125, 31, 200, 33, 1, 124, 123, 49, 199, 86, 87, 215, 186, 17, 73, 73, 93, 39, 58, 106, 1, 91, 218, 253, 120, 44, 45, 141, 41, 86, 155, 155, 52, 177, 200, 196, 1, 121, 168, 158, 203, 59, 60, 248, 217, 21, 65, 225, 192, 110, 253, 26, 1, 151, 82, 134, 190, 74, 75, 118, 80, 91, 145, 25, 113, 202, 188, 97, 1, 61, 231, 39, 110, 29, 222, 147, 36, 178, 245, 95, 13, 253, 43, 186, 1, 134, 240, 216, 100, 210, 106, 220, 86, 143, 249, 91, 40, 126, 91, 20, 1, 168, 44, 15, 254, 134, 133, 147, 44, 243, 247, 114, 178, 93, 114, 89, 1, 210, 247, 146, 189, 232, 102, 184, 247, 239, 181, 15, 31, 245, 206, 175, 1, 227, 218, 123, 118, 81, 64, 211, 250, 181, 209, 105, 51, 89, 225, 13, 1, 156, 209, 26, 212, 101, 16, 136, 121, 35, 70, 68, 128, 175, 89, 81, 1, 3, 134, 33, 73, 127, 20, 234, 87, 172, 87, 85, 96, 27, 176, 165, 1, 193, 243, 180, 141, 207, 76, 242, 182, 203, 86, 53, 28, 17, 142, 7, 1, 178, 48, 34, 113, 3, 224, 174, 164, 126, 172, 66, 99, 149, 113, 73, 1, 223, 188, 106, 77, 4, 152, 218, 77, 158, 87, 19, 188, 250, 205, 155, 1, 11, 182, 98, 176, 2, 159, 168, 240, 194, 22, 140, 181, 188, 96, 1, 1, 142, 99, 123, 92, 195, 198, 210, 172, 115, 28, 239, 226, 235, 184, 65, 1, 113, 60, 154, 51, 116, 120, 7, 152, 144, 227, 170, 219, 38, 39, 146, 1, 142, 203, 128, 64, 145, 86, 9, 190, 116, 156, 149, 146, 240, 176, 246, 1, 56, 127, 80, 200, 26, 214, 197, 246, 200, 129, 157, 91, 150, 46, 58, 1, 7, 159, 100, 122, 161, 75, 119, 52, 59, 226, 132, 242, 59, 186, 136, 1, 200, 198, 253, 216, 137, 30, 149, 1, 202, 26, 38, 239, 202, 232, 234, 1, 61, 156, 158, 39, 22, 51, 253, 64, 190, 208, 119, 213, 126, 209, 50, 1, 76, 67, 134, 177, 219, 127, 60, 209, 237, 196, 213, 138, 222, 133, 127, 1, 32, 212, 231, 157, 210, 159, 139, 69, 41, 54, 139, 45, 86, 103, 223, 1, 148, 228, 176, 162, 227, 67, 119, 203, 217, 1, 119, 220, 149, 160, 43, 1, 185, 29, 93, 139, 220, 20, 85, 62, 80, 194, 148, 83, 187, 136, 118, 1, 39, 101, 52, 174, 19, 90, 234, 77, 228, 242, 121, 40, 234, 42, 212, 1, 56, 191, 224, 76, 76, 120, 178, 176, 206, 55, 76, 89, 210, 154, 36, 1, 6, 239, 24, 96, 95, 22, 223, 92, 194, 69, 159, 239, 134, 193, 109, 1, 200, 42, 31, 56, 247, 219, 22, 244, 50, 23, 135, 171, 232, 49, 201, 1, 189, 122, 19, 131, 122, 73, 142, 216, 127, 110, 52, 107, 49, 191, 29, 1, 108, 89, 216, 35, 217, 219, 177, 206, 31, 138, 1, 198, 253, 46, 101, 1, 199, 111, 206, 108, 207, 82, 94, 194, 167, 236, 129, 55, 189, 122, 190, 1, 220, 5, 1, 164, 193, 243, 122, 217, 232, 51, 177, 66, 182, 12, 23, 1, 84, 71, 1, 13, 178, 176, 217, 15, 227, 128, 93, 211, 227, 207, 92, 1, 41, 153, 65, 144, 222, 28, 208, 211, 27, 225, 52, 200, 220, 3, 180, 1, 185, 255, 40, 26, 11, 18, 98, 100, 177, 12, 33, 253, 105, 130, 16, 1, 168, 63, 179, 224, 141, 150, 122, 189, 221, 79, 105, 124, 4, 163, 84, 1, 146, 15, 224, 88, 49, 60, 217, 44, 213, 163, 131, 155, 197, 203, 169, 1, 187, 9, 140, 215, 158, 197, 7, 60, 101, 70, 50, 129, 91, 31, 10, 1, 42, 12, 111, 141, 6, 183, 9, 139, 254, 215, 126, 97, 50, 167, 76, 1, 52, 207, 202, 48, 200, 36, 204, 45, 254, 141, 222, 249, 254, 208, 159, 1, 128, 193, 126, 30, 253, 150, 159, 220, 190, 24, 43, 92, 159, 226, 3, 1, 225, 113, 30, 102, 188, 124, 199, 147, 238, 222, 53, 51, 71, 219, 68, 1, 89, 14, 166, 127, 235, 91, 185, 56, 170, 86, 3, 0, 25, 18, 150, 1, 239, 145, 143, 95, 230, 178, 231, 198, 84, 44, 4, 64, 159, 150, 251, 1, 53, 187, 185, 251, 207, 207, 80, 252, 180, 155, 2, 136, 35, 62, 61, 1, 3, 42, 168, 250, 195, 3, 101, 59, 162, 66, 3, 106, 172, 141, 140, 1, 132, 52, 82, 249, 180, 68, 62, 202, 74, 19, 132, 132, 23, 177, 239, 1, 210, 96, 211, 27, 241, 234, 102, 190, 14, 140, 210, 178, 174, 206, 53, 1, 7, 57, 200, 98, 173, 165, 0, 110, 18, 47, 135, 95, 90, 66, 131, 1, 73, 71, 122, 187, 24, 207, 128, 9, 215, 250, 104, 247, 240, 18, 228, 1, 141, 108, 44, 117, 111, 129, 240, 101, 198, 156, 161, 154, 214, 139, 46, 1, 177, 135, 119, 82, 203, 161, 108, 255, 247, 3, 74, 65, 204, 46, 122, 1, 157, 105, 21, 39, 62, 202, 71, 255, 245, 132, 156, 81, 127, 186, 216, 1, 2, 98, 109, 216, 102, 222, 140, 191, 25, 211, 1, 147, 143, 116, 39, 1, 131, 186, 136, 142, 0, 22, 112, 47, 224, 71, 194, 119, 179, 81, 113, 1, 35, 233, 42, 178, 128, 27, 76, 59, 216, 217, 178, 85, 32, 166, 205, 1, 182, 209, 90, 111, 48, 145, 15, 37, 39, 200, 143, 53, 212, 135, 32, 1, 35, 134, 49, 139, 124, 117, 83, 238, 48, 186, 243, 66, 201, 169, 104, 1, 172, 231, 253, 173, 219, 82, 232, 41, 189, 168, 176, 147, 59, 212, 194, 1, 204, 176, 190, 76, 201, 51, 49, 58, 118, 105, 78, 60, 165, 196, 25, 1, 255, 92, 238, 159, 187, 128, 189, 200, 211, 3, 98, 139, 206, 53, 96, 1, 62, 244, 233, 135, 234, 224, 236, 186, 200, 132, 58, 46, 66, 67, 184, 1, 167, 56, 242, 148, 146, 12, 212, 116, 253, 146, 228, 92, 9, 42, 19, 1, 209, 198, 46, 58, 183, 15, 9, 210, 188, 183, 29, 180, 139, 244, 87, 1, 133, 120, 186, 8, 165, 83, 139, 6, 172, 37, 37, 161, 174, 241, 173, 1, 83, 139, 116, 37, 71, 20, 23, 132, 139, 55, 183, 36, 13, 183, 12, 1, 40, 174, 209, 238, 88, 217, 28, 101, 110, 5, 229, 109, 208, 228, 79, 1, 178, 25, 134, 42, 175, 15, 100, 254, 201, 70, 94, 137, 4, 222, 163, 1, 15, 208, 147, 122, 205, 137, 254, 62, 62, 236, 218, 213, 194, 106, 6, 1, 19, 196, 56, 217, 64, 44, 190, 206, 77, 167, 81, 139, 115, 5, 72, 1, 24, 245, 134, 15, 81, 183, 109, 66, 33, 17, 38, 110, 208, 6, 154, 1, 47, 89, 180, 169, 146, 146, 132, 201, 180, 202, 215, 68, 66, 68, 0, 1, 122, 111, 33, 84, 55, 183, 229, 251, 97, 189, 13, 214, 82, 85, 64, 1, 89, 203, 41, 41, 5, 37, 223, 122, 186, 44, 145, 139, 167, 106, 144, 1, 47, 62, 116, 115, 70, 238, 150, 25, 233, 119, 117, 110, 81, 133, 244, 1, 221, 166, 40, 8, 236, 84, 254, 175, 241, 106, 9, 229, 82, 211, 56, 1, 149, 208, 50, 10, 39, 234, 253, 27, 174, 197, 75, 158, 39, 8, 135, 1, 186, 132, 191, 204, 176, 100, 253, 162, 25, 183, 222, 133, 49, 202, 232, 1, 244, 178, 247, 127, 238, 94, 222, 5, 112, 50, 171, 243, 94, 126, 49, 1, 177, 159, 245, 31, 170, 246, 85, 7, 12, 255, 149, 176, 246, 221, 125, 1, 158, 7, 243, 167, 84, 116, 43, 9, 207, 126, 187, 92, 116, 85, 221, 1, 195, 228, 247, 232, 180, 40, 187, 101, 65, 47, 245, 185, 104, 85, 42, 1, 243, 221, 53, 35, 226, 242, 41, 191, 17, 123, 114, 232, 194, 234, 116, 1, 112, 85, 3, 172, 154, 111, 244, 46, 214, 25, 143, 162, 115, 37, 210, 1, 102, 21, 130, 171, 192, 197, 88, 221, 37, 112, 153, 69, 104, 87, 35, 1, 192, 154, 98, 214, 48, 247, 174, 84, 47, 204, 255, 86, 66, 45, 108, 1, 112, 65, 251, 11, 253, 180, 218, 41, 59, 191, 191, 236, 146, 56, 199, 1, 230, 8, 125, 39, 30, 177, 40, 250, 132, 215, 247, 211, 91, 131, 28, 1, 31, 75, 92, 177, 101, 221, 178, 56, 102, 205, 245, 200, 50, 164, 99, 1, 231, 93, 179, 29, 191, 148, 223, 198, 191, 64, 51, 123, 63, 141, 188, 1, 176, 26, 144, 114, 247, 188, 75, 220, 119, 8, 0, 173, 71, 216, 21, 1, 92, 33, 52, 79, 53, 172, 94, 211, 149, 10, 64, 152, 89, 78, 91, 1, 180, 41, 1, 163, 66, 87, 54, 72, 59, 13, 80, 254, 239, 33, 178, 1, 16, 186, 224, 165, 137, 246, 33, 13, 69, 8, 242, 254, 53, 85, 15, 1, 148, 232, 88, 15, 44, 116, 106, 80, 86, 138, 174, 126, 131, 42, 83, 1, 185, 34, 47, 19, 55, 17, 133, 228, 235, 44, 90, 94, 36, 245, 167, 1, 180, 117, 253, 107, 194, 42, 211, 110, 19, 92, 248, 186, 54, 249, 8, 1, 33, 211, 252, 6, 115, 245, 135, 74, 24, 115, 182, 105, 132, 55, 75, 1, 233, 7, 188, 200, 207, 242, 41, 93, 222, 15, 36, 132, 101, 5, 158, 1, 241, 132, 117, 221, 193, 55, 58, 250, 234, 137, 150, 114, 95, 195, 2, 1, 46, 230, 210, 84, 178, 197, 200, 184, 101, 44, 60, 79, 55, 116, 67, 1, 185, 159, 7, 234, 30, 247, 250, 38, 127, 55, 11, 35, 69, 81, 148, 1, 168, 135, 137, 164, 230, 180, 185, 240, 94, 5, 206, 107, 150, 101, 249, 1, 201, 244, 213, 38, 16, 17, 116, 86, 91, 195, 96, 3, 126, 223, 59, 1, 251, 113, 139, 48, 84, 21, 17, 44, 50, 244, 56, 132, 93, 215, 138, 1, 122, 78, 174, 60, 169, 90, 21, 183, 62, 49, 71, 229, 52, 141, 237, 1, 12, 241, 236, 197, 169, 88, 109, 50, 199, 126, 76, 15, 65, 120, 52, 1, 79, 45, 104, 55, 212, 174, 8, 255, 120, 158, 31, 83, 81, 150, 129, 1, 163, 56, 66, 69, 137, 218, 202, 62, 23, 134, 231, 167, 229, 251, 225, 1, 102, 99, 73, 203, 149, 200, 62, 135, 206, 179, 240, 136, 111, 61, 45, 1, 63, 188, 27, 62, 187, 122, 14, 41, 194, 224, 44, 107, 203, 140, 120, 1, 79, 171, 162, 13, 106, 25, 82, 179, 242, 24, 248, 69, 254, 175, 214, 1, 17, 171, 133, 72, 226, 79, 19, 176, 151, 15, 187, 235, 254, 45, 38, 1, 214, 21, 167, 218, 218, 35, 24, 156, 125, 211, 169, 166, 126, 185, 111, 1, 75, 219, 80, 145, 209, 44, 30, 3, 93, 72, 84, 80, 222, 167, 203, 1, 15, 137, 210, 250, 2, 220, 242, 33, 58, 173, 52, 242, 234, 72, 31, 1, 83, 43, 135, 185, 3, 147, 111, 170, 136, 216, 193, 174, 37, 27, 103, 1, 40, 246, 232, 167, 196, 119, 11, 213, 170, 78, 114, 26, 239, 225, 192, 1, 217, 153, 241, 232, 218, 42, 39, 197, 42, 113, 135, 112, 53, 141, 24, 1, 79, 0, 46, 163, 145, 245, 112, 118, 117, 77, 169, 204, 130, 176, 94, 1, 99, 128, 249, 11, 246, 50, 13, 212, 210, 160, 211, 127, 163, 92, 182, 1, 62, 240, 123, 199, 217, 63, 136, 196, 131, 68, 228, 47, 230, 249, 17, 1, 77, 236, 90, 57, 208, 79, 170, 181, 164, 85, 221, 187, 95, 120, 86, 1, 96, 167, 177, 71, 196, 227, 20, 227, 13, 171, 212, 170, 119, 22, 172, 1, 156, 8, 207, 172, 90, 14, 237, 173, 232, 234, 196, 202, 10, 142, 11, 1, 195, 202, 2, 88, 241, 81, 104, 217, 162, 37, 118, 125, 141, 113, 78, 1, 116, 125, 3, 174, 109, 102, 194, 143, 11, 175, 211, 220, 240, 13, 162, 1, 104, 46, 194, 140, 4, 128, 217, 57, 103, 77, 4, 138, 182, 72, 5, 1, 3, 186, 242, 175, 5, 224, 79, 8, 193, 96, 133, 44, 228, 154, 70, 1, 131, 104, 239, 27, 7, 216, 99, 74, 241, 184, 166, 55, 157, 65, 152, 1, 164, 66, 235, 226, 8, 206, 252, 156, 45, 103, 144, 133, 4, 82, 254, 1, 167, 9, 211, 141, 197, 0, 30, 130, 124, 64, 122, 211, 66, 243, 62, 1, 16, 204, 71, 241, 246, 128, 165, 162, 155, 208, 88, 136, 19, 176, 142, 1, 21, 191, 153, 173, 52, 225, 78, 139, 194, 4, 111, 106, 24, 92, 242, 1, 109, 23, 128, 236, 192, 76, 17, 151, 249, 98, 133, 66, 143, 121, 55, 1, 72, 29, 160, 39, 241, 159, 213, 252, 183, 187, 38, 19, 243, 87, 133, 1, 154, 36, 136, 113, 237, 7, 11, 252, 165, 106, 240, 215, 239, 173, 230, 1, 224, 22, 245, 102, 244, 228, 134, 189, 167, 66, 246, 230, 181, 44, 48, 1, 152, 92, 178, 128, 49, 158, 232, 172, 81, 211, 179, 96, 227, 55, 124, 1, 190, 243, 222, 224, 189, 197, 34, 24, 38, 200, 224, 56, 220, 69, 219, 1, 87, 88, 139, 172, 150, 187, 21, 207, 23, 125, 140, 163, 169, 11, 41, 1, 109, 46, 174, 87, 124, 42, 219, 194, 93, 156, 111, 12, 148, 78, 115, 1, 8, 186, 153, 109, 27, 245, 145, 51, 117, 131, 139, 15, 57, 34, 208, 1, 69, 20, 128, 36, 49, 57, 59, 64, 41, 50, 183, 169, 99, 21, 34, 1, 86, 25, 160, 109, 125, 7, 74, 144, 179, 254, 36, 148, 188, 154, 106, 1, 172, 31, 8, 201, 92, 137, 92, 116, 96, 62, 46, 185, 107, 65, 197, 1, 203, 19, 165, 253, 217, 213, 185, 72, 252, 230, 188, 83, 227, 72, 27, 1, 190, 88, 14, 125, 80, 75, 232, 90, 187, 32, 172, 40, 28, 27, 98, 1, 238, 238, 81, 156, 36, 94, 162, 49, 234, 40, 215, 50, 227, 161, 186, 1, 84, 53, 179, 225, 214, 122, 5, 95, 146, 121, 198, 255, 45, 165, 20, 1, 170, 2, 32, 154, 140, 217, 198, 246, 246, 23, 184, 127, 121, 206, 89, 1, 84, 3, 168, 192, 239, 143, 120, 180, 244, 29, 166, 223, 23, 66, 176, 1, 20, 2, 105, 216, 245, 89, 203, 240, 184, 210, 199, 235, 78, 41, 14, 1, 154, 66, 131, 78, 115, 48, 254, 44, 103, 199, 185, 166, 162, 179, 81, 1, 64, 19, 36, 34, 144, 188, 61, 248, 64, 57, 104, 80, 139, 32, 166, 1, 8, 140, 86, 21, 218, 149, 38, 155, 200, 35, 65, 18, 87, 212, 7, 1, 10, 47, 172, 154, 80, 59, 240, 193, 186, 108, 209, 214, 108, 201, 73, 1, 205, 58, 87, 193, 36, 74, 108, 114, 233, 199, 133, 12, 200, 59, 156, 1, 192, 132, 214, 248, 86, 174, 131, 231, 241, 156, 211, 7, 93, 165, 1, 1, 240, 37, 12, 183, 236, 153, 100, 97, 46, 132, 200, 73, 180, 14, 66, 1, 108, 47, 207, 228, 103, 192, 189, 249, 57, 165, 58, 92, 97, 146, 146, 1, 71, 251, 2, 222, 129, 48, 45, 120, 136, 78, 73, 179, 249, 54, 247, 1, 12, 221, 193, 42, 81, 62, 28, 75, 21, 209, 13, 16, 92, 130, 58, 1, 79, 84, 114, 117, 229, 77, 227, 157, 90, 69, 17, 20, 243, 34, 137, 1, 99, 233, 206, 210, 94, 33, 92, 69, 177, 150, 21, 217, 175, 107, 235, 1, 222, 81, 193, 67, 219, 148, 89, 203, 46, 126, 173, 231, 77, 35, 51, 1, 85, 166, 177, 20, 18, 250, 47, 126, 186, 221, 152, 97, 33, 236, 127, 1, 235, 15, 222, 153, 150, 248, 187, 29, 41, 21, 255, 185, 41, 231, 223, 1, 243, 201, 42, 32, 94, 123, 149, 178, 57, 109, 63, 20, 122, 240, 43, 1, 111, 124, 53, 168, 53, 218, 58, 31, 136, 72, 79, 153, 152, 236, 118, 1, 139, 219, 66, 18, 195, 144, 9, 39, 170, 26, 163, 191, 190, 167, 212, 1, 55, 201, 105, 235, 121, 250, 101, 88, 170, 240, 197, 55, 215, 232, 36, 1, 133, 59, 68, 102, 24, 121, 127, 238, 212, 108, 183, 5, 13, 35, 110, 1, 102, 74, 213, 127, 94, 87, 31, 42, 10, 72, 37, 71, 208, 171, 201, 1, 128, 78, 229, 15, 155, 150, 83, 90, 6, 77, 119, 44, 98, 11, 30, 1, 32, 162, 222, 211, 65, 124, 232, 240, 71, 32, 149, 183, 58, 142, 101, 1, 168, 74, 214, 72, 82, 155, 34, 237, 89, 104, 122, 101, 201, 241, 190, 1, 169, 238, 133, 109, 19, 161, 53, 52, 56, 129, 108, 223, 29, 87, 23, 1, 83, 106, 231, 72, 88, 9, 67, 65, 134, 161, 71, 87, 229, 44, 93, 1, 232, 68, 33, 91, 174, 203, 147, 209, 231, 137, 25, 173, 30, 120, 180, 1, 17, 203, 244, 248, 76, 95, 252, 226, 48, 246, 47, 44, 19, 203, 16, 1, 213, 253, 49, 55, 32, 119, 187, 27, 189, 243, 59, 247, 215, 253, 84, 1, 74, 125, 254, 68, 232, 84, 170, 98, 172, 240, 10, 245, 77, 61, 170, 1, 78, 14, 31, 43, 17, 117, 170, 189, 107, 214, 38, 185, 80, 102, 10, 1, 226, 209, 230, 117, 85, 18, 21, 173, 6, 140, 112, 231, 228, 255, 76, 1, 91, 134, 96, 211, 234, 86, 90, 88, 8, 175, 76, 33, 222, 63, 160, 1, 248, 83, 28, 196, 82, 118, 56, 55, 101, 237, 207, 212, 234, 39, 4, 1, 247, 104, 35, 117, 231, 147, 6, 133, 190, 232, 3, 138, 229, 49, 69, 1, 52, 67, 108, 82, 225, 56, 72, 38, 238, 226, 132, 236, 94, 126, 150, 1, 2, 84, 7, 167, 25, 71, 218, 175, 169, 27, 166, 167, 246, 29, 252, 1, 129, 148, 100, 8, 112, 108, 232, 13, 74, 209, 199, 40, 186, 146, 61, 1, 161, 185, 125, 10, 140, 135, 98, 145, 156, 197, 249, 178, 104, 247, 140, 1, 10, 40, 29, 13, 111, 41, 187, 181, 3, 55, 184, 223, 66, 53, 240, 1, 6, 57, 50, 104, 229, 249, 148, 81, 98, 34, 211, 203, 73, 33, 54, 1, 71, 199, 62, 194, 94, 56, 250, 229, 250, 234, 199, 62, 156, 169, 131, 1, 25, 121, 206, 114, 118, 198, 120, 159, 185, 229, 121, 78, 3, 148, 228, 1, 176, 11, 193, 7, 10, 124, 171, 3, 148, 47, 12, 17, 130, 220, 46, 1, 156, 78, 177, 137, 12, 91, 150, 4, 121, 59, 79, 149, 162, 147, 122, 1, 67, 162, 29, 172, 207, 241, 187, 69, 87, 10, 163, 58, 139, 56, 217, 1, 106, 133, 146, 203, 33, 119, 149, 139, 118, 230, 165, 4, 87, 195, 39, 1, 196, 38, 119, 62, 234, 212, 122, 46, 20, 96, 207, 197, 44, 180, 113, 1, 117, 240, 20, 206, 36, 138, 25, 58, 25, 56, 67, 247, 55, 33, 206, 1, 73, 22, 205, 0, 87, 246, 79, 196, 15, 3, 138, 250, 194, 212, 32, 1, 219, 91, 0, 193, 236, 243, 99, 181, 211, 131, 44, 185, 243, 9, 105, 1, 210, 114, 64, 241, 231, 240, 188, 162, 200, 164, 119, 167, 112, 76, 195, 1, 195, 71, 200, 246, 144, 22, 182, 101, 253, 198, 170, 104, 198, 15, 26, 1, 180, 89, 122, 52, 53, 156, 35, 191, 188, 120, 213, 2, 184, 147, 96, 1, 33, 240, 152, 129, 66, 131, 236, 238, 235, 214, 138, 3, 166, 184, 184, 1, 21, 150, 255, 144, 9, 210, 83, 117, 83, 198, 54, 194, 103, 115, 19, 1, 154, 123, 63, 245, 139, 198, 168, 82, 232, 119, 196, 178, 65, 80, 88, 1, 129, 90, 143, 242, 46, 248, 82, 103, 226, 149, 117, 31, 82, 100, 174, 1, 144, 152, 153, 87, 29, 219, 147, 128, 173, 125, 169, 83, 179, 254, 12, 1, 180, 254, 127, 173, 228, 209, 184, 224, 24, 221, 147, 40, 96, 62, 80, 1, 98, 254, 223, 216, 93, 6, 231, 24, 95, 212, 184, 50, 248, 77, 164, 1, 253, 254, 139, 167, 250, 99, 144, 111, 187, 132, 179, 31, 187, 176, 6, 1, 188, 254, 110, 81, 249, 124, 116, 75, 234, 101, 160, 231, 233, 92, 72, 1, 107, 190, 202, 165, 55, 156, 81, 222, 100, 127, 136, 97, 36, 116, 154, 1, 3, 183, 158, 199, 162, 1, 243, 10, 159, 79, 245, 188, 150, 136, 0, 1, 196, 100, 134, 121, 11, 194, 175, 205, 134, 163, 50, 108, 188, 170, 64, 1, 245, 253, 231, 87, 142, 178, 27, 129, 104, 76, 63, 135, 107, 213, 144, 1, 114, 253, 225, 237, 49, 159, 98, 161, 130, 31, 15, 105, 198, 10, 245, 1, 103, 62, 173, 52, 127, 163, 221, 164, 177, 115, 169, 1, 188, 38, 57, 1, 1, 142, 216, 1, 95, 12, 21, 14, 158, 208, 19, 2, 107, 112, 135, 1, 129, 177, 78, 194, 118, 79, 154, 145, 197, 196, 152, 194, 133, 76, 233, 1, 241, 46, 113, 57, 170, 113, 0, 123, 251, 122, 159, 153, 211, 207, 49, 1, 173, 122, 205, 199, 20, 142, 192, 89, 186, 89, 7, 128, 200, 67, 126, 1, 88, 217, 192, 249, 153, 177, 48, 240, 40, 48, 9, 160, 186, 212, 221, 1, 215, 135, 24, 60, 0, 111, 30, 150, 25, 190, 5, 164, 244, 164, 42, 1, 205, 169, 30, 75, 192, 10, 166, 251, 159, 45, 7, 205, 49, 78, 117, 1, 64, 84, 230, 93, 112, 141, 143, 250, 7, 249, 72, 64, 190, 161, 210, 1, 168, 244, 175, 58, 102, 184, 153, 252, 164, 155, 45, 232, 22, 165, 35, 1, 210, 241, 91, 201, 127, 38, 192, 59, 142, 2, 57, 162, 92, 142, 108, 1, 71, 238, 178, 187, 31, 48, 176, 202, 49, 67, 199, 202, 243, 177, 199, 1, 236, 212, 79, 213, 19, 30, 174, 30, 255, 137, 188, 94, 56, 207, 28, 1, 39, 202, 163, 202, 152, 165, 89, 230, 126, 172, 107, 118, 6, 3, 100, 1, 177, 188, 76, 253, 254, 14, 240, 159, 158, 151, 6, 20, 200, 3, 189, 1, 239, 245, 79, 94, 95, 9, 246, 35, 195, 30, 132, 12, 93, 34, 22, 1, 106, 243, 227, 53, 183, 139, 243, 236, 115, 38, 165, 79, 244, 170, 91, 1, 69, 240, 92, 3, 165, 110, 48, 232, 16, 112, 142, 99, 177, 149, 178, 1, 43, 22, 26, 34, 39, 69, 30, 145, 10, 6, 57, 222, 142, 157, 15, 1, 182, 155, 160, 234, 112, 214, 101, 53, 141, 71, 199, 149, 242, 132, 83, 1, 163, 194, 72, 37, 13, 76, 191, 130, 112, 25, 57, 59, 47, 102, 168, 1, 166, 121, 77, 55, 136, 143, 183, 81, 230, 175, 3, 133, 221, 63, 9, 1, 16, 216, 32, 69, 106, 115, 37, 230, 223, 155, 68, 230, 212, 143, 75, 1, 20, 14, 105, 214, 68, 208, 174, 223, 215, 194, 213, 31, 202, 115, 158, 1, 204, 168, 1, 6, 43, 66, 205, 235, 198, 153, 229, 83, 94, 8, 3, 1, 255, 18, 130, 199, 181, 146, 192, 166, 56, 0, 223, 232, 117, 202, 67, 1, 191, 151, 98, 57, 99, 183, 112, 208, 70, 192, 22, 99, 19, 189, 148, 1, 175, 61, 187, 7, 60, 229, 140, 132, 88, 112, 220, 59, 88, 236, 249, 1, 141, 6, 213, 132, 69, 15, 216, 82, 55, 198, 105, 37, 183, 51, 60, 1, 49, 72, 10, 230, 22, 19, 142, 39, 197, 55, 196, 238, 164, 64, 139, 1, 48, 46, 48, 117, 110, 101, 120, 112, 101, 99, 116, 101, 100, 32, 101, 110, 100, 32, 111, 102, 32, 102, 105, 108, 101, 111, 116, 104, 101, 114, 32, 111, 115, 32, 101, 114, 114, 111, 114, 111, 112, 101, 114, 97, 116, 105, 111, 110, 32, 105, 110, 116, 101, 114, 114, 117, 112, 116, 101, 100, 119, 114, 105, 116, 101, 32, 122, 101, 114, 111, 116, 105, 109, 101, 100, 32, 111, 117, 116, 105, 110, 118, 97, 108, 105, 100, 32, 100, 97, 116, 97, 105, 110, 118, 97, 108, 105, 100, 32, 105, 110, 112, 117, 116, 32, 112, 97, 114, 97, 109, 101, 116, 101, 114, 111, 112, 101, 114, 97, 116, 105, 111, 110, 32, 119, 111, 117, 108, 100, 32, 98, 108, 111, 99, 107, 101, 110, 116, 105, 116, 121, 32, 97, 108, 114, 101, 97, 100, 121, 32, 101, 120, 105, 115, 116, 115, 98, 114, 111, 107, 101, 110, 32, 112, 105, 112, 101, 97, 100, 100, 114, 101, 115, 115, 32, 110, 111, 116, 32, 97, 118, 97, 105, 108, 97, 98, 108, 101, 97, 100, 100, 114, 101, 115, 115, 32, 105, 110, 32, 117, 115, 101, 110, 111, 116, 32, 99, 111, 110, 110, 101, 99, 116, 101, 100, 99, 111, 110, 110, 101, 99, 116, 105, 111, 110, 32, 97, 98, 111, 114, 116, 101, 100, 99, 111, 110, 110, 101, 99, 116, 105, 111, 110, 32, 114, 101, 115, 101, 116, 99, 111, 110, 110, 101, 99, 116, 105, 111, 110, 32, 114, 101, 102, 117, 115, 101, 100, 112, 101, 114, 109, 105, 115, 115, 105, 111, 110, 32, 100, 101, 110, 105, 101, 100, 101, 110, 116, 105, 116, 121, 32, 110, 111, 116, 32, 102, 111, 117, 110, 100, 1, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 32, 40, 111, 115, 32, 101, 114, 114, 111, 114, 32, 111, 112, 101, 114, 97, 116, 105, 111, 110, 32, 115, 117, 99, 99, 101, 115, 115, 102, 117, 108, 0, 0, 0, 0, 0, 0, 0, 0, 0, 105, 110, 116, 101, 114, 110, 97, 108, 32, 101, 114, 114, 111, 114, 58, 32, 101, 110, 116, 101, 114, 101, 100, 32, 117, 110, 114, 101, 97, 99, 104, 97, 98, 108, 101, 32, 99, 111, 100, 101, 108, 105, 98, 97, 108, 108, 111, 99, 47, 114, 97, 119, 95, 118, 101, 99, 46, 114, 115, 99, 97, 112, 97, 99, 105, 116, 121, 32, 111, 118, 101, 114, 102, 108, 111, 119, 48, 120, 48, 48, 48, 49, 48, 50, 48, 51, 48, 52, 48, 53, 48, 54, 48, 55, 48, 56, 48, 57, 49, 48, 49, 49, 49, 50, 49, 51, 49, 52, 49, 53, 49, 54, 49, 55, 49, 56, 49, 57, 50, 48, 50, 49, 50, 50, 50, 51, 50, 52, 50, 53, 50, 54, 50, 55, 50, 56, 50, 57, 51, 48, 51, 49, 51, 50, 51, 51, 51, 52, 51, 53, 51, 54, 51, 55, 51, 56, 51, 57, 52, 48, 52, 49, 52, 50, 52, 51, 52, 52, 52, 53, 52, 54, 52, 55, 52, 56, 52, 57, 53, 48, 53, 49, 53, 50, 53, 51, 53, 52, 53, 53, 53, 54, 53, 55, 53, 56, 53, 57, 54, 48, 54, 49, 54, 50, 54, 51, 54, 52, 54, 53, 54, 54, 54, 55, 54, 56, 54, 57, 55, 48, 55, 49, 55, 50, 55, 51, 55, 52, 55, 53, 55, 54, 55, 55, 55, 56, 55, 57, 56, 48, 56, 49, 56, 50, 56, 51, 56, 52, 56, 53, 56, 54, 56, 55, 56, 56, 56, 57, 57, 48, 57, 49, 57, 50, 57, 51, 57, 52, 57, 53, 57, 54, 57, 55, 57, 56, 57, 57, 0, 1, 3, 5, 5, 6, 6, 3, 7, 6, 8, 8, 9, 17, 10, 28, 11, 25, 12, 20, 13, 18, 14, 22, 15, 4, 16, 3, 18, 18, 19, 9, 22, 1, 23, 5, 24, 2, 25, 3, 26, 7, 28, 2, 29, 1, 31, 22, 32, 3, 43, 6, 44, 2, 45, 11, 46, 1, 48, 3, 49, 2, 50, 2, 169, 2, 170, 4, 171, 8, 250, 2, 251, 5, 253, 4, 254, 3, 255, 9, 173, 120, 121, 139, 141, 162, 48, 87, 88, 139, 140, 144, 28, 29, 221, 14, 15, 75, 76, 251, 252, 46, 47, 63, 92, 93, 95, 181, 226, 132, 141, 142, 145, 146, 169, 177, 186, 187, 197, 198, 201, 202, 222, 228, 229, 255, 0, 4, 17, 18, 41, 49, 52, 55, 58, 59, 61, 73, 74, 93, 132, 142, 146, 169, 177, 180, 186, 187, 198, 202, 206, 207, 228, 229, 0, 4, 13, 14, 17, 18, 41, 49, 52, 58, 59, 69, 70, 73, 74, 94, 100, 101, 132, 145, 155, 157, 201, 206, 207, 13, 17, 41, 69, 73, 87, 100, 101, 141, 145, 169, 180, 186, 187, 197, 201, 223, 228, 229, 240, 4, 13, 17, 69, 73, 100, 101, 128, 129, 132, 178, 188, 190, 191, 213, 215, 240, 241, 131, 133, 134, 137, 139, 140, 152, 160, 164, 166, 168, 169, 172, 186, 190, 191, 197, 199, 206, 207, 218, 219, 72, 152, 189, 205, 198, 206, 207, 73, 78, 79, 87, 89, 94, 95, 137, 142, 143, 177, 182, 183, 191, 193, 198, 199, 215, 17, 22, 23, 91, 92, 246, 247, 254, 255, 128, 13, 109, 113, 222, 223, 14, 15, 31, 110, 111, 28, 29, 95, 125, 126, 174, 175, 187, 188, 250, 22, 23, 30, 31, 70, 71, 78, 79, 88, 90, 92, 94, 126, 127, 181, 197, 212, 213, 220, 240, 241, 245, 114, 115, 143, 116, 117, 150, 151, 201, 255, 47, 95, 38, 46, 47, 167, 175, 183, 191, 199, 207, 215, 223, 154, 64, 151, 152, 48, 143, 31, 255, 206, 255, 78, 79, 90, 91, 7, 8, 15, 16, 39, 47, 238, 239, 110, 111, 55, 61, 63, 66, 69, 144, 145, 254, 255, 83, 103, 117, 200, 201, 208, 209, 216, 217, 231, 254, 255, 0, 32, 95, 34, 130, 223, 4, 130, 68, 8, 27, 4, 6, 17, 129, 172, 14, 128, 171, 53, 30, 21, 128, 224, 3, 25, 8, 1, 4, 47, 4, 52, 4, 7, 3, 1, 7, 6, 7, 17, 10, 80, 15, 18, 7, 85, 8, 2, 4, 28, 10, 9, 3, 8, 3, 7, 3, 2, 3, 3, 3, 12, 4, 5, 3, 11, 6, 1, 14, 21, 5, 58, 3, 17, 7, 6, 5, 16, 8, 86, 7, 2, 7, 21, 13, 80, 4, 67, 3, 45, 3, 1, 4, 17, 6, 15, 12, 58, 4, 29, 37, 13, 6, 76, 32, 109, 4, 106, 37, 128, 200, 5, 130, 176, 3, 26, 6, 130, 253, 3, 89, 7, 21, 11, 23, 9, 20, 12, 20, 12, 106, 6, 10, 6, 26, 6, 89, 7, 43, 5, 70, 10, 44, 4, 12, 4, 1, 3, 49, 11, 44, 4, 26, 6, 11, 3, 128, 172, 6, 10, 6, 31, 65, 76, 4, 45, 3, 116, 8, 60, 3, 15, 3, 60, 7, 56, 8, 42, 6, 130, 255, 17, 24, 8, 47, 17, 45, 3, 32, 16, 33, 15, 128, 140, 4, 130, 151, 25, 11, 21, 136, 148, 5, 47, 5, 59, 7, 2, 14, 24, 9, 128, 175, 49, 116, 12, 128, 214, 26, 12, 5, 128, 255, 5, 128, 182, 5, 36, 12, 155, 198, 10, 210, 48, 16, 132, 141, 3, 55, 9, 129, 92, 20, 128, 184, 8, 128, 186, 61, 53, 4, 10, 6, 56, 8, 70, 8, 12, 6, 116, 11, 30, 3, 90, 4, 89, 9, 128, 131, 24, 28, 10, 22, 9, 70, 10, 128, 138, 6, 171, 164, 12, 23, 4, 49, 161, 4, 129, 218, 38, 7, 12, 5, 5, 128, 165, 17, 129, 109, 16, 120, 40, 42, 6, 76, 4, 128, 141, 4, 128, 190, 3, 27, 3, 15, 13, 0, 6, 1, 1, 3, 1, 4, 2, 8, 8, 9, 2, 10, 5, 11, 2, 16, 1, 17, 4, 18, 5, 19, 17, 20, 2, 21, 2, 23, 2, 26, 2, 28, 5, 29, 8, 36, 1, 106, 3, 107, 2, 188, 2, 209, 2, 212, 12, 213, 9, 214, 2, 215, 2, 218, 1, 224, 5, 232, 2, 238, 32, 240, 4, 249, 4, 12, 39, 59, 62, 78, 79, 143, 158, 158, 159, 6, 7, 9, 54, 61, 62, 86, 243, 208, 209, 4, 20, 24, 54, 55, 86, 87, 189, 53, 206, 207, 224, 18, 135, 137, 142, 158, 4, 13, 14, 17, 18, 41, 49, 52, 58, 69, 70, 73, 74, 78, 79, 100, 101, 90, 92, 182, 183, 27, 28, 132, 133, 9, 55, 144, 145, 168, 7, 10, 59, 62, 102, 105, 143, 146, 111, 95, 238, 239, 90, 98, 154, 155, 39, 40, 85, 157, 160, 161, 163, 164, 167, 168, 173, 186, 188, 196, 6, 11, 12, 21, 29, 58, 63, 69, 81, 166, 167, 204, 205, 160, 7, 25, 26, 34, 37, 197, 198, 4, 32, 35, 37, 38, 40, 51, 56, 58, 72, 74, 76, 80, 83, 85, 86, 88, 90, 92, 94, 96, 99, 101, 102, 107, 115, 120, 125, 127, 138, 164, 170, 175, 176, 192, 208, 63, 113, 114, 123, 94, 34, 123, 5, 3, 4, 45, 3, 101, 4, 1, 47, 46, 128, 130, 29, 3, 49, 15, 28, 4, 36, 9, 30, 5, 43, 5, 68, 4, 14, 42, 128, 170, 6, 36, 4, 36, 4, 40, 8, 52, 11, 1, 128, 144, 129, 55, 9, 22, 10, 8, 128, 152, 57, 3, 99, 8, 9, 48, 22, 5, 33, 3, 27, 5, 1, 64, 56, 4, 75, 5, 47, 4, 10, 7, 9, 7, 64, 32, 39, 4, 12, 9, 54, 3, 58, 5, 26, 7, 4, 12, 7, 80, 73, 55, 51, 13, 51, 7, 46, 8, 10, 129, 38, 31, 128, 129, 40, 8, 42, 128, 166, 78, 4, 30, 15, 67, 14, 25, 7, 10, 6, 71, 9, 39, 9, 117, 11, 63, 65, 42, 6, 59, 5, 10, 6, 81, 6, 1, 5, 16, 3, 5, 128, 139, 95, 33, 72, 8, 10, 128, 166, 94, 34, 69, 11, 10, 6, 13, 19, 56, 8, 10, 54, 44, 4, 16, 128, 192, 60, 100, 83, 12, 1, 129, 0, 72, 8, 83, 29, 57, 129, 7, 70, 10, 29, 3, 71, 73, 55, 3, 14, 8, 10, 6, 57, 7, 10, 129, 54, 25, 129, 7, 131, 154, 102, 117, 11, 128, 196, 138, 188, 132, 47, 143, 209, 130, 71, 161, 185, 130, 57, 7, 42, 4, 2, 96, 38, 10, 70, 10, 40, 5, 19, 130, 176, 91, 101, 69, 11, 47, 16, 17, 64, 2, 30, 151, 242, 14, 130, 243, 165, 13, 129, 31, 81, 129, 140, 137, 4, 107, 5, 13, 3, 9, 7, 16, 147, 96, 128, 246, 10, 115, 8, 110, 23, 70, 128, 154, 20, 12, 87, 9, 25, 128, 135, 129, 71, 3, 133, 66, 15, 21, 133, 80, 43, 135, 213, 128, 215, 41, 75, 5, 10, 4, 2, 131, 17, 68, 129, 75, 60, 6, 1, 4, 85, 5, 27, 52, 2, 129, 14, 44, 4, 100, 12, 86, 10, 13, 3, 92, 4, 61, 57, 29, 13, 44, 4, 9, 7, 2, 14, 6, 128, 154, 131, 213, 11, 13, 3, 10, 6, 116, 12, 89, 39, 12, 4, 56, 8, 10, 6, 40, 8, 30, 82, 12, 4, 103, 3, 41, 13, 10, 6, 3, 13, 48, 96, 14, 133, 146, 108, 105, 98, 99, 111, 114, 101, 47, 115, 108, 105, 99, 101, 47, 109, 111, 100, 46, 114, 115, 105, 110, 100, 101, 120, 32, 32, 111, 117, 116, 32, 111, 102, 32, 114, 97, 110, 103, 101, 32, 102, 111, 114, 32, 115, 108, 105, 99, 101, 32, 111, 102, 32, 108, 101, 110, 103, 116, 104, 32, 115, 108, 105, 99, 101, 32, 105, 110, 100, 101, 120, 32, 115, 116, 97, 114, 116, 115, 32, 97, 116, 32, 32, 98, 117, 116, 32, 101, 110, 100, 115, 32, 97, 116, 32, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 91, 46, 46, 46, 93, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 108, 105, 98, 99, 111, 114, 101, 47, 115, 116, 114, 47, 109, 111, 100, 46, 114, 115, 98, 121, 116, 101, 32, 105, 110, 100, 101, 120, 32, 32, 105, 115, 32, 110, 111, 116, 32, 97, 32, 99, 104, 97, 114, 32, 98, 111, 117, 110, 100, 97, 114, 121, 59, 32, 105, 116, 32, 105, 115, 32, 105, 110, 115, 105, 100, 101, 32, 32, 40, 98, 121, 116, 101, 115, 32, 41, 32, 111, 102, 32, 96, 96, 98, 101, 103, 105, 110, 32, 60, 61, 32, 101, 110, 100, 32, 40, 32, 60, 61, 32, 41, 32, 119, 104, 101, 110, 32, 115, 108, 105, 99, 105, 110, 103, 32, 96, 32, 105, 115, 32, 111, 117, 116, 32, 111, 102, 32, 98, 111, 117, 110, 100, 115, 32, 111, 102, 32, 96, 85, 116, 102, 56, 69, 114, 114, 111, 114, 118, 97, 108, 105, 100, 95, 117, 112, 95, 116, 111, 101, 114, 114, 111, 114, 95, 108, 101, 110, 0, 0, 0, 0, 0, 108, 105, 98, 99, 111, 114, 101, 47, 102, 109, 116, 47, 109, 111, 100, 46, 114, 115, 99, 97, 108, 108, 101, 100, 32, 96, 79, 112, 116, 105, 111, 110, 58, 58, 117, 110, 119, 114, 97, 112, 40, 41, 96, 32, 111, 110, 32, 97, 32, 96, 78, 111, 110, 101, 96, 32, 118, 97, 108, 117, 101, 108, 105, 98, 99, 111, 114, 101, 47, 111, 112, 116, 105, 111, 110, 46, 114, 115, 69, 114, 114, 111, 114, 10, 32, 10, 125, 32, 125, 40, 41, 32, 32, 32, 32, 32, 123, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 108, 105, 98, 99, 111, 114, 101, 47, 117, 110, 105, 99, 111, 100, 101, 47, 98, 111, 111, 108, 95, 116, 114, 105, 101, 46, 114, 115, 0, 0, 0, 0, 0, 0, 192, 251, 239, 62, 0, 0, 0, 0, 0, 14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 248, 255, 251, 255, 255, 255, 7, 0, 0, 0, 0, 0, 0, 20, 254, 33, 254, 0, 12, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 80, 30, 32, 128, 0, 12, 0, 0, 64, 6, 0, 0, 0, 0, 0, 0, 16, 134, 57, 2, 0, 0, 0, 35, 0, 190, 33, 0, 0, 12, 0, 0, 252, 2, 0, 0, 0, 0, 0, 0, 208, 30, 32, 192, 0, 12, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 64, 1, 32, 128, 0, 0, 0, 0, 0, 17, 0, 0, 0, 0, 0, 0, 192, 193, 61, 96, 0, 12, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 144, 68, 48, 96, 0, 12, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 88, 30, 32, 128, 0, 12, 0, 0, 0, 0, 132, 92, 128, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 242, 7, 128, 127, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 242, 27, 0, 63, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 160, 2, 0, 0, 0, 0, 0, 0, 254, 127, 223, 224, 255, 254, 255, 255, 255, 31, 64, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 224, 253, 102, 0, 0, 0, 195, 1, 0, 30, 0, 100, 32, 0, 32, 0, 0, 0, 0, 0, 0, 0, 224, 0, 0, 0, 0, 0, 0, 28, 0, 0, 0, 28, 0, 0, 0, 12, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 176, 63, 64, 254, 15, 32, 0, 0, 0, 0, 0, 56, 0, 0, 0, 0, 0, 0, 96, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 135, 1, 4, 14, 0, 0, 128, 9, 0, 0, 0, 0, 0, 0, 64, 127, 229, 31, 248, 159, 0, 0, 0, 0, 0, 0, 255, 127, 15, 0, 0, 0, 0, 0, 208, 23, 4, 0, 0, 0, 0, 248, 15, 0, 3, 0, 0, 0, 60, 59, 0, 0, 0, 0, 0, 0, 64, 163, 3, 0, 0, 0, 0, 0, 0, 240, 207, 0, 0, 0, 247, 255, 253, 33, 16, 3, 255, 255, 255, 255, 255, 255, 255, 251, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 1, 0, 0, 0, 0, 0, 0, 128, 3, 0, 0, 0, 0, 0, 0, 0, 0, 128, 0, 0, 0, 0, 255, 255, 255, 255, 0, 0, 0, 0, 0, 252, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 247, 63, 0, 0, 0, 192, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 68, 8, 0, 0, 96, 0, 0, 0, 48, 0, 0, 0, 255, 255, 3, 128, 0, 0, 0, 0, 192, 63, 0, 0, 128, 255, 3, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 200, 19, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 0, 126, 102, 0, 8, 16, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 157, 193, 2, 0, 0, 0, 0, 48, 64, 0, 0, 0, 0, 0, 32, 33, 0, 0, 0, 0, 0, 64, 0, 0, 0, 0, 255, 255, 0, 0, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 8, 9, 10, 0, 11, 12, 13, 14, 15, 0, 0, 16, 17, 18, 0, 0, 19, 20, 21, 22, 0, 0, 23, 24, 25, 26, 27, 0, 28, 0, 0, 0, 29, 0, 0, 0, 0, 0, 0, 0, 30, 31, 32, 0, 0, 0, 0, 0, 33, 0, 34, 0, 35, 36, 37, 0, 0, 0, 0, 38, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 39, 40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 41, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 43, 44, 0, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 46, 47, 48, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 49, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 50, 0, 51, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 52, 53, 0, 0, 53, 53, 53, 54, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 192, 7, 110, 240, 0, 0, 0, 0, 0, 135, 0, 0, 0, 0, 96, 0, 0, 0, 0, 0, 0, 0, 240, 0, 0, 0, 192, 255, 1, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 255, 127, 0, 0, 0, 0, 0, 0, 128, 3, 0, 0, 0, 0, 0, 120, 6, 7, 0, 0, 0, 128, 239, 31, 0, 0, 0, 0, 0, 0, 0, 8, 0, 3, 0, 0, 0, 0, 0, 192, 127, 0, 30, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 211, 64, 0, 0, 0, 128, 248, 7, 0, 0, 3, 0, 0, 0, 0, 0, 0, 88, 1, 0, 128, 0, 192, 31, 31, 0, 0, 0, 0, 0, 0, 0, 0, 255, 92, 0, 0, 64, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 249, 165, 13, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 60, 176, 1, 0, 0, 48, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 248, 167, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 40, 191, 0, 0, 0, 0, 224, 188, 15, 0, 0, 0, 0, 0, 0, 0, 128, 255, 6, 254, 7, 0, 0, 0, 0, 248, 121, 128, 0, 126, 14, 0, 0, 0, 0, 0, 252, 127, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 127, 191, 0, 0, 252, 255, 255, 252, 109, 0, 0, 0, 0, 0, 0, 0, 126, 180, 191, 0, 0, 0, 0, 0, 0, 0, 0, 0, 163, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 24, 0, 0, 0, 0, 0, 0, 0, 31, 0, 0, 0, 0, 0, 0, 0, 127, 0, 0, 128, 7, 0, 0, 0, 0, 0, 0, 0, 0, 96, 0, 0, 0, 0, 0, 0, 0, 0, 160, 195, 7, 248, 231, 15, 0, 0, 0, 60, 0, 0, 28, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255, 255, 127, 248, 255, 255, 255, 255, 255, 31, 32, 0, 16, 0, 0, 248, 254, 255, 0, 0, 127, 255, 255, 249, 219, 7, 0, 0, 0, 0, 127, 0, 0, 0, 0, 0, 240, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 0, 0, 46, 46, 105, 110, 100, 101, 120, 32, 111, 117, 116, 32, 111, 102, 32, 98, 111, 117, 110, 100, 115, 58, 32, 116, 104, 101, 32, 108, 101, 110, 32, 105, 115, 32, 32, 98, 117, 116, 32, 116, 104, 101, 32, 105, 110, 100, 101, 120, 32, 105, 115, 32, 78, 111, 110, 101, 83, 111, 109, 101, 1, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 108, 105, 98, 99, 111, 114, 101, 47, 114, 101, 115, 117, 108, 116, 46, 114, 115, 58, 32, 97, 114, 105, 116, 104, 109, 101, 116, 105, 99, 32, 111, 112, 101, 114, 97, 116, 105, 111, 110, 32, 111, 118, 101, 114, 102, 108, 111, 119, 97, 100, 100, 105, 116, 105, 111, 110, 99, 111, 100, 101, 101, 120, 112, 108, 105, 99, 105, 116, 32, 112, 97, 110, 105, 99, 115, 114, 99, 47, 108, 105, 98, 46, 114, 115, 73, 110, 116, 101, 103, 101, 114, 32, 111, 118, 101, 114, 102, 108, 111, 119, 32, 119, 104, 101, 110, 32, 99, 97, 115, 116, 105, 110, 103, 32, 85, 50, 53, 54, 47, 114, 111, 111, 116, 47, 46, 99, 97, 114, 103, 111, 47, 114, 101, 103, 105, 115, 116, 114, 121, 47, 115, 114, 99, 47, 103, 105, 116, 104, 117, 98, 46, 99, 111, 109, 45, 49, 101, 99, 99, 54, 50, 57, 57, 100, 98, 57, 101, 99, 56, 50, 51, 47, 117, 105, 110, 116, 45, 48, 46, 51, 46, 48, 47, 115, 114, 99, 47, 117, 105, 110, 116, 46, 114, 115, 97, 114, 103, 117, 109, 101, 110, 116, 32, 100, 101, 99, 111, 100, 105, 110, 103, 32, 102, 97, 105, 108, 101, 100, 99, 97, 108, 108, 101, 100, 32, 96, 82, 101, 115, 117, 108, 116, 58, 58, 117, 110, 119, 114, 97, 112, 40, 41, 96, 32, 111, 110, 32, 97, 110, 32, 96, 69, 114, 114, 96, 32, 118, 97, 108, 117, 101, 116, 114, 117, 101, 102, 97, 108, 115, 101, 44, 58, 125, 123, 110, 117, 108, 108, 0, 65, 248, 253, 192, 0, 11, 160, 18, 0, 0, 16, 0, 82, 0, 0, 0, 68, 6, 0, 0, 18, 0, 0, 0, 188, 0, 16, 0, 80, 0, 0, 0, 211, 7, 0, 0, 9, 0, 0, 0, 112, 0, 16, 0, 76, 0, 0, 0, 18, 2, 0, 0, 48, 0, 0, 0, 18, 0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 19, 0, 0, 0, 20, 0, 0, 0, 21, 0, 0, 0, 122, 4, 16, 0, 6, 0, 0, 0, 128, 4, 16, 0, 8, 0, 0, 0, 136, 4, 16, 0, 10, 0, 0, 0, 26, 55, 16, 0, 1, 0, 0, 0, 146, 4, 16, 0, 36, 0, 0, 0, 248, 44, 16, 0, 19, 0, 0, 0, 75, 2, 0, 0, 9, 0, 0, 0, 12, 62, 16, 0, 0, 0, 0, 0, 168, 44, 16, 0, 11, 0, 0, 0, 26, 55, 16, 0, 1, 0, 0, 0, 12, 62, 16, 0, 0, 0, 0, 0, 208, 44, 16, 0, 40, 0, 0, 0, 248, 44, 16, 0, 19, 0, 0, 0, 248, 1, 0, 0, 30, 0, 0, 0, 11, 45, 16, 0, 17, 0, 0, 0, 248, 44, 16, 0, 19, 0, 0, 0, 245, 2, 0, 0, 5, 0, 0, 0, 18, 51, 16, 0, 6, 0, 0, 0, 24, 51, 16, 0, 34, 0, 0, 0, 254, 50, 16, 0, 20, 0, 0, 0, 113, 8, 0, 0, 5, 0, 0, 0, 58, 51, 16, 0, 22, 0, 0, 0, 80, 51, 16, 0, 13, 0, 0, 0, 254, 50, 16, 0, 20, 0, 0, 0, 119, 8, 0, 0, 5, 0, 0, 0, 38, 54, 16, 0, 11, 0, 0, 0, 136, 54, 16, 0, 22, 0, 0, 0, 101, 54, 16, 0, 1, 0, 0, 0, 20, 54, 16, 0, 18, 0, 0, 0, 46, 8, 0, 0, 9, 0, 0, 0, 102, 54, 16, 0, 14, 0, 0, 0, 116, 54, 16, 0, 4, 0, 0, 0, 120, 54, 16, 0, 16, 0, 0, 0, 101, 54, 16, 0, 1, 0, 0, 0, 20, 54, 16, 0, 18, 0, 0, 0, 50, 8, 0, 0, 5, 0, 0, 0, 38, 54, 16, 0, 11, 0, 0, 0, 49, 54, 16, 0, 38, 0, 0, 0, 87, 54, 16, 0, 8, 0, 0, 0, 95, 54, 16, 0, 6, 0, 0, 0, 101, 54, 16, 0, 1, 0, 0, 0, 20, 54, 16, 0, 18, 0, 0, 0, 63, 8, 0, 0, 5, 0, 0, 0, 22, 0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 23, 0, 0, 0, 22, 0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 24, 0, 0, 0, 25, 0, 0, 0, 12, 0, 0, 0, 4, 0, 0, 0, 26, 0, 0, 0, 27, 0, 0, 0, 28, 0, 0, 0, 192, 54, 16, 0, 18, 0, 0, 0, 87, 4, 0, 0, 17, 0, 0, 0, 210, 54, 16, 0, 43, 0, 0, 0, 253, 54, 16, 0, 17, 0, 0, 0, 99, 1, 0, 0, 21, 0, 0, 0, 192, 54, 16, 0, 18, 0, 0, 0, 75, 4, 0, 0, 40, 0, 0, 0, 29, 0, 0, 0, 4, 0, 0, 0, 4, 0, 0, 0, 30, 0, 0, 0, 31, 0, 0, 0, 32, 0, 0, 0, 12, 62, 16, 0, 0, 0, 0, 0, 20, 55, 16, 0, 1, 0, 0, 0, 237, 61, 16, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 248, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 254, 255, 255, 255, 255, 191, 182, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 7, 0, 0, 0, 0, 0, 248, 255, 255, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 192, 159, 159, 61, 0, 0, 0, 0, 2, 0, 0, 0, 255, 255, 255, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 192, 255, 1, 0, 0, 0, 0, 0, 0, 248, 15, 32, 80, 55, 16, 0, 74, 0, 0, 0, 160, 57, 16, 0, 0, 2, 0, 0, 160, 59, 16, 0, 55, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 8, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 2, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 33, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 34, 35, 36, 37, 38, 2, 39, 2, 40, 2, 2, 2, 41, 42, 43, 2, 44, 45, 46, 47, 48, 2, 2, 49, 2, 2, 2, 50, 2, 2, 2, 2, 2, 2, 2, 2, 51, 2, 2, 52, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 53, 2, 54, 2, 55, 2, 2, 2, 2, 2, 2, 2, 2, 56, 2, 57, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 58, 59, 60, 2, 2, 2, 2, 61, 2, 2, 62, 63, 64, 65, 66, 67, 68, 69, 70, 2, 2, 2, 71, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 72, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 73, 2, 2, 2, 2, 2, 59, 2, 0, 1, 2, 2, 2, 2, 3, 2, 2, 2, 2, 4, 2, 5, 6, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 7, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 12, 62, 16, 0, 0, 0, 0, 0, 88, 61, 16, 0, 2, 0, 0, 0, 48, 55, 16, 0, 28, 0, 0, 0, 49, 0, 0, 0, 25, 0, 0, 0, 48, 55, 16, 0, 28, 0, 0, 0, 50, 0, 0, 0, 32, 0, 0, 0, 48, 55, 16, 0, 28, 0, 0, 0, 52, 0, 0, 0, 25, 0, 0, 0, 48, 55, 16, 0, 28, 0, 0, 0, 53, 0, 0, 0, 24, 0, 0, 0, 48, 55, 16, 0, 28, 0, 0, 0, 54, 0, 0, 0, 32, 0, 0, 0, 90, 61, 16, 0, 32, 0, 0, 0, 122, 61, 16, 0, 18, 0, 0, 0, 33, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 34, 0, 0, 0, 12, 62, 16, 0, 0, 0, 0, 0, 237, 61, 16, 0, 2, 0, 0, 0, 220, 61, 16, 0, 17, 0, 0, 0, 241, 3, 0, 0, 5, 0, 0, 0, 239, 61, 16, 0, 29, 0, 0, 0, 82, 62, 16, 0, 76, 0, 0, 0, 98, 5, 0, 0, 1, 0, 0, 0, 48, 62, 16, 0, 34, 0, 0, 0, 82, 62, 16, 0, 76, 0, 0, 0, 98, 5, 0, 0, 1, 0, 0, 0, 24, 62, 16, 0, 14, 0, 0, 0, 38, 62, 16, 0, 10, 0, 0, 0, 28, 0, 0, 0, 1, 0, 0, 0, 0, 65, 152, 144, 193, 0, 11, 208, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         let initial_state = ContractState::new(addr);
         let key = [1u8; 32];
-        let mut engine = WasmEngine::new(&bytecode, 100_000, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 20].to_vec(), initial_state.clone(), "addition".to_string(), key).unwrap();
+        let mut engine = WasmEngine::new(&bytecode, 100_000, [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 20].to_vec(), initial_state.clone(), "addition".to_string(), key, HostCapabilities::default(), GasMultiplier::default()).unwrap();
         engine.compute().unwrap();
         let mut after = super::ContractState {
             contract_address: b"Enigma".sha256(),
@@ -0,0 +1,56 @@
+//! Regenerates the ciphertexts of the test vectors in `enigma-runtime-t/tests/vectors`.
+//! The plaintext, key and IV of every vector are kept as is, only `ciphertext` is rewritten,
+//! so run this after intentionally changing how the state or the deltas are serialized or encrypted:
+//! `cargo run -- ../tests/vectors`
+use enigma_crypto::symmetric;
+use enigma_types::{Hash256, StateKey};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{env, fs, path::Path};
+
+/// Mirrors `enigma_runtime_t::data::ContractState` without the fields that aren't serialized.
+#[derive(Serialize, Deserialize)]
+struct ContractState {
+    json: Value,
+    delta_hash: Hash256,
+    delta_index: u32,
+}
+
+/// Mirrors `enigma_runtime_t::data::StatePatch` without the fields that aren't serialized.
+#[derive(Serialize, Deserialize)]
+struct StatePatch {
+    patch: json_patch::Patch,
+    previous_hash: Hash256,
+}
+
+fn serialize<T: Serialize>(plaintext: &Value) -> Vec<u8> {
+    let obj: T = serde_json::from_value(plaintext.clone()).expect("The plaintext doesn't match the vector kind");
+    let mut buf = Vec::new();
+    obj.serialize(&mut rmp_serde::Serializer::new(&mut buf)).unwrap();
+    buf
+}
+
+fn regenerate(path: &Path) {
+    let mut vector: Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+    let key: StateKey = serde_json::from_value(vector["key"].clone()).unwrap();
+    let iv: [u8; 12] = serde_json::from_value(vector["iv"].clone()).unwrap();
+    let buf = match vector["kind"].as_str() {
+        Some("state") => serialize::<ContractState>(&vector["plaintext"]),
+        Some("patch") => serialize::<StatePatch>(&vector["plaintext"]),
+        kind => panic!("Unknown vector kind {:?} in {}", kind, path.display()),
+    };
+    let ciphertext = symmetric::encrypt_with_nonce(&buf, &key, Some(iv)).unwrap();
+    vector["ciphertext"] = serde_json::to_value(ciphertext).unwrap();
+    fs::write(path, serde_json::to_string_pretty(&vector).unwrap() + "\n").unwrap();
+    println!("Regenerated {}", path.display());
+}
+
+fn main() {
+    let dir = env::args().nth(1).unwrap_or_else(|| "../tests/vectors".to_string());
+    for entry in fs::read_dir(&dir).expect("Failed reading the vectors directory") {
+        let path = entry.unwrap().path();
+        if path.extension().map_or(false, |ext| ext == "json") {
+            regenerate(&path);
+        }
+    }
+}
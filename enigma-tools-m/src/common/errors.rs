@@ -2,6 +2,7 @@
 //! This is a module for the errors of this crate.
 //! we use Failure to handle the Error and Display traits and other conversions.
 //!
+use crate::localstd::string::String;
 use failure::Fail;
 
 /// This Error enum is used to represent errors from this library.
@@ -17,4 +18,15 @@ pub enum ToolsError {
         /// `Err` is the custom message that should explain what and where was the problem.
         err: &'static str
     },
+
+    /// The `LockError` error.
+    ///
+    /// This error means that a lock couldn't be taken, because it's poisoned or because it stayed held for every attempt.
+    #[fail(display = "Failed taking the {} lock: {}", name, err)]
+    LockError {
+        /// The name of the lock.
+        name: String,
+        /// Why it couldn't be taken.
+        err: &'static str,
+    },
 }
@@ -3,25 +3,95 @@
 //! Right now only a trait that can convert `[u8; 64]` to a 20 bytes Ethereum address
 //! or a 20 bytes Ethereum address String in hex representation.
 
-use crate::localstd::string::String;
+use crate::localstd::string::{String, ToString};
+use crate::localstd::sync::{TryLockError, TryLockResult};
+use crate::ToolsError;
 use enigma_crypto::hash::Keccak256;
 use rustc_hex::ToHex;
 
 #[cfg(feature = "sgx")]
 use crate::localstd::sync::{SgxMutex as Mutex, SgxMutexGuard as MutexGuard};
+#[cfg(feature = "sgx")]
+use crate::localstd::sync::{SgxRwLock as RwLock, SgxRwLockReadGuard as RwLockReadGuard, SgxRwLockWriteGuard as RwLockWriteGuard};
 
 #[cfg(feature = "std")]
 use crate::localstd::sync::{Mutex, MutexGuard};
+#[cfg(feature = "std")]
+use crate::localstd::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The default number of attempts of the `*_with_attempts` methods to take a lock.
+/// The enclave has no trusted clock, so they're bounded by a number of attempts and not by a duration.
+pub const DEFAULT_LOCK_ATTEMPTS: u32 = 1_000_000;
+
+fn poisoned(name: &str) -> ToolsError { ToolsError::LockError { name: name.to_string(), err: "the lock is poisoned" } }
+
+/// Tries to take a lock up to `attempts` times, failing right away if it's poisoned.
+fn take_with_attempts<G>(name: &str, attempts: u32, mut try_take: impl FnMut() -> TryLockResult<G>) -> Result<G, ToolsError> {
+    for _ in 0..attempts.max(1) {
+        match try_take() {
+            Ok(guard) => return Ok(guard),
+            Err(TryLockError::Poisoned(_)) => return Err(poisoned(name)),
+            Err(TryLockError::WouldBlock) => core::sync::atomic::spin_loop_hint(),
+        }
+    }
+    Err(ToolsError::LockError { name: name.to_string(), err: "the lock stayed held for every attempt" })
+}
 
 /// A trait that is basically a shortcut for `mutex.lock().expect(format!("{} mutex is posion", name))`
 /// you instead call `mutex.lock_expect(name)` and it will act the same.
 pub trait LockExpectMutex<T> {
     /// See trait documentation. a shortcut for `lock()` and `expect()`
     fn lock_expect(&self, name: &str) -> MutexGuard<T>;
+    /// Waits for the mutex like `lock_expect`, but returns an error instead of panicking if it's poisoned.
+    fn lock_or_error(&self, name: &str) -> Result<MutexGuard<T>, ToolsError>;
+    /// Like `lock_expect` but returns an error if the mutex is poisoned or if it's still held after `attempts` tries,
+    /// so a stuck lock fails the call instead of blocking it forever (see `DEFAULT_LOCK_ATTEMPTS`).
+    fn lock_with_attempts(&self, name: &str, attempts: u32) -> Result<MutexGuard<T>, ToolsError>;
 }
 
 impl<T> LockExpectMutex<T> for Mutex<T> {
     fn lock_expect(&self, name: &str) -> MutexGuard<T> { self.lock().unwrap_or_else(|_| panic!("{} mutex is poison", name)) }
+
+    fn lock_or_error(&self, name: &str) -> Result<MutexGuard<T>, ToolsError> { self.lock().map_err(|_| poisoned(name)) }
+
+    fn lock_with_attempts(&self, name: &str, attempts: u32) -> Result<MutexGuard<T>, ToolsError> {
+        take_with_attempts(name, attempts, || self.try_lock())
+    }
+}
+
+/// The same shortcut as `LockExpectMutex` but for a `RwLock`,
+/// any number of readers can hold the lock together while a writer gets exclusive access.
+pub trait LockExpectRwLock<T> {
+    /// A shortcut for `read()` and `expect()`
+    fn read_expect(&self, name: &str) -> RwLockReadGuard<T>;
+    /// A shortcut for `write()` and `expect()`
+    fn write_expect(&self, name: &str) -> RwLockWriteGuard<T>;
+    /// Waits for the lock like `read_expect`, but returns an error instead of panicking if it's poisoned.
+    fn read_or_error(&self, name: &str) -> Result<RwLockReadGuard<T>, ToolsError>;
+    /// Waits for the lock like `write_expect`, but returns an error instead of panicking if it's poisoned.
+    fn write_or_error(&self, name: &str) -> Result<RwLockWriteGuard<T>, ToolsError>;
+    /// Like `read_expect` but gives up after `attempts` tries, see `LockExpectMutex::lock_with_attempts`.
+    fn read_with_attempts(&self, name: &str, attempts: u32) -> Result<RwLockReadGuard<T>, ToolsError>;
+    /// Like `write_expect` but gives up after `attempts` tries, see `LockExpectMutex::lock_with_attempts`.
+    fn write_with_attempts(&self, name: &str, attempts: u32) -> Result<RwLockWriteGuard<T>, ToolsError>;
+}
+
+impl<T> LockExpectRwLock<T> for RwLock<T> {
+    fn read_expect(&self, name: &str) -> RwLockReadGuard<T> { self.read().unwrap_or_else(|_| panic!("{} lock is poison", name)) }
+
+    fn write_expect(&self, name: &str) -> RwLockWriteGuard<T> { self.write().unwrap_or_else(|_| panic!("{} lock is poison", name)) }
+
+    fn read_or_error(&self, name: &str) -> Result<RwLockReadGuard<T>, ToolsError> { self.read().map_err(|_| poisoned(name)) }
+
+    fn write_or_error(&self, name: &str) -> Result<RwLockWriteGuard<T>, ToolsError> { self.write().map_err(|_| poisoned(name)) }
+
+    fn read_with_attempts(&self, name: &str, attempts: u32) -> Result<RwLockReadGuard<T>, ToolsError> {
+        take_with_attempts(name, attempts, || self.try_read())
+    }
+
+    fn write_with_attempts(&self, name: &str, attempts: u32) -> Result<RwLockWriteGuard<T>, ToolsError> {
+        take_with_attempts(name, attempts, || self.try_write())
+    }
 }
 
 /// A trait to convert an object into an Ethereum Address
@@ -49,3 +119,129 @@ impl EthereumAddress<String, [u8; 20]> for [u8; 64] {
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc, Barrier};
+    use std::thread;
+
+    #[test]
+    fn test_lock_with_attempts() {
+        let mutex = Arc::new(Mutex::new(0u32));
+        *mutex.lock_with_attempts("Test", 1).unwrap() += 1;
+
+        // Another thread holds the lock until it's told to release it.
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let holder = {
+            let mutex = Arc::clone(&mutex);
+            thread::spawn(move || {
+                let _guard = mutex.lock_expect("Test");
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            })
+        };
+        locked_rx.recv().unwrap();
+        match mutex.lock_with_attempts("Test", 1000) {
+            Err(ToolsError::LockError { name, .. }) => assert_eq!(name, "Test"),
+            other => panic!("Expected a lock error, got: {:?}", other.map(|guard| *guard)),
+        }
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+        assert_eq!(*mutex.lock_with_attempts("Test", 1).unwrap(), 1);
+
+        // A poisoned lock is an error too, instead of a panic.
+        let poisoned = Arc::clone(&mutex);
+        assert!(thread::spawn(move || {
+            let _guard = poisoned.lock_expect("Test");
+            panic!("poisoning the lock");
+        }).join().is_err());
+        assert!(mutex.lock_with_attempts("Test", DEFAULT_LOCK_ATTEMPTS).is_err());
+        assert!(mutex.lock_or_error("Test").is_err());
+    }
+
+    #[test]
+    fn test_rwlock_or_error_waits() {
+        let lock = Arc::new(RwLock::new(0u32));
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let writer = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || {
+                let mut guard = lock.write_expect("Test");
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                *guard += 1;
+            })
+        };
+        locked_rx.recv().unwrap();
+        // The reader waits for the writer however long it holds the lock, instead of failing.
+        let reader = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || *lock.read_or_error("Test").unwrap())
+        };
+        release_tx.send(()).unwrap();
+        writer.join().unwrap();
+        assert_eq!(reader.join().unwrap(), 1);
+        *lock.write_or_error("Test").unwrap() += 1;
+
+        let poisoned = Arc::clone(&lock);
+        assert!(thread::spawn(move || {
+            let _guard = poisoned.write_expect("Test");
+            panic!("poisoning the lock");
+        }).join().is_err());
+        assert!(lock.read_or_error("Test").is_err());
+        assert!(lock.write_or_error("Test").is_err());
+    }
+
+    #[test]
+    fn test_rwlock_concurrent_readers() {
+        let lock = Arc::new(RwLock::new(0u32));
+        // Every reader holds its guard until all of them hold one, which deadlocks if the readers take turns.
+        let barrier = Arc::new(Barrier::new(4));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let (lock, barrier) = (Arc::clone(&lock), Arc::clone(&barrier));
+            thread::spawn(move || {
+                let guard = lock.read_or_error("Test").unwrap();
+                barrier.wait();
+                *guard
+            })
+        }).collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), 0);
+        }
+
+        // Readers that run next to a writer see either the old or the new value, never a partial write.
+        let writer = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    *lock.write_or_error("Test").unwrap() += 2;
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_| {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || (0..100).for_each(|_| assert_eq!(*lock.read_or_error("Test").unwrap() % 2, 0)))
+        }).collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(*lock.read_or_error("Test").unwrap(), 200);
+    }
+
+    #[test]
+    fn test_rwlock_with_attempts() {
+        let lock = RwLock::new(0u32);
+        {
+            let _reader = lock.read_with_attempts("Test", 1).unwrap();
+            // Readers share the lock while a writer has to wait for them.
+            assert!(lock.read_with_attempts("Test", 1).is_ok());
+            assert!(lock.write_with_attempts("Test", 1000).is_err());
+        }
+        *lock.write_with_attempts("Test", 1).unwrap() += 1;
+        assert_eq!(*lock.read_with_attempts("Test", 1).unwrap(), 1);
+    }
+}
@@ -6,25 +6,78 @@ use log_derive::logfn;
 
 use bigint;
 use crate::ethabi::{encode, Address, Bytes, Token};
-use crate::ethereum_types::{H160, U256};
+use crate::ethereum_types::{H160, H256, U256};
 use enigma_crypto::hash::Keccak256;
 use enigma_types::ContractAddress;
 pub use rlp::{decode, encode as rlpEncode, Encodable, Decodable, DecoderError, UntrustedRlp, RlpStream};
 
 pub const EPOCH_CAP: usize = 2;
+/// The most selection rounds (nonces) tried when filling a group of workers.
+pub const MAX_SELECTION_ROUNDS: u64 = 1024;
+
+// Both `ethereum_types` and `bigint` store the hashes as big-endian bytes
+// and the integers as 64 bit limbs ordered from the least significant one,
+// so the conversions between them copy the inner representation as is.
+// When converting raw bytes into a bigint use `bigint_be`/`bigint_le` which state the byte order.
 
 pub trait FromBigint<T>: Sized {
     fn from_bigint(_: T) -> Self;
 }
 
+pub trait IntoBigint<T> {
+    fn into_bigint(self) -> T;
+}
+
 impl FromBigint<bigint::H160> for H160 {
+    /// Same big-endian bytes.
     fn from_bigint(b: bigint::H160) -> Self { H160(b.0) }
 }
 
+impl FromBigint<bigint::H256> for H256 {
+    /// Same big-endian bytes.
+    fn from_bigint(b: bigint::H256) -> Self { H256(b.0) }
+}
+
 impl FromBigint<bigint::U256> for U256 {
+    /// Same limbs, least significant first.
     fn from_bigint(b: bigint::U256) -> Self { U256(b.0) }
 }
 
+impl IntoBigint<bigint::H160> for H160 {
+    /// Same big-endian bytes.
+    fn into_bigint(self) -> bigint::H160 { bigint::H160(self.0) }
+}
+
+impl IntoBigint<bigint::H256> for H256 {
+    /// Same big-endian bytes.
+    fn into_bigint(self) -> bigint::H256 { bigint::H256(self.0) }
+}
+
+impl IntoBigint<bigint::U256> for U256 {
+    /// Same limbs, least significant first.
+    fn into_bigint(self) -> bigint::U256 { bigint::U256(self.0) }
+}
+
+/// Read 32 bytes as a big-endian integer, the first byte is the most significant.
+pub fn bigint_be(bytes: &[u8; 32]) -> bigint::U256 {
+    let mut limbs = [0u64; 4];
+    for (i, limb) in limbs.iter_mut().enumerate() {
+        let start = 32 - (i + 1) * 8;
+        *limb = bytes[start..start + 8].iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+    }
+    bigint::U256(limbs)
+}
+
+/// Read 32 bytes as a little-endian integer, the first byte is the least significant.
+pub fn bigint_le(bytes: &[u8; 32]) -> bigint::U256 {
+    let mut limbs = [0u64; 4];
+    for (i, limb) in limbs.iter_mut().enumerate() {
+        let start = i * 8;
+        *limb = bytes[start..start + 8].iter().rev().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+    }
+    bigint::U256(limbs)
+}
+
 pub trait RawEncodable {
     fn raw_encode(&self) -> Bytes;
 }
@@ -52,6 +105,31 @@ pub struct InputWorkerParams {
 }
 
 impl InputWorkerParams {
+    /// Sort the workers by their address, keeping every stake next to its worker.
+    /// Only the encoding is canonical (see `rlp_append`), the workers themselves stay in the contract's order
+    /// since the worker selection and the epoch signature depend on it.
+    fn canonicalize(&mut self) {
+        if self.workers.len() != self.stakes.len() {
+            return;
+        }
+        let mut pairs: Vec<(Address, U256)> = self.workers.iter().cloned().zip(self.stakes.iter().cloned()).collect();
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        let (workers, stakes) = pairs.into_iter().unzip();
+        self.workers = workers;
+        self.stakes = stakes;
+    }
+
+    /// RLP encodes the parameters with the workers in their order rather than the canonical one,
+    /// this is how they're passed to the enclave, which selects the workers and signs the epoch with the contract's order.
+    pub fn rlp_in_order(&self) -> Vec<u8> { rlpEncode(&InOrder(self)).to_vec() }
+
+    fn append_fields(&self, s: &mut RlpStream) {
+        s.begin_list(3);
+        s.append(&self.km_block_number.into_bigint());
+        s.append_list(&self.workers.iter().map(|a| a.into_bigint()).collect::<Vec<bigint::H160>>());
+        s.append_list(&self.stakes.iter().map(|b| b.into_bigint()).collect::<Vec<bigint::U256>>());
+    }
+
     /// Run the worker selection algorithm against the current epoch
     ///
     /// # Arguments
@@ -70,8 +148,17 @@ impl InputWorkerParams {
         }
     }
 
+    /// Run the worker selection algorithm for a group of workers against the current epoch
+    ///
+    /// # Arguments
+    ///
+    /// * `sc_addr` - The Secret Contract address
+    /// * `seed` - The random seed for the selected epoch
+    /// * `group_size` - The amount of workers to select, defaults to 1.
+    ///   If there aren't enough unique workers that can be selected the group is capped to the available ones.
+    ///
     #[logfn(DEBUG)]
-    fn get_selected_workers(&self, sc_addr: ContractAddress, seed: U256, group_size: Option<u64>) -> Vec<Address> {
+    pub fn get_selected_workers(&self, sc_addr: ContractAddress, seed: U256, group_size: Option<u64>) -> Vec<Address> {
         let mut selected_workers = Vec::new();
         if self.workers.is_empty() || self.workers.len() != self.stakes.len() {
             debug!("Invalid worker selection parameters {:?}", self);
@@ -81,35 +168,59 @@ impl InputWorkerParams {
         for &balance in &self.stakes {
             balance_sum += balance;
         }
+        // Like the Enigma contract, which refuses to select when there's no stake at all.
+        if balance_sum.is_zero() {
+            debug!("No stakes to select by {:?}", self);
+            return selected_workers;
+        }
         // Using the same type as the Enigma contract
         let mut nonce = U256::zero();
-        let group_size = group_size.unwrap_or(1);
-
-        while selected_workers.len() < group_size as usize {
-            let token = WorkerSelectionToken { seed, sc_addr, nonce };
-            // This is equivalent to encodePacked in Solidity
-            let hash = token.raw_encode().keccak256();
-            let mut rand_val: U256 = U256::from(*hash) % balance_sum;
-            debug!("The initial random value: {:?}", rand_val.0);
-            let mut selected_worker = self.workers.last().unwrap();
-
-            for (i, worker) in self.workers.iter().enumerate() {
-                let (new_rand, overflow) = rand_val.overflowing_sub(self.stakes[i]);
-                if overflow || new_rand.is_zero() {
-                    selected_worker = worker;
-                    break;
-                }
-                rand_val = new_rand;
-                debug!("The next random value: {:?}", rand_val.0);
+        // Workers without a stake are never selected (unless first in the list and the random value is 0),
+        // and the same address can't be in the group twice. The contract never returns for a larger group.
+        let mut eligible = Vec::with_capacity(self.workers.len());
+        for (worker, stake) in self.workers.iter().zip(&self.stakes) {
+            if !stake.is_zero() && !eligible.contains(worker) {
+                eligible.push(*worker);
             }
-            if !selected_workers.contains(selected_worker) {
-                selected_workers.push(*selected_worker);
+        }
+        let mut group_size = group_size.unwrap_or(1);
+        if group_size > eligible.len() as u64 {
+            debug!("Capping the group size {} to the {} eligible workers", group_size, eligible.len());
+            group_size = eligible.len() as u64;
+        }
+
+        while selected_workers.len() < group_size as usize && nonce < MAX_SELECTION_ROUNDS.into() {
+            let selected_worker = self.weighted_selection(sc_addr, seed, nonce, balance_sum);
+            if !selected_workers.contains(&selected_worker) {
+                selected_workers.push(selected_worker);
             }
             nonce += 1.into();
         }
         debug!("The selected workers: {:?}", selected_workers);
         selected_workers
     }
+
+    /// Walk the workers with a random value in the range of the total stake,
+    /// so each worker is selected with probability proportional to its stake.
+    /// This is the contract's selection whatever the stakes are, equal stakes included, so every node agrees with it.
+    fn weighted_selection(&self, sc_addr: ContractAddress, seed: U256, nonce: U256, balance_sum: U256) -> Address {
+        let token = WorkerSelectionToken { seed, sc_addr, nonce };
+        // This is equivalent to encodePacked in Solidity
+        let hash = token.raw_encode().keccak256();
+        let mut rand_val: U256 = U256::from(*hash) % balance_sum;
+        debug!("The initial random value: {:?}", rand_val.0);
+
+        for (i, worker) in self.workers.iter().enumerate() {
+            let (new_rand, overflow) = rand_val.overflowing_sub(self.stakes[i]);
+            if overflow || new_rand.is_zero() {
+                return *worker;
+            }
+            rand_val = new_rand;
+            debug!("The next random value: {:?}", rand_val.0);
+        }
+        // The random value is below the total stake, so the walk always ends on a worker before this.
+        self.workers[self.workers.len() - 1]
+    }
 }
 
 impl Decodable for InputWorkerParams {
@@ -123,10 +234,188 @@ impl Decodable for InputWorkerParams {
 }
 
 impl Encodable for InputWorkerParams {
+    /// Always encodes the canonical order of the workers, regardless of the order they're in.
     fn rlp_append(&self, s: &mut RlpStream) {
-        s.begin_list(3);
-        s.append(&bigint::U256(self.km_block_number.0));
-        s.append_list(&self.workers.iter().map(|a| bigint::H160(a.0)).collect::<Vec<_>>());
-        s.append_list(&self.stakes.iter().map(|b| bigint::U256(b.0)).collect::<Vec<_>>());
+        let mut params = self.clone();
+        params.canonicalize();
+        params.append_fields(s);
+    }
+}
+
+/// Encodes `InputWorkerParams` without sorting the workers, see `InputWorkerParams::rlp_in_order`.
+struct InOrder<'a>(&'a InputWorkerParams);
+
+impl<'a> Encodable for InOrder<'a> {
+    fn rlp_append(&self, s: &mut RlpStream) { self.0.append_fields(s) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known_u256() -> U256 { (U256::from(0xdead_beef_u64) << 128) + U256::from(42) }
+
+    fn known_bigint() -> bigint::U256 { (bigint::U256::from(0xdead_beef_u64) << 128) + bigint::U256::from(42) }
+
+    #[test]
+    fn test_u256_into_bigint() {
+        assert_eq!(known_u256().into_bigint(), known_bigint());
+        assert_eq!(U256::from_bigint(known_bigint()), known_u256());
+    }
+
+    #[test]
+    fn test_h256_into_bigint() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0xab;
+        bytes[31] = 1;
+        let hash: bigint::H256 = H256(bytes).into_bigint();
+        assert_eq!(hash.0, bytes);
+        assert_eq!(H256::from_bigint(hash), H256(bytes));
+    }
+
+    #[test]
+    fn test_equal_stakes_selection() {
+        let workers = vec![H160([0x11; 20]), H160([0x22; 20]), H160([0x33; 20])];
+        let mut params = InputWorkerParams { km_block_number: 1.into(), workers, stakes: vec![100.into(); 3] };
+        let sc_addr = ContractAddress::from([0xaa; 32]);
+        let seed = U256::from(1234);
+        // keccak256(seed, sc_addr, 0) % 300 is 171, which the walk ends on at the second worker.
+        let expected = H160([0x22; 20]);
+
+        let selected = params.get_selected_worker(sc_addr, seed).unwrap();
+        assert_eq!(selected, expected);
+        for _ in 0..10 {
+            assert_eq!(params.get_selected_worker(sc_addr, seed), Some(selected));
+        }
+        // Like in the contract the walk goes by the order of the workers.
+        params.workers.swap(1, 2);
+        assert_eq!(params.get_selected_worker(sc_addr, seed), Some(H160([0x33; 20])));
+        // Without any stake there's nothing to select by.
+        params.stakes = vec![0.into(); 3];
+        assert_eq!(params.get_selected_worker(sc_addr, seed), None);
+    }
+
+    #[test]
+    fn test_group_size_above_workers() {
+        let workers = vec![H160([0x11; 20]), H160([0x22; 20]), H160([0x33; 20])];
+        let params = InputWorkerParams { km_block_number: 1.into(), workers, stakes: vec![100.into(), 200.into(), 300.into()] };
+        let sc_addr = ContractAddress::from([0xaa; 32]);
+
+        let mut selected = params.get_selected_workers(sc_addr, 1234.into(), Some(5));
+        assert_eq!(selected.len(), 3);
+        selected.sort();
+        assert_eq!(selected, params.workers);
+    }
+
+    /// The worker selection of the Enigma contract (`selectWorkerGroupImpl`) ported statement by statement,
+    /// with plain integers and byte arrays, to cross-check `get_selected_workers` against.
+    /// It doesn't skip workers without a stake nor caps the group or the rounds, so like the contract
+    /// it never returns for a group larger than the workers with a stake, and reverts (returns nothing) without stakes.
+    fn contract_selection(sc_addr: [u8; 32], seed: u64, workers: &[[u8; 20]], stakes: &[u64], group_size: usize) -> Vec<[u8; 20]> {
+        let word = |n: u64| { let mut w = [0u8; 32]; w[24..].copy_from_slice(&n.to_be_bytes()); w };
+        // uint tokenCpt = sum(stakes); require(tokenCpt > 0)
+        let token_cpt: u128 = stakes.iter().map(|s| u128::from(*s)).sum();
+        if token_cpt == 0 {
+            return Vec::new();
+        }
+        let mut selected_workers: Vec<[u8; 20]> = Vec::with_capacity(group_size);
+        let mut nonce = 0u64;
+        for _ in 0..group_size {
+            // do { ... } while (the worker is already in the group)
+            let selected_worker = loop {
+                // int randVal = int256(uint256(keccak256(abi.encodePacked(_seed, _scAddr, nonce))) % tokenCpt)
+                let hash = [&word(seed)[..], &sc_addr[..], &word(nonce)[..]].concat().keccak256();
+                let mut rand_val = hash.iter().fold(0u128, |acc, b| (acc * 256 + u128::from(*b)) % token_cpt) as i128;
+                let mut k = 0;
+                rand_val -= i128::from(stakes[k]);
+                while rand_val > 0 {
+                    k += 1;
+                    rand_val -= i128::from(stakes[k]);
+                }
+                nonce += 1;
+                if !selected_workers.contains(&workers[k]) {
+                    break workers[k];
+                }
+            };
+            selected_workers.push(selected_worker);
+        }
+        selected_workers
+    }
+
+    #[test]
+    fn test_selection_against_contract() {
+        // xorshift64, so the cases are random but the same in every run.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || { state ^= state << 13; state ^= state >> 7; state ^= state << 17; state };
+
+        for _ in 0..200 {
+            let count = (next() % 8 + 1) as usize;
+            let workers: Vec<[u8; 20]> = (0..count).map(|_| { let mut w = [0u8; 20]; w[..8].copy_from_slice(&next().to_be_bytes()); w }).collect();
+            // Mostly different stakes, but also cases with zero stakes and with equal stakes.
+            let stakes: Vec<u64> = match next() % 4 {
+                0 => vec![next() % 1000; count],
+                1 => (0..count).map(|_| next() % 3).collect(),
+                _ => (0..count).map(|_| next() % 1000 + 1).collect(),
+            };
+            let mut sc_addr = [0u8; 32];
+            sc_addr[..8].copy_from_slice(&next().to_be_bytes());
+            let seed = next();
+            // Only groups the contract can fill, it never returns for larger ones.
+            let with_stake = stakes.iter().filter(|s| **s != 0).count();
+            let group_size = ((next() % 5 + 1) as usize).min(with_stake);
+
+            let params = InputWorkerParams {
+                km_block_number: 1.into(),
+                workers: workers.iter().map(|w| H160(*w)).collect(),
+                stakes: stakes.iter().map(|s| U256::from(*s)).collect(),
+            };
+            let selected = params.get_selected_workers(sc_addr.into(), seed.into(), Some(group_size as u64));
+            let expected: Vec<H160> = contract_selection(sc_addr, seed, &workers, &stakes, group_size).into_iter().map(H160).collect();
+            // A group that takes more than `MAX_SELECTION_ROUNDS` is cut short, but agrees with the contract as far as it goes.
+            if selected.len() < group_size {
+                assert!(expected.starts_with(&selected), "workers: {:?}, stakes: {:?}, seed: {}", workers, stakes, seed);
+            } else {
+                assert_eq!(selected, expected, "workers: {:?}, stakes: {:?}, seed: {}", workers, stakes, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn test_canonical_encoding() {
+        let workers = vec![H160([0x33; 20]), H160([0x11; 20]), H160([0x44; 20]), H160([0x22; 20])];
+        let stakes: Vec<U256> = vec![3.into(), 1.into(), 4.into(), 2.into()];
+        let params = InputWorkerParams { km_block_number: 10.into(), workers: workers.clone(), stakes: stakes.clone() };
+        let encoded = rlpEncode(&params).to_vec();
+
+        for rotation in 1..workers.len() {
+            let (mut shuffled_workers, mut shuffled_stakes) = (workers.clone(), stakes.clone());
+            shuffled_workers.rotate_left(rotation);
+            shuffled_stakes.rotate_left(rotation);
+            shuffled_workers.swap(0, 1);
+            shuffled_stakes.swap(0, 1);
+            let shuffled = InputWorkerParams { km_block_number: 10.into(), workers: shuffled_workers, stakes: shuffled_stakes };
+            assert_eq!(rlpEncode(&shuffled).to_vec(), encoded);
+        }
+
+        let decoded: InputWorkerParams = decode(&encoded);
+        assert_eq!(decoded.workers, vec![H160([0x11; 20]), H160([0x22; 20]), H160([0x33; 20]), H160([0x44; 20])]);
+        assert_eq!(decoded.stakes, vec![1.into(), 2.into(), 3.into(), 4.into()]);
+        // Encoded in order, the workers keep their order through decoding, so the selection runs on the contract's order.
+        let decoded: InputWorkerParams = decode(&params.rlp_in_order());
+        assert_eq!((decoded.workers, decoded.stakes), (workers, stakes));
+    }
+
+    #[test]
+    fn test_bigint_endianness() {
+        let mut bytes = [0u8; 32];
+        bytes[31] = 42;
+        bytes[15] = 0xef;
+        bytes[14] = 0xbe;
+        bytes[13] = 0xad;
+        bytes[12] = 0xde;
+        assert_eq!(bigint_be(&bytes), known_bigint());
+        bytes.reverse();
+        assert_eq!(bigint_le(&bytes), known_bigint());
+        assert_eq!(bigint_be(&bytes), (bigint::U256::from(42) << 248) + (bigint::U256::from(0xefbe_adde_u64) << 96));
     }
 }
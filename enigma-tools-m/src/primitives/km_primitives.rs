@@ -3,6 +3,7 @@
 //! And the User for exchanging DH keys.
 
 use crate::common::errors::ToolsError::{self, MessagingError};
+use crate::primitives::signature_domain::SignatureDomain;
 use crate::localstd::vec::Vec;
 use crate::rmp_serde::{Deserializer, Serializer};
 use crate::serde::{Deserialize, Serialize};
@@ -13,6 +14,10 @@ use enigma_types::{ContractAddress, DhKey, PubKey, StateKey};
 /// A Message ID type, used to identify each message to the response.
 pub type MsgID = [u8; 12];
 
+/// The state key of a contract and the address of the worker the KM node selected for it,
+/// the deltas of the contract are only accepted from a worker the KM node selected.
+pub type ProvisionedKey = (ContractAddress, StateKey, [u8; 20]);
+
 /// the size of the publicKey in the network.
 pub const PUB_KEY_SIZE: usize = 64;
 
@@ -30,8 +35,8 @@ pub fn verify_key_size(pubkey: &[u8]) -> Result<(), ToolsError> {
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(crate = "crate::serde")]
 pub enum PrincipalMessageType {
-    /// A Response from the KM node, containing a list of (Address, Key) tuples.
-    Response(Vec<(ContractAddress, StateKey)>),
+    /// A Response from the KM node, containing a list of (Address, Key, Selected Worker) tuples.
+    Response(Vec<ProvisionedKey>),
     /// A Request for the KM node.
     // todo: split PrincipalMessage into PrincipalRequestMessage and PrincipalResponseMessage
     // todo: in order to remove redundant fields (data is not needed for ptt request)
@@ -171,6 +176,124 @@ impl<'a> Encryption<&'a DhKey, CryptoError, Self, [u8; 12]> for PrincipalMessage
     }
 }
 
+impl PrincipalMessage {
+    /// Decrypts a packed PTT response with the DH key derived from the public key it carries.
+    pub fn decrypt_ptt_response(self, key: &DhKey) -> Result<PttResponse, CryptoError> {
+        let dec = Self::decrypt(self, key)?;
+        match dec.data {
+            PrincipalMessageType::Response(keys) => Ok(PttResponse { keys, pubkey: dec.pubkey, id: dec.id }),
+            _ => Err(CryptoError::DecryptionError),
+        }
+    }
+}
+
+/// A PTT request of a worker for the state keys of its contracts,
+/// the typed form of a `PrincipalMessage` holding a `Request`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(crate = "crate::serde")]
+pub struct PttRequest {
+    /// The DH public key of the worker, the response is encrypted with a key derived from it.
+    pub pubkey: Vec<u8>,
+    /// The id of the request, the response carries the same one.
+    pub id: MsgID,
+}
+
+impl PttRequest {
+    /// Creates a request with a random id.
+    pub fn new(pubkey: PubKey) -> Result<Self, CryptoError> {
+        let mut id = [0u8; 12];
+        rand::random(&mut id)?;
+        Ok(Self { pubkey: pubkey.to_vec(), id })
+    }
+
+    /// The bytes the worker signs, the same as [`PrincipalMessage::to_sign()`] of the request.
+    pub fn to_sign(&self) -> Result<Vec<u8>, ToolsError> { PrincipalMessage::from(self.clone()).to_sign() }
+
+    /// This will serialize the request using MessagePack, in the format of `PrincipalMessage`.
+    pub fn into_message(self) -> Result<Vec<u8>, ToolsError> { PrincipalMessage::from(self).into_message() }
+
+    /// This will deserialize a request packed by `into_message`, fails on any other `PrincipalMessage`
+    /// and on a public key that isn't `PUB_KEY_SIZE` bytes.
+    pub fn from_message(msg: &[u8]) -> Result<Self, ToolsError> {
+        let msg = PrincipalMessage::from_message(msg)?;
+        if msg.data != PrincipalMessageType::Request {
+            return Err(MessagingError { err: "the message isn't a PTT request" });
+        }
+        verify_key_size(&msg.pubkey)?;
+        Ok(Self { pubkey: msg.pubkey, id: msg.id })
+    }
+
+    /// Will return the DH public key of the worker, the request must have been checked by `from_message`.
+    pub fn get_pubkey(&self) -> PubKey {
+        let mut pubkey = [0u8; 64];
+        pubkey.copy_from_slice(&self.pubkey[..]);
+        pubkey
+    }
+}
+
+impl From<PttRequest> for PrincipalMessage {
+    fn from(req: PttRequest) -> Self { Self { data: PrincipalMessageType::Request, pubkey: req.pubkey, id: req.id } }
+}
+
+/// The response of the KM node to a `PttRequest`,
+/// the typed form of a `PrincipalMessage` holding a `Response` (it's packed encrypted, as an `EncryptedResponse`).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(crate = "crate::serde")]
+pub struct PttResponse {
+    /// The state key of every requested contract and the worker selected for it.
+    pub keys: Vec<ProvisionedKey>,
+    /// The DH public key of the KM node.
+    pub pubkey: Vec<u8>,
+    /// The id of the request this responds to.
+    pub id: MsgID,
+}
+
+impl PttResponse {
+    /// Creates the response to `request` by a KM node with the DH public key `pubkey`.
+    pub fn new(request: &PttRequest, keys: Vec<ProvisionedKey>, pubkey: PubKey) -> Self {
+        Self { keys, pubkey: pubkey.to_vec(), id: request.id }
+    }
+
+    /// This will encrypt the keys with the DH key shared with the worker and serialize the response using MessagePack,
+    /// the worker unpacks it with [`PrincipalMessage::from_message()`] and [`PrincipalMessage::decrypt_ptt_response()`].
+    pub fn into_message(self, key: &DhKey) -> Result<Vec<u8>, ToolsError> {
+        let msg = PrincipalMessage { data: PrincipalMessageType::Response(self.keys), pubkey: self.pubkey, id: self.id };
+        msg.encrypt(key).map_err(|_| MessagingError { err: "can't encrypt the response" })?.into_message()
+    }
+
+    /// Will return the DH public key of the KM node.
+    pub fn get_pubkey(&self) -> PubKey {
+        let mut pubkey = [0u8; 64];
+        pubkey.copy_from_slice(&self.pubkey[..]);
+        pubkey
+    }
+}
+
+/// How the enclave signs a `UserMessage`, a client that doesn't ask for a version gets the `Legacy` signature it already verifies.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserKeySigVersion {
+    /// A signature over [`UserMessage::to_sign`].
+    Legacy = 1,
+    /// A signature over [`UserMessage::to_sign`] tagged with [`SignatureDomain::UserKey`].
+    Tagged = 2,
+}
+
+impl UserKeySigVersion {
+    /// The version numbered `version`, as it's passed to the enclave.
+    pub fn from_u32(version: u32) -> Result<Self, ToolsError> {
+        match version {
+            1 => Ok(UserKeySigVersion::Legacy),
+            2 => Ok(UserKeySigVersion::Tagged),
+            _ => Err(MessagingError { err: "Unknown version of the user key signature" }),
+        }
+    }
+}
+
+impl Default for UserKeySigVersion {
+    fn default() -> Self { UserKeySigVersion::Legacy }
+}
+
 /// A struct to represent the UserMessage for the key exchange.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(crate = "crate::serde")]
@@ -196,6 +319,14 @@ impl UserMessage {
         hash::prepare_hash_multiple(&to_sign)
     }
 
+    /// The bytes the enclave signs for a signature of `version`.
+    pub fn to_sign_versioned(&self, version: UserKeySigVersion) -> Vec<u8> {
+        match version {
+            UserKeySigVersion::Legacy => self.to_sign(),
+            UserKeySigVersion::Tagged => SignatureDomain::UserKey.tagged(&self.to_sign()),
+        }
+    }
+
     /// This will serialize the Message using MessagePack.
     pub fn into_message(self) -> Result<Vec<u8>, ToolsError> {
         let mut buf = Vec::new();
@@ -225,7 +356,9 @@ impl UserMessage {
 
 #[cfg(test)]
 mod tests {
-    use super::{PrincipalMessage, PrincipalMessageType};
+    use super::{PrincipalMessage, PrincipalMessageType, PttRequest, PttResponse, UserKeySigVersion, UserMessage};
+    use crate::primitives::signature_domain::SignatureDomain;
+    use enigma_crypto::asymmetric::KeyPair;
     use enigma_crypto::hash::Sha256;
     use enigma_crypto::Encryption;
 
@@ -255,7 +388,7 @@ mod tests {
 
     #[test]
     fn test_encrypt_response() {
-        let enc = vec![195, 38, 192, 74, 88, 16, 137, 135, 207, 55, 231, 118, 249, 61, 195, 224, 63, 196, 241, 106, 78, 168, 173, 219, 207, 22, 170, 96, 122, 179, 196, 113, 182, 144, 124, 131, 226, 232, 197, 171, 8, 246, 211, 64, 243, 184, 206, 230, 208, 207, 182, 72, 131, 6, 120, 95, 206, 187, 5, 93, 183, 180, 62, 183, 196, 11, 161, 203, 226, 45, 171, 108, 240, 120, 203, 145, 26, 247, 128, 9, 133, 13, 233, 105, 131, 99, 154, 6, 136, 88, 112, 186, 196, 210, 190, 247, 96, 113, 70, 241, 163, 162, 242, 40, 207, 117, 148, 38, 133, 234, 100, 9, 6, 238, 251, 81, 181, 13, 139, 88, 187, 66, 195, 170, 245, 237, 230, 180, 217, 83, 84, 177, 247, 58, 173, 30, 222, 194, 21, 38, 221, 165, 196, 101, 20, 147, 103, 149, 3, 254, 248, 85, 234, 40, 48, 99, 143, 202, 4, 136, 97, 99, 71, 199, 145, 211, 106, 211, 10, 13, 212, 56, 205, 83, 38, 26, 172, 102, 146, 188, 97, 216, 195, 40, 65, 11, 156, 142, 206, 109, 224, 203, 26, 246, 51, 228, 203, 16, 143, 0, 224, 169, 119, 107, 133, 160, 125, 6, 57, 215, 241, 69, 189, 70, 30, 133, 117, 163, 77, 46, 166, 104, 204, 131, 247, 184, 139, 199, 104, 247, 72, 236, 187, 239, 245, 221, 81, 177, 206, 226, 9, 213, 226, 55, 119, 203, 44, 11, 47, 4, 152, 92, 202, 63, 68, 13, 34, 247, 12, 194, 170, 198, 35, 158, 95, 2, 22, 10, 128, 65, 254, 105, 194, 211, 14, 40, 248, 180, 84, 74, 147, 235, 226, 101, 81, 94, 57, 158, 3, 225, 145, 164, 141, 134, 157, 235, 199, 203, 180, 58, 131, 20, 41, 12, 202, 137, 49, 164, 239, 209, 182, 86, 146, 218, 12, 167, 211, 41, 216, 162, 24, 109, 136, 221, 234, 253, 193, 114, 145, 15, 188, 218, 48, 221, 247, 157, 210, 57, 238, 19, 209, 251, 102, 142, 100, 57, 221, 85, 38, 88, 191, 169, 128, 230, 8, 181, 156, 210, 190, 118, 13, 68, 47, 138, 4, 130, 174, 77, 76, 232, 70, 181, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
+        let enc = vec![195, 39, 192, 74, 88, 16, 137, 135, 207, 55, 231, 118, 249, 61, 195, 224, 63, 196, 241, 106, 78, 168, 173, 219, 207, 22, 170, 96, 122, 179, 196, 113, 182, 144, 124, 131, 226, 232, 197, 171, 8, 246, 211, 64, 243, 184, 206, 230, 208, 207, 182, 72, 131, 6, 120, 95, 206, 187, 5, 93, 183, 180, 62, 183, 196, 11, 161, 203, 226, 45, 171, 108, 190, 164, 223, 177, 27, 246, 129, 8, 132, 12, 232, 104, 130, 98, 155, 7, 137, 89, 113, 187, 197, 211, 191, 101, 189, 112, 103, 241, 163, 162, 242, 40, 207, 117, 148, 38, 88, 235, 69, 10, 5, 237, 248, 82, 182, 14, 136, 91, 184, 65, 192, 169, 246, 238, 229, 183, 218, 80, 87, 111, 245, 24, 173, 30, 222, 194, 21, 38, 221, 165, 196, 245, 202, 145, 69, 149, 3, 254, 248, 85, 234, 40, 48, 99, 143, 202, 4, 136, 97, 99, 71, 199, 145, 211, 180, 209, 28, 14, 215, 59, 206, 80, 37, 25, 175, 101, 145, 97, 96, 249, 193, 42, 67, 9, 158, 140, 204, 253, 63, 200, 57, 247, 50, 229, 202, 17, 142, 1, 225, 168, 118, 106, 132, 161, 124, 7, 56, 214, 240, 68, 188, 71, 142, 91, 119, 129, 76, 47, 167, 105, 205, 130, 246, 103, 136, 228, 104, 247, 72, 236, 187, 239, 245, 221, 81, 177, 206, 226, 9, 213, 226, 55, 119, 203, 44, 11, 47, 4, 71, 95, 233, 56, 67, 10, 37, 240, 11, 197, 114, 194, 51, 152, 89, 4, 16, 12, 134, 71, 248, 111, 196, 213, 8, 46, 254, 178, 82, 76, 149, 237, 228, 242, 137, 204, 197, 157, 32, 230, 150, 163, 138, 129, 154, 236, 192, 204, 179, 61, 132, 19, 46, 11, 205, 142, 54, 163, 232, 214, 177, 81, 149, 221, 11, 160, 212, 46, 223, 122, 28, 145, 140, 249, 235, 252, 192, 115, 144, 14, 189, 219, 49, 220, 246, 156, 211, 56, 239, 18, 208, 250, 103, 143, 101, 56, 220, 84, 39, 89, 190, 168, 129, 231, 209, 176, 168, 41, 57, 141, 53, 246, 183, 132, 56, 43, 62, 94, 231, 187, 96, 122, 180, 71, 208, 72, 207, 18, 183, 120, 98, 106, 97, 199, 192, 57, 166, 165, 41, 30, 209, 127, 72, 92, 75, 42, 167, 49, 125, 128, 246, 191, 192, 31, 225, 186, 176, 195, 71, 19, 104, 107, 110, 230, 4, 20, 226, 68, 64, 217, 181, 210, 106, 55, 74, 1, 192, 67, 115, 110, 81, 212, 1, 9, 189, 102, 218, 112, 178, 119, 52, 247, 107, 47, 253, 67, 246, 186, 124, 163, 19, 143, 179, 16, 101, 69, 114, 166, 12, 77, 3, 120, 200, 35, 168, 215, 71, 21, 204, 3, 132, 95, 135, 106, 218, 108, 174, 84, 43, 198, 135, 241, 218, 175, 167, 248, 115, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
         let response = get_response();
         let mut enc_response = response.clone();
         enc_response.data = PrincipalMessageType::EncryptedResponse(enc);
@@ -264,7 +397,7 @@ mod tests {
 
     #[test]
     fn test_decrypt_reponse() {
-        let enc = vec![195, 38, 192, 74, 88, 16, 137, 135, 207, 55, 231, 118, 249, 61, 195, 224, 63, 196, 241, 106, 78, 168, 173, 219, 207, 22, 170, 96, 122, 179, 196, 113, 182, 144, 124, 131, 226, 232, 197, 171, 8, 246, 211, 64, 243, 184, 206, 230, 208, 207, 182, 72, 131, 6, 120, 95, 206, 187, 5, 93, 183, 180, 62, 183, 196, 11, 161, 203, 226, 45, 171, 108, 240, 120, 203, 145, 26, 247, 128, 9, 133, 13, 233, 105, 131, 99, 154, 6, 136, 88, 112, 186, 196, 210, 190, 247, 96, 113, 70, 241, 163, 162, 242, 40, 207, 117, 148, 38, 133, 234, 100, 9, 6, 238, 251, 81, 181, 13, 139, 88, 187, 66, 195, 170, 245, 237, 230, 180, 217, 83, 84, 177, 247, 58, 173, 30, 222, 194, 21, 38, 221, 165, 196, 101, 20, 147, 103, 149, 3, 254, 248, 85, 234, 40, 48, 99, 143, 202, 4, 136, 97, 99, 71, 199, 145, 211, 106, 211, 10, 13, 212, 56, 205, 83, 38, 26, 172, 102, 146, 188, 97, 216, 195, 40, 65, 11, 156, 142, 206, 109, 224, 203, 26, 246, 51, 228, 203, 16, 143, 0, 224, 169, 119, 107, 133, 160, 125, 6, 57, 215, 241, 69, 189, 70, 30, 133, 117, 163, 77, 46, 166, 104, 204, 131, 247, 184, 139, 199, 104, 247, 72, 236, 187, 239, 245, 221, 81, 177, 206, 226, 9, 213, 226, 55, 119, 203, 44, 11, 47, 4, 152, 92, 202, 63, 68, 13, 34, 247, 12, 194, 170, 198, 35, 158, 95, 2, 22, 10, 128, 65, 254, 105, 194, 211, 14, 40, 248, 180, 84, 74, 147, 235, 226, 101, 81, 94, 57, 158, 3, 225, 145, 164, 141, 134, 157, 235, 199, 203, 180, 58, 131, 20, 41, 12, 202, 137, 49, 164, 239, 209, 182, 86, 146, 218, 12, 167, 211, 41, 216, 162, 24, 109, 136, 221, 234, 253, 193, 114, 145, 15, 188, 218, 48, 221, 247, 157, 210, 57, 238, 19, 209, 251, 102, 142, 100, 57, 221, 85, 38, 88, 191, 169, 128, 230, 8, 181, 156, 210, 190, 118, 13, 68, 47, 138, 4, 130, 174, 77, 76, 232, 70, 181, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
+        let enc = vec![195, 39, 192, 74, 88, 16, 137, 135, 207, 55, 231, 118, 249, 61, 195, 224, 63, 196, 241, 106, 78, 168, 173, 219, 207, 22, 170, 96, 122, 179, 196, 113, 182, 144, 124, 131, 226, 232, 197, 171, 8, 246, 211, 64, 243, 184, 206, 230, 208, 207, 182, 72, 131, 6, 120, 95, 206, 187, 5, 93, 183, 180, 62, 183, 196, 11, 161, 203, 226, 45, 171, 108, 190, 164, 223, 177, 27, 246, 129, 8, 132, 12, 232, 104, 130, 98, 155, 7, 137, 89, 113, 187, 197, 211, 191, 101, 189, 112, 103, 241, 163, 162, 242, 40, 207, 117, 148, 38, 88, 235, 69, 10, 5, 237, 248, 82, 182, 14, 136, 91, 184, 65, 192, 169, 246, 238, 229, 183, 218, 80, 87, 111, 245, 24, 173, 30, 222, 194, 21, 38, 221, 165, 196, 245, 202, 145, 69, 149, 3, 254, 248, 85, 234, 40, 48, 99, 143, 202, 4, 136, 97, 99, 71, 199, 145, 211, 180, 209, 28, 14, 215, 59, 206, 80, 37, 25, 175, 101, 145, 97, 96, 249, 193, 42, 67, 9, 158, 140, 204, 253, 63, 200, 57, 247, 50, 229, 202, 17, 142, 1, 225, 168, 118, 106, 132, 161, 124, 7, 56, 214, 240, 68, 188, 71, 142, 91, 119, 129, 76, 47, 167, 105, 205, 130, 246, 103, 136, 228, 104, 247, 72, 236, 187, 239, 245, 221, 81, 177, 206, 226, 9, 213, 226, 55, 119, 203, 44, 11, 47, 4, 71, 95, 233, 56, 67, 10, 37, 240, 11, 197, 114, 194, 51, 152, 89, 4, 16, 12, 134, 71, 248, 111, 196, 213, 8, 46, 254, 178, 82, 76, 149, 237, 228, 242, 137, 204, 197, 157, 32, 230, 150, 163, 138, 129, 154, 236, 192, 204, 179, 61, 132, 19, 46, 11, 205, 142, 54, 163, 232, 214, 177, 81, 149, 221, 11, 160, 212, 46, 223, 122, 28, 145, 140, 249, 235, 252, 192, 115, 144, 14, 189, 219, 49, 220, 246, 156, 211, 56, 239, 18, 208, 250, 103, 143, 101, 56, 220, 84, 39, 89, 190, 168, 129, 231, 209, 176, 168, 41, 57, 141, 53, 246, 183, 132, 56, 43, 62, 94, 231, 187, 96, 122, 180, 71, 208, 72, 207, 18, 183, 120, 98, 106, 97, 199, 192, 57, 166, 165, 41, 30, 209, 127, 72, 92, 75, 42, 167, 49, 125, 128, 246, 191, 192, 31, 225, 186, 176, 195, 71, 19, 104, 107, 110, 230, 4, 20, 226, 68, 64, 217, 181, 210, 106, 55, 74, 1, 192, 67, 115, 110, 81, 212, 1, 9, 189, 102, 218, 112, 178, 119, 52, 247, 107, 47, 253, 67, 246, 186, 124, 163, 19, 143, 179, 16, 101, 69, 114, 166, 12, 77, 3, 120, 200, 35, 168, 215, 71, 21, 204, 3, 132, 95, 135, 106, 218, 108, 174, 84, 43, 198, 135, 241, 218, 175, 167, 248, 115, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, ];
         let response = get_response();
         let mut enc_response = response.clone();
         enc_response.data = PrincipalMessageType::EncryptedResponse(enc);
@@ -278,6 +411,56 @@ mod tests {
         assert_eq!(res, decrypt(enc))
     }
 
+    #[test]
+    fn test_ptt_round_trip() {
+        let worker_keys = KeyPair::new().unwrap();
+        let req = PttRequest::new(worker_keys.get_pubkey()).unwrap();
+        let packed = req.clone().into_message().unwrap();
+        // The typed request is packed the same as the `PrincipalMessage` it stands for.
+        assert_eq!(PrincipalMessage::from_message(&packed).unwrap(), PrincipalMessage::from(req.clone()));
+        assert_eq!(req.to_sign().unwrap(), PrincipalMessage::from(req.clone()).to_sign().unwrap());
+        let parsed = PttRequest::from_message(&packed).unwrap();
+        assert_eq!(parsed, req);
+        assert_eq!(parsed.get_pubkey()[..], worker_keys.get_pubkey()[..]);
+
+        let km_keys = KeyPair::new().unwrap();
+        let keys = vec![([1u8; 32].into(), [2u8; 32], [5u8; 20]), ([3u8; 32].into(), [4u8; 32], [6u8; 20])];
+        let res = PttResponse::new(&parsed, keys, km_keys.get_pubkey());
+        let packed = res.clone().into_message(&km_keys.derive_key(&parsed.get_pubkey()).unwrap()).unwrap();
+        // A response isn't mistaken for a request.
+        assert!(PttRequest::from_message(&packed).is_err());
+
+        // Neither is a request with a public key of the wrong length.
+        let short = PrincipalMessage { data: PrincipalMessageType::Request, pubkey: vec![1u8; 10], id: req.id };
+        assert!(PttRequest::from_message(&short.into_message().unwrap()).is_err());
+
+        let msg = PrincipalMessage::from_message(&packed).unwrap();
+        assert!(msg.is_encrypted_response());
+        assert_eq!(msg.get_id(), req.id);
+        let key = worker_keys.derive_key(&msg.get_pubkey()).unwrap();
+        assert_eq!(msg.decrypt_ptt_response(&key).unwrap(), res);
+    }
+
+    #[test]
+    fn test_ptt_request_from_message() {
+        let msg = get_request().into_message().unwrap();
+        let req = PttRequest::from_message(&msg).unwrap();
+        assert_eq!(req.id, [75, 52, 85, 160, 254, 16, 9, 130, 50, 81, 252, 231]);
+        assert_eq!(req.get_pubkey()[..], [0u8; 64][..]);
+    }
+
+    #[test]
+    fn test_user_key_sig_versions() {
+        let msg = UserMessage::new([7u8; 64]);
+        // Clients that don't ask for a version keep getting the signature they verify.
+        assert_eq!(UserKeySigVersion::default(), UserKeySigVersion::Legacy);
+        assert_eq!(msg.to_sign_versioned(UserKeySigVersion::Legacy), msg.to_sign());
+        assert_eq!(msg.to_sign_versioned(UserKeySigVersion::Tagged), SignatureDomain::UserKey.tagged(&msg.to_sign()));
+        assert_eq!(UserKeySigVersion::from_u32(UserKeySigVersion::Tagged as u32).unwrap(), UserKeySigVersion::Tagged);
+        assert!(UserKeySigVersion::from_u32(0).is_err());
+        assert!(UserKeySigVersion::from_u32(3).is_err());
+    }
+
     fn get_request() -> PrincipalMessage {
         let data = PrincipalMessageType::Request;
         let id = [75, 52, 85, 160, 254, 16, 9, 130, 50, 81, 252, 231];
@@ -287,11 +470,11 @@ mod tests {
 
     fn get_response() -> PrincipalMessage {
         let data = PrincipalMessageType::Response(vec![
-            ([0u8; 32].into(), [1u8; 32]),
-            ([1u8; 32].into(), [2u8; 32]),
-            ([2u8; 32].into(), [3u8; 32]),
-            ([3u8; 32].into(), [4u8; 32]),
-            ([4u8; 32].into(), [5u8; 32]),
+            ([0u8; 32].into(), [1u8; 32], [0u8; 20]),
+            ([1u8; 32].into(), [2u8; 32], [1u8; 20]),
+            ([2u8; 32].into(), [3u8; 32], [2u8; 20]),
+            ([3u8; 32].into(), [4u8; 32], [3u8; 20]),
+            ([4u8; 32].into(), [5u8; 32], [4u8; 20]),
         ]);
         let id = [75, 52, 85, 160, 254, 16, 9, 130, 50, 81, 252, 231];
 
@@ -1,3 +1,5 @@
 //! # Primitives.
 //! This is a sub module for more modules.
-pub mod km_primitives;
\ No newline at end of file
+pub mod km_primitives;
+pub mod signature_domain;
+pub mod task_primitives;
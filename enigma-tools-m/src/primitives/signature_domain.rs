@@ -0,0 +1,82 @@
+//! # Signature Domains.
+//! The enclave signs several kinds of messages with the same key, so every message is tagged with the domain it belongs to before it's signed.
+//! This way a signature over one kind of message can't be replayed as a signature over another kind,
+//! even if the two messages happen to encode to the same bytes.
+
+use crate::common::utils::EthereumAddress;
+use crate::localstd::vec::Vec;
+use enigma_crypto::{asymmetric::KeyPair, hash, CryptoError};
+
+/// The kinds of messages the enclave signs with a domain tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureDomain {
+    /// The message carrying the enclave's DH key for a user, see [`crate::primitives::km_primitives::UserMessage`].
+    UserKey,
+    /// The full result of a task, see [`crate::primitives::task_primitives::compute_result_message`].
+    Output,
+    /// The receipt of a deployment, see [`crate::primitives::task_primitives::DeployReceipt`].
+    DeployReceipt,
+    /// The verdict of replaying a task, see [`crate::primitives::task_primitives::ReplayVerdict`].
+    Replay,
+    /// The deployer's approval of destroying a contract, see [`crate::primitives::task_primitives::destroy_contract_message`].
+    DestroyContract,
+}
+
+impl SignatureDomain {
+    /// The tag of the domain.
+    pub fn tag(self) -> &'static [u8] {
+        match self {
+            SignatureDomain::UserKey => b"ENIGMA_USERKEY",
+            SignatureDomain::Output => b"ENIGMA_OUTPUT",
+            SignatureDomain::DeployReceipt => b"ENIGMA_DEPLOY_RECEIPT",
+            SignatureDomain::Replay => b"ENIGMA_REPLAY",
+            SignatureDomain::DestroyContract => b"ENIGMA_DESTROY_CONTRACT",
+        }
+    }
+
+    /// The bytes that are actually signed for `message` in this domain, the tag followed by the message (length prefixed).
+    pub fn tagged(self, message: &[u8]) -> Vec<u8> {
+        hash::prepare_hash_multiple(&[self.tag(), message])
+    }
+
+    /// Verifies that `sig` was made by the Ethereum address `signer` over `message` in this domain.
+    pub fn verify(self, message: &[u8], sig: [u8; 65], signer: &[u8; 20]) -> Result<(), CryptoError> {
+        let recovered: [u8; 20] = KeyPair::recover(&self.tagged(message), sig)?.address();
+        if &recovered != signer {
+            return Err(CryptoError::VerificationError);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SignatureDomain;
+    use crate::common::utils::EthereumAddress;
+    use enigma_crypto::asymmetric::KeyPair;
+
+    #[test]
+    fn test_tagged_messages_differ() {
+        let msg = b"Enigma";
+        assert_ne!(SignatureDomain::UserKey.tagged(msg), SignatureDomain::Output.tagged(msg));
+        assert_ne!(SignatureDomain::Output.tagged(msg), SignatureDomain::DeployReceipt.tagged(msg));
+        assert_ne!(SignatureDomain::Output.tagged(msg), SignatureDomain::Replay.tagged(msg));
+        assert_ne!(SignatureDomain::DeployReceipt.tagged(msg), SignatureDomain::DestroyContract.tagged(msg));
+        // Moving bytes between the tag and the message mustn't produce the same bytes.
+        assert_ne!(SignatureDomain::Output.tagged(b"X"), SignatureDomain::Output.tagged(b""));
+    }
+
+    #[test]
+    fn test_verify_requires_the_same_domain() {
+        let keys = KeyPair::new().unwrap();
+        let signer: [u8; 20] = keys.get_pubkey().address();
+        let msg = b"Enigma";
+        let sig = keys.sign(&SignatureDomain::UserKey.tagged(msg)).unwrap();
+
+        SignatureDomain::UserKey.verify(msg, sig, &signer).unwrap();
+        assert!(SignatureDomain::Output.verify(msg, sig, &signer).is_err());
+        assert!(SignatureDomain::DeployReceipt.verify(msg, sig, &signer).is_err());
+        assert!(SignatureDomain::UserKey.verify(b"Other", sig, &signer).is_err());
+        assert!(SignatureDomain::UserKey.verify(msg, sig, &[0u8; 20]).is_err());
+    }
+}
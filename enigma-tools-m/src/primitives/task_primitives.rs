@@ -0,0 +1,181 @@
+//! # Task Primitives.
+//! This module contains the messages the enclave signs over the results of a task,
+//! so that whoever receives a result can rebuild the message and verify it against the worker's signing address.
+
+use crate::localstd::vec::Vec;
+use enigma_crypto::hash;
+use enigma_types::{ContractAddress, GasMultiplier, Hash256, HostCapabilities, ResultStatus};
+
+/// The message signed over the full result of a computation: S(output, deltaHash, usedGas, taskId, status).
+/// `output` is the encrypted output, `delta_hash` is the keccak256 of the encrypted delta (zeros if the state didn't change)
+/// and on failure the output is the encrypted error with a zero `delta_hash`.
+/// Every part is length prefixed so the encoding is canonical.
+pub fn compute_result_message(output: &[u8], delta_hash: &Hash256, used_gas: u64, task_id: &[u8; 32], status: ResultStatus) -> Vec<u8> {
+    hash::prepare_hash_multiple(&[output, &delta_hash[..], &used_gas.to_be_bytes()[..], &task_id[..], &[status as u8][..]])
+}
+
+/// The verdict of replaying a task, binding whether the replay matched to the task that was replayed and to the claimed result,
+/// so it can't be presented for other inputs or for a claim other than the one that was replayed.
+/// The task is replayed with the inputs the worker got and the claim is its result as it returned it, so `inputs_hash`,
+/// `claimed_delta_hash` and `claimed_output_hash` are the ones the worker signed in its `ExecuteResult`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayVerdict {
+    /// The id of the replayed task.
+    pub task_id: [u8; 32],
+    /// The keccak256 of the encrypted inputs, as in the signature of the computation: H(callable, args, address, userPubKey).
+    pub inputs_hash: Hash256,
+    /// The keccak256 of the bytecode the task was replayed on.
+    pub exe_code_hash: Hash256,
+    /// The gas limit the task was replayed with.
+    pub gas_limit: u64,
+    /// The keccak256 of the claimed encrypted delta (zeros if no delta was claimed).
+    pub claimed_delta_hash: Hash256,
+    /// The keccak256 of the claimed encrypted output.
+    pub claimed_output_hash: Hash256,
+    /// The gas that was claimed for the task.
+    pub claimed_gas: u64,
+    /// Whether the replay produced the claimed result.
+    pub matches: bool,
+}
+
+impl ReplayVerdict {
+    /// The message the enclave signs over the verdict:
+    /// S(taskId, inputsHash, exeCodeHash, gasLimit, claimedDeltaHash, claimedOutputHash, claimedGas, matches).
+    pub fn to_sign(&self) -> Vec<u8> {
+        hash::prepare_hash_multiple(&[
+            &self.task_id[..],
+            &self.inputs_hash[..],
+            &self.exe_code_hash[..],
+            &self.gas_limit.to_be_bytes()[..],
+            &self.claimed_delta_hash[..],
+            &self.claimed_output_hash[..],
+            &self.claimed_gas.to_be_bytes()[..],
+            &[self.matches as u8][..],
+        ])
+    }
+}
+
+/// A receipt of a deployment, binding the address of a contract to the bytecode it was deployed from and to its deployer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeployReceipt {
+    /// The address of the deployed contract.
+    pub address: ContractAddress,
+    /// The keccak256 of the pre-deployment bytecode.
+    pub precode_hash: Hash256,
+    /// The Ethereum address of the deployer, as given in the metadata of the deployment (zeros if it had none).
+    /// It's not the address of the DH key of the task, that key is ephemeral.
+    pub deployer: [u8; 20],
+    /// The keccak256 of the encrypted delta the constructor produced (zeros if it didn't change the state).
+    pub delta_hash: Hash256,
+    /// The price the contract was deployed with, its computations are charged by it.
+    pub gas_multiplier: GasMultiplier,
+    /// The host functions the contract is allowed to import.
+    pub capabilities: HostCapabilities,
+}
+
+impl DeployReceipt {
+    /// The message the enclave signs over the receipt: S(address, preCodeHash, deployer, deltaHash, gasMultiplier, capabilities).
+    pub fn to_sign(&self) -> Vec<u8> {
+        let (gas_multiplier, capabilities) = (self.gas_multiplier.to_be_bytes(), self.capabilities.to_be_bytes());
+        hash::prepare_hash_multiple(&[
+            &self.address[..],
+            &self.precode_hash[..],
+            &self.deployer[..],
+            &self.delta_hash[..],
+            &gas_multiplier[..],
+            &capabilities[..],
+        ])
+    }
+}
+
+/// The message the enclave MACs over the settings a contract was deployed with, see `ExecuteResult::settings_mac`.
+pub fn contract_settings_message(address: &ContractAddress, gas_multiplier: GasMultiplier, capabilities: HostCapabilities) -> Vec<u8> {
+    hash::prepare_hash_multiple(&[&address[..], &gas_multiplier.to_be_bytes()[..], &capabilities.to_be_bytes()[..]])
+}
+
+/// The message the deployer signs to destroy a contract: S(address, tip).
+/// `tip` is the index of the contract's latest delta, so the approval can't be replayed once the contract moved on.
+pub fn destroy_contract_message(address: &ContractAddress, tip: u32) -> Vec<u8> {
+    hash::prepare_hash_multiple(&[&address[..], &tip.to_be_bytes()[..]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_result_message, contract_settings_message, destroy_contract_message, DeployReceipt, ReplayVerdict};
+    use enigma_types::{GasMultiplier, Hash256, HostCapabilities, ResultStatus};
+
+    #[test]
+    fn test_compute_result_message() {
+        let output = b"output";
+        let delta_hash = Hash256::from([1u8; 32]);
+        let task_id = [2u8; 32];
+        let msg = compute_result_message(output, &delta_hash, 10, &task_id, ResultStatus::Ok);
+
+        assert_ne!(msg, compute_result_message(output, &Hash256::from([3u8; 32]), 10, &task_id, ResultStatus::Ok));
+        assert_ne!(msg, compute_result_message(output, &delta_hash, 11, &task_id, ResultStatus::Ok));
+        assert_ne!(msg, compute_result_message(output, &delta_hash, 10, &[0u8; 32], ResultStatus::Ok));
+        assert_ne!(msg, compute_result_message(output, &delta_hash, 10, &task_id, ResultStatus::Failure));
+        // Moving bytes between the output and the rest mustn't produce the same message.
+        assert_ne!(msg, compute_result_message(b"outpu", &delta_hash, 10, &task_id, ResultStatus::Ok));
+    }
+
+    #[test]
+    fn test_replay_verdict_message() {
+        let verdict = ReplayVerdict {
+            task_id: [1u8; 32],
+            inputs_hash: [2u8; 32].into(),
+            exe_code_hash: [3u8; 32].into(),
+            gas_limit: 100,
+            claimed_delta_hash: [4u8; 32].into(),
+            claimed_output_hash: [5u8; 32].into(),
+            claimed_gas: 10,
+            matches: true,
+        };
+        let msg = verdict.to_sign();
+
+        assert_ne!(msg, ReplayVerdict { matches: false, ..verdict }.to_sign());
+        assert_ne!(msg, ReplayVerdict { task_id: [0u8; 32], ..verdict }.to_sign());
+        assert_ne!(msg, ReplayVerdict { inputs_hash: [0u8; 32].into(), ..verdict }.to_sign());
+        assert_ne!(msg, ReplayVerdict { exe_code_hash: [0u8; 32].into(), ..verdict }.to_sign());
+        assert_ne!(msg, ReplayVerdict { gas_limit: 101, ..verdict }.to_sign());
+        assert_ne!(msg, ReplayVerdict { claimed_delta_hash: [5u8; 32].into(), claimed_output_hash: [4u8; 32].into(), ..verdict }.to_sign());
+        assert_ne!(msg, ReplayVerdict { claimed_gas: 11, ..verdict }.to_sign());
+    }
+
+    #[test]
+    fn test_deploy_receipt_message() {
+        let receipt = DeployReceipt {
+            address: [1u8; 32].into(),
+            precode_hash: [2u8; 32].into(),
+            deployer: [3u8; 20],
+            delta_hash: [4u8; 32].into(),
+            gas_multiplier: GasMultiplier::default(),
+            capabilities: HostCapabilities::default(),
+        };
+        let msg = receipt.to_sign();
+
+        assert_ne!(msg, DeployReceipt { address: [5u8; 32].into(), ..receipt }.to_sign());
+        assert_ne!(msg, DeployReceipt { precode_hash: [5u8; 32].into(), ..receipt }.to_sign());
+        assert_ne!(msg, DeployReceipt { deployer: [5u8; 20], ..receipt }.to_sign());
+        assert_ne!(msg, DeployReceipt { delta_hash: [5u8; 32].into(), ..receipt }.to_sign());
+        assert_ne!(msg, DeployReceipt { gas_multiplier: GasMultiplier { mul: 2, div: 1 }, ..receipt }.to_sign());
+        assert_ne!(msg, DeployReceipt { capabilities: HostCapabilities::NONE, ..receipt }.to_sign());
+    }
+
+    #[test]
+    fn test_contract_settings_message() {
+        let (multiplier, capabilities) = (GasMultiplier { mul: 1, div: 2 }, HostCapabilities::ALL);
+        let msg = contract_settings_message(&[1u8; 32].into(), multiplier, capabilities);
+        assert_ne!(msg, contract_settings_message(&[2u8; 32].into(), multiplier, capabilities));
+        assert_ne!(msg, contract_settings_message(&[1u8; 32].into(), GasMultiplier { mul: 2, div: 1 }, capabilities));
+        assert_ne!(msg, contract_settings_message(&[1u8; 32].into(), multiplier, capabilities.without(HostCapabilities::RANDOMNESS)));
+    }
+
+    #[test]
+    fn test_destroy_contract_message() {
+        let address = [1u8; 32].into();
+        let msg = destroy_contract_message(&address, 3);
+        assert_ne!(msg, destroy_contract_message(&address, 4));
+        assert_ne!(msg, destroy_contract_message(&[2u8; 32].into(), 3));
+    }
+}
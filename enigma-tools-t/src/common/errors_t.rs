@@ -4,7 +4,12 @@ use json_patch;
 use pwasm_utils as wasm_utils;
 use sgx_types::sgx_status_t;
 use enigma_crypto::CryptoError;
+use enigma_crypto::{rand, symmetric};
+use enigma_types::SymmetricKey;
+use rustc_hex::ToHex;
+use std::fmt;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::string::{String, ToString};
 use wasmi::{self, TrapKind};
 use parity_wasm;
@@ -80,20 +85,21 @@ impl From<wasm_utils::stack_height::Error> for EnclaveError {
     }
 }
 
+fn from_wasm_error(err: &WasmError) -> EnclaveError {
+    match err {
+        WasmError::GasLimit => EnclaveError::FailedTaskError(FailedTaskError::GasLimitError),
+        WasmError::WasmiError(e) => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError { err: format!("{}", e) }),
+        WasmError::EnclaveError(err) => err.clone(),
+    }
+}
+
 // This is for final conversion from the result of wasmi execution to core result
 impl From<wasmi::Error> for EnclaveError{
     fn from(e: wasmi::Error) -> Self {
         match e {
             wasmi::Error::Trap(kind) => {
                 match kind.kind() {
-                    TrapKind::Host(t) => {
-                        match (**t).downcast_ref::<WasmError>()
-                            .expect("Failed to downcast to expected error type"){
-                            WasmError::GasLimit => EnclaveError::FailedTaskError(FailedTaskError::GasLimitError),
-                            WasmError::WasmiError(e) => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError { err: format!("{}", e) }),
-                            WasmError::EnclaveError(err) => err.clone(),
-                        }
-                    },
+                    TrapKind::Host(t) => from_wasm_error((**t).downcast_ref::<WasmError>().expect("Failed to downcast to expected error type")),
                     TrapKind::Unreachable => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError{ err: "unreachable".to_string() }),
                     TrapKind::MemoryAccessOutOfBounds => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError{ err: "memory access out of bounds".to_string() }),
                     TrapKind::TableAccessOutOfBounds | TrapKind::ElemUninitialized => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError{ err: "table access out of bounds".to_string() }),
@@ -103,12 +109,17 @@ impl From<wasmi::Error> for EnclaveError{
                     TrapKind::StackOverflow => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError{ err: "stack overflow".to_string() }),
                 }
             }
+            // The import resolver fails the instantiation with a host error of its own (e.g. for too much memory).
+            wasmi::Error::Host(ref t) => match (**t).downcast_ref::<WasmError>() {
+                Some(err) => from_wasm_error(err),
+                None => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError { err: e.to_string() }),
+            },
             _ => EnclaveError::FailedTaskError(FailedTaskError::WasmCodeExecutionError { err: e.to_string() })
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum EnclaveError {
     FailedTaskError(FailedTaskError),
     FailedTaskErrorWithGas {
@@ -118,12 +129,77 @@ pub enum EnclaveError {
     SystemError(EnclaveSystemError)
 }
 
-impl ::std::fmt::Display for EnclaveError {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::result::Result<(), ::std::fmt::Error> {
+static ERROR_DETAILS: AtomicBool = AtomicBool::new(false);
+
+/// Makes `Display` of an `EnclaveError` include the fields that may hold secret material, for debugging only.
+pub fn set_error_details(enabled: bool) { ERROR_DETAILS.store(enabled, Ordering::SeqCst) }
+
+pub fn error_details() -> bool { ERROR_DETAILS.load(Ordering::SeqCst) }
+
+lazy_static! {
+    /// The key of the fingerprints of redacted fields, it's random so a fingerprint can't be matched against guessed values.
+    /// `None` if the enclave had no randomness for it, then there's no fingerprint at all.
+    static ref FINGERPRINT_KEY: Option<SymmetricKey> = {
+        let mut key = SymmetricKey::default();
+        rand::random(&mut key).ok().map(|_| key)
+    };
+}
+
+/// Stands for a field that may hold secret material (e.g. decrypted inputs or state),
+/// it's displayed as a prefix of its HMAC so the same value can still be recognized across the logs of the enclave.
+struct Redacted<'a>(&'a str);
+
+impl<'a> fmt::Display for Redacted<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *FINGERPRINT_KEY {
+            Some(ref key) => {
+                let fingerprint: String = symmetric::mac(key, self.0.as_bytes())[..8].to_hex();
+                write!(f, "<redacted {}>", fingerprint)
+            }
+            None => write!(f, "<redacted>"),
+        }
+    }
+}
+
+/// The fields that may hold secret material are redacted unless `set_error_details` was enabled.
+/// The alternate form (`{:#}`) always shows them, it's only for text that's returned encrypted to the user.
+impl fmt::Display for EnclaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let detailed = f.alternate() || error_details();
         match self {
-            EnclaveError::FailedTaskError(ref e) => write!(f, "{}", e),
-            EnclaveError::FailedTaskErrorWithGas{err, ..} => write!(f, "{}", err),
-            EnclaveError::SystemError(ref e) => write!(f, "{}", e),
+            EnclaveError::FailedTaskError(e) | EnclaveError::FailedTaskErrorWithGas { err: e, .. } => match e {
+                _ if detailed => write!(f, "{}", e),
+                FailedTaskError::InputError { message } => write!(f, "Input Error: {}", Redacted(message)),
+                FailedTaskError::WasmCodeExecutionError { err } => write!(f, "Error in execution of WASM code: {}", Redacted(err)),
+                FailedTaskError::Reverted { reason } => write!(f, "The contract reverted: {}", Redacted(reason)),
+                _ => write!(f, "{}", e),
+            },
+            EnclaveError::SystemError(e) => match e {
+                _ if detailed => write!(f, "{}", e),
+                EnclaveSystemError::StateError { err } => write!(f, "There's a State error with: {}", Redacted(err)),
+                EnclaveSystemError::KeyProvisionError { err } => write!(f, "Failed to provide state key: {}", Redacted(err)),
+                _ => write!(f, "{}", e),
+            },
+        }
+    }
+}
+
+/// Redacts the same fields as `Display`, so logging an error with `{:?}` doesn't leak them either.
+impl fmt::Debug for EnclaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if error_details() {
+            return match self {
+                EnclaveError::FailedTaskError(e) => f.debug_tuple("FailedTaskError").field(e).finish(),
+                EnclaveError::FailedTaskErrorWithGas { used_gas, err } => {
+                    f.debug_struct("FailedTaskErrorWithGas").field("used_gas", used_gas).field("err", err).finish()
+                }
+                EnclaveError::SystemError(e) => f.debug_tuple("SystemError").field(e).finish(),
+            };
+        }
+        match self {
+            EnclaveError::FailedTaskError(_) => write!(f, "FailedTaskError({})", self),
+            EnclaveError::FailedTaskErrorWithGas { used_gas, .. } => write!(f, "FailedTaskErrorWithGas {{ used_gas: {}, err: {} }}", used_gas, self),
+            EnclaveError::SystemError(_) => write!(f, "SystemError({})", self),
         }
     }
 }
@@ -141,6 +217,21 @@ pub enum FailedTaskError {
 
     #[fail(display = "Invocation resulted in gas limit violated")]
     GasLimitError,
+
+    #[fail(display = "The contract reverted: {}", reason)]
+    Reverted { reason: String },
+
+    #[fail(display = "The execution went over its native stack budget of {} bytes", budget)]
+    NativeStackOverflow { budget: usize },
+
+    #[fail(display = "The value nests arrays and objects deeper than the limit of {}", max_depth)]
+    JsonTooDeep { max_depth: usize },
+
+    #[fail(display = "The state grew to {} bytes, over the limit of {} bytes", size, max_size)]
+    StateTooLarge { size: usize, max_size: usize },
+
+    #[fail(display = "The contract requested up to {} pages of memory but at most {} are allowed", requested, allowed)]
+    MemoryLimitExceeded { requested: u32, allowed: u32 },
 }
 
 #[derive(Debug, Fail, Clone)]
@@ -168,6 +259,9 @@ pub enum EnclaveSystemError {
 
     #[fail(display = "Failed to provide state key: {}", err)]
     KeyProvisionError { err: String },
+
+    #[fail(display = "There's an error with a lock: {}", err)]
+    LockError { err: String },
 }
 
 impl From<CryptoError> for EnclaveError {
@@ -179,7 +273,8 @@ impl From<CryptoError> for EnclaveError {
 impl From<ToolsError> for EnclaveError {
     fn from(err: ToolsError) -> Self {
         match err {
-            ToolsError::MessagingError {err} => EnclaveError::SystemError(EnclaveSystemError::MessagingError { err: err.to_string() })
+            ToolsError::MessagingError {err} => EnclaveError::SystemError(EnclaveSystemError::MessagingError { err: err.to_string() }),
+            e @ ToolsError::LockError { .. } => EnclaveError::SystemError(EnclaveSystemError::LockError { err: e.to_string() }),
         }
     }
 }
@@ -246,13 +341,54 @@ impl Into<EnclaveReturn> for EnclaveError {
                         | ImproperEncryption
                         | ParsingError { ..}
                         | RecoveryError { .. }
+                        | VerificationError
                         => EnclaveReturn::EncryptionError,
                     }
                     WorkerAuthError { .. } => EnclaveReturn::WorkerAuthError,
                     KeyProvisionError { .. } => EnclaveReturn::KeyProvisionError,
+                    LockError { .. } => EnclaveReturn::LockError,
                  }
 
              }
         }
     }
 }
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+    use enigma_crypto::hash::Sha256;
+
+    pub fn test_redacted_display() {
+        let secret = "decrypted balance: 1000000";
+        let err = EnclaveError::FailedTaskError(FailedTaskError::InputError { message: secret.to_string() });
+        let redacted = format!("{}", err);
+        assert!(redacted.starts_with("Input Error: <redacted "));
+        assert!(!redacted.contains(secret));
+        // The same value is redacted the same way, so it can be recognized across logs.
+        assert_eq!(redacted, format!("{}", EnclaveError::FailedTaskError(FailedTaskError::InputError { message: secret.to_string() })));
+        // The fingerprint is keyed, it isn't a plain hash of the value.
+        let hash: String = secret.as_bytes().sha256()[..4].to_hex();
+        assert!(!redacted.contains(&hash));
+        // Debug redacts the same fields.
+        let debug = format!("{:?}", err);
+        assert!(debug.starts_with("FailedTaskError(Input Error: <redacted "));
+        assert!(!debug.contains(secret));
+        assert!(!format!("{:#?}", err).contains(secret));
+
+        let state_err = EnclaveError::SystemError(EnclaveSystemError::StateError { err: secret.to_string() });
+        assert!(!format!("{}", state_err).contains(secret));
+        // Fields that can't hold secrets are shown as they are.
+        let gas = EnclaveError::FailedTaskError(FailedTaskError::NativeStackOverflow { budget: 512 });
+        assert_eq!(format!("{}", gas), "The execution went over its native stack budget of 512 bytes");
+
+        // The full detail is only shown in the alternate form or with the debug flag.
+        assert_eq!(format!("{:#}", err), format!("Input Error: {}", secret));
+        set_error_details(true);
+        let detailed = format!("{}", state_err);
+        let detailed_debug = format!("{:?}", state_err);
+        set_error_details(false);
+        assert!(detailed.contains(secret));
+        assert!(detailed_debug.contains(secret));
+    }
+}
@@ -8,8 +8,11 @@ use std::untrusted::fs;
 use std::untrusted::fs::{File, remove_file};
 
 use common::errors_t::{EnclaveError, EnclaveError::*, EnclaveSystemError::*};
+use enigma_crypto::asymmetric::KeyPair;
 
 pub const SEAL_LOG_SIZE: usize = 2048;
+/// The version of a sealed key pair document, its layout is the same as `storage_t::SecretKeyStorage`.
+pub const KEYPAIR_DOCUMENT_VERSION: u32 = 0x1;
 
 #[derive(Copy, Clone, Default, Debug)]
 pub struct SealedDocumentStorage<T: ?Sized> {
@@ -117,7 +120,7 @@ pub fn load_sealed_document(path: &PathBuf, sealed_document: &mut [u8]) -> Resul
         }
     };
     match file.read(sealed_document) {
-        Ok(_) => println!("Sealed document: {:?} loaded successfully.", path),
+        Ok(_) => enclave_log!(Info, "Sealed document: {:?} loaded successfully.", path),
         Err(err) => {
             return Err(SystemError(OcallError { command: "load_sealed_document".to_string(), err: format!("{:?}", err) }));
         }
@@ -125,9 +128,32 @@ pub fn load_sealed_document(path: &PathBuf, sealed_document: &mut [u8]) -> Resul
     Ok(())
 }
 
+/// Loads the key pair sealed at `path`, or generates one and seals it there if there's none yet,
+/// so the key (and the address derived from it) stays the same when the enclave restarts.
+/// A document that exists but can't be unsealed is an error instead of being replaced, since replacing it changes the key.
+pub fn get_or_create_sealed_keypair(path: &PathBuf) -> Result<KeyPair, EnclaveError> {
+    let mut sealed_log = [0u8; SEAL_LOG_SIZE];
+    if is_document(path) {
+        load_sealed_document(path, &mut sealed_log)?;
+        return match SealedDocumentStorage::<[u8; 32]>::unseal(&mut sealed_log)? {
+            Some(doc) => Ok(KeyPair::from_slice(&doc.data)?),
+            None => Err(SystemError(OcallError {
+                command: "get_or_create_sealed_keypair".to_string(),
+                err: format!("The key pair sealed in {:?} can't be unsealed by this enclave", path),
+            })),
+        };
+    }
+    let keypair = KeyPair::new()?;
+    let doc = SealedDocumentStorage { version: KEYPAIR_DOCUMENT_VERSION, data: keypair.get_privkey() };
+    doc.seal(&mut sealed_log)?;
+    save_sealed_document(path, &sealed_log)?;
+    Ok(keypair)
+}
+
 //#[cfg(debug_assertions)]
 pub mod tests {
     use super::*;
+    use enigma_tools_m::utils::EthereumAddress;
 
 //use std::untrusted::fs::*;
 
@@ -155,4 +181,20 @@ pub mod tests {
         let f = remove_file(&p);
         assert!(f.is_ok());
     }
+
+    pub fn test_sealed_keypair_survives_restart() {
+        let p = PathBuf::from("keypair_test.sealed");
+        let _ = remove_file(&p);
+        let created = get_or_create_sealed_keypair(&p).unwrap();
+        assert!(is_document(&p));
+        // A restart loses everything but the sealed document.
+        let reloaded = get_or_create_sealed_keypair(&p).unwrap();
+        assert_eq!(created.get_pubkey().address(), reloaded.get_pubkey().address());
+        assert_eq!(created.get_privkey(), reloaded.get_privkey());
+
+        // A document this enclave can't unseal isn't silently replaced with a new key.
+        save_sealed_document(&p, &[0u8; SEAL_LOG_SIZE]).unwrap();
+        assert!(get_or_create_sealed_keypair(&p).is_err());
+        assert!(remove_file(&p).is_ok());
+    }
 }
@@ -0,0 +1,68 @@
+//! Assertions that behave the same in debug and release enclaves:
+//! a failed `enclave_assert!` logs where it failed through an ocall and then aborts the enclave.
+use crate::esgx::ocalls_t::{self, LogLevel};
+use sgx_trts::trts::rsgx_abort;
+use std::string::String;
+
+/// Where an `enclave_assert!` failed.
+#[derive(Debug, Clone, Copy)]
+pub struct AssertLocation {
+    pub file: &'static str,
+    pub line: u32,
+    pub expr: &'static str,
+}
+
+/// The message that's logged for a failed assertion.
+pub fn assertion_report(location: &AssertLocation, msg: &str) -> String {
+    if msg.is_empty() {
+        format!("Enclave assertion `{}` failed at {}:{}", location.expr, location.file, location.line)
+    } else {
+        format!("Enclave assertion `{}` failed at {}:{}: {}", location.expr, location.file, location.line, msg)
+    }
+}
+
+/// Logs the failed assertion to the untrusted app and aborts the enclave, called by `enclave_assert!`.
+#[cold]
+#[inline(never)]
+pub fn assert_failed(location: &AssertLocation, msg: &str) -> ! {
+    fail_with(location, msg, |report| ocalls_t::log(LogLevel::Error, report), || rsgx_abort())
+}
+
+fn fail_with<L, A, R>(location: &AssertLocation, msg: &str, log: L, abort: A) -> R
+where L: FnOnce(&str), A: FnOnce() -> R {
+    log(&assertion_report(location, msg));
+    abort()
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::string::ToString;
+    use std::vec::Vec;
+
+    pub fn test_assert_failure_logs_location() {
+        let events = RefCell::new(Vec::new());
+        let location = AssertLocation { file: file!(), line: line!(), expr: "1 + 1 == 3" };
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            fail_with(
+                &location,
+                "math is broken",
+                |report| events.borrow_mut().push(report.to_string()),
+                || {
+                    events.borrow_mut().push("abort".to_string());
+                    panic!("aborted");
+                },
+            )
+        }));
+        assert!(res.is_err());
+
+        let events = events.into_inner();
+        assert_eq!(events.len(), 2);
+        // The location is logged before the enclave aborts.
+        assert!(events[0].contains(&format!("{}:{}", location.file, location.line)), "{}", events[0]);
+        assert!(events[0].contains("`1 + 1 == 3`") && events[0].ends_with("math is broken"), "{}", events[0]);
+        assert_eq!(events[1], "abort");
+    }
+}
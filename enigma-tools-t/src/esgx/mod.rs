@@ -1 +1,2 @@
-pub mod ocalls_t;
\ No newline at end of file
+pub mod assert_t;
+pub mod ocalls_t;
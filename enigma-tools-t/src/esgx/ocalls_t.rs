@@ -8,6 +8,24 @@ const PATH_MAX: usize = 4096; // linux/limits.h - this depends on the FS.
 extern "C" {
     fn ocall_get_home(output: *mut u8, result_len: &mut usize) -> sgx_status_t;
     fn ocall_save_to_memory(ptr: *mut u64, data_ptr: *const u8, data_len: usize) -> sgx_status_t;
+    fn ocall_log(level: u32, msg_ptr: *const u8, msg_len: usize) -> sgx_status_t;
+}
+
+/// The severity of a log message, numbered like `log::Level` on the untrusted side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+/// Logs `msg` through the `log` framework of the untrusted app instead of printing it to stdout,
+/// the app decides where it goes and can drop it altogether.
+/// Logging is best effort, a failing ocall is ignored.
+pub fn log(level: LogLevel, msg: &str) {
+    let _ = unsafe { ocall_log(level as u32, msg.as_c_ptr(), msg.len()) };
 }
 
 pub fn get_home_path() -> Result<PathBuf, EnclaveError> {
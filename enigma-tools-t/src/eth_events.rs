@@ -0,0 +1,20 @@
+use enigma_crypto::hash::Keccak256;
+use enigma_types::Hash256;
+
+/// Computes the topic0 of an Ethereum event, which is the Keccak256 of its canonical signature.
+/// The signature must be in the canonical form, without spaces and argument names,
+/// e.g. `"Transfer(address,address,uint256)"`.
+pub fn event_topic(signature: &str) -> Hash256 {
+    signature.as_bytes().keccak256()
+}
+
+#[cfg(debug_assertions)]
+pub mod tests {
+    use eth_events::*;
+
+    pub fn test_event_topic_erc20_transfer() {
+        let topic = event_topic("Transfer(address,address,uint256)");
+        let expected = Hash256::from_hex("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef").unwrap();
+        assert_eq!(topic, expected);
+    }
+}
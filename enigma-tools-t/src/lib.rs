@@ -15,6 +15,7 @@ extern crate parity_wasm;
 extern crate pwasm_utils;
 extern crate sgx_tse;
 extern crate sgx_tseal;
+extern crate sgx_trts;
 extern crate sgx_types;
 
 extern crate bigint;
@@ -22,6 +23,8 @@ extern crate hexutil;
 extern crate rlp;
 extern crate wasmi;
 extern crate rustc_hex;
+#[macro_use]
+extern crate lazy_static;
 
 #[macro_use]
 pub mod macros;
@@ -32,6 +35,7 @@ pub mod quote_t;
 pub mod document_storage_t; //TODO: Copy of storage_t with more generic naming convention
 pub mod storage_t;
 pub mod esgx;
+pub mod eth_events;
 
 
 #[cfg(test)]
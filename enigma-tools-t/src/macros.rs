@@ -1,8 +1,35 @@
+/// Sends a message to the untrusted app's `log` framework with the given `LogLevel` (see `esgx::ocalls_t::log`).
+#[macro_export]
+macro_rules! enclave_log {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::esgx::ocalls_t::log($crate::esgx::ocalls_t::LogLevel::$level, &format!($($arg)*))
+    };
+}
+
+/// Like `assert!` but the same in debug and release builds,
+/// a failure logs the location through `esgx::assert_t::assert_failed` and aborts the enclave.
+#[macro_export]
+macro_rules! enclave_assert {
+    ($cond:expr) => {
+        if !$cond {
+            let location = $crate::esgx::assert_t::AssertLocation { file: file!(), line: line!(), expr: stringify!($cond) };
+            $crate::esgx::assert_t::assert_failed(&location, "");
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            let location = $crate::esgx::assert_t::AssertLocation { file: file!(), line: line!(), expr: stringify!($cond) };
+            $crate::esgx::assert_t::assert_failed(&location, &format!($($arg)+));
+        }
+    };
+}
+
+/// Logs in `Debug` level, only in debug builds.
 #[macro_export]
 macro_rules! debug_println {
     ($($arg:tt)*) => {
         if cfg!(debug_assertions) {
-            println!($($arg)*);
+            $crate::esgx::ocalls_t::log($crate::esgx::ocalls_t::LogLevel::Debug, &format!($($arg)*));
         }
     };
-}
\ No newline at end of file
+}
@@ -1,2 +1,4 @@
 // the attestation service end-point
 pub const ATTESTATION_SERVICE_URL: &str = "https://sgx.enigma.co/api";
+// the SPID the quotes are produced with when none is configured
+pub const DEFAULT_SPID: &str = "B0335FD3BC1CCA8F804EB98A6420592D";
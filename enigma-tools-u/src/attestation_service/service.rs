@@ -2,6 +2,7 @@
 //! all of the data here is directly from the API https://software.intel.com/sites/default/files/managed/7e/3b/ias-api-spec.pdf
 //! Some of this code is pretty old and can be rewritten in a more idiomatic way(and even generally better).
 
+use attestation_service::constants::{ATTESTATION_SERVICE_URL, DEFAULT_SPID};
 use base64;
 use common_u::errors;
 use failure::Error;
@@ -13,11 +14,95 @@ use reqwest::{self, Client};
 use rlp;
 use serde_json;
 use serde_json::Value;
+use std::fmt;
 use std::io::Read;
 use std::mem;
+use std::str::FromStr;
 use std::string::ToString;
 
 const ATTESTATION_SERVICE_DEFAULT_RETRIES: u32 = 10;
+const SUBSCRIPTION_KEY_HEADER: &str = "Ocp-Apim-Subscription-Key";
+
+/// Where the attestation reports are requested from and the SPID the quotes are produced with,
+/// so a node can be pointed at a different service (dev, prod or a mock) without recompiling.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttestationConfig {
+    pub spid: String,
+    /// Sent in the `Ocp-Apim-Subscription-Key` header if the service requires one.
+    pub subscription_key: Option<String>,
+    pub url: String,
+    /// amount of attempts per network call
+    pub retries: u32,
+    /// What to do with a quote of a platform that's `GROUP_OUT_OF_DATE`.
+    pub out_of_date_policy: OutOfDatePolicy,
+}
+
+impl Default for AttestationConfig {
+    fn default() -> Self {
+        AttestationConfig {
+            spid: DEFAULT_SPID.to_string(),
+            subscription_key: None,
+            url: ATTESTATION_SERVICE_URL.to_string(),
+            retries: ATTESTATION_SERVICE_DEFAULT_RETRIES,
+            out_of_date_policy: OutOfDatePolicy::default(),
+        }
+    }
+}
+
+/// What to do with a quote the service reports as `GROUP_OUT_OF_DATE`, the status of a platform that's missing security updates
+/// (microcode or SGX software), un-patched hosts get it often, some deployments tolerate them and others don't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfDatePolicy {
+    Accept,
+    /// Accept it but report a warning with the registration params.
+    AcceptWithWarning,
+    Reject,
+}
+
+impl Default for OutOfDatePolicy {
+    fn default() -> Self { OutOfDatePolicy::AcceptWithWarning }
+}
+
+impl FromStr for OutOfDatePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(OutOfDatePolicy::Accept),
+            "warn" => Ok(OutOfDatePolicy::AcceptWithWarning),
+            "reject" => Ok(OutOfDatePolicy::Reject),
+            _ => Err(format!("Unknown policy {:?}, expected one of: accept, warn, reject", s)),
+        }
+    }
+}
+
+/// The classification of `isvEnclaveQuoteStatus`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuoteStatus {
+    Ok,
+    GroupOutOfDate,
+    /// Any other status (e.g. `CONFIGURATION_NEEDED`), as the service reported it.
+    Other(String),
+}
+
+impl<'a> From<&'a str> for QuoteStatus {
+    fn from(status: &str) -> Self {
+        match status {
+            "OK" => QuoteStatus::Ok,
+            "GROUP_OUT_OF_DATE" => QuoteStatus::GroupOutOfDate,
+            other => QuoteStatus::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for QuoteStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuoteStatus::Ok => write!(f, "OK"),
+            QuoteStatus::GroupOutOfDate => write!(f, "GROUP_OUT_OF_DATE"),
+            QuoteStatus::Other(status) => write!(f, "{}", status),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct ASReport {
@@ -59,6 +144,9 @@ pub struct ASResponse {
 pub struct Params {
     pub quote: String,
     pub production: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub spid: Option<String>,
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct QuoteRequest {
@@ -105,15 +193,26 @@ pub struct AttestationService {
     connection_str: String,
     /// amount of attempts per network call
     retries: u32,
+    spid: Option<String>,
+    subscription_key: Option<String>,
 }
 
 impl AttestationService {
     pub fn new(conn_str: &str) -> AttestationService {
-        AttestationService { connection_str: conn_str.to_string(), retries: ATTESTATION_SERVICE_DEFAULT_RETRIES }
+        AttestationService::new_with_retries(conn_str, ATTESTATION_SERVICE_DEFAULT_RETRIES)
     }
 
     pub fn new_with_retries(conn_str: &str, retries: u32) -> AttestationService {
-        AttestationService { connection_str: conn_str.to_string(), retries }
+        AttestationService { connection_str: conn_str.to_string(), retries, spid: None, subscription_key: None }
+    }
+
+    pub fn from_config(config: &AttestationConfig) -> AttestationService {
+        AttestationService {
+            connection_str: config.url.clone(),
+            retries: config.retries,
+            spid: Some(config.spid.clone()),
+            subscription_key: config.subscription_key.clone(),
+        }
     }
 
     #[logfn(TRACE)]
@@ -132,13 +231,18 @@ impl AttestationService {
             params: Params {
                 quote,
                 production: true,
+                spid: self.spid.clone(),
             },
             id: 1,
         }
     }
 
     fn attempt_request(&self, client: &Client, quote_req: &QuoteRequest) -> Result<ASResponse, Error> {
-        let mut res = client.post(self.connection_str.as_str()).json(&quote_req).send()?;
+        let mut req = client.post(self.connection_str.as_str()).json(&quote_req);
+        if let Some(key) = &self.subscription_key {
+            req = req.header(SUBSCRIPTION_KEY_HEADER, key.as_str());
+        }
+        let mut res = req.send()?;
         let response_str = res.text()?;
         let json_response: Value = serde_json::from_str(response_str.as_str())?;
 
@@ -215,6 +319,26 @@ impl AttestationService {
     }
 }
 
+impl ASReport {
+    pub fn quote_status(&self) -> QuoteStatus { QuoteStatus::from(self.isv_enclave_quote_status.as_str()) }
+
+    /// Applies `policy` to the status of the quote, returns the warning to report for a quote that's accepted with one.
+    /// Statuses other than `OK` and `GROUP_OUT_OF_DATE` (e.g. `GROUP_REVOKED` or `SIGNATURE_INVALID`) are always rejected.
+    pub fn check_quote_status(&self, policy: OutOfDatePolicy) -> Result<Option<String>, Error> {
+        match (self.quote_status(), policy) {
+            (QuoteStatus::Ok, _) | (QuoteStatus::GroupOutOfDate, OutOfDatePolicy::Accept) => Ok(None),
+            (QuoteStatus::GroupOutOfDate, OutOfDatePolicy::AcceptWithWarning) => Ok(Some("The quote status is GROUP_OUT_OF_DATE".to_string())),
+            (QuoteStatus::GroupOutOfDate, OutOfDatePolicy::Reject) => {
+                let message = "The quote status is GROUP_OUT_OF_DATE, the platform is missing security updates".to_string();
+                Err(errors::AttestationServiceErr { message }.into())
+            }
+            (QuoteStatus::Other(status), _) => {
+                Err(errors::AttestationServiceErr { message: format!("The quote status is {}", status) }.into())
+            }
+        }
+    }
+}
+
 impl ASResponse {
     pub fn get_quote(&self) -> Result<Quote, Error> { Quote::from_base64(&self.result.report.isv_enclave_quote_body) }
 }
@@ -373,6 +497,55 @@ mod test {
         assert_eq!(true, as_response.result.validate);
         assert_eq!("2.0", as_response.jsonrpc);
     }
+    #[test]
+    fn test_attestation_config_mock_endpoint() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+        use std::thread;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/mock-attestation", listener.local_addr().unwrap());
+        // Answer a single request with an error and hand back the raw request that was received.
+        let mock = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let len = text.lines()
+                        .find(|l| l.to_lowercase().starts_with("content-length:"))
+                        .map(|l| l[15..].trim().parse::<usize>().unwrap())
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + len || n == 0 { break; }
+                }
+            }
+            let body = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"mock"}}"#;
+            write!(stream, "HTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let config = AttestationConfig {
+            spid: "1601F95C39B9EA307FEAABB901ADC3EE".to_string(),
+            subscription_key: Some("mock-key".to_string()),
+            url,
+            retries: 0,
+            ..Default::default()
+        };
+        let service = AttestationService::from_config(&config);
+        let res = service.get_report("quote".to_string());
+        assert!(res.unwrap_err().downcast::<AttestationServiceErr>().is_ok());
+
+        let request = mock.join().unwrap();
+        assert!(request.starts_with("POST /mock-attestation "));
+        assert!(request.to_lowercase().contains("ocp-apim-subscription-key: mock-key"));
+        let body: serde_json::Value = serde_json::from_str(&request[request.find("\r\n\r\n").unwrap() + 4..]).unwrap();
+        assert_eq!(body["params"]["spid"], "1601F95C39B9EA307FEAABB901ADC3EE");
+        assert_eq!(body["params"]["quote"], "quote");
+    }
+
     #[test]
     fn test_decoding_quote() {
         let isv_enclave_quote = "AgAAANoKAAAHAAYAAAAAABYB+Vw5ueowf+qruQGtw+5gbJslhOX9eWDNazWpHhBVBAT/////AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABwAAAAAAAAAHAAAAAAAAABIhP23bLUNSZ1yvFIrZa0pu/zt6/n3X8qNjMVbWgOGDAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAACD1xnnferKFHD2uvYqTXdDA8iZ22kCD5xw7h38CMfOngAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAweDRlNmRkMjg0NzdkM2NkY2QzMTA3NTA3YjYxNzM3YWFhMTU5MTYwNzAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
@@ -405,4 +578,51 @@ mod test {
         assert_eq!(&quote.report_body.report_data[..20], &address[..]);
         assert!(response.result.verify_report().unwrap());
     }
+
+    fn report_with_status(status: &str) -> ASReport {
+        let fixture = format!(r#"{{"id":"100342731086430570647295023189732744265","timestamp":"2018-07-15T16:06:47.993263","version":3,"isvEnclaveQuoteStatus":"{}","isvEnclaveQuoteBody":""}}"#, status);
+        serde_json::from_str(&fixture).unwrap()
+    }
+
+    #[test]
+    fn test_out_of_date_policy_accept() {
+        let report = report_with_status("GROUP_OUT_OF_DATE");
+        assert_eq!(report.quote_status(), QuoteStatus::GroupOutOfDate);
+        assert_eq!(report.check_quote_status(OutOfDatePolicy::Accept).unwrap(), None);
+    }
+
+    #[test]
+    fn test_out_of_date_policy_accept_with_warning() {
+        let report = report_with_status("GROUP_OUT_OF_DATE");
+        let warning = report.check_quote_status(OutOfDatePolicy::AcceptWithWarning).unwrap().unwrap();
+        assert!(warning.contains("GROUP_OUT_OF_DATE"));
+    }
+
+    #[test]
+    fn test_out_of_date_policy_reject() {
+        let report = report_with_status("GROUP_OUT_OF_DATE");
+        let err = report.check_quote_status(OutOfDatePolicy::Reject).unwrap_err();
+        assert!(err.downcast::<AttestationServiceErr>().is_ok());
+        // The policy only applies to out of date platforms.
+        assert_eq!(report_with_status("OK").check_quote_status(OutOfDatePolicy::Reject).unwrap(), None);
+    }
+
+    #[test]
+    fn test_other_quote_statuses_rejected() {
+        for status in &["GROUP_REVOKED", "SIGNATURE_INVALID", "KEY_REVOKED", "CONFIGURATION_NEEDED"] {
+            for policy in &[OutOfDatePolicy::Accept, OutOfDatePolicy::AcceptWithWarning, OutOfDatePolicy::Reject] {
+                let err = report_with_status(status).check_quote_status(*policy).unwrap_err();
+                assert!(err.to_string().contains(status), "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_out_of_date_policy() {
+        assert_eq!("accept".parse::<OutOfDatePolicy>().unwrap(), OutOfDatePolicy::Accept);
+        assert_eq!("warn".parse::<OutOfDatePolicy>().unwrap(), OutOfDatePolicy::AcceptWithWarning);
+        assert_eq!("reject".parse::<OutOfDatePolicy>().unwrap(), OutOfDatePolicy::Reject);
+        assert!("ignore".parse::<OutOfDatePolicy>().is_err());
+        assert_eq!(report_with_status("CONFIGURATION_NEEDED").quote_status(), QuoteStatus::Other("CONFIGURATION_NEEDED".to_string()));
+    }
 }
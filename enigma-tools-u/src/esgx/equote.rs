@@ -20,6 +20,16 @@ extern "C" {
                          quote_size: uint32_t) -> sgx_status_t;
 }
 
+/// The size of the EPID signature of a quote produced without a signature revocation list (like `produce_quote` does).
+pub const EPID_SIGNATURE_SIZE: usize = 680;
+
+/// The length of a base64 encoded quote with a `signature_size` bytes signature.
+/// `sgx_quote_t` is the fixed part of the quote, its size includes `signature_len` but not the signature that follows it.
+pub fn expected_quote_len(signature_size: usize) -> usize {
+    let quote_size = std::mem::size_of::<sgx_quote_t>() + signature_size;
+    (quote_size + 2) / 3 * 4
+}
+
 pub fn retry_quote(eid: sgx_enclave_id_t, spid: &str, times: usize) -> Result<String, Error> {
     let mut quote = String::new();
     for _ in 0..times {
@@ -118,3 +128,23 @@ pub fn produce_quote(eid: sgx_enclave_id_t, spid: &str) -> Result<String, Error>
     let encoded_quote = base64::encode(&the_quote);
     Ok(encoded_quote)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expected_quote_len() {
+        // A quote produced by `produce_quote`.
+        let quote = "AgAAANoKAAAHAAYAAAAAALAzX9O8HMqPgE65imQgWS3bL6zst0H4QfxKAKurXXnVBAX/////AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABwAAAAAAAAAHAAAAAAAAAIzp3AzhlP03bwcSpF+o5J3dlTq2zu0T03uf7PbnLtMYAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAACD1xnnferKFHD2uvYqTXdDA8iZ22kCD5xw7h38CMfOngAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAD9sUtS1/Vn5lvk3Mxh+eX0AOjdoAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAqAIAADM2OO98uEjJQLRmzAvAqO4nirzimAHK0PjdgI8MT0xKDy/Paohf208N04YWgzl4kOjrG0X/T8LUphwzn3qB7XkycWqDO9RsLbNIpKRiVBIttztbn0/kxcwo6p54OeOLfhFbxaTn0wkzEYJhGWVR+j6IUGxubDwinf0fO+2vPu20kW1NzSV/Le8fyYzC4v5sIblVW8VZESsbuFd+bFbbcNzco9cH6cNI68FMkeMHoZF/Z4HvP7DR2sIiLnmYcavDbTlzG7OwaTDNcTCNfKsKReK76TRtu+m018QArsRTdrAwx7gZY2788RBpn0veSkU+v9QxNnZmqfpMolAXdu3ksQul4R8bzQ8HoiRkQvedCY8K+5j3GLvDjLCUgB4JP8Vhtt6KjABRO5o4+s3Uj2gBAABJIOqpxIvbG5zmizV7zUe4jAJQoPVM3jtcxXwU9PH5saXiCPHBpTEBpK/2r/5bUnIIBkshRbQ8/kP6/lLhEOu3Fkfh7UMMoizPO8uGQimLBGwbAFyAgU4G8TGeUbYWEGuRRJoKDoclzm9edJZ7mApMlmiT9t2VMLMsg7l49sO1T1TtgK/zpwwLvr2f4a/vmkJWviOcIRimFD+V20xw+EMXYl8Aj4x4Rw62+oiQe0mKvh3K4gXIamejnQHZ/Mrbeh8ai0n1J+GMeKFxxSkeytGZVrT+a75WjLAcJtt5QAU3Em1ELsWLUVUI58mLTe/u+hsjTlWizXAruElzhCIijvR96aHc+lzd/a+EmsQ4mI/mWPxqdoUciznhG4VlxNAhXSw8zn77k8m+1GaBSxvAUDwFOf/V3KcQUYp5Cswo1MD4t26Rn5LBqF1I0I27d/BHD+KUwl7W5doG4Ec6egnoofkSTUnjI3G+9btxIVV2nYWzfXauZzseiZQn";
+        assert_eq!(expected_quote_len(EPID_SIGNATURE_SIZE), quote.len());
+
+        // The signature length in the quote is the one the length is derived from.
+        let bytes = base64::decode(quote).unwrap();
+        let offset = std::mem::size_of::<sgx_quote_t>() - 4;
+        let mut signature_len = [0u8; 4];
+        signature_len.copy_from_slice(&bytes[offset..offset + 4]);
+        assert_eq!(u32::from_le_bytes(signature_len) as usize, EPID_SIGNATURE_SIZE);
+        assert_eq!(bytes.len(), offset + 4 + EPID_SIGNATURE_SIZE);
+    }
+}
@@ -1,5 +1,6 @@
 pub mod equote;
 pub mod general;
 pub mod ocalls_u;
+pub mod output_u;
 
 pub use self::general::init_enclave;
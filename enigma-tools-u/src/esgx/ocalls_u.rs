@@ -1,11 +1,21 @@
 #![allow(unused_attributes)]
 
 use std::{ptr, slice};
+use std::sync::atomic::{AtomicBool, Ordering};
 use enigma_types::traits::SliceCPtr;
+use log::Level;
 use crate::esgx::general;
 
 pub static ENCLAVE_DIR: &'static str = ".enigma";
 
+/// The `log` target of the messages the enclave logs.
+pub const ENCLAVE_LOG_TARGET: &str = "enclave";
+
+static SILENCE_ENCLAVE_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Drops everything the enclave logs instead of passing it to the `log` framework.
+pub fn silence_enclave_logs(silence: bool) { SILENCE_ENCLAVE_LOGS.store(silence, Ordering::SeqCst); }
+
 #[no_mangle]
 pub unsafe extern "C" fn ocall_get_home(output: *mut u8, result_len: &mut usize) {
     let path = general::storage_dir(ENCLAVE_DIR).unwrap(); // TODO: Handle the Error here. it wasn't handled before.
@@ -19,4 +29,111 @@ pub unsafe extern "C" fn ocall_save_to_memory(data_ptr: *const u8, data_len: usi
     let data = slice::from_raw_parts(data_ptr, data_len).to_vec();
     let ptr = Box::into_raw(Box::new(data.into_boxed_slice())) as *const u8;
     ptr as u64
-}
\ No newline at end of file
+}
+
+/// The sink of the enclave's logs, `level` is numbered like `log::Level` and anything unknown is logged as `Trace`.
+#[no_mangle]
+pub unsafe extern "C" fn ocall_log(level: u32, msg_ptr: *const u8, msg_len: usize) {
+    if SILENCE_ENCLAVE_LOGS.load(Ordering::SeqCst) {
+        return;
+    }
+    let level = match level {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        4 => Level::Debug,
+        _ => Level::Trace,
+    };
+    let msg = String::from_utf8_lossy(slice::from_raw_parts(msg_ptr, msg_len));
+    log!(target: ENCLAVE_LOG_TARGET, level, "{}", msg);
+}
+
+/// The out-param of an ecall that returns a variable sized output.
+/// The enclave saves the output with `ocall_save_to_memory` and writes the pointer it got back into the buffer.
+#[derive(Debug, Default)]
+pub struct OutputBuffer(u64);
+
+impl OutputBuffer {
+    pub fn new() -> Self { Self::default() }
+
+    /// The pointer to pass to the ecall as its `uint64_t*` out-param.
+    pub fn as_mut_ptr(&mut self) -> *mut u64 { &mut self.0 }
+
+    /// The output the enclave saved, empty if the ecall didn't save anything.
+    pub fn into_output(self) -> Box<[u8]> {
+        // Only the enclave writes into the buffer, and only pointers it got from `ocall_save_to_memory`.
+        unsafe { Self::take(self.0 as *const u8) }
+    }
+
+    /// Take back the ownership of an output saved by `ocall_save_to_memory`, an empty slice for a null pointer.
+    ///
+    /// # Safety
+    /// `ptr` must be null or a pointer returned by `ocall_save_to_memory` that wasn't taken already.
+    pub unsafe fn take(ptr: *const u8) -> Box<[u8]> {
+        if ptr.is_null() {
+            return Box::new([]);
+        }
+        *Box::from_raw(ptr as *mut Box<[u8]>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ocall_log, ocall_save_to_memory, silence_enclave_logs, OutputBuffer, ENCLAVE_LOG_TARGET};
+    use log::{Level, Log, Metadata, Record};
+    use std::sync::Mutex;
+
+    /// Keeps every record logged with the enclave's target.
+    struct CaptureLogger(Mutex<Vec<(Level, String)>>);
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool { metadata.target() == ENCLAVE_LOG_TARGET }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.0.lock().unwrap().push((record.level(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Behaves like an ecall that saves `data` as its output.
+    unsafe fn mock_ecall(data: &[u8], serialized_ptr: *mut u64) {
+        *serialized_ptr = ocall_save_to_memory(data.as_ptr(), data.len());
+    }
+
+    #[test]
+    fn test_output_buffer() {
+        let data = b"the output of the enclave";
+        let mut output = OutputBuffer::new();
+        unsafe { mock_ecall(data, output.as_mut_ptr()) };
+        let output = output.into_output();
+        assert_eq!(output.len(), data.len());
+        assert_eq!(&output[..], &data[..]);
+    }
+
+    #[test]
+    fn test_output_buffer_untouched() {
+        assert!(OutputBuffer::new().into_output().is_empty());
+    }
+
+    #[test]
+    fn test_enclave_log_sink() {
+        // This is the only test in the crate that sets a logger.
+        let logger: &'static CaptureLogger = Box::leak(Box::new(CaptureLogger(Mutex::new(Vec::new()))));
+        log::set_logger(logger).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let msg = "Sealed document loaded successfully.";
+        unsafe { ocall_log(3, msg.as_ptr(), msg.len()) };
+        let err = "Failed applying delta";
+        unsafe { ocall_log(1, err.as_ptr(), err.len()) };
+        assert_eq!(*logger.0.lock().unwrap(), vec![(Level::Info, msg.to_string()), (Level::Error, err.to_string())]);
+
+        silence_enclave_logs(true);
+        unsafe { ocall_log(3, msg.as_ptr(), msg.len()) };
+        silence_enclave_logs(false);
+        assert_eq!(logger.0.lock().unwrap().len(), 2);
+    }
+}
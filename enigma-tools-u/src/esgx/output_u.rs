@@ -0,0 +1,100 @@
+//! # Ecall Outputs.
+//! The untrusted side of the two phase protocol of the ecalls with a variable length output.
+//! The ecall gets a buffer with some capacity and reports the length of its output,
+//! if the output fit it was written into the buffer, otherwise the enclave keeps it and reports a handle to it too.
+//! Then the output is fetched with that handle (`ecall_fetch_output`) into a buffer of exactly its length.
+//! The ecall itself runs only once either way, which matters for the ecalls that aren't idempotent.
+
+/// The capacity of the buffer an ecall gets in the first phase, most outputs fit in it.
+pub const DEFAULT_OUTPUT_CAP: usize = 4 * 1024;
+
+/// Returns the output of `ecall`, which is given a buffer of `cap` bytes and writes the length of its output and its handle
+/// into the two `u64`s (the `uint64_t*` out-params of the ecall).
+/// If the output didn't fit into the buffer it's fetched by `fetch` with the handle into a buffer of the reported length.
+pub fn ecall_output<E, Q, F>(cap: usize, ecall: Q, fetch: F) -> Result<Vec<u8>, E>
+where Q: FnOnce(&mut [u8], &mut u64, &mut u64) -> Result<(), E>,
+      F: FnOnce(u64, &mut [u8]) -> Result<(), E> {
+    let mut output = vec![0u8; cap];
+    let (mut len, mut handle) = (0u64, 0u64);
+    ecall(&mut output, &mut len, &mut handle)?;
+    let len = len as usize;
+    if len <= cap {
+        output.truncate(len);
+        return Ok(output);
+    }
+    let mut output = vec![0u8; len];
+    fetch(handle, &mut output)?;
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ecall_output;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+
+    /// Behaves like the enclave's side of the protocol, keeps the outputs that didn't fit.
+    #[derive(Default)]
+    struct MockEnclave {
+        pending: RefCell<HashMap<u64, Vec<u8>>>,
+        calls: Cell<usize>,
+    }
+
+    impl MockEnclave {
+        /// An ecall whose output is `data`.
+        fn ecall(&self, data: &[u8], output: &mut [u8], len: &mut u64, handle: &mut u64) -> Result<(), String> {
+            self.calls.set(self.calls.get() + 1);
+            *len = data.len() as u64;
+            if data.len() <= output.len() {
+                output[..data.len()].copy_from_slice(data);
+                *handle = 0;
+            } else {
+                *handle = self.calls.get() as u64;
+                self.pending.borrow_mut().insert(*handle, data.to_vec());
+            }
+            Ok(())
+        }
+
+        fn fetch(&self, handle: u64, output: &mut [u8]) -> Result<(), String> {
+            let data = self.pending.borrow_mut().remove(&handle).ok_or("no such output")?;
+            if data.len() != output.len() {
+                return Err("wrong length".to_string());
+            }
+            output.copy_from_slice(&data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_output_fits() {
+        let enclave = MockEnclave::default();
+        let data = b"a short output";
+        let output = ecall_output(64, |o, l, h| enclave.ecall(data, o, l, h), |_, _| panic!("nothing to fetch")).unwrap();
+        assert_eq!(output, data.to_vec());
+        assert_eq!(enclave.calls.get(), 1);
+    }
+
+    #[test]
+    fn test_output_fetched() {
+        let enclave = MockEnclave::default();
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let output = ecall_output(64, |o, l, h| enclave.ecall(&data, o, l, h), |h, o| enclave.fetch(h, o)).unwrap();
+        assert_eq!(output, data);
+        // The ecall ran once, and the enclave doesn't keep the output after it was fetched.
+        assert_eq!(enclave.calls.get(), 1);
+        assert!(enclave.pending.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_empty_output() {
+        let enclave = MockEnclave::default();
+        let output = ecall_output(0, |o, l, h| enclave.ecall(&[], o, l, h), |_, _| panic!("nothing to fetch")).unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_ecall_error() {
+        let res = ecall_output::<String, _, _>(64, |_, _, _| Err("failed".to_string()), |_, _| panic!("nothing to fetch"));
+        assert_eq!(res.unwrap_err(), "failed");
+    }
+}
@@ -21,6 +21,8 @@ fn main() {
         .include_item("MsgID")
         .include_item("PubKey")
         .include_item("RawPointer")
+        .include_item("HostCapabilities")
+        .include_item("GasMultiplier")
         .with_crate(&crate_dir)
         .generate()
         .expect("Unable to generate bindings")
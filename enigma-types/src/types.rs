@@ -9,7 +9,8 @@
 //! Note: Please use the right types even if they're only aliases right now,
 //! this helps both for readability and if in the future we decide to change the alias.
 
-use core::{fmt, mem, ptr, default::Default};
+use core::{fmt, mem, default::Default};
+use crate::serde::{Serialize, Deserialize};
 
 pub use crate::hash::Hash256;
 /// The size of the symmetric 256 bit key we use for encryption (in bytes).
@@ -64,6 +65,8 @@ pub enum EnclaveReturn {
     // TODO: should consider merging with a different error.
     /// Missing StateKeys in the KM node.
     KeyProvisionError,
+    /// LockError, a lock in the enclave is poisoned or stayed held for too long.
+    LockError,
     /// Something went really wrong.
     Other
 }
@@ -80,24 +83,131 @@ pub enum ResultStatus {
 }
 
 
+/// The host functions a contract is allowed to import, chosen by the deployer.
+/// They're authenticated by the enclave together with the price of the contract (see `ExecuteResult::settings_mac`)
+/// and checked whenever the contract is instantiated, in the deployment and in every computation. By default everything is allowed.
+#[repr(C)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "crate::serde")]
+pub struct HostCapabilities {
+    bits: u32,
+}
+
+impl HostCapabilities {
+    /// No capabilities at all.
+    pub const NONE: HostCapabilities = HostCapabilities { bits: 0 };
+    /// Randomness from the enclave (the `rand` import), forbid it to make the contract reproducible.
+    pub const RANDOMNESS: HostCapabilities = HostCapabilities { bits: 1 };
+    /// Calls to other (Ethereum) contracts (the `write_eth_bridge` import).
+    pub const ETHEREUM_BRIDGE: HostCapabilities = HostCapabilities { bits: 1 << 1 };
+    /// All of the capabilities.
+    pub const ALL: HostCapabilities = HostCapabilities { bits: (1 << 2) - 1 };
+
+    /// Returns true if all of the capabilities in `other` are allowed.
+    pub fn contains(self, other: HostCapabilities) -> bool { self.bits & other.bits == other.bits }
+
+    /// Returns these capabilities without the ones in `other`.
+    pub fn without(self, other: HostCapabilities) -> HostCapabilities { HostCapabilities { bits: self.bits & !other.bits } }
+
+    /// The canonical encoding of the capabilities, as they're authenticated by the enclave.
+    pub fn to_be_bytes(self) -> [u8; 4] { self.bits.to_be_bytes() }
+}
+
+impl Default for HostCapabilities {
+    fn default() -> HostCapabilities { HostCapabilities::ALL }
+}
+
+/// The price of a single contract relative to the base cost table, every wasm instruction it executes costs `cost * mul / div`
+/// (rounded down, but at least 1).
+/// It's chosen when the contract is deployed (e.g. to make trusted system contracts cheaper), by default it's 1.
+#[repr(C)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "crate::serde")]
+pub struct GasMultiplier {
+    /// The numerator of the multiplier.
+    pub mul: u32,
+    /// The denominator of the multiplier, 0 is treated as 1.
+    pub div: u32,
+}
+
+impl GasMultiplier {
+    /// Scales `cost` by the multiplier, saturating at `u32::max_value()`.
+    /// A cost that isn't 0 stays at least 1, so no multiplier makes an instruction free.
+    pub fn apply(self, cost: u32) -> u32 {
+        if cost == 0 {
+            return 0;
+        }
+        let scaled = u64::from(cost) * u64::from(self.mul) / u64::from(self.div.max(1));
+        if scaled > u64::from(u32::max_value()) { u32::max_value() } else { (scaled as u32).max(1) }
+    }
+
+    /// The canonical encoding of the multiplier, as it's authenticated by the enclave.
+    pub fn to_be_bytes(self) -> [u8; 8] {
+        let mut bytes = [0u8; 8];
+        bytes[..4].copy_from_slice(&self.mul.to_be_bytes());
+        bytes[4..].copy_from_slice(&self.div.to_be_bytes());
+        bytes
+    }
+}
+
+impl Default for GasMultiplier {
+    fn default() -> GasMultiplier { GasMultiplier { mul: 1, div: 1 } }
+}
+
 /// This struct is what returned from a Deploy/Compute ecall, it contains all the needed data.
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct ExecuteResult {
-    /// A pointer to the output of the execution using [`ocall_save_to_memory`](../replace_me) (on the untrusted stack)
-    pub output: *const u8,
-    /// A pointer to the resulting delta using [`ocall_save_to_memory`](../replace_me) (on the untrusted stack)
-    pub delta_ptr: *const u8,
+    /// The handle the output of the execution is fetched with using `ecall_fetch_output`, 0 if it's empty.
+    pub output_handle: u64,
+    /// The length of the output of the execution.
+    pub output_len: u64,
+    /// The handle the resulting delta is fetched with using `ecall_fetch_output`, 0 if it's empty.
+    pub delta_handle: u64,
+    /// The length of the resulting delta.
+    pub delta_len: u64,
     /// The delta index number.
     pub delta_index: u32,
-    /// A pointer to the Ethereum payload using [`ocall_save_to_memory`](../replace_me) (on the untrusted stack)
-    pub ethereum_payload_ptr: *const u8,
+    /// The handle the Ethereum payload is fetched with using `ecall_fetch_output`, 0 if it's empty.
+    pub ethereum_payload_handle: u64,
+    /// The length of the Ethereum payload.
+    pub ethereum_payload_len: u64,
     /// The ethereum address that the payload belongs to.
     pub ethereum_address: [u8; 20],
     /// A signature by the enclave on all of the results.
     pub signature: [u8; 65],
     /// The gas used by the execution.
     pub used_gas: u64,
+    /// On deployment, the part of `used_gas` that was spent executing the constructor.
+    pub constructor_gas: u64,
+    /// On deployment, the part of `used_gas` that was charged for storing the deployed bytecode.
+    pub storage_gas: u64,
+    /// On computation, the largest the WASM memory of the contract got (in bytes), this isn't signed.
+    pub peak_memory_bytes: u64,
+    /// The instructions the contract executed as the injected gas counter metered them, this isn't signed.
+    /// Every instruction counts as much as it costs in the contract's cost table (scaled by its gas multiplier), so loads, stores,
+    /// multiplications and divisions count as more than one. The host functions don't count.
+    pub instructions: u64,
+    /// On computation, a signature by the enclave on the output, the delta hash, the used gas, the task id and the status,
+    /// see [`compute_result_message`](../enigma_tools_m/primitives/task_primitives/fn.compute_result_message.html).
+    /// On deployment, a signature by the enclave on the
+    /// [`DeployReceipt`](../enigma_tools_m/primitives/task_primitives/struct.DeployReceipt.html).
+    pub result_signature: [u8; 65],
+    /// On deployment, a MAC over the gas multiplier and the host capabilities of the contract under a key only the holders of its state key have.
+    /// It's stored with them and every computation of the contract must present it.
+    pub settings_mac: [u8; 32],
+}
+
+/// What replaying a computation cost, it's returned by `ecall_replay_compute` next to the signed verdict and isn't signed.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// The gas the replay used, also when the replayed task failed.
+    pub used_gas: u64,
+    /// The instructions the replay executed, see `ExecuteResult::instructions`, 0 if the replayed task failed.
+    pub instructions: u64,
+    /// Whether the replayed task failed, then it doesn't match any claim.
+    pub failed: bool,
 }
 
 /// This struct is a wrapper to a raw pointer.
@@ -178,25 +288,29 @@ impl From<bool> for ResultStatus {
 
 impl Default for ExecuteResult {
     fn default() -> ExecuteResult {
-        ExecuteResult {
-            output: ptr::null(),
-            delta_ptr: ptr::null(),
-            ethereum_payload_ptr: ptr::null(),
-            .. unsafe { mem::zeroed() }
-        }
+        unsafe { mem::zeroed() }
     }
 }
 
 impl fmt::Debug for ExecuteResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut debug_trait_builder = f.debug_struct("ExecuteResult");
-        debug_trait_builder.field("output", &(self.output));
-        debug_trait_builder.field("delta_ptr", &(self.delta_ptr));
+        debug_trait_builder.field("output_handle", &(self.output_handle));
+        debug_trait_builder.field("output_len", &(self.output_len));
+        debug_trait_builder.field("delta_handle", &(self.delta_handle));
+        debug_trait_builder.field("delta_len", &(self.delta_len));
         debug_trait_builder.field("delta_index", &(self.delta_index));
-        debug_trait_builder.field("ethereum_payload_ptr", &(self.ethereum_payload_ptr));
+        debug_trait_builder.field("ethereum_payload_handle", &(self.ethereum_payload_handle));
+        debug_trait_builder.field("ethereum_payload_len", &(self.ethereum_payload_len));
         debug_trait_builder.field("ethereum_address", &(self.ethereum_address));
         debug_trait_builder.field("signature", &(&self.signature[..]));
         debug_trait_builder.field("used_gas", &(self.used_gas));
+        debug_trait_builder.field("constructor_gas", &(self.constructor_gas));
+        debug_trait_builder.field("storage_gas", &(self.storage_gas));
+        debug_trait_builder.field("peak_memory_bytes", &(self.peak_memory_bytes));
+        debug_trait_builder.field("instructions", &(self.instructions));
+        debug_trait_builder.field("result_signature", &(&self.result_signature[..]));
+        debug_trait_builder.field("settings_mac", &(self.settings_mac));
         debug_trait_builder.finish()
     }
 }
@@ -223,6 +337,7 @@ impl fmt::Display for EnclaveReturn {
             MessagingError => "EnclaveReturn: MessagingError",
             WorkerAuthError => "EnclaveReturn: WorkerAuthError",
             KeyProvisionError => "EnclaveReturn: KeyProvisionError",
+            LockError => "EnclaveReturn: LockError",
             Other => "EnclaveReturn: Other",
         };
         write!(f, "{}", p)
@@ -256,3 +371,18 @@ impl<T: ResultToEnclaveReturn> From<Result<(), T>> for EnclaveReturn {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::GasMultiplier;
+    #[test]
+    fn test_gas_multiplier_apply() {
+        assert_eq!(GasMultiplier { mul: 3, div: 2 }.apply(4), 6);
+        assert_eq!(GasMultiplier { mul: 1, div: 0 }.apply(4), 4);
+        assert_eq!(GasMultiplier { mul: u32::max_value(), div: 1 }.apply(2), u32::max_value());
+        // Rounding down never makes an instruction free.
+        assert_eq!(GasMultiplier { mul: 1, div: 8 }.apply(1), 1);
+        assert_eq!(GasMultiplier { mul: 0, div: 1 }.apply(16), 1);
+        assert_eq!(GasMultiplier { mul: 1, div: 8 }.apply(0), 0);
+    }
+}
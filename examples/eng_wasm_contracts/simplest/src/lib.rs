@@ -11,10 +11,27 @@ pub trait ContractInterface{
     fn write() -> Vec<u8>;
     fn check_address(addr: H256) -> H256;
     fn check_addresses(addr1: H256, addr2: H256) -> Vec<H256>;
+    fn check_eth_address(addr: H160) -> H160;
+    fn store_caller() -> H160;
+    fn get_stored_caller() -> H160;
+    fn next_counters() -> Vec<U256>;
+    fn store_u128(x: U128) -> U128;
+    fn get_u128() -> U128;
+    fn write_and_panic();
+    fn write_and_revert(x: U256);
+    fn fill_state();
+    fn clear_state();
+    fn write_invalid_bytes();
+    fn write_nested(depth: U256);
+    fn write_namespaces(x: U256, y: U256) -> Vec<U256>;
     fn choose_rand_color() -> Vec<u8>;
     fn get_scrambled_vec();
     fn addition(x: U256, y: U256) -> U256;
     fn get_last_sum() -> U256;
+    fn store_big(x: U256) -> U256;
+    fn get_parties() -> (Vec<H256>, Vec<U256>);
+    fn get_parties_manually();
+    fn grow_memory(size: U256);
     fn print_test(x: U256, y: U256);
     fn dynamic_types(bytes_arr: Vec<Vec<u8>>, string_arr: Vec<String>, fixed_arr: Vec<H256>);
     fn construct(param: U256);
@@ -23,6 +40,12 @@ pub trait ContractInterface{
 pub struct Contract;
 
 impl Contract {
+    fn parties() -> (Vec<H256>, Vec<U256>) {
+        let addresses = vec![H256::from([1u8; 32]), H256::from([2u8; 32])];
+        let amounts = vec![U256::from(10), U256::from(20), U256::from(30)];
+        (addresses, amounts)
+    }
+
     fn gen_loc(len: usize) -> usize {
         assert!(len > 0);
         let rand: u32 = Rand::gen();
@@ -62,6 +85,50 @@ impl ContractInterface for Contract {
         assert_eq!(read_val, addr);
         read_val
     }
+
+    fn check_eth_address(addr: H160) -> H160 {
+        write_state!("eth_addr" => addr);
+        let read_val: H160 = read_state!("eth_addr").unwrap_or_default();
+        assert_eq!(read_val, addr);
+        assert_eq!(H160::from_hex(&read_val.to_hex()).unwrap(), addr);
+        read_val
+    }
+
+    fn store_caller() -> H160 {
+        let caller = caller();
+        write_state!("caller" => caller);
+        caller
+    }
+
+    fn get_stored_caller() -> H160 {
+        read_state!("caller").unwrap_or_default()
+    }
+
+    fn next_counters() -> Vec<U256> {
+        (0..3).map(|_| U256::from(Counter::next())).collect()
+    }
+
+    fn store_u128(x: U128) -> U128 {
+        write_state!("u128" => x);
+        read_state!("u128").unwrap_or_default()
+    }
+
+    fn get_u128() -> U128 {
+        read_state!("u128").unwrap_or_default()
+    }
+
+    // the writes should be discarded since the execution traps
+    fn write_and_panic() {
+        write_state!("first" => 1u64, "second" => 2u64);
+        panic!("panicking after writing to the state");
+    }
+
+    // the writes should be discarded and the reason returned to the caller
+    fn write_and_revert(x: U256) {
+        write_state!("first" => x.as_u64());
+        require!(x.as_u64() < 10, "x must be smaller than 10");
+    }
+
     fn dynamic_types(bytes_arr: Vec<Vec<u8>>, string_arr: Vec<String>, fixed_arr: Vec<H256>) {
         eprint!("array of bytes: {:?}",bytes_arr);
         eprint!("array of String: {:?}",string_arr);
@@ -83,6 +150,50 @@ impl ContractInterface for Contract {
         ret
     }
 
+    // writes a large value so that removing it frees a noticeable amount of state
+    fn fill_state() {
+        let large: String = "enigma".repeat(200);
+        write_state!("large" => large);
+    }
+
+    // removes the key without reading it first, so the executed code is the same whether the key exists or not
+    fn clear_state() {
+        let key = "large";
+        unsafe { external::remove_from_state(key.as_ptr(), key.len() as u32) };
+    }
+
+    // writes bytes that aren't valid UTF-8 (and therefore not valid JSON) directly into the state
+    fn write_invalid_bytes() {
+        let key = "bytes";
+        let value = [0xffu8, 0xfe, 0x00, 0xc3, 0x28];
+        unsafe { external::write_state(key.as_ptr(), key.len() as u32, value.as_ptr(), value.len() as u32) };
+    }
+
+    // writes an array nested `depth` times, the runtime walks it recursively when it stores it
+    fn write_nested(depth: U256) {
+        let depth = depth.as_usize();
+        let key = "nested";
+        let mut value = "[".repeat(depth);
+        value.push('1');
+        value.push_str(&"]".repeat(depth));
+        unsafe { external::write_state(key.as_ptr(), key.len() as u32, value.as_ptr(), value.len() as u32) };
+    }
+
+    // the same key in two namespaces holds two different values
+    fn write_namespaces(x: U256, y: U256) -> Vec<U256> {
+        write_state!(ns "first", "value" => x.as_u64());
+        write_state!(ns "second", "value" => y.as_u64());
+        let flat: Option<u64> = read_state!("value");
+        assert!(flat.is_none());
+
+        let first: u64 = read_state!(ns "first", "value").unwrap_or_default();
+        let second: u64 = read_state!(ns "second", "value").unwrap_or_default();
+        let mut ret = Vec::with_capacity(2);
+        ret.push(first.into());
+        ret.push(second.into());
+        ret
+    }
+
     // tests the random service
     fn choose_rand_color() -> Vec<u8> {
         let mut colors = Vec::new();
@@ -113,6 +224,29 @@ impl ContractInterface for Contract {
         sum.into()
     }
 
+    // writes a number that doesn't fit in a double and reads it back
+    fn store_big(x: U256) -> U256 {
+        write_state!("big" => x);
+        read_state!("big").unwrap_or_default()
+    }
+
+    // returns two arrays as a tuple
+    fn get_parties() -> (Vec<H256>, Vec<U256>) {
+        Self::parties()
+    }
+
+    // returns the same arrays as `get_parties` without going through the generated dispatch
+    fn get_parties_manually() {
+        let (addresses, amounts) = Self::parties();
+        ret_tuple!(addresses, amounts);
+    }
+
+    fn grow_memory(size: U256) {
+        let buf = vec![1u8; size.as_usize()];
+        let sum: u64 = buf.iter().map(|b| *b as u64).sum();
+        write_state!("curr_sum" => sum);
+    }
+
     fn print_test(x: U256, y: U256) {
         eprint!("{:?} {:?}", x.as_u64(), y.as_u64());
     }